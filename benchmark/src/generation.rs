@@ -137,6 +137,13 @@ async fn prefill(
         .map(|id| Request {
             id: id.into(),
             prefill_logprobs: false,
+            top_n_tokens: 0,
+            prefix_len: 0,
+            session_id: None,
+            prefill_offset: 0,
+            prefill_chunk_len: None,
+            input_chunks: vec![],
+            add_special_tokens: true,
             inputs: sequence.clone(),
             truncate: sequence_length,
             parameters: Some(parameters.clone()),
@@ -144,6 +151,7 @@ async fn prefill(
                 max_new_tokens: decode_length,
                 stop_sequences: vec![],
                 ignore_eos_token: true, // Will not stop even if a eos token is generated
+                stop_token_ids: vec![],
             }),
         })
         .collect();
@@ -217,7 +225,5 @@ fn create_sequence(sequence_length: u32, tokenizer: Tokenizer) -> String {
     // Truncate to sequence_length
     encoding.truncate(sequence_length as usize, 0, TruncationDirection::Left);
     // Decode
-    tokenizer
-        .decode(encoding.get_ids(), false)
-        .unwrap()
+    tokenizer.decode(encoding.get_ids(), false).unwrap()
 }
@@ -7,8 +7,9 @@ mod utils;
 use crate::app::App;
 use crate::event::Event;
 use crossterm::ExecutableCommand;
+use std::collections::HashMap;
 use std::io;
-use text_generation_client::{NextTokenChooserParameters, ShardedClient};
+use text_generation_client::{GrammarType, NextTokenChooserParameters, ShardedClient};
 use tokenizers::Tokenizer;
 use tokio::sync::{broadcast, mpsc};
 use tui::backend::CrosstermBackend;
@@ -28,6 +29,9 @@ pub async fn run(
     top_k: Option<u32>,
     top_p: Option<f32>,
     typical_p: Option<f32>,
+    min_p: Option<f32>,
+    epsilon_cutoff: Option<f32>,
+    eta_cutoff: Option<f32>,
     repetition_penalty: Option<f32>,
     no_repeat_ngram_size: Option<u32>,
     watermark: bool,
@@ -46,6 +50,19 @@ pub async fn run(
         repetition_penalty: repetition_penalty.unwrap_or(1.0),
         no_repeat_ngram_size: no_repeat_ngram_size.unwrap_or(0),
         watermark,
+        logit_bias: HashMap::new(),
+        grammar: String::new(),
+        grammar_type: GrammarType::None as i32,
+        min_p: min_p.unwrap_or(0.0),
+        frequency_penalty: 0.0,
+        presence_penalty: 0.0,
+        bad_words_ids: vec![],
+        epsilon_cutoff: epsilon_cutoff.unwrap_or(0.0),
+        eta_cutoff: eta_cutoff.unwrap_or(0.0),
+        mirostat: 0,
+        mirostat_tau: 0.0,
+        mirostat_eta: 0.0,
+        token_healing_prefix: String::new(),
     };
 
     // Initialize terminal properties
@@ -68,6 +85,9 @@ pub async fn run(
     // Channel to check if tasks terminated
     let (shutdown_guard_sender, mut shutdown_guard_receiver) = mpsc::channel(1);
 
+    // Metrics of the calls to the shards, shared with the client
+    let rpc_metrics = client.metrics().clone();
+
     // Create generation task
     tokio::spawn(generation::generation_task(
         tokenizer,
@@ -140,6 +160,9 @@ pub async fn run(
         top_k,
         top_p,
         typical_p,
+        min_p,
+        epsilon_cutoff,
+        eta_cutoff,
         repetition_penalty,
         watermark,
         do_sample,
@@ -154,5 +177,8 @@ pub async fn run(
     let throughput_table = table::throughput_table(&app.data);
     println!("\n{throughput_table}\n");
 
+    let rpc_table = table::rpc_table(&rpc_metrics.snapshot());
+    println!("\n{rpc_table}\n");
+
     Ok(())
 }
@@ -4,7 +4,7 @@
 /// and: https://github.com/orhun/rust-tui-template
 use clap::Parser;
 use std::path::Path;
-use text_generation_client::ShardedClient;
+use text_generation_client::{ShardedClient, TlsConfig};
 use tokenizers::{FromPretrainedParameters, Tokenizer};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -55,10 +55,16 @@ struct Args {
     warmups: usize,
 
     /// The location of the grpc socket. This benchmark tool bypasses the router
-    /// completely and directly talks to the gRPC processes
+    /// completely and directly talks to the gRPC processes. Can also be the
+    /// `http(s)://` uri of shards running on another host
     #[clap(default_value = "/tmp/text-generation-server-0", short, long, env)]
     master_shard_uds_path: String,
 
+    /// PEM certificate of the authority of the certificates of `https://` shards,
+    /// the system roots are trusted if unset
+    #[clap(long, env)]
+    shard_tls_ca_cert: Option<String>,
+
     /// Generation parameter in case you want to specifically test/debug particular
     /// decoding strategies, for full doc refer to the `text-generation-server`
     #[clap(long, env)]
@@ -79,6 +85,21 @@ struct Args {
     #[clap(long, env)]
     typical_p: Option<f32>,
 
+    /// Generation parameter in case you want to specifically test/debug particular
+    /// decoding strategies, for full doc refer to the `text-generation-server`
+    #[clap(long, env)]
+    min_p: Option<f32>,
+
+    /// Generation parameter in case you want to specifically test/debug particular
+    /// decoding strategies, for full doc refer to the `text-generation-server`
+    #[clap(long, env)]
+    epsilon_cutoff: Option<f32>,
+
+    /// Generation parameter in case you want to specifically test/debug particular
+    /// decoding strategies, for full doc refer to the `text-generation-server`
+    #[clap(long, env)]
+    eta_cutoff: Option<f32>,
+
     /// Generation parameter in case you want to specifically test/debug particular
     /// decoding strategies, for full doc refer to the `text-generation-server`
     #[clap(long, env)]
@@ -97,6 +118,8 @@ struct Args {
     #[clap(long, env)]
     min_new_tokens: Option<u32>,
 
+    /// Generation parameter in case you want to specifically test/debug particular
+    /// decoding strategies, for full doc refer to the `text-generation-server`
     #[clap(long, env)]
     no_repeat_ngram_size: Option<u32>,
 }
@@ -119,12 +142,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         top_k,
         top_p,
         typical_p,
+        min_p,
+        epsilon_cutoff,
+        eta_cutoff,
         repetition_penalty,
         watermark,
         do_sample,
         min_new_tokens,
         no_repeat_ngram_size,
         master_shard_uds_path,
+        shard_tls_ca_cert,
     } = args;
 
     let batch_size = batch_size.unwrap_or(vec![1, 2, 4, 8, 16, 32]);
@@ -162,11 +189,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build()
         .unwrap()
         .block_on(async {
-            // Instantiate sharded client from the master unix socket
+            // Instantiate sharded client from the master unix socket or uri
             tracing::info!("Connect to model server");
-            let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path)
-                .await
-                .expect("Could not connect to server");
+            let tls = TlsConfig {
+                ca_certificate: shard_tls_ca_cert
+                    .map(|path| std::fs::read(path).expect("Could not read the CA certificate")),
+                ..Default::default()
+            };
+            let mut sharded_client =
+                ShardedClient::connect_address(master_shard_uds_path, Some(tls))
+                    .await
+                    .expect("Could not connect to server");
             // Clear the cache; useful if the webserver rebooted
             sharded_client
                 .clear_cache(None)
@@ -187,6 +220,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 top_k,
                 top_p,
                 typical_p,
+                min_p,
+                epsilon_cutoff,
+                eta_cutoff,
                 repetition_penalty,
                 no_repeat_ngram_size,
                 watermark,
@@ -1,6 +1,8 @@
 use crate::app::Data;
+use std::collections::BTreeMap;
 use tabled::settings::Merge;
 use tabled::{builder::Builder, settings::Style, Table};
+use text_generation_client::MethodMetrics;
 
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn parameters_table(
@@ -13,6 +15,9 @@ pub(crate) fn parameters_table(
     top_k: Option<u32>,
     top_p: Option<f32>,
     typical_p: Option<f32>,
+    min_p: Option<f32>,
+    epsilon_cutoff: Option<f32>,
+    eta_cutoff: Option<f32>,
     repetition_penalty: Option<f32>,
     watermark: bool,
     do_sample: bool,
@@ -32,6 +37,9 @@ pub(crate) fn parameters_table(
     builder.push_record(["Top K", &format!("{top_k:?}")]);
     builder.push_record(["Top P", &format!("{top_p:?}")]);
     builder.push_record(["Typical P", &format!("{typical_p:?}")]);
+    builder.push_record(["Min P", &format!("{min_p:?}")]);
+    builder.push_record(["Epsilon Cutoff", &format!("{epsilon_cutoff:?}")]);
+    builder.push_record(["Eta Cutoff", &format!("{eta_cutoff:?}")]);
     builder.push_record(["Repetition Penalty", &format!("{repetition_penalty:?}")]);
     builder.push_record(["Watermark", &watermark.to_string()]);
     builder.push_record(["Do Sample", &do_sample.to_string()]);
@@ -104,6 +112,43 @@ pub(crate) fn throughput_table(data: &Data) -> Table {
     table
 }
 
+pub(crate) fn rpc_table(methods: &BTreeMap<&'static str, MethodMetrics>) -> Table {
+    let mut builder = Builder::default();
+
+    builder.set_header([
+        "Shard Call",
+        "Calls",
+        "Errors",
+        "Average",
+        "Highest",
+        "Request Size",
+        "Response Size",
+    ]);
+
+    for (method, metrics) in methods {
+        let average = metrics
+            .mean_latency()
+            .map_or(f64::NAN, |latency| latency.as_secs_f64() * 1000.0);
+        let calls = metrics.calls as f64;
+
+        let row = [
+            *method,
+            &metrics.calls.to_string(),
+            &metrics.errors.to_string(),
+            &format_value(average, "ms"),
+            &format_value(metrics.max_latency.as_secs_f64() * 1000.0, "ms"),
+            &format_value(metrics.request_bytes as f64 / calls, "bytes"),
+            &format_value(metrics.response_bytes as f64 / calls, "bytes"),
+        ];
+
+        builder.push_record(row);
+    }
+
+    let mut table = builder.build();
+    table.with(Style::markdown());
+    table
+}
+
 fn add_latencies(
     builder: &mut Builder,
     step: &'static str,
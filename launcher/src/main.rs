@@ -93,6 +93,11 @@ struct Args {
     #[clap(default_value = "2", long, env)]
     validation_workers: usize,
 
+    /// The maximum number of inputs a tokenizer worker encodes together. The inputs queued while
+    /// a worker is busy are encoded in a single batch instead of one at a time.
+    #[clap(default_value = "8", long, env)]
+    max_tokenization_batch_size: usize,
+
     /// Whether to shard the model across multiple GPUs
     /// By default text-generation-inference will use all available GPUs to run
     /// the model. Setting it to `false` deactivates `num_shard`.
@@ -137,14 +142,28 @@ struct Args {
     #[clap(default_value = "2", long, env)]
     max_best_of: usize,
 
+    /// This is the maximum number of prompts clients can send at once to `/generate`
+    /// by setting `inputs` to an array of prompts.
+    #[clap(default_value = "4", long, env)]
+    max_client_batch_size: usize,
+
     /// This is the maximum allowed value for clients to set `stop_sequences`.
     /// Stop sequences are used to allow the model to stop on more than just
     /// the EOS token, and enable more complex "prompting" where users can preprompt
     /// the model in a specific way and define their "own" stop token aligned with
-    /// their prompt.
+    /// their prompt. The literal `stop` and the `stop_regex` sequences of a request count
+    /// together against this limit.
     #[clap(default_value = "4", long, env)]
     max_stop_sequences: usize,
 
+    /// This is the maximum allowed value for clients to set `top_n_tokens`.
+    /// `top_n_tokens` is used to return information about the `n` most likely
+    /// tokens at each generation step, instead of just the sampled token. This
+    /// information can be used for downstream tasks like for classification or
+    /// ranking.
+    #[clap(default_value = "5", long, env)]
+    max_top_n_tokens: u32,
+
     /// This is the maximum allowed input length (expressed in number of tokens)
     /// for users. The larger this value, the longer prompt users can send which
     /// can impact the overall memory required to handle the load.
@@ -230,11 +249,98 @@ struct Args {
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
 
+    /// The port to serve the public gRPC generation API on, with the API keys, rate limits
+    /// and intake pause of the HTTP API. The gRPC API is disabled if unset.
+    #[clap(long, env)]
+    grpc_port: Option<u16>,
+
     /// The name of the socket for gRPC communication between the webserver
     /// and the shards.
     #[clap(default_value = "/tmp/text-generation-server", long, env)]
     shard_uds_path: String,
 
+    /// The first port of the TLS gRPC listeners of the shards, shard `n` listening on
+    /// `shard_tls_port + n`. When set, the webserver connects to the shards over TLS
+    /// instead of their unix sockets.
+    #[clap(long, env)]
+    shard_tls_port: Option<u16>,
+
+    /// The host of the shards in the uris of their TLS listeners, it must match their
+    /// certificate.
+    #[clap(default_value = "localhost", long, env)]
+    shard_tls_hostname: String,
+
+    /// The PEM certificate and key of the shards, required with `shard_tls_port`.
+    #[clap(long, env)]
+    shard_tls_cert: Option<String>,
+    #[clap(long, env)]
+    shard_tls_key: Option<String>,
+
+    /// The PEM certificate of the authority of the shard and webserver certificates.
+    /// When set, the shards require the webserver to authenticate with a certificate
+    /// signed by this authority (mutual TLS).
+    #[clap(long, env)]
+    shard_tls_ca_cert: Option<String>,
+
+    /// The PEM certificate and key the webserver authenticates to the shards with,
+    /// required with `shard_tls_ca_cert`.
+    #[clap(long, env)]
+    router_tls_cert: Option<String>,
+    #[clap(long, env)]
+    router_tls_key: Option<String>,
+
+    /// The number of retries of the idempotent calls of the webserver to the shards
+    /// (health, info, cache clearing and batch filtering) failing with a transient error.
+    /// The backoff between the retries doubles from `shard_retry_initial_backoff_ms` up
+    /// to `shard_retry_max_backoff_ms`, with a random jitter.
+    #[clap(default_value = "3", long, env)]
+    shard_max_retries: u32,
+    #[clap(default_value = "50", long, env)]
+    shard_retry_initial_backoff_ms: u64,
+    #[clap(default_value = "1000", long, env)]
+    shard_retry_max_backoff_ms: u64,
+
+    /// The interval of the health probes of the shards by the webserver. The webserver
+    /// reconnects in the background to the shards failing a probe. 0 disables the probes.
+    #[clap(default_value = "10", long, env)]
+    shard_probe_interval_secs: u64,
+
+    /// The timeouts of the calls of the webserver to the shards. The control timeout applies
+    /// to each attempt of the health, info, cache clearing, sessions release and batch
+    /// filtering calls. Unset timeouts wait indefinitely.
+    #[clap(long, env)]
+    shard_prefill_timeout_ms: Option<u64>,
+    #[clap(long, env)]
+    shard_decode_timeout_ms: Option<u64>,
+    #[clap(long, env)]
+    shard_warmup_timeout_secs: Option<u64>,
+    #[clap(long, env)]
+    shard_control_timeout_ms: Option<u64>,
+
+    /// The compression of the gRPC messages sent by the webserver to the shards (`gzip`),
+    /// useful for the long prompts of remote shards. zstd is not supported.
+    #[clap(long, env)]
+    shard_request_compression: Option<String>,
+
+    /// The compression of the gRPC messages sent by the shards to the webserver (`gzip`).
+    #[clap(long, env)]
+    shard_response_compression: Option<String>,
+
+    /// Whether the webserver receives the generated tokens of the decode steps as soon as each
+    /// shard produces them, instead of once every shard finished the step.
+    #[clap(long, env)]
+    shard_stream_decode: bool,
+
+    /// The percentile of the latencies of the health and info calls of the webserver after
+    /// which they are also sent to another replica of the model (see
+    /// `replica-shard-uds-path`), the first answer being used. Unset disables the hedging.
+    #[clap(long, env)]
+    shard_hedging_percentile: Option<f64>,
+
+    /// The minimum delay before hedging a health or info call.
+    #[clap(default_value = "10", long, env)]
+    shard_hedging_min_delay_ms: u64,
+
     /// The address the master shard will listen on. (setting used by torch distributed)
     #[clap(default_value = "localhost", long, env)]
     master_addr: String,
@@ -272,8 +378,295 @@ struct Args {
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
 
+    /// Additional models served by the router, as `<model id>=<master shard uds path>`.
+    /// Their shards must be launched separately, for example by another launcher.
+    /// Requests select a model with their `model` field and default to `model-id`.
+    #[clap(long, env, value_delimiter = ',')]
+    model_backend: Vec<String>,
+
+    /// Master shard uds paths or `http(s)://` uris of independent replicas of `model-id`.
+    /// Their shards must be launched separately, for example by another launcher. The
+    /// requests are balanced between the shards of this launcher and the replicas by their
+    /// number of outstanding requests.
+    #[clap(long, env, value_delimiter = ',')]
+    replica_shard_uds_path: Vec<String>,
+
+    /// Maximum number of concurrent requests of a model, as `<model id>=<limit>`, overriding
+    /// `max-concurrent-requests` so that a slow model cannot take the whole capacity of the
+    /// router from the others.
+    #[clap(long, env, value_delimiter = ',')]
+    model_max_concurrent_requests: Vec<String>,
+
+    /// Fill-in-the-middle sentinel tokens of a model, as `[<model id>=]<prefix>,<suffix>,<middle>`,
+    /// used to assemble the prompts of the requests with a `suffix`. The StarCoder, CodeGemma
+    /// and DeepSeek-Coder tokens are detected in the vocabulary when unset.
+    /// Can be repeated for every model.
+    #[clap(long, env)]
+    fim_tokens: Vec<String>,
+
+    /// Origins allowed to call the API from a browser. Any origin is allowed when unset.
     #[clap(long, env)]
     cors_allow_origin: Vec<String>,
+
+    /// Comma separated HTTP methods allowed in CORS requests
+    #[clap(long, env)]
+    cors_allow_methods: Option<String>,
+
+    /// Comma separated headers allowed in CORS requests
+    #[clap(long, env)]
+    cors_allow_headers: Option<String>,
+
+    /// Allow CORS requests with credentials such as cookies. Requires `cors-allow-origin`.
+    #[clap(long, env)]
+    cors_allow_credentials: bool,
+
+    /// Path to a JSON file of API keys used to authenticate the inference routes.
+    /// Each entry has a `key`, a `tenant`, optional `max_concurrent_requests` and
+    /// `daily_token_quota` limits and an optional `scheduling_weight` (default 1): queued
+    /// requests of the tenants are batched in weighted round-robin. The requests of a key over
+    /// its `max_concurrent_requests` are rejected with a 429 and `X-Tenant-Concurrency-*`
+    /// headers. The file is reloaded when it changes.
+    #[clap(long, env)]
+    api_keys_file: Option<String>,
+
+    /// Maximum number of tokens generated per minute for each API key, or for each client IP
+    /// when `api_keys_file` is unset. Requests are rejected with a 429 once the limit is reached.
+    #[clap(long, env)]
+    rate_limit_tokens_per_minute: Option<u32>,
+
+    /// Path of an audit log where one JSON record is written for each finished request,
+    /// with its id, tenant, parameters, token counts and finish reason.
+    /// The audit log is disabled when unset.
+    #[clap(long, env)]
+    audit_log_path: Option<String>,
+
+    /// Size in megabytes after which the audit log is rotated
+    #[clap(default_value = "100", long, env)]
+    audit_log_max_file_size: u64,
+
+    /// Number of rotated audit log files kept
+    #[clap(default_value = "5", long, env)]
+    audit_log_max_files: usize,
+
+    /// How prompts and outputs are written to the audit log:
+    /// `none`, `hash` (SHA-256), `truncated` or `full`
+    #[clap(default_value = "none", long, env)]
+    audit_log_content: String,
+
+    /// Fields removed from the audit records:
+    /// `tenant`, `parameters`, `prompt`, `output` or `error`
+    #[clap(long, env, value_delimiter = ',')]
+    audit_log_redact: Vec<String>,
+
+    /// Maximum number of prompt tokens kept by the router to detect the prompt prefixes
+    /// shared between requests, such as long system prompts. The length of the shared prefix
+    /// is sent to the shards so that they can skip prefilling it again.
+    /// Prefix caching is disabled when unset, or when the model does not reuse the KV cache
+    /// of the prefixes (only the flash attention models do).
+    #[clap(long, env)]
+    prefix_cache_max_tokens: Option<usize>,
+
+    /// Maximum number of multi-turn sessions whose KV cache is pinned on the shards.
+    /// Requests with a `session_id` only prefill the tokens added since the previous turn
+    /// of their session. Sessions are disabled when unset, or when the model does not pin
+    /// the KV cache of the sessions (only the flash attention models do).
+    #[clap(long, env)]
+    max_sessions: Option<usize>,
+
+    /// Number of seconds after which an idle session is evicted and its KV cache released
+    #[clap(default_value = "600", long, env)]
+    session_ttl_secs: u64,
+
+    /// Number of seconds between the keep-alive comments sent on idle Server-Sent Events
+    /// streams, for example during long prefills. Lower it if a proxy closes idle connections.
+    #[clap(default_value = "15", long, env)]
+    sse_keep_alive_secs: u64,
+
+    /// Maximum number of queued requests for `/health/ready` to report the router as ready.
+    /// When the queue is longer, the probe fails so that traffic goes to other replicas.
+    #[clap(long, env)]
+    ready_max_queue_size: Option<usize>,
+
+    /// Number of seconds the webserver keeps serving the in-flight and queued requests after
+    /// receiving a termination signal, before closing the shard connections and exiting.
+    /// New requests are refused during that time.
+    #[clap(default_value = "60", long, env)]
+    drain_timeout_secs: u64,
+
+    /// JSON file of default generation parameters used when a request leaves them unset, for
+    /// example `{"temperature": 0.7, "top_p": 0.9, "max_new_tokens": 256, "stop": ["</s>"]}`.
+    /// The file is reloaded when it is modified or when the webserver receives SIGHUP.
+    #[clap(long, env)]
+    default_parameters_file: Option<String>,
+
+    /// Directory of prompt templates (`.j2` or `.jinja` files) requests can reference by name
+    /// with `template` and `variables` instead of sending the whole prompt. The templates are
+    /// reloaded when they are modified or when the webserver receives SIGHUP.
+    #[clap(long, env)]
+    prompt_templates_dir: Option<String>,
+
+    /// JSON file of content filter rules applied to the prompts before they are queued. Each
+    /// rule matches a regex `pattern` or any of the `keywords` and either rejects the request
+    /// or rewrites the matches, for example
+    /// `[{"name": "no-credentials", "pattern": "sk-[a-z0-9]{8,}", "action": "reject"}]`.
+    #[clap(long, env)]
+    content_filter_rules_file: Option<String>,
+
+    /// URL of an external content filter called with `{"inputs", "tenant"}` after the rules.
+    /// It answers `{"action": "allow"}`, `{"action": "reject", "policy", "reason"}` or
+    /// `{"action": "rewrite", "inputs"}`. Requests are rejected if it is unavailable.
+    #[clap(long, env)]
+    content_filter_url: Option<String>,
+
+    /// JSON file of rules, in the format of `content_filter_rules_file`, checked on the generated
+    /// text. A generation matching any rule is stopped: the rest of the response is replaced by
+    /// `moderation_message` with the `content_filter` finish reason.
+    #[clap(long, env)]
+    moderation_rules_file: Option<String>,
+
+    /// URL of an external moderation endpoint called with `{"text", "tenant"}` every
+    /// `moderation_interval_tokens` generated tokens and at the end of the generation. It
+    /// answers `{"flagged", "policy", "reason"}`. Generations continue if it is unavailable.
+    #[clap(long, env)]
+    moderation_url: Option<String>,
+
+    /// Text replacing the rest of a response stopped by the moderation.
+    #[clap(long, env)]
+    moderation_message: Option<String>,
+
+    /// Number of generated tokens between two calls of `moderation_url`.
+    #[clap(default_value = "16", long, env)]
+    moderation_interval_tokens: usize,
+
+    /// Mask the emails, phone numbers and credit card numbers of the `prompts`, the `outputs`
+    /// or `both` for regulated deployments. Prompts are redacted before the content filter,
+    /// outputs are streamed a word at a time.
+    #[clap(long, env)]
+    pii_redaction: Option<String>,
+
+    /// JSONL file the token usage of every tenant is appended to, for billing or budgeting.
+    /// Requires `api_keys_file`. The tenants can also query their usage on `/usage`.
+    #[clap(long, env)]
+    usage_log_path: Option<String>,
+
+    /// Number of seconds between two writes of the usage accumulated since the last write to
+    /// `usage_log_path`
+    #[clap(default_value = "60", long, env)]
+    usage_flush_interval_secs: u64,
+
+    /// Bearer API key of the `/admin` routes, which pause, drain and resume the intake of
+    /// requests, clear the KV cache of the shards and change `max_concurrent_requests` and
+    /// `max_waiting_tokens` at runtime. The routes are disabled when unset.
+    #[clap(long, env)]
+    admin_api_key: Option<String>,
+
+    /// Shrink the effective `max_batch_total_tokens` when a shard runs out of memory, down to
+    /// `max_total_tokens`, and grow it back slowly to the configured value once the shards
+    /// keep up. Useful when the static setting is too optimistic for long-context traffic.
+    #[clap(long, env)]
+    adaptive_batch_total_tokens: bool,
+
+    /// With `adaptive_batch_total_tokens`, also shrink the budget when a decoding step takes
+    /// longer than this number of milliseconds
+    #[clap(long, env)]
+    target_decode_latency_ms: Option<u64>,
+
+    /// Number of seconds during which the response of a non-streaming request with an
+    /// `Idempotency-Key` header is replayed to the retries with the same key. 0 disables the
+    /// deduplication.
+    #[clap(default_value = "60", long, env)]
+    idempotency_window_secs: u64,
+
+    /// Text replacing the middle of the prompts truncated with `"truncation_side": "middle"`.
+    /// Defaults to `"\n...\n"`
+    #[clap(long, env)]
+    truncation_marker: Option<String>,
+
+    /// What happens to the requests without `on_overflow` whose prompt and `max_new_tokens`
+    /// exceed the context of the model: `error` rejects them, `truncate` removes tokens from
+    /// their `truncation_side` and `truncate-middle` from the middle of their prompt.
+    #[clap(default_value = "error", long, env)]
+    default_on_overflow: String,
+
+    /// Let the requests with a higher `priority` preempt the running requests of a lower
+    /// priority when the batch is full. Preempted requests are queued again and resume their
+    /// generation from the text they generated, instead of making urgent requests wait for
+    /// long generations to finish. Requests with a grammar or a session are never preempted.
+    #[clap(long, env)]
+    preemption: bool,
+
+    /// Maximum number of milliseconds a request waits in the queue. Requests waiting longer are
+    /// rejected with a 429 and a `Retry-After` header instead of being generated after their
+    /// client gave up.
+    #[clap(long, env)]
+    max_queue_duration_ms: Option<u64>,
+
+    /// Maximum number of prompt tokens of a request prefilled at once while other requests are
+    /// generating. Longer prompts are prefilled in chunks interleaved with the decoding steps of
+    /// the running requests, so that they do not stall their token emission.
+    /// Ignored if the model does not support partial prefills (only the flash attention models
+    /// do).
+    #[clap(long, env)]
+    max_prefill_chunk_tokens: Option<u32>,
+
+    /// Budget of the tokens the requests of a batch can still generate, independent from the
+    /// prefill budget `max_batch_prefill_tokens`. When set, `max_batch_total_tokens` only has to
+    /// fit the prompts and the tokens generated so far instead of reserving `max_new_tokens` for
+    /// every request, which lets decode-heavy workloads batch more requests.
+    /// The budget is re-evaluated at every decoding step as requests generate their tokens.
+    #[clap(long, env)]
+    max_batch_decode_tokens: Option<u32>,
+
+    /// Order in which the queued requests are batched:
+    /// `fifo` (order of arrival), `priority` (higher priorities first, then order of arrival),
+    /// `shortest-job-first` (fewest prompt and new tokens first) or `fair-share`
+    /// (higher priorities first, then weighted round-robin across the tenants of the API keys)
+    #[clap(default_value = "fair-share", long, env)]
+    scheduling_policy: String,
+
+    /// Number of milliseconds a queued request waits to be batched as if it had the next
+    /// priority level, so that low priority requests eventually run under a sustained load of
+    /// higher priority requests. Requires the `priority` or `fair-share` scheduling policy.
+    #[clap(long, env)]
+    priority_aging_ms: Option<u64>,
+
+    /// Generate identical greedy requests in flight at the same time only once and send the
+    /// result to all of them. Useful when many clients send the same prompts at once.
+    #[clap(long, env)]
+    deduplicate_requests: bool,
+
+    /// Target number of milliseconds between two tokens of the running requests. When set, the
+    /// `waiting_served_ratio` is adapted to the observed inter-token latency: it rises while the
+    /// tokens are slower than the target, so that fewer new requests interrupt the running batch,
+    /// and falls while they are faster.
+    #[clap(long, env)]
+    target_inter_token_latency_ms: Option<u64>,
+
+    /// Path of a file journaling the requests accepted but not batched yet. When the router
+    /// restarts, the requests left in the journal are generated again and their outcome is
+    /// written to the logs and to the audit log, as their clients are gone.
+    #[clap(long, env)]
+    queue_journal_path: Option<String>,
+
+    /// Batch together the requests predicted to generate a similar number of tokens, to reduce
+    /// the padding and the stragglers of the batches. The predictions are learned from the
+    /// fraction of `max_new_tokens` the finished requests generated.
+    #[clap(long, env)]
+    length_aware_batching: bool,
+
+    /// Number of milliseconds after which a queued request is batched before the shorter
+    /// requests, so that long requests are not postponed forever under a sustained load of short
+    /// ones. Requires the `shortest-job-first` scheduling policy.
+    #[clap(long, env)]
+    max_starvation_ms: Option<u64>,
+
+    /// Request rejected when the concurrent requests limit is reached:
+    /// `reject-newest` (the new request), `reject-oldest` (the oldest queued request),
+    /// `reject-lowest-priority` (the queued request of the lowest priority, if lower than the new
+    /// one) or `early-drop` (new requests are rejected with a probability rising as the requests
+    /// in flight approach the limit)
+    #[clap(default_value = "reject-newest", long, env)]
+    shedding_policy: String,
     #[clap(long, env)]
     watermark_gamma: Option<f32>,
     #[clap(long, env)]
@@ -296,6 +689,17 @@ struct Args {
     env: bool,
 }
 
+/// TLS listener of a shard
+#[derive(Debug)]
+struct ShardTls {
+    port: u16,
+    hostname: String,
+    cert: String,
+    key: String,
+    /// Authority of the client certificates, the clients must present one when set
+    client_ca_cert: Option<String>,
+}
+
 #[derive(Debug)]
 enum ShardStatus {
     Ready,
@@ -323,6 +727,8 @@ fn shard_manager(
     watermark_delta: Option<f32>,
     cuda_memory_fraction: f32,
     otlp_endpoint: Option<String>,
+    shard_tls: Option<ShardTls>,
+    compression: Option<String>,
     status_sender: mpsc::Sender<ShardStatus>,
     shutdown: Arc<AtomicBool>,
     _shutdown_sender: mpsc::Sender<()>,
@@ -391,6 +797,28 @@ fn shard_manager(
         shard_args.push(otlp_endpoint);
     }
 
+    // Compression of the responses
+    if let Some(compression) = compression {
+        shard_args.push("--compression".to_string());
+        shard_args.push(compression);
+    }
+
+    // TLS listener
+    if let Some(shard_tls) = shard_tls {
+        shard_args.push("--tls-port".to_string());
+        shard_args.push(shard_tls.port.to_string());
+        shard_args.push("--tls-hostname".to_string());
+        shard_args.push(shard_tls.hostname);
+        shard_args.push("--tls-cert".to_string());
+        shard_args.push(shard_tls.cert);
+        shard_args.push("--tls-key".to_string());
+        shard_args.push(shard_tls.key);
+        if let Some(client_ca_cert) = shard_tls.client_ca_cert {
+            shard_args.push("--tls-client-ca".to_string());
+            shard_args.push(client_ca_cert);
+        }
+    }
+
     // Copy current process env
     let mut envs: Vec<(OsString, OsString)> = env::vars_os().collect();
 
@@ -804,6 +1232,14 @@ fn spawn_shards(
         let shutdown = shutdown.clone();
         let shutdown_sender = shutdown_sender.clone();
         let otlp_endpoint = args.otlp_endpoint.clone();
+        let shard_tls = args.shard_tls_port.map(|port| ShardTls {
+            port,
+            hostname: args.shard_tls_hostname.clone(),
+            cert: args.shard_tls_cert.clone().unwrap(),
+            key: args.shard_tls_key.clone().unwrap(),
+            client_ca_cert: args.shard_tls_ca_cert.clone(),
+        });
+        let compression = args.shard_response_compression.clone();
         let quantize = args.quantize;
         let dtype = args.dtype;
         let trust_remote_code = args.trust_remote_code;
@@ -834,6 +1270,8 @@ fn spawn_shards(
                 watermark_delta,
                 cuda_memory_fraction,
                 otlp_endpoint,
+                shard_tls,
+                compression,
                 status_sender,
                 shutdown,
                 shutdown_sender,
@@ -883,8 +1321,12 @@ fn spawn_webserver(
         args.max_concurrent_requests.to_string(),
         "--max-best-of".to_string(),
         args.max_best_of.to_string(),
+        "--max-client-batch-size".to_string(),
+        args.max_client_batch_size.to_string(),
         "--max-stop-sequences".to_string(),
         args.max_stop_sequences.to_string(),
+        "--max-top-n-tokens".to_string(),
+        args.max_top_n_tokens.to_string(),
         "--max-input-length".to_string(),
         args.max_input_length.to_string(),
         "--max-total-tokens".to_string(),
@@ -897,14 +1339,78 @@ fn spawn_webserver(
         args.max_waiting_tokens.to_string(),
         "--validation-workers".to_string(),
         args.validation_workers.to_string(),
+        "--max-tokenization-batch-size".to_string(),
+        args.max_tokenization_batch_size.to_string(),
         "--hostname".to_string(),
         args.hostname.to_string(),
         "--port".to_string(),
         args.port.to_string(),
         "--master-shard-uds-path".to_string(),
-        format!("{}-0", args.shard_uds_path)
+        match args.shard_tls_port {
+            Some(port) => format!("https://{}:{port}", args.shard_tls_hostname),
+            None => format!("{}-0", args.shard_uds_path),
+        },
     ];
 
+    // Retries of the idempotent calls to the shards
+    router_args.push("--shard-max-retries".to_string());
+    router_args.push(args.shard_max_retries.to_string());
+    router_args.push("--shard-retry-initial-backoff-ms".to_string());
+    router_args.push(args.shard_retry_initial_backoff_ms.to_string());
+    router_args.push("--shard-retry-max-backoff-ms".to_string());
+    router_args.push(args.shard_retry_max_backoff_ms.to_string());
+    router_args.push("--shard-probe-interval-secs".to_string());
+    router_args.push(args.shard_probe_interval_secs.to_string());
+    // Timeouts of the calls to the shards
+    let shard_timeouts = [
+        ("--shard-prefill-timeout-ms", args.shard_prefill_timeout_ms),
+        ("--shard-decode-timeout-ms", args.shard_decode_timeout_ms),
+        (
+            "--shard-warmup-timeout-secs",
+            args.shard_warmup_timeout_secs,
+        ),
+        ("--shard-control-timeout-ms", args.shard_control_timeout_ms),
+    ];
+    for (flag, timeout) in shard_timeouts {
+        if let Some(timeout) = timeout {
+            router_args.push(flag.to_string());
+            router_args.push(timeout.to_string());
+        }
+    }
+    if let Some(compression) = args.shard_request_compression {
+        router_args.push("--shard-request-compression".to_string());
+        router_args.push(compression);
+    }
+    if let Some(compression) = args.shard_response_compression {
+        router_args.push("--shard-response-compression".to_string());
+        router_args.push(compression);
+    }
+    if args.shard_stream_decode {
+        router_args.push("--shard-stream-decode".to_string());
+    }
+    if let Some(shard_hedging_percentile) = args.shard_hedging_percentile {
+        router_args.push("--shard-hedging-percentile".to_string());
+        router_args.push(shard_hedging_percentile.to_string());
+    }
+    router_args.push("--shard-hedging-min-delay-ms".to_string());
+    router_args.push(args.shard_hedging_min_delay_ms.to_string());
+
+    // TLS connection to the shards
+    if args.shard_tls_port.is_some() {
+        if let Some(shard_tls_ca_cert) = args.shard_tls_ca_cert {
+            router_args.push("--shard-tls-ca-cert".to_string());
+            router_args.push(shard_tls_ca_cert);
+        }
+        if let (Some(router_tls_cert), Some(router_tls_key)) =
+            (args.router_tls_cert, args.router_tls_key)
+        {
+            router_args.push("--shard-tls-cert".to_string());
+            router_args.push(router_tls_cert);
+            router_args.push("--shard-tls-key".to_string());
+            router_args.push(router_tls_key);
+        }
+    }
+
     router_args.push("--tokenizer-name".to_string());
     if let Some(base_model_id) = args.base_model_id {
         router_args.push(base_model_id.to_string());
@@ -912,6 +1418,25 @@ fn spawn_webserver(
         router_args.push(args.model_id);
     }
 
+    // Additional models
+    for replica_shard_uds_path in args.replica_shard_uds_path {
+        router_args.push("--replica-shard-uds-path".to_string());
+        router_args.push(replica_shard_uds_path);
+    }
+    for model_backend in args.model_backend {
+        router_args.push("--model-backend".to_string());
+        router_args.push(model_backend);
+    }
+    for model_max_concurrent_requests in args.model_max_concurrent_requests {
+        router_args.push("--model-max-concurrent-requests".to_string());
+        router_args.push(model_max_concurrent_requests);
+    }
+
+    for fim_tokens in args.fim_tokens {
+        router_args.push("--fim-tokens".to_string());
+        router_args.push(fim_tokens);
+    }
+
     // Model optional max batch total tokens
     if let Some(max_batch_total_tokens) = args.max_batch_total_tokens {
         router_args.push("--max-batch-total-tokens".to_string());
@@ -924,6 +1449,12 @@ fn spawn_webserver(
         router_args.push(revision.to_string())
     }
 
+    // Optional public gRPC API
+    if let Some(grpc_port) = args.grpc_port {
+        router_args.push("--grpc-port".to_string());
+        router_args.push(grpc_port.to_string());
+    }
+
     if args.json_output {
         router_args.push("--json-output".to_string());
     }
@@ -939,6 +1470,198 @@ fn spawn_webserver(
         router_args.push("--cors-allow-origin".to_string());
         router_args.push(origin);
     }
+    if let Some(cors_allow_methods) = args.cors_allow_methods {
+        router_args.push("--cors-allow-methods".to_string());
+        router_args.push(cors_allow_methods);
+    }
+    if let Some(cors_allow_headers) = args.cors_allow_headers {
+        router_args.push("--cors-allow-headers".to_string());
+        router_args.push(cors_allow_headers);
+    }
+    if args.cors_allow_credentials {
+        router_args.push("--cors-allow-credentials".to_string());
+    }
+
+    // API keys
+    if let Some(api_keys_file) = args.api_keys_file {
+        router_args.push("--api-keys-file".to_string());
+        router_args.push(api_keys_file);
+    }
+
+    // Rate limiting
+    if let Some(rate_limit_tokens_per_minute) = args.rate_limit_tokens_per_minute {
+        router_args.push("--rate-limit-tokens-per-minute".to_string());
+        router_args.push(rate_limit_tokens_per_minute.to_string());
+    }
+
+    // Audit log
+    if let Some(audit_log_path) = args.audit_log_path {
+        router_args.push("--audit-log-path".to_string());
+        router_args.push(audit_log_path);
+        router_args.push("--audit-log-max-file-size".to_string());
+        router_args.push(args.audit_log_max_file_size.to_string());
+        router_args.push("--audit-log-max-files".to_string());
+        router_args.push(args.audit_log_max_files.to_string());
+        router_args.push("--audit-log-content".to_string());
+        router_args.push(args.audit_log_content);
+        for field in args.audit_log_redact {
+            router_args.push("--audit-log-redact".to_string());
+            router_args.push(field);
+        }
+    }
+
+    // Prefix caching
+    if let Some(prefix_cache_max_tokens) = args.prefix_cache_max_tokens {
+        router_args.push("--prefix-cache-max-tokens".to_string());
+        router_args.push(prefix_cache_max_tokens.to_string());
+    }
+
+    // Sessions
+    if let Some(max_sessions) = args.max_sessions {
+        router_args.push("--max-sessions".to_string());
+        router_args.push(max_sessions.to_string());
+        router_args.push("--session-ttl-secs".to_string());
+        router_args.push(args.session_ttl_secs.to_string());
+    }
+
+    router_args.push("--sse-keep-alive-secs".to_string());
+    router_args.push(args.sse_keep_alive_secs.to_string());
+
+    router_args.push("--drain-timeout-secs".to_string());
+    router_args.push(args.drain_timeout_secs.to_string());
+
+    if let Some(default_parameters_file) = args.default_parameters_file {
+        router_args.push("--default-parameters-file".to_string());
+        router_args.push(default_parameters_file);
+    }
+
+    if let Some(prompt_templates_dir) = args.prompt_templates_dir {
+        router_args.push("--prompt-templates-dir".to_string());
+        router_args.push(prompt_templates_dir);
+    }
+
+    if let Some(content_filter_rules_file) = args.content_filter_rules_file {
+        router_args.push("--content-filter-rules-file".to_string());
+        router_args.push(content_filter_rules_file);
+    }
+
+    if let Some(content_filter_url) = args.content_filter_url {
+        router_args.push("--content-filter-url".to_string());
+        router_args.push(content_filter_url);
+    }
+
+    if let Some(moderation_rules_file) = args.moderation_rules_file {
+        router_args.push("--moderation-rules-file".to_string());
+        router_args.push(moderation_rules_file);
+    }
+
+    if let Some(moderation_url) = args.moderation_url {
+        router_args.push("--moderation-url".to_string());
+        router_args.push(moderation_url);
+    }
+
+    if let Some(moderation_message) = args.moderation_message {
+        router_args.push("--moderation-message".to_string());
+        router_args.push(moderation_message);
+    }
+
+    router_args.push("--moderation-interval-tokens".to_string());
+    router_args.push(args.moderation_interval_tokens.to_string());
+
+    if let Some(pii_redaction) = args.pii_redaction {
+        router_args.push("--pii-redaction".to_string());
+        router_args.push(pii_redaction);
+    }
+
+    if let Some(usage_log_path) = args.usage_log_path {
+        router_args.push("--usage-log-path".to_string());
+        router_args.push(usage_log_path);
+        router_args.push("--usage-flush-interval-secs".to_string());
+        router_args.push(args.usage_flush_interval_secs.to_string());
+    }
+
+    if let Some(admin_api_key) = args.admin_api_key {
+        router_args.push("--admin-api-key".to_string());
+        router_args.push(admin_api_key);
+    }
+
+    if args.adaptive_batch_total_tokens {
+        router_args.push("--adaptive-batch-total-tokens".to_string());
+    }
+
+    if let Some(target_decode_latency_ms) = args.target_decode_latency_ms {
+        router_args.push("--target-decode-latency-ms".to_string());
+        router_args.push(target_decode_latency_ms.to_string());
+    }
+
+    router_args.push("--idempotency-window-secs".to_string());
+    router_args.push(args.idempotency_window_secs.to_string());
+
+    if let Some(truncation_marker) = args.truncation_marker {
+        router_args.push("--truncation-marker".to_string());
+        router_args.push(truncation_marker);
+    }
+
+    router_args.push("--default-on-overflow".to_string());
+    router_args.push(args.default_on_overflow);
+
+    if args.preemption {
+        router_args.push("--preemption".to_string());
+    }
+
+    if let Some(max_queue_duration_ms) = args.max_queue_duration_ms {
+        router_args.push("--max-queue-duration-ms".to_string());
+        router_args.push(max_queue_duration_ms.to_string());
+    }
+
+    if let Some(max_prefill_chunk_tokens) = args.max_prefill_chunk_tokens {
+        router_args.push("--max-prefill-chunk-tokens".to_string());
+        router_args.push(max_prefill_chunk_tokens.to_string());
+    }
+
+    if let Some(max_batch_decode_tokens) = args.max_batch_decode_tokens {
+        router_args.push("--max-batch-decode-tokens".to_string());
+        router_args.push(max_batch_decode_tokens.to_string());
+    }
+
+    router_args.push("--scheduling-policy".to_string());
+    router_args.push(args.scheduling_policy);
+
+    if let Some(priority_aging_ms) = args.priority_aging_ms {
+        router_args.push("--priority-aging-ms".to_string());
+        router_args.push(priority_aging_ms.to_string());
+    }
+
+    if args.deduplicate_requests {
+        router_args.push("--deduplicate-requests".to_string());
+    }
+
+    if let Some(target_inter_token_latency_ms) = args.target_inter_token_latency_ms {
+        router_args.push("--target-inter-token-latency-ms".to_string());
+        router_args.push(target_inter_token_latency_ms.to_string());
+    }
+
+    if let Some(queue_journal_path) = args.queue_journal_path {
+        router_args.push("--queue-journal-path".to_string());
+        router_args.push(queue_journal_path);
+    }
+
+    if args.length_aware_batching {
+        router_args.push("--length-aware-batching".to_string());
+    }
+
+    if let Some(max_starvation_ms) = args.max_starvation_ms {
+        router_args.push("--max-starvation-ms".to_string());
+        router_args.push(max_starvation_ms.to_string());
+    }
+
+    router_args.push("--shedding-policy".to_string());
+    router_args.push(args.shedding_policy);
+
+    if let Some(ready_max_queue_size) = args.ready_max_queue_size {
+        router_args.push("--ready-max-queue-size".to_string());
+        router_args.push(ready_max_queue_size.to_string());
+    }
 
     // Ngrok
     if args.ngrok {
@@ -1067,6 +1790,27 @@ fn main() -> Result<(), LauncherError> {
             "`validation_workers` must be > 0".to_string(),
         ));
     }
+    if args.max_tokenization_batch_size == 0 {
+        return Err(LauncherError::ArgumentValidation(
+            "`max_tokenization_batch_size` must be > 0".to_string(),
+        ));
+    }
+    if args.shard_tls_port.is_some() {
+        if args.shard_tls_cert.is_none() || args.shard_tls_key.is_none() {
+            return Err(LauncherError::ArgumentValidation(
+                "`shard_tls_cert` and `shard_tls_key` must be set with `shard_tls_port`"
+                    .to_string(),
+            ));
+        }
+        if args.shard_tls_ca_cert.is_some()
+            && (args.router_tls_cert.is_none() || args.router_tls_key.is_none())
+        {
+            return Err(LauncherError::ArgumentValidation(
+                "`router_tls_cert` and `router_tls_key` must be set with `shard_tls_ca_cert`"
+                    .to_string(),
+            ));
+        }
+    }
     if args.trust_remote_code {
         tracing::warn!(
             "`trust_remote_code` is set. Trusting that model `{}` do not contain malicious code.",
@@ -1155,6 +1899,8 @@ fn main() -> Result<(), LauncherError> {
         return Ok(());
     }
 
+    // Leave time to the webserver to drain its requests before killing it
+    let webserver_terminate_timeout = Duration::from_secs(args.drain_timeout_secs + 30);
     let mut webserver =
         spawn_webserver(args, shutdown.clone(), &shutdown_receiver).map_err(|err| {
             shutdown_shards(shutdown.clone(), &shutdown_receiver);
@@ -1184,7 +1930,7 @@ fn main() -> Result<(), LauncherError> {
     }
 
     // Graceful termination
-    terminate("webserver", webserver, Duration::from_secs(90)).unwrap();
+    terminate("webserver", webserver, webserver_terminate_timeout).unwrap();
     shutdown_shards(shutdown, &shutdown_receiver);
 
     exit_code
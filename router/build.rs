@@ -1,4 +1,5 @@
 use std::error::Error;
+use std::fs;
 use vergen::EmitBuilder;
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -22,5 +23,20 @@ fn main() -> Result<(), Box<dyn Error>> {
         println!("cargo:rustc-env=DOCKER_LABEL={label}");
     }
 
+    // Compile the public gRPC API
+    println!("cargo:rerun-if-changed=../proto/router.proto");
+    fs::create_dir("src/grpc/pb").unwrap_or(());
+
+    let mut config = prost_build::Config::new();
+    config.protoc_arg("--experimental_allow_proto3_optional");
+
+    tonic_build::configure()
+        .build_client(false)
+        .build_server(true)
+        .out_dir("src/grpc/pb")
+        .include_file("mod.rs")
+        .compile_with_config(config, &["../proto/router.proto"], &["../proto"])
+        .unwrap_or_else(|e| panic!("protobuf compilation failed: {e}"));
+
     Ok(())
 }
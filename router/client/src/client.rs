@@ -1,47 +1,232 @@
+use crate::compression::record_compressed_size;
 /// Single shard Client
 use crate::pb::generate::v1::text_generation_service_client::TextGenerationServiceClient;
 use crate::pb::generate::v1::*;
 use crate::Result;
+use crate::{Compression, RetryPolicy, RpcMetrics, TlsConfig};
+use futures::stream::BoxStream;
 use grpc_metadata::InjectTelemetryContext;
 use std::cmp::min;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tonic::transport::{Channel, Uri};
+use tonic::Status;
 use tracing::instrument;
 
+/// Address of a shard, kept to reconnect to it
+#[derive(Debug, Clone)]
+enum Address {
+    Uri(Uri),
+    Tcp(Uri, Option<TlsConfig>),
+    Uds(String),
+}
+
+impl Address {
+    async fn connect(&self) -> Result<Channel> {
+        let channel = match self {
+            Address::Uri(uri) => Channel::builder(uri.clone()).connect().await?,
+            Address::Tcp(uri, tls) => {
+                let mut endpoint = Channel::builder(uri.clone());
+                if let Some(tls) = tls.clone() {
+                    endpoint = endpoint.tls_config(tls.client_config())?;
+                }
+                endpoint.connect().await?
+            }
+            Address::Uds(path) => {
+                let path = path.clone();
+                Channel::from_shared("http://[::]:50051".to_string())
+                    .unwrap()
+                    .connect_with_connector(tower::service_fn(move |_: Uri| {
+                        tokio::net::UnixStream::connect(path.clone())
+                    }))
+                    .await?
+            }
+        };
+        Ok(channel)
+    }
+}
+
+/// Timeouts of the calls to a shard, the calls without a timeout wait indefinitely
+#[derive(Debug, Clone, Copy, Default)]
+struct Timeouts {
+    prefill: Option<Duration>,
+    decode: Option<Duration>,
+    warmup: Option<Duration>,
+    control: Option<Duration>,
+}
+
+/// Wait for the answer of `call` for at most `timeout`
+async fn deadline<T>(
+    timeout: Option<Duration>,
+    call: impl Future<Output = std::result::Result<T, Status>>,
+) -> std::result::Result<T, Status> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, call)
+            .await
+            .unwrap_or_else(|_| {
+                Err(Status::deadline_exceeded(format!(
+                    "no answer after {timeout:?}"
+                )))
+            }),
+        None => call.await,
+    }
+}
+
 /// Text Generation Inference gRPC client
+///
+/// The clones of a client share its connection, which is replaced for all of them on `reconnect`
 #[derive(Debug, Clone)]
 pub struct Client {
-    stub: TextGenerationServiceClient<Channel>,
+    stub: Arc<RwLock<TextGenerationServiceClient<Channel>>>,
+    address: Address,
+    retry_policy: RetryPolicy,
+    timeouts: Timeouts,
+    /// Compression of the messages sent to the shard
+    request_compression: Option<Compression>,
+    /// Compression accepted for the messages sent by the shard
+    response_compression: Option<Compression>,
+    /// Whether the generations of the decode steps are streamed
+    decode_streaming: bool,
+    /// Metrics of the calls, shared with the clones of the client
+    metrics: RpcMetrics,
 }
 
 impl Client {
-    /// Returns a client connected to the given url
-    pub async fn connect(uri: Uri) -> Result<Self> {
-        let channel = Channel::builder(uri).connect().await?;
+    async fn connect_address(address: Address) -> Result<Self> {
+        let channel = address.connect().await?;
 
         Ok(Self {
-            stub: TextGenerationServiceClient::new(channel),
+            stub: Arc::new(RwLock::new(TextGenerationServiceClient::new(channel))),
+            address,
+            retry_policy: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            request_compression: None,
+            response_compression: None,
+            decode_streaming: false,
+            metrics: RpcMetrics::new(),
         })
     }
 
+    /// Returns a client connected to the given url
+    pub async fn connect(uri: Uri) -> Result<Self> {
+        Self::connect_address(Address::Uri(uri)).await
+    }
+
+    /// Returns a client connected to the given uri over TCP, with TLS if `tls` is set
+    pub async fn connect_tcp(uri: Uri, tls: Option<TlsConfig>) -> Result<Self> {
+        Self::connect_address(Address::Tcp(uri, tls)).await
+    }
+
     /// Returns a client connected to the given unix socket
     pub async fn connect_uds(path: String) -> Result<Self> {
-        let channel = Channel::from_shared("http://[::]:50051".to_string())
-            .unwrap()
-            .connect_with_connector(tower::service_fn(move |_: Uri| {
-                tokio::net::UnixStream::connect(path.clone())
-            }))
-            .await?;
+        Self::connect_address(Address::Uds(path)).await
+    }
 
-        Ok(Self {
-            stub: TextGenerationServiceClient::new(channel),
-        })
+    /// Replace the connection of the client and of its clones by a new connection to the shard
+    pub async fn reconnect(&self) -> Result<()> {
+        let channel = self.address.connect().await?;
+        *self.stub.write().unwrap() = TextGenerationServiceClient::new(channel);
+        Ok(())
+    }
+
+    /// Stub of the current connection
+    fn stub(&self) -> TextGenerationServiceClient<Channel> {
+        let mut stub = self.stub.read().unwrap().clone();
+        if let Some(compression) = self.request_compression {
+            stub = stub.send_compressed(compression.encoding());
+        }
+        if let Some(compression) = self.response_compression {
+            stub = stub.accept_compressed(compression.encoding());
+        }
+        stub
+    }
+
+    /// Set the retry policy of the idempotent methods
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// Compress the messages sent to the shard with `compression`
+    pub fn with_request_compression(mut self, compression: Compression) -> Self {
+        self.request_compression = Some(compression);
+        self
+    }
+
+    /// Accept the messages sent by the shard compressed with `compression`, the shard must also
+    /// be configured to compress them
+    pub fn with_response_compression(mut self, compression: Compression) -> Self {
+        self.response_compression = Some(compression);
+        self
+    }
+
+    /// Record the metrics of the calls in the given handle, shared with other clients
+    pub fn with_metrics(mut self, metrics: RpcMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Handle on the metrics of the calls of the client
+    pub fn metrics(&self) -> &RpcMetrics {
+        &self.metrics
+    }
+
+    /// Stream the generations of the decode steps with `decode_stream`
+    pub fn with_decode_streaming(mut self) -> Self {
+        self.decode_streaming = true;
+        self
+    }
+
+    pub(crate) fn streams_decode(&self) -> bool {
+        self.decode_streaming
+    }
+
+    pub(crate) fn decode_timeout(&self) -> Option<Duration> {
+        self.timeouts.decode
+    }
+
+    /// Set the timeout of the `prefill` and `embed` calls
+    pub fn with_prefill_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.prefill = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of the `decode` calls
+    pub fn with_decode_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.decode = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of the `warmup` calls
+    pub fn with_warmup_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.warmup = Some(timeout);
+        self
+    }
+
+    /// Set the timeout of the control calls: service discovery, health, info, cache clearing,
+    /// sessions release and batch filtering
+    ///
+    /// The timeout applies to each attempt of the retried calls
+    pub fn with_control_timeout(mut self, timeout: Duration) -> Self {
+        self.timeouts.control = Some(timeout);
+        self
     }
 
     /// Returns a list of uris or unix sockets of all shards
     #[instrument(skip(self))]
     pub async fn service_discovery(&mut self) -> Result<Vec<String>> {
-        let request = tonic::Request::new(ServiceDiscoveryRequest {}).inject_context();
-        let response = self.stub.service_discovery(request).await?;
+        let mut stub = self.stub();
+        let response = self
+            .metrics
+            .observe("service_discovery", ServiceDiscoveryRequest {}, |request| {
+                deadline(
+                    self.timeouts.control,
+                    stub.service_discovery(request.inject_context()),
+                )
+            })
+            .await?;
         let urls = response
             .into_inner()
             .urls
@@ -58,24 +243,82 @@ impl Client {
     /// Get model info
     #[instrument(skip(self))]
     pub async fn info(&mut self) -> Result<InfoResponse> {
-        let request = tonic::Request::new(InfoRequest {}).inject_context();
-        let response = self.stub.info(request).await?.into_inner();
+        let timeout = self.timeouts.control;
+        let response = self
+            .retry_policy
+            .call("info", || {
+                let mut stub = self.stub();
+                let metrics = self.metrics.clone();
+                async move {
+                    metrics
+                        .observe("info", InfoRequest {}, |request| {
+                            deadline(timeout, stub.info(request.inject_context()))
+                        })
+                        .await
+                }
+            })
+            .await?
+            .into_inner();
         Ok(response)
     }
 
     /// Get model health
     #[instrument(skip(self))]
     pub async fn health(&mut self) -> Result<HealthResponse> {
-        let request = tonic::Request::new(HealthRequest {}).inject_context();
-        let response = self.stub.health(request).await?.into_inner();
+        let timeout = self.timeouts.control;
+        let response = self
+            .retry_policy
+            .call("health", || {
+                let mut stub = self.stub();
+                let metrics = self.metrics.clone();
+                async move {
+                    metrics
+                        .observe("health", HealthRequest {}, |request| {
+                            deadline(timeout, stub.health(request.inject_context()))
+                        })
+                        .await
+                }
+            })
+            .await?
+            .into_inner();
         Ok(response)
     }
 
     /// Clear the past generations cache
     #[instrument(skip(self))]
     pub async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
-        let request = tonic::Request::new(ClearCacheRequest { id: batch_id }).inject_context();
-        self.stub.clear_cache(request).await?;
+        let timeout = self.timeouts.control;
+        self.retry_policy
+            .call("clear_cache", || {
+                let mut stub = self.stub();
+                let metrics = self.metrics.clone();
+                async move {
+                    metrics
+                        .observe(
+                            "clear_cache",
+                            ClearCacheRequest { id: batch_id },
+                            |request| deadline(timeout, stub.clear_cache(request.inject_context())),
+                        )
+                        .await
+                }
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Release the pinned KV cache of sessions
+    #[instrument(skip(self))]
+    pub async fn release_sessions(&mut self, session_ids: Vec<String>) -> Result<()> {
+        let request = ReleaseSessionsRequest { session_ids };
+        let mut stub = self.stub();
+        self.metrics
+            .observe("release_sessions", request, |request| {
+                deadline(
+                    self.timeouts.control,
+                    stub.release_sessions(request.inject_context()),
+                )
+            })
+            .await?;
         Ok(())
     }
 
@@ -86,12 +329,26 @@ impl Client {
         batch_id: u64,
         request_ids: Vec<u64>,
     ) -> Result<Option<CachedBatch>> {
-        let request = tonic::Request::new(FilterBatchRequest {
-            batch_id,
-            request_ids,
-        })
-        .inject_context();
-        let filtered_batch = self.stub.filter_batch(request).await?.into_inner();
+        let timeout = self.timeouts.control;
+        let filtered_batch = self
+            .retry_policy
+            .call("filter_batch", || {
+                let mut stub = self.stub();
+                let metrics = self.metrics.clone();
+                let request = FilterBatchRequest {
+                    batch_id,
+                    request_ids: request_ids.clone(),
+                };
+                async move {
+                    metrics
+                        .observe("filter_batch", request, |request| {
+                            deadline(timeout, stub.filter_batch(request.inject_context()))
+                        })
+                        .await
+                }
+            })
+            .await?
+            .into_inner();
         Ok(filtered_batch.batch)
     }
 
@@ -126,13 +383,34 @@ impl Client {
                     repetition_penalty: 1.2,
                     no_repeat_ngram_size: 0,
                     watermark: true,
+                    logit_bias: HashMap::new(),
+                    grammar: String::new(),
+                    grammar_type: GrammarType::None as i32,
+                    min_p: 0.05,
+                    frequency_penalty: 0.1,
+                    presence_penalty: 0.1,
+                    bad_words_ids: vec![],
+                    epsilon_cutoff: 3e-4,
+                    eta_cutoff: 0.0,
+                    mirostat: 2,
+                    mirostat_tau: 5.0,
+                    mirostat_eta: 0.1,
+                    token_healing_prefix: String::new(),
                 }),
                 stopping_parameters: Some(StoppingCriteriaParameters {
                     max_new_tokens: 2,
                     stop_sequences: vec![],
                     ignore_eos_token: false,
+                    stop_token_ids: vec![],
                 }),
                 prefill_logprobs: true,
+                top_n_tokens: 20,
+                prefix_len: 0,
+                session_id: None,
+                prefill_offset: 0,
+                prefill_chunk_len: None,
+                input_chunks: vec![],
+                add_special_tokens: true,
             });
             n_tokens += max_input_length;
         }
@@ -144,8 +422,15 @@ impl Client {
             max_tokens: 0,
         };
 
-        let request = tonic::Request::new(WarmupRequest { batch: Some(batch) }).inject_context();
-        let response = self.stub.warmup(request).await?.into_inner();
+        let request = WarmupRequest { batch: Some(batch) };
+        let mut stub = self.stub();
+        let response = self
+            .metrics
+            .observe("warmup", request, |request| {
+                deadline(self.timeouts.warmup, stub.warmup(request.inject_context()))
+            })
+            .await?
+            .into_inner();
         Ok(response.max_supported_total_tokens)
     }
 
@@ -158,8 +443,19 @@ impl Client {
         &mut self,
         batch: Batch,
     ) -> Result<(Vec<Generation>, Option<CachedBatch>)> {
-        let request = tonic::Request::new(PrefillRequest { batch: Some(batch) }).inject_context();
-        let response = self.stub.prefill(request).await?.into_inner();
+        let request = PrefillRequest { batch: Some(batch) };
+        record_compressed_size("prefill", &request, self.request_compression);
+        let mut stub = self.stub();
+        let response = self
+            .metrics
+            .observe("prefill", request, |request| {
+                deadline(
+                    self.timeouts.prefill,
+                    stub.prefill(request.inject_context()),
+                )
+            })
+            .await?
+            .into_inner();
         Ok((response.generations, response.batch))
     }
 
@@ -172,8 +468,52 @@ impl Client {
         &mut self,
         batches: Vec<CachedBatch>,
     ) -> Result<(Vec<Generation>, Option<CachedBatch>)> {
-        let request = tonic::Request::new(DecodeRequest { batches }).inject_context();
-        let response = self.stub.decode(request).await?.into_inner();
+        let mut stub = self.stub();
+        let response = self
+            .metrics
+            .observe("decode", DecodeRequest { batches }, |request| {
+                deadline(self.timeouts.decode, stub.decode(request.inject_context()))
+            })
+            .await?
+            .into_inner();
         Ok((response.generations, response.batch))
     }
+
+    /// Generate one token for each request in the given cached batches, streaming the generations
+    ///
+    /// Returns the stream of the generations, whose last message carries the next cached batch.
+    /// The decode timeout only applies to the start of the stream.
+    #[instrument(skip_all, fields(size = batches.iter().map(|batch|{batch.size}).sum::<u32>()))]
+    pub async fn decode_stream(
+        &mut self,
+        batches: Vec<CachedBatch>,
+    ) -> Result<BoxStream<'static, std::result::Result<DecodeStreamResponse, Status>>> {
+        let mut stub = self.stub();
+        let responses = self
+            .metrics
+            .observe_stream("decode_stream", DecodeRequest { batches }, |request| {
+                deadline(
+                    self.timeouts.decode,
+                    stub.decode_stream(request.inject_context()),
+                )
+            })
+            .await?;
+        Ok(responses)
+    }
+
+    /// Compute the pooled embeddings of the given inputs
+    ///
+    /// Returns one embedding per input
+    #[instrument(skip_all, fields(size = inputs.len()))]
+    pub async fn embed(&mut self, inputs: Vec<String>, truncate: u32) -> Result<Vec<Embedding>> {
+        let mut stub = self.stub();
+        let response = self
+            .metrics
+            .observe("embed", EmbedRequest { inputs, truncate }, |request| {
+                deadline(self.timeouts.prefill, stub.embed(request.inject_context()))
+            })
+            .await?
+            .into_inner();
+        Ok(response.embeddings)
+    }
 }
@@ -0,0 +1,80 @@
+/// Compression of the gRPC messages exchanged with the shards
+use flate2::write::GzEncoder;
+use prost::Message;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tonic::codec::CompressionEncoding;
+
+/// Compression of the messages of a direction
+///
+/// zstd is not supported: tonic only implements it from 0.10, the client is built with tonic 0.9.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+}
+
+impl Compression {
+    pub(crate) fn encoding(&self) -> CompressionEncoding {
+        match self {
+            Compression::Gzip => CompressionEncoding::Gzip,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            _ => Err(format!(
+                "unsupported compression `{s}`, expected `gzip` (zstd is not supported)"
+            )),
+        }
+    }
+}
+
+/// One message out of `COMPRESSED_SIZE_SAMPLING` has its compressed size recorded
+const COMPRESSED_SIZE_SAMPLING: usize = 64;
+
+/// Number of messages whose compressed size could have been recorded
+static COMPRESSED_SIZE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Record the size of a sample of the `message`s sent to the shards by `method` after their
+/// `compression`, the size before compression being recorded with the metrics of every call
+///
+/// tonic does not expose the size of the encoded messages so the compressed size is measured by
+/// compressing the message again. It is only measured for one message out of
+/// `COMPRESSED_SIZE_SAMPLING` of the large messages of the prefills.
+pub(crate) fn record_compressed_size(
+    method: &'static str,
+    message: &impl Message,
+    compression: Option<Compression>,
+) {
+    let Some(compression) = compression else {
+        return;
+    };
+    if COMPRESSED_SIZE_CALLS.fetch_add(1, Ordering::Relaxed) % COMPRESSED_SIZE_SAMPLING != 0 {
+        return;
+    }
+    let encoded = message.encode_to_vec();
+    let compressed = match compression {
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(&encoded)
+                .and_then(|_| encoder.finish())
+                .map(|compressed| compressed.len())
+        }
+    };
+    if let Ok(compressed) = compressed {
+        metrics::histogram!("tgi_shard_request_bytes", compressed as f64, "method" => method, "encoding" => compression.name());
+    }
+}
@@ -0,0 +1,49 @@
+/// Hedging of the control calls between the replicas of a model
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Number of latencies the hedging delay is computed from
+const LATENCY_WINDOW: usize = 128;
+
+/// Minimum number of latencies before the delay is computed from them
+const MIN_SAMPLES: usize = 16;
+
+/// Hedging policy of the control calls answered identically by every replica: `health` and
+/// `info`
+///
+/// A call not answered after the `percentile` of the latencies of the previous calls is also sent
+/// to another replica. The first successful answer is returned and the other call is cancelled.
+#[derive(Clone, Copy, Debug)]
+pub struct HedgingPolicy {
+    /// Percentile of the latencies, between 0 and 100
+    pub percentile: f64,
+    /// Minimum delay, also used until enough latencies are recorded
+    pub min_delay: Duration,
+}
+
+/// Latencies of the last calls of a method
+#[derive(Debug, Default)]
+pub(crate) struct LatencyWindow {
+    latencies: VecDeque<Duration>,
+}
+
+impl LatencyWindow {
+    pub(crate) fn record(&mut self, latency: Duration) {
+        if self.latencies.len() == LATENCY_WINDOW {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+    }
+
+    /// Delay before hedging a call of the method
+    pub(crate) fn delay(&self, policy: &HedgingPolicy) -> Duration {
+        if self.latencies.len() < MIN_SAMPLES {
+            return policy.min_delay;
+        }
+        let mut latencies: Vec<Duration> = self.latencies.iter().copied().collect();
+        latencies.sort_unstable();
+        let index = (policy.percentile.clamp(0.0, 100.0) / 100.0 * (latencies.len() - 1) as f64)
+            .round() as usize;
+        latencies[index].max(policy.min_delay)
+    }
+}
@@ -1,22 +1,60 @@
 //! Text Generation gRPC client library
 
 mod client;
+mod compression;
+mod hedging;
 #[allow(clippy::derive_partial_eq_without_eq)]
 mod pb;
+mod retry;
+mod rpc_metrics;
 mod sharded_client;
 
 pub use client::Client;
+pub use compression::Compression;
+pub use hedging::HedgingPolicy;
+pub use pb::generate::v1::input_chunk::Chunk;
 pub use pb::generate::v1::HealthResponse;
 pub use pb::generate::v1::InfoResponse as ShardInfo;
 pub use pb::generate::v1::{
-    Batch, CachedBatch, FinishReason, GeneratedText, Generation, NextTokenChooserParameters,
-    PrefillTokens, Request, StoppingCriteriaParameters,
+    Batch, CachedBatch, DecodeStreamResponse, Embedding, FinishReason, GeneratedText, Generation,
+    GrammarType, Image, InputChunk, NextTokenChooserParameters, PrefillTokens, Request,
+    StoppingCriteriaParameters, TokenIds,
 };
-pub use sharded_client::ShardedClient;
+pub use retry::RetryPolicy;
+pub use rpc_metrics::{MethodMetrics, RpcMetrics};
+pub use sharded_client::{ConnectionState, ShardedClient};
 use thiserror::Error;
-use tonic::transport;
+use tonic::transport::{self, Certificate, ClientTlsConfig, Identity};
 use tonic::Status;
 
+/// TLS configuration of the TCP connections to the shards
+#[derive(Clone, Debug, Default)]
+pub struct TlsConfig {
+    /// PEM encoded certificate of the authority of the shard certificates, the system roots are
+    /// trusted if unset
+    pub ca_certificate: Option<Vec<u8>>,
+    /// PEM encoded certificate and key of the client, for shards requiring mutual TLS
+    pub identity: Option<(Vec<u8>, Vec<u8>)>,
+    /// Name checked against the shard certificates instead of the host of the uri
+    pub domain_name: Option<String>,
+}
+
+impl TlsConfig {
+    fn client_config(self) -> ClientTlsConfig {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca_certificate) = self.ca_certificate {
+            config = config.ca_certificate(Certificate::from_pem(ca_certificate));
+        }
+        if let Some((certificate, key)) = self.identity {
+            config = config.identity(Identity::from_pem(certificate, key));
+        }
+        if let Some(domain_name) = self.domain_name {
+            config = config.domain_name(domain_name);
+        }
+        config
+    }
+}
+
 #[derive(Error, Debug, Clone)]
 pub enum ClientError {
     #[error("Could not connect to Text Generation server: {0}")]
@@ -0,0 +1,79 @@
+/// Retry of the idempotent gRPC methods
+use rand::Rng;
+use std::future::Future;
+use std::time::Duration;
+use tonic::{Code, Status};
+
+/// Retry policy of the idempotent gRPC methods: `health`, `info`, `clear_cache` and
+/// `filter_batch`
+///
+/// Calls failing with a transient error are retried up to `max_retries` times. The backoff doubles
+/// after every attempt, from `initial_backoff` up to `max_backoff`, and a random jitter of up to
+/// half the backoff is removed so that the shards are not retried in lockstep.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy without retries
+    pub fn disabled() -> Self {
+        Self {
+            max_retries: 0,
+            ..Self::default()
+        }
+    }
+
+    /// Backoff before the retry following the `attempt`th failed attempt, starting at 0
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        let jitter = rand::thread_rng().gen_range(0.0..=0.5);
+        backoff.mul_f64(1.0 - jitter)
+    }
+
+    /// Call `method` until it succeeds, fails with a non transient error or runs out of retries
+    pub(crate) async fn call<T, F, Fut>(&self, method: &str, mut call: F) -> Result<T, Status>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Status>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match call().await {
+                Err(status) if attempt < self.max_retries && is_transient(&status) => {
+                    let backoff = self.backoff(attempt);
+                    tracing::warn!(
+                        "`{method}` failed: {}, retrying in {backoff:?}",
+                        status.message()
+                    );
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether a call failing with `status` can succeed when retried
+fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable | Code::DeadlineExceeded | Code::ResourceExhausted | Code::Aborted
+    )
+}
@@ -0,0 +1,165 @@
+/// Metrics of the gRPC calls to the shards
+use futures::stream::{self, BoxStream, StreamExt};
+use prost::Message;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tonic::{Request, Response, Status, Streaming};
+
+/// Handle on the metrics of the calls of the clients sharing it
+///
+/// Every call is also recorded in the `metrics` registry, the handle serves the users without a
+/// metrics exporter. The clones of a handle share its metrics.
+#[derive(Clone, Debug, Default)]
+pub struct RpcMetrics {
+    methods: Arc<Mutex<BTreeMap<&'static str, MethodMetrics>>>,
+}
+
+/// Metrics of the calls of a method
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MethodMetrics {
+    /// Number of calls, each attempt of a retried call being counted
+    pub calls: u64,
+    /// Number of failed calls
+    pub errors: u64,
+    /// Total latency of the calls
+    pub total_latency: Duration,
+    /// Latency of the slowest call
+    pub max_latency: Duration,
+    /// Total size of the requests, before compression
+    pub request_bytes: u64,
+    /// Total size of the successful responses, before compression
+    pub response_bytes: u64,
+}
+
+impl MethodMetrics {
+    /// Average latency of the calls
+    pub fn mean_latency(&self) -> Option<Duration> {
+        let calls = u32::try_from(self.calls).ok().filter(|calls| *calls > 0)?;
+        Some(self.total_latency / calls)
+    }
+}
+
+impl RpcMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Metrics of the methods called since the creation or the last reset of the handle, by
+    /// method name
+    pub fn snapshot(&self) -> BTreeMap<&'static str, MethodMetrics> {
+        self.methods.lock().unwrap().clone()
+    }
+
+    /// Forget the metrics of the previous calls
+    pub fn reset(&self) {
+        self.methods.lock().unwrap().clear();
+    }
+
+    /// Record a call of `method` sending `request_bytes` and answered after `latency` by a
+    /// `response` of the given size or an error
+    pub(crate) fn record(
+        &self,
+        method: &'static str,
+        request_bytes: usize,
+        latency: Duration,
+        response: Result<usize, &Status>,
+    ) {
+        metrics::histogram!("tgi_shard_request_duration", latency.as_secs_f64(), "method" => method);
+        metrics::histogram!("tgi_shard_request_bytes", request_bytes as f64, "method" => method, "encoding" => "identity");
+        match response {
+            Ok(response_bytes) => {
+                metrics::histogram!("tgi_shard_response_bytes", response_bytes as f64, "method" => method);
+            }
+            Err(status) => {
+                metrics::increment_counter!("tgi_shard_request_failure", "method" => method, "code" => status.code().description());
+            }
+        }
+
+        let mut methods = self.methods.lock().unwrap();
+        let method_metrics = methods.entry(method).or_default();
+        method_metrics.calls += 1;
+        method_metrics.total_latency += latency;
+        method_metrics.max_latency = method_metrics.max_latency.max(latency);
+        method_metrics.request_bytes += request_bytes as u64;
+        match response {
+            Ok(response_bytes) => method_metrics.response_bytes += response_bytes as u64,
+            Err(_) => method_metrics.errors += 1,
+        }
+    }
+
+    /// Make the unary `call` of `method` with the given `request`, recording its metrics
+    pub(crate) async fn observe<M, T, F, Fut>(
+        &self,
+        method: &'static str,
+        request: M,
+        call: F,
+    ) -> Result<Response<T>, Status>
+    where
+        M: Message,
+        T: Message,
+        F: FnOnce(Request<M>) -> Fut,
+        Fut: Future<Output = Result<Response<T>, Status>>,
+    {
+        let request_bytes = request.encoded_len();
+        let start = Instant::now();
+        let result = call(Request::new(request)).await;
+        let response = match &result {
+            Ok(response) => Ok(response.get_ref().encoded_len()),
+            Err(status) => Err(status),
+        };
+        self.record(method, request_bytes, start.elapsed(), response);
+        result
+    }
+
+    /// Make the server-streaming `call` of `method` with the given `request`, recording its
+    /// metrics at the end of the stream
+    ///
+    /// The latency is the one of the whole stream and the response size the sum of its messages.
+    /// A stream dropped before its end is not recorded.
+    pub(crate) async fn observe_stream<M, T, F, Fut>(
+        &self,
+        method: &'static str,
+        request: M,
+        call: F,
+    ) -> Result<BoxStream<'static, Result<T, Status>>, Status>
+    where
+        M: Message,
+        T: Message + 'static,
+        F: FnOnce(Request<M>) -> Fut,
+        Fut: Future<Output = Result<Response<Streaming<T>>, Status>>,
+    {
+        let request_bytes = request.encoded_len();
+        let start = Instant::now();
+        let responses = match call(Request::new(request)).await {
+            Ok(response) => response.into_inner(),
+            Err(status) => {
+                self.record(method, request_bytes, start.elapsed(), Err(&status));
+                return Err(status);
+            }
+        };
+        let metrics = self.clone();
+        let responses = stream::unfold(Some((responses, 0)), move |state| {
+            let metrics = metrics.clone();
+            async move {
+                let (mut responses, response_bytes) = state?;
+                match responses.next().await {
+                    Some(Ok(response)) => {
+                        let response_bytes = response_bytes + response.encoded_len();
+                        Some((Ok(response), Some((responses, response_bytes))))
+                    }
+                    Some(Err(status)) => {
+                        metrics.record(method, request_bytes, start.elapsed(), Err(&status));
+                        Some((Err(status), None))
+                    }
+                    None => {
+                        metrics.record(method, request_bytes, start.elapsed(), Ok(response_bytes));
+                        None
+                    }
+                }
+            }
+        });
+        Ok(responses.boxed())
+    }
+}
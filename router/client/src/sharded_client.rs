@@ -1,19 +1,220 @@
+use crate::hedging::{HedgingPolicy, LatencyWindow};
 /// Multi shard Client
-use crate::{Batch, CachedBatch, Client, Generation, HealthResponse, ShardInfo};
-use crate::{ClientError, Result};
-use futures::future::join_all;
+use crate::{Batch, CachedBatch, Client, Embedding, Generation, HealthResponse, ShardInfo};
+use crate::{ClientError, Compression, Result, RetryPolicy, RpcMetrics, TlsConfig};
+use futures::future::{join_all, select_ok};
+use futures::stream::{select_all, StreamExt};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 use tonic::transport::Uri;
 use tracing::instrument;
 
+/// State of the connection to a shard reported by `ShardedClient::probe_connections`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The shard answers the health probes
+    Connected,
+    /// The shard failed a health probe, it is reconnected to in the background
+    Disconnected,
+}
+
+/// Independent replicas of a model, each one with its own tensor parallel shards
+#[derive(Debug)]
+struct Replicas {
+    clients: Vec<Vec<Client>>,
+    /// Number of requests outstanding on each replica
+    outstanding: Vec<AtomicUsize>,
+    hedging: Option<HedgingPolicy>,
+    /// Latencies of the hedged methods
+    latencies: Mutex<HashMap<&'static str, LatencyWindow>>,
+}
+
+impl Replicas {
+    fn new(clients: Vec<Vec<Client>>, hedging: Option<HedgingPolicy>) -> Self {
+        let outstanding = clients.iter().map(|_| AtomicUsize::new(0)).collect();
+        Self {
+            clients,
+            outstanding,
+            hedging,
+            latencies: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 /// Text Generation Inference gRPC multi client
+///
+/// The client sends its calls to the shards of a single replica of the model, and knows the load
+/// of the other replicas to balance the requests with `least_outstanding`.
 pub struct ShardedClient {
     clients: Vec<Client>,
+    replicas: Arc<Replicas>,
+    /// Index of the replica of this client in `replicas`
+    replica: usize,
 }
 
 impl ShardedClient {
     fn new(clients: Vec<Client>) -> Self {
-        Self { clients }
+        // The shards share the metrics handle of the first one
+        let clients: Vec<Client> = match clients.first().map(|client| client.metrics().clone()) {
+            Some(metrics) => clients
+                .into_iter()
+                .map(|client| client.with_metrics(metrics.clone()))
+                .collect(),
+            None => clients,
+        };
+        Self {
+            replicas: Arc::new(Replicas::new(vec![clients.clone()], None)),
+            clients,
+            replica: 0,
+        }
+    }
+
+    /// Add independent `replicas` of the model to this client, which becomes the first replica
+    ///
+    /// The replicas keep their configuration, such as their retry policy.
+    pub fn with_replicas(self, replicas: Vec<ShardedClient>) -> Self {
+        let clients = std::iter::once(self.clients.clone())
+            .chain(replicas.into_iter().map(|replica| replica.clients))
+            .collect();
+        Self {
+            clients: self.clients,
+            replicas: Arc::new(Replicas::new(clients, self.replicas.hedging)),
+            replica: 0,
+        }
+    }
+
+    /// Hedge the `health` and `info` calls between the replicas with `hedging`
+    pub fn with_hedging(self, hedging: HedgingPolicy) -> Self {
+        Self {
+            replicas: Arc::new(Replicas::new(self.replicas.clients.clone(), Some(hedging))),
+            ..self
+        }
+    }
+
+    /// Client of the `replica`th replica of the model
+    fn replica_client(&self, replica: usize) -> ShardedClient {
+        Self {
+            clients: self.replicas.clients[replica].clone(),
+            replicas: self.replicas.clone(),
+            replica,
+        }
+    }
+
+    /// Clients of every replica of the model, this one included
+    pub fn replicas(&self) -> Vec<ShardedClient> {
+        (0..self.replicas.clients.len())
+            .map(|replica| self.replica_client(replica))
+            .collect()
+    }
+
+    /// Set the number of requests outstanding on this replica
+    pub fn set_outstanding(&self, outstanding: usize) {
+        self.replicas.outstanding[self.replica].store(outstanding, Ordering::SeqCst);
+    }
+
+    /// Whether no other replica has fewer outstanding requests than this one
+    pub fn is_least_outstanding(&self) -> bool {
+        let outstanding = &self.replicas.outstanding;
+        let own = outstanding[self.replica].load(Ordering::SeqCst);
+        outstanding
+            .iter()
+            .all(|other| own <= other.load(Ordering::SeqCst))
+    }
+
+    /// Client of the replica with the fewest outstanding requests
+    pub fn least_outstanding(&self) -> ShardedClient {
+        let outstanding = &self.replicas.outstanding;
+        let replica = (0..outstanding.len())
+            .min_by_key(|&replica| outstanding[replica].load(Ordering::SeqCst))
+            .unwrap_or(self.replica);
+        self.replica_client(replica)
+    }
+
+    /// Client of the other replica with the fewest outstanding requests
+    fn other_replica(&self) -> ShardedClient {
+        let outstanding = &self.replicas.outstanding;
+        let replica = (0..outstanding.len())
+            .filter(|&replica| replica != self.replica)
+            .min_by_key(|&replica| outstanding[replica].load(Ordering::SeqCst))
+            .unwrap_or(self.replica);
+        self.replica_client(replica)
+    }
+
+    /// Call `method` with `call` on this replica, and on another replica if this one has not
+    /// answered after the hedging delay
+    async fn hedged<T, F, Fut>(&self, method: &'static str, call: F) -> Result<T>
+    where
+        F: Fn(ShardedClient) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let Some(hedging) = self
+            .replicas
+            .hedging
+            .filter(|_| self.replicas.clients.len() > 1)
+        else {
+            return call(self.clone()).await;
+        };
+        let delay = self
+            .replicas
+            .latencies
+            .lock()
+            .unwrap()
+            .entry(method)
+            .or_default()
+            .delay(&hedging);
+
+        let start = Instant::now();
+        let mut first = Box::pin(call(self.clone()));
+        let result = match tokio::time::timeout(delay, &mut first).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::debug!("`{method}` not answered after {delay:?}, hedging");
+                let second = Box::pin(call(self.other_replica()));
+                // The slowest call is cancelled when dropped
+                select_ok([first, second])
+                    .await
+                    .map(|(response, _)| response)
+            }
+        };
+        if result.is_ok() {
+            self.replicas
+                .latencies
+                .lock()
+                .unwrap()
+                .entry(method)
+                .or_default()
+                .record(start.elapsed());
+        }
+        result
+    }
+
+    /// GRPC health check of the model
+    ///
+    /// With a hedging policy, the model is healthy when the first replica answering is healthy.
+    /// Otherwise every replica must be healthy.
+    #[instrument(skip(self))]
+    pub async fn hedged_health(&mut self) -> Result<HealthResponse> {
+        if self.replicas.hedging.is_none() {
+            let mut response = HealthResponse::default();
+            for mut replica in self.replicas() {
+                response = replica.health().await?;
+            }
+            return Ok(response);
+        }
+        self.hedged("health", |mut client| async move { client.health().await })
+            .await
+    }
+
+    /// Get the model info from the first replica answering
+    #[instrument(skip(self))]
+    pub async fn hedged_info(&mut self) -> Result<ShardInfo> {
+        self.hedged("info", |mut client| async move { client.info().await })
+            .await
     }
 
     /// Create a new ShardedClient from a master client. The master client will communicate with
@@ -38,6 +239,161 @@ impl ShardedClient {
         Self::from_master_client(master_client).await
     }
 
+    /// Returns a client connected over TCP to the given uri, with TLS if `tls` is set
+    ///
+    /// The other shards must advertise TCP uris in the service discovery, they are connected to
+    /// with the same TLS configuration. A single shard is only reached through the master uri, as
+    /// it usually advertises its unix socket.
+    pub async fn connect_tcp(uri: Uri, tls: Option<TlsConfig>) -> Result<Self> {
+        let mut master_client = Client::connect_tcp(uri, tls.clone()).await?;
+        let urls = master_client.service_discovery().await?;
+        if urls.len() == 1 {
+            return Ok(Self::new(vec![master_client]));
+        }
+        let futures = urls.into_iter().map(|url| {
+            let tls = tls.clone();
+            async move {
+                let uri = url
+                    .parse::<Uri>()
+                    .ok()
+                    .filter(|uri| uri.scheme().is_some())
+                    .ok_or_else(|| {
+                        ClientError::Connection(format!("shard `{url}` is not reachable over TCP"))
+                    })?;
+                Client::connect_tcp(uri, tls).await
+            }
+        });
+        let clients: Result<Vec<Client>> = join_all(futures).await.into_iter().collect();
+        Ok(Self::new(clients?))
+    }
+
+    /// Returns a client connected to the given unix socket path or `http(s)://` uri
+    ///
+    /// `tls` is only used with `https://` uris
+    pub async fn connect_address(address: String, tls: Option<TlsConfig>) -> Result<Self> {
+        if !address.starts_with("http://") && !address.starts_with("https://") {
+            return Self::connect_uds(address).await;
+        }
+        let uri = address
+            .parse::<Uri>()
+            .map_err(|err| ClientError::Connection(format!("invalid uri `{address}`: {err}")))?;
+        let tls = match uri.scheme_str() {
+            Some("https") => Some(tls.unwrap_or_default()),
+            _ => None,
+        };
+        Self::connect_tcp(uri, tls).await
+    }
+
+    /// Configure the client of every shard with `configure`
+    ///
+    /// Must be done before `with_replicas`, the returned client has no other replica
+    fn configure(self, configure: impl Fn(Client) -> Client) -> Self {
+        Self::new(self.clients.into_iter().map(configure).collect())
+    }
+
+    /// Set the retry policy of the idempotent methods of every shard
+    pub fn with_retry_policy(self, retry_policy: RetryPolicy) -> Self {
+        self.configure(|client| client.with_retry_policy(retry_policy))
+    }
+
+    /// Compress the messages sent to every shard with `compression`
+    pub fn with_request_compression(self, compression: Compression) -> Self {
+        self.configure(|client| client.with_request_compression(compression))
+    }
+
+    /// Accept the messages sent by every shard compressed with `compression`
+    pub fn with_response_compression(self, compression: Compression) -> Self {
+        self.configure(|client| client.with_response_compression(compression))
+    }
+
+    /// Record the metrics of the calls to every shard in the given handle
+    pub fn with_metrics(self, metrics: RpcMetrics) -> Self {
+        self.configure(|client| client.with_metrics(metrics.clone()))
+    }
+
+    /// Handle on the metrics of the calls to the shards
+    pub fn metrics(&self) -> &RpcMetrics {
+        self.clients[0].metrics()
+    }
+
+    /// Stream the generations of the decode steps of every shard with `decode_stream`
+    pub fn with_decode_streaming(self) -> Self {
+        self.configure(Client::with_decode_streaming)
+    }
+
+    /// Set the timeout of the `prefill` and `embed` calls to every shard
+    pub fn with_prefill_timeout(self, timeout: Duration) -> Self {
+        self.configure(|client| client.with_prefill_timeout(timeout))
+    }
+
+    /// Set the timeout of the `decode` calls to every shard
+    pub fn with_decode_timeout(self, timeout: Duration) -> Self {
+        self.configure(|client| client.with_decode_timeout(timeout))
+    }
+
+    /// Set the timeout of the `warmup` calls to every shard
+    pub fn with_warmup_timeout(self, timeout: Duration) -> Self {
+        self.configure(|client| client.with_warmup_timeout(timeout))
+    }
+
+    /// Set the timeout of the control calls to every shard
+    pub fn with_control_timeout(self, timeout: Duration) -> Self {
+        self.configure(|client| client.with_control_timeout(timeout))
+    }
+
+    /// Probe the health of every shard every `interval`, reconnecting to the shards failing a
+    /// probe until they answer again
+    ///
+    /// `on_state_change` is called with the index of a shard when its connection is lost or
+    /// restored. The reconnections are shared with all the clones of this client, so that the
+    /// requests succeed again without restarting the router.
+    pub fn probe_connections<F>(&self, interval: Duration, on_state_change: F) -> JoinHandle<()>
+    where
+        F: Fn(usize, ConnectionState) + Send + 'static,
+    {
+        // Probes are not retried, a failed probe triggers a reconnection
+        let clients: Vec<Client> = self
+            .clients
+            .iter()
+            .map(|client| client.clone().with_retry_policy(RetryPolicy::disabled()))
+            .collect();
+        tokio::spawn(async move {
+            let mut states = vec![ConnectionState::Connected; clients.len()];
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let futures = clients.iter().zip(&states).map(|(client, state)| {
+                    let mut client = client.clone();
+                    let state = *state;
+                    async move {
+                        if state == ConnectionState::Disconnected {
+                            client.reconnect().await?;
+                        }
+                        tokio::time::timeout(interval, client.health())
+                            .await
+                            .map_err(|_| {
+                                ClientError::Connection("health probe timed out".to_string())
+                            })?
+                    }
+                });
+                let results = join_all(futures).await;
+                for (shard, (result, state)) in results.into_iter().zip(&mut states).enumerate() {
+                    let new_state = match result {
+                        Ok(_) => ConnectionState::Connected,
+                        Err(err) => {
+                            tracing::warn!("Shard {shard} failed its health probe: {err}");
+                            ConnectionState::Disconnected
+                        }
+                    };
+                    if new_state != *state {
+                        *state = new_state;
+                        on_state_change(shard, new_state);
+                    }
+                }
+            }
+        })
+    }
+
     /// Get the model info
     #[instrument(skip(self))]
     pub async fn info(&mut self) -> Result<ShardInfo> {
@@ -60,6 +416,22 @@ impl ShardedClient {
         join_all(futures).await.pop().unwrap()
     }
 
+    /// GRPC health check and info of every shard, with the latency of its health check
+    #[instrument(skip(self))]
+    pub async fn shards_health(&mut self) -> Vec<Result<(Duration, ShardInfo)>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| async move {
+                let start_time = Instant::now();
+                client.health().await?;
+                let latency = start_time.elapsed();
+                Ok((latency, client.info().await?))
+            })
+            .collect();
+        join_all(futures).await
+    }
+
     /// Clear the past generations cache
     #[instrument(skip(self))]
     pub async fn clear_cache(&mut self, batch_id: Option<u64>) -> Result<()> {
@@ -71,6 +443,17 @@ impl ShardedClient {
         join_all(futures).await.into_iter().collect()
     }
 
+    /// Release the pinned KV cache of sessions
+    #[instrument(skip(self))]
+    pub async fn release_sessions(&mut self, session_ids: Vec<String>) -> Result<()> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| client.release_sessions(session_ids.clone()))
+            .collect();
+        join_all(futures).await.into_iter().collect()
+    }
+
     /// Filter a cached batch
     #[instrument(skip(self))]
     pub async fn filter_batch(
@@ -146,16 +529,94 @@ impl ShardedClient {
             join_all(futures).await.into_iter().collect();
         merge_generations(results?)
     }
+
+    /// Generate one token for each request in the given cached batches, calling
+    /// `on_generations` with the generations of the shards as soon as they are received
+    ///
+    /// Returns the next cached batch
+    #[instrument(skip_all, fields(size = batches.iter().map(|batch|{batch.size}).sum::<u32>()))]
+    pub async fn decode_stream(
+        &mut self,
+        batches: Vec<CachedBatch>,
+        mut on_generations: impl FnMut(Vec<Generation>),
+    ) -> Result<Option<CachedBatch>> {
+        let timeout = self.clients[0].decode_timeout();
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.decode_stream(batches.clone())))
+            .collect();
+        let streams: Vec<_> = join_all(futures).await.into_iter().collect::<Result<_>>()?;
+
+        let mut next_batches = Vec::new();
+        let receive = async {
+            let mut responses = select_all(streams);
+            while let Some(response) = responses.next().await {
+                let response = response?;
+                if !response.generations.is_empty() {
+                    on_generations(response.generations);
+                }
+                next_batches.extend(response.batch);
+            }
+            Ok::<_, ClientError>(())
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, receive).await.map_err(|_| {
+                ClientError::Generation(format!("decode not finished after {timeout:?}"))
+            })??,
+            None => receive.await?,
+        }
+
+        let mut next_batches = next_batches.into_iter();
+        Ok(next_batches.next().map(|mut next_batch| {
+            for shard_batch in next_batches {
+                merge_free_blocks(&mut next_batch, shard_batch);
+            }
+            next_batch
+        }))
+    }
+
+    /// Whether the generations of the decode steps are streamed with `decode_stream`
+    pub fn streams_decode(&self) -> bool {
+        self.clients.iter().all(Client::streams_decode)
+    }
+
+    /// Compute the pooled embeddings of the given inputs
+    ///
+    /// Returns one embedding per input
+    #[instrument(skip_all, fields(size = inputs.len()))]
+    pub async fn embed(&mut self, inputs: Vec<String>, truncate: u32) -> Result<Vec<Embedding>> {
+        let futures: Vec<_> = self
+            .clients
+            .iter_mut()
+            .map(|client| Box::pin(client.embed(inputs.clone(), truncate)))
+            .collect();
+        // all shards return the same message
+        join_all(futures).await.pop().unwrap()
+    }
 }
 
 /// Merge generations from the different model shards
 fn merge_generations(
     mut results: Vec<(Vec<Generation>, Option<CachedBatch>)>,
 ) -> Result<(Vec<Generation>, Option<CachedBatch>)> {
-    let (mut generations, next_batch) = results.pop().ok_or(ClientError::EmptyResults)?;
+    let (mut generations, mut next_batch) = results.pop().ok_or(ClientError::EmptyResults)?;
 
-    for (mut shard_generations, _) in results.into_iter() {
+    for (mut shard_generations, shard_batch) in results.into_iter() {
         generations.append(&mut shard_generations);
+        if let (Some(next_batch), Some(shard_batch)) = (&mut next_batch, shard_batch) {
+            merge_free_blocks(next_batch, shard_batch);
+        }
     }
     Ok((generations, next_batch))
 }
+
+/// Merge the free blocks of the next batch of a shard into `next_batch`
+///
+/// The KV cache is as free as the one of the fullest shard
+fn merge_free_blocks(next_batch: &mut CachedBatch, shard_batch: CachedBatch) {
+    next_batch.free_blocks = match (next_batch.free_blocks, shard_batch.free_blocks) {
+        (Some(free_blocks), Some(shard_free_blocks)) => Some(free_blocks.min(shard_free_blocks)),
+        (free_blocks, shard_free_blocks) => free_blocks.or(shard_free_blocks),
+    };
+}
@@ -50,6 +50,22 @@ fn inject(metadata: &mut tonic::metadata::MetadataMap) {
     })
 }
 
+/// Extract a context from a gRPC request's metadata, such as the W3C `traceparent` and
+/// `tracestate` of the caller
+fn extract(metadata: &tonic::metadata::MetadataMap) -> opentelemetry::Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&MetadataExtractor(metadata)))
+}
+
+pub trait ExtractTelemetryContext {
+    fn extract_context(&self) -> opentelemetry::Context;
+}
+
+impl<T> ExtractTelemetryContext for tonic::Request<T> {
+    fn extract_context(&self) -> opentelemetry::Context {
+        extract(self.metadata())
+    }
+}
+
 pub trait InjectTelemetryContext {
     fn inject_context(self) -> Self;
 }
@@ -0,0 +1,261 @@
+/// Administration routes controlling the intake of requests and the shards
+use crate::models::Models;
+use crate::ErrorResponse;
+use axum::extract::{Query, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::{Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::{Extension, Json, Router};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Interval between two checks of the in-flight requests while draining
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the router accepts new inference requests
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Intake {
+    paused: Arc<AtomicBool>,
+}
+
+impl Intake {
+    fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+}
+
+/// Reject the new inference requests while the intake is paused
+pub(crate) async fn intake<B>(
+    State(intake): State<Intake>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    if intake.is_paused() {
+        metrics::increment_counter!("tgi_request_failure", "err" => "paused");
+        return Err(error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "The router is not accepting new requests",
+            "paused",
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Authenticate the administration requests with the admin API key
+async fn auth<B>(
+    State(admin_api_key): State<Arc<str>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or(false, |key| key.trim() == &*admin_api_key);
+    if !authorized {
+        return Err(error(
+            StatusCode::UNAUTHORIZED,
+            "Invalid or missing admin API key",
+            "authentication",
+        ));
+    }
+    Ok(next.run(request).await)
+}
+
+/// Administration routes under `/admin`, authenticated with `admin_api_key`
+pub(crate) fn routes(admin_api_key: String, intake: Intake, models: Models) -> Router {
+    Router::new()
+        .route("/admin/status", get(status))
+        .route("/admin/pause", post(pause))
+        .route("/admin/resume", post(resume))
+        .route("/admin/drain", post(drain))
+        .route("/admin/clear_cache", post(clear_cache))
+        .route("/admin/limits", post(limits))
+        .route_layer(middleware::from_fn_with_state(
+            Arc::<str>::from(admin_api_key),
+            auth,
+        ))
+        .layer(Extension(intake))
+        .layer(Extension(models))
+}
+
+#[derive(Debug, Serialize)]
+struct ModelStatus {
+    model_id: String,
+    /// Requests being validated, queued or generated
+    in_flight: usize,
+    /// Requests waiting in the queue
+    queued: usize,
+    max_concurrent_requests: usize,
+    max_waiting_tokens: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct AdminStatus {
+    paused: bool,
+    models: Vec<ModelStatus>,
+}
+
+impl AdminStatus {
+    async fn new(intake: &Intake, models: &Models) -> Self {
+        let mut model_statuses = Vec::new();
+        for (model_id, infer) in models.iter() {
+            model_statuses.push(ModelStatus {
+                model_id: model_id.clone(),
+                in_flight: infer.in_flight(),
+                queued: infer.queue_size().await,
+                max_concurrent_requests: infer.max_concurrent_requests(),
+                max_waiting_tokens: infer.max_waiting_tokens(),
+            });
+        }
+        Self {
+            paused: intake.is_paused(),
+            models: model_statuses,
+        }
+    }
+
+    fn in_flight(&self) -> usize {
+        self.models.iter().map(|model| model.in_flight).sum()
+    }
+}
+
+/// Intake and in-flight requests of every model
+async fn status(intake: Extension<Intake>, models: Extension<Models>) -> Json<AdminStatus> {
+    Json(AdminStatus::new(&intake, &models).await)
+}
+
+/// Stop accepting new inference requests
+async fn pause(intake: Extension<Intake>, models: Extension<Models>) -> Json<AdminStatus> {
+    intake.set_paused(true);
+    tracing::info!("Intake paused");
+    Json(AdminStatus::new(&intake, &models).await)
+}
+
+/// Accept new inference requests again
+async fn resume(intake: Extension<Intake>, models: Extension<Models>) -> Json<AdminStatus> {
+    intake.set_paused(false);
+    tracing::info!("Intake resumed");
+    Json(AdminStatus::new(&intake, &models).await)
+}
+
+#[derive(Debug, Deserialize)]
+struct DrainParameters {
+    /// Maximum number of seconds to wait for the in-flight requests
+    #[serde(default = "default_drain_timeout_secs")]
+    timeout_secs: u64,
+}
+
+fn default_drain_timeout_secs() -> u64 {
+    60
+}
+
+/// Pause the intake and wait for the in-flight requests to finish
+///
+/// Answers with the remaining in-flight requests once they are all finished or when the timeout
+/// elapses
+async fn drain(
+    intake: Extension<Intake>,
+    models: Extension<Models>,
+    parameters: Query<DrainParameters>,
+) -> Json<AdminStatus> {
+    intake.set_paused(true);
+    tracing::info!("Intake paused, draining the in-flight requests");
+    let deadline = Instant::now() + Duration::from_secs(parameters.timeout_secs);
+    loop {
+        let status = AdminStatus::new(&intake, &models).await;
+        if status.in_flight() == 0 || Instant::now() >= deadline {
+            return Json(status);
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+/// Clear the KV cache of the shards of every model
+///
+/// Refused while requests are in flight as their batches would be dropped
+async fn clear_cache(
+    intake: Extension<Intake>,
+    models: Extension<Models>,
+) -> Result<Json<AdminStatus>, (StatusCode, Json<ErrorResponse>)> {
+    let status = AdminStatus::new(&intake, &models).await;
+    if status.in_flight() > 0 {
+        return Err(error(
+            StatusCode::CONFLICT,
+            "Requests are in flight, drain the router first",
+            "in_flight",
+        ));
+    }
+    for (model_id, infer) in models.iter() {
+        infer.clear_cache().await.map_err(|err| {
+            tracing::error!("Could not clear the cache of {model_id}: {err}");
+            error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &err.to_string(),
+                "clear_cache",
+            )
+        })?;
+    }
+    tracing::info!("Shards cache cleared");
+    Ok(Json(status))
+}
+
+#[derive(Debug, Deserialize)]
+struct Limits {
+    #[serde(default)]
+    max_concurrent_requests: Option<usize>,
+    #[serde(default)]
+    max_waiting_tokens: Option<usize>,
+}
+
+/// Change the limits of every model without restarting the router
+///
+/// Lowering `max_concurrent_requests` does not interrupt the running requests, the new limit
+/// applies as they finish
+async fn limits(
+    intake: Extension<Intake>,
+    models: Extension<Models>,
+    Json(limits): Json<Limits>,
+) -> Result<Json<AdminStatus>, (StatusCode, Json<ErrorResponse>)> {
+    if limits.max_concurrent_requests == Some(0) {
+        return Err(error(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "`max_concurrent_requests` must be > 0",
+            "validation",
+        ));
+    }
+    for (_, infer) in models.iter() {
+        if let Some(max_concurrent_requests) = limits.max_concurrent_requests {
+            infer.set_max_concurrent_requests(max_concurrent_requests);
+        }
+        if let Some(max_waiting_tokens) = limits.max_waiting_tokens {
+            infer.set_max_waiting_tokens(max_waiting_tokens);
+        }
+    }
+    Ok(Json(AdminStatus::new(&intake, &models).await))
+}
+
+fn error(
+    status_code: StatusCode,
+    error: &str,
+    error_type: &str,
+) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        status_code,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            error_type: error_type.to_string(),
+            load: None,
+            policy_violation: None,
+        }),
+    )
+}
@@ -0,0 +1,342 @@
+/// Audit log of the generation requests
+use crate::infer::InferError;
+use crate::queue::Entry;
+use crate::{FinishReason, Priority};
+use clap::ValueEnum;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use text_generation_client::GeneratedText;
+
+/// Number of characters kept of the prompts and outputs with `AuditContent::Truncated`
+const TRUNCATED_LENGTH: usize = 128;
+
+/// How the prompts and outputs are written to the audit log
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AuditContent {
+    /// Not written
+    None,
+    /// SHA-256 hash
+    Hash,
+    /// First characters
+    Truncated,
+    /// Full text
+    Full,
+}
+
+/// Fields of the audit records that can be redacted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditField {
+    Tenant,
+    Parameters,
+    Prompt,
+    Output,
+    Error,
+}
+
+/// Audit log configuration
+#[derive(Clone, Debug)]
+pub struct AuditConfig {
+    /// Path of the current audit log file
+    pub path: PathBuf,
+    /// Size in bytes after which the file is rotated
+    pub max_file_size: u64,
+    /// Number of rotated files kept
+    pub max_files: usize,
+    pub content: AuditContent,
+    pub redact: Vec<AuditField>,
+}
+
+/// Writes one JSON record per finished request to rotating files
+///
+/// Records are written by a background thread so that the batching task never blocks on IO
+#[derive(Clone, Debug)]
+pub(crate) struct AuditLog {
+    content: AuditContent,
+    redact: Vec<AuditField>,
+    sender: flume::Sender<Vec<u8>>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(config: AuditConfig) -> std::io::Result<Self> {
+        let mut file = RotatingFile::open(config.path, config.max_file_size, config.max_files)?;
+        let (sender, receiver) = flume::unbounded::<Vec<u8>>();
+        std::thread::spawn(move || {
+            while let Ok(record) = receiver.recv() {
+                if let Err(err) = file.write(&record) {
+                    metrics::increment_counter!("tgi_audit_log_failure");
+                    tracing::error!("Could not write to the audit log: {err}");
+                }
+            }
+        });
+        Ok(Self {
+            content: config.content,
+            redact: config.redact,
+            sender,
+        })
+    }
+
+    /// Record a request that generated its last token
+    pub(crate) fn record_end(&self, id: u64, entry: &Entry, generated_text: &GeneratedText) {
+        let mut record = self.record(id, entry);
+        record.generated_tokens = Some(generated_text.generated_tokens);
+        record.finish_reason = Some(FinishReason::from(generated_text.finish_reason));
+        record.output = self.content(AuditField::Output, &generated_text.text);
+        self.send(record);
+    }
+
+    /// Record a request cancelled by its client
+    pub(crate) fn record_cancelled(&self, id: u64, entry: &Entry) {
+        let mut record = self.record(id, entry);
+        record.error_type = Some("cancelled".to_string());
+        self.send(record);
+    }
+
+    /// Record a request that failed
+    pub(crate) fn record_error(&self, id: u64, entry: &Entry, error: &InferError) {
+        let mut record = self.record(id, entry);
+        record.error_type = Some(error.error_type().to_string());
+        if !self.redact.contains(&AuditField::Error) {
+            record.error = Some(error.to_string());
+        }
+        self.send(record);
+    }
+
+    fn record<'a>(&self, id: u64, entry: &'a Entry) -> AuditRecord<'a> {
+        let request = &entry.request;
+        let parameters = AuditParameters {
+            priority: request.priority,
+            temperature: request.parameters.temperature,
+            top_k: request.parameters.top_k,
+            top_p: request.parameters.top_p,
+            typical_p: request.parameters.typical_p,
+            min_p: request.parameters.min_p,
+            epsilon_cutoff: request.parameters.epsilon_cutoff,
+            eta_cutoff: request.parameters.eta_cutoff,
+            mirostat: request.parameters.mirostat,
+            do_sample: request.parameters.do_sample,
+            seed: request.parameters.seed,
+            repetition_penalty: request.parameters.repetition_penalty,
+            frequency_penalty: request.parameters.frequency_penalty,
+            presence_penalty: request.parameters.presence_penalty,
+            no_repeat_ngram_size: request.parameters.no_repeat_ngram_size,
+            watermark: request.parameters.watermark,
+            max_new_tokens: request.stopping_parameters.max_new_tokens,
+            stop_sequences: request.stopping_parameters.stop_sequences.len(),
+            bad_words: request.parameters.bad_words_ids.len(),
+            top_n_tokens: request.top_n_tokens,
+            grammar: !request.parameters.grammar.is_empty(),
+        };
+        AuditRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            request_id: id,
+            tenant: request
+                .tenant
+                .as_ref()
+                .filter(|_| !self.redact.contains(&AuditField::Tenant))
+                .map(|tenant| tenant.name()),
+            parameters: (!self.redact.contains(&AuditField::Parameters)).then_some(parameters),
+            input_tokens: request.input_length,
+            generated_tokens: None,
+            finish_reason: None,
+            queue_time_ms: entry
+                .batch_time
+                .unwrap_or_else(tokio::time::Instant::now)
+                .duration_since(entry.queue_time)
+                .as_millis(),
+            inference_time_ms: entry.batch_time.map(|time| time.elapsed().as_millis()),
+            prompt: self.content(AuditField::Prompt, &request.inputs),
+            output: None,
+            error_type: None,
+            error: None,
+            redacted: &self.redact,
+        }
+    }
+
+    fn content(&self, field: AuditField, text: &str) -> Option<String> {
+        if self.redact.contains(&field) {
+            return None;
+        }
+        match self.content {
+            AuditContent::None => None,
+            AuditContent::Hash => Some(format!("sha256:{:x}", Sha256::digest(text.as_bytes()))),
+            AuditContent::Truncated => Some(truncate(text)),
+            AuditContent::Full => Some(text.to_string()),
+        }
+    }
+
+    fn send(&self, record: AuditRecord) {
+        let mut line = serde_json::to_vec(&record).expect("audit records are serializable");
+        line.push(b'\n');
+        // The writer thread lives as long as the router
+        self.sender.send(line).unwrap_or(());
+    }
+}
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(TRUNCATED_LENGTH) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+#[derive(Serialize)]
+struct AuditParameters {
+    priority: Priority,
+    temperature: f32,
+    top_k: u32,
+    top_p: f32,
+    typical_p: f32,
+    min_p: f32,
+    epsilon_cutoff: f32,
+    eta_cutoff: f32,
+    mirostat: u32,
+    do_sample: bool,
+    seed: u64,
+    repetition_penalty: f32,
+    frequency_penalty: f32,
+    presence_penalty: f32,
+    no_repeat_ngram_size: u32,
+    watermark: bool,
+    max_new_tokens: u32,
+    /// Number of stop sequences, which can contain user data
+    stop_sequences: usize,
+    /// Number of banned token sequences
+    bad_words: usize,
+    top_n_tokens: u32,
+    grammar: bool,
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp_ms: u128,
+    request_id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tenant: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parameters: Option<AuditParameters>,
+    input_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    generated_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finish_reason: Option<FinishReason>,
+    queue_time_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    inference_time_ms: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    redacted: &'a [AuditField],
+}
+
+/// Append-only file rotated when it grows over `max_size`
+///
+/// Rotated files are suffixed with `.1` (most recent) to `.{max_files}` (oldest)
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn write(&mut self, record: &[u8]) -> std::io::Result<()> {
+        if self.size > 0 && self.size + record.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(record)?;
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short"), "short");
+        let long = "é".repeat(TRUNCATED_LENGTH + 1);
+        assert_eq!(
+            truncate(&long),
+            format!("{}...", "é".repeat(TRUNCATED_LENGTH))
+        );
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("audit-{}", rand::random::<u64>()));
+        let path = dir.join("audit.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for record in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write(record.as_bytes()).unwrap();
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            std::fs::read_to_string(file.rotated_path(1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(file.rotated_path(2)).unwrap(),
+            "second\n"
+        );
+        assert!(!file.rotated_path(3).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
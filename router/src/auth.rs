@@ -0,0 +1,520 @@
+/// API key authentication and per-key quotas
+use crate::usage::{TenantUsage, UsageTracker};
+use crate::ErrorResponse;
+use axum::body::{boxed, BoxBody, Bytes, HttpBody};
+use axum::extract::State;
+use axum::http::header::AUTHORIZATION;
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+/// Interval between two checks of the API keys file
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Entry of the API keys file
+///
+/// The file contains a JSON array of entries
+#[derive(Debug, Deserialize)]
+struct ApiKeyConfig {
+    key: String,
+    tenant: String,
+    #[serde(default)]
+    max_concurrent_requests: Option<usize>,
+    #[serde(default)]
+    daily_token_quota: Option<u64>,
+    /// Share of the batch slots the tenant gets when several tenants are queued
+    #[serde(default = "default_scheduling_weight")]
+    scheduling_weight: u32,
+}
+
+fn default_scheduling_weight() -> u32 {
+    1
+}
+
+/// Tokens used by an API key during a day
+#[derive(Debug, Default)]
+struct DailyUsage {
+    /// Days since the UNIX epoch
+    day: u64,
+    tokens: u64,
+}
+
+impl DailyUsage {
+    /// Tokens used today, resetting the usage when the day changes
+    fn today(&mut self) -> &mut u64 {
+        let day = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            / 86400;
+        if day != self.day {
+            self.day = day;
+            self.tokens = 0;
+        }
+        &mut self.tokens
+    }
+}
+
+#[derive(Debug)]
+struct KeyState {
+    tenant: String,
+    daily_token_quota: Option<u64>,
+    scheduling_weight: u32,
+    /// Limit of concurrent requests
+    max_concurrent_requests: Option<usize>,
+    /// Requests of the key in flight, kept across reloads of the API keys file
+    in_flight: Arc<AtomicUsize>,
+    /// Usage of the key, kept across reloads of the API keys file
+    usage: Arc<Mutex<DailyUsage>>,
+    /// Usage accounting of all the tenants
+    usage_tracker: UsageTracker,
+}
+
+/// Identity of the tenant owning the API key of a request
+#[derive(Clone, Debug)]
+pub(crate) struct Tenant(Arc<KeyState>);
+
+impl Tenant {
+    pub(crate) fn name(&self) -> &str {
+        &self.0.tenant
+    }
+
+    /// Number of consecutive queued requests of the tenant batched before the next tenant
+    pub(crate) fn scheduling_weight(&self) -> u32 {
+        self.0.scheduling_weight
+    }
+
+    /// Count the tokens of a request against the daily quota of the tenant
+    pub(crate) fn record_tokens(&self, tokens: u32) {
+        *self.0.usage.lock().unwrap().today() += tokens as u64;
+        metrics::counter!("tgi_tenant_tokens", tokens as u64, "tenant" => self.0.tenant.clone());
+    }
+
+    /// Count a finished request of the tenant against its quota and in its usage accounting
+    pub(crate) fn record_usage(&self, prompt_tokens: u32, generated_tokens: u32) {
+        self.record_tokens(prompt_tokens + generated_tokens);
+        self.0
+            .usage_tracker
+            .record(&self.0.tenant, prompt_tokens, generated_tokens);
+    }
+
+    /// Usage of the tenant since the router started
+    pub(crate) fn usage(&self) -> TenantUsage {
+        self.0.usage_tracker.get(&self.0.tenant)
+    }
+
+    /// Count a new request against the concurrent requests limit of the key
+    ///
+    /// Returns None if the key has no limit, and an error if the limit is reached
+    pub(crate) fn acquire(&self) -> Result<Option<TenantPermit>, ConcurrencyExceeded> {
+        let Some(limit) = self.0.max_concurrent_requests else {
+            return Ok(None);
+        };
+        self.0
+            .in_flight
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |in_flight| {
+                (in_flight < limit).then_some(in_flight + 1)
+            })
+            .map(|in_flight| {
+                Some(TenantPermit {
+                    in_flight: self.0.in_flight.clone(),
+                    limit,
+                    remaining: limit - in_flight - 1,
+                })
+            })
+            .map_err(|_| ConcurrencyExceeded { limit })
+    }
+
+    pub(crate) fn quota_exceeded(&self) -> bool {
+        match self.0.daily_token_quota {
+            Some(quota) => *self.0.usage.lock().unwrap().today() >= quota,
+            None => false,
+        }
+    }
+}
+
+/// API keys loaded from a file
+///
+/// The file is reloaded when it is modified
+#[derive(Clone, Debug)]
+pub(crate) struct ApiKeys {
+    path: PathBuf,
+    keys: Arc<RwLock<HashMap<String, Tenant>>>,
+    usage_tracker: UsageTracker,
+}
+
+impl ApiKeys {
+    pub(crate) fn load(path: PathBuf) -> Result<Self, AuthError> {
+        let api_keys = Self {
+            path,
+            keys: Arc::new(RwLock::new(HashMap::new())),
+            usage_tracker: UsageTracker::default(),
+        };
+        api_keys.reload()?;
+        Ok(api_keys)
+    }
+
+    /// Read the API keys file and replace the current keys
+    fn reload(&self) -> Result<(), AuthError> {
+        let content = std::fs::read_to_string(&self.path)?;
+        let configs: Vec<ApiKeyConfig> = serde_json::from_str(&content)?;
+
+        let mut keys = self.keys.write().unwrap();
+        let mut new_keys = HashMap::with_capacity(configs.len());
+        for config in configs {
+            if config.key.is_empty() {
+                return Err(AuthError::EmptyKey(config.tenant));
+            }
+            if config.scheduling_weight == 0 {
+                return Err(AuthError::SchedulingWeight(config.tenant));
+            }
+            // Keep the usage and the requests in flight of existing keys
+            let (usage, in_flight) = keys
+                .get(&config.key)
+                .map(|tenant| (tenant.0.usage.clone(), tenant.0.in_flight.clone()))
+                .unwrap_or_default();
+            let tenant = Tenant(Arc::new(KeyState {
+                tenant: config.tenant,
+                daily_token_quota: config.daily_token_quota,
+                scheduling_weight: config.scheduling_weight,
+                max_concurrent_requests: config.max_concurrent_requests,
+                in_flight,
+                usage,
+                usage_tracker: self.usage_tracker.clone(),
+            }));
+            if new_keys.insert(config.key, tenant).is_some() {
+                return Err(AuthError::DuplicateKey);
+            }
+        }
+        *keys = new_keys;
+        tracing::info!("Loaded {} API keys", keys.len());
+        Ok(())
+    }
+
+    /// Reload the API keys file in the background when it is modified
+    pub(crate) fn spawn_reload_task(&self) {
+        let api_keys = self.clone();
+        tokio::spawn(async move {
+            let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified());
+            let mut last_modified = modified(&api_keys.path).ok();
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                let current = modified(&api_keys.path).ok();
+                if current != last_modified {
+                    last_modified = current;
+                    // Keep the current keys if the new file is invalid
+                    if let Err(err) = api_keys.reload() {
+                        tracing::error!("Could not reload the API keys: {err}");
+                    }
+                }
+            }
+        });
+    }
+
+    /// Usage accounting of the tenants of the keys
+    pub(crate) fn usage_tracker(&self) -> &UsageTracker {
+        &self.usage_tracker
+    }
+
+    /// Tenant of the bearer API key of an `Authorization` header
+    pub(crate) fn authenticate(&self, authorization: Option<&str>) -> Option<Tenant> {
+        authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|key| self.get(key.trim()))
+    }
+
+    fn get(&self, key: &str) -> Option<Tenant> {
+        self.keys.read().unwrap().get(key).cloned()
+    }
+}
+
+/// Request counted against the concurrent requests limit of its key until dropped
+#[derive(Debug)]
+pub(crate) struct TenantPermit {
+    in_flight: Arc<AtomicUsize>,
+    limit: usize,
+    /// Requests the key could still send when this one was admitted
+    remaining: usize,
+}
+
+impl Drop for TenantPermit {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// The key reached its limit of concurrent requests
+#[derive(Debug)]
+pub(crate) struct ConcurrencyExceeded {
+    limit: usize,
+}
+
+/// Headers telling the client of a tenant its concurrent requests limit
+fn concurrency_headers(headers: &mut HeaderMap, limit: usize, remaining: usize) {
+    headers.insert(
+        "x-tenant-concurrency-limit",
+        HeaderValue::from(limit as u64),
+    );
+    headers.insert(
+        "x-tenant-concurrency-remaining",
+        HeaderValue::from(remaining as u64),
+    );
+}
+
+/// Authenticate the request with its bearer API key and enforce the limits of the key
+///
+/// The `Tenant` of the key is added to the request extensions. The requests over the concurrent
+/// requests limit of the key are rejected independently of the concurrency limit of the router,
+/// so that a tenant cannot take all the slots of the other tenants.
+pub(crate) async fn auth<B>(
+    State(api_keys): State<ApiKeys>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let authorization = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    let tenant = api_keys.authenticate(authorization).ok_or_else(|| {
+        metrics::increment_counter!("tgi_request_failure", "err" => "unauthorized");
+        error(
+            StatusCode::UNAUTHORIZED,
+            "Invalid or missing API key",
+            "authentication",
+        )
+    })?;
+
+    if tenant.quota_exceeded() {
+        metrics::increment_counter!("tgi_request_failure", "err" => "quota");
+        return Err(error(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Daily token quota exceeded",
+            "quota",
+        ));
+    }
+
+    let permit = match tenant.acquire() {
+        Ok(permit) => permit,
+        Err(ConcurrencyExceeded { limit }) => {
+            metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
+            metrics::increment_counter!("tgi_tenant_concurrency_exceeded", "tenant" => tenant.name().to_string());
+            let mut response = error(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many concurrent requests for this API key",
+                "overloaded",
+            )
+            .into_response();
+            let headers = response.headers_mut();
+            concurrency_headers(headers, limit, 0);
+            // Visible ASCII tenant names are valid header values
+            if let Ok(value) = HeaderValue::from_str(tenant.name()) {
+                headers.insert("x-tenant", value);
+            }
+            return Ok(response);
+        }
+    };
+
+    request.extensions_mut().insert(tenant);
+    let response = next.run(request).await;
+
+    // Streamed responses are still running when the handler returns
+    Ok(match permit {
+        Some(permit) => {
+            let mut response = response;
+            concurrency_headers(response.headers_mut(), permit.limit, permit.remaining);
+            response.map(|body| {
+                boxed(PermitBody {
+                    body,
+                    _permit: permit,
+                })
+            })
+        }
+        None => response,
+    })
+}
+
+fn error(
+    status_code: StatusCode,
+    error: &str,
+    error_type: &str,
+) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        status_code,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            error_type: error_type.to_string(),
+            load: None,
+            policy_violation: None,
+        }),
+    )
+}
+
+/// Response body holding a concurrency permit until the response is sent
+struct PermitBody {
+    body: BoxBody,
+    _permit: TenantPermit,
+}
+
+impl HttpBody for PermitBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+}
+
+#[cfg(test)]
+impl Tenant {
+    /// Tenant without limits, used by the tests of the other modules
+    pub(crate) fn test(name: &str, scheduling_weight: u32) -> Self {
+        Self(Arc::new(KeyState {
+            tenant: name.to_string(),
+            daily_token_quota: None,
+            scheduling_weight,
+            max_concurrent_requests: None,
+            in_flight: Arc::default(),
+            usage: Arc::default(),
+            usage_tracker: UsageTracker::default(),
+        }))
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum AuthError {
+    #[error("Unable to read the API keys file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to parse the API keys file: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Empty API key for tenant `{0}`")]
+    EmptyKey(String),
+    #[error("Duplicate API key")]
+    DuplicateKey,
+    #[error("`scheduling_weight` of tenant `{0}` must be strictly positive")]
+    SchedulingWeight(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys_file(content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("api-keys-{}.json", rand::random::<u64>()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let path = keys_file(r#"[{"key": "secret", "tenant": "acme", "daily_token_quota": 10}]"#);
+        let api_keys = ApiKeys::load(path.clone()).unwrap();
+        assert!(api_keys.get("wrong").is_none());
+        let tenant = api_keys.get("secret").unwrap();
+        assert_eq!(tenant.name(), "acme");
+
+        tenant.record_tokens(6);
+        assert!(!tenant.quota_exceeded());
+        tenant.record_tokens(4);
+        assert!(tenant.quota_exceeded());
+
+        // The usage is kept across reloads
+        api_keys.reload().unwrap();
+        assert!(api_keys.get("secret").unwrap().quota_exceeded());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_api_keys_usage() {
+        let path = keys_file(
+            r#"[{"key": "first", "tenant": "acme"}, {"key": "second", "tenant": "acme"}]"#,
+        );
+        let api_keys = ApiKeys::load(path.clone()).unwrap();
+        api_keys.get("first").unwrap().record_usage(10, 5);
+        // The usage is accounted per tenant and kept across reloads
+        api_keys.reload().unwrap();
+        api_keys.get("second").unwrap().record_usage(2, 1);
+        let usage = api_keys.get("first").unwrap().usage();
+        assert_eq!(usage.requests, 2);
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.generated_tokens, 6);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_api_keys_concurrency() {
+        let path =
+            keys_file(r#"[{"key": "secret", "tenant": "acme", "max_concurrent_requests": 2}]"#);
+        let api_keys = ApiKeys::load(path.clone()).unwrap();
+        let tenant = api_keys.get("secret").unwrap();
+        let first = tenant.acquire().unwrap().unwrap();
+        assert_eq!(first.remaining, 1);
+
+        // The requests in flight are kept across reloads
+        api_keys.reload().unwrap();
+        let tenant = api_keys.get("secret").unwrap();
+        let second = tenant.acquire().unwrap().unwrap();
+        assert_eq!(second.remaining, 0);
+        assert!(matches!(
+            tenant.acquire(),
+            Err(ConcurrencyExceeded { limit: 2 })
+        ));
+
+        drop(first);
+        assert!(tenant.acquire().is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_api_keys_duplicate() {
+        let path =
+            keys_file(r#"[{"key": "secret", "tenant": "a"}, {"key": "secret", "tenant": "b"}]"#);
+        assert!(matches!(
+            ApiKeys::load(path.clone()),
+            Err(AuthError::DuplicateKey)
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_api_keys_scheduling_weight() {
+        let path = keys_file(
+            r#"[{"key": "bulk", "tenant": "a"}, {"key": "chat", "tenant": "b", "scheduling_weight": 4}]"#,
+        );
+        let api_keys = ApiKeys::load(path.clone()).unwrap();
+        assert_eq!(api_keys.get("bulk").unwrap().scheduling_weight(), 1);
+        assert_eq!(api_keys.get("chat").unwrap().scheduling_weight(), 4);
+        std::fs::remove_file(path).unwrap();
+
+        let path = keys_file(r#"[{"key": "secret", "tenant": "a", "scheduling_weight": 0}]"#);
+        assert!(matches!(
+            ApiKeys::load(path.clone()),
+            Err(AuthError::SchedulingWeight(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+/// Cancellation of the requests in flight by request id
+use crate::infer::{InferError, InferStreamResponse};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::Notify;
+
+type ResponseReceiver = flume::Receiver<Result<InferStreamResponse, InferError>>;
+
+/// Cancellation flag of a request
+#[derive(Debug, Default)]
+struct Cancellation {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+/// Cancellation of a request, set by `Cancellations::cancel`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CancelToken {
+    cancellation: Arc<Cancellation>,
+}
+
+impl CancelToken {
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancellation.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Wait until the request is cancelled
+    async fn cancelled(&self) {
+        // Created before checking the flag so that a cancellation in between is not missed
+        let notified = self.cancellation.notify.notified();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+
+    /// Forward the responses of `response_rx` until the request is cancelled
+    ///
+    /// A cancelled request receives `InferError::Cancelled` and `response_rx` is dropped: the
+    /// batching task then stops the generation as if the client disconnected.
+    pub(crate) fn forward(self, response_rx: ResponseReceiver) -> ResponseReceiver {
+        let (response_tx, cancellable_rx) = flume::unbounded();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    response = response_rx.recv_async() => match response {
+                        Ok(response) => {
+                            if response_tx.send(response).is_err() {
+                                break;
+                            }
+                        }
+                        Err(_) => break,
+                    },
+                    _ = self.cancelled() => {
+                        metrics::increment_counter!("tgi_request_cancelled");
+                        let _ = response_tx.send(Err(InferError::Cancelled));
+                        break;
+                    }
+                }
+            }
+        });
+        cancellable_rx
+    }
+}
+
+/// Request in flight and the tenant that sent it
+#[derive(Debug)]
+struct CancellableRequest {
+    tenant: Option<String>,
+    cancellation: Weak<Cancellation>,
+}
+
+/// Requests in flight that can be cancelled by their request id
+///
+/// A request can only be cancelled by its tenant. The requests are forgotten once all the clones
+/// of their token are dropped.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Cancellations {
+    requests: Arc<Mutex<HashMap<String, CancellableRequest>>>,
+}
+
+impl Cancellations {
+    /// Token of the request `request_id` of `tenant`, cancelled by `cancel`
+    pub(crate) fn register(&self, request_id: &str, tenant: Option<&str>) -> CancelToken {
+        let token = CancelToken::default();
+        let mut requests = self.requests.lock().unwrap();
+        requests.retain(|_, request| request.cancellation.strong_count() > 0);
+        requests.insert(
+            request_id.to_string(),
+            CancellableRequest {
+                tenant: tenant.map(str::to_string),
+                cancellation: Arc::downgrade(&token.cancellation),
+            },
+        );
+        token
+    }
+
+    /// Cancel the request `request_id` of `tenant`, returns whether it was in flight
+    pub(crate) fn cancel(&self, request_id: &str, tenant: Option<&str>) -> bool {
+        let requests = self.requests.lock().unwrap();
+        let cancellation = requests
+            .get(request_id)
+            .filter(|request| request.tenant.as_deref() == tenant)
+            .and_then(|request| request.cancellation.upgrade());
+        match cancellation {
+            Some(cancellation) => {
+                cancellation.cancelled.store(true, Ordering::SeqCst);
+                cancellation.notify.notify_waiters();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel() {
+        let cancellations = Cancellations::default();
+        let token = cancellations.register("request", Some("tenant"));
+        assert!(!cancellations.cancel("other", Some("tenant")));
+        // Requests of other tenants cannot be cancelled
+        assert!(!cancellations.cancel("request", None));
+        assert!(!token.is_cancelled());
+
+        assert!(cancellations.cancel("request", Some("tenant")));
+        assert!(token.is_cancelled());
+
+        // The request is done
+        drop(token);
+        assert!(!cancellations.cancel("request", Some("tenant")));
+    }
+
+    #[tokio::test]
+    async fn test_forward() {
+        let cancellations = Cancellations::default();
+        let (response_tx, response_rx) = flume::unbounded();
+        let response_rx = cancellations.register("request", None).forward(response_rx);
+
+        cancellations.cancel("request", None);
+        assert!(matches!(
+            response_rx.recv_async().await,
+            Ok(Err(InferError::Cancelled))
+        ));
+        // The batching task sees the client as disconnected
+        assert!(response_tx.is_disconnected());
+    }
+}
@@ -0,0 +1,68 @@
+/// Generated text of a request constrained to one of `choices`
+///
+/// Once a single choice starts with the generated text, the following tokens are forced by the
+/// grammar so the generation can stop and the rest of the choice be appended instead
+#[derive(Debug)]
+pub(crate) struct ChoiceTracker {
+    choices: Vec<String>,
+    generated: String,
+    generated_tokens: u32,
+}
+
+impl ChoiceTracker {
+    pub(crate) fn new(choices: Vec<String>) -> Self {
+        Self {
+            choices,
+            generated: String::new(),
+            generated_tokens: 0,
+        }
+    }
+
+    /// Add the text of a generated token and return the rest of the choice once it is the only
+    /// one starting with the generated text
+    pub(crate) fn push(&mut self, text: &str) -> Option<String> {
+        self.generated.push_str(text);
+        self.generated_tokens += 1;
+        let mut candidates = self
+            .choices
+            .iter()
+            .filter(|choice| choice.starts_with(&self.generated));
+        match (candidates.next(), candidates.next()) {
+            (Some(choice), None) => Some(choice[self.generated.len()..].to_string()),
+            _ => None,
+        }
+    }
+
+    /// Text generated so far
+    pub(crate) fn generated(&self) -> &str {
+        &self.generated
+    }
+
+    pub(crate) fn generated_tokens(&self) -> u32 {
+        self.generated_tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_choice_tracker() {
+        let choices = vec![
+            "positive".to_string(),
+            "negative".to_string(),
+            "neutral".to_string(),
+        ];
+        let mut tracker = ChoiceTracker::new(choices);
+        assert_eq!(tracker.push("ne"), None);
+        assert_eq!(tracker.push("g"), Some("ative".to_string()));
+        assert_eq!(tracker.generated(), "neg");
+        assert_eq!(tracker.generated_tokens(), 2);
+
+        // A choice prefix of another one is not determined until the other is excluded
+        let mut tracker = ChoiceTracker::new(vec!["yes".to_string(), "yes!".to_string()]);
+        assert_eq!(tracker.push("yes"), None);
+        assert_eq!(tracker.push("!"), Some(String::new()));
+    }
+}
@@ -0,0 +1,160 @@
+/// Prefill of long prompts split into chunks interleaved with the decoding steps of the running
+/// batch, so that a long prompt does not stall the token emission of the running requests
+use crate::Entry;
+use nohash_hasher::IntMap;
+use text_generation_client::Batch;
+use tracing::Span;
+
+/// Batch whose prefill is split into chunks
+///
+/// Each chunk prefills at most `chunk_len` input tokens of every request of the batch. The shards
+/// keep the KV cache of the partially prefilled batch under its ID until its last chunk.
+#[derive(Debug)]
+pub(crate) struct ChunkedPrefill {
+    batch: Batch,
+    /// Number of input tokens following the prefix of each request of the batch
+    input_lens: Vec<u32>,
+    /// Maximum number of input tokens of a request prefilled by a chunk, None to not split
+    chunk_len: Option<u32>,
+    /// Number of input tokens of the longest request prefilled by the previous chunks
+    offset: u32,
+    pub(crate) entries: IntMap<u64, Entry>,
+    pub(crate) span: Span,
+}
+
+impl ChunkedPrefill {
+    pub(crate) fn new(
+        batch: Batch,
+        entries: IntMap<u64, Entry>,
+        span: Span,
+        chunk_len: Option<u32>,
+    ) -> Self {
+        let input_lens = batch
+            .requests
+            .iter()
+            .map(|request| {
+                let entry = entries
+                    .get(&request.id)
+                    .expect("ID not found in entries. This is a bug.");
+                entry
+                    .request
+                    .input_length
+                    .saturating_sub(request.prefix_len)
+            })
+            .collect();
+        Self {
+            batch,
+            input_lens,
+            chunk_len,
+            offset: 0,
+            entries,
+            span,
+        }
+    }
+
+    /// Next partial chunk of the prefill, None once the remaining input tokens fit in the last
+    /// chunk
+    pub(crate) fn next_chunk(&mut self) -> Option<Batch> {
+        let chunk_len = self.chunk_len?;
+        let max_input_len = self.input_lens.iter().copied().max().unwrap_or(0);
+        if max_input_len.saturating_sub(self.offset) <= chunk_len {
+            return None;
+        }
+
+        let mut batch = self.batch.clone();
+        for (request, &input_len) in batch.requests.iter_mut().zip(self.input_lens.iter()) {
+            request.prefill_offset = self.prefilled(input_len);
+            request.prefill_chunk_len =
+                Some((last_chunk_start(input_len) - request.prefill_offset).min(chunk_len));
+        }
+        self.offset += chunk_len;
+        metrics::increment_counter!("tgi_batch_prefill_chunks");
+        Some(batch)
+    }
+
+    /// Last chunk of the prefill, with all the remaining input tokens
+    pub(crate) fn last_chunk(self) -> (Batch, IntMap<u64, Entry>, Span) {
+        let mut batch = self.batch;
+        for (request, &input_len) in batch.requests.iter_mut().zip(self.input_lens.iter()) {
+            request.prefill_offset = self.prefilled(input_len);
+        }
+        (batch, self.entries, self.span)
+    }
+
+    /// Number of input tokens of a request of `input_len` tokens prefilled by the previous chunks
+    fn prefilled(&self, input_len: u32) -> u32 {
+        self.offset.min(last_chunk_start(input_len))
+    }
+}
+
+/// The last input token of a request is always prefilled by the last chunk, which generates the
+/// first token of all the requests of the batch
+fn last_chunk_start(input_len: u32) -> u32 {
+    input_len.saturating_sub(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use text_generation_client::Request;
+
+    fn chunked_prefill(input_lens: Vec<u32>, chunk_len: Option<u32>) -> ChunkedPrefill {
+        let requests = (0..input_lens.len() as u64)
+            .map(|id| Request {
+                id,
+                ..Default::default()
+            })
+            .collect();
+        ChunkedPrefill {
+            batch: Batch {
+                id: 0,
+                requests,
+                size: input_lens.len() as u32,
+                max_tokens: 0,
+            },
+            input_lens,
+            chunk_len,
+            offset: 0,
+            entries: IntMap::default(),
+            span: Span::none(),
+        }
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut chunked = chunked_prefill(vec![5, 12], Some(4));
+
+        let chunk = chunked.next_chunk().unwrap();
+        let chunk_lens: Vec<Option<u32>> = chunk
+            .requests
+            .iter()
+            .map(|request| request.prefill_chunk_len)
+            .collect();
+        assert_eq!(chunk_lens, vec![Some(4), Some(4)]);
+
+        let chunk = chunked.next_chunk().unwrap();
+        let chunk_lens: Vec<Option<u32>> = chunk
+            .requests
+            .iter()
+            .map(|request| request.prefill_chunk_len)
+            .collect();
+        // The last input token of the shorter request is left for the last chunk
+        assert_eq!(chunk_lens, vec![Some(0), Some(4)]);
+        assert!(chunk.requests.iter().all(|r| r.prefill_offset == 4));
+
+        // The last 4 tokens of the longest request fit in the last chunk
+        assert!(chunked.next_chunk().is_none());
+        let (batch, _, _) = chunked.last_chunk();
+        let offsets: Vec<u32> = batch.requests.iter().map(|r| r.prefill_offset).collect();
+        assert_eq!(offsets, vec![4, 8]);
+        assert!(batch.requests.iter().all(|r| r.prefill_chunk_len.is_none()));
+    }
+
+    #[test]
+    fn test_chunks_disabled() {
+        let mut chunked = chunked_prefill(vec![30000], None);
+        assert!(chunked.next_chunk().is_none());
+        let (batch, _, _) = chunked.last_chunk();
+        assert_eq!(batch.requests[0].prefill_offset, 0);
+    }
+}
@@ -0,0 +1,173 @@
+/// Coalescing of the streamed tokens into larger chunks
+use crate::holdback::StreamedToken;
+use crate::Token;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Characters ending a sentence
+const SENTENCE_ENDS: [char; 6] = ['.', '!', '?', '。', '！', '？'];
+
+/// Size of the chunks of a stream
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StreamGranularity {
+    /// One event per token
+    #[default]
+    Token,
+    /// One event per word, the whitespace preceding a word is part of it
+    Word,
+    /// One event per sentence or line
+    Sentence,
+}
+
+/// Chunk of a stream
+///
+/// A chunk is streamed as a single token whose text is the text of its tokens, whose id and top
+/// tokens are the ones of its last token and whose log probability is the sum of the log
+/// probabilities of its tokens.
+#[derive(Debug)]
+pub(crate) struct StreamChunk {
+    pub token: Token,
+    /// Ids of the generated tokens of the chunk
+    pub token_ids: Vec<u32>,
+    pub top_tokens: Vec<Token>,
+}
+
+/// Coalesces the streamed tokens into chunks of the requested granularity
+#[derive(Debug)]
+pub(crate) struct StreamChunker {
+    granularity: StreamGranularity,
+    held: Vec<StreamedToken>,
+}
+
+impl StreamChunker {
+    pub(crate) fn new(granularity: StreamGranularity) -> Self {
+        Self {
+            granularity,
+            held: Vec::new(),
+        }
+    }
+
+    /// Add a streamed token and return the chunks that are complete
+    pub(crate) fn push(&mut self, streamed: StreamedToken) -> Vec<StreamChunk> {
+        let mut chunks = Vec::new();
+        match self.granularity {
+            StreamGranularity::Token => {
+                self.held.push(streamed);
+                chunks.extend(self.flush());
+            }
+            StreamGranularity::Word => {
+                // A token starting with whitespace starts a new word
+                if streamed.0.text.starts_with(char::is_whitespace) {
+                    chunks.extend(self.flush());
+                }
+                let ends_line = streamed.0.text.ends_with('\n');
+                self.held.push(streamed);
+                if ends_line {
+                    chunks.extend(self.flush());
+                }
+            }
+            StreamGranularity::Sentence => {
+                let text = streamed.0.text.trim_end();
+                let ends_sentence = text.ends_with(SENTENCE_ENDS) || streamed.0.text.contains('\n');
+                self.held.push(streamed);
+                if ends_sentence {
+                    chunks.extend(self.flush());
+                }
+            }
+        }
+        chunks
+    }
+
+    /// Add the last streamed token and return the last chunk
+    pub(crate) fn finish(&mut self, streamed: StreamedToken) -> StreamChunk {
+        self.held.push(streamed);
+        // Unwrap is safe as a token was just added
+        self.flush().unwrap()
+    }
+
+    /// Merge the held tokens into a chunk
+    fn flush(&mut self) -> Option<StreamChunk> {
+        let (last, top_tokens) = self.held.pop()?;
+        let mut text = String::new();
+        let mut token_ids = Vec::with_capacity(self.held.len() + 1);
+        let mut logprob = 0.0;
+        let mut special = true;
+        for (token, _) in self.held.drain(..) {
+            text.push_str(&token.text);
+            token_ids.push(token.id);
+            logprob += token.logprob;
+            special &= token.special;
+        }
+        text.push_str(&last.text);
+        token_ids.push(last.id);
+        let token = Token {
+            id: last.id,
+            text,
+            logprob: logprob + last.logprob,
+            special: special && last.special,
+        };
+        Some(StreamChunk {
+            token,
+            token_ids,
+            top_tokens,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(id: u32, text: &str) -> StreamedToken {
+        let token = Token {
+            id,
+            text: text.to_string(),
+            logprob: -0.5,
+            special: false,
+        };
+        (token, vec![])
+    }
+
+    fn texts(chunks: Vec<StreamChunk>) -> Vec<String> {
+        chunks.into_iter().map(|chunk| chunk.token.text).collect()
+    }
+
+    #[test]
+    fn test_chunker_token() {
+        let mut chunker = StreamChunker::new(StreamGranularity::Token);
+        assert_eq!(texts(chunker.push(token(0, "He"))), vec!["He"]);
+        let last = chunker.finish(token(1, "llo"));
+        assert_eq!(last.token.text, "llo");
+        assert_eq!(last.token_ids, vec![1]);
+    }
+
+    #[test]
+    fn test_chunker_word() {
+        let mut chunker = StreamChunker::new(StreamGranularity::Word);
+        assert!(chunker.push(token(0, "He")).is_empty());
+        assert!(chunker.push(token(1, "llo")).is_empty());
+        let chunks = chunker.push(token(2, " wor"));
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].token.text, "Hello");
+        assert_eq!(chunks[0].token.id, 1);
+        assert_eq!(chunks[0].token_ids, vec![0, 1]);
+        assert_eq!(chunks[0].token.logprob, -1.0);
+        assert!(chunker.push(token(3, "ld")).is_empty());
+        assert_eq!(texts(chunker.push(token(4, "!\n"))), vec![" world!\n"]);
+        assert_eq!(chunker.finish(token(5, "Bye")).token.text, "Bye");
+    }
+
+    #[test]
+    fn test_chunker_sentence() {
+        let mut chunker = StreamChunker::new(StreamGranularity::Sentence);
+        assert!(chunker.push(token(0, "Hi")).is_empty());
+        assert_eq!(texts(chunker.push(token(1, "."))), vec!["Hi."]);
+        assert!(chunker.push(token(2, " How")).is_empty());
+        assert!(chunker.push(token(3, " are")).is_empty());
+        let last = chunker.finish(token(4, " you"));
+        assert_eq!(last.token.text, " How are you");
+        assert_eq!(last.token.id, 4);
+        assert_eq!(last.token_ids, vec![2, 3, 4]);
+    }
+}
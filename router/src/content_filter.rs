@@ -0,0 +1,286 @@
+/// Content filter of the prompts, applied before the requests enter the queue
+use crate::validation::ValidationError;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+/// Timeout of the calls to the content filter endpoint
+const CONTENT_FILTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Policy a prompt violates, sent to the client with the rejection
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, ToSchema)]
+pub(crate) struct PolicyViolation {
+    /// Name of the violated policy
+    #[schema(example = "no-credentials")]
+    pub policy: String,
+    /// Why the prompt violates the policy
+    #[schema(nullable = true, example = "the prompt contains an API key")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// What a rule does with the prompts it matches
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum RuleAction {
+    Reject,
+    /// Replace the matches with `replacement`
+    Rewrite,
+}
+
+/// Rule of the rules file
+///
+/// The file contains a JSON array of rules matching either a regular expression `pattern` or
+/// any of the `keywords`, case insensitively
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    name: String,
+    #[serde(default)]
+    pattern: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    action: RuleAction,
+    #[serde(default)]
+    replacement: String,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Rule matching a regular expression
+#[derive(Clone, Debug)]
+pub(crate) struct Rule {
+    name: String,
+    regex: Regex,
+    action: RuleAction,
+    replacement: String,
+    reason: Option<String>,
+}
+
+impl Rule {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
+    }
+
+    pub(crate) fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+}
+
+/// Read the rules of a rules file
+pub(crate) fn load_rules(path: &Path) -> Result<Vec<Rule>, ContentFilterError> {
+    let content = std::fs::read_to_string(path)?;
+    let rules: Vec<RuleConfig> = serde_json::from_str(&content)?;
+    rules.into_iter().map(Rule::try_from).collect()
+}
+
+impl TryFrom<RuleConfig> for Rule {
+    type Error = ContentFilterError;
+
+    fn try_from(config: RuleConfig) -> Result<Self, Self::Error> {
+        let pattern = match (config.pattern, config.keywords.is_empty()) {
+            (Some(pattern), true) => pattern,
+            (None, false) => {
+                let keywords: Vec<String> = config
+                    .keywords
+                    .iter()
+                    .map(|keyword| format!(r"\b{}\b", regex::escape(keyword)))
+                    .collect();
+                keywords.join("|")
+            }
+            _ => {
+                return Err(ContentFilterError::Rule(
+                    config.name,
+                    "exactly one of `pattern` and `keywords` must be set".to_string(),
+                ))
+            }
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(true)
+            .build()
+            .map_err(|err| ContentFilterError::Rule(config.name.clone(), err.to_string()))?;
+        Ok(Self {
+            name: config.name,
+            regex,
+            action: config.action,
+            replacement: config.replacement,
+            reason: config.reason,
+        })
+    }
+}
+
+/// Request sent to the content filter endpoint
+#[derive(Serialize)]
+struct FilterRequest<'a> {
+    inputs: &'a str,
+    tenant: Option<&'a str>,
+}
+
+/// Decision of the content filter endpoint
+#[derive(Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum FilterResponse {
+    Allow,
+    Reject {
+        policy: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    Rewrite {
+        inputs: String,
+    },
+}
+
+/// Filter rejecting or rewriting the prompts with built-in rules and an external endpoint
+///
+/// The rules are applied first, in order, then the endpoint is called with the rewritten prompt
+#[derive(Clone, Debug)]
+pub(crate) struct ContentFilter {
+    rules: Vec<Rule>,
+    endpoint: Option<(reqwest::Client, String)>,
+}
+
+impl ContentFilter {
+    pub(crate) fn new(
+        rules_file: Option<&Path>,
+        endpoint: Option<String>,
+    ) -> Result<Self, ContentFilterError> {
+        let rules = rules_file.map(load_rules).transpose()?.unwrap_or_default();
+        Ok(Self {
+            rules,
+            endpoint: endpoint.map(|url| (reqwest::Client::new(), url)),
+        })
+    }
+
+    /// Filter the prompt of a request of `tenant`, returns the prompt to generate from
+    pub(crate) async fn apply(
+        &self,
+        mut inputs: String,
+        tenant: Option<&str>,
+    ) -> Result<String, ValidationError> {
+        for rule in &self.rules {
+            if !rule.regex.is_match(&inputs) {
+                continue;
+            }
+            metrics::increment_counter!("tgi_content_filter_match", "rule" => rule.name.clone());
+            match rule.action {
+                RuleAction::Reject => {
+                    return Err(ValidationError::PolicyViolation(PolicyViolation {
+                        policy: rule.name.clone(),
+                        reason: rule.reason.clone(),
+                    }))
+                }
+                RuleAction::Rewrite => {
+                    inputs = rule
+                        .regex
+                        .replace_all(&inputs, rule.replacement.as_str())
+                        .into_owned();
+                }
+            }
+        }
+
+        let Some((client, url)) = &self.endpoint else {
+            return Ok(inputs);
+        };
+        let request = serde_json::to_string(&FilterRequest {
+            inputs: &inputs,
+            tenant,
+        })
+        .unwrap();
+        let response = client
+            .post(url)
+            .timeout(CONTENT_FILTER_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| ValidationError::ContentFilter(err.to_string()))?
+            .text()
+            .await
+            .map_err(|err| ValidationError::ContentFilter(err.to_string()))?;
+        let response: FilterResponse = serde_json::from_str(&response)
+            .map_err(|err| ValidationError::ContentFilter(err.to_string()))?;
+        match response {
+            FilterResponse::Allow => Ok(inputs),
+            FilterResponse::Reject { policy, reason } => {
+                metrics::increment_counter!("tgi_content_filter_match", "rule" => policy.clone());
+                Err(ValidationError::PolicyViolation(PolicyViolation {
+                    policy,
+                    reason,
+                }))
+            }
+            FilterResponse::Rewrite { inputs } => Ok(inputs),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum ContentFilterError {
+    #[error("Unable to read the rules file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to parse the rules file: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("Invalid rule `{0}`: {1}")]
+    Rule(String, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn content_filter(rules: &str) -> ContentFilter {
+        let path =
+            std::env::temp_dir().join(format!("content-filter-{}.json", rand::random::<u64>()));
+        std::fs::write(&path, rules).unwrap();
+        ContentFilter::new(Some(&path), None).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_content_filter() {
+        let content_filter = content_filter(
+            r#"[
+                {"name": "no-credentials", "pattern": "sk-[a-z0-9]{8,}", "action": "reject", "reason": "the prompt contains an API key"},
+                {"name": "profanity", "keywords": ["darn"], "action": "rewrite", "replacement": "***"}
+            ]"#,
+        );
+
+        let inputs = content_filter
+            .apply("Darn, darnit".to_string(), None)
+            .await
+            .unwrap();
+        assert_eq!(inputs, "***, darnit");
+
+        match content_filter
+            .apply("My key is sk-abcdef123456".to_string(), None)
+            .await
+        {
+            Err(ValidationError::PolicyViolation(violation)) => {
+                assert_eq!(violation.policy, "no-credentials");
+                assert_eq!(
+                    violation.reason.as_deref(),
+                    Some("the prompt contains an API key")
+                );
+            }
+            _ => panic!("Unexpected policy violation"),
+        }
+    }
+
+    #[test]
+    fn test_content_filter_invalid_rule() {
+        let path =
+            std::env::temp_dir().join(format!("content-filter-{}.json", rand::random::<u64>()));
+        std::fs::write(&path, r#"[{"name": "empty", "action": "reject"}]"#).unwrap();
+        assert!(matches!(
+            ContentFilter::new(Some(&path), None),
+            Err(ContentFilterError::Rule(..))
+        ));
+    }
+}
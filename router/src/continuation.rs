@@ -0,0 +1,101 @@
+/// Paused generations continued later from their session
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Generation paused after `pause_after` tokens
+#[derive(Clone, Debug)]
+pub(crate) struct Continuation {
+    /// Session whose KV cache holds the prompt and the generated tokens
+    pub session_id: String,
+    /// Prompt followed by the generated text
+    pub inputs: String,
+    /// Tenant of the API key of the paused request
+    tenant: Option<String>,
+    paused: Instant,
+}
+
+/// Registry of the paused generations by continuation token
+///
+/// A continuation lives as long as the session it continues: it expires after `ttl` and the
+/// oldest one is evicted when the registry is full.
+#[derive(Clone, Debug)]
+pub(crate) struct Continuations {
+    continuations: Arc<Mutex<HashMap<String, Continuation>>>,
+    max_continuations: usize,
+    ttl: Duration,
+}
+
+impl Continuations {
+    pub(crate) fn new(max_continuations: usize, ttl: Duration) -> Self {
+        Self {
+            continuations: Arc::new(Mutex::new(HashMap::new())),
+            max_continuations,
+            ttl,
+        }
+    }
+
+    /// Save a paused generation, returns its continuation token
+    pub(crate) fn pause(&self, session_id: &str, inputs: String, tenant: Option<&str>) -> String {
+        let now = Instant::now();
+        let token = format!("{:032x}", rand::random::<u128>());
+        let mut continuations = self.continuations.lock().unwrap();
+        continuations.retain(|_, continuation| now.duration_since(continuation.paused) <= self.ttl);
+        if continuations.len() >= self.max_continuations {
+            let oldest = continuations
+                .iter()
+                .min_by_key(|(_, continuation)| continuation.paused)
+                .map(|(token, _)| token.clone());
+            if let Some(oldest) = oldest {
+                continuations.remove(&oldest);
+            }
+        }
+        continuations.insert(
+            token.clone(),
+            Continuation {
+                session_id: session_id.to_string(),
+                inputs,
+                tenant: tenant.map(str::to_string),
+                paused: now,
+            },
+        );
+        metrics::gauge!("tgi_continuations", continuations.len() as f64);
+        token
+    }
+
+    /// Paused generation of `token` if it was paused by `tenant` and did not expire
+    ///
+    /// A token can be continued several times, each time from the same point.
+    pub(crate) fn get(&self, token: &str, tenant: Option<&str>) -> Option<Continuation> {
+        let continuations = self.continuations.lock().unwrap();
+        continuations
+            .get(token)
+            .filter(|continuation| {
+                continuation.tenant.as_deref() == tenant
+                    && continuation.paused.elapsed() <= self.ttl
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_continuations() {
+        let continuations = Continuations::new(1, Duration::from_secs(60));
+        let first = continuations.pause("session", "Hello world".to_string(), Some("tenant"));
+        let continuation = continuations.get(&first, Some("tenant")).unwrap();
+        assert_eq!(continuation.session_id, "session");
+        assert_eq!(continuation.inputs, "Hello world");
+        // Paused by another tenant
+        assert!(continuations.get(&first, None).is_none());
+
+        // The oldest continuation is evicted when the registry is full
+        let second = continuations.pause("other", "Hi".to_string(), None);
+        assert!(continuations.get(&first, Some("tenant")).is_none());
+        assert!(continuations.get(&second, None).is_some());
+    }
+}
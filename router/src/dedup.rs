@@ -0,0 +1,362 @@
+/// Deduplication of the identical deterministic requests in flight
+use crate::auth::Tenant;
+use crate::infer::{InferError, InferStreamResponse};
+use crate::rate_limit::RateLimit;
+use crate::validation::ValidGenerateRequest;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Interval between two checks of the disconnection of the requests of a generation
+const DISCONNECTION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+type ResponseSender = flume::Sender<Result<InferStreamResponse, InferError>>;
+type ResponseReceiver = flume::Receiver<Result<InferStreamResponse, InferError>>;
+
+/// Generations in flight that identical requests can follow
+///
+/// The first request of a key is generated and its responses are fanned out to the identical
+/// requests arriving before it ends. A request joining late first receives the responses sent
+/// so far.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct InFlight {
+    generations: Arc<Mutex<HashMap<String, SharedGeneration>>>,
+}
+
+/// Responses of a generation in flight and the requests following it
+#[derive(Debug, Default)]
+struct SharedGeneration {
+    responses: Vec<InferStreamResponse>,
+    followers: Vec<ResponseSender>,
+    /// Usage of the followers, including the disconnected ones
+    charges: Vec<Charge>,
+}
+
+impl SharedGeneration {
+    /// Charge the followers for the tokens generated for them
+    fn charge_followers(&self) {
+        let generated_tokens = self
+            .responses
+            .iter()
+            .find_map(|response| match response {
+                InferStreamResponse::End { generated_text, .. } => {
+                    Some(generated_text.generated_tokens)
+                }
+                _ => None,
+            })
+            .unwrap_or_else(|| {
+                self.responses
+                    .iter()
+                    .filter(|response| matches!(response, InferStreamResponse::Intermediate { .. }))
+                    .count() as u32
+            });
+        for charge in self.charges.iter() {
+            charge.record(generated_tokens);
+        }
+    }
+}
+
+/// Tenant and rate limit a follower is charged to as if it generated the shared tokens itself
+#[derive(Debug)]
+pub(crate) struct Charge {
+    tenant: Option<Tenant>,
+    rate_limit: Option<RateLimit>,
+    input_length: u32,
+}
+
+impl Charge {
+    pub(crate) fn new(request: &ValidGenerateRequest) -> Self {
+        Self {
+            tenant: request.tenant.clone(),
+            rate_limit: request.rate_limit.clone(),
+            input_length: request.input_length,
+        }
+    }
+
+    fn record(&self, generated_tokens: u32) {
+        if let Some(tenant) = &self.tenant {
+            tenant.record_usage(self.input_length, generated_tokens);
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit.record_tokens(generated_tokens);
+        }
+    }
+}
+
+/// Remove a generation from the generations in flight when its task ends, whatever the reason
+struct Finish {
+    generations: Arc<Mutex<HashMap<String, SharedGeneration>>>,
+    key: String,
+}
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        let generation = self.generations.lock().unwrap().remove(&self.key);
+        if let Some(generation) = generation {
+            generation.charge_followers();
+        }
+    }
+}
+
+impl InFlight {
+    /// Key of the identical requests, None if the generation of `request` cannot be shared
+    ///
+    /// Only greedy requests are deterministic. Sessions and deadlines are specific to a request.
+    pub(crate) fn key(request: &ValidGenerateRequest) -> Option<String> {
+        if request.parameters.do_sample
+            || request.session_id.is_some()
+            || request.deadline.is_some()
+        {
+            return None;
+        }
+        // The seed is random when not set and unused by greedy decoding
+        let mut parameters = request.parameters.clone();
+        parameters.seed = 0;
+        Some(format!(
+            "{:?}",
+            (
+                &request.inputs,
+                request.add_special_tokens,
+                request.truncate,
+                request.decoder_input_details,
+                parameters,
+                &request.stopping_parameters,
+                request.top_n_tokens,
+                request.priority,
+                &request.choices,
+                &request.stop_regexes,
+                request.queue_events,
+            )
+        ))
+    }
+
+    /// Follow the generation of an identical request in flight
+    ///
+    /// Returns None if there is none, the caller must then `lead` the generation of `key`. The
+    /// follower is charged with `charge` when the generation ends.
+    pub(crate) fn follow(&self, key: &str, charge: Charge) -> Option<ResponseReceiver> {
+        let mut generations = self.generations.lock().unwrap();
+        let Some(generation) = generations.get_mut(key) else {
+            generations.insert(key.to_string(), SharedGeneration::default());
+            return None;
+        };
+
+        let (response_tx, response_rx) = flume::unbounded();
+        for response in generation.responses.iter() {
+            // Unwrap is safe here as the receiver is not dropped yet
+            response_tx.send(Ok(response.clone())).unwrap();
+        }
+        generation.followers.push(response_tx);
+        generation.charges.push(charge);
+        metrics::increment_counter!("tgi_request_deduplicated");
+        Some(response_rx)
+    }
+
+    /// Lead the generation of `key`, sending its responses to `response_tx` and to the requests
+    /// following it
+    ///
+    /// Returns the sender of the generated responses. The generation is cancelled once the
+    /// leader and all the followers are disconnected: the receiver of the generated responses is
+    /// then dropped.
+    pub(crate) fn lead(&self, key: String, response_tx: ResponseSender) -> ResponseSender {
+        let (generation_tx, generation_rx) = flume::unbounded();
+        let generations = self.generations.clone();
+
+        tokio::spawn(async move {
+            let finish = Finish {
+                generations: generations.clone(),
+                key: key.clone(),
+            };
+            let mut interval = tokio::time::interval(DISCONNECTION_POLL_INTERVAL);
+            loop {
+                let response = tokio::select! {
+                    response = generation_rx.recv_async() => match response {
+                        Ok(response) => response,
+                        // The batching task dropped the generation without ending it
+                        Err(_) => break,
+                    },
+                    _ = interval.tick() => {
+                        let mut generations = generations.lock().unwrap();
+                        let Some(generation) = generations.get_mut(&key) else {
+                            break;
+                        };
+                        generation
+                            .followers
+                            .retain(|follower| !follower.is_disconnected());
+                        if response_tx.is_disconnected() && generation.followers.is_empty() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                let mut generations = generations.lock().unwrap();
+                let generation = generations
+                    .get_mut(&key)
+                    .expect("Generation not found in flight. This is a bug.");
+
+                let done = match &response {
+                    Ok(response) => {
+                        generation.responses.push(response.clone());
+                        generation
+                            .followers
+                            .retain(|follower| follower.send(Ok(response.clone())).is_ok());
+                        matches!(response, InferStreamResponse::End { .. })
+                    }
+                    Err(err) => {
+                        for follower in generation.followers.iter() {
+                            let _ = follower.send(Err(shared_error(err)));
+                        }
+                        true
+                    }
+                };
+                let _ = response_tx.send(response);
+
+                if done || (response_tx.is_disconnected() && generation.followers.is_empty()) {
+                    break;
+                }
+            }
+            // Remove the generation before the responses receiver is dropped
+            drop(finish);
+        });
+
+        generation_tx
+    }
+}
+
+/// Copy of an error of the batching task sent to the followers of the generation
+fn shared_error(err: &InferError) -> InferError {
+    match err {
+        InferError::GenerationError(message) => InferError::GenerationError(message.clone()),
+        InferError::IncompleteGeneration => InferError::IncompleteGeneration,
+        InferError::Timeout => InferError::Timeout,
+        InferError::QueueTimeout(max_queue_duration) => {
+            InferError::QueueTimeout(*max_queue_duration)
+        }
+        err => InferError::GenerationError(err.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_flight_follow() {
+        let in_flight = InFlight::default();
+        assert!(in_flight.follow("key", charge(None)).is_none());
+
+        let (leader_tx, leader_rx) = flume::unbounded();
+        let generation_tx = in_flight.lead("key".to_string(), leader_tx);
+        generation_tx
+            .send(Ok(InferStreamResponse::Queued {
+                position: 1,
+                estimated_wait: None,
+            }))
+            .unwrap();
+        leader_rx.recv_async().await.unwrap().unwrap();
+
+        // A late follower receives the responses sent so far
+        let follower_rx = in_flight.follow("key", charge(None)).unwrap();
+        assert!(matches!(
+            follower_rx.recv_async().await,
+            Ok(Ok(InferStreamResponse::Queued { position: 1, .. }))
+        ));
+
+        generation_tx
+            .send(Err(InferError::GenerationError("failed".to_string())))
+            .unwrap();
+        assert!(matches!(
+            leader_rx.recv_async().await,
+            Ok(Err(InferError::GenerationError(_)))
+        ));
+        assert!(matches!(
+            follower_rx.recv_async().await,
+            Ok(Err(InferError::GenerationError(_)))
+        ));
+
+        // The generation is done, the next identical request leads a new one
+        assert!(in_flight.follow("key", charge(None)).is_none());
+    }
+
+    fn charge(tenant: Option<Tenant>) -> Charge {
+        Charge {
+            tenant,
+            rate_limit: None,
+            input_length: 3,
+        }
+    }
+
+    fn end(generated_tokens: u32) -> Result<InferStreamResponse, InferError> {
+        Ok(InferStreamResponse::End {
+            token: crate::Token {
+                id: 1,
+                text: ".".to_string(),
+                logprob: 0.0,
+                special: false,
+            },
+            top_tokens: vec![],
+            generated_text: text_generation_client::GeneratedText {
+                text: "Done.".to_string(),
+                generated_tokens,
+                finish_reason: text_generation_client::FinishReason::EosToken as i32,
+                seed: None,
+            },
+            queued: tokio::time::Instant::now(),
+            start: tokio::time::Instant::now(),
+            continuation: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_charge_followers() {
+        let in_flight = InFlight::default();
+        assert!(in_flight.follow("key", charge(None)).is_none());
+        let (leader_tx, leader_rx) = flume::unbounded();
+        let generation_tx = in_flight.lead("key".to_string(), leader_tx);
+
+        let tenant = Tenant::test("acme", 1);
+        let follower_rx = in_flight
+            .follow("key", charge(Some(tenant.clone())))
+            .unwrap();
+        generation_tx.send(end(5)).unwrap();
+        leader_rx.recv_async().await.unwrap().unwrap();
+        follower_rx.recv_async().await.unwrap().unwrap();
+
+        // The follower is charged for the shared tokens
+        let usage = tenant.usage();
+        assert_eq!(usage.requests, 1);
+        assert_eq!(usage.prompt_tokens, 3);
+        assert_eq!(usage.generated_tokens, 5);
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_dropped() {
+        let in_flight = InFlight::default();
+        assert!(in_flight.follow("key", charge(None)).is_none());
+        let (leader_tx, _leader_rx) = flume::unbounded();
+        let generation_tx = in_flight.lead("key".to_string(), leader_tx);
+        let follower_rx = in_flight.follow("key", charge(None)).unwrap();
+
+        // The generation is dropped without ending
+        drop(generation_tx);
+        assert!(follower_rx.recv_async().await.is_err());
+        assert!(in_flight.follow("key", charge(None)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_cancelled() {
+        let in_flight = InFlight::default();
+        assert!(in_flight.follow("key", charge(None)).is_none());
+        let (leader_tx, leader_rx) = flume::unbounded();
+        let generation_tx = in_flight.lead("key".to_string(), leader_tx);
+        let follower_rx = in_flight.follow("key", charge(None)).unwrap();
+
+        // The generation is cancelled without waiting for its next response
+        drop(leader_rx);
+        drop(follower_rx);
+        tokio::time::sleep(3 * DISCONNECTION_POLL_INTERVAL).await;
+        assert!(generation_tx.is_disconnected());
+        assert!(in_flight.follow("key", charge(None)).is_none());
+    }
+}
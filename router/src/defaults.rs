@@ -0,0 +1,163 @@
+/// Operator defaults of the generation parameters
+use crate::GenerateParameters;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use thiserror::Error;
+
+/// Interval between two checks of the defaults file
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Content of the defaults file
+///
+/// The file contains a JSON object, every field is optional
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct DefaultParameters {
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    max_new_tokens: Option<u32>,
+    stop: Option<Vec<String>>,
+}
+
+impl DefaultParameters {
+    /// Set the parameters the request left unset
+    fn apply(&self, parameters: &mut GenerateParameters) {
+        if parameters.temperature.is_none() {
+            parameters.temperature = self.temperature;
+        }
+        if parameters.top_p.is_none() {
+            parameters.top_p = self.top_p;
+        }
+        if parameters.max_new_tokens.is_none() {
+            parameters.max_new_tokens = self.max_new_tokens;
+        }
+        if parameters.stop.is_empty() {
+            if let Some(stop) = &self.stop {
+                parameters.stop = stop.clone();
+            }
+        }
+    }
+}
+
+/// Default generation parameters loaded from a file
+///
+/// The file is reloaded when it is modified or when the router receives `SIGHUP`, so that
+/// operators can tune the defaults without restarting the router
+#[derive(Clone, Debug)]
+pub(crate) struct ParameterDefaults {
+    path: PathBuf,
+    defaults: Arc<RwLock<DefaultParameters>>,
+}
+
+impl ParameterDefaults {
+    pub(crate) fn load(path: PathBuf) -> Result<Self, DefaultsError> {
+        let defaults = Self {
+            path,
+            defaults: Arc::default(),
+        };
+        defaults.reload()?;
+        Ok(defaults)
+    }
+
+    /// Read the defaults file and replace the current defaults
+    fn reload(&self) -> Result<(), DefaultsError> {
+        let content = std::fs::read_to_string(&self.path)?;
+        let defaults: DefaultParameters = serde_json::from_str(&content)?;
+        tracing::info!("Loaded default generation parameters: {defaults:?}");
+        *self.defaults.write().unwrap() = defaults;
+        Ok(())
+    }
+
+    /// Set the parameters the request left unset to their default
+    pub(crate) fn apply(&self, parameters: &mut GenerateParameters) {
+        self.defaults.read().unwrap().apply(parameters);
+    }
+
+    /// Reload the defaults file in the background when it is modified or on `SIGHUP`
+    pub(crate) fn spawn_reload_task(&self) {
+        let defaults = self.clone();
+        tokio::spawn(async move {
+            let modified = |path: &PathBuf| std::fs::metadata(path).and_then(|m| m.modified());
+            let mut last_modified = modified(&defaults.path).ok();
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+            #[cfg(unix)]
+            let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .expect("failed to install SIGHUP handler");
+
+            loop {
+                #[cfg(unix)]
+                let signaled = tokio::select! {
+                    _ = interval.tick() => false,
+                    _ = hangup.recv() => true,
+                };
+                #[cfg(not(unix))]
+                let signaled = {
+                    interval.tick().await;
+                    false
+                };
+
+                let current = modified(&defaults.path).ok();
+                if signaled || current != last_modified {
+                    last_modified = current;
+                    // Keep the current defaults if the new file is invalid
+                    if let Err(err) = defaults.reload() {
+                        tracing::error!("Could not reload the default parameters: {err}");
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum DefaultsError {
+    #[error("Unable to read the default parameters file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to parse the default parameters file: {0}")]
+    Parse(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::default_parameters;
+
+    fn defaults_file(content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("defaults-{}.json", rand::random::<u64>()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_parameter_defaults() {
+        let path = defaults_file(r#"{"temperature": 0.7, "max_new_tokens": 64, "stop": ["</s>"]}"#);
+        let defaults = ParameterDefaults::load(path.clone()).unwrap();
+
+        let mut parameters = default_parameters();
+        parameters.max_new_tokens = Some(8);
+        defaults.apply(&mut parameters);
+        assert_eq!(parameters.temperature, Some(0.7));
+        assert_eq!(parameters.top_p, None);
+        // Parameters set by the request are kept
+        assert_eq!(parameters.max_new_tokens, Some(8));
+        assert_eq!(parameters.stop, vec!["</s>".to_string()]);
+
+        // An invalid file keeps the current defaults
+        std::fs::write(&path, r#"{"temprature": 0.5}"#).unwrap();
+        assert!(matches!(defaults.reload(), Err(DefaultsError::Parse(_))));
+        let mut parameters = default_parameters();
+        defaults.apply(&mut parameters);
+        assert_eq!(parameters.temperature, Some(0.7));
+
+        std::fs::write(&path, r#"{"top_p": 0.9}"#).unwrap();
+        defaults.reload().unwrap();
+        let mut parameters = default_parameters();
+        defaults.apply(&mut parameters);
+        assert_eq!(parameters.temperature, None);
+        assert_eq!(parameters.top_p, Some(0.9));
+        std::fs::remove_file(path).unwrap();
+    }
+}
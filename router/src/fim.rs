@@ -0,0 +1,85 @@
+/// Fill-in-the-middle prompts of code completion models
+use tokenizers::Tokenizer;
+
+/// Sentinel tokens of the fill-in-the-middle formats detected in the tokenizer vocabulary
+const KNOWN_FIM_TOKENS: [(&str, &str, &str); 3] = [
+    // StarCoder, SantaCoder, StableCode
+    ("<fim_prefix>", "<fim_suffix>", "<fim_middle>"),
+    // CodeGemma, Qwen2.5-Coder
+    ("<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"),
+    // DeepSeek-Coder
+    ("<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"),
+];
+
+/// Sentinel tokens of a model trained to fill in the middle
+///
+/// The prompt is assembled in the prefix-suffix-middle order: the model generates the text
+/// between the prefix and the suffix after the middle token
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FimTokens {
+    prefix: String,
+    suffix: String,
+    middle: String,
+}
+
+impl FimTokens {
+    /// Parse `<prefix token>,<suffix token>,<middle token>`
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut tokens = value.split(',').map(str::trim);
+        let (Some(prefix), Some(suffix), Some(middle), None) =
+            (tokens.next(), tokens.next(), tokens.next(), tokens.next())
+        else {
+            return None;
+        };
+        if prefix.is_empty() || suffix.is_empty() || middle.is_empty() {
+            return None;
+        }
+        Some(Self {
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+            middle: middle.to_string(),
+        })
+    }
+
+    /// Sentinel tokens of a known fill-in-the-middle format of the tokenizer vocabulary
+    pub fn detect(tokenizer: &Tokenizer) -> Option<Self> {
+        KNOWN_FIM_TOKENS
+            .iter()
+            .find(|(prefix, suffix, middle)| {
+                [prefix, suffix, middle]
+                    .iter()
+                    .all(|token| tokenizer.token_to_id(token).is_some())
+            })
+            .map(|(prefix, suffix, middle)| Self {
+                prefix: prefix.to_string(),
+                suffix: suffix.to_string(),
+                middle: middle.to_string(),
+            })
+    }
+
+    /// Prompt generating the text between `prefix` and `suffix`
+    pub(crate) fn prompt(&self, prefix: &str, suffix: &str) -> String {
+        format!(
+            "{}{prefix}{}{suffix}{}",
+            self.prefix, self.suffix, self.middle
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fim_tokens() {
+        let fim_tokens = FimTokens::parse("<PRE>, <SUF>, <MID>").unwrap();
+        assert_eq!(
+            fim_tokens.prompt("def add(a, b):\n", "\n    return c"),
+            "<PRE>def add(a, b):\n<SUF>\n    return c<MID>"
+        );
+
+        assert_eq!(FimTokens::parse("<PRE>,<SUF>"), None);
+        assert_eq!(FimTokens::parse("<PRE>,<SUF>,<MID>,<EOT>"), None);
+        assert_eq!(FimTokens::parse("<PRE>,,<MID>"), None);
+    }
+}
@@ -0,0 +1,624 @@
+/// Compilation of GBNF grammars into the pushdown automaton of the shards
+use crate::grammar::GrammarError;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Maximum length of a GBNF grammar
+const MAX_GBNF_LENGTH: usize = 16384;
+/// Maximum number of elements of the compiled grammar, repetitions are expanded
+pub(crate) const MAX_ELEMENTS: usize = 65536;
+/// Maximum bound of a `{m,n}` repetition
+const MAX_REPETITIONS: u32 = 1024;
+/// Maximum depth of nested groups
+const MAX_DEPTH: usize = 32;
+/// Largest Unicode code point
+const MAX_CODE_POINT: u32 = 0x10FFFF;
+
+/// Element of a sequence of a compiled rule
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Element {
+    /// One character in the inclusive ranges of code points, or out of them when negated
+    Chars {
+        ranges: Vec<(u32, u32)>,
+        negated: bool,
+    },
+    /// Reference to a rule by index
+    Rule(usize),
+}
+
+/// GBNF grammar compiled into the rules of a pushdown automaton
+///
+/// Every rule is a list of alternatives, each a sequence of characters and references to rules.
+/// The shards keep the stacks of positions in the rules being matched and only allow the tokens
+/// whose characters advance one of them. Groups and repetitions are compiled into generated rules
+/// and the grammar must not be left recursive.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct CompiledGrammar {
+    /// Index of the `root` rule
+    pub root: usize,
+    pub rules: Vec<Vec<Vec<Element>>>,
+}
+
+/// Compile a GBNF grammar (llama.cpp syntax) into the JSON representation of its pushdown
+/// automaton
+pub(crate) fn gbnf_to_automaton(grammar: &str) -> Result<String, GrammarError> {
+    let compiled = parse_gbnf(grammar)?;
+    // Serializing plain structs cannot fail
+    Ok(serde_json::to_string(&compiled).unwrap())
+}
+
+pub(crate) fn parse_gbnf(grammar: &str) -> Result<CompiledGrammar, GrammarError> {
+    if grammar.len() > MAX_GBNF_LENGTH {
+        return Err(GrammarError::InvalidGbnf(format!(
+            "grammar must have less than {MAX_GBNF_LENGTH} characters. Given: {}",
+            grammar.len()
+        )));
+    }
+    let mut parser = Parser {
+        src: grammar,
+        pos: 0,
+        names: HashMap::new(),
+        rules: Vec::new(),
+        rule_names: Vec::new(),
+        current: String::new(),
+        elements: 0,
+    };
+    parser.parse()?;
+
+    let root = *parser
+        .names
+        .get("root")
+        .ok_or_else(|| GrammarError::InvalidGbnf("missing `root` rule".into()))?;
+    let mut rules = Vec::with_capacity(parser.rules.len());
+    for (rule, name) in parser.rules.into_iter().zip(&parser.rule_names) {
+        rules.push(
+            rule.ok_or_else(|| GrammarError::InvalidGbnf(format!("undefined rule `{name}`")))?,
+        );
+    }
+    if let Some(rule) = left_recursive_rule(&rules) {
+        return Err(GrammarError::InvalidGbnf(format!(
+            "rule `{}` is left recursive",
+            parser.rule_names[rule]
+        )));
+    }
+    Ok(CompiledGrammar { root, rules })
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    /// Byte offset in `src`
+    pos: usize,
+    /// Index of the named rules
+    names: HashMap<String, usize>,
+    /// Alternatives of the rules, `None` until a named rule is defined
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+    /// Names of the rules, generated rules are named after the rule they are part of
+    rule_names: Vec<String>,
+    /// Name of the rule being parsed
+    current: String,
+    /// Number of elements of all the rules
+    elements: usize,
+}
+
+impl Parser<'_> {
+    fn parse(&mut self) -> Result<(), GrammarError> {
+        loop {
+            self.skip_space(true);
+            if self.peek().is_none() {
+                return Ok(());
+            }
+            let name = self.parse_name()?;
+            self.skip_space(false);
+            if !self.src[self.pos..].starts_with("::=") {
+                return Err(self.error("expected `::=`"));
+            }
+            self.pos += 3;
+            self.skip_space(true);
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                return Err(self.error(&format!("rule `{name}` is defined twice")));
+            }
+            self.current = name;
+            let alternatives = self.parse_alternatives(false, 0)?;
+            self.rules[id] = Some(alternatives);
+            // A rule ends at the end of its line
+            match self.peek() {
+                None | Some('\n') => {}
+                Some(c) => return Err(self.error(&format!("unexpected `{c}`"))),
+            }
+        }
+    }
+
+    fn parse_alternatives(
+        &mut self,
+        nested: bool,
+        depth: usize,
+    ) -> Result<Vec<Vec<Element>>, GrammarError> {
+        let mut alternatives = vec![self.parse_sequence(nested, depth)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            self.skip_space(true);
+            alternatives.push(self.parse_sequence(nested, depth)?);
+        }
+        Ok(alternatives)
+    }
+
+    /// Parse a sequence, ending at a newline outside of groups
+    fn parse_sequence(&mut self, nested: bool, depth: usize) -> Result<Vec<Element>, GrammarError> {
+        let mut sequence = Vec::new();
+        // Start of the last element, the operand of a repetition
+        let mut last_start = None;
+        while let Some(c) = self.peek() {
+            let start = sequence.len();
+            match c {
+                '"' => {
+                    self.pos += 1;
+                    loop {
+                        match self.peek() {
+                            None => return Err(self.error("unterminated string")),
+                            Some('"') => {
+                                self.pos += 1;
+                                break;
+                            }
+                            Some(_) => {
+                                let c = self.parse_char()?;
+                                sequence.push(Element::Chars {
+                                    ranges: vec![(c, c)],
+                                    negated: false,
+                                });
+                            }
+                        }
+                    }
+                }
+                '[' => {
+                    self.pos += 1;
+                    let element = self.parse_char_class()?;
+                    sequence.push(element);
+                }
+                '.' => {
+                    self.pos += 1;
+                    sequence.push(Element::Chars {
+                        ranges: vec![(0, MAX_CODE_POINT)],
+                        negated: false,
+                    });
+                }
+                '(' => {
+                    if depth >= MAX_DEPTH {
+                        return Err(self.error(&format!(
+                            "groups are nested more than {MAX_DEPTH} levels deep"
+                        )));
+                    }
+                    self.pos += 1;
+                    self.skip_space(true);
+                    let alternatives = self.parse_alternatives(true, depth + 1)?;
+                    if self.peek() != Some(')') {
+                        return Err(self.error("expected `)`"));
+                    }
+                    self.pos += 1;
+                    let id = self.generated_rule(alternatives);
+                    sequence.push(Element::Rule(id));
+                }
+                '*' | '+' | '?' | '{' => {
+                    let Some(last_start) = last_start else {
+                        return Err(self.error(&format!("`{c}` must follow an element")));
+                    };
+                    self.pos += 1;
+                    let (min, max) = match c {
+                        '*' => (0, None),
+                        '+' => (1, None),
+                        '?' => (0, Some(1)),
+                        _ => self.parse_bounds(nested)?,
+                    };
+                    let operand = sequence.split_off(last_start);
+                    let repeated = self.repetition(operand, min, max)?;
+                    sequence.extend(repeated);
+                    self.skip_space(nested);
+                    continue;
+                }
+                c if is_name_char(c) => {
+                    let name = self.parse_name()?;
+                    let id = self.rule_id(&name);
+                    sequence.push(Element::Rule(id));
+                }
+                _ => break,
+            }
+            last_start = Some(start);
+            self.add_elements(sequence.len() - start)?;
+            self.skip_space(nested);
+        }
+        Ok(sequence)
+    }
+
+    /// Parse the character class following `[`
+    fn parse_char_class(&mut self) -> Result<Element, GrammarError> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated character class")),
+                Some(']') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(_) => {
+                    let start = self.parse_char()?;
+                    let end = if self.src[self.pos..].starts_with('-')
+                        && !self.src[self.pos..].starts_with("-]")
+                    {
+                        self.pos += 1;
+                        self.parse_char()?
+                    } else {
+                        start
+                    };
+                    if end < start {
+                        return Err(self.error("invalid character range"));
+                    }
+                    ranges.push((start, end));
+                }
+            }
+        }
+        if ranges.is_empty() {
+            return Err(self.error("empty character class"));
+        }
+        Ok(Element::Chars { ranges, negated })
+    }
+
+    /// Parse the `m}`, `m,}` or `m,n}` bounds following `{`
+    fn parse_bounds(&mut self, nested: bool) -> Result<(u32, Option<u32>), GrammarError> {
+        self.skip_space(nested);
+        let min = self.parse_integer()?;
+        self.skip_space(nested);
+        let max = if self.peek() == Some(',') {
+            self.pos += 1;
+            self.skip_space(nested);
+            match self.peek() {
+                Some(c) if c.is_ascii_digit() => Some(self.parse_integer()?),
+                _ => None,
+            }
+        } else {
+            Some(min)
+        };
+        self.skip_space(nested);
+        if self.peek() != Some('}') {
+            return Err(self.error("expected `}`"));
+        }
+        self.pos += 1;
+        if max.map_or(false, |max| max < min) {
+            return Err(self.error("repetition maximum must be >= minimum"));
+        }
+        if max.unwrap_or(min) > MAX_REPETITIONS {
+            return Err(self.error(&format!("repetition bounds must be <= {MAX_REPETITIONS}")));
+        }
+        Ok((min, max))
+    }
+
+    /// Elements repeating `operand` between `min` and `max` times
+    fn repetition(
+        &mut self,
+        operand: Vec<Element>,
+        min: u32,
+        max: Option<u32>,
+    ) -> Result<Vec<Element>, GrammarError> {
+        let repetitions = max.unwrap_or(min + 1) as usize;
+        self.add_elements(operand.len().saturating_mul(repetitions))?;
+
+        let mut repeated = Vec::with_capacity(operand.len() * min as usize + 1);
+        for _ in 0..min {
+            repeated.extend(operand.iter().cloned());
+        }
+        let tail = match max {
+            // tail ::= operand tail | ""
+            None => {
+                let id = self.generated_rule(Vec::new());
+                let mut alternative = operand;
+                alternative.push(Element::Rule(id));
+                self.rules[id] = Some(vec![alternative, Vec::new()]);
+                Some(id)
+            }
+            // tail ::= operand tail' | "", nested (max - min) times
+            Some(max) => {
+                let mut tail = None;
+                for _ in min..max {
+                    let mut alternative = operand.clone();
+                    alternative.extend(tail.map(Element::Rule));
+                    tail = Some(self.generated_rule(vec![alternative, Vec::new()]));
+                }
+                tail
+            }
+        };
+        repeated.extend(tail.map(Element::Rule));
+        Ok(repeated)
+    }
+
+    /// Parse a character of a string or a character class, with its escape sequence
+    fn parse_char(&mut self) -> Result<u32, GrammarError> {
+        let c = self
+            .bump()
+            .ok_or_else(|| self.error("unexpected end of grammar"))?;
+        if c != '\\' {
+            return Ok(c as u32);
+        }
+        let escaped = self
+            .bump()
+            .ok_or_else(|| self.error("unexpected end of grammar"))?;
+        match escaped {
+            'n' => Ok('\n' as u32),
+            'r' => Ok('\r' as u32),
+            't' => Ok('\t' as u32),
+            '\\' | '"' | '[' | ']' | '-' => Ok(escaped as u32),
+            'x' => self.parse_hex(2),
+            'u' => self.parse_hex(4),
+            'U' => self.parse_hex(8),
+            _ => Err(self.error(&format!("unknown escape `\\{escaped}`"))),
+        }
+    }
+
+    fn parse_hex(&mut self, digits: usize) -> Result<u32, GrammarError> {
+        let mut value = 0u32;
+        for _ in 0..digits {
+            let digit = self
+                .bump()
+                .and_then(|c| c.to_digit(16))
+                .ok_or_else(|| self.error("invalid hexadecimal escape"))?;
+            value = value * 16 + digit;
+        }
+        if value > MAX_CODE_POINT {
+            return Err(self.error("escaped character is not a Unicode code point"));
+        }
+        Ok(value)
+    }
+
+    fn parse_integer(&mut self) -> Result<u32, GrammarError> {
+        let start = self.pos;
+        while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.src[start..self.pos]
+            .parse()
+            .map_err(|_| self.error("expected an integer"))
+    }
+
+    fn parse_name(&mut self) -> Result<String, GrammarError> {
+        let start = self.pos;
+        while self.peek().map_or(false, is_name_char) {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a rule name"));
+        }
+        Ok(self.src[start..self.pos].to_string())
+    }
+
+    /// Skip the spaces and comments, and the newlines if `newlines`
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\n' if newlines => self.pos += 1,
+                '#' => {
+                    while self.peek().map_or(false, |c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    /// Index of a named rule, which may be defined later
+    fn rule_id(&mut self, name: &str) -> usize {
+        if let Some(&id) = self.names.get(name) {
+            return id;
+        }
+        let id = self.rules.len();
+        self.rules.push(None);
+        self.rule_names.push(name.to_string());
+        self.names.insert(name.to_string(), id);
+        id
+    }
+
+    fn generated_rule(&mut self, alternatives: Vec<Vec<Element>>) -> usize {
+        self.rules.push(Some(alternatives));
+        self.rule_names.push(self.current.clone());
+        self.rules.len() - 1
+    }
+
+    fn add_elements(&mut self, elements: usize) -> Result<(), GrammarError> {
+        self.elements = self.elements.saturating_add(elements);
+        if self.elements > MAX_ELEMENTS {
+            return Err(GrammarError::InvalidGbnf(format!(
+                "grammar must have less than {MAX_ELEMENTS} elements once repetitions are expanded"
+            )));
+        }
+        Ok(())
+    }
+
+    fn error(&self, message: &str) -> GrammarError {
+        let before = &self.src[..self.pos];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+        GrammarError::InvalidGbnf(format!("{message} at line {line}, column {column}"))
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+/// A rule of a left recursion, which the pushdown automaton cannot expand
+fn left_recursive_rule(rules: &[Vec<Vec<Element>>]) -> Option<usize> {
+    // Rules matching the empty string
+    let mut nullable = vec![false; rules.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (id, alternatives) in rules.iter().enumerate() {
+            if !nullable[id]
+                && alternatives.iter().any(|sequence| {
+                    sequence
+                        .iter()
+                        .all(|element| matches!(element, Element::Rule(rule) if nullable[*rule]))
+                })
+            {
+                nullable[id] = true;
+                changed = true;
+            }
+        }
+    }
+
+    // Rules that can start a rule
+    let leftmost: Vec<Vec<usize>> = rules
+        .iter()
+        .map(|alternatives| {
+            let mut leftmost = Vec::new();
+            for sequence in alternatives {
+                for element in sequence {
+                    match element {
+                        Element::Rule(rule) => {
+                            leftmost.push(*rule);
+                            if !nullable[*rule] {
+                                break;
+                            }
+                        }
+                        Element::Chars { .. } => break,
+                    }
+                }
+            }
+            leftmost
+        })
+        .collect();
+
+    // Depth first search of a cycle: 0 unvisited, 1 on the stack, 2 done
+    let mut state = vec![0u8; rules.len()];
+    for start in 0..rules.len() {
+        if state[start] != 0 {
+            continue;
+        }
+        state[start] = 1;
+        let mut stack = vec![(start, 0)];
+        while let Some((rule, next)) = stack.last_mut() {
+            let rule = *rule;
+            match leftmost[rule].get(*next) {
+                Some(&child) => {
+                    *next += 1;
+                    match state[child] {
+                        0 => {
+                            state[child] = 1;
+                            stack.push((child, 0));
+                        }
+                        1 => return Some(child),
+                        _ => {}
+                    }
+                }
+                None => {
+                    state[rule] = 2;
+                    stack.pop();
+                }
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literal(c: char) -> Element {
+        Element::Chars {
+            ranges: vec![(c as u32, c as u32)],
+            negated: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_gbnf() {
+        let grammar = parse_gbnf(
+            r#"
+# Yes or no answer
+root ::= answer "."
+answer ::= "y" | [^a-z\n]
+"#,
+        )
+        .unwrap();
+        assert_eq!(grammar.root, 0);
+        assert_eq!(
+            grammar.rules,
+            vec![
+                vec![vec![Element::Rule(1), literal('.')]],
+                vec![
+                    vec![literal('y')],
+                    vec![Element::Chars {
+                        ranges: vec![('a' as u32, 'z' as u32), (10, 10)],
+                        negated: true,
+                    }],
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_gbnf_repetitions() {
+        let grammar = parse_gbnf(r#"root ::= ("a" | "b")+ "c"{1,2}"#).unwrap();
+        // root ::= group group* "c" "c"?
+        assert_eq!(
+            grammar.rules[0],
+            vec![vec![
+                Element::Rule(1),
+                Element::Rule(1),
+                Element::Rule(2),
+                literal('c'),
+                Element::Rule(3),
+            ]]
+        );
+        assert_eq!(
+            grammar.rules[1],
+            vec![vec![literal('a')], vec![literal('b')]]
+        );
+        assert_eq!(
+            grammar.rules[2],
+            vec![vec![Element::Rule(1), Element::Rule(2)], vec![]]
+        );
+        assert_eq!(grammar.rules[3], vec![vec![literal('c')], vec![]]);
+
+        // Multi-line groups
+        assert!(parse_gbnf("root ::= (\n  \"a\"\n  | \"b\"\n)").is_ok());
+        assert!(gbnf_to_automaton("root ::= [0-9]{2}")
+            .unwrap()
+            .starts_with(r#"{"root":0,"rules":[[[{"chars":{"ranges":[[48,57]],"negated":false}}"#));
+    }
+
+    #[test]
+    fn test_parse_gbnf_invalid() {
+        // Missing root
+        assert!(parse_gbnf(r#"answer ::= "yes""#).is_err());
+        // Undefined rule
+        assert!(parse_gbnf("root ::= answer").is_err());
+        // Left recursion, also through a rule matching the empty string
+        assert!(parse_gbnf(r#"root ::= root "a" | "a""#).is_err());
+        assert!(parse_gbnf("root ::= empty root \"a\" | \"a\"\nempty ::= \"\"").is_err());
+        // Syntax
+        assert!(parse_gbnf(r#"root ::= "a"#).is_err());
+        assert!(parse_gbnf(r#"root ::= [z-a]"#).is_err());
+        assert!(parse_gbnf(r#"root ::= *"a""#).is_err());
+        assert!(parse_gbnf(r#"root ::= "a"{3,2}"#).is_err());
+        assert!(parse_gbnf(r#"root "a""#).is_err());
+        // Too large once expanded
+        let grammar = format!("root ::= {}", [r#""abcdefghij"{1000}"#; 7].join(" "));
+        assert!(parse_gbnf(&grammar).is_err());
+    }
+}
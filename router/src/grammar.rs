@@ -0,0 +1,738 @@
+/// Grammar compilation logic
+use crate::gbnf::{CompiledGrammar, Element, MAX_ELEMENTS};
+use regex::RegexBuilder;
+use regex_syntax::hir::{Class, Hir, HirKind, Literal, Look};
+use regex_syntax::ParserBuilder;
+use serde_json::{Map, Value};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use thiserror::Error;
+
+/// Whitespace allowed between JSON tokens
+const WHITESPACE: &str = r"[\n ]*";
+/// JSON string without control characters
+const STRING: &str = r#""(?:[^"\\\x00-\x1f\x7f-\x9f]|\\.)*""#;
+const STRING_INNER: &str = r#"(?:[^"\\\x00-\x1f\x7f-\x9f]|\\.)"#;
+const INTEGER: &str = r"-?(?:0|[1-9][0-9]*)";
+const NUMBER: &str = r"-?(?:0|[1-9][0-9]*)(?:\.[0-9]+)?(?:[eE][+-][0-9]+)?";
+const BOOLEAN: &str = r"(?:true|false)";
+const NULL: &str = r"null";
+
+/// Maximum number of strings of a choice
+const MAX_CHOICES: usize = 256;
+/// Maximum depth of nested schemas
+const MAX_DEPTH: usize = 32;
+/// Maximum length of a user provided regular expression
+const MAX_REGEX_LENGTH: usize = 4096;
+/// Maximum size of the compiled regular expression, larger patterns create huge automatons
+/// in the shards
+const MAX_REGEX_SIZE: usize = 1 << 20;
+
+/// Compile a JSON schema into a regular expression matching all the JSON documents valid
+/// against the schema
+///
+/// The regular expression is compiled with [`regex_to_automaton`] before being sent to the shards
+pub(crate) fn json_schema_to_regex(schema: &Value) -> Result<String, GrammarError> {
+    SchemaCompiler { root: schema }.compile(schema, 0)
+}
+
+/// Check that a user provided regular expression can be used to constrain the generation
+///
+/// Look-arounds and back-references are rejected as they cannot be compiled to an automaton,
+/// as well as patterns whose automaton would be too large
+pub(crate) fn validate_regex(pattern: &str) -> Result<(), GrammarError> {
+    if pattern.is_empty() {
+        return Err(GrammarError::InvalidRegex("pattern cannot be empty".into()));
+    }
+    if pattern.len() > MAX_REGEX_LENGTH {
+        return Err(GrammarError::InvalidRegex(format!(
+            "pattern must have less than {MAX_REGEX_LENGTH} characters. Given: {}",
+            pattern.len()
+        )));
+    }
+    RegexBuilder::new(pattern)
+        .size_limit(MAX_REGEX_SIZE)
+        .nest_limit(MAX_DEPTH as u32)
+        .build()
+        .map_err(|err| GrammarError::InvalidRegex(err.to_string()))?;
+    Ok(())
+}
+
+/// Compile a regular expression into the JSON representation of the pushdown automaton of the
+/// shards, the same one as GBNF grammars
+///
+/// The whole generation must match the pattern, so anchors are always satisfied at its bounds
+/// and are ignored. Word boundaries cannot be checked one character at a time and are rejected.
+pub(crate) fn regex_to_automaton(pattern: &str) -> Result<String, GrammarError> {
+    let hir = ParserBuilder::new()
+        .nest_limit(MAX_DEPTH as u32)
+        .build()
+        .parse(pattern)
+        .map_err(|err| GrammarError::InvalidRegex(err.to_string()))?;
+
+    // The root rule is the first one
+    let mut compiler = RegexCompiler {
+        rules: vec![Vec::new()],
+        elements: 0,
+    };
+    let root = compiler.sequence(&hir)?;
+    compiler.rules[0] = vec![root];
+
+    let compiled = CompiledGrammar {
+        root: 0,
+        rules: compiler.rules,
+    };
+    // Serializing plain structs cannot fail
+    Ok(serde_json::to_string(&compiled).unwrap())
+}
+
+/// Compile a choice into a regular expression matching exactly one of the strings
+pub(crate) fn choices_to_regex(choices: &[String]) -> Result<String, GrammarError> {
+    if choices.is_empty() || choices.len() > MAX_CHOICES {
+        return Err(GrammarError::InvalidChoice(format!(
+            "must have between 1 and {MAX_CHOICES} strings. Given: {}",
+            choices.len()
+        )));
+    }
+    if choices.iter().any(String::is_empty) {
+        return Err(GrammarError::InvalidChoice(
+            "strings cannot be empty".into(),
+        ));
+    }
+    Ok(alternation(
+        choices.iter().map(|choice| regex_escape(choice)).collect(),
+    ))
+}
+
+struct SchemaCompiler<'a> {
+    root: &'a Value,
+}
+
+impl<'a> SchemaCompiler<'a> {
+    fn compile(&self, schema: &'a Value, depth: usize) -> Result<String, GrammarError> {
+        if depth > MAX_DEPTH {
+            return Err(GrammarError::TooDeep(MAX_DEPTH));
+        }
+
+        let schema = match schema {
+            // `true` accepts any value
+            Value::Bool(true) => return Err(GrammarError::Unsupported("`true` schema".into())),
+            Value::Object(schema) => schema,
+            _ => {
+                return Err(GrammarError::InvalidSchema(
+                    "schema must be an object".into(),
+                ))
+            }
+        };
+
+        if let Some(reference) = schema.get("$ref") {
+            let reference = reference
+                .as_str()
+                .ok_or_else(|| GrammarError::InvalidSchema("`$ref` must be a string".into()))?;
+            return self.compile(self.resolve(reference)?, depth + 1);
+        }
+        if let Some(value) = schema.get("const") {
+            return Ok(regex_escape(&value.to_string()));
+        }
+        if let Some(values) = schema.get("enum") {
+            let values = values
+                .as_array()
+                .ok_or_else(|| GrammarError::InvalidSchema("`enum` must be an array".into()))?;
+            let choices: Vec<String> = values
+                .iter()
+                .map(|value| regex_escape(&value.to_string()))
+                .collect();
+            return Ok(alternation(choices));
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(schemas) = schema.get(keyword) {
+                let schemas = schemas.as_array().ok_or_else(|| {
+                    GrammarError::InvalidSchema(format!("`{keyword}` must be an array"))
+                })?;
+                let choices = schemas
+                    .iter()
+                    .map(|schema| self.compile(schema, depth + 1))
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(alternation(choices));
+            }
+        }
+        if let Some(schemas) = schema.get("allOf") {
+            return match schemas.as_array().map(Vec::as_slice) {
+                Some([schema]) => self.compile(schema, depth + 1),
+                _ => Err(GrammarError::Unsupported(
+                    "`allOf` with more than one schema".into(),
+                )),
+            };
+        }
+
+        match schema.get("type") {
+            Some(Value::String(schema_type)) => self.compile_type(schema_type, schema, depth),
+            Some(Value::Array(schema_types)) => {
+                let choices = schema_types
+                    .iter()
+                    .map(|schema_type| match schema_type {
+                        Value::String(schema_type) => self.compile_type(schema_type, schema, depth),
+                        _ => Err(GrammarError::InvalidSchema(
+                            "`type` must be a string or an array of strings".into(),
+                        )),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(alternation(choices))
+            }
+            Some(_) => Err(GrammarError::InvalidSchema(
+                "`type` must be a string or an array of strings".into(),
+            )),
+            None if schema.contains_key("properties") => self.compile_object(schema, depth),
+            None => Err(GrammarError::Unsupported("schema without a `type`".into())),
+        }
+    }
+
+    fn compile_type(
+        &self,
+        schema_type: &str,
+        schema: &'a Map<String, Value>,
+        depth: usize,
+    ) -> Result<String, GrammarError> {
+        match schema_type {
+            "object" => self.compile_object(schema, depth),
+            "array" => self.compile_array(schema, depth),
+            "string" => compile_string(schema),
+            "integer" => Ok(INTEGER.to_string()),
+            "number" => Ok(NUMBER.to_string()),
+            "boolean" => Ok(BOOLEAN.to_string()),
+            "null" => Ok(NULL.to_string()),
+            _ => Err(GrammarError::InvalidSchema(format!(
+                "unknown type `{schema_type}`"
+            ))),
+        }
+    }
+
+    fn compile_object(
+        &self,
+        schema: &'a Map<String, Value>,
+        depth: usize,
+    ) -> Result<String, GrammarError> {
+        let properties = match schema.get("properties") {
+            Some(Value::Object(properties)) => properties,
+            Some(_) => {
+                return Err(GrammarError::InvalidSchema(
+                    "`properties` must be an object".into(),
+                ))
+            }
+            None => {
+                return Err(GrammarError::Unsupported(
+                    "object without `properties`".into(),
+                ))
+            }
+        };
+        let required: Vec<&str> = match schema.get("required") {
+            Some(Value::Array(required)) => required.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+
+        let separator = format!("{WHITESPACE},{WHITESPACE}");
+        let mut members = Vec::with_capacity(properties.len());
+        for (name, property) in properties {
+            let key = regex_escape(&Value::String(name.clone()).to_string());
+            let value = self.compile(property, depth + 1)?;
+            members.push((
+                format!("{key}{WHITESPACE}:{WHITESPACE}{value}"),
+                required.contains(&name.as_str()),
+            ));
+        }
+
+        // Properties are generated in the schema order. Commas must only separate the properties
+        // that are present, which depends on the position of the first required property.
+        let body = match members.iter().position(|(_, required)| *required) {
+            Some(first_required) => {
+                let mut body = String::new();
+                for (member, _) in &members[..first_required] {
+                    body.push_str(&format!("(?:{member}{separator})?"));
+                }
+                body.push_str(&members[first_required].0);
+                for (member, required) in &members[first_required + 1..] {
+                    match required {
+                        true => body.push_str(&format!("{separator}{member}")),
+                        false => body.push_str(&format!("(?:{separator}{member})?")),
+                    }
+                }
+                body
+            }
+            None if members.is_empty() => String::new(),
+            None => {
+                let choices = (0..members.len())
+                    .map(|start| {
+                        let mut choice = members[start].0.clone();
+                        for (member, _) in &members[start + 1..] {
+                            choice.push_str(&format!("(?:{separator}{member})?"));
+                        }
+                        choice
+                    })
+                    .collect();
+                format!("{}?", alternation(choices))
+            }
+        };
+
+        Ok(format!(r"\{{{WHITESPACE}{body}{WHITESPACE}\}}"))
+    }
+
+    fn compile_array(
+        &self,
+        schema: &'a Map<String, Value>,
+        depth: usize,
+    ) -> Result<String, GrammarError> {
+        let item = match schema.get("items") {
+            Some(items) => self.compile(items, depth + 1)?,
+            None => return Err(GrammarError::Unsupported("array without `items`".into())),
+        };
+        let separator = format!("{WHITESPACE},{WHITESPACE}");
+
+        let min_items = schema.get("minItems").and_then(Value::as_u64).unwrap_or(0);
+        let max_items = schema.get("maxItems").and_then(Value::as_u64);
+        if max_items.map_or(false, |max_items| max_items < min_items) {
+            return Err(GrammarError::InvalidSchema(
+                "`maxItems` must be >= `minItems`".into(),
+            ));
+        }
+        let body = match (min_items, max_items) {
+            (_, Some(0)) => String::new(),
+            (0, None) => format!("(?:{item}(?:{separator}{item})*)?"),
+            (0, Some(max_items)) => {
+                format!("(?:{item}(?:{separator}{item}){{0,{}}})?", max_items - 1)
+            }
+            (min_items, None) => format!("{item}(?:{separator}{item}){{{},}}", min_items - 1),
+            (min_items, Some(max_items)) => format!(
+                "{item}(?:{separator}{item}){{{},{}}}",
+                min_items - 1,
+                max_items - 1
+            ),
+        };
+
+        Ok(format!(r"\[{WHITESPACE}{body}{WHITESPACE}\]"))
+    }
+
+    /// Resolve a local reference such as `#/definitions/Name` or `#/$defs/Name`
+    fn resolve(&self, reference: &str) -> Result<&'a Value, GrammarError> {
+        let path = reference
+            .strip_prefix('#')
+            .ok_or_else(|| GrammarError::Unsupported(format!("remote `$ref` {reference}")))?;
+        let mut schema = self.root;
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            let segment = segment.replace("~1", "/").replace("~0", "~");
+            schema = schema.get(&segment).ok_or_else(|| {
+                GrammarError::InvalidSchema(format!("unresolved `$ref` {reference}"))
+            })?;
+        }
+        Ok(schema)
+    }
+}
+
+fn compile_string(schema: &Map<String, Value>) -> Result<String, GrammarError> {
+    if schema.contains_key("pattern") || schema.contains_key("format") {
+        return Err(GrammarError::Unsupported(
+            "`pattern` and `format` on strings".into(),
+        ));
+    }
+    let min_length = schema.get("minLength").and_then(Value::as_u64);
+    let max_length = schema.get("maxLength").and_then(Value::as_u64);
+    Ok(match (min_length, max_length) {
+        (None, None) => STRING.to_string(),
+        (min_length, None) => format!("\"{STRING_INNER}{{{},}}\"", min_length.unwrap_or(0)),
+        (min_length, Some(max_length)) => format!(
+            "\"{STRING_INNER}{{{},{max_length}}}\"",
+            min_length.unwrap_or(0)
+        ),
+    })
+}
+
+fn alternation(choices: Vec<String>) -> String {
+    format!("(?:{})", choices.join("|"))
+}
+
+/// Escape the regex meta characters of a literal
+fn regex_escape(literal: &str) -> String {
+    let mut escaped = String::with_capacity(literal.len());
+    for c in literal.chars() {
+        if r"\.+*?()|[]{}^$#&-~".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Compiler of the high-level intermediate representation of a regular expression into rules
+///
+/// Alternations and repeated expressions become generated rules. Repetitions are right
+/// recursive so that the stacks of the shards stay bounded.
+struct RegexCompiler {
+    rules: Vec<Vec<Vec<Element>>>,
+    /// Number of elements of all the rules
+    elements: usize,
+}
+
+impl RegexCompiler {
+    fn sequence(&mut self, hir: &Hir) -> Result<Vec<Element>, GrammarError> {
+        let mut sequence = Vec::new();
+        self.push(hir, &mut sequence)?;
+        Ok(sequence)
+    }
+
+    fn push(&mut self, hir: &Hir, sequence: &mut Vec<Element>) -> Result<(), GrammarError> {
+        match hir.kind() {
+            HirKind::Empty => {}
+            HirKind::Literal(Literal(bytes)) => {
+                let literal = std::str::from_utf8(bytes).map_err(|_| {
+                    GrammarError::InvalidRegex("literals must be valid UTF-8".into())
+                })?;
+                for c in literal.chars() {
+                    self.element(sequence, chars(vec![(c as u32, c as u32)]))?;
+                }
+            }
+            HirKind::Class(Class::Unicode(class)) => {
+                let ranges = class
+                    .ranges()
+                    .iter()
+                    .map(|range| (range.start() as u32, range.end() as u32))
+                    .collect();
+                self.element(sequence, chars(ranges))?;
+            }
+            HirKind::Class(Class::Bytes(class)) => {
+                // Only ASCII bytes are characters on their own
+                if class.ranges().iter().any(|range| range.end() > 0x7F) {
+                    return Err(GrammarError::InvalidRegex(
+                        "byte classes must only match ASCII characters".into(),
+                    ));
+                }
+                let ranges = class
+                    .ranges()
+                    .iter()
+                    .map(|range| (range.start() as u32, range.end() as u32))
+                    .collect();
+                self.element(sequence, chars(ranges))?;
+            }
+            HirKind::Look(
+                Look::Start
+                | Look::End
+                | Look::StartLF
+                | Look::EndLF
+                | Look::StartCRLF
+                | Look::EndCRLF,
+            ) => {}
+            HirKind::Look(_) => {
+                return Err(GrammarError::InvalidRegex(
+                    "word boundaries are not supported".into(),
+                ))
+            }
+            HirKind::Capture(capture) => self.push(&capture.sub, sequence)?,
+            HirKind::Concat(hirs) => {
+                for hir in hirs {
+                    self.push(hir, sequence)?;
+                }
+            }
+            HirKind::Alternation(hirs) => {
+                let alternatives = hirs
+                    .iter()
+                    .map(|hir| self.sequence(hir))
+                    .collect::<Result<_, _>>()?;
+                let rule = self.rule(alternatives);
+                self.element(sequence, Element::Rule(rule))?;
+            }
+            HirKind::Repetition(repetition) => {
+                let sub = self.sequence(&repetition.sub)?;
+                let sub = self.rule(vec![sub]);
+                for _ in 0..repetition.min {
+                    self.element(sequence, Element::Rule(sub))?;
+                }
+                match repetition.max {
+                    // repeated ::= sub repeated | ""
+                    None => {
+                        let repeated = self.rules.len();
+                        self.count(2)?;
+                        self.rule(vec![
+                            vec![Element::Rule(sub), Element::Rule(repeated)],
+                            Vec::new(),
+                        ]);
+                        self.element(sequence, Element::Rule(repeated))?;
+                    }
+                    // optional ::= sub next_optional | ""
+                    Some(max) => {
+                        let mut optional = None;
+                        for _ in repetition.min..max {
+                            self.count(2)?;
+                            let mut alternative = vec![Element::Rule(sub)];
+                            alternative.extend(optional.map(Element::Rule));
+                            optional = Some(self.rule(vec![alternative, Vec::new()]));
+                        }
+                        if let Some(optional) = optional {
+                            self.element(sequence, Element::Rule(optional))?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a rule whose elements were already counted
+    fn rule(&mut self, alternatives: Vec<Vec<Element>>) -> usize {
+        self.rules.push(alternatives);
+        self.rules.len() - 1
+    }
+
+    fn element(
+        &mut self,
+        sequence: &mut Vec<Element>,
+        element: Element,
+    ) -> Result<(), GrammarError> {
+        self.count(1)?;
+        sequence.push(element);
+        Ok(())
+    }
+
+    fn count(&mut self, elements: usize) -> Result<(), GrammarError> {
+        self.elements += elements;
+        if self.elements > MAX_ELEMENTS {
+            return Err(GrammarError::InvalidRegex(format!(
+                "pattern must compile to less than {MAX_ELEMENTS} elements once repetitions are expanded"
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn chars(ranges: Vec<(u32, u32)>) -> Element {
+    Element::Chars {
+        ranges,
+        negated: false,
+    }
+}
+
+/// Least recently used cache of compiled grammars, shared between all the validation calls
+///
+/// Clients usually send the same schema with every request so we avoid compiling it again.
+/// Grammars are keyed by a hash of their type and source, seeded per process so that clients
+/// cannot craft a grammar colliding with the grammar of another client.
+#[derive(Debug)]
+pub(crate) struct GrammarCache {
+    capacity: usize,
+    hash_builder: RandomState,
+    /// Compiled grammars by key, with the tick of their last use
+    grammars: HashMap<u64, (String, u64)>,
+    tick: u64,
+}
+
+impl GrammarCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            hash_builder: RandomState::new(),
+            grammars: HashMap::with_capacity(capacity),
+            tick: 0,
+        }
+    }
+
+    /// Get the compiled `grammar` of `grammar_type` or compile it with `compile`
+    pub(crate) fn get_or_compile(
+        &mut self,
+        grammar_type: &'static str,
+        grammar: &str,
+        compile: impl FnOnce() -> Result<String, GrammarError>,
+    ) -> Result<String, GrammarError> {
+        let key = self.key(grammar_type, grammar);
+        self.tick += 1;
+        if let Some((compiled, last_used)) = self.grammars.get_mut(&key) {
+            metrics::increment_counter!("tgi_grammar_cache_hit", "type" => grammar_type);
+            *last_used = self.tick;
+            return Ok(compiled.clone());
+        }
+        metrics::increment_counter!("tgi_grammar_cache_miss", "type" => grammar_type);
+
+        let start = std::time::Instant::now();
+        let compiled = compile()?;
+        metrics::histogram!(
+            "tgi_grammar_compile_duration",
+            start.elapsed().as_secs_f64(),
+            "type" => grammar_type
+        );
+        if self.grammars.len() >= self.capacity {
+            let least_recently_used = self
+                .grammars
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| *key);
+            if let Some(key) = least_recently_used {
+                self.grammars.remove(&key);
+                metrics::increment_counter!("tgi_grammar_cache_eviction");
+            }
+        }
+        self.grammars.insert(key, (compiled.clone(), self.tick));
+        metrics::gauge!("tgi_grammar_cache_size", self.grammars.len() as f64);
+        Ok(compiled)
+    }
+
+    fn key(&self, grammar_type: &str, grammar: &str) -> u64 {
+        let mut hasher = self.hash_builder.build_hasher();
+        grammar_type.hash(&mut hasher);
+        grammar.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum GrammarError {
+    #[error("invalid JSON schema: {0}")]
+    InvalidSchema(String),
+    #[error("unsupported JSON schema: {0}")]
+    Unsupported(String),
+    #[error("JSON schema is nested more than {0} levels deep")]
+    TooDeep(usize),
+    #[error("invalid regex: {0}")]
+    InvalidRegex(String),
+    #[error("invalid GBNF grammar: {0}")]
+    InvalidGbnf(String),
+    #[error("invalid choice: {0}")]
+    InvalidChoice(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_schema_to_regex_primitives() {
+        assert_eq!(
+            json_schema_to_regex(&json!({"type": "integer"})).unwrap(),
+            INTEGER
+        );
+        assert_eq!(
+            json_schema_to_regex(&json!({"type": ["boolean", "null"]})).unwrap(),
+            "(?:(?:true|false)|null)"
+        );
+        assert_eq!(
+            json_schema_to_regex(&json!({"enum": ["a.b", 1]})).unwrap(),
+            r#"(?:"a\.b"|1)"#
+        );
+    }
+
+    #[test]
+    fn test_json_schema_to_regex_object() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string"},
+                "age": {"type": "integer"}
+            },
+            "required": ["name"]
+        });
+        assert_eq!(
+            json_schema_to_regex(&schema).unwrap(),
+            format!(
+                r#"\{{{WHITESPACE}"name"{WHITESPACE}:{WHITESPACE}{STRING}(?:{WHITESPACE},{WHITESPACE}"age"{WHITESPACE}:{WHITESPACE}{INTEGER})?{WHITESPACE}\}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_json_schema_to_regex_ref() {
+        let schema = json!({
+            "$defs": {"id": {"type": "integer"}},
+            "type": "array",
+            "items": {"$ref": "#/$defs/id"},
+            "maxItems": 2
+        });
+        assert_eq!(
+            json_schema_to_regex(&schema).unwrap(),
+            format!(
+                r"\[{WHITESPACE}(?:{INTEGER}(?:{WHITESPACE},{WHITESPACE}{INTEGER}){{0,1}})?{WHITESPACE}\]"
+            )
+        );
+    }
+
+    #[test]
+    fn test_json_schema_to_regex_invalid() {
+        assert!(json_schema_to_regex(&json!("string")).is_err());
+        assert!(json_schema_to_regex(&json!({"type": "unknown"})).is_err());
+        assert!(json_schema_to_regex(&json!({"$ref": "#/missing"})).is_err());
+    }
+
+    #[test]
+    fn test_validate_regex() {
+        assert!(validate_regex(r"[0-9]{4}-[0-9]{2}-[0-9]{2}").is_ok());
+        assert!(validate_regex("").is_err());
+        assert!(validate_regex("(unclosed").is_err());
+        // Look-arounds and back-references
+        assert!(validate_regex("(?=a)b").is_err());
+        assert!(validate_regex(r"(a)\1").is_err());
+        // Automaton is too large
+        assert!(validate_regex(r"(?:\w{1000}){1000}").is_err());
+    }
+
+    #[test]
+    fn test_regex_to_automaton() {
+        let automaton: Value =
+            serde_json::from_str(&regex_to_automaton("^a[0-9]+$").unwrap()).unwrap();
+        assert_eq!(
+            automaton,
+            json!({
+                "root": 0,
+                "rules": [
+                    [[
+                        {"chars": {"ranges": [[97, 97]], "negated": false}},
+                        {"rule": 1},
+                        {"rule": 2}
+                    ]],
+                    [[{"chars": {"ranges": [[48, 57]], "negated": false}}]],
+                    [[{"rule": 1}, {"rule": 2}], []]
+                ]
+            })
+        );
+        assert!(
+            regex_to_automaton(&json_schema_to_regex(&json!({"type": "number"})).unwrap()).is_ok()
+        );
+        assert!(regex_to_automaton(r"\bword\b").is_err());
+        // Too many elements once the repetitions are expanded
+        assert!(regex_to_automaton(r"(?:ab){0,100000}").is_err());
+    }
+
+    #[test]
+    fn test_choices_to_regex() {
+        let choices = vec!["yes".to_string(), "no.".to_string()];
+        assert_eq!(choices_to_regex(&choices).unwrap(), r"(?:yes|no\.)");
+        assert!(choices_to_regex(&[]).is_err());
+        assert!(choices_to_regex(&["".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_grammar_cache() {
+        let mut cache = GrammarCache::new(2);
+        let mut get = |grammar_type, grammar, compiled: &str| {
+            cache
+                .get_or_compile(grammar_type, grammar, || Ok(compiled.to_string()))
+                .unwrap()
+        };
+        assert_eq!(get("json", "a", "1"), "1");
+        // Cached
+        assert_eq!(get("json", "a", "2"), "1");
+        // Same source, other type
+        assert_eq!(get("regex", "a", "3"), "3");
+        // Using "a" again makes the regex the least recently used grammar
+        assert_eq!(get("json", "a", "4"), "1");
+        assert_eq!(get("json", "b", "5"), "5");
+        assert_eq!(get("json", "a", "6"), "1");
+        assert_eq!(get("regex", "a", "7"), "7");
+
+        // Failed compilations are not cached
+        assert!(cache
+            .get_or_compile("json", "c", || Err(GrammarError::TooDeep(1)))
+            .is_err());
+        assert_eq!(
+            cache
+                .get_or_compile("json", "c", || Ok("8".to_string()))
+                .unwrap(),
+            "8"
+        );
+    }
+}
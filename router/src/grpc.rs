@@ -0,0 +1,604 @@
+/// gRPC front-end of the router
+use crate::admin::Intake;
+use crate::auth::{ApiKeys, Tenant, TenantPermit};
+use crate::chunking::StreamGranularity;
+use crate::holdback::StopSequenceHoldback;
+use crate::infer::{InferError, InferStreamResponse};
+use crate::models::Models;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::server::{best_of_stream_responses, generate_internal};
+use crate::validation::ValidationError;
+use crate::ErrorResponse;
+use axum::extract::Extension;
+use axum::http::StatusCode;
+use axum::Json;
+use futures::{Stream, StreamExt};
+use grpc_metadata::ExtractTelemetryContext;
+use pb::router::v1::text_generation_server::{TextGeneration, TextGenerationServer};
+use pb::router::v1::{
+    grammar, BestOfSequence, Details, FinishReason, GenerateRequest, GenerateResponse,
+    GenerateStreamResponse, PrefillToken, StreamDetails, Token, TopTokens,
+};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio::time::Instant;
+use tonic::{Code, Request, Response, Status};
+use tracing::{info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+#[allow(clippy::derive_partial_eq_without_eq)]
+mod pb;
+
+/// Serve the generation API over gRPC, admitting the requests as the HTTP inference routes
+pub(crate) async fn run(
+    models: Models,
+    admission: Admission,
+    addr: SocketAddr,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    tracing::info!("Serving gRPC API on {addr}");
+    let service = TextGenerationServer::with_interceptor(
+        TextGenerationService { models },
+        move |mut request: Request<()>| {
+            let admitted = admission.admit(&request)?;
+            request.extensions_mut().insert(admitted);
+            Ok(request)
+        },
+    );
+    tonic::transport::Server::builder()
+        .add_service(service)
+        .serve_with_shutdown(addr, shutdown)
+        .await
+}
+
+/// Limits of the HTTP inference routes, also enforced on the gRPC requests
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Admission {
+    /// Pause of the intake by the administration routes
+    pub(crate) intake: Option<Intake>,
+    /// API keys authenticating the `authorization` metadata of the requests
+    pub(crate) api_keys: Option<ApiKeys>,
+    pub(crate) rate_limiter: Option<RateLimiter>,
+}
+
+impl Admission {
+    /// Check a request against the intake, its API key and its rate limit, in the order of the
+    /// HTTP layers
+    fn admit<T>(&self, request: &Request<T>) -> Result<Admitted, Status> {
+        if self.intake.as_ref().map_or(false, Intake::is_paused) {
+            metrics::increment_counter!("tgi_request_failure", "err" => "paused");
+            return Err(Status::unavailable("The router is not accepting new requests"));
+        }
+
+        let mut admitted = Admitted::default();
+        if let Some(api_keys) = &self.api_keys {
+            let authorization = request
+                .metadata()
+                .get("authorization")
+                .and_then(|value| value.to_str().ok());
+            let tenant = api_keys.authenticate(authorization).ok_or_else(|| {
+                metrics::increment_counter!("tgi_request_failure", "err" => "unauthorized");
+                Status::unauthenticated("Invalid or missing API key")
+            })?;
+            if tenant.quota_exceeded() {
+                metrics::increment_counter!("tgi_request_failure", "err" => "quota");
+                return Err(Status::resource_exhausted("Daily token quota exceeded"));
+            }
+            admitted.permit = tenant.acquire().map_err(|_| {
+                metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
+                metrics::increment_counter!("tgi_tenant_concurrency_exceeded", "tenant" => tenant.name().to_string());
+                Status::resource_exhausted("Too many concurrent requests for this API key")
+            })?;
+            admitted.tenant = Some(tenant);
+        }
+
+        if let Some(rate_limiter) = &self.rate_limiter {
+            let client_ip = request
+                .remote_addr()
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            let rate_limit = rate_limiter.bucket_for(admitted.tenant.as_ref(), &client_ip);
+            if !rate_limit.has_tokens() {
+                metrics::increment_counter!("tgi_request_failure", "err" => "rate_limited");
+                return Err(Status::resource_exhausted("Generated tokens rate limit exceeded"));
+            }
+            admitted.rate_limit = Some(rate_limit);
+        }
+        Ok(admitted)
+    }
+}
+
+/// Identity and limits of an admitted request
+///
+/// The concurrency permit of the API key is held until the response is sent.
+#[derive(Debug, Default)]
+struct Admitted {
+    tenant: Option<Tenant>,
+    rate_limit: Option<RateLimit>,
+    permit: Option<TenantPermit>,
+}
+
+impl Admitted {
+    /// Generation request of a gRPC request, charged to the admitted tenant and rate limit
+    fn generate_request(
+        &mut self,
+        request: GenerateRequest,
+    ) -> Result<crate::GenerateRequest, Status> {
+        let mut req = crate::GenerateRequest::try_from(request)?;
+        req.parameters.tenant = self.tenant.take();
+        req.parameters.rate_limit = self.rate_limit.take();
+        Ok(req)
+    }
+}
+
+/// Implementation of the `router.v1.TextGeneration` service sharing the model backends of the
+/// HTTP server
+struct TextGenerationService {
+    models: Models,
+}
+
+#[tonic::async_trait]
+impl TextGeneration for TextGenerationService {
+    async fn generate(
+        &self,
+        mut request: Request<GenerateRequest>,
+    ) -> Result<Response<GenerateResponse>, Status> {
+        let parent = request.extract_context();
+        let mut admitted = request
+            .extensions_mut()
+            .remove::<Admitted>()
+            .unwrap_or_default();
+        let req = admitted.generate_request(request.into_inner())?;
+        let span = info_span!(
+            "grpc_generate",
+            parameters = ?req.parameters,
+            total_time = tracing::field::Empty,
+            validation_time = tracing::field::Empty,
+            queue_time = tracing::field::Empty,
+            inference_time = tracing::field::Empty,
+            time_per_token = tracing::field::Empty,
+            seed = tracing::field::Empty,
+        );
+        span.set_parent(parent);
+        let (_, infer) = self.models.get(req.model.as_deref())?;
+
+        let (_headers, _input_length, Json(response)) =
+            generate_internal(Extension(infer), Json(req), None, span.clone())
+                .instrument(span)
+                .await
+                .map_err(status)?;
+
+        Ok(Response::new(response.into()))
+    }
+
+    type GenerateStreamStream =
+        Pin<Box<dyn Stream<Item = Result<GenerateStreamResponse, Status>> + Send>>;
+
+    async fn generate_stream(
+        &self,
+        mut request: Request<GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStreamStream>, Status> {
+        let start_time = Instant::now();
+        metrics::increment_counter!("tgi_request_count");
+
+        let parent = request.extract_context();
+        let mut admitted = request
+            .extensions_mut()
+            .remove::<Admitted>()
+            .unwrap_or_default();
+        let req = admitted.generate_request(request.into_inner())?;
+        let span = info_span!("grpc_generate_stream", parameters = ?req.parameters);
+        span.set_parent(parent);
+        let (_, infer) = self.models.get(req.model.as_deref())?;
+
+        let mut add_prompt = None;
+        if req.parameters.return_full_text.unwrap_or(false) {
+            add_prompt = Some(req.inputs.clone());
+        }
+        let details = req.parameters.details;
+        let mut holdback = StopSequenceHoldback::new(req.parameters.stop.clone())
+            .with_granularity(req.parameters.stream_granularity);
+
+        if req.parameters.decoder_input_details {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            return Err(InferError::from(ValidationError::PrefillDetailsStream).into());
+        }
+        if req.parameters.score_prompt {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            return Err(InferError::from(ValidationError::ScorePromptStream).into());
+        }
+
+        let best_of = req.parameters.best_of.unwrap_or(1);
+        if best_of != 1 {
+            // All the candidates must be generated before the best one is known
+            let (response, _) = infer
+                .generate_best_of(req, best_of)
+                .instrument(span.clone())
+                .await?;
+
+            metrics::increment_counter!("tgi_request_success");
+            metrics::histogram!("tgi_request_duration", start_time.elapsed().as_secs_f64());
+            metrics::histogram!(
+                "tgi_request_generated_tokens",
+                response.generated_text.generated_tokens as f64
+            );
+            tracing::info!(parent: &span, "Success");
+
+            let responses: Vec<Result<GenerateStreamResponse, Status>> =
+                best_of_stream_responses(response, add_prompt, details, holdback)
+                    .into_iter()
+                    .map(|response| Ok(response.into()))
+                    .collect();
+            return Ok(Response::new(Box::pin(futures::stream::iter(responses))));
+        }
+
+        let (permit, input_length, mut response_stream) =
+            infer.generate_stream(req).instrument(span.clone()).await?;
+
+        // The generation is cancelled when the client drops the stream
+        let stream = async_stream::stream! {
+            // Keep the permits as long as the stream lives
+            let _permit = permit;
+            let _admitted = admitted;
+
+            while let Some(response) = response_stream.next().await {
+                match response {
+                    // Queue positions and prefill are ignored
+                    Ok(InferStreamResponse::Queued { .. } | InferStreamResponse::Prefill(_)) => {}
+                    Ok(InferStreamResponse::Intermediate { token, top_tokens }) => {
+                        tracing::debug!(parent: &span, "Token: {:?}", token);
+                        // Tokens that could start a stop sequence are held back
+                        for chunk in holdback.push(token, top_tokens) {
+                            yield Ok(GenerateStreamResponse::from(crate::StreamResponse::from(chunk)));
+                        }
+                    }
+                    Ok(InferStreamResponse::End {
+                        token,
+                        top_tokens,
+                        generated_text,
+                        continuation,
+                        ..
+                    }) => {
+                        let stopped = generated_text.finish_reason
+                            == text_generation_client::FinishReason::StopSequence as i32;
+                        let (released, last) = holdback.finish(token, top_tokens, stopped);
+                        for chunk in released {
+                            yield Ok(GenerateStreamResponse::from(crate::StreamResponse::from(chunk)));
+                        }
+
+                        let details = match details {
+                            true => Some(StreamDetails {
+                                finish_reason: finish_reason(generated_text.finish_reason),
+                                generated_tokens: generated_text.generated_tokens,
+                                seed: generated_text.seed,
+                                prompt_tokens: input_length,
+                            }),
+                            false => None,
+                        };
+
+                        metrics::increment_counter!("tgi_request_success");
+                        metrics::histogram!("tgi_request_duration", start_time.elapsed().as_secs_f64());
+                        metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
+
+                        let mut output_text = generated_text.text;
+                        if let Some(prompt) = add_prompt {
+                            output_text = prompt + &output_text;
+                        }
+                        tracing::debug!(parent: &span, "Output: {}", output_text);
+                        tracing::info!(parent: &span, "Success");
+
+                        yield Ok(GenerateStreamResponse {
+                            generated_text: Some(output_text),
+                            details,
+                            continuation,
+                            ..GenerateStreamResponse::from(crate::StreamResponse::from(last))
+                        });
+                        return;
+                    }
+                    Err(err) => {
+                        yield Err(err.into());
+                        return;
+                    }
+                }
+            }
+
+            let err = InferError::IncompleteGeneration;
+            metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
+            tracing::error!(parent: &span, "{err}");
+            yield Err(err.into());
+        };
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+impl TryFrom<GenerateRequest> for crate::GenerateRequest {
+    type Error = Status;
+
+    fn try_from(req: GenerateRequest) -> Result<Self, Self::Error> {
+        let parameters = req.parameters.unwrap_or_default();
+        let grammar = match parameters.grammar.and_then(|grammar| grammar.value) {
+            None => None,
+            Some(grammar::Value::Json(schema)) => Some(crate::GrammarType::Json(
+                serde_json::from_str(&schema).map_err(|err| {
+                    Status::invalid_argument(format!("`grammar` is not valid JSON: {err}"))
+                })?,
+            )),
+            Some(grammar::Value::Regex(pattern)) => Some(crate::GrammarType::Regex(pattern)),
+            Some(grammar::Value::Gbnf(grammar)) => Some(crate::GrammarType::Gbnf(grammar)),
+            Some(grammar::Value::Choice(choice)) => Some(crate::GrammarType::Choice(choice.values)),
+        };
+        let priority = parameters
+            .priority
+            .map(|priority| priority.parse::<crate::Priority>())
+            .transpose()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let truncation_side = parameters
+            .truncation_side
+            .map(|side| side.parse::<crate::TruncationSide>())
+            .transpose()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?
+            .unwrap_or_default();
+        let on_overflow = parameters
+            .on_overflow
+            .map(|policy| policy.parse::<crate::OverflowPolicy>())
+            .transpose()
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        Ok(Self {
+            model: req.model,
+            inputs: req.inputs,
+            parameters: crate::GenerateParameters {
+                best_of: parameters.best_of.map(|best_of| best_of as usize),
+                temperature: parameters.temperature,
+                repetition_penalty: parameters.repetition_penalty,
+                frequency_penalty: parameters.frequency_penalty,
+                presence_penalty: parameters.presence_penalty,
+                top_k: parameters.top_k,
+                top_p: parameters.top_p,
+                typical_p: parameters.typical_p,
+                min_p: parameters.min_p,
+                epsilon_cutoff: parameters.epsilon_cutoff,
+                eta_cutoff: parameters.eta_cutoff,
+                mirostat: parameters.mirostat,
+                mirostat_tau: parameters.mirostat_tau,
+                mirostat_eta: parameters.mirostat_eta,
+                token_healing: parameters.token_healing.unwrap_or(false),
+                truncation_side,
+                on_overflow,
+                do_sample: parameters.do_sample,
+                max_new_tokens: parameters.max_new_tokens,
+                min_new_tokens: parameters
+                    .min_new_tokens
+                    .unwrap_or_else(crate::default_min_new_tokens),
+                return_full_text: parameters.return_full_text,
+                stop: parameters.stop,
+                stop_regex: parameters.stop_regex,
+                stop_token_ids: parameters.stop_token_ids,
+                suffix: parameters.suffix,
+                bad_words: parameters.bad_words,
+                truncate: parameters.truncate.map(|truncate| truncate as usize),
+                no_repeat_ngram_size: parameters
+                    .no_repeat_ngram_size
+                    .unwrap_or_else(crate::default_no_repeat_ngram_size),
+                watermark: parameters.watermark,
+                details: parameters.details,
+                decoder_input_details: parameters.decoder_input_details,
+                score_prompt: parameters.score_prompt.unwrap_or(false),
+                seed: parameters.seed,
+                logit_bias: Some(parameters.logit_bias).filter(|bias| !bias.is_empty()),
+                top_n_tokens: parameters.top_n_tokens,
+                grammar,
+                priority,
+                timeout_ms: parameters.timeout_ms,
+                session_id: parameters.session_id,
+                template: None,
+                variables: None,
+                pause_after: parameters.pause_after,
+                continuation: parameters.continuation,
+                stream_granularity: StreamGranularity::Token,
+                queue_events: false,
+                tenant: None,
+                rate_limit: None,
+                cancel: None,
+                journal_id: None,
+            },
+            add_special_tokens: req.add_special_tokens.unwrap_or(true),
+        })
+    }
+}
+
+impl From<crate::GenerateResponse> for GenerateResponse {
+    fn from(response: crate::GenerateResponse) -> Self {
+        Self {
+            generated_text: response.generated_text,
+            details: response.details.map(|details| Details {
+                finish_reason: FinishReason::from(details.finish_reason) as i32,
+                generated_tokens: details.generated_tokens,
+                seed: details.seed,
+                prompt_tokens: details.prompt_tokens,
+                prefill: details
+                    .prefill
+                    .into_iter()
+                    .map(PrefillToken::from)
+                    .collect(),
+                tokens: details.tokens.into_iter().map(Token::from).collect(),
+                top_tokens: details
+                    .top_tokens
+                    .into_iter()
+                    .map(TopTokens::from)
+                    .collect(),
+                best_of_sequences: details
+                    .best_of_sequences
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|sequence| BestOfSequence {
+                        generated_text: sequence.generated_text,
+                        finish_reason: FinishReason::from(sequence.finish_reason) as i32,
+                        generated_tokens: sequence.generated_tokens,
+                        seed: sequence.seed,
+                        prefill: sequence
+                            .prefill
+                            .into_iter()
+                            .map(PrefillToken::from)
+                            .collect(),
+                        tokens: sequence.tokens.into_iter().map(Token::from).collect(),
+                        top_tokens: sequence
+                            .top_tokens
+                            .into_iter()
+                            .map(TopTokens::from)
+                            .collect(),
+                    })
+                    .collect(),
+            }),
+            continuation: response.continuation,
+        }
+    }
+}
+
+impl From<crate::StreamResponse> for GenerateStreamResponse {
+    fn from(response: crate::StreamResponse) -> Self {
+        Self {
+            token: Some(response.token.into()),
+            generated_text: response.generated_text,
+            details: response.details.map(|details| StreamDetails {
+                finish_reason: FinishReason::from(details.finish_reason) as i32,
+                generated_tokens: details.generated_tokens,
+                seed: details.seed,
+                prompt_tokens: details.prompt_tokens,
+            }),
+            top_tokens: response.top_tokens.into_iter().map(Token::from).collect(),
+            token_ids: response.token_ids,
+            continuation: response.continuation,
+        }
+    }
+}
+
+impl From<crate::PrefillToken> for PrefillToken {
+    fn from(token: crate::PrefillToken) -> Self {
+        Self {
+            id: token.id,
+            text: token.text,
+            logprob: token.logprob,
+        }
+    }
+}
+
+impl From<crate::Token> for Token {
+    fn from(token: crate::Token) -> Self {
+        Self {
+            id: token.id,
+            text: token.text,
+            logprob: token.logprob,
+            special: token.special,
+        }
+    }
+}
+
+impl From<Vec<crate::Token>> for TopTokens {
+    fn from(tokens: Vec<crate::Token>) -> Self {
+        Self {
+            tokens: tokens.into_iter().map(Token::from).collect(),
+        }
+    }
+}
+
+impl From<crate::FinishReason> for FinishReason {
+    fn from(finish_reason: crate::FinishReason) -> Self {
+        match finish_reason {
+            crate::FinishReason::Length => FinishReason::Length,
+            crate::FinishReason::EndOfSequenceToken => FinishReason::EosToken,
+            crate::FinishReason::StopSequence => FinishReason::StopSequence,
+            crate::FinishReason::Cancelled => FinishReason::Cancelled,
+            crate::FinishReason::ContentFilter => FinishReason::ContentFilter,
+        }
+    }
+}
+
+/// Convert a shard finish reason to a router finish reason
+fn finish_reason(finish_reason: i32) -> i32 {
+    FinishReason::from(crate::FinishReason::from(finish_reason)) as i32
+}
+
+/// Convert HTTP errors to gRPC status
+fn status((status_code, Json(err)): (StatusCode, Json<ErrorResponse>)) -> Status {
+    let code = match status_code {
+        StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::FAILED_DEPENDENCY => Code::Aborted,
+        StatusCode::REQUEST_TIMEOUT => Code::DeadlineExceeded,
+        StatusCode::CONFLICT => Code::Aborted,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        status_code if status_code.as_u16() == 499 => Code::Cancelled,
+        _ => Code::Internal,
+    };
+    Status::new(code, err.error)
+}
+
+impl From<InferError> for Status {
+    fn from(err: InferError) -> Self {
+        status(err.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admission_api_keys() {
+        let path = std::env::temp_dir().join(format!("api-keys-{}.json", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            r#"[{"key": "secret", "tenant": "acme", "max_concurrent_requests": 1}]"#,
+        )
+        .unwrap();
+        let admission = Admission {
+            api_keys: Some(ApiKeys::load(path.clone()).unwrap()),
+            rate_limiter: Some(RateLimiter::new(60)),
+            ..Admission::default()
+        };
+
+        // The requests without a valid API key are rejected
+        let status = admission.admit(&Request::new(())).unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        let admitted = admission.admit(&request).unwrap();
+        assert_eq!(admitted.tenant.as_ref().unwrap().name(), "acme");
+        assert!(admitted.rate_limit.is_some());
+
+        // The concurrent requests limit of the key is shared with the HTTP API
+        let status = admission.admit(&request).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        drop(admitted);
+        assert!(admission.admit(&request).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_continuation() {
+        let req = crate::GenerateRequest::try_from(GenerateRequest {
+            inputs: "".to_string(),
+            parameters: Some(pb::router::v1::GenerateParameters {
+                pause_after: Some(64),
+                continuation: Some("token".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(req.parameters.pause_after, Some(64));
+        assert_eq!(req.parameters.continuation.as_deref(), Some("token"));
+
+        let response = GenerateResponse::from(crate::GenerateResponse {
+            generated_text: "Hello".to_string(),
+            details: None,
+            continuation: Some("next".to_string()),
+        });
+        assert_eq!(response.continuation.as_deref(), Some("next"));
+    }
+}
@@ -1,69 +1,190 @@
-use std::sync::atomic::{AtomicBool, Ordering};
+use crate::{ModelHealth, ShardHealth};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use text_generation_client::{
-    Batch, NextTokenChooserParameters, Request, ShardedClient, StoppingCriteriaParameters,
+    Batch, GrammarType, NextTokenChooserParameters, Request, ShardedClient,
+    StoppingCriteriaParameters,
 };
 
 // Note: Request ids and batch ids cannot collide.
 const LIVENESS_ID: u64 = u64::MAX;
 const BATCH_ID: u64 = u64::MAX;
 
+/// Health of the generations of a batching task, shared with the health checks
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GenerationHealth {
+    healthy: Arc<AtomicBool>,
+    /// Unix timestamp in milliseconds of the last successful decode, 0 if there was none
+    last_decode_ms: Arc<AtomicU64>,
+}
+
+impl GenerationHealth {
+    pub(crate) fn set(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::SeqCst);
+    }
+
+    pub(crate) fn get(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    /// Record a successful decode step
+    pub(crate) fn decoded(&self) {
+        self.set(true);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+        self.last_decode_ms.store(now, Ordering::SeqCst);
+    }
+
+    pub(crate) fn last_decode_ms(&self) -> Option<u64> {
+        match self.last_decode_ms.load(Ordering::SeqCst) {
+            0 => None,
+            ms => Some(ms),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct Health {
+    model_id: String,
     client: ShardedClient,
-    generation_health: Arc<AtomicBool>,
+    generation_health: GenerationHealth,
 }
 
 impl Health {
-    pub(crate) fn new(client: ShardedClient, generation_health: Arc<AtomicBool>) -> Self {
+    pub(crate) fn new(
+        model_id: String,
+        client: ShardedClient,
+        generation_health: GenerationHealth,
+    ) -> Self {
         Self {
+            model_id,
             client,
             generation_health,
         }
     }
 
-    pub(crate) async fn check(&mut self) -> bool {
-        if self.generation_health.load(Ordering::SeqCst) {
-            // Generation is healthy, we only check that the shards are answering gRPC calls
-            self.client.health().await.is_ok()
-        } else {
-            // Generation is unhealthy or have not sent any generation request yet
+    /// Detailed status of the model and of each of its shards
+    ///
+    /// The shards of the replicas of the model are ranked after the shards of the first replica
+    pub(crate) async fn status(&mut self) -> ModelHealth {
+        let mut results = Vec::new();
+        for mut replica in self.client.replicas() {
+            results.extend(replica.shards_health().await);
+        }
+        let shards: Vec<ShardHealth> = results
+            .into_iter()
+            .enumerate()
+            .map(|(rank, result)| match result {
+                Ok((latency, info)) => ShardHealth {
+                    rank,
+                    healthy: true,
+                    latency_ms: Some(latency.as_secs_f64() * 1000.0),
+                    dtype: Some(info.dtype),
+                    device_type: Some(info.device_type),
+                    error: None,
+                },
+                Err(err) => ShardHealth {
+                    rank,
+                    healthy: false,
+                    latency_ms: None,
+                    dtype: None,
+                    device_type: None,
+                    error: Some(err.to_string()),
+                },
+            })
+            .collect();
+
+        // If the shards are answering gRPC calls but generation is unhealthy or have not sent any
+        // generation request yet, check that they can generate
+        let mut healthy = shards.iter().all(|shard| shard.healthy);
+        if healthy && !self.generation_health.get() {
+            healthy = self.check_generation().await;
+        }
 
-            // Dummy batch of 1 token and 1 generated token
-            let liveness_request = Request {
-                id: LIVENESS_ID,
-                inputs: "liveness".to_string(),
-                truncate: 10,
-                prefill_logprobs: false,
-                parameters: Some(NextTokenChooserParameters {
-                    temperature: 1.0,
-                    top_k: 0,
-                    top_p: 1.0,
-                    typical_p: 1.0,
-                    do_sample: false,
-                    min_new_tokens: 0,
-                    seed: 0,
-                    repetition_penalty: 1.0,
-                    no_repeat_ngram_size: 0,
-                    watermark: false,
-                }),
-                stopping_parameters: Some(StoppingCriteriaParameters {
-                    max_new_tokens: 1,
-                    stop_sequences: vec![],
-                    ignore_eos_token: false,
-                }),
-            };
-            let batch = Batch {
-                id: BATCH_ID,
-                requests: vec![liveness_request],
-                size: 1,
-                max_tokens: 2,
-            };
-            // Skips the queue
-            let value = self.client.prefill(batch).await.is_ok();
-            // Update generation health
-            self.generation_health.store(value, Ordering::SeqCst);
-            value
+        ModelHealth {
+            model_id: self.model_id.clone(),
+            healthy,
+            last_decode_ms: self.generation_health.last_decode_ms(),
+            shards,
         }
     }
+
+    pub(crate) fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    /// Whether the shards are reachable and can generate
+    ///
+    /// The router only starts serving once the model is warmed up, so a successful generation
+    /// means the model is ready
+    pub(crate) async fn ready(&mut self) -> bool {
+        if self.client.hedged_health().await.is_err() {
+            return false;
+        }
+        self.generation_health.get() || self.check_generation().await
+    }
+
+    /// Run a dummy prefill skipping the queue
+    async fn check_generation(&mut self) -> bool {
+        // Dummy batch of 1 token and 1 generated token
+        let liveness_request = Request {
+            id: LIVENESS_ID,
+            inputs: "liveness".to_string(),
+            truncate: 10,
+            prefill_logprobs: false,
+            top_n_tokens: 0,
+            prefix_len: 0,
+            session_id: None,
+            prefill_offset: 0,
+            prefill_chunk_len: None,
+            input_chunks: vec![],
+            add_special_tokens: true,
+            parameters: Some(NextTokenChooserParameters {
+                temperature: 1.0,
+                top_k: 0,
+                top_p: 1.0,
+                typical_p: 1.0,
+                do_sample: false,
+                min_new_tokens: 0,
+                seed: 0,
+                repetition_penalty: 1.0,
+                no_repeat_ngram_size: 0,
+                watermark: false,
+                logit_bias: HashMap::new(),
+                grammar: String::new(),
+                grammar_type: GrammarType::None as i32,
+                min_p: 0.0,
+                frequency_penalty: 0.0,
+                presence_penalty: 0.0,
+                bad_words_ids: vec![],
+                epsilon_cutoff: 0.0,
+                eta_cutoff: 0.0,
+                mirostat: 0,
+                mirostat_tau: 0.0,
+                mirostat_eta: 0.0,
+                token_healing_prefix: String::new(),
+            }),
+            stopping_parameters: Some(StoppingCriteriaParameters {
+                max_new_tokens: 1,
+                stop_sequences: vec![],
+                ignore_eos_token: false,
+                stop_token_ids: vec![],
+            }),
+        };
+        let batch = Batch {
+            id: BATCH_ID,
+            requests: vec![liveness_request],
+            size: 1,
+            max_tokens: 2,
+        };
+        // Skips the queue
+        let value = self.client.prefill(batch).await.is_ok();
+        // Update generation health
+        self.generation_health.set(value);
+        value
+    }
 }
@@ -0,0 +1,230 @@
+/// Stop sequence holdback for streamed tokens
+use crate::chunking::{StreamChunk, StreamChunker, StreamGranularity};
+use crate::Token;
+use std::collections::VecDeque;
+
+/// A streamed token and its top tokens
+pub(crate) type StreamedToken = (Token, Vec<Token>);
+
+/// Withholds the streamed tokens that could be the beginning of a stop sequence
+///
+/// Stop sequences can span several tokens. Tokens are held back as long as their text could be
+/// the beginning of a stop sequence, and are released once a following token disambiguates them.
+/// When the generation ends on a stop sequence, the held back stop sequence is never streamed.
+///
+/// The released tokens are then coalesced into chunks of the stream granularity.
+#[derive(Debug)]
+pub(crate) struct StopSequenceHoldback {
+    stop_sequences: Vec<String>,
+    /// Length of the longest stop sequence
+    max_stop_length: usize,
+    held: VecDeque<StreamedToken>,
+    /// Concatenated text of the held tokens
+    held_text: String,
+    chunker: StreamChunker,
+}
+
+impl StopSequenceHoldback {
+    pub(crate) fn new(stop_sequences: Vec<String>) -> Self {
+        let stop_sequences: Vec<String> = stop_sequences
+            .into_iter()
+            .filter(|stop| !stop.is_empty())
+            .collect();
+        let max_stop_length = stop_sequences.iter().map(String::len).max().unwrap_or(0);
+        Self {
+            stop_sequences,
+            max_stop_length,
+            held: VecDeque::new(),
+            held_text: String::new(),
+            chunker: StreamChunker::new(StreamGranularity::Token),
+        }
+    }
+
+    /// Coalesce the streamed tokens into chunks of `granularity`
+    pub(crate) fn with_granularity(mut self, granularity: StreamGranularity) -> Self {
+        self.chunker = StreamChunker::new(granularity);
+        self
+    }
+
+    /// Add a generated token and return the chunks that can be streamed
+    pub(crate) fn push(&mut self, token: Token, top_tokens: Vec<Token>) -> Vec<StreamChunk> {
+        self.hold(token, top_tokens)
+            .into_iter()
+            .flat_map(|streamed| self.chunker.push(streamed))
+            .collect()
+    }
+
+    /// Add the last generated token and return the chunks that can be streamed followed by the
+    /// last chunk to stream
+    ///
+    /// If the generation `stopped` on a stop sequence, the stop sequence is removed from the
+    /// streamed chunks
+    pub(crate) fn finish(
+        &mut self,
+        token: Token,
+        top_tokens: Vec<Token>,
+        stopped: bool,
+    ) -> (Vec<StreamChunk>, StreamChunk) {
+        let (released, last) = self.release(token, top_tokens, stopped);
+        let chunks = released
+            .into_iter()
+            .flat_map(|streamed| self.chunker.push(streamed))
+            .collect();
+        (chunks, self.chunker.finish(last))
+    }
+
+    /// Add a generated token and return the tokens that can be streamed
+    fn hold(&mut self, token: Token, top_tokens: Vec<Token>) -> Vec<StreamedToken> {
+        self.held_text.push_str(&token.text);
+        self.held.push_back((token, top_tokens));
+
+        // Only the end of the text can be the beginning of a stop sequence
+        let kept = self.held_text.len() - self.longest_stop_prefix();
+        let mut released = Vec::new();
+        let mut released_length = 0;
+        while let Some((token, _)) = self.held.front() {
+            if released_length + token.text.len() > kept {
+                break;
+            }
+            released_length += token.text.len();
+            released.push(self.held.pop_front().unwrap());
+        }
+        self.held_text.drain(..released_length);
+        released
+    }
+
+    /// Add the last generated token and return the tokens that can be streamed followed by the
+    /// last token to stream
+    ///
+    /// If the generation `stopped` on a stop sequence, the stop sequence is removed from the
+    /// streamed tokens
+    fn release(
+        &mut self,
+        token: Token,
+        top_tokens: Vec<Token>,
+        stopped: bool,
+    ) -> (Vec<StreamedToken>, StreamedToken) {
+        self.held_text.push_str(&token.text);
+        self.held.push_back((token, top_tokens));
+        let held_text = std::mem::take(&mut self.held_text);
+        let mut held: Vec<StreamedToken> = self.held.drain(..).collect();
+
+        let stop_length = match stopped {
+            true => self
+                .stop_sequences
+                .iter()
+                .filter(|stop| held_text.ends_with(stop.as_str()))
+                .map(String::len)
+                .max(),
+            false => None,
+        };
+
+        match stop_length {
+            // Stream everything
+            None => {
+                let last = held.pop().unwrap();
+                (held, last)
+            }
+            // Stream the tokens before the stop sequence and truncate the token where it starts
+            Some(stop_length) => {
+                let cut = held_text.len() - stop_length;
+                let mut released = Vec::new();
+                let mut start = 0;
+                for (mut token, top_tokens) in held {
+                    let end = start + token.text.len();
+                    if end <= cut {
+                        released.push((token, top_tokens));
+                        start = end;
+                    } else {
+                        token.text.truncate(cut - start);
+                        return (released, (token, top_tokens));
+                    }
+                }
+                unreachable!("the stop sequence is not empty")
+            }
+        }
+    }
+
+    /// Length of the longest suffix of the held text that is the beginning of a stop sequence
+    fn longest_stop_prefix(&self) -> usize {
+        let max_length = self.max_stop_length.min(self.held_text.len());
+        (1..=max_length)
+            .rev()
+            .filter(|&length| {
+                self.held_text
+                    .is_char_boundary(self.held_text.len() - length)
+            })
+            .find(|&length| {
+                let suffix = &self.held_text[self.held_text.len() - length..];
+                self.stop_sequences
+                    .iter()
+                    .any(|stop| stop.starts_with(suffix))
+            })
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(text: &str) -> Token {
+        Token {
+            id: 0,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: false,
+        }
+    }
+
+    fn texts(chunks: Vec<StreamChunk>) -> Vec<String> {
+        chunks.into_iter().map(|chunk| chunk.token.text).collect()
+    }
+
+    #[test]
+    fn test_holdback_release() {
+        let mut holdback = StopSequenceHoldback::new(vec!["\nUser:".to_string()]);
+        assert_eq!(texts(holdback.push(token("Hello"), vec![])), vec!["Hello"]);
+        // Could be the beginning of the stop sequence
+        assert!(holdback.push(token("\n"), vec![]).is_empty());
+        assert!(holdback.push(token("Us"), vec![]).is_empty());
+        // Disambiguated
+        assert_eq!(
+            texts(holdback.push(token("ually"), vec![])),
+            vec!["\n", "Us", "ually"]
+        );
+    }
+
+    #[test]
+    fn test_holdback_stop_sequence() {
+        let mut holdback = StopSequenceHoldback::new(vec!["\nUser:".to_string()]);
+        assert_eq!(texts(holdback.push(token("Hi!"), vec![])), vec!["Hi!"]);
+        assert!(holdback.push(token("\nUs"), vec![]).is_empty());
+        let (released, last) = holdback.finish(token("er:"), vec![], true);
+        assert!(released.is_empty());
+        assert_eq!(last.token.text, "");
+
+        // Stop sequence starting in the middle of a token
+        let mut holdback = StopSequenceHoldback::new(vec!["\nUser:".to_string()]);
+        assert_eq!(texts(holdback.push(token("Hi"), vec![])), vec!["Hi"]);
+        assert!(holdback.push(token("!\n"), vec![]).is_empty());
+        let (released, last) = holdback.finish(token("User:"), vec![], true);
+        assert!(released.is_empty());
+        assert_eq!(last.token.text, "!");
+    }
+
+    #[test]
+    fn test_holdback_no_stop() {
+        let mut holdback = StopSequenceHoldback::new(vec!["###".to_string()]);
+        assert!(holdback.push(token("#"), vec![]).is_empty());
+        let (released, last) = holdback.finish(token("#"), vec![], false);
+        assert_eq!(texts(released), vec!["#"]);
+        assert_eq!(last.token.text, "#");
+
+        let mut holdback = StopSequenceHoldback::new(vec![]);
+        assert_eq!(texts(holdback.push(token("a"), vec![])), vec!["a"]);
+        let (released, last) = holdback.finish(token("b"), vec![], false);
+        assert!(released.is_empty());
+        assert_eq!(last.token.text, "b");
+    }
+}
@@ -0,0 +1,252 @@
+/// Deduplication of the retried requests with idempotency keys
+use crate::auth::Tenant;
+use crate::rate_limit::client_ip;
+use crate::ErrorResponse;
+use axum::body::{boxed, Bytes, Full, HttpBody};
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Header of the idempotency key of a request
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+
+/// Maximum length in bytes of an idempotency key
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 256;
+
+/// Response of a request, replayed to its retries
+#[derive(Clone, Debug)]
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+#[derive(Debug)]
+enum CacheEntry {
+    /// The first request with the key is running
+    InFlight,
+    /// The first request with the key finished
+    Done {
+        response: StoredResponse,
+        expires: Instant,
+    },
+}
+
+/// State of an idempotency key when a request starts
+#[derive(Debug)]
+enum Begin {
+    /// First request with the key
+    New,
+    /// A request with the key is still running
+    InFlight,
+    /// A request with the key finished within the window
+    Replay(StoredResponse),
+}
+
+/// Responses of the requests with an idempotency key, kept for a short window
+///
+/// The retries of a non-streaming request received within the window get the response of the
+/// first request instead of generating again
+#[derive(Clone, Debug)]
+pub(crate) struct IdempotencyCache {
+    window: Duration,
+    entries: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+impl IdempotencyCache {
+    pub(crate) fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn begin(&self, key: &str, now: Instant) -> Begin {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| match entry {
+            CacheEntry::InFlight => true,
+            CacheEntry::Done { expires, .. } => *expires > now,
+        });
+        match entries.get(key) {
+            Some(CacheEntry::InFlight) => Begin::InFlight,
+            Some(CacheEntry::Done { response, .. }) => Begin::Replay(response.clone()),
+            None => {
+                entries.insert(key.to_string(), CacheEntry::InFlight);
+                Begin::New
+            }
+        }
+    }
+
+    /// Keep the response of the key until the end of the window
+    fn complete(&self, key: &str, response: StoredResponse, now: Instant) {
+        self.entries.lock().unwrap().insert(
+            key.to_string(),
+            CacheEntry::Done {
+                response,
+                expires: now + self.window,
+            },
+        );
+    }
+
+    /// Forget the key so that a retry generates again
+    fn abandon(&self, key: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if matches!(entries.get(key), Some(CacheEntry::InFlight)) {
+            entries.remove(key);
+        }
+    }
+}
+
+/// Forgets an in-flight key if its request is dropped before finishing, e.g. when the client
+/// disconnects
+struct InFlightGuard<'a> {
+    cache: &'a IdempotencyCache,
+    key: &'a str,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.cache.abandon(self.key);
+    }
+}
+
+/// Replay the response of a previous request with the same `Idempotency-Key`
+///
+/// Keys are scoped to the tenant of the request, or to the client IP when API keys are disabled,
+/// and to the route. Streaming and unsuccessful responses are not kept.
+pub(crate) async fn idempotency<B>(
+    State(cache): State<IdempotencyCache>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(key) = request.headers().get(IDEMPOTENCY_KEY) else {
+        return next.run(request).await;
+    };
+    let key = match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => key.to_string(),
+        _ => {
+            return error(
+                StatusCode::BAD_REQUEST,
+                &format!("`Idempotency-Key` must be 1 to {MAX_IDEMPOTENCY_KEY_LENGTH} ASCII bytes"),
+            )
+        }
+    };
+    let scope = match request.extensions().get::<Tenant>() {
+        Some(tenant) => format!("tenant:{}", tenant.name()),
+        None => format!("ip:{}", client_ip(&request)),
+    };
+    let key = format!("{scope} {} {key}", request.uri().path());
+
+    match cache.begin(&key, Instant::now()) {
+        Begin::New => {}
+        Begin::InFlight => {
+            metrics::increment_counter!("tgi_request_failure", "err" => "idempotency");
+            return error(
+                StatusCode::CONFLICT,
+                "A request with the same `Idempotency-Key` is in progress",
+            );
+        }
+        Begin::Replay(stored) => {
+            metrics::increment_counter!("tgi_idempotent_replay");
+            tracing::info!("Replaying the response of a previous request");
+            let mut response = Response::new(boxed(Full::from(stored.body)));
+            *response.status_mut() = stored.status;
+            *response.headers_mut() = stored.headers;
+            response
+                .headers_mut()
+                .insert("idempotent-replayed", HeaderValue::from_static("true"));
+            return response;
+        }
+    }
+
+    let guard = InFlightGuard {
+        cache: &cache,
+        key: &key,
+    };
+    let response = next.run(request).await;
+    let streaming = response.headers().get(CONTENT_TYPE).map_or(false, |value| {
+        value.as_bytes().starts_with(b"text/event-stream")
+    });
+    if streaming || !response.status().is_success() {
+        // The guard forgets the key
+        return response;
+    }
+
+    let (parts, mut body) = response.into_parts();
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        match chunk {
+            Ok(chunk) => buffer.extend_from_slice(&chunk),
+            Err(err) => {
+                tracing::error!("Could not read the response body: {err}");
+                return error(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
+            }
+        }
+    }
+    let body = Bytes::from(buffer);
+    let stored = StoredResponse {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    };
+    cache.complete(&key, stored, Instant::now());
+    std::mem::forget(guard);
+    Response::from_parts(parts, boxed(Full::from(body)))
+}
+
+fn error(status_code: StatusCode, error: &str) -> Response {
+    (
+        status_code,
+        Json(ErrorResponse {
+            error: error.to_string(),
+            error_type: "idempotency".to_string(),
+            load: None,
+            policy_violation: None,
+        }),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stored(body: &'static str) -> StoredResponse {
+        StoredResponse {
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from(body),
+        }
+    }
+
+    #[test]
+    fn test_idempotency_cache() {
+        let cache = IdempotencyCache::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert!(matches!(cache.begin("a", start), Begin::New));
+        assert!(matches!(cache.begin("a", start), Begin::InFlight));
+        assert!(matches!(cache.begin("b", start), Begin::New));
+
+        cache.complete("a", stored("hello"), start);
+        match cache.begin("a", start + Duration::from_secs(30)) {
+            Begin::Replay(response) => assert_eq!(response.body, "hello"),
+            _ => panic!("Response not replayed"),
+        }
+        // Expired
+        assert!(matches!(
+            cache.begin("a", start + Duration::from_secs(61)),
+            Begin::New
+        ));
+
+        // A failed request can be retried
+        cache.abandon("b");
+        assert!(matches!(cache.begin("b", start), Begin::New));
+    }
+}
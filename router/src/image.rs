@@ -0,0 +1,168 @@
+/// Image inputs of multimodal models
+use crate::validation::ValidationError;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::time::Duration;
+use text_generation_client::{Chunk, Image, InputChunk};
+
+/// Maximum number of images of a request
+pub(crate) const MAX_IMAGES: usize = 8;
+/// Maximum size in bytes of an image
+const MAX_IMAGE_SIZE: usize = 20 * 1024 * 1024;
+/// Timeout of the download of an image URL
+const IMAGE_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Part of the inputs of a request
+#[derive(Debug, PartialEq)]
+pub(crate) enum InputPart<'a> {
+    Text(&'a str),
+    /// Source of an image embedded as `![](<source>)`
+    Image(&'a str),
+}
+
+/// Split `inputs` into text and the sources of the images embedded as `![](<source>)`
+pub(crate) fn split_inputs(inputs: &str) -> Vec<InputPart<'_>> {
+    let mut parts = Vec::new();
+    let mut rest = inputs;
+    while let Some(start) = rest.find("![](") {
+        let source_start = start + 4;
+        let Some(source_length) = rest[source_start..].find(')') else {
+            break;
+        };
+        if start > 0 {
+            parts.push(InputPart::Text(&rest[..start]));
+        }
+        parts.push(InputPart::Image(
+            &rest[source_start..source_start + source_length],
+        ));
+        rest = &rest[source_start + source_length + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(InputPart::Text(rest));
+    }
+    parts
+}
+
+/// Load the images of `parts` into the chunks sent to the shards
+pub(crate) async fn input_chunks(
+    client: &reqwest::Client,
+    parts: Vec<InputPart<'_>>,
+) -> Result<Vec<InputChunk>, ValidationError> {
+    let mut chunks = Vec::with_capacity(parts.len());
+    for part in parts {
+        let chunk = match part {
+            InputPart::Text(text) => Chunk::Text(text.to_string()),
+            InputPart::Image(source) => Chunk::Image(load_image(client, source).await?),
+        };
+        chunks.push(InputChunk { chunk: Some(chunk) });
+    }
+    Ok(chunks)
+}
+
+/// Decode a base64 data URI or download an HTTP(S) URL and check the image format
+async fn load_image(client: &reqwest::Client, source: &str) -> Result<Image, ValidationError> {
+    let data = if let Some(data_uri) = source.strip_prefix("data:") {
+        let (_, data) = data_uri
+            .split_once(";base64,")
+            .ok_or_else(|| ValidationError::Image("data URIs must be base64 encoded".into()))?;
+        // Do not decode images that are obviously too large
+        if data.len() / 4 * 3 > MAX_IMAGE_SIZE {
+            return Err(ValidationError::ImageSize(MAX_IMAGE_SIZE));
+        }
+        STANDARD
+            .decode(data)
+            .map_err(|err| ValidationError::Image(format!("invalid base64: {err}")))?
+    } else if source.starts_with("https://") || source.starts_with("http://") {
+        download(client, source).await?
+    } else {
+        return Err(ValidationError::Image(
+            "images must be base64 data URIs or HTTP(S) URLs".into(),
+        ));
+    };
+    if data.len() > MAX_IMAGE_SIZE {
+        return Err(ValidationError::ImageSize(MAX_IMAGE_SIZE));
+    }
+    let mimetype = image_mimetype(&data).ok_or(ValidationError::ImageFormat)?;
+    metrics::histogram!("tgi_request_image_size", data.len() as f64);
+    Ok(Image {
+        data,
+        mimetype: mimetype.to_string(),
+    })
+}
+
+/// Download an image, stopping as soon as it is larger than `MAX_IMAGE_SIZE`
+async fn download(client: &reqwest::Client, url: &str) -> Result<Vec<u8>, ValidationError> {
+    let download_error =
+        |err: reqwest::Error| ValidationError::Image(format!("could not download `{url}`: {err}"));
+    let mut response = client
+        .get(url)
+        .timeout(IMAGE_DOWNLOAD_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(download_error)?;
+    let mut data = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(download_error)? {
+        if data.len() + chunk.len() > MAX_IMAGE_SIZE {
+            return Err(ValidationError::ImageSize(MAX_IMAGE_SIZE));
+        }
+        data.extend_from_slice(&chunk);
+    }
+    Ok(data)
+}
+
+/// Format of an encoded image from its magic bytes
+fn image_mimetype(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_inputs() {
+        assert_eq!(
+            split_inputs("What is this?![](https://example.com/cat.png) And ![](data:x)"),
+            vec![
+                InputPart::Text("What is this?"),
+                InputPart::Image("https://example.com/cat.png"),
+                InputPart::Text(" And "),
+                InputPart::Image("data:x"),
+            ]
+        );
+        assert_eq!(split_inputs("![]("), vec![InputPart::Text("![](")]);
+        assert!(split_inputs("").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_load_image() {
+        let client = reqwest::Client::new();
+        let png = STANDARD.encode(b"\x89PNG\r\n\x1a\n0000");
+        let image = load_image(&client, &format!("data:image/png;base64,{png}"))
+            .await
+            .unwrap();
+        assert_eq!(image.mimetype, "image/png");
+        assert_eq!(image.data.len(), 12);
+
+        let text = STANDARD.encode(b"Hello world");
+        match load_image(&client, &format!("data:image/png;base64,{text}")).await {
+            Err(ValidationError::ImageFormat) => (),
+            _ => panic!("Unexpected image format"),
+        }
+        match load_image(&client, "file:///etc/passwd").await {
+            Err(ValidationError::Image(_)) => (),
+            _ => panic!("Unexpected image source"),
+        }
+    }
+}
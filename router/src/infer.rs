@@ -1,25 +1,54 @@
 /// Batching and inference logic
+use crate::audit::AuditLog;
+use crate::auth::Tenant;
+use crate::cancellation::CancelToken;
+use crate::choice::ChoiceTracker;
+use crate::chunked_prefill::ChunkedPrefill;
+use crate::content_filter::PolicyViolation;
+use crate::continuation::Continuations;
+use crate::dedup::{Charge, InFlight};
+use crate::defaults::ParameterDefaults;
+use crate::health::GenerationHealth;
+use crate::journal::QueueJournal;
+use crate::length_prediction::LengthPredictor;
+use crate::moderation::OutputModeration;
+use crate::preemption::{self, Progress};
+use crate::prefix_cache::PrefixCache;
+use crate::redaction::PiiRedactor;
+use crate::scheduling::SchedulingPolicyKind;
+use crate::served_ratio::ServedRatio;
+use crate::sessions::Sessions;
+use crate::shedding::SheddingPolicy;
+use crate::stop_regex::StopRegexTracker;
+use crate::template::ChatTemplate;
+use crate::token_budget::{AdaptiveBudgetConfig, TokenBudget};
 use crate::validation::{Validation, ValidationError};
-use crate::{Entry, Queue, Token};
-use crate::{GenerateRequest, PrefillToken};
+use crate::{Entry, Queue, QueueLoad, Token};
+use crate::{GenerateRequest, HubTokenizerConfig, Message, PrefillToken, Tool};
 use flume::r#async::RecvStream;
 use flume::SendTimeoutError;
 use futures::future::try_join_all;
 use futures::stream::StreamExt;
 use nohash_hasher::IntMap;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use text_generation_client::{
-    Batch, CachedBatch, ClientError, GeneratedText, Generation, PrefillTokens, ShardedClient,
+    Batch, CachedBatch, ClientError, FinishReason, GeneratedText, Generation, PrefillTokens,
+    ShardedClient,
 };
 use thiserror::Error;
+use tokenizers::Encoding;
 use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio::time::Instant;
 use tracing::{info_span, instrument, Instrument, Span};
 
+/// Paged Attention block size of the shards
+const BLOCK_SIZE: u32 = 16;
+
+/// Maximum time a new request waits for the permit of the queued request rejected in its place
+const SHED_PERMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
 /// Inference struct
 #[derive(Clone)]
 pub struct Infer {
@@ -30,13 +59,107 @@ pub struct Infer {
     /// Shared state
     shared: Arc<Shared>,
     /// Inference limit
-    limit_concurrent_requests: Arc<Semaphore>,
+    limit_concurrent_requests: ConcurrencyLimit,
+    /// Chat template
+    chat_template: Option<ChatTemplate>,
+    /// Client used for requests that skip the batching task
+    client: ShardedClient,
+    /// Input tokens of the embedding requests in flight, up to `max_batch_prefill_tokens`
+    embed_tokens: Arc<Semaphore>,
+    /// Maximum number of input tokens of the embedding requests in flight
+    max_embed_tokens: u32,
+    /// Audit log of the generation requests
+    audit_log: Option<AuditLog>,
+    /// Sessions whose KV cache is pinned on the shards
+    sessions: Option<Sessions>,
+    /// Paused generations continued from their session
+    continuations: Option<Continuations>,
+    /// Operator defaults of the parameters left unset by the requests
+    parameter_defaults: Option<ParameterDefaults>,
+    /// Whether running requests can be preempted by waiting requests of a higher priority
+    preemption: bool,
+    /// Generations shared by the identical requests in flight
+    in_flight: Option<InFlight>,
+    /// Journal of the queued requests
+    queue_journal: Option<QueueJournal>,
+    /// Id of the model served by this backend
+    model_id: String,
+    /// Predictor of the number of tokens generated by the requests, to batch together the
+    /// requests expected to finish at similar times
+    length_predictor: Option<LengthPredictor>,
+    /// Request rejected when the concurrency limit is reached
+    shedding_policy: SheddingPolicy,
+    /// Moderation of the generated text
+    output_moderation: Option<Arc<OutputModeration>>,
+    /// Redaction of the personally identifiable information of the generated text
+    pii_redactor: Option<Arc<PiiRedactor>>,
 }
 
 /// Infer shared state
 struct Shared {
     /// Batching background Tokio task notifier
     batching_task: Notify,
+    /// Maximum number of decoding steps before a smaller batch is added to the running batch
+    max_waiting_tokens: AtomicUsize,
+}
+
+/// Limit of the concurrent requests, resizable at runtime
+#[derive(Clone, Debug)]
+struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    max: Arc<AtomicUsize>,
+    /// Permits to remove from the semaphore once they are released by the running requests
+    shrinking: Arc<AtomicUsize>,
+}
+
+impl ConcurrencyLimit {
+    fn new(max: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max)),
+            max: Arc::new(AtomicUsize::new(max)),
+            shrinking: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn try_acquire(&self) -> Result<OwnedSemaphorePermit, TryAcquireError> {
+        self.semaphore.clone().try_acquire_owned()
+    }
+
+    /// Wait for a permit released by a running request
+    async fn acquire(&self) -> OwnedSemaphorePermit {
+        // The semaphore is never closed
+        self.semaphore.clone().acquire_owned().await.unwrap()
+    }
+
+    fn max(&self) -> usize {
+        self.max.load(Ordering::SeqCst)
+    }
+
+    fn in_flight(&self) -> usize {
+        (self.max() + self.shrinking.load(Ordering::SeqCst))
+            .saturating_sub(self.semaphore.available_permits())
+    }
+
+    /// Change the limit without interrupting the running requests
+    ///
+    /// When the limit decreases, the permits are removed as the running requests release them
+    fn resize(&self, max: usize) {
+        let previous = self.max.swap(max, Ordering::SeqCst);
+        if max > previous {
+            self.semaphore.add_permits(max - previous);
+        } else if max < previous {
+            let shrink = previous - max;
+            self.shrinking.fetch_add(shrink, Ordering::SeqCst);
+            let limit = self.clone();
+            tokio::spawn(async move {
+                for _ in 0..shrink {
+                    // The semaphore is never closed
+                    limit.semaphore.acquire().await.unwrap().forget();
+                    limit.shrinking.fetch_sub(1, Ordering::SeqCst);
+                }
+            });
+        }
+    }
 }
 
 impl Infer {
@@ -50,35 +173,226 @@ impl Infer {
         max_waiting_tokens: usize,
         max_concurrent_requests: usize,
         requires_padding: bool,
-        generation_health: Arc<AtomicBool>,
+        generation_health: GenerationHealth,
+        tokenizer_config: HubTokenizerConfig,
+        audit_log: Option<AuditLog>,
+        prefix_cache_max_tokens: Option<usize>,
+        max_sessions: Option<usize>,
+        session_ttl: Duration,
+        parameter_defaults: Option<ParameterDefaults>,
+        adaptive_budget: Option<AdaptiveBudgetConfig>,
+        preemption: bool,
+        max_queue_duration: Option<Duration>,
+        max_prefill_chunk_tokens: Option<u32>,
+        max_batch_decode_tokens: Option<u32>,
+        scheduling_policy: SchedulingPolicyKind,
+        priority_aging: Option<Duration>,
+        deduplicate_requests: bool,
+        target_inter_token_latency: Option<Duration>,
+        model_id: String,
+        queue_journal: Option<QueueJournal>,
+        length_aware_batching: bool,
+        max_starvation: Option<Duration>,
+        shedding_policy: SheddingPolicy,
+        output_moderation: Option<Arc<OutputModeration>>,
+        pii_redactor: Option<Arc<PiiRedactor>>,
     ) -> Self {
         // Infer shared state
-        let queue = Queue::new(requires_padding, 16);
+        let queue = Queue::new(
+            model_id.clone(),
+            requires_padding,
+            BLOCK_SIZE,
+            prefix_cache_max_tokens.map(PrefixCache::new),
+            max_queue_duration,
+            scheduling_policy,
+            priority_aging,
+            max_starvation,
+        );
         let shared = Arc::new(Shared {
             batching_task: Notify::new(),
+            max_waiting_tokens: AtomicUsize::new(max_waiting_tokens),
         });
 
-        // Spawn batching background task that contains all the inference logic
-        tokio::spawn(batching_task(
-            client,
-            waiting_served_ratio,
-            max_batch_prefill_tokens,
-            max_batch_total_tokens,
-            max_waiting_tokens,
-            queue.clone(),
-            shared.clone(),
-            generation_health,
-        ));
+        // Spawn batching background task that contains all the inference logic, one for each
+        // replica of the model
+        for replica in client.replicas() {
+            tokio::spawn(batching_task(
+                replica,
+                ServedRatio::new(waiting_served_ratio, target_inter_token_latency),
+                max_batch_prefill_tokens,
+                TokenBudget::new(max_batch_total_tokens, adaptive_budget),
+                queue.clone(),
+                shared.clone(),
+                generation_health.clone(),
+                preemption,
+                max_prefill_chunk_tokens,
+                max_batch_decode_tokens,
+                model_id.clone(),
+            ));
+        }
 
         // Inference limit with a semaphore
-        let semaphore = Arc::new(Semaphore::new(max_concurrent_requests));
+        let limit_concurrent_requests = ConcurrencyLimit::new(max_concurrent_requests);
+
+        // Chat template
+        let chat_template = ChatTemplate::new(tokenizer_config);
 
         Self {
             validation,
             queue,
             shared,
-            limit_concurrent_requests: semaphore,
+            limit_concurrent_requests,
+            chat_template,
+            sessions: max_sessions
+                .map(|max_sessions| Sessions::new(max_sessions, session_ttl, client.clone())),
+            continuations: max_sessions
+                .map(|max_sessions| Continuations::new(max_sessions, session_ttl)),
+            client,
+            embed_tokens: Arc::new(Semaphore::new(max_batch_prefill_tokens as usize)),
+            max_embed_tokens: max_batch_prefill_tokens,
+            audit_log,
+            parameter_defaults,
+            preemption,
+            in_flight: deduplicate_requests.then(InFlight::default),
+            queue_journal,
+            model_id,
+            length_predictor: length_aware_batching.then(LengthPredictor::default),
+            shedding_policy,
+            output_moderation,
+            pii_redactor: pii_redactor.filter(|pii_redactor| pii_redactor.redacts_outputs()),
+        }
+    }
+
+    /// Number of requests waiting in the queue
+    pub(crate) async fn queue_size(&self) -> usize {
+        self.queue.len().await
+    }
+
+    /// Number of requests being validated, queued or generated
+    pub(crate) fn in_flight(&self) -> usize {
+        self.limit_concurrent_requests.in_flight()
+    }
+
+    pub(crate) fn max_concurrent_requests(&self) -> usize {
+        self.limit_concurrent_requests.max()
+    }
+
+    /// Change the limit of concurrent requests, the running requests are not interrupted
+    pub(crate) fn set_max_concurrent_requests(&self, max_concurrent_requests: usize) {
+        self.limit_concurrent_requests
+            .resize(max_concurrent_requests);
+        tracing::info!("Maximum concurrent requests set to {max_concurrent_requests}");
+    }
+
+    pub(crate) fn max_waiting_tokens(&self) -> usize {
+        self.shared.max_waiting_tokens.load(Ordering::SeqCst)
+    }
+
+    /// Change the number of decoding steps after which the batching task adds waiting requests
+    /// to the running batch
+    pub(crate) fn set_max_waiting_tokens(&self, max_waiting_tokens: usize) {
+        self.shared
+            .max_waiting_tokens
+            .store(max_waiting_tokens, Ordering::SeqCst);
+        tracing::info!("Maximum waiting tokens set to {max_waiting_tokens}");
+    }
+
+    /// Clear the KV cache of the shards and evict the idle sessions
+    ///
+    /// Must only be called without requests in flight as their batches are dropped
+    pub(crate) async fn clear_cache(&self) -> Result<(), ClientError> {
+        if let Some(sessions) = &self.sessions {
+            sessions.clear();
+        }
+        for mut replica in self.client.replicas() {
+            replica.clear_cache(None).await?;
+        }
+        Ok(())
+    }
+
+    /// Render the chat `messages` and `tools` to a prompt using the model chat template
+    #[instrument(skip_all)]
+    pub(crate) fn apply_chat_template(
+        &self,
+        messages: Vec<Message>,
+        tools: Option<&[Tool]>,
+    ) -> Result<String, InferError> {
+        let chat_template = self
+            .chat_template
+            .as_ref()
+            .ok_or(InferError::MissingChatTemplate)?;
+        chat_template.apply(messages, tools).map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "template");
+            tracing::error!("{err}");
+            InferError::TemplateError(err)
+        })
+    }
+
+    /// Validate the number of completions `n` of a request
+    pub(crate) fn validate_n(&self, n: usize) -> Result<usize, InferError> {
+        self.validation.validate_n(n).map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            InferError::ValidationError(err)
+        })
+    }
+
+    /// Validate the number of `best_of` candidates of a request
+    pub(crate) fn validate_best_of(&self, best_of: usize) -> Result<usize, InferError> {
+        self.validation.validate_best_of(best_of).map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            InferError::ValidationError(err)
+        })
+    }
+
+    /// Error of a request rejected by the concurrency limit, with the load of the queue
+    async fn overloaded(&self, err: TryAcquireError) -> InferError {
+        metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
+        tracing::error!("{err}");
+        InferError::Overloaded(self.queue.load().await)
+    }
+
+    /// Acquire a permit of the concurrency limit for `request`, shedding the load according to
+    /// the shedding policy
+    async fn acquire(&self, request: &GenerateRequest) -> Result<OwnedSemaphorePermit, InferError> {
+        let limit = &self.limit_concurrent_requests;
+        if self
+            .shedding_policy
+            .drop_early(limit.in_flight(), limit.max())
+        {
+            metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
+            tracing::error!("Request dropped early by the shedding policy");
+            return Err(InferError::Overloaded(self.queue.load().await));
+        }
+
+        let err = match limit.try_acquire() {
+            Ok(permit) => return Ok(permit),
+            Err(err) => err,
+        };
+        let priority = request.parameters.priority.unwrap_or_default();
+        if self.queue.shed(self.shedding_policy, priority).await {
+            // The permit of the rejected request is released once its client got the error
+            if let Ok(permit) = tokio::time::timeout(SHED_PERMIT_TIMEOUT, limit.acquire()).await {
+                return Ok(permit);
+            }
         }
+        Err(self.overloaded(err).await)
+    }
+
+    /// Acquire the permits of `n` requests like `request` at once, so that the requests are
+    /// either all admitted or all rejected before any of them is queued
+    pub(crate) async fn acquire_many(
+        &self,
+        request: &GenerateRequest,
+        n: usize,
+    ) -> Result<Vec<OwnedSemaphorePermit>, InferError> {
+        let mut permits = Vec::with_capacity(n);
+        for _ in 0..n {
+            // The permits acquired so far are released on error
+            permits.push(self.acquire(request).await?);
+        }
+        Ok(permits)
     }
 
     /// Add a new request to the queue and return a stream of InferStreamResponse
@@ -89,20 +403,42 @@ impl Infer {
     ) -> Result<
         (
             OwnedSemaphorePermit,
+            u32,
             RecvStream<Result<InferStreamResponse, InferError>>,
         ),
         InferError,
     > {
         // Limit concurrent requests by acquiring a permit from the semaphore
-        let permit = self
-            .clone()
-            .limit_concurrent_requests
-            .try_acquire_owned()
-            .map_err(|err| {
-                metrics::increment_counter!("tgi_request_failure", "err" => "overloaded");
-                tracing::error!("{err}");
-                err
-            })?;
+        let permit = self.acquire(&request).await?;
+        self.generate_stream_with_permit(request, permit).await
+    }
+
+    /// Like `generate_stream`, with a permit of the concurrency limit acquired by the caller
+    async fn generate_stream_with_permit(
+        &self,
+        mut request: GenerateRequest,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<
+        (
+            OwnedSemaphorePermit,
+            u32,
+            RecvStream<Result<InferStreamResponse, InferError>>,
+        ),
+        InferError,
+    > {
+        let pausable = self.continue_paused(&mut request).map_err(|err| {
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            err
+        })?;
+
+        // Request as sent by the client, replayed if the router restarts before it is batched
+        let journaled = self.queue_journal.is_some().then(|| request.clone());
+        let cancel = request.parameters.cancel.take();
+
+        if let Some(parameter_defaults) = &self.parameter_defaults {
+            parameter_defaults.apply(&mut request.parameters);
+        }
 
         // Validate request
         let valid_request = self.validation.validate(request).await.map_err(|err| {
@@ -111,8 +447,66 @@ impl Infer {
             err
         })?;
 
+        // Start a new turn of the session
+        let session = match (&valid_request.session_id, &self.sessions) {
+            (None, _) => None,
+            (Some(_), None) => {
+                metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                let err = ValidationError::SessionsDisabled;
+                tracing::error!("{err}");
+                return Err(err.into());
+            }
+            (Some(session_id), Some(sessions)) => {
+                let mut turn = sessions
+                    .start(session_id.clone(), &valid_request.input_ids)
+                    .map_err(|err| {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "session");
+                        tracing::error!("{err}");
+                        err
+                    })?;
+                // Prefill logprobs need the whole prompt
+                if valid_request.decoder_input_details {
+                    turn.prefix_len = 0;
+                }
+                Some(turn)
+            }
+        };
+
+        // Follow the generation of an identical request in flight
+        let in_flight = self
+            .in_flight
+            .as_ref()
+            .and_then(|in_flight| Some((in_flight, InFlight::key(&valid_request)?)));
+        if let Some((in_flight, key)) = &in_flight {
+            if let Some(response_rx) = in_flight.follow(key, Charge::new(&valid_request)) {
+                let tenant = valid_request.tenant.as_ref().map(|t| t.name().to_string());
+                let response_rx = self.forward(response_rx, cancel, tenant);
+                return Ok((
+                    permit,
+                    valid_request.input_length,
+                    response_rx.into_stream(),
+                ));
+            }
+        }
+
         // MPSC channel to communicate with the background batching task
         let (response_tx, response_rx) = flume::unbounded();
+        // The responses of the generation are also sent to the identical requests following it
+        let response_tx = match in_flight {
+            Some((in_flight, key)) => in_flight.lead(key, response_tx),
+            None => response_tx,
+        };
+        let input_length = valid_request.input_length;
+        let tenant = valid_request.tenant.as_ref().map(|t| t.name().to_string());
+        let length_prediction = self.length_predictor.as_ref().map(|length_predictor| {
+            length_predictor.predict(valid_request.stopping_parameters.max_new_tokens)
+        });
+        let choice = (!valid_request.choices.is_empty())
+            .then(|| ChoiceTracker::new(valid_request.choices.clone()));
+        let stop_regex = (!valid_request.stop_regexes.is_empty())
+            .then(|| StopRegexTracker::new(valid_request.stop_regexes.clone()));
+        let progress =
+            (self.preemption && preemption::preemptible(&valid_request)).then(Progress::default);
 
         // Append the request to the queue
         self.queue.append(Entry {
@@ -122,6 +516,21 @@ impl Infer {
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            audit_log: self.audit_log.clone(),
+            session,
+            choice,
+            stop_regex,
+            progress,
+            queue_position: None,
+            generated_tokens: 0,
+            priority_boost: 0,
+            journal: self
+                .queue_journal
+                .as_ref()
+                .zip(journaled)
+                .map(|(queue_journal, request)| queue_journal.queued(&self.model_id, &request)),
+            length_prediction,
+            continuations: self.continuations.clone().filter(|_| pausable),
         });
 
         // Notify the background task that we have a new entry in the queue that needs
@@ -129,7 +538,127 @@ impl Infer {
         self.shared.batching_task.notify_one();
 
         // Return stream
-        Ok((permit, response_rx.into_stream()))
+        let response_rx = self.forward(response_rx, cancel, tenant);
+        Ok((permit, input_length, response_rx.into_stream()))
+    }
+
+    /// Moderate and redact the responses of a request of `tenant` and make them cancellable
+    fn forward(
+        &self,
+        response_rx: flume::Receiver<Result<InferStreamResponse, InferError>>,
+        cancel: Option<CancelToken>,
+        tenant: Option<String>,
+    ) -> flume::Receiver<Result<InferStreamResponse, InferError>> {
+        let response_rx = match &self.output_moderation {
+            Some(output_moderation) => output_moderation.forward(response_rx, tenant),
+            None => response_rx,
+        };
+        // Redacted after the moderation, which needs the generated text
+        let response_rx = match &self.pii_redactor {
+            Some(pii_redactor) => pii_redactor.forward(response_rx),
+            None => response_rx,
+        };
+        match cancel {
+            Some(cancel) => cancel.forward(response_rx),
+            None => response_rx,
+        }
+    }
+
+    /// Continue the paused generation of the `continuation` token of `request`, and give a
+    /// session of its own to a request pausing after `pause_after` tokens
+    ///
+    /// Returns whether the generation pauses
+    fn continue_paused(&self, request: &mut GenerateRequest) -> Result<bool, ValidationError> {
+        let parameters = &mut request.parameters;
+        if parameters.pause_after.is_none() && parameters.continuation.is_none() {
+            return Ok(false);
+        }
+        let continuations = self
+            .continuations
+            .as_ref()
+            .ok_or(ValidationError::SessionsDisabled)?;
+
+        if let Some(token) = parameters.continuation.take() {
+            let tenant = parameters.tenant.as_ref().map(Tenant::name);
+            let continuation = continuations
+                .get(&token, tenant)
+                .ok_or(ValidationError::Continuation)?;
+            // The KV cache of the prompt and generated text is still pinned by the session
+            request.inputs = continuation.inputs + &request.inputs;
+            parameters.session_id = Some(continuation.session_id);
+            metrics::increment_counter!("tgi_request_continued");
+        }
+
+        let Some(pause_after) = parameters.pause_after else {
+            return Ok(false);
+        };
+        if pause_after == 0 {
+            return Err(ValidationError::PauseAfter);
+        }
+        parameters.max_new_tokens = Some(
+            parameters
+                .max_new_tokens
+                .map_or(pause_after, |max_new_tokens| {
+                    max_new_tokens.min(pause_after)
+                }),
+        );
+        if parameters.session_id.is_none() {
+            parameters.session_id = Some(format!("paused-{:032x}", rand::random::<u128>()));
+        }
+        Ok(true)
+    }
+
+    /// Tokenize the input
+    ///
+    /// The offsets of the encoding are byte offsets in the returned input, which is truncated
+    /// like the inputs sent to the shards
+    #[instrument(skip_all)]
+    pub(crate) async fn tokenize(
+        &self,
+        request: GenerateRequest,
+    ) -> Result<Option<(Encoding, String)>, InferError> {
+        // Tokenize request
+        let inputs = request.inputs;
+        let truncate = request.parameters.truncate;
+        let truncation_side = request.parameters.truncation_side;
+        let add_special_tokens = request.add_special_tokens;
+        let tokenize = |inputs: String, truncate: Option<usize>| async move {
+            self.validation
+                .tokenize(inputs, truncate, truncation_side, add_special_tokens)
+                .await
+                .map_err(|err| {
+                    tracing::error!("Tokenization {err}");
+                    err
+                })
+        };
+        let Some((encoding, truncated)) = tokenize(inputs.clone(), truncate).await? else {
+            return Ok(None);
+        };
+        if truncated == inputs {
+            return Ok(Some((encoding, truncated)));
+        }
+        // The offsets of a truncated encoding refer to the original inputs, encode the truncated
+        // inputs again to get offsets in the text the shards receive
+        Ok(tokenize(truncated, None).await?)
+    }
+
+    /// Decode token ids
+    #[instrument(skip_all)]
+    pub(crate) async fn detokenize(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<Option<String>, InferError> {
+        let text = self
+            .validation
+            .detokenize(ids, skip_special_tokens)
+            .await
+            .map_err(|err| {
+                tracing::error!("Detokenization {err}");
+                err
+            })?;
+
+        Ok(text)
     }
 
     /// Add a new request to the queue and return a InferResponse
@@ -138,19 +667,63 @@ impl Infer {
         &self,
         request: GenerateRequest,
     ) -> Result<InferResponse, InferError> {
+        // Limit concurrent requests by acquiring a permit from the semaphore
+        let permit = self.acquire(&request).await?;
+        self.generate_with_permit(request, permit).await
+    }
+
+    /// Like `generate`, with a permit of the concurrency limit acquired by the caller
+    pub(crate) async fn generate_with_permit(
+        &self,
+        request: GenerateRequest,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<InferResponse, InferError> {
+        let queued = Instant::now();
         // Create stream and keep semaphore permit as long as generate lives
-        let (_permit, mut stream) = self.generate_stream(request).await?;
+        let (_permit, input_length, mut stream) =
+            self.generate_stream_with_permit(request, permit).await?;
 
         // Return values
         let mut result_prefill = Vec::new();
         let mut result_tokens = Vec::new();
+        let mut result_top_tokens = Vec::new();
         let mut result_generated_text = None;
         let mut result_start = None;
         let mut result_queued = None;
+        let mut result_continuation = None;
 
         // Iterate on stream
         while let Some(response) = stream.next().await {
-            match response? {
+            let response = match response {
+                Ok(response) => response,
+                // Return the tokens generated before the cancellation
+                Err(InferError::Cancelled) => {
+                    let text = result_tokens
+                        .iter()
+                        .filter(|token| !token.special)
+                        .map(|token| token.text.as_str())
+                        .collect();
+                    return Ok(InferResponse {
+                        input_length,
+                        generated_text: GeneratedText {
+                            text,
+                            generated_tokens: result_tokens.len() as u32,
+                            finish_reason: FinishReason::Cancelled as i32,
+                            seed: None,
+                        },
+                        prefill: result_prefill,
+                        tokens: result_tokens,
+                        top_tokens: result_top_tokens,
+                        queued,
+                        start: Instant::now(),
+                        continuation: None,
+                    });
+                }
+                Err(err) => return Err(err),
+            };
+            match response {
+                // The position in the queue is only streamed
+                InferStreamResponse::Queued { .. } => {}
                 // Add prefill tokens
                 InferStreamResponse::Prefill(tokens) => {
                     // Create Token objects
@@ -164,19 +737,31 @@ impl Infer {
                         .collect();
                 }
                 // Push last token
-                InferStreamResponse::Token(token) => result_tokens.push(token),
+                InferStreamResponse::Intermediate { token, top_tokens } => {
+                    result_tokens.push(token);
+                    // Top tokens are only sent if the user asked for them
+                    if !top_tokens.is_empty() {
+                        result_top_tokens.push(top_tokens);
+                    }
+                }
                 // Final message
                 // Set return values
                 InferStreamResponse::End {
                     token,
+                    top_tokens,
                     generated_text,
                     start,
                     queued,
+                    continuation,
                 } => {
                     result_tokens.push(token);
+                    if !top_tokens.is_empty() {
+                        result_top_tokens.push(top_tokens);
+                    }
                     result_generated_text = Some(generated_text);
                     result_start = Some(start);
-                    result_queued = Some(queued)
+                    result_queued = Some(queued);
+                    result_continuation = continuation;
                 }
             }
         }
@@ -186,11 +771,14 @@ impl Infer {
             (result_generated_text, result_queued, result_start)
         {
             Ok(InferResponse {
+                input_length,
                 prefill: result_prefill,
                 tokens: result_tokens,
+                top_tokens: result_top_tokens,
                 generated_text,
                 queued,
                 start,
+                continuation: result_continuation,
             })
         } else {
             let err = InferError::IncompleteGeneration;
@@ -199,6 +787,80 @@ impl Infer {
             Err(err)
         }
     }
+
+    /// Validate `inputs` and compute their pooled embeddings
+    ///
+    /// Embeddings do not go through the batching task as they only require a single forward.
+    /// Like the prefills of the batching task, the embedding requests in flight are limited to
+    /// `max_batch_prefill_tokens` input tokens in total, so that their forwards fit the memory
+    /// reserved for the prefills. Returns the embeddings and the total number of input tokens
+    #[instrument(skip_all, fields(size = inputs.len()))]
+    pub(crate) async fn embed(
+        &self,
+        inputs: Vec<String>,
+    ) -> Result<(Vec<Vec<f32>>, u32), InferError> {
+        // Limit concurrent requests by acquiring a permit from the semaphore
+        let _permit = match self.limit_concurrent_requests.try_acquire() {
+            Ok(permit) => permit,
+            Err(err) => return Err(self.overloaded(err).await),
+        };
+
+        if inputs.is_empty() {
+            let err = InferError::from(ValidationError::EmptyEmbedInputs);
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // Validate inputs
+        let mut valid_inputs = Vec::with_capacity(inputs.len());
+        let mut input_length = 0;
+        for input in inputs {
+            let (input, length) =
+                self.validation
+                    .validate_embed_input(input)
+                    .await
+                    .map_err(|err| {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                        tracing::error!("{err}");
+                        err
+                    })?;
+            valid_inputs.push(input);
+            input_length += length as u32;
+        }
+        if input_length > self.max_embed_tokens {
+            let err = InferError::from(ValidationError::EmbedTokens(
+                self.max_embed_tokens,
+                input_length,
+            ));
+            metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+            tracing::error!("{err}");
+            return Err(err);
+        }
+
+        // Wait for the embedding requests in flight to leave room for the inputs
+        // Unwrap is safe here as the semaphore is never closed
+        let _tokens = self.embed_tokens.acquire_many(input_length).await.unwrap();
+        let mut client = self.client.least_outstanding();
+        let embeddings = client
+            .embed(valid_inputs, self.validation.max_input_length() as u32)
+            .await
+            .map_err(|err| {
+                let err = InferError::GenerationError(err.to_string());
+                metrics::increment_counter!("tgi_request_failure", "err" => "embed");
+                tracing::error!("{err}");
+                err
+            })?;
+
+        Ok((
+            embeddings
+                .into_iter()
+                .map(|embedding| embedding.values)
+                .collect(),
+            input_length,
+        ))
+    }
+
     /// Add best_of new requests to the queue and return a InferResponse of the sequence with
     /// the highest log probability per token
     #[instrument(skip(self))]
@@ -209,10 +871,24 @@ impl Infer {
     ) -> Result<(InferResponse, Vec<InferResponse>), InferError> {
         // validate  best_of parameter separately
         let best_of = self.validation.validate_best_of(best_of)?;
+        let permits = self.acquire_many(&request, best_of).await?;
+        self.generate_best_of_with_permits(request, permits).await
+    }
 
+    /// Like `generate_best_of`, with a permit of the concurrency limit acquired by the caller
+    /// for each of the candidates
+    pub(crate) async fn generate_best_of_with_permits(
+        &self,
+        request: GenerateRequest,
+        permits: Vec<OwnedSemaphorePermit>,
+    ) -> Result<(InferResponse, Vec<InferResponse>), InferError> {
         // create multiple generate requests
-        let mut infer_responses: Vec<InferResponse> =
-            try_join_all((0..best_of).map(|_| self.generate(request.clone()))).await?;
+        let mut infer_responses: Vec<InferResponse> = try_join_all(
+            permits
+                .into_iter()
+                .map(|permit| self.generate_with_permit(request.clone(), permit)),
+        )
+        .await?;
 
         // get the sequence with the highest log probability per token
         let mut max_index = 0;
@@ -245,13 +921,16 @@ impl Infer {
 #[allow(clippy::too_many_arguments)]
 async fn batching_task(
     mut client: ShardedClient,
-    waiting_served_ratio: f32,
+    mut served_ratio: ServedRatio,
     max_batch_prefill_tokens: u32,
-    max_batch_total_tokens: u32,
-    max_waiting_tokens: usize,
+    mut token_budget: TokenBudget,
     queue: Queue,
     shared: Arc<Shared>,
-    generation_health: Arc<AtomicBool>,
+    generation_health: GenerationHealth,
+    preemption: bool,
+    max_prefill_chunk_tokens: Option<u32>,
+    max_batch_decode_tokens: Option<u32>,
+    model_id: String,
 ) {
     // Infinite loop
     loop {
@@ -262,38 +941,101 @@ async fn batching_task(
         // This batch might be smaller than the maximum batch size if there are not enough requests
         // waiting in the queue
         while let Some((mut entries, batch, span)) = queue
-            .next_batch(None, max_batch_prefill_tokens, max_batch_total_tokens)
+            .next_batch(
+                None,
+                max_batch_prefill_tokens,
+                token_budget.get(),
+                max_batch_decode_tokens,
+            )
             .await
         {
-            let mut cached_batch = prefill(&mut client, batch, &mut entries, &generation_health)
-                .instrument(span)
-                .await;
+            client.set_outstanding(entries.len());
+            let mut cached_batch = prefill(
+                &mut client,
+                batch,
+                &mut entries,
+                &generation_health,
+                &mut token_budget,
+            )
+            .instrument(span)
+            .await;
             let mut waiting_tokens = 1;
+            // Instant the running requests received their last token
+            let mut last_token_time = Instant::now();
+            // New batch whose prefill is interleaved with the decoding steps of the running batch
+            let mut chunked: Option<ChunkedPrefill> = None;
 
             // We loop until we do not receive any cached batch from the inference server (== until
             // all requests have met their stopping criteria)
             while let Some(batch) = cached_batch {
+                // Stop generating for the clients that disconnected to free their slots
+                let batch = match filter_cancelled(&mut client, batch, &mut entries).await {
+                    Some(batch) => batch,
+                    None => {
+                        cached_batch = prefill_remaining(
+                            &mut client,
+                            chunked.take(),
+                            &mut entries,
+                            &generation_health,
+                            &mut token_budget,
+                        )
+                        .await;
+                        continue;
+                    }
+                };
+
+                // Outstanding requests of this replica, balanced with the other replicas
+                let chunked_entries = chunked.as_ref().map_or(0, |chunked| chunked.entries.len());
+                client.set_outstanding(entries.len() + chunked_entries);
+
                 // Get current batch info
                 let batch_size = batch.size;
                 let batch_max_tokens = batch.max_tokens;
                 let mut batches = vec![batch];
-                metrics::gauge!("tgi_batch_current_size", batch_size as f64);
-                metrics::gauge!("tgi_batch_current_max_tokens", batch_max_tokens as f64);
+                metrics::gauge!("tgi_batch_current_size", batch_size as f64, "model" => model_id.clone());
+                metrics::gauge!("tgi_batch_current_max_tokens", batch_max_tokens as f64, "model" => model_id.clone());
+                metrics::gauge!("tgi_request_running", entries.len() as f64, "model" => model_id.clone());
 
+                let max_waiting_tokens = shared.max_waiting_tokens.load(Ordering::SeqCst);
                 let min_size = if waiting_tokens >= max_waiting_tokens {
                     // If we didn't onboard any new requests since >= max_waiting_tokens, we try
                     // to add a new batch even though its size might be small
                     None
                 } else {
                     // Minimum batch size
-                    Some((batch_size as f32 * waiting_served_ratio).floor() as usize)
+                    Some((batch_size as f32 * served_ratio.get()).floor() as usize)
                 };
 
-                let token_budget = max_batch_total_tokens.saturating_sub(batch_max_tokens);
+                let (new_batch_token_budget, new_batch_decode_token_budget) =
+                    match max_batch_decode_tokens {
+                        // The running requests only hold the tokens of their prompt and the tokens
+                        // they generated, the tokens they can still generate are budgeted separately
+                        Some(max_batch_decode_tokens) => {
+                            let (used_tokens, decode_tokens) = running_tokens(&entries);
+                            (
+                                token_budget.get().saturating_sub(used_tokens),
+                                Some(max_batch_decode_tokens.saturating_sub(decode_tokens)),
+                            )
+                        }
+                        None => (token_budget.get().saturating_sub(batch_max_tokens), None),
+                    };
+                // The shards know how much of their KV cache is actually free, which is less
+                // than estimated when the requests fill it unevenly
+                let new_batch_token_budget =
+                    token_budget.cap_to_free_tokens(new_batch_token_budget);
 
-                // Try to get a new batch
-                if let Some((mut new_entries, new_batch, span)) = queue
-                    .next_batch(min_size, max_batch_prefill_tokens, token_budget)
+                // Try to get a new batch, unless the prefill of the previous one is not done
+                if chunked.is_some() {
+                    // The next chunk is prefilled below
+                } else if !client.is_least_outstanding() {
+                    // The waiting requests are left to a less loaded replica
+                } else if let Some((new_entries, new_batch, span)) = queue
+                    .next_batch(
+                        min_size,
+                        max_batch_prefill_tokens,
+                        new_batch_token_budget,
+                        new_batch_decode_token_budget,
+                    )
                     .await
                 {
                     // Tracking metrics
@@ -314,17 +1056,66 @@ async fn batching_task(
                         entry.temp_span = Some(entry_waiting_span);
                     });
 
-                    // Generate one token for this new batch to have the attention past in cache
-                    let new_cached_batch =
-                        prefill(&mut client, new_batch, &mut new_entries, &generation_health)
+                    chunked = Some(ChunkedPrefill::new(
+                        new_batch,
+                        new_entries,
+                        span,
+                        max_prefill_chunk_tokens,
+                    ));
+                } else if preemption
+                    && preemption::preempt(&queue, &mut entries, new_batch_token_budget).await
+                {
+                    // Remove the preempted entries from the running batch
+                    let batch = batches.pop().unwrap();
+                    match filter_batch(&mut client, Some(batch), &entries).await {
+                        Some(batch) => batches.push(batch),
+                        None => {
+                            cached_batch = None;
+                            continue;
+                        }
+                    }
+                    // Add the waiting requests at the next step whatever the size of the batch
+                    waiting_tokens = max_waiting_tokens;
+                }
+
+                if let Some(mut new_chunked) = chunked.take() {
+                    match new_chunked.next_chunk() {
+                        // Prefill a chunk of the new batch before the next decoding step
+                        Some(chunk) => {
+                            let span = new_chunked.span.clone();
+                            if prefill_chunk(
+                                &mut client,
+                                chunk,
+                                &mut new_chunked.entries,
+                                &generation_health,
+                                &mut token_budget,
+                            )
+                            .instrument(span)
+                            .await
+                            {
+                                chunked = Some(new_chunked);
+                            }
+                        }
+                        None => {
+                            let (new_batch, mut new_entries, span) = new_chunked.last_chunk();
+                            // Generate one token for this new batch to have the attention past in cache
+                            let new_cached_batch = prefill(
+                                &mut client,
+                                new_batch,
+                                &mut new_entries,
+                                &generation_health,
+                                &mut token_budget,
+                            )
                             .instrument(span)
                             .await;
-                    // Reset waiting counter
-                    waiting_tokens = 1;
-                    // Extend current batch with the new batch
-                    if let Some(new_cached_batch) = new_cached_batch {
-                        entries.extend(new_entries);
-                        batches.push(new_cached_batch);
+                            // Reset waiting counter
+                            waiting_tokens = 1;
+                            // Extend current batch with the new batch
+                            if let Some(new_cached_batch) = new_cached_batch {
+                                entries.extend(new_entries);
+                                batches.push(new_cached_batch);
+                            }
+                        }
                     }
                 }
 
@@ -342,13 +1133,36 @@ async fn batching_task(
                     entry.temp_span = Some(entry_batch_span);
                 });
 
-                cached_batch = decode(&mut client, batches, &mut entries, &generation_health)
-                    .instrument(next_batch_span)
-                    .await;
+                cached_batch = decode(
+                    &mut client,
+                    batches,
+                    &mut entries,
+                    &generation_health,
+                    &mut token_budget,
+                )
+                .instrument(next_batch_span)
+                .await;
                 waiting_tokens += 1;
+                // The prefills of the new batches delayed the tokens of the running requests
+                let now = Instant::now();
+                served_ratio.on_token(now - last_token_time);
+                last_token_time = now;
+
+                if cached_batch.is_none() {
+                    cached_batch = prefill_remaining(
+                        &mut client,
+                        chunked.take(),
+                        &mut entries,
+                        &generation_health,
+                        &mut token_budget,
+                    )
+                    .await;
+                }
             }
-            metrics::gauge!("tgi_batch_current_size", 0.0);
-            metrics::gauge!("tgi_batch_current_max_tokens", 0.0);
+            client.set_outstanding(0);
+            metrics::gauge!("tgi_batch_current_size", 0.0, "model" => model_id.clone());
+            metrics::gauge!("tgi_batch_current_max_tokens", 0.0, "model" => model_id.clone());
+            metrics::gauge!("tgi_request_running", 0.0, "model" => model_id.clone());
         }
     }
 }
@@ -358,19 +1172,32 @@ async fn prefill(
     client: &mut ShardedClient,
     batch: Batch,
     entries: &mut IntMap<u64, Entry>,
-    generation_health: &Arc<AtomicBool>,
+    generation_health: &GenerationHealth,
+    token_budget: &mut TokenBudget,
 ) -> Option<CachedBatch> {
     let start_time = Instant::now();
     let batch_id = batch.id;
     metrics::increment_counter!("tgi_batch_inference_count", "method" => "prefill");
+    metrics::histogram!("tgi_batch_forward_size", batch.size as f64, "method" => "prefill");
+    metrics::histogram!("tgi_batch_forward_max_tokens", batch.max_tokens as f64, "method" => "prefill");
+    let prefill_tokens: u32 = entries
+        .values()
+        .map(|entry| entry.request.input_length)
+        .sum();
+    metrics::histogram!("tgi_batch_forward_prefill_tokens", prefill_tokens as f64);
 
     match client.prefill(batch).await {
         Ok((generations, next_batch)) => {
             // Update health
-            generation_health.store(true, Ordering::SeqCst);
+            generation_health.set(true);
             // Send generated tokens and filter stopped entries
             filter_send_generations(generations, entries);
 
+            // The shards report their free blocks before the batch is filtered
+            token_budget.on_free_blocks(
+                next_batch.as_ref().and_then(|batch| batch.free_blocks),
+                BLOCK_SIZE,
+            );
             // Filter next batch and remove requests that were stopped
             let next_batch = filter_batch(client, next_batch, entries).await;
 
@@ -381,7 +1208,8 @@ async fn prefill(
         // If we have an error, we discard the whole batch
         Err(err) => {
             // Update health
-            generation_health.store(false, Ordering::SeqCst);
+            generation_health.set(false);
+            token_budget.on_error(&err);
             let _ = client.clear_cache(Some(batch_id)).await;
             send_errors(err, entries);
             metrics::increment_counter!("tgi_batch_inference_failure", "method" => "prefill");
@@ -390,24 +1218,137 @@ async fn prefill(
     }
 }
 
+/// Number of tokens held by the running entries and number of tokens they can still generate
+fn running_tokens(entries: &IntMap<u64, Entry>) -> (u32, u32) {
+    entries.values().fold((0, 0), |(used, decode), entry| {
+        let request = &entry.request;
+        (
+            used + request.input_length + entry.generated_tokens,
+            decode
+                + request
+                    .stopping_parameters
+                    .max_new_tokens
+                    .saturating_sub(entry.generated_tokens),
+        )
+    })
+}
+
+/// Prefill a partial chunk of a new batch, its requests have no generation until the last chunk
+///
+/// Returns whether the chunk was prefilled
+#[instrument(skip_all)]
+async fn prefill_chunk(
+    client: &mut ShardedClient,
+    batch: Batch,
+    entries: &mut IntMap<u64, Entry>,
+    generation_health: &GenerationHealth,
+    token_budget: &mut TokenBudget,
+) -> bool {
+    let start_time = Instant::now();
+    let batch_id = batch.id;
+    metrics::increment_counter!("tgi_batch_inference_count", "method" => "prefill_chunk");
+    let chunk_tokens: u32 = batch
+        .requests
+        .iter()
+        .filter_map(|request| request.prefill_chunk_len)
+        .sum();
+    metrics::histogram!("tgi_batch_forward_prefill_tokens", chunk_tokens as f64);
+
+    let result = client.prefill(batch).await.and_then(|(generations, next_batch)| {
+        // The shards only fill the KV cache of a partial chunk and keep its batch under its ID:
+        // no request has a generation before the last chunk
+        match generations.is_empty() && next_batch.is_none() {
+            true => Ok(()),
+            false => Err(ClientError::Generation(
+                "the shards generated tokens before the last chunk of a prefill".to_string(),
+            )),
+        }
+    });
+    match result {
+        Ok(()) => {
+            // Update health
+            generation_health.set(true);
+            metrics::histogram!("tgi_batch_inference_duration", start_time.elapsed().as_secs_f64(), "method" => "prefill_chunk");
+            metrics::increment_counter!("tgi_batch_inference_success", "method" => "prefill_chunk");
+            true
+        }
+        // If we have an error, we discard the whole batch
+        Err(err) => {
+            // Update health
+            generation_health.set(false);
+            token_budget.on_error(&err);
+            let _ = client.clear_cache(Some(batch_id)).await;
+            send_errors(err, entries);
+            metrics::increment_counter!("tgi_batch_inference_failure", "method" => "prefill_chunk");
+            false
+        }
+    }
+}
+
+/// Prefill the remaining input tokens of a chunked new batch at once, once there is no running
+/// batch left to interleave its chunks with
+async fn prefill_remaining(
+    client: &mut ShardedClient,
+    chunked: Option<ChunkedPrefill>,
+    entries: &mut IntMap<u64, Entry>,
+    generation_health: &GenerationHealth,
+    token_budget: &mut TokenBudget,
+) -> Option<CachedBatch> {
+    let (batch, mut new_entries, span) = chunked?.last_chunk();
+    let cached_batch = prefill(
+        client,
+        batch,
+        &mut new_entries,
+        generation_health,
+        token_budget,
+    )
+    .instrument(span)
+    .await;
+    entries.extend(new_entries);
+    cached_batch
+}
+
 #[instrument(skip_all)]
 async fn decode(
     client: &mut ShardedClient,
     batches: Vec<CachedBatch>,
     entries: &mut IntMap<u64, Entry>,
-    generation_health: &Arc<AtomicBool>,
+    generation_health: &GenerationHealth,
+    token_budget: &mut TokenBudget,
 ) -> Option<CachedBatch> {
     let start_time = Instant::now();
     let batch_ids: Vec<u64> = batches.iter().map(|b| b.id).collect();
     metrics::increment_counter!("tgi_batch_inference_count", "method" => "decode");
+    let batch_size: u32 = batches.iter().map(|b| b.size).sum();
+    let batch_max_tokens: u32 = batches.iter().map(|b| b.max_tokens).sum();
+    metrics::histogram!("tgi_batch_forward_size", batch_size as f64, "method" => "decode");
+    metrics::histogram!("tgi_batch_forward_max_tokens", batch_max_tokens as f64, "method" => "decode");
+
+    let result = if client.streams_decode() {
+        // Generated tokens are sent as soon as they are received from the shards
+        client
+            .decode_stream(batches, |generations| {
+                filter_send_generations(generations, entries)
+            })
+            .await
+            .map(|next_batch| (Vec::new(), next_batch))
+    } else {
+        client.decode(batches).await
+    };
 
-    match client.decode(batches).await {
+    match result {
         Ok((generations, next_batch)) => {
             // Update health
-            generation_health.store(true, Ordering::SeqCst);
+            generation_health.decoded();
+            token_budget.on_decode(start_time.elapsed());
             // Send generated tokens and filter stopped entries
             filter_send_generations(generations, entries);
 
+            // The shards report their free blocks before the batch is filtered
+            token_budget.on_free_blocks(
+                next_batch.as_ref().and_then(|batch| batch.free_blocks),
+                BLOCK_SIZE,
+            );
             // Filter next batch and remove requests that were stopped
             let next_batch = filter_batch(client, next_batch, entries).await;
 
@@ -417,7 +1358,8 @@ async fn decode(
         }
         // If we have an error, we discard the whole batch
         Err(err) => {
-            generation_health.store(false, Ordering::SeqCst);
+            generation_health.set(false);
+            token_budget.on_error(&err);
             for id in batch_ids {
                 let _ = client.clear_cache(Some(id)).await;
             }
@@ -461,6 +1403,30 @@ async fn filter_batch(
     }
 }
 
+/// Remove the requests whose client disconnected from `entries` and from `batch`
+///
+/// Clients disconnect by dropping their response stream, which is only noticed when the next
+/// generation is sent. Checking before each decode avoids generating one more token for them.
+#[instrument(skip_all)]
+async fn filter_cancelled(
+    client: &mut ShardedClient,
+    batch: CachedBatch,
+    entries: &mut IntMap<u64, Entry>,
+) -> Option<CachedBatch> {
+    entries.retain(|&id, entry| {
+        if entry.response_tx.is_disconnected() {
+            metrics::increment_counter!("tgi_request_failure", "err" => "cancelled");
+            tracing::info!(parent: &entry.span, "Request cancelled by the client");
+            if let Some(audit_log) = &entry.audit_log {
+                audit_log.record_cancelled(id, entry);
+            }
+            return false;
+        }
+        true
+    });
+    filter_batch(client, Some(batch), entries).await
+}
+
 /// Send one or multiple `InferStreamResponse` to Infer for all `entries`
 /// and filter entries
 #[instrument(skip_all)]
@@ -470,7 +1436,7 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
         // Get entry
         // We can `expect` here as the request id should always be in the entries
         let entry = entries
-            .get(&id)
+            .get_mut(&id)
             .expect("ID not found in entries. This is a bug.");
 
         // Create and enter a span to link this function back to the entry
@@ -488,6 +1454,10 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
         }).unwrap_or(true);
         if stopped {
             entries.remove(&id).expect("ID not found in entries. This is a bug.");
+        } else if entry.expired(Instant::now()) {
+            // Abort the generation, the request will be removed from the batch by `filter_batch`
+            let entry = entries.remove(&id).expect("ID not found in entries. This is a bug.");
+            entry.notify_timeout(id);
         }
     });
 }
@@ -495,7 +1465,7 @@ fn filter_send_generations(generations: Vec<Generation>, entries: &mut IntMap<u6
 /// Send responses through the `entry` response channel
 fn send_responses(
     generation: Generation,
-    entry: &Entry,
+    entry: &mut Entry,
 ) -> Result<bool, Box<SendTimeoutError<Result<InferStreamResponse, InferError>>>> {
     // Return directly if the channel is disconnected
     if entry.response_tx.is_disconnected() {
@@ -504,6 +1474,10 @@ fn send_responses(
 
     let mut stopped = false;
 
+    if let Some(session) = &mut entry.session {
+        session.push_token(generation.token_id);
+    }
+
     if let Some(prefill_tokens) = generation.prefill_tokens {
         // Send message
         entry.response_tx.send_timeout(
@@ -513,30 +1487,119 @@ fn send_responses(
     }
 
     // Create last Token
-    let token = Token {
+    let mut token = Token {
         id: generation.token_id,
         text: generation.token_text,
         logprob: generation.token_logprob,
         special: generation.token_is_special,
     };
+    entry.generated_tokens += 1;
+    if let Some(progress) = &mut entry.progress {
+        progress.push(&token.text);
+    }
 
-    if let Some(generated_text) = generation.generated_text {
+    // Create top tokens
+    let top_tokens = generation
+        .top_tokens
+        .map(|top_tokens| {
+            top_tokens
+                .ids
+                .into_iter()
+                .zip(top_tokens.logprobs)
+                .zip(top_tokens.texts)
+                .zip(top_tokens.is_special)
+                .map(|(((id, logprob), text), special)| Token {
+                    id,
+                    text,
+                    logprob,
+                    special,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    // The shards only know of the text generated since the request was last preempted
+    let mut generated_text = match &entry.progress {
+        Some(progress) => generation
+            .generated_text
+            .map(|generated_text| progress.complete(generated_text)),
+        None => generation.generated_text,
+    };
+    if let (true, Some(choice)) = (generated_text.is_none(), &mut entry.choice) {
+        // The rest of the choice is forced by the grammar, stop the generation early
+        if let Some(rest) = choice.push(&token.text) {
+            token.text.push_str(&rest);
+            metrics::increment_counter!("tgi_request_choice_early_stop");
+            generated_text = Some(GeneratedText {
+                text: format!("{}{rest}", choice.generated()),
+                generated_tokens: choice.generated_tokens(),
+                finish_reason: FinishReason::EosToken as i32,
+                seed: entry
+                    .request
+                    .parameters
+                    .do_sample
+                    .then_some(entry.request.parameters.seed),
+            });
+        }
+    }
+    if let (true, Some(stop_regex)) = (generated_text.is_none(), &mut entry.stop_regex) {
+        // The shards do not evaluate the regex stop sequences, stop the generation here
+        if stop_regex.push(&token.text) {
+            metrics::increment_counter!("tgi_request_stop_regex");
+            generated_text = Some(GeneratedText {
+                text: stop_regex.generated().to_string(),
+                generated_tokens: stop_regex.generated_tokens(),
+                finish_reason: FinishReason::StopSequence as i32,
+                seed: entry
+                    .request
+                    .parameters
+                    .do_sample
+                    .then_some(entry.request.parameters.seed),
+            });
+        }
+    }
+
+    if let Some(generated_text) = generated_text {
         // Generation has ended
         stopped = true;
+        if let Some(audit_log) = &entry.audit_log {
+            audit_log.record_end(generation.request_id, entry, &generated_text);
+        }
+        if let Some(length_prediction) = &entry.length_prediction {
+            length_prediction.finish(generated_text.generated_tokens);
+        }
+        if let Some(session) = &mut entry.session {
+            session.finish(&entry.request.input_ids);
+        }
+        // Save the paused generation to continue it from its session
+        let continuation = match (&entry.continuations, &entry.request.session_id) {
+            (Some(continuations), Some(session_id))
+                if generated_text.finish_reason == FinishReason::Length as i32 =>
+            {
+                Some(continuations.pause(
+                    session_id,
+                    format!("{}{}", entry.request.inputs, generated_text.text),
+                    entry.request.tenant.as_ref().map(Tenant::name),
+                ))
+            }
+            _ => None,
+        };
         // Send message
         entry.response_tx.send_timeout(
             Ok(InferStreamResponse::End {
                 token,
+                top_tokens,
                 generated_text,
                 queued: entry.queue_time,
                 start: entry.batch_time.unwrap(),
+                continuation,
             }),
             Duration::from_millis(10),
         )?;
     } else {
         // Send message
         entry.response_tx.send_timeout(
-            Ok(InferStreamResponse::Token(token)),
+            Ok(InferStreamResponse::Intermediate { token, top_tokens }),
             Duration::from_millis(10),
         )?;
     }
@@ -546,12 +1609,15 @@ fn send_responses(
 /// Send errors to Infer for all `entries`
 #[instrument(skip_all)]
 fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
-    entries.drain().for_each(|(_, entry)| {
+    entries.drain().for_each(|(id, entry)| {
         // Create and enter a span to link this function back to the entry
         let _send_error_span = info_span!(parent: entry.temp_span.as_ref().expect("batch_span is None. This is a bug."), "send_error").entered();
         let err = InferError::GenerationError(error.to_string());
         metrics::increment_counter!("tgi_request_failure", "err" => "generation");
         tracing::error!("{err}");
+        if let Some(audit_log) = &entry.audit_log {
+            audit_log.record_error(id, &entry, &err);
+        }
 
         // unwrap_or is valid here as we don't care if the receiver is gone.
         entry
@@ -561,28 +1627,46 @@ fn send_errors(error: ClientError, entries: &mut IntMap<u64, Entry>) {
     });
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) enum InferStreamResponse {
+    // Position of the request while it is queued, only sent if the request asked for it
+    Queued {
+        position: usize,
+        estimated_wait: Option<Duration>,
+    },
     // Optional first message
     Prefill(PrefillTokens),
     // Intermediate messages
-    Token(Token),
+    Intermediate {
+        token: Token,
+        top_tokens: Vec<Token>,
+    },
     // Last message
     End {
         token: Token,
+        top_tokens: Vec<Token>,
         generated_text: GeneratedText,
         start: Instant,
         queued: Instant,
+        /// Continuation token of the generation if it paused after `pause_after` tokens
+        continuation: Option<String>,
     },
 }
 
 #[derive(Debug)]
 pub(crate) struct InferResponse {
+    /// input_length is the input as perceived by the rust tokenizer in the
+    /// validation pathway. It is redundant with prefill.len() but prefill
+    /// has data only if the user asked for it. This will always be filled.
+    pub(crate) input_length: u32,
     pub(crate) prefill: Vec<PrefillToken>,
     pub(crate) tokens: Vec<Token>,
+    pub(crate) top_tokens: Vec<Vec<Token>>,
     pub(crate) generated_text: GeneratedText,
     pub(crate) queued: Instant,
     pub(crate) start: Instant,
+    /// Continuation token of the generation if it paused after `pause_after` tokens
+    pub(crate) continuation: Option<String>,
 }
 
 #[derive(Debug, Error)]
@@ -590,11 +1674,27 @@ pub enum InferError {
     #[error("Request failed during generation: {0}")]
     GenerationError(String),
     #[error("Model is overloaded")]
-    Overloaded(#[from] TryAcquireError),
+    Overloaded(QueueLoad),
     #[error("Input validation error: {0}")]
     ValidationError(#[from] ValidationError),
     #[error("Incomplete generation")]
     IncompleteGeneration,
+    #[error("This model does not provide a chat template")]
+    MissingChatTemplate,
+    #[error("Template error: {0}")]
+    TemplateError(#[from] minijinja::Error),
+    #[error("Request timed out")]
+    Timeout,
+    #[error("Request waited in the queue for more than {}ms", .0.as_millis())]
+    QueueTimeout(Duration),
+    #[error("Session is busy with another request")]
+    SessionBusy,
+    #[error("Too many sessions")]
+    SessionsFull,
+    #[error("Model {0} is not served")]
+    ModelNotFound(String),
+    #[error("Request cancelled")]
+    Cancelled,
 }
 
 impl InferError {
@@ -602,8 +1702,65 @@ impl InferError {
         match self {
             InferError::GenerationError(_) => "generation",
             InferError::Overloaded(_) => "overloaded",
+            InferError::ValidationError(ValidationError::PolicyViolation(_)) => "policy_violation",
+            InferError::ValidationError(ValidationError::ContentFilter(_)) => "content_filter",
             InferError::ValidationError(_) => "validation",
             InferError::IncompleteGeneration => "incomplete_generation",
+            InferError::MissingChatTemplate | InferError::TemplateError(_) => "template_error",
+            InferError::Timeout => "timeout",
+            InferError::QueueTimeout(_) => "queue_timeout",
+            InferError::SessionBusy => "session_busy",
+            InferError::SessionsFull => "sessions_full",
+            InferError::ModelNotFound(_) => "model_not_found",
+            InferError::Cancelled => "cancelled",
         }
     }
+
+    /// Load of the router sent with the error
+    pub(crate) fn load(&self) -> Option<QueueLoad> {
+        match self {
+            InferError::Overloaded(load) => Some(load.clone()),
+            _ => None,
+        }
+    }
+
+    /// Policy violated by the request sent with the error
+    pub(crate) fn policy_violation(&self) -> Option<PolicyViolation> {
+        match self {
+            InferError::ValidationError(ValidationError::PolicyViolation(violation)) => {
+                Some(violation.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_concurrency_limit_resize() {
+        let limit = ConcurrencyLimit::new(2);
+        let first = limit.try_acquire().unwrap();
+        let second = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_err());
+        assert_eq!(limit.in_flight(), 2);
+
+        // Shrinking waits for the running requests
+        limit.resize(1);
+        assert_eq!(limit.max(), 1);
+        assert_eq!(limit.in_flight(), 2);
+        drop(first);
+        tokio::task::yield_now().await;
+        assert_eq!(limit.in_flight(), 1);
+        assert!(limit.try_acquire().is_err());
+        drop(second);
+        assert_eq!(limit.in_flight(), 0);
+
+        limit.resize(3);
+        let _permits: Vec<_> = (0..3).map(|_| limit.try_acquire().unwrap()).collect();
+        assert!(limit.try_acquire().is_err());
+        assert_eq!(limit.in_flight(), 3);
+    }
 }
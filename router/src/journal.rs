@@ -0,0 +1,390 @@
+/// Journal of the queued requests, replayed when the router restarts
+use crate::infer::InferError;
+use crate::models::Models;
+use crate::GenerateRequest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Interval between two attempts to replay a request rejected by the concurrency limit
+const REPLAY_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of dequeued records after which the journal is rewritten with only the queued records
+const COMPACTION_THRESHOLD: usize = 1024;
+
+/// Record of the journal, one JSON object per line
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Record {
+    /// The request was added to the queue of the model
+    Queued {
+        id: u64,
+        model: String,
+        request: GenerateRequest,
+    },
+    /// The request left the queue, batched or dropped
+    Dequeued { id: u64 },
+}
+
+/// Appends the requests accepted but not batched yet to a file, so that a restart of the router
+/// does not drop them
+///
+/// Records are written and synced by a background thread. The file is truncated whenever the
+/// queues are empty, and rewritten with only the records of the queued requests once enough
+/// requests left the queues.
+#[derive(Clone, Debug)]
+pub(crate) struct QueueJournal {
+    next_id: Arc<AtomicU64>,
+    sender: flume::Sender<Record>,
+}
+
+impl QueueJournal {
+    /// Open the journal at `path`, returning the requests left queued by the previous run of the
+    /// router
+    ///
+    /// The returned requests stay in the journal until they are batched again or their replay
+    /// fails, so that a crash before their replay does not lose them.
+    pub(crate) fn open(path: &Path) -> std::io::Result<(Self, Vec<JournaledRequest>)> {
+        let pending = match File::open(path) {
+            Ok(file) => pending(BufReader::new(file))?,
+            Err(err) if err.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+
+        // The journal is replaced by one holding only the records of the pending requests
+        let mut queued = BTreeMap::new();
+        for (id, model, request) in pending.iter() {
+            let record = Record::Queued {
+                id: *id,
+                model: model.clone(),
+                request: request.clone(),
+            };
+            queued.insert(*id, line(&record)?);
+        }
+        let mut journal_file = JournalFile {
+            file: rewrite(path, queued.values())?,
+            path: path.to_path_buf(),
+            queued,
+            dequeued: 0,
+        };
+
+        let (sender, receiver) = flume::unbounded::<Record>();
+        std::thread::spawn(move || {
+            while let Ok(record) = receiver.recv() {
+                if let Err(err) = journal_file.write(&record) {
+                    metrics::increment_counter!("tgi_queue_journal_failure");
+                    tracing::error!("Could not write to the queue journal: {err}");
+                }
+            }
+        });
+
+        let next_id = pending.last().map_or(0, |(id, _, _)| id + 1);
+        let pending = pending
+            .into_iter()
+            .map(|(id, model_id, mut request)| {
+                // Batching the replayed request dequeues its record
+                request.parameters.journal_id = Some(id);
+                JournaledRequest {
+                    model_id,
+                    request,
+                    entry: JournalEntry {
+                        id,
+                        sender: sender.clone(),
+                    },
+                }
+            })
+            .collect();
+        let journal = Self {
+            next_id: Arc::new(AtomicU64::new(next_id)),
+            sender,
+        };
+        Ok((journal, pending))
+    }
+
+    /// Journal a request added to the queue of `model_id`, until the returned entry is dropped
+    ///
+    /// A request replayed from the journal keeps its record.
+    pub(crate) fn queued(&self, model_id: &str, request: &GenerateRequest) -> JournalEntry {
+        let id = request
+            .parameters
+            .journal_id
+            .unwrap_or_else(|| self.next_id.fetch_add(1, Ordering::SeqCst));
+        let _ = self.sender.send(Record::Queued {
+            id,
+            model: model_id.to_string(),
+            request: request.clone(),
+        });
+        JournalEntry {
+            id,
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+/// Request left queued by the previous run of the router
+#[derive(Debug)]
+pub(crate) struct JournaledRequest {
+    model_id: String,
+    request: GenerateRequest,
+    /// Record of the request, dequeued if its replay fails before it is batched
+    entry: JournalEntry,
+}
+
+/// Request in the journal, recorded as dequeued when dropped
+#[derive(Debug)]
+pub(crate) struct JournalEntry {
+    id: u64,
+    sender: flume::Sender<Record>,
+}
+
+impl Drop for JournalEntry {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Record::Dequeued { id: self.id });
+    }
+}
+
+/// Journal file written by the background thread
+struct JournalFile {
+    path: PathBuf,
+    file: File,
+    /// Lines of the records of the queued requests, by ID
+    queued: BTreeMap<u64, Vec<u8>>,
+    /// Number of dequeued records written since the journal was last rewritten
+    dequeued: usize,
+}
+
+impl JournalFile {
+    /// Write a record to the journal, truncating it when nothing is queued anymore and
+    /// compacting it when enough requests were dequeued
+    fn write(&mut self, record: &Record) -> std::io::Result<()> {
+        match record {
+            Record::Queued { id, .. } => {
+                // A replayed request is still in the journal
+                if self.queued.contains_key(id) {
+                    return Ok(());
+                }
+                let line = line(record)?;
+                self.file.write_all(&line)?;
+                self.queued.insert(*id, line);
+            }
+            Record::Dequeued { id } => {
+                if self.queued.remove(id).is_none() {
+                    return Ok(());
+                }
+                if self.queued.is_empty() {
+                    self.file.set_len(0)?;
+                    self.file.seek(SeekFrom::Start(0))?;
+                    self.dequeued = 0;
+                } else if self.dequeued + 1 >= COMPACTION_THRESHOLD {
+                    self.file = rewrite(&self.path, self.queued.values())?;
+                    self.dequeued = 0;
+                    return Ok(());
+                } else {
+                    self.file.write_all(&line(record)?)?;
+                    self.dequeued += 1;
+                }
+            }
+        }
+        self.file.sync_data()
+    }
+}
+
+/// Line of a record in the journal
+fn line(record: &Record) -> std::io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Replace the journal at `path` with `lines`, returning the new journal opened for writing
+///
+/// The lines are written to a temporary file renamed over the journal once synced, so that a
+/// crash leaves either the previous or the new journal whole.
+fn rewrite<'a>(path: &Path, lines: impl Iterator<Item = &'a Vec<u8>>) -> std::io::Result<File> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = File::create(&temp_path)?;
+    for line in lines {
+        file.write_all(line)?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    Ok(file)
+}
+
+/// Requests queued and never dequeued with their ID and model, in the order they were queued
+fn pending(reader: impl BufRead) -> std::io::Result<Vec<(u64, String, GenerateRequest)>> {
+    let mut pending = BTreeMap::new();
+    for line in reader.lines() {
+        // The last record may be partially written if the router crashed
+        let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+            tracing::warn!("Skipping an invalid record of the queue journal");
+            continue;
+        };
+        match record {
+            Record::Queued { id, model, request } => {
+                pending.insert(id, (model, request));
+            }
+            Record::Dequeued { id } => {
+                pending.remove(&id);
+            }
+        }
+    }
+    Ok(pending
+        .into_iter()
+        .map(|(id, (model, request))| (id, model, request))
+        .collect())
+}
+
+/// Generate again the requests left queued by the previous run of the router
+///
+/// Their clients are gone: the outcome is only recorded in the logs, metrics and audit log.
+pub(crate) fn replay(models: &Models, pending: Vec<JournaledRequest>) {
+    if pending.is_empty() {
+        return;
+    }
+    tracing::info!("Replaying {} requests of the queue journal", pending.len());
+
+    for JournaledRequest {
+        model_id,
+        request,
+        entry,
+    } in pending
+    {
+        let infer = match models.get(Some(&model_id)) {
+            Ok((_, infer)) => infer,
+            Err(err) => {
+                tracing::warn!("Dropping a request of the queue journal: {err}");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            // The record is dequeued when the request is batched, or here if it never is
+            let _entry = entry;
+            loop {
+                match infer.generate(request.clone()).await {
+                    Ok(_) => {
+                        metrics::increment_counter!("tgi_queue_journal_replayed");
+                        break;
+                    }
+                    Err(InferError::Overloaded(_)) => {
+                        tokio::time::sleep(REPLAY_RETRY_INTERVAL).await
+                    }
+                    Err(err) => {
+                        tracing::error!("Replayed request of the queue journal failed: {err}");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(inputs: &str) -> GenerateRequest {
+        serde_json::from_value(serde_json::json!({ "inputs": inputs })).unwrap()
+    }
+
+    #[test]
+    fn test_pending() {
+        let records = [
+            Record::Queued {
+                id: 0,
+                model: "model".to_string(),
+                request: request("first"),
+            },
+            Record::Queued {
+                id: 1,
+                model: "model".to_string(),
+                request: request("second"),
+            },
+            Record::Dequeued { id: 0 },
+        ];
+        let mut journal = String::new();
+        for record in records.iter() {
+            journal.push_str(&serde_json::to_string(record).unwrap());
+            journal.push('\n');
+        }
+        // Record partially written by a crash
+        journal.push_str("{\"op\":\"queued\",\"id\":2,");
+
+        let pending = pending(journal.as_bytes()).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0, 1);
+        assert_eq!(pending[0].1, "model");
+        assert_eq!(pending[0].2.inputs, "second");
+    }
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("queue-journal-{}.jsonl", rand::random::<u64>()))
+    }
+
+    fn read_pending(path: &Path) -> Vec<(u64, String, GenerateRequest)> {
+        pending(BufReader::new(File::open(path).unwrap())).unwrap()
+    }
+
+    #[test]
+    fn test_open_keeps_pending() {
+        let path = temp_path();
+        let record = Record::Queued {
+            id: 3,
+            model: "model".to_string(),
+            request: request("pending"),
+        };
+        std::fs::write(&path, line(&record).unwrap()).unwrap();
+
+        let (journal, pending) = QueueJournal::open(&path).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].request.parameters.journal_id, Some(3));
+        // The pending request is not lost if the router crashes before replaying it
+        assert_eq!(read_pending(&path)[0].0, 3);
+        // The new requests do not reuse the IDs of the pending ones
+        assert_eq!(journal.queued("model", &request("new")).id, 4);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_compaction() {
+        let path = temp_path();
+        let mut journal_file = JournalFile {
+            file: rewrite(&path, std::iter::empty()).unwrap(),
+            path: path.clone(),
+            queued: BTreeMap::new(),
+            dequeued: 0,
+        };
+        let queued = |id| Record::Queued {
+            id,
+            model: "model".to_string(),
+            request: request("queued"),
+        };
+
+        // A request stays queued while many others are dequeued
+        journal_file.write(&queued(0)).unwrap();
+        for id in 1..=COMPACTION_THRESHOLD as u64 {
+            journal_file.write(&queued(id)).unwrap();
+            journal_file.write(&Record::Dequeued { id }).unwrap();
+        }
+        // Only the record of the queued request is left
+        let journal = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(journal.lines().count(), 1);
+        assert_eq!(read_pending(&path)[0].0, 0);
+
+        // The journal is appended to after its compaction
+        journal_file.write(&queued(2000)).unwrap();
+        assert_eq!(read_pending(&path).len(), 2);
+
+        // And truncated once nothing is queued
+        journal_file.write(&Record::Dequeued { id: 0 }).unwrap();
+        journal_file.write(&Record::Dequeued { id: 2000 }).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+/// Prediction of the number of tokens generated by the requests, to batch together the requests
+/// expected to finish at similar times
+use std::sync::{Arc, Mutex};
+
+/// Number of buckets of `max_new_tokens`, one per power of two
+const BUCKETS: usize = u32::BITS as usize + 1;
+
+/// Weight of the last finished request in the average of its bucket
+const WEIGHT: f64 = 0.05;
+
+/// Ratio above which two requests are not expected to finish at similar times
+const MAX_LENGTH_RATIO: u32 = 2;
+
+/// Learns the average fraction of `max_new_tokens` generated by the requests before they stop,
+/// by bucket of `max_new_tokens`
+///
+/// Until a request of a bucket finishes, the requests of the bucket are predicted to generate
+/// `max_new_tokens` tokens.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct LengthPredictor {
+    ratios: Arc<Mutex<[Option<f64>; BUCKETS]>>,
+}
+
+impl LengthPredictor {
+    /// Predicted number of tokens generated by a request
+    pub(crate) fn predict(&self, max_new_tokens: u32) -> LengthPrediction {
+        let ratio = self.ratios.lock().unwrap()[bucket(max_new_tokens)].unwrap_or(1.0);
+        LengthPrediction {
+            predictor: self.clone(),
+            max_new_tokens,
+            tokens: (max_new_tokens as f64 * ratio).round() as u32,
+        }
+    }
+
+    fn observe(&self, max_new_tokens: u32, generated_tokens: u32) {
+        if max_new_tokens == 0 {
+            return;
+        }
+        let ratio = (generated_tokens as f64 / max_new_tokens as f64).min(1.0);
+        let mut ratios = self.ratios.lock().unwrap();
+        let average = &mut ratios[bucket(max_new_tokens)];
+        *average = Some(average.map_or(ratio, |average| average + WEIGHT * (ratio - average)));
+    }
+}
+
+fn bucket(max_new_tokens: u32) -> usize {
+    (u32::BITS - max_new_tokens.leading_zeros()) as usize
+}
+
+/// Predicted number of tokens generated by a request
+#[derive(Debug)]
+pub(crate) struct LengthPrediction {
+    predictor: LengthPredictor,
+    max_new_tokens: u32,
+    pub(crate) tokens: u32,
+}
+
+impl LengthPrediction {
+    /// Whether the request is expected to finish at a similar time as a request generating
+    /// `tokens` tokens
+    pub(crate) fn similar(&self, tokens: u32) -> bool {
+        let (shortest, longest) = (self.tokens.min(tokens), self.tokens.max(tokens));
+        longest <= shortest.max(1) * MAX_LENGTH_RATIO
+    }
+
+    /// Learn from the number of tokens the request actually generated
+    pub(crate) fn finish(&self, generated_tokens: u32) {
+        metrics::histogram!(
+            "tgi_request_length_prediction_error",
+            self.tokens as f64 - generated_tokens as f64
+        );
+        self.predictor
+            .observe(self.max_new_tokens, generated_tokens);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_prediction() {
+        let predictor = LengthPredictor::default();
+        let prediction = predictor.predict(100);
+        assert_eq!(prediction.tokens, 100);
+
+        prediction.finish(20);
+        assert_eq!(predictor.predict(100).tokens, 20);
+        // Same bucket of `max_new_tokens`
+        assert_eq!(predictor.predict(120).tokens, 24);
+        // Other bucket
+        assert_eq!(predictor.predict(1000).tokens, 1000);
+
+        assert!(predictor.predict(100).similar(40));
+        assert!(!predictor.predict(100).similar(41));
+        assert!(!predictor.predict(1000).similar(20));
+    }
+}
@@ -1,15 +1,63 @@
+mod admin;
+mod audit;
+mod auth;
+mod cancellation;
+mod choice;
+mod chunked_prefill;
+mod chunking;
+mod content_filter;
+mod continuation;
+mod dedup;
+mod defaults;
+mod fim;
+mod gbnf;
+mod grammar;
+mod grpc;
 mod health;
+mod holdback;
+mod idempotency;
+mod image;
 /// Text Generation Inference Webserver
 mod infer;
+mod journal;
+mod length_prediction;
+mod models;
+mod moderation;
+mod preemption;
+mod prefix_cache;
+mod prompt_templates;
 mod queue;
+mod rate_limit;
+mod redaction;
+mod request_id;
+mod scheduling;
+mod served_ratio;
 pub mod server;
+mod sessions;
+mod shedding;
+mod stop_regex;
+mod template;
+mod token_budget;
+mod usage;
 mod validation;
 
+pub use audit::{AuditConfig, AuditContent, AuditField};
+use auth::Tenant;
+use cancellation::CancelToken;
+use chunking::{StreamChunk, StreamGranularity};
+use clap::ValueEnum;
+use content_filter::PolicyViolation;
+pub use fim::FimTokens;
 use infer::Infer;
 use queue::{Entry, Queue};
+use rate_limit::RateLimit;
+pub use redaction::RedactionDirection;
+pub use scheduling::SchedulingPolicyKind;
 use serde::{Deserialize, Serialize};
+pub use shedding::SheddingPolicy;
+use std::collections::HashMap;
 use utoipa::ToSchema;
-use validation::Validation;
+use validation::{Validation, ValidationError};
 
 /// Hub type
 #[derive(Clone, Debug, Deserialize)]
@@ -20,6 +68,34 @@ pub struct HubModelInfo {
     pub pipeline_tag: Option<String>,
 }
 
+/// Subset of the `tokenizer_config.json` of a model
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HubTokenizerConfig {
+    #[serde(default)]
+    pub chat_template: Option<String>,
+    #[serde(default)]
+    pub bos_token: Option<TokenizerConfigToken>,
+    #[serde(default)]
+    pub eos_token: Option<TokenizerConfigToken>,
+}
+
+/// Special tokens are either serialized as a plain string or as an `AddedToken` object
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum TokenizerConfigToken {
+    String(String),
+    Object { content: String },
+}
+
+impl TokenizerConfigToken {
+    pub fn as_str(&self) -> &str {
+        match self {
+            TokenizerConfigToken::String(content) => content,
+            TokenizerConfigToken::Object { content } => content,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct Info {
     /// Model info
@@ -33,13 +109,20 @@ pub struct Info {
     pub model_device_type: String,
     #[schema(nullable = true, example = "text-generation")]
     pub model_pipeline_tag: Option<String>,
+    /// Ids of the served models, starting with the default model described above
+    #[schema(example = json ! (["bigscience/blomm-560m"]))]
+    pub models: Vec<String>,
     /// Router Parameters
     #[schema(example = "128")]
     pub max_concurrent_requests: usize,
     #[schema(example = "2")]
     pub max_best_of: usize,
     #[schema(example = "4")]
+    pub max_client_batch_size: usize,
+    #[schema(example = "4")]
     pub max_stop_sequences: usize,
+    #[schema(example = "5")]
+    pub max_top_n_tokens: u32,
     #[schema(example = "1024")]
     pub max_input_length: usize,
     #[schema(example = "2048")]
@@ -61,7 +144,7 @@ pub struct Info {
     pub docker_label: Option<&'static str>,
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub(crate) struct GenerateParameters {
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 1)]
@@ -82,6 +165,27 @@ pub(crate) struct GenerateParameters {
         example = 1.03
     )]
     pub repetition_penalty: Option<f32>,
+    /// Penalize the tokens proportionally to the number of times they were generated, as the
+    /// OpenAI API
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub frequency_penalty: Option<f32>,
+    /// Penalize the tokens that were generated at least once, as the OpenAI API
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub presence_penalty: Option<f32>,
     #[serde(default)]
     #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 10)]
     pub top_k: Option<i32>,
@@ -103,26 +207,130 @@ pub(crate) struct GenerateParameters {
         example = 0.95
     )]
     pub typical_p: Option<f32>,
+    /// Discard the tokens whose probability is lower than `min_p` times the probability of the
+    /// most likely token
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        exclusive_maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.05
+    )]
+    pub min_p: Option<f32>,
+    /// Discard the tokens whose probability is lower than `epsilon_cutoff`
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        exclusive_maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.0003
+    )]
+    pub epsilon_cutoff: Option<f32>,
+    /// Discard the tokens whose probability is lower than
+    /// `min(eta_cutoff, sqrt(eta_cutoff) * exp(-entropy))`, as in "Truncation Sampling as Language
+    /// Model Desmoothing"
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        exclusive_maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.0003
+    )]
+    pub eta_cutoff: Option<f32>,
+    /// Mirostat sampling mode, 1 or 2, adapting the truncation of every step to keep the surprise
+    /// of the generated text close to `mirostat_tau`
+    #[serde(default)]
+    #[schema(
+        minimum = 1,
+        maximum = 2,
+        nullable = true,
+        default = "null",
+        example = 2
+    )]
+    pub mirostat: Option<u32>,
+    /// Target surprise of mirostat sampling
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        nullable = true,
+        default = "5.0",
+        example = 5.0
+    )]
+    pub mirostat_tau: Option<f32>,
+    /// Learning rate of mirostat sampling
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "0.1",
+        example = 0.1
+    )]
+    pub mirostat_eta: Option<f32>,
     #[serde(default)]
     #[schema(default = "false", example = true)]
     pub do_sample: bool,
-    #[serde(default = "default_max_new_tokens")]
-    #[schema(exclusive_minimum = 0, exclusive_maximum = 512, default = "20")]
-    pub max_new_tokens: u32,
+    /// Defaults to 20 unless the router is configured with other defaults
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        exclusive_maximum = 512,
+        nullable = true,
+        default = "null",
+        example = 20
+    )]
+    pub max_new_tokens: Option<u32>,
     #[serde(default = "default_min_new_tokens")]
     #[schema(exclusive_minimum = 0, exclusive_maximum = 512, default = "0")]
     pub min_new_tokens: u32,
+    /// Prepend the prompt to the generated text. Defaults to false, except on the `/` route
+    /// of text-generation models
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = false)]
     pub return_full_text: Option<bool>,
+    /// Strings ending the generation, up to `max_stop_sequences` with `stop_regex`
     #[serde(default)]
-    #[schema(inline, max_items = 4, example = json ! (["photographer"]))]
+    #[schema(inline, example = json ! (["photographer"]))]
     pub stop: Vec<String>,
+    /// Regular expressions ending the generation once they match the generated text, counted
+    /// with `stop` against `max_stop_sequences`
+    #[serde(default)]
+    #[schema(inline, example = json ! (["\\n\\s*\\n"]))]
+    pub stop_regex: Vec<String>,
+    /// Token ids ending the generation, more robust than `stop` when a stop sequence has several
+    /// tokenizations
+    #[serde(default)]
+    #[schema(inline, max_items = 32, example = json ! ([2]))]
+    pub stop_token_ids: Vec<u32>,
+    /// Text following the generated text: the model fills in the middle between `inputs` and
+    /// `suffix`
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "\n    return result")]
+    pub suffix: Option<String>,
+    /// Words that must never appear in the generated text
+    #[serde(default)]
+    #[schema(inline, max_items = 32, example = json ! (["damn"]))]
+    pub bad_words: Vec<String>,
     #[serde(default)]
     #[schema(nullable = true, default = "null", example = "null")]
     pub truncate: Option<usize>,
+    /// Side the tokens are removed from when the inputs are longer than `truncate`. `middle`
+    /// keeps both the beginning and the end of the inputs, e.g. the system prompt and the
+    /// question of a retrieval augmented prompt
+    #[serde(default)]
+    #[schema(default = "left", example = "middle")]
+    pub truncation_side: TruncationSide,
+    /// What happens when the inputs and `max_new_tokens` exceed the context of the model,
+    /// the server default if unset. Ignored when `truncate` is set
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "truncate")]
+    pub on_overflow: Option<OverflowPolicy>,
+    /// Size of the n-grams of the generated text that cannot appear twice, 0 disables it
     #[serde(default = "default_no_repeat_ngram_size")]
-    #[schema(exclusive_minimum = 0, exclusive_maximum = 20, default="0")]
+    #[schema(minimum = 0, maximum = 20, default = "0", example = 3)]
     pub no_repeat_ngram_size: u32,
     #[serde(default)]
     #[schema(default = "false", example = true)]
@@ -133,6 +341,13 @@ pub(crate) struct GenerateParameters {
     #[serde(default)]
     #[schema(default = "true")]
     pub decoder_input_details: bool,
+    /// Score the prompt instead of generating: the response only contains the log probabilities
+    /// of the prompt tokens in the `prefill` details, e.g. to compute the perplexity of the
+    /// prompt. `max_new_tokens` is ignored
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub score_prompt: bool,
+    /// Sampling seed, random when unset. The seed used is returned in the details
     #[serde(default)]
     #[schema(
         exclusive_minimum = 0,
@@ -141,6 +356,191 @@ pub(crate) struct GenerateParameters {
         example = "null"
     )]
     pub seed: Option<u64>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ({"1234": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 5)]
+    pub top_n_tokens: Option<u32>,
+    #[serde(default)]
+    #[schema(
+        nullable = true,
+        default = "null",
+        value_type = Object,
+        example = json ! ({"type": "json", "value": {"type": "object", "properties": {"name": {"type": "string"}}}})
+    )]
+    pub grammar: Option<GrammarType>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "high")]
+    pub priority: Option<Priority>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0,
+        nullable = true,
+        default = "null",
+        example = 30000
+    )]
+    pub timeout_ms: Option<u64>,
+    /// Multi-turn session of the request: the next turn of the session only prefills the
+    /// tokens added after the prompt and generated text of this turn
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "conversation-42")]
+    pub session_id: Option<String>,
+    /// Name of a prompt template of the router rendered into the prompt, with the `inputs`
+    /// available as the `inputs` variable
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "summarize_v2")]
+    pub template: Option<String>,
+    /// Variables of the prompt `template`
+    #[serde(default)]
+    #[schema(value_type = Option<Object>, nullable = true, default = "null", example = json!({"language": "French"}))]
+    pub variables: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Pause the generation after this many tokens. The response then carries a `continuation`
+    /// token to continue the generation later without prefilling it again. Needs sessions.
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 64)]
+    pub pause_after: Option<u32>,
+    /// Continuation token of a paused generation of the same model to continue. The `inputs`
+    /// are appended to its prompt and generated text, they are usually empty
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub continuation: Option<String>,
+    /// Remove the last token of the prompt and let the model generate it again, the generated
+    /// text then starts with the removed text. Improves the completion of prompts ending in the
+    /// middle of a word or of a URL
+    #[serde(default)]
+    #[schema(default = "false", example = false)]
+    pub token_healing: bool,
+    /// Send `queue` events with the position of the request in the queue and an estimate of its
+    /// wait while it is queued, streaming only
+    #[serde(default)]
+    #[schema(default = "false", example = true)]
+    pub queue_events: bool,
+    /// Size of the chunks of streamed responses. Coarser chunks reduce the number of events
+    /// sent to clients on slow networks
+    #[serde(default)]
+    #[schema(default = "token", example = "word")]
+    pub stream_granularity: StreamGranularity,
+    /// Tenant of the API key of the request
+    #[serde(skip)]
+    pub tenant: Option<Tenant>,
+    /// Bucket the generated tokens of the request are taken from
+    #[serde(skip)]
+    pub rate_limit: Option<RateLimit>,
+    /// Cancelled by `DELETE /generate/{request_id}`
+    #[serde(skip)]
+    pub cancel: Option<CancelToken>,
+    /// ID of the record of the request in the queue journal if it is replayed from it
+    #[serde(skip)]
+    pub journal_id: Option<u64>,
+}
+
+/// Grammar constraining the generated text
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub(crate) enum GrammarType {
+    /// JSON schema the generated text must conform to
+    Json(serde_json::Value),
+    /// Regular expression the whole generated text must match, without word boundaries
+    Regex(String),
+    /// Context-free grammar in the GBNF syntax of llama.cpp, starting with the `root` rule
+    Gbnf(String),
+    /// Strings the generated text must be exactly one of
+    Choice(Vec<String>),
+}
+
+/// Scheduling priority of a request
+///
+/// When forming batches, queued requests are taken from the highest priority first
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord, ToSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    /// Number of priority levels
+    pub(crate) const LEVELS: usize = 3;
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => Err(ValidationError::Priority(s.to_string())),
+        }
+    }
+}
+
+/// Side the tokens of over-long inputs are removed from
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TruncationSide {
+    /// Keep the end of the inputs
+    #[default]
+    Left,
+    /// Keep the beginning of the inputs
+    Right,
+    /// Keep the beginning and the end of the inputs, replacing the middle with a marker
+    Middle,
+}
+
+/// What happens to the requests whose inputs and `max_new_tokens` exceed the context of the model
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, ToSchema, ValueEnum,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Reject the request
+    #[default]
+    Error,
+    /// Remove tokens from the `truncation_side` of the inputs
+    Truncate,
+    /// Remove tokens from the middle of the inputs, replacing them with a marker
+    TruncateMiddle,
+}
+
+impl std::str::FromStr for OverflowPolicy {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "truncate" => Ok(Self::Truncate),
+            "truncate_middle" => Ok(Self::TruncateMiddle),
+            _ => Err(ValidationError::OnOverflow(s.to_string())),
+        }
+    }
+}
+
+impl std::str::FromStr for TruncationSide {
+    type Err = ValidationError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "left" => Ok(Self::Left),
+            "right" => Ok(Self::Right),
+            "middle" => Ok(Self::Middle),
+            _ => Err(ValidationError::TruncationSide(s.to_string())),
+        }
+    }
 }
 
 fn default_max_new_tokens() -> u32 {
@@ -160,47 +560,178 @@ fn default_parameters() -> GenerateParameters {
         best_of: None,
         temperature: None,
         repetition_penalty: None,
+        frequency_penalty: None,
+        presence_penalty: None,
         top_k: None,
         top_p: None,
         typical_p: None,
+        min_p: None,
+        epsilon_cutoff: None,
+        eta_cutoff: None,
+        mirostat: None,
+        mirostat_tau: None,
+        mirostat_eta: None,
         do_sample: false,
-        max_new_tokens: default_max_new_tokens(),
+        max_new_tokens: None,
         min_new_tokens: default_min_new_tokens(),
         return_full_text: None,
         stop: Vec::new(),
+        stop_regex: Vec::new(),
+        stop_token_ids: Vec::new(),
+        suffix: None,
+        bad_words: Vec::new(),
         truncate: None,
+        truncation_side: TruncationSide::Left,
+        on_overflow: None,
         no_repeat_ngram_size: default_no_repeat_ngram_size(),
         watermark: false,
         details: false,
         decoder_input_details: false,
+        score_prompt: false,
+        queue_events: false,
         seed: None,
+        logit_bias: None,
+        top_n_tokens: None,
+        grammar: None,
+        priority: None,
+        timeout_ms: None,
+        session_id: None,
+        template: None,
+        variables: None,
+        pause_after: None,
+        continuation: None,
+        token_healing: false,
+        stream_granularity: StreamGranularity::Token,
+        tenant: None,
+        rate_limit: None,
+        cancel: None,
+        journal_id: None,
     }
 }
 
-#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
 pub(crate) struct GenerateRequest {
+    /// Model generating the text when several models are served, the default model if unset
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub model: Option<String>,
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
+    /// Add the special tokens of the tokenizer, such as the BOS token, to the inputs
+    #[serde(default = "default_add_special_tokens")]
+    #[schema(default = "true", example = true)]
+    pub add_special_tokens: bool,
+}
+
+fn default_add_special_tokens() -> bool {
+    true
+}
+
+impl GenerateRequest {
+    /// Request of the completion at `index` when generating `n` completions of this request
+    ///
+    /// Each completion uses a different seed derived from the seed of the request, so that the
+    /// completions differ but can still be reproduced. The first completion keeps the seed of
+    /// the request and the others hash it with their index, so that the completions of requests
+    /// with close seeds do not share their seeds.
+    pub(crate) fn completion(&self, index: usize) -> Self {
+        let mut request = self.clone();
+        request.parameters.seed = self.parameters.seed.map(|seed| match index {
+            0 => seed,
+            index => splitmix64(seed ^ splitmix64(index as u64)),
+        });
+        request
+    }
+}
+
+/// SplitMix64 finalizer, a bijective mix of the bits of `x`
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
 
 #[derive(Clone, Debug, Deserialize, ToSchema)]
 pub(crate) struct CompatGenerateRequest {
+    /// Model generating the text when several models are served, the default model if unset
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub model: Option<String>,
     #[schema(example = "My name is Olivier and I")]
     pub inputs: String,
     #[serde(default = "default_parameters")]
     pub parameters: GenerateParameters,
+    #[serde(default = "default_add_special_tokens")]
+    #[schema(default = "true", example = true)]
+    pub add_special_tokens: bool,
     #[serde(default)]
     #[schema(default = "false")]
     pub stream: bool,
 }
 
+/// Messages sent by the client over the `/generate_ws` WebSocket
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum WebSocketRequest {
+    /// Start a new generation
+    Generate(GenerateRequest),
+    /// Cancel the running generation, or the generation of another request with `request_id`
+    Cancel {
+        #[serde(default)]
+        request_id: Option<String>,
+    },
+}
+
+/// Prompts of a `/generate` request
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum GenerateInputs {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+/// Body of a `/generate` request, whose `inputs` can be a batch of prompts sharing the same
+/// parameters
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct GenerateBatchRequest {
+    #[serde(default)]
+    pub model: Option<String>,
+    pub inputs: GenerateInputs,
+    #[serde(default = "default_parameters")]
+    pub parameters: GenerateParameters,
+    #[serde(default = "default_add_special_tokens")]
+    pub add_special_tokens: bool,
+}
+
+impl From<GenerateRequest> for GenerateBatchRequest {
+    fn from(req: GenerateRequest) -> Self {
+        Self {
+            model: req.model,
+            inputs: GenerateInputs::Single(req.inputs),
+            parameters: req.parameters,
+            add_special_tokens: req.add_special_tokens,
+        }
+    }
+}
+
+/// Response of a `/generate` request, with one response per prompt for a batch of prompts
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum GenerateOutput {
+    Single(GenerateResponse),
+    Batch(Vec<GenerateResponse>),
+}
+
 impl From<CompatGenerateRequest> for GenerateRequest {
     fn from(req: CompatGenerateRequest) -> Self {
         Self {
+            model: req.model,
             inputs: req.inputs,
             parameters: req.parameters,
+            add_special_tokens: req.add_special_tokens,
         }
     }
 }
@@ -215,7 +746,7 @@ pub struct PrefillToken {
     logprob: f32,
 }
 
-#[derive(Debug, Serialize, ToSchema)]
+#[derive(Clone, Debug, Serialize, ToSchema)]
 pub struct Token {
     #[schema(example = 0)]
     id: u32,
@@ -237,6 +768,23 @@ pub(crate) enum FinishReason {
     EndOfSequenceToken,
     #[schema(rename = "stop_sequence")]
     StopSequence,
+    #[schema(rename = "cancelled")]
+    Cancelled,
+    #[schema(rename = "content_filter")]
+    ContentFilter,
+}
+
+impl FinishReason {
+    /// OpenAI compatible finish reason
+    pub(crate) fn openai(&self) -> &'static str {
+        match self {
+            FinishReason::Length => "length",
+            FinishReason::EndOfSequenceToken
+            | FinishReason::StopSequence
+            | FinishReason::Cancelled => "stop",
+            FinishReason::ContentFilter => "content_filter",
+        }
+    }
 }
 
 #[derive(Serialize, ToSchema)]
@@ -251,6 +799,8 @@ pub(crate) struct BestOfSequence {
     pub seed: Option<u64>,
     pub prefill: Vec<PrefillToken>,
     pub tokens: Vec<Token>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_tokens: Vec<Vec<Token>>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -259,10 +809,15 @@ pub(crate) struct Details {
     pub finish_reason: FinishReason,
     #[schema(example = 1)]
     pub generated_tokens: u32,
+    /// Number of prompt tokens, as tokenized by the router
+    #[schema(example = 5)]
+    pub prompt_tokens: u32,
     #[schema(nullable = true, example = 42)]
     pub seed: Option<u64>,
     pub prefill: Vec<PrefillToken>,
     pub tokens: Vec<Token>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_tokens: Vec<Vec<Token>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub best_of_sequences: Option<Vec<BestOfSequence>>,
 }
@@ -273,6 +828,10 @@ pub(crate) struct GenerateResponse {
     pub generated_text: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub details: Option<Details>,
+    /// Token continuing the generation, set if it paused after `pause_after` tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub continuation: Option<String>,
 }
 
 #[derive(Serialize, ToSchema)]
@@ -281,6 +840,9 @@ pub(crate) struct StreamDetails {
     pub finish_reason: FinishReason,
     #[schema(example = 1)]
     pub generated_tokens: u32,
+    /// Number of prompt tokens, as tokenized by the router
+    #[schema(example = 5)]
+    pub prompt_tokens: u32,
     #[schema(nullable = true, example = 42)]
     pub seed: Option<u64>,
 }
@@ -288,23 +850,855 @@ pub(crate) struct StreamDetails {
 #[derive(Serialize, ToSchema)]
 pub(crate) struct StreamResponse {
     pub token: Token,
+    /// Ids of the generated tokens of this event, several when `stream_granularity` coalesces
+    /// tokens
+    #[schema(example = json ! ([0]))]
+    pub token_ids: Vec<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub top_tokens: Vec<Token>,
     #[schema(nullable = true, default = "null", example = "test")]
     pub generated_text: Option<String>,
     #[schema(nullable = true, default = "null")]
     pub details: Option<StreamDetails>,
+    /// Token continuing the generation, set on the last event if it paused after
+    /// `pause_after` tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "null")]
+    pub continuation: Option<String>,
+}
+
+/// Data of the `queue` events sent while a streaming request is queued
+#[derive(Serialize, ToSchema)]
+pub(crate) struct QueuePosition {
+    /// Position of the request in the queue, 1 for the next request to be batched
+    #[schema(example = 7)]
+    pub position: usize,
+    /// Estimated wait before the request is batched, unknown until requests left the queue
+    #[schema(nullable = true, example = 1200)]
+    pub estimated_wait_ms: Option<u128>,
+}
+
+impl From<StreamChunk> for StreamResponse {
+    fn from(chunk: StreamChunk) -> Self {
+        Self {
+            token: chunk.token,
+            token_ids: chunk.token_ids,
+            top_tokens: chunk.top_tokens,
+            generated_text: None,
+            details: None,
+            continuation: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct CompletionRequest {
+    /// Model serving the request when several models are served, ignored otherwise
+    #[serde(default)]
+    #[schema(nullable = true, example = "bigscience/blomm-560m")]
+    pub model: Option<String>,
+    #[schema(example = "My name is Olivier and I")]
+    pub prompt: String,
+    /// Text following the completion, for models trained to fill in the middle
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub suffix: Option<String>,
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 32)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    #[schema(minimum = 0.0, nullable = true, default = "null", example = 0.5)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.95
+    )]
+    pub top_p: Option<f32>,
+    /// Strings ending the generation, up to `max_stop_sequences`
+    #[serde(default)]
+    #[schema(inline, example = json ! (["photographer"]))]
+    pub stop: Vec<String>,
+    /// Number of completions to generate for the prompt
+    #[serde(default = "default_n")]
+    #[schema(exclusive_minimum = 0, default = "1", example = 1)]
+    pub n: usize,
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub stream: bool,
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub echo: bool,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = 42)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ({"1234": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub presence_penalty: Option<f32>,
+}
+
+impl From<CompletionRequest> for GenerateRequest {
+    fn from(req: CompletionRequest) -> Self {
+        let mut parameters = openai_parameters(
+            req.max_tokens,
+            req.temperature,
+            req.top_p,
+            req.stop,
+            req.seed,
+            req.logit_bias,
+        );
+        parameters.return_full_text = Some(req.echo);
+        parameters.suffix = req.suffix;
+        parameters.frequency_penalty = req.frequency_penalty;
+        parameters.presence_penalty = req.presence_penalty;
+
+        Self {
+            model: req.model,
+            inputs: req.prompt,
+            parameters,
+            add_special_tokens: true,
+        }
+    }
+}
+
+fn default_n() -> usize {
+    1
+}
+
+/// Map OpenAI sampling parameters to `GenerateParameters`
+fn openai_parameters(
+    max_tokens: Option<u32>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    stop: Vec<String>,
+    seed: Option<u64>,
+    logit_bias: Option<HashMap<u32, f32>>,
+) -> GenerateParameters {
+    // OpenAI clients use `temperature == 0` for greedy decoding and `top_p == 1` as the
+    // default, both of which are rejected by the validation
+    let do_sample = temperature.map_or(true, |temperature| temperature > 0.0);
+    let temperature = temperature.filter(|&temperature| temperature > 0.0);
+    let top_p = top_p.filter(|&top_p| top_p < 1.0);
+
+    GenerateParameters {
+        temperature,
+        top_p,
+        do_sample,
+        max_new_tokens: max_tokens,
+        return_full_text: Some(false),
+        stop,
+        details: true,
+        seed,
+        logit_bias,
+        ..default_parameters()
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Completion {
+    #[schema(example = "cmpl-2c2a8f3e9b1d4a67")]
+    pub id: String,
+    #[schema(example = "text_completion")]
+    pub object: String,
+    #[schema(example = "1706270835")]
+    pub created: u64,
+    #[schema(example = "bigscience/blomm-560m")]
+    pub model: String,
+    pub choices: Vec<CompletionChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct CompletionChoice {
+    #[schema(example = 0)]
+    pub index: u32,
+    #[schema(example = "test")]
+    pub text: String,
+    #[schema(nullable = true, example = "length")]
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct Usage {
+    #[schema(example = 5)]
+    pub prompt_tokens: u32,
+    #[schema(example = 20)]
+    pub completion_tokens: u32,
+    #[schema(example = 25)]
+    pub total_tokens: u32,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct Message {
+    #[schema(example = "user")]
+    pub role: String,
+    /// Empty for the assistant messages calling tools
+    #[serde(default, deserialize_with = "deserialize_null_default")]
+    #[schema(example = "What is Deep Learning?")]
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Id of the tool call answered by a `tool` message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "call_2c2a8f3e9b1d4a67")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub(crate) fn new(role: impl Into<String>, content: impl Into<String>) -> Self {
+        Self {
+            role: role.into(),
+            content: content.into(),
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+}
+
+fn deserialize_null_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Instructions appended to the last message of the conversation when tools are available
+const DEFAULT_TOOL_PROMPT: &str = "You have access to the following functions. Call one of them by answering with a JSON object containing the `name` of the function and its `arguments`.";
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct Tool {
+    #[serde(rename = "type")]
+    #[schema(example = "function")]
+    pub tool_type: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct FunctionDefinition {
+    #[schema(example = "get_current_weather")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "Get the current weather of a city")]
+    pub description: Option<String>,
+    /// JSON schema of the arguments
+    #[serde(default = "default_function_parameters")]
+    #[schema(value_type = Object, example = json ! ({"type": "object", "properties": {"city": {"type": "string"}}}))]
+    pub parameters: serde_json::Value,
+}
+
+fn default_function_parameters() -> serde_json::Value {
+    serde_json::json!({"type": "object", "properties": {}})
+}
+
+/// Whether and which tool the model calls
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum ToolChoice {
+    Mode(ToolChoiceMode),
+    Function(NamedToolChoice),
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ToolChoiceMode {
+    /// The tools are ignored
+    None,
+    /// The model answers with text or calls one of the tools
+    Auto,
+    /// The model calls one of the tools
+    Required,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct NamedToolChoice {
+    pub function: FunctionName,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct FunctionName {
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct ToolCall {
+    #[schema(example = "call_2c2a8f3e9b1d4a67")]
+    pub id: String,
+    #[serde(rename = "type")]
+    #[schema(example = "function")]
+    pub tool_type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, ToSchema)]
+pub(crate) struct FunctionCall {
+    #[schema(example = "get_current_weather")]
+    pub name: String,
+    /// JSON encoded arguments
+    #[schema(example = "{\"city\":\"Paris\"}")]
+    pub arguments: String,
+}
+
+impl ToolCall {
+    /// Parse a text generated with the grammar of `ChatRequest::tools_grammar`
+    ///
+    /// Returns None if the text is not a whole tool call: the model answered with text, or the
+    /// generation stopped before the end of the tool call
+    pub(crate) fn parse(text: &str) -> Option<Self> {
+        #[derive(Deserialize)]
+        struct GeneratedCall {
+            name: String,
+            arguments: serde_json::Value,
+        }
+
+        let call: GeneratedCall = serde_json::from_str(text).ok()?;
+        Some(Self {
+            id: format!("call_{:016x}", rand::random::<u64>()),
+            tool_type: "function".to_string(),
+            function: FunctionCall {
+                name: call.name,
+                arguments: call.arguments.to_string(),
+            },
+        })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct ChatRequest {
+    /// Model serving the request when several models are served, ignored otherwise
+    #[serde(default)]
+    #[schema(nullable = true, example = "bigscience/blomm-560m")]
+    pub model: Option<String>,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    #[schema(exclusive_minimum = 0, nullable = true, default = "null", example = 32)]
+    pub max_tokens: Option<u32>,
+    #[serde(default)]
+    #[schema(minimum = 0.0, nullable = true, default = "null", example = 0.5)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    #[schema(
+        exclusive_minimum = 0.0,
+        maximum = 1.0,
+        nullable = true,
+        default = "null",
+        example = 0.95
+    )]
+    pub top_p: Option<f32>,
+    /// Strings ending the generation, up to `max_stop_sequences`
+    #[serde(default)]
+    #[schema(inline, example = json ! (["photographer"]))]
+    pub stop: Vec<String>,
+    /// Number of completions to generate for the prompt
+    #[serde(default = "default_n")]
+    #[schema(exclusive_minimum = 0, default = "1", example = 1)]
+    pub n: usize,
+    #[serde(default)]
+    #[schema(default = "false")]
+    pub stream: bool,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = 42)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = json ! ({"1234": -100.0}))]
+    pub logit_bias: Option<HashMap<u32, f32>>,
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub frequency_penalty: Option<f32>,
+    #[serde(default)]
+    #[schema(
+        minimum = -2.0,
+        maximum = 2.0,
+        nullable = true,
+        default = "null",
+        example = 0.5
+    )]
+    pub presence_penalty: Option<f32>,
+    /// Tools the model may call
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub tools: Option<Vec<Tool>>,
+    /// `none`, `auto`, `required` or `{"type": "function", "function": {"name": "..."}}`
+    ///
+    /// With `auto` or when unset, the model answers with text or calls one of the tools. The
+    /// output is only constrained to a tool call with `required` or a named function.
+    #[serde(default)]
+    #[schema(nullable = true, value_type = Object, default = "null", example = "auto")]
+    pub tool_choice: Option<ToolChoice>,
+    /// Instructions given to the model with the tools, appended to the last message
+    #[serde(default)]
+    #[schema(nullable = true, default = "null")]
+    pub tool_prompt: Option<String>,
+}
+
+impl ChatRequest {
+    /// Tools the model may call, according to `tool_choice`
+    pub(crate) fn selected_tools(&self) -> Result<Option<Vec<Tool>>, ValidationError> {
+        let tools = match &self.tools {
+            Some(tools) if !tools.is_empty() => tools,
+            _ => return Ok(None),
+        };
+        match &self.tool_choice {
+            Some(ToolChoice::Mode(ToolChoiceMode::None)) => Ok(None),
+            None | Some(ToolChoice::Mode(_)) => Ok(Some(tools.clone())),
+            Some(ToolChoice::Function(choice)) => tools
+                .iter()
+                .find(|tool| tool.function.name == choice.function.name)
+                .map(|tool| Some(vec![tool.clone()]))
+                .ok_or_else(|| ValidationError::ToolChoice(choice.function.name.clone())),
+        }
+    }
+
+    /// Whether `tool_choice` forces the model to call one of the selected tools
+    pub(crate) fn requires_tool_call(&self) -> bool {
+        matches!(
+            self.tool_choice,
+            Some(ToolChoice::Mode(ToolChoiceMode::Required)) | Some(ToolChoice::Function(_))
+        )
+    }
+
+    /// Messages of the conversation, with the tool prompt and the tool definitions appended to
+    /// the last message for the templates that do not render the `tools` variable
+    pub(crate) fn messages(&self, tools: Option<&[Tool]>) -> Vec<Message> {
+        let mut messages = self.messages.clone();
+        if let (Some(tools), Some(last)) = (tools, messages.last_mut()) {
+            let tool_prompt = self.tool_prompt.as_deref().unwrap_or(DEFAULT_TOOL_PROMPT);
+            let definitions: Vec<&FunctionDefinition> =
+                tools.iter().map(|tool| &tool.function).collect();
+            let definitions = serde_json::to_string(&definitions).expect("tools are serializable");
+            last.content = format!("{}\n\n{tool_prompt}\n{definitions}", last.content);
+        }
+        messages
+    }
+
+    /// Grammar constraining the output to a call of one of the `tools`
+    fn tools_grammar(tools: &[Tool]) -> GrammarType {
+        let calls: Vec<serde_json::Value> = tools
+            .iter()
+            .map(|tool| {
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "name": {"const": tool.function.name},
+                        "arguments": tool.function.parameters,
+                    },
+                    "required": ["name", "arguments"],
+                })
+            })
+            .collect();
+        GrammarType::Json(serde_json::json!({ "oneOf": calls }))
+    }
+
+    /// Create a `GenerateRequest` from the prompt rendered by the chat template
+    pub(crate) fn into_generate_request(
+        self,
+        inputs: String,
+        tools: Option<&[Tool]>,
+    ) -> GenerateRequest {
+        let requires_tool_call = self.requires_tool_call();
+        let mut parameters = openai_parameters(
+            self.max_tokens,
+            self.temperature,
+            self.top_p,
+            self.stop,
+            self.seed,
+            self.logit_bias,
+        );
+        parameters.frequency_penalty = self.frequency_penalty;
+        parameters.presence_penalty = self.presence_penalty;
+        parameters.grammar = tools
+            .filter(|_| requires_tool_call)
+            .map(Self::tools_grammar);
+        GenerateRequest {
+            model: self.model,
+            inputs,
+            parameters,
+            // The chat template adds the special tokens of the conversation
+            add_special_tokens: false,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletion {
+    #[schema(example = "chatcmpl-2c2a8f3e9b1d4a67")]
+    pub id: String,
+    #[schema(example = "chat.completion")]
+    pub object: String,
+    #[schema(example = "1706270835")]
+    pub created: u64,
+    #[schema(example = "bigscience/blomm-560m")]
+    pub model: String,
+    pub choices: Vec<ChatCompletionChoice>,
+    pub usage: Usage,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionChoice {
+    #[schema(example = 0)]
+    pub index: u32,
+    pub message: Message,
+    #[schema(example = "length")]
+    pub finish_reason: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionChunk {
+    #[schema(example = "chatcmpl-2c2a8f3e9b1d4a67")]
+    pub id: String,
+    #[schema(example = "chat.completion.chunk")]
+    pub object: String,
+    #[schema(example = "1706270835")]
+    pub created: u64,
+    #[schema(example = "bigscience/blomm-560m")]
+    pub model: String,
+    pub choices: Vec<ChatCompletionChunkChoice>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionChunkChoice {
+    #[schema(example = 0)]
+    pub index: u32,
+    pub delta: ChatCompletionDelta,
+    #[schema(nullable = true, example = "length")]
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "assistant")]
+    pub role: Option<String>,
+    #[schema(example = "test")]
+    pub content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub tool_calls: Option<Vec<ChatCompletionToolCallDelta>>,
+}
+
+/// Tool call sent at once in the last delta of a stream
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ChatCompletionToolCallDelta {
+    #[schema(example = 0)]
+    pub index: u32,
+    #[schema(example = "call_2c2a8f3e9b1d4a67")]
+    pub id: String,
+    #[serde(rename = "type")]
+    #[schema(example = "function")]
+    pub tool_type: String,
+    pub function: FunctionCall,
+}
+
+impl From<ToolCall> for ChatCompletionToolCallDelta {
+    fn from(call: ToolCall) -> Self {
+        Self {
+            index: 0,
+            id: call.id,
+            tool_type: call.tool_type,
+            function: call.function,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum EmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl From<EmbeddingInput> for Vec<String> {
+    fn from(input: EmbeddingInput) -> Self {
+        match input {
+            EmbeddingInput::Single(input) => vec![input],
+            EmbeddingInput::Batch(inputs) => inputs,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct EmbeddingRequest {
+    /// Model serving the request when several models are served, ignored otherwise
+    #[serde(default)]
+    #[schema(nullable = true, example = "bigscience/blomm-560m")]
+    pub model: Option<String>,
+    #[schema(value_type = Vec<String>, example = json ! (["What is Deep Learning?"]))]
+    pub input: EmbeddingInput,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbeddingResponse {
+    #[schema(example = "list")]
+    pub object: String,
+    pub data: Vec<EmbeddingData>,
+    #[schema(example = "bigscience/blomm-560m")]
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbeddingData {
+    #[schema(example = "embedding")]
+    pub object: String,
+    #[schema(example = json ! ([0.0023064255, -0.009327292, 0.015797347]))]
+    pub embedding: Vec<f32>,
+    #[schema(example = 0)]
+    pub index: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct EmbeddingUsage {
+    #[schema(example = 5)]
+    pub prompt_tokens: u32,
+    #[schema(example = 5)]
+    pub total_tokens: u32,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SimpleToken {
+    #[schema(example = 0)]
+    id: u32,
+    #[schema(example = "test")]
+    text: String,
+    #[schema(example = 0)]
+    start: usize,
+    #[schema(example = 2)]
+    stop: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+#[schema(example = json ! ([{"id": 0, "text": "test", "start": 0, "stop": 4}]))]
+pub struct TokenizeResponse(Vec<SimpleToken>);
+
+#[derive(Clone, Debug, Deserialize, ToSchema)]
+pub(crate) struct DetokenizeRequest {
+    /// Model whose tokenizer decodes the ids when several models are served
+    #[serde(default)]
+    #[schema(nullable = true, default = "null", example = "null")]
+    pub model: Option<String>,
+    #[schema(example = json ! ([15496, 995]))]
+    pub ids: Vec<u32>,
+    #[serde(default = "default_skip_special_tokens")]
+    #[schema(default = "true")]
+    pub skip_special_tokens: bool,
+}
+
+fn default_skip_special_tokens() -> bool {
+    true
+}
+
+#[derive(Serialize, ToSchema)]
+pub(crate) struct DetokenizeResponse {
+    #[schema(example = "Hello world")]
+    pub text: String,
+}
+
+/// Status of the served models returned by the health route
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct HealthStatus {
+    /// `true` if every served model is healthy
+    #[schema(example = true)]
+    pub healthy: bool,
+    pub models: Vec<ModelHealth>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ModelHealth {
+    #[schema(example = "bigscience/blomm-560m")]
+    pub model_id: String,
+    #[schema(example = true)]
+    pub healthy: bool,
+    /// Unix timestamp in milliseconds of the last successful decode step
+    #[schema(nullable = true, example = 1706000000000u64)]
+    pub last_decode_ms: Option<u64>,
+    pub shards: Vec<ShardHealth>,
+}
+
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct ShardHealth {
+    #[schema(example = 0)]
+    pub rank: usize,
+    #[schema(example = true)]
+    pub healthy: bool,
+    /// Latency of the gRPC health check of the shard
+    #[schema(nullable = true, example = 1.5)]
+    pub latency_ms: Option<f64>,
+    #[schema(nullable = true, example = "torch.float16")]
+    pub dtype: Option<String>,
+    #[schema(nullable = true, example = "cuda")]
+    pub device_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true, example = "transport error")]
+    pub error: Option<String>,
 }
 
 #[derive(Serialize, ToSchema)]
 pub(crate) struct ErrorResponse {
     pub error: String,
     pub error_type: String,
+    /// Load of the router, only sent with the `overloaded` errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub load: Option<QueueLoad>,
+    /// Policy violated by the request, only sent with the `policy_violation` errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(nullable = true)]
+    pub policy_violation: Option<PolicyViolation>,
+}
+
+/// Load of the router when a request is rejected, to let the clients back off
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub(crate) struct QueueLoad {
+    /// Number of requests waiting in the queue
+    #[schema(example = 12)]
+    pub queue_size: usize,
+    /// Average time between two batches, unknown until requests left the queue
+    #[schema(nullable = true, example = 150)]
+    pub average_batch_latency_ms: Option<u128>,
+    /// Estimated wait before a new request is batched, unknown until requests left the queue
+    #[schema(nullable = true, example = 1950)]
+    pub estimated_wait_ms: Option<u128>,
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use std::io::Write;
     use tokenizers::Tokenizer;
 
+    #[test]
+    fn test_completion_seed() {
+        let mut request = GenerateRequest {
+            model: None,
+            inputs: "Hello".to_string(),
+            parameters: default_parameters(),
+            add_special_tokens: true,
+        };
+        assert_eq!(request.completion(1).parameters.seed, None);
+
+        request.parameters.seed = Some(u64::MAX);
+        assert_eq!(request.completion(0).parameters.seed, Some(u64::MAX));
+        assert_ne!(request.completion(1).parameters.seed, Some(u64::MAX));
+
+        // The completions of requests with consecutive seeds do not share their seeds
+        let mut seeds = std::collections::HashSet::new();
+        for seed in 0..16 {
+            request.parameters.seed = Some(seed);
+            for index in 0..16 {
+                assert!(seeds.insert(request.completion(index).parameters.seed));
+            }
+        }
+    }
+
+    #[test]
+    fn test_chat_request_tools() {
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "messages": [{"role": "user", "content": "What is the weather in Paris?"}],
+            "tools": [
+                {"type": "function", "function": {"name": "get_time"}},
+                {"type": "function", "function": {
+                    "name": "get_current_weather",
+                    "parameters": {"type": "object", "properties": {"city": {"type": "string"}}},
+                }},
+            ],
+            "tool_choice": {"type": "function", "function": {"name": "get_current_weather"}},
+        }))
+        .unwrap();
+        let tools = request.selected_tools().unwrap().unwrap();
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].function.name, "get_current_weather");
+
+        let messages = request.messages(Some(&tools));
+        assert!(messages[0]
+            .content
+            .starts_with("What is the weather in Paris?\n\n"));
+        assert!(messages[0].content.ends_with("\"name\":\"get_current_weather\",\"parameters\":{\"type\":\"object\",\"properties\":{\"city\":{\"type\":\"string\"}}}}]"));
+
+        let request = request.into_generate_request("prompt".to_string(), Some(&tools));
+        // The BOS token of the prompt comes from the chat template
+        assert!(!request.add_special_tokens);
+        let Some(GrammarType::Json(schema)) = request.parameters.grammar else {
+            panic!("tools must set a JSON grammar");
+        };
+        assert!(grammar::json_schema_to_regex(&schema).is_ok());
+
+        let call =
+            ToolCall::parse(r#"{"name": "get_current_weather", "arguments": {"city": "Paris"}}"#)
+                .unwrap();
+        assert_eq!(call.function.name, "get_current_weather");
+        assert_eq!(call.function.arguments, r#"{"city":"Paris"}"#);
+        assert!(ToolCall::parse(r#"{"name": "get_current_weather", "argu"#).is_none());
+    }
+
+    #[test]
+    fn test_chat_request_tool_choice() {
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "messages": [],
+            "tools": [{"type": "function", "function": {"name": "get_time"}}],
+            "tool_choice": "none",
+        }))
+        .unwrap();
+        assert!(request.selected_tools().unwrap().is_none());
+
+        let request: ChatRequest = serde_json::from_value(serde_json::json!({
+            "messages": [],
+            "tools": [{"type": "function", "function": {"name": "get_time"}}],
+            "tool_choice": {"type": "function", "function": {"name": "get_date"}},
+        }))
+        .unwrap();
+        assert!(matches!(
+            request.selected_tools(),
+            Err(ValidationError::ToolChoice(name)) if name == "get_date"
+        ));
+
+        // The model may answer with text unless a tool call is required
+        for (tool_choice, constrained) in [
+            (serde_json::Value::Null, false),
+            (serde_json::json!("auto"), false),
+            (serde_json::json!("required"), true),
+        ] {
+            let request: ChatRequest = serde_json::from_value(serde_json::json!({
+                "messages": [],
+                "tools": [{"type": "function", "function": {"name": "get_time"}}],
+                "tool_choice": tool_choice,
+            }))
+            .unwrap();
+            let tools = request.selected_tools().unwrap().unwrap();
+            assert_eq!(tools.len(), 1);
+            let request = request.into_generate_request("prompt".to_string(), Some(&tools));
+            assert_eq!(request.parameters.grammar.is_some(), constrained);
+        }
+    }
+
     pub(crate) async fn get_tokenizer() -> Tokenizer {
         if !std::path::Path::new("tokenizer.json").exists() {
             let content = reqwest::get("https://huggingface.co/gpt2/raw/main/tokenizer.json")
@@ -7,11 +7,19 @@ use opentelemetry::sdk::trace::Sampler;
 use opentelemetry::sdk::Resource;
 use opentelemetry::{global, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
-use text_generation_client::{ClientError, ShardedClient};
-use text_generation_router::{server, HubModelInfo};
+use text_generation_client::{
+    ClientError, Compression, ConnectionState, HedgingPolicy, RetryPolicy, ShardedClient, TlsConfig,
+};
+use text_generation_router::server::{self, Backend, CorsConfig};
+use text_generation_router::{
+    AuditConfig, AuditContent, AuditField, FimTokens, HubModelInfo, HubTokenizerConfig,
+    OverflowPolicy, RedactionDirection, SchedulingPolicyKind, SheddingPolicy,
+};
 use thiserror::Error;
 use tokenizers::{FromPretrainedParameters, Tokenizer};
 use tower_http::cors::AllowOrigin;
@@ -28,7 +36,11 @@ struct Args {
     #[clap(default_value = "2", long, env)]
     max_best_of: usize,
     #[clap(default_value = "4", long, env)]
+    max_client_batch_size: usize,
+    #[clap(default_value = "4", long, env)]
     max_stop_sequences: usize,
+    #[clap(default_value = "5", long, env)]
+    max_top_n_tokens: u32,
     #[clap(default_value = "1024", long, env)]
     max_input_length: usize,
     #[clap(default_value = "2048", long, env)]
@@ -45,20 +57,197 @@ struct Args {
     hostname: String,
     #[clap(default_value = "3000", long, short, env)]
     port: u16,
+    #[clap(long, env)]
+    grpc_port: Option<u16>,
+    /// Unix socket path of the master shard, or its `http(s)://` uri when the shards run on
+    /// another host
     #[clap(default_value = "/tmp/text-generation-server-0", long, env)]
     master_shard_uds_path: String,
+    /// Master shard unix socket paths or `http(s)://` uris of independent replicas of the default
+    /// model, the requests are balanced between the replicas by their outstanding requests
+    #[clap(long, env, value_delimiter = ',')]
+    replica_shard_uds_path: Vec<String>,
+    /// PEM certificate of the authority of the certificates of the `https://` shards, the system
+    /// roots are trusted if unset
+    #[clap(long, env)]
+    shard_tls_ca_cert: Option<PathBuf>,
+    /// PEM certificate and key of the router, for shards requiring mutual TLS
+    #[clap(long, env)]
+    shard_tls_cert: Option<PathBuf>,
+    #[clap(long, env)]
+    shard_tls_key: Option<PathBuf>,
+    /// Name checked against the certificates of the shards instead of the host of their uri
+    #[clap(long, env)]
+    shard_tls_domain: Option<String>,
+    /// Number of retries of the idempotent calls to the shards (health, info, cache clearing and
+    /// batch filtering) failing with a transient error, 0 disables the retries
+    #[clap(default_value = "3", long, env)]
+    shard_max_retries: u32,
+    /// Backoff before the first retry, doubled after every retry and jittered
+    #[clap(default_value = "50", long, env)]
+    shard_retry_initial_backoff_ms: u64,
+    #[clap(default_value = "1000", long, env)]
+    shard_retry_max_backoff_ms: u64,
+    /// Interval of the health probes of the shards, the shards failing a probe are reconnected to
+    /// in the background. 0 disables the probes
+    #[clap(default_value = "10", long, env)]
+    shard_probe_interval_secs: u64,
+    /// Timeout of the prefill and embedding calls to the shards
+    #[clap(long, env)]
+    shard_prefill_timeout_ms: Option<u64>,
+    /// Timeout of the decode calls to the shards
+    #[clap(long, env)]
+    shard_decode_timeout_ms: Option<u64>,
+    /// Timeout of the warmup calls to the shards
+    #[clap(long, env)]
+    shard_warmup_timeout_secs: Option<u64>,
+    /// Timeout of each attempt of the control calls to the shards: health, info, cache clearing,
+    /// sessions release and batch filtering
+    #[clap(long, env)]
+    shard_control_timeout_ms: Option<u64>,
+    /// Compression of the messages sent to the shards (`gzip`, zstd is not supported)
+    #[clap(long, env)]
+    shard_request_compression: Option<Compression>,
+    /// Compression accepted for the messages sent by the shards (`gzip`), the shards must also be
+    /// configured to compress them
+    #[clap(long, env)]
+    shard_response_compression: Option<Compression>,
+    /// Receive the generated tokens of the decode steps as soon as each shard produces them
+    #[clap(long, env)]
+    shard_stream_decode: bool,
+    /// Percentile of the latencies of the health and info calls after which they are also sent to
+    /// another replica of the model, the first answer being used. Unset disables the hedging
+    #[clap(long, env)]
+    shard_hedging_percentile: Option<f64>,
+    /// Minimum delay before hedging a call
+    #[clap(default_value = "10", long, env)]
+    shard_hedging_min_delay_ms: u64,
+    /// Additional models served with their own shards, as `<model id>=<master shard uds path>`,
+    /// the path can also be an `http(s)://` uri
+    #[clap(long, env, value_delimiter = ',')]
+    model_backend: Vec<String>,
+    /// Maximum number of concurrent requests of a model, as `<model id>=<limit>`, overriding
+    /// `max_concurrent_requests`
+    #[clap(long, env, value_delimiter = ',')]
+    model_max_concurrent_requests: Vec<String>,
+    /// Fill-in-the-middle sentinel tokens, as `[<model id>=]<prefix>,<suffix>,<middle>`
+    #[clap(long, env)]
+    fim_tokens: Vec<String>,
     #[clap(default_value = "bigscience/bloom", long, env)]
     tokenizer_name: String,
     #[clap(long, env)]
     revision: Option<String>,
     #[clap(default_value = "2", long, env)]
     validation_workers: usize,
+    #[clap(default_value = "8", long, env)]
+    max_tokenization_batch_size: usize,
     #[clap(long, env)]
     json_output: bool,
     #[clap(long, env)]
     otlp_endpoint: Option<String>,
     #[clap(long, env)]
     cors_allow_origin: Option<Vec<String>>,
+    #[clap(default_value = "GET,POST", long, env, value_delimiter = ',')]
+    cors_allow_methods: Vec<String>,
+    #[clap(
+        default_value = "content-type,authorization,x-priority",
+        long,
+        env,
+        value_delimiter = ','
+    )]
+    cors_allow_headers: Vec<String>,
+    #[clap(long, env)]
+    cors_allow_credentials: bool,
+    #[clap(long, env)]
+    api_keys_file: Option<PathBuf>,
+    #[clap(long, env)]
+    rate_limit_tokens_per_minute: Option<u32>,
+    #[clap(long, env)]
+    audit_log_path: Option<PathBuf>,
+    /// Size in megabytes after which the audit log file is rotated
+    #[clap(default_value = "100", long, env)]
+    audit_log_max_file_size: u64,
+    #[clap(default_value = "5", long, env)]
+    audit_log_max_files: usize,
+    #[clap(default_value = "none", long, env, value_enum)]
+    audit_log_content: AuditContent,
+    #[clap(long, env, value_enum, value_delimiter = ',')]
+    audit_log_redact: Vec<AuditField>,
+    #[clap(long, env)]
+    prefix_cache_max_tokens: Option<usize>,
+    #[clap(long, env)]
+    max_sessions: Option<usize>,
+    #[clap(default_value = "600", long, env)]
+    session_ttl_secs: u64,
+    #[clap(default_value = "15", long, env)]
+    sse_keep_alive_secs: u64,
+    #[clap(long, env)]
+    ready_max_queue_size: Option<usize>,
+    #[clap(default_value = "60", long, env)]
+    drain_timeout_secs: u64,
+    #[clap(long, env)]
+    default_parameters_file: Option<PathBuf>,
+    #[clap(long, env)]
+    prompt_templates_dir: Option<PathBuf>,
+    #[clap(long, env)]
+    content_filter_rules_file: Option<PathBuf>,
+    #[clap(long, env)]
+    content_filter_url: Option<String>,
+    #[clap(long, env)]
+    moderation_rules_file: Option<PathBuf>,
+    #[clap(long, env)]
+    moderation_url: Option<String>,
+    #[clap(
+        default_value = " [The rest of this response was removed by the content policy.]",
+        long,
+        env
+    )]
+    moderation_message: String,
+    #[clap(default_value = "16", long, env)]
+    moderation_interval_tokens: usize,
+    #[clap(long, env, value_enum)]
+    pii_redaction: Option<RedactionDirection>,
+    #[clap(long, env)]
+    usage_log_path: Option<PathBuf>,
+    #[clap(default_value = "60", long, env)]
+    usage_flush_interval_secs: u64,
+    #[clap(long, env)]
+    admin_api_key: Option<String>,
+    #[clap(long, env)]
+    adaptive_batch_total_tokens: bool,
+    #[clap(long, env)]
+    target_decode_latency_ms: Option<u64>,
+    #[clap(default_value = "60", long, env)]
+    idempotency_window_secs: u64,
+    #[clap(long, env)]
+    truncation_marker: Option<String>,
+    #[clap(default_value = "error", long, env, value_enum)]
+    default_on_overflow: OverflowPolicy,
+    #[clap(long, env)]
+    preemption: bool,
+    #[clap(long, env)]
+    max_queue_duration_ms: Option<u64>,
+    #[clap(long, env)]
+    max_prefill_chunk_tokens: Option<u32>,
+    #[clap(long, env)]
+    max_batch_decode_tokens: Option<u32>,
+    #[clap(default_value = "fair-share", long, env, value_enum)]
+    scheduling_policy: SchedulingPolicyKind,
+    #[clap(long, env)]
+    priority_aging_ms: Option<u64>,
+    #[clap(long, env)]
+    deduplicate_requests: bool,
+    #[clap(long, env)]
+    target_inter_token_latency_ms: Option<u64>,
+    /// File journaling the queued requests, replayed when the router restarts
+    #[clap(long, env)]
+    queue_journal_path: Option<PathBuf>,
+    #[clap(long, env)]
+    length_aware_batching: bool,
+    #[clap(long, env)]
+    max_starvation_ms: Option<u64>,
+    #[clap(default_value = "reject-newest", long, env, value_enum)]
+    shedding_policy: SheddingPolicy,
     #[clap(long, env)]
     ngrok: bool,
     #[clap(long, env)]
@@ -74,7 +263,9 @@ fn main() -> Result<(), RouterError> {
     let Args {
         max_concurrent_requests,
         max_best_of,
+        max_client_batch_size,
         max_stop_sequences,
+        max_top_n_tokens,
         max_input_length,
         max_total_tokens,
         waiting_served_ratio,
@@ -83,13 +274,81 @@ fn main() -> Result<(), RouterError> {
         max_waiting_tokens,
         hostname,
         port,
+        grpc_port,
         master_shard_uds_path,
+        replica_shard_uds_path,
+        shard_tls_ca_cert,
+        shard_tls_cert,
+        shard_tls_key,
+        shard_tls_domain,
+        shard_max_retries,
+        shard_retry_initial_backoff_ms,
+        shard_retry_max_backoff_ms,
+        shard_probe_interval_secs,
+        shard_prefill_timeout_ms,
+        shard_decode_timeout_ms,
+        shard_warmup_timeout_secs,
+        shard_control_timeout_ms,
+        shard_request_compression,
+        shard_response_compression,
+        shard_stream_decode,
+        shard_hedging_percentile,
+        shard_hedging_min_delay_ms,
+        model_backend,
+        model_max_concurrent_requests,
+        fim_tokens,
         tokenizer_name,
         revision,
         validation_workers,
+        max_tokenization_batch_size,
         json_output,
         otlp_endpoint,
         cors_allow_origin,
+        cors_allow_methods,
+        cors_allow_headers,
+        cors_allow_credentials,
+        api_keys_file,
+        rate_limit_tokens_per_minute,
+        audit_log_path,
+        audit_log_max_file_size,
+        audit_log_max_files,
+        audit_log_content,
+        audit_log_redact,
+        prefix_cache_max_tokens,
+        max_sessions,
+        session_ttl_secs,
+        sse_keep_alive_secs,
+        ready_max_queue_size,
+        drain_timeout_secs,
+        default_parameters_file,
+        prompt_templates_dir,
+        content_filter_rules_file,
+        content_filter_url,
+        moderation_rules_file,
+        moderation_url,
+        moderation_message,
+        moderation_interval_tokens,
+        pii_redaction,
+        usage_log_path,
+        usage_flush_interval_secs,
+        admin_api_key,
+        adaptive_batch_total_tokens,
+        target_decode_latency_ms,
+        idempotency_window_secs,
+        truncation_marker,
+        default_on_overflow,
+        preemption,
+        max_queue_duration_ms,
+        max_prefill_chunk_tokens,
+        max_batch_decode_tokens,
+        scheduling_policy,
+        priority_aging_ms,
+        deduplicate_requests,
+        target_inter_token_latency_ms,
+        queue_journal_path,
+        length_aware_batching,
+        max_starvation_ms,
+        shedding_policy,
         ngrok,
         ngrok_authtoken,
         ngrok_edge,
@@ -105,11 +364,142 @@ fn main() -> Result<(), RouterError> {
         return Err(RouterError::ArgumentValidation(format!("`max_batch_prefill_tokens` must be >= `max_input_length`. Given: {max_batch_prefill_tokens} and {max_input_length}")));
     }
 
+    if rate_limit_tokens_per_minute == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`rate_limit_tokens_per_minute` must be > 0".to_string(),
+        ));
+    }
+
+    if max_client_batch_size == 0 {
+        return Err(RouterError::ArgumentValidation(
+            "`max_client_batch_size` must be > 0".to_string(),
+        ));
+    }
+
+    if usage_log_path.is_some() && api_keys_file.is_none() {
+        return Err(RouterError::ArgumentValidation(
+            "`usage_log_path` requires `api_keys_file` as usage is accounted per tenant"
+                .to_string(),
+        ));
+    }
+
+    if admin_api_key.as_deref().map_or(false, str::is_empty) {
+        return Err(RouterError::ArgumentValidation(
+            "`admin_api_key` must not be empty".to_string(),
+        ));
+    }
+
+    if target_decode_latency_ms.is_some() && !adaptive_batch_total_tokens {
+        return Err(RouterError::ArgumentValidation(
+            "`target_decode_latency_ms` requires `adaptive_batch_total_tokens`".to_string(),
+        ));
+    }
+
+    if moderation_interval_tokens == 0 {
+        return Err(RouterError::ArgumentValidation(
+            "`moderation_interval_tokens` must be > 0".to_string(),
+        ));
+    }
+
+    if usage_flush_interval_secs == 0 {
+        return Err(RouterError::ArgumentValidation(
+            "`usage_flush_interval_secs` must be > 0".to_string(),
+        ));
+    }
+
+    if sse_keep_alive_secs == 0 {
+        return Err(RouterError::ArgumentValidation(
+            "`sse_keep_alive_secs` must be > 0".to_string(),
+        ));
+    }
+
+    if max_queue_duration_ms == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`max_queue_duration_ms` must be > 0".to_string(),
+        ));
+    }
+
+    if max_prefill_chunk_tokens == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`max_prefill_chunk_tokens` must be > 0".to_string(),
+        ));
+    }
+
+    if max_batch_decode_tokens == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`max_batch_decode_tokens` must be > 0".to_string(),
+        ));
+    }
+
+    if priority_aging_ms == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`priority_aging_ms` must be > 0".to_string(),
+        ));
+    }
+    if priority_aging_ms.is_some()
+        && matches!(
+            scheduling_policy,
+            SchedulingPolicyKind::Fifo | SchedulingPolicyKind::ShortestJobFirst
+        )
+    {
+        return Err(RouterError::ArgumentValidation(
+            "`priority_aging_ms` requires the `priority` or `fair-share` scheduling policy"
+                .to_string(),
+        ));
+    }
+
+    if max_starvation_ms == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`max_starvation_ms` must be > 0".to_string(),
+        ));
+    }
+    if max_starvation_ms.is_some() && scheduling_policy != SchedulingPolicyKind::ShortestJobFirst {
+        return Err(RouterError::ArgumentValidation(
+            "`max_starvation_ms` requires the `shortest-job-first` scheduling policy".to_string(),
+        ));
+    }
+
+    if target_inter_token_latency_ms == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`target_inter_token_latency_ms` must be > 0".to_string(),
+        ));
+    }
+
+    if max_sessions == Some(0) {
+        return Err(RouterError::ArgumentValidation(
+            "`max_sessions` must be > 0".to_string(),
+        ));
+    }
+    if max_sessions.is_some() && !replica_shard_uds_path.is_empty() {
+        // The KV cache of a session is pinned on the shards of a single replica
+        return Err(RouterError::ArgumentValidation(
+            "`max_sessions` cannot be set with `replica_shard_uds_path`".to_string(),
+        ));
+    }
+
+    if audit_log_max_file_size == 0 {
+        return Err(RouterError::ArgumentValidation(
+            "`audit_log_max_file_size` must be > 0".to_string(),
+        ));
+    }
+    let audit_config = audit_log_path.map(|path| AuditConfig {
+        path,
+        max_file_size: audit_log_max_file_size * 1024 * 1024,
+        max_files: audit_log_max_files,
+        content: audit_log_content,
+        redact: audit_log_redact,
+    });
+
     if validation_workers == 0 {
         return Err(RouterError::ArgumentValidation(
             "`validation_workers` must be > 0".to_string(),
         ));
     }
+    if max_tokenization_batch_size == 0 {
+        return Err(RouterError::ArgumentValidation(
+            "`max_tokenization_batch_size` must be > 0".to_string(),
+        ));
+    }
 
     if let Some(ref max_batch_total_tokens) = max_batch_total_tokens {
         if max_batch_prefill_tokens > *max_batch_total_tokens {
@@ -120,37 +510,172 @@ fn main() -> Result<(), RouterError> {
         }
     }
 
-    // CORS allowed origins
-    // map to go inside the option and then map to parse from String to HeaderValue
-    // Finally, convert to AllowOrigin
-    let cors_allow_origin: Option<AllowOrigin> = cors_allow_origin.map(|cors_allow_origin| {
-        AllowOrigin::list(
-            cors_allow_origin
-                .iter()
-                .map(|origin| origin.parse::<HeaderValue>().unwrap()),
-        )
+    // CORS configuration
+    // `*` allows any origin, as when no origin is given
+    let cors_allow_origin =
+        cors_allow_origin.filter(|origins| !origins.iter().any(|origin| origin == "*"));
+    // Browsers reject credentialed requests when any origin is allowed
+    if cors_allow_credentials && cors_allow_origin.is_none() {
+        return Err(RouterError::ArgumentValidation(
+            "`cors_allow_credentials` requires `cors_allow_origin`".to_string(),
+        ));
+    }
+    let cors_allow_origin = cors_allow_origin
+        .map(|origins| parse_cors_values::<HeaderValue>("cors_allow_origin", &origins))
+        .transpose()?
+        .map(AllowOrigin::list);
+    let cors_config = CorsConfig {
+        allow_origin: cors_allow_origin,
+        allow_methods: parse_cors_values("cors_allow_methods", &cors_allow_methods)?,
+        allow_headers: parse_cors_values("cors_allow_headers", &cors_allow_headers)?,
+        allow_credentials: cors_allow_credentials,
+    };
+
+    // TLS configuration of the shards connected to with an `https://` uri
+    let read_pem = |name: &str, path: PathBuf| {
+        std::fs::read(&path).map_err(|err| {
+            RouterError::ArgumentValidation(format!(
+                "could not read `{name}` {}: {err}",
+                path.display()
+            ))
+        })
+    };
+    let identity = match (shard_tls_cert, shard_tls_key) {
+        (Some(cert), Some(key)) => Some((
+            read_pem("shard_tls_cert", cert)?,
+            read_pem("shard_tls_key", key)?,
+        )),
+        (None, None) => None,
+        _ => {
+            return Err(RouterError::ArgumentValidation(
+                "`shard_tls_cert` and `shard_tls_key` must be set together".to_string(),
+            ))
+        }
+    };
+    let shard_tls = TlsConfig {
+        ca_certificate: shard_tls_ca_cert
+            .map(|path| read_pem("shard_tls_ca_cert", path))
+            .transpose()?,
+        identity,
+        domain_name: shard_tls_domain,
+    };
+    if shard_retry_initial_backoff_ms > shard_retry_max_backoff_ms {
+        return Err(RouterError::ArgumentValidation(
+            "`shard_retry_initial_backoff_ms` must be <= `shard_retry_max_backoff_ms`".to_string(),
+        ));
+    }
+    let shard_retry_policy = RetryPolicy {
+        max_retries: shard_max_retries,
+        initial_backoff: Duration::from_millis(shard_retry_initial_backoff_ms),
+        max_backoff: Duration::from_millis(shard_retry_max_backoff_ms),
+    };
+    let shard_probe_interval =
+        (shard_probe_interval_secs > 0).then(|| Duration::from_secs(shard_probe_interval_secs));
+    if let Some(percentile) = shard_hedging_percentile {
+        if !(0.0..=100.0).contains(&percentile) {
+            return Err(RouterError::ArgumentValidation(
+                "`shard_hedging_percentile` must be between 0 and 100".to_string(),
+            ));
+        }
+    }
+    for (name, timeout) in [
+        ("shard_prefill_timeout_ms", shard_prefill_timeout_ms),
+        ("shard_decode_timeout_ms", shard_decode_timeout_ms),
+        ("shard_warmup_timeout_secs", shard_warmup_timeout_secs),
+        ("shard_control_timeout_ms", shard_control_timeout_ms),
+    ] {
+        if timeout == Some(0) {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`{name}` must be > 0"
+            )));
+        }
+    }
+    let shard_timeouts = ShardTimeouts {
+        prefill: shard_prefill_timeout_ms.map(Duration::from_millis),
+        decode: shard_decode_timeout_ms.map(Duration::from_millis),
+        warmup: shard_warmup_timeout_secs.map(Duration::from_secs),
+        control: shard_control_timeout_ms.map(Duration::from_millis),
+    };
+    let shard_hedging = shard_hedging_percentile.map(|percentile| HedgingPolicy {
+        percentile,
+        min_delay: Duration::from_millis(shard_hedging_min_delay_ms),
     });
 
+    // Additional models
+    let mut model_backends: Vec<(String, String)> = Vec::with_capacity(model_backend.len());
+    for backend in model_backend {
+        let Some((model_id, uds_path)) = backend.split_once('=') else {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`model_backend` must be `<model id>=<master shard uds path>`. Given: {backend}"
+            )));
+        };
+        if model_id == tokenizer_name || model_backends.iter().any(|(other, _)| other == model_id) {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`model_backend` {model_id} is served more than once"
+            )));
+        }
+        model_backends.push((model_id.to_string(), uds_path.to_string()));
+    }
+
+    // Fill-in-the-middle sentinel tokens of the models
+    let mut model_fim_tokens: HashMap<String, FimTokens> = HashMap::new();
+    for value in fim_tokens {
+        let (model_id, tokens) = value
+            .split_once('=')
+            .unwrap_or((tokenizer_name.as_str(), value.as_str()));
+        let Some(tokens) = FimTokens::parse(tokens) else {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`fim_tokens` must be `[<model id>=]<prefix>,<suffix>,<middle>`. Given: {value}"
+            )));
+        };
+        if model_id != tokenizer_name && !model_backends.iter().any(|(other, _)| other == model_id)
+        {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`fim_tokens` model {model_id} is not served"
+            )));
+        }
+        model_fim_tokens.insert(model_id.to_string(), tokens);
+    }
+
+    // Concurrency limits of the models
+    let mut model_limits: HashMap<String, usize> = HashMap::new();
+    for value in model_max_concurrent_requests {
+        let Some((model_id, limit)) = value
+            .split_once('=')
+            .and_then(|(model_id, limit)| Some((model_id, limit.parse::<usize>().ok()?)))
+        else {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`model_max_concurrent_requests` must be `<model id>=<limit>`. Given: {value}"
+            )));
+        };
+        if limit == 0 {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`model_max_concurrent_requests` of {model_id} must be > 0"
+            )));
+        }
+        if model_id != tokenizer_name && !model_backends.iter().any(|(other, _)| other == model_id)
+        {
+            return Err(RouterError::ArgumentValidation(format!(
+                "`model_max_concurrent_requests` model {model_id} is not served"
+            )));
+        }
+        model_limits.insert(model_id.to_string(), limit);
+    }
+
     // Parse Huggingface hub token
     let authorization_token = std::env::var("HUGGING_FACE_HUB_TOKEN").ok();
 
-    // Tokenizer instance
-    // This will only be used to validate payloads
-    let local_path = Path::new(&tokenizer_name);
-    let local_model = local_path.exists() && local_path.is_dir();
-    let tokenizer = if local_model {
-        // Load local tokenizer
-        Tokenizer::from_file(local_path.join("tokenizer.json")).ok()
-    } else {
-        // Download and instantiate tokenizer
-        // We need to download it outside of the Tokio runtime
-        let params = FromPretrainedParameters {
-            revision: revision.clone().unwrap_or("main".to_string()),
-            auth_token: authorization_token.clone(),
-            ..Default::default()
-        };
-        Tokenizer::from_pretrained(tokenizer_name.clone(), Some(params)).ok()
-    };
+    // Tokenizer instances
+    // They will only be used to validate payloads
+    let tokenizer = load_tokenizer(
+        &tokenizer_name,
+        revision.clone(),
+        authorization_token.clone(),
+    );
+    let model_backend_tokenizers: Vec<Option<Tokenizer>> = model_backends
+        .iter()
+        .map(|(model_id, _)| load_tokenizer(model_id, None, authorization_token.clone()))
+        .collect();
 
     // Launch Tokio runtime
     tokio::runtime::Builder::new_multi_thread()
@@ -159,86 +684,67 @@ fn main() -> Result<(), RouterError> {
         .block_on(async {
             init_logging(otlp_endpoint, json_output);
 
-            if tokenizer.is_none() {
-                tracing::warn!(
-                    "Could not find a fast tokenizer implementation for {tokenizer_name}"
-                );
-                tracing::warn!("Rust input length validation and truncation is disabled");
-            }
-
-            // Get Model info
-            let model_info = match local_model {
-                true => HubModelInfo {
-                    model_id: tokenizer_name.clone(),
-                    sha: None,
-                    pipeline_tag: None,
-                },
-                false => get_model_info(&tokenizer_name, revision, authorization_token)
-                    .await
-                    .unwrap_or_else(|| {
-                        tracing::warn!("Could not retrieve model info from the Hugging Face hub.");
-                        HubModelInfo {
-                            model_id: tokenizer_name.to_string(),
-                            sha: None,
-                            pipeline_tag: None,
-                        }
+            // Connect to the shards of every model, starting with the default one
+            let models = std::iter::once((
+                tokenizer_name,
+                revision,
+                master_shard_uds_path,
+                replica_shard_uds_path,
+                tokenizer,
+            ))
+            .chain(
+                model_backends
+                    .into_iter()
+                    .zip(model_backend_tokenizers)
+                    .map(|((model_id, uds_path), tokenizer)| {
+                        (model_id, None, uds_path, Vec::new(), tokenizer)
                     }),
-            };
+            );
+            let mut backends = Vec::new();
+            for (model_id, revision, uds_path, replica_uds_paths, tokenizer) in models {
+                let fim_tokens = model_fim_tokens.remove(&model_id);
+                let max_concurrent_requests = model_limits
+                    .remove(&model_id)
+                    .unwrap_or(max_concurrent_requests);
+                let backend = connect_backend(
+                    model_id,
+                    revision,
+                    uds_path,
+                    replica_uds_paths,
+                    shard_tls.clone(),
+                    shard_retry_policy,
+                    shard_timeouts,
+                    shard_request_compression,
+                    shard_response_compression,
+                    shard_stream_decode,
+                    shard_probe_interval,
+                    shard_hedging,
+                    tokenizer,
+                    fim_tokens,
+                    max_concurrent_requests,
+                    authorization_token.clone(),
+                    max_input_length,
+                    max_total_tokens,
+                    max_batch_prefill_tokens,
+                    max_batch_total_tokens,
+                )
+                .await?;
+                backends.push(backend);
+            }
 
             // if pipeline-tag == text-generation we default to return_full_text = true
-            let compat_return_full_text = match &model_info.pipeline_tag {
+            let default_model_info = &backends[0].model_info;
+            let compat_return_full_text = match &default_model_info.pipeline_tag {
                 None => {
-                    tracing::warn!("no pipeline tag found for model {tokenizer_name}");
+                    tracing::warn!(
+                        "no pipeline tag found for model {}",
+                        default_model_info.model_id
+                    );
                     false
                 }
                 Some(pipeline_tag) => pipeline_tag.as_str() == "text-generation",
             };
 
-            // Instantiate sharded client from the master unix socket
-            let mut sharded_client = ShardedClient::connect_uds(master_shard_uds_path)
-                .await
-                .map_err(RouterError::Connection)?;
-            // Clear the cache; useful if the webserver rebooted
-            sharded_client
-                .clear_cache(None)
-                .await
-                .map_err(RouterError::Cache)?;
-            // Get info from the shard
-            let shard_info = sharded_client.info().await.map_err(RouterError::Info)?;
-
-            // Warmup model
-            tracing::info!("Warming up model");
-            let max_supported_batch_total_tokens = match sharded_client
-                .warmup(max_input_length as u32, max_batch_prefill_tokens)
-                .await
-                .map_err(RouterError::Warmup)?
-            {
-                // Older models do not support automatic max-batch-total-tokens
-                None => {
-                    let max_batch_total_tokens = max_batch_total_tokens.unwrap_or(
-                        16000.max((max_total_tokens as u32).max(max_batch_prefill_tokens)),
-                    );
-                    tracing::warn!("Model does not support automatic max batch total tokens");
-                    max_batch_total_tokens
-                }
-                // Flash attention models return their max supported total tokens
-                Some(max_supported_batch_total_tokens) => {
-                    // Warn if user added his own max-batch-total-tokens as we will ignore it
-                    if max_batch_total_tokens.is_some() {
-                        tracing::warn!(
-                            "`--max-batch-total-tokens` is deprecated for Flash \
-                        Attention models."
-                        );
-                        tracing::warn!(
-                            "Inferred max batch total tokens: {max_supported_batch_total_tokens}"
-                        );
-                    }
-                    max_supported_batch_total_tokens
-                }
-            };
-            tracing::info!("Setting max batch total tokens to {max_supported_batch_total_tokens}");
-            tracing::info!("Connected");
-
             let addr = match hostname.parse() {
                 Ok(ip) => SocketAddr::new(ip, port),
                 Err(_) => {
@@ -246,26 +752,64 @@ fn main() -> Result<(), RouterError> {
                     SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), port)
                 }
             };
+            let grpc_addr = grpc_port.map(|grpc_port| SocketAddr::new(addr.ip(), grpc_port));
 
             // Run server
             server::run(
-                model_info,
-                shard_info,
+                backends,
                 compat_return_full_text,
-                max_concurrent_requests,
                 max_best_of,
+                max_client_batch_size,
                 max_stop_sequences,
+                max_top_n_tokens,
                 max_input_length,
                 max_total_tokens,
                 waiting_served_ratio,
                 max_batch_prefill_tokens,
-                max_supported_batch_total_tokens,
                 max_waiting_tokens,
-                sharded_client,
-                tokenizer,
                 validation_workers,
+                max_tokenization_batch_size,
                 addr,
-                cors_allow_origin,
+                grpc_addr,
+                cors_config,
+                api_keys_file,
+                rate_limit_tokens_per_minute,
+                audit_config,
+                prefix_cache_max_tokens,
+                max_sessions,
+                Duration::from_secs(session_ttl_secs),
+                Duration::from_secs(sse_keep_alive_secs),
+                ready_max_queue_size,
+                Duration::from_secs(drain_timeout_secs),
+                default_parameters_file,
+                prompt_templates_dir,
+                content_filter_rules_file,
+                content_filter_url,
+                moderation_rules_file,
+                moderation_url,
+                moderation_message,
+                moderation_interval_tokens,
+                pii_redaction,
+                usage_log_path,
+                Duration::from_secs(usage_flush_interval_secs),
+                admin_api_key,
+                adaptive_batch_total_tokens,
+                target_decode_latency_ms.map(Duration::from_millis),
+                (idempotency_window_secs > 0).then(|| Duration::from_secs(idempotency_window_secs)),
+                truncation_marker,
+                default_on_overflow,
+                preemption,
+                max_queue_duration_ms.map(Duration::from_millis),
+                max_prefill_chunk_tokens,
+                max_batch_decode_tokens,
+                scheduling_policy,
+                priority_aging_ms.map(Duration::from_millis),
+                deduplicate_requests,
+                target_inter_token_latency_ms.map(Duration::from_millis),
+                queue_journal_path,
+                length_aware_batching,
+                max_starvation_ms.map(Duration::from_millis),
+                shedding_policy,
                 ngrok,
                 ngrok_authtoken,
                 ngrok_edge,
@@ -275,6 +819,266 @@ fn main() -> Result<(), RouterError> {
         })
 }
 
+/// Load the tokenizer of a model from a local directory or from the Hugging Face hub
+///
+/// Must be called outside of the Tokio runtime as the tokenizer is downloaded with a blocking client
+fn load_tokenizer(
+    tokenizer_name: &str,
+    revision: Option<String>,
+    authorization_token: Option<String>,
+) -> Option<Tokenizer> {
+    let local_path = Path::new(tokenizer_name);
+    if local_path.exists() && local_path.is_dir() {
+        // Load local tokenizer
+        Tokenizer::from_file(local_path.join("tokenizer.json")).ok()
+    } else {
+        // Download and instantiate tokenizer
+        let params = FromPretrainedParameters {
+            revision: revision.unwrap_or("main".to_string()),
+            auth_token: authorization_token,
+            ..Default::default()
+        };
+        Tokenizer::from_pretrained(tokenizer_name, Some(params)).ok()
+    }
+}
+
+/// Timeouts of the calls to the shards, unset timeouts wait indefinitely
+#[derive(Clone, Copy, Debug)]
+struct ShardTimeouts {
+    prefill: Option<Duration>,
+    decode: Option<Duration>,
+    warmup: Option<Duration>,
+    control: Option<Duration>,
+}
+
+impl ShardTimeouts {
+    fn apply(&self, mut client: ShardedClient) -> ShardedClient {
+        if let Some(timeout) = self.prefill {
+            client = client.with_prefill_timeout(timeout);
+        }
+        if let Some(timeout) = self.decode {
+            client = client.with_decode_timeout(timeout);
+        }
+        if let Some(timeout) = self.warmup {
+            client = client.with_warmup_timeout(timeout);
+        }
+        if let Some(timeout) = self.control {
+            client = client.with_control_timeout(timeout);
+        }
+        client
+    }
+}
+
+/// Get the info of a model, connect to its shards and warm them up
+#[allow(clippy::too_many_arguments)]
+async fn connect_backend(
+    tokenizer_name: String,
+    revision: Option<String>,
+    master_shard_uds_path: String,
+    replica_uds_paths: Vec<String>,
+    shard_tls: TlsConfig,
+    shard_retry_policy: RetryPolicy,
+    shard_timeouts: ShardTimeouts,
+    shard_request_compression: Option<Compression>,
+    shard_response_compression: Option<Compression>,
+    shard_stream_decode: bool,
+    shard_probe_interval: Option<Duration>,
+    shard_hedging: Option<HedgingPolicy>,
+    tokenizer: Option<Tokenizer>,
+    fim_tokens: Option<FimTokens>,
+    max_concurrent_requests: usize,
+    authorization_token: Option<String>,
+    max_input_length: usize,
+    max_total_tokens: usize,
+    max_batch_prefill_tokens: u32,
+    max_batch_total_tokens: Option<u32>,
+) -> Result<Backend, RouterError> {
+    let local_path = Path::new(&tokenizer_name);
+    let local_model = local_path.exists() && local_path.is_dir();
+
+    if tokenizer.is_none() {
+        tracing::warn!("Could not find a fast tokenizer implementation for {tokenizer_name}");
+        tracing::warn!("Rust input length validation and truncation is disabled");
+    }
+
+    // Get the tokenizer config used to render chat templates
+    let tokenizer_config = match local_model {
+        true => get_local_tokenizer_config(local_path),
+        false => {
+            get_tokenizer_config(
+                &tokenizer_name,
+                revision.clone(),
+                authorization_token.clone(),
+            )
+            .await
+        }
+    };
+    let tokenizer_config = tokenizer_config.unwrap_or_else(|| {
+        tracing::warn!("Could not find tokenizer config for {tokenizer_name}");
+        HubTokenizerConfig::default()
+    });
+    if tokenizer_config.chat_template.is_none() {
+        tracing::warn!(
+            "No chat template found, `/v1/chat/completions` is disabled for {tokenizer_name}"
+        );
+    }
+
+    // Fill-in-the-middle sentinel tokens, detected in the vocabulary unless configured
+    let fim_tokens = fim_tokens.or_else(|| tokenizer.as_ref().and_then(FimTokens::detect));
+    match &fim_tokens {
+        Some(fim_tokens) => tracing::info!("Fill-in-the-middle tokens: {fim_tokens:?}"),
+        None => tracing::info!("No fill-in-the-middle tokens, `suffix` is disabled"),
+    }
+
+    // Get Model info
+    let model_info = match local_model {
+        true => HubModelInfo {
+            model_id: tokenizer_name.clone(),
+            sha: None,
+            pipeline_tag: None,
+        },
+        false => get_model_info(&tokenizer_name, revision, authorization_token)
+            .await
+            .unwrap_or_else(|| {
+                tracing::warn!("Could not retrieve model info from the Hugging Face hub.");
+                HubModelInfo {
+                    model_id: tokenizer_name.to_string(),
+                    sha: None,
+                    pipeline_tag: None,
+                }
+            }),
+    };
+
+    // Instantiate sharded client from the master unix socket or uri
+    let sharded_client =
+        ShardedClient::connect_address(master_shard_uds_path, Some(shard_tls.clone()))
+            .await
+            .map_err(RouterError::Connection)?;
+    // Configuration of the clients of the shards of every replica
+    let configure = |client: ShardedClient| {
+        let mut client = shard_timeouts.apply(client.with_retry_policy(shard_retry_policy));
+        if let Some(compression) = shard_request_compression {
+            client = client.with_request_compression(compression);
+        }
+        if let Some(compression) = shard_response_compression {
+            client = client.with_response_compression(compression);
+        }
+        if shard_stream_decode {
+            client = client.with_decode_streaming();
+        }
+        client
+    };
+    let sharded_client = configure(sharded_client);
+    // Instantiate the sharded clients of the other replicas
+    let mut replica_clients = Vec::with_capacity(replica_uds_paths.len());
+    for replica_uds_path in replica_uds_paths {
+        let replica_client =
+            ShardedClient::connect_address(replica_uds_path, Some(shard_tls.clone()))
+                .await
+                .map_err(RouterError::Connection)?;
+        replica_clients.push(configure(replica_client));
+    }
+    let mut sharded_client = sharded_client.with_replicas(replica_clients);
+    if let Some(shard_hedging) = shard_hedging {
+        sharded_client = sharded_client.with_hedging(shard_hedging);
+    }
+    let mut replicas = sharded_client.replicas();
+    if replicas.len() > 1 {
+        tracing::info!(
+            "Balancing {tokenizer_name} between {} replicas",
+            replicas.len()
+        );
+    }
+    // Clear the cache; useful if the webserver rebooted
+    for replica in &mut replicas {
+        replica
+            .clear_cache(None)
+            .await
+            .map_err(RouterError::Cache)?;
+    }
+    // Get info from the shard
+    let shard_info = sharded_client
+        .hedged_info()
+        .await
+        .map_err(RouterError::Info)?;
+
+    // Warmup model, the batches of every replica are limited by the smallest one
+    tracing::info!("Warming up model {tokenizer_name}");
+    let mut max_supported_batch_total_tokens = None;
+    for replica in &mut replicas {
+        if let Some(max_supported) = replica
+            .warmup(max_input_length as u32, max_batch_prefill_tokens)
+            .await
+            .map_err(RouterError::Warmup)?
+        {
+            max_supported_batch_total_tokens = Some(
+                max_supported_batch_total_tokens
+                    .map_or(max_supported, |tokens: u32| tokens.min(max_supported)),
+            );
+        }
+    }
+    let max_supported_batch_total_tokens = match max_supported_batch_total_tokens {
+        // Older models do not support automatic max-batch-total-tokens
+        None => {
+            let max_batch_total_tokens = max_batch_total_tokens
+                .unwrap_or(16000.max((max_total_tokens as u32).max(max_batch_prefill_tokens)));
+            tracing::warn!("Model does not support automatic max batch total tokens");
+            max_batch_total_tokens
+        }
+        // Flash attention models return their max supported total tokens
+        Some(max_supported_batch_total_tokens) => {
+            // Warn if user added his own max-batch-total-tokens as we will ignore it
+            if max_batch_total_tokens.is_some() {
+                tracing::warn!(
+                    "`--max-batch-total-tokens` is deprecated for Flash \
+                Attention models."
+                );
+                tracing::warn!(
+                    "Inferred max batch total tokens: {max_supported_batch_total_tokens}"
+                );
+            }
+            max_supported_batch_total_tokens
+        }
+    };
+    tracing::info!("Setting max batch total tokens to {max_supported_batch_total_tokens}");
+    tracing::info!("Connected to {tokenizer_name}");
+
+    // Reconnect to the shards whose connection breaks
+    if let Some(shard_probe_interval) = shard_probe_interval {
+        for (replica_index, replica) in replicas.iter().enumerate() {
+            let model_id = tokenizer_name.clone();
+            replica.probe_connections(shard_probe_interval, move |shard, state| {
+                let connected = match state {
+                    ConnectionState::Connected => {
+                        tracing::info!(
+                            "Reconnected to shard {shard} of replica {replica_index} of {model_id}"
+                        );
+                        1.0
+                    }
+                    ConnectionState::Disconnected => {
+                        tracing::error!(
+                            "Lost the connection to shard {shard} of replica {replica_index} of {model_id}"
+                        );
+                        0.0
+                    }
+                };
+                metrics::gauge!("tgi_shard_connected", connected, "model" => model_id.clone(), "replica" => replica_index.to_string(), "shard" => shard.to_string());
+            });
+        }
+    }
+
+    Ok(Backend {
+        model_info,
+        tokenizer_config,
+        tokenizer,
+        fim_tokens,
+        max_concurrent_requests,
+        shard_info,
+        client: sharded_client,
+        max_batch_total_tokens: max_supported_batch_total_tokens,
+    })
+}
+
 /// Init logging using env variables LOG_LEVEL and LOG_FORMAT:
 ///     - otlp_endpoint is an optional URL to an Open Telemetry collector
 ///     - LOG_LEVEL may be TRACE, DEBUG, INFO, WARN or ERROR (default to INFO)
@@ -371,6 +1175,50 @@ pub async fn get_model_info(
     }
 }
 
+/// get tokenizer config from the Huggingface Hub
+pub async fn get_tokenizer_config(
+    model_id: &str,
+    revision: Option<String>,
+    token: Option<String>,
+) -> Option<HubTokenizerConfig> {
+    let revision = revision.unwrap_or("main".to_string());
+
+    let client = reqwest::Client::new();
+    // Poor man's urlencode
+    let revision = revision.replace('/', "%2F");
+    let url = format!("https://huggingface.co/{model_id}/resolve/{revision}/tokenizer_config.json");
+    let mut builder = client.get(url).timeout(Duration::from_secs(5));
+    if let Some(token) = token {
+        builder = builder.bearer_auth(token);
+    }
+
+    let response = builder.send().await.ok()?;
+
+    if response.status().is_success() {
+        serde_json::from_str(&response.text().await.ok()?).ok()
+    } else {
+        None
+    }
+}
+
+/// get tokenizer config from a local model directory
+fn get_local_tokenizer_config(local_path: &Path) -> Option<HubTokenizerConfig> {
+    let content = std::fs::read_to_string(local_path.join("tokenizer_config.json")).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Parse the values of a CORS argument
+fn parse_cors_values<T: FromStr>(name: &str, values: &[String]) -> Result<Vec<T>, RouterError> {
+    values
+        .iter()
+        .map(|value| {
+            value.trim().parse().map_err(|_| {
+                RouterError::ArgumentValidation(format!("`{name}` is not valid. Given: {value}"))
+            })
+        })
+        .collect()
+}
+
 #[derive(Debug, Error)]
 enum RouterError {
     #[error("Argument validation error: {0}")]
@@ -0,0 +1,55 @@
+/// Routing of the requests to the backends of the served models
+use crate::infer::InferError;
+use crate::Infer;
+use std::sync::Arc;
+
+/// Inference backends of the models served by the router, each with its own shards, queue and
+/// batching task
+///
+/// Requests select a backend with their `model` field. The first model is the default one, used
+/// when a request does not name a model. When a single model is served, the `model` field is
+/// ignored as clients of the OpenAI compatible routes often set it to a placeholder.
+#[derive(Clone)]
+pub(crate) struct Models {
+    models: Arc<Vec<(String, Infer)>>,
+}
+
+impl Models {
+    /// `models` must not be empty
+    pub(crate) fn new(models: Vec<(String, Infer)>) -> Self {
+        assert!(!models.is_empty(), "at least one model must be served");
+        Self {
+            models: Arc::new(models),
+        }
+    }
+
+    /// Id and backend of the requested model
+    pub(crate) fn get(&self, model: Option<&str>) -> Result<(String, Infer), InferError> {
+        let (model_id, infer) = match model {
+            Some(model) if self.models.len() > 1 => self
+                .models
+                .iter()
+                .find(|(model_id, _)| model_id == model)
+                .ok_or_else(|| {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "model_not_found");
+                    tracing::error!("Model {model} is not served");
+                    InferError::ModelNotFound(model.to_string())
+                })?,
+            _ => &self.models[0],
+        };
+        Ok((model_id.clone(), infer.clone()))
+    }
+
+    /// Ids and backends of the served models
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &(String, Infer)> {
+        self.models.iter()
+    }
+
+    /// Ids of the served models
+    pub(crate) fn ids(&self) -> Vec<String> {
+        self.models
+            .iter()
+            .map(|(model_id, _)| model_id.clone())
+            .collect()
+    }
+}
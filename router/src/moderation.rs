@@ -0,0 +1,282 @@
+/// Moderation of the generated text, stopping the generations that violate a policy
+use crate::content_filter::{load_rules, ContentFilterError, PolicyViolation, Rule};
+use crate::infer::{InferError, InferStreamResponse};
+use crate::Token;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use text_generation_client::{FinishReason, GeneratedText};
+use tokio::time::Instant;
+
+type ResponseReceiver = flume::Receiver<Result<InferStreamResponse, InferError>>;
+
+/// Timeout of the calls to the moderation endpoint
+const MODERATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Request sent to the moderation endpoint
+#[derive(Serialize)]
+struct ModerationRequest<'a> {
+    text: &'a str,
+    tenant: Option<&'a str>,
+}
+
+/// Score of the generated text by the moderation endpoint
+#[derive(Deserialize)]
+struct ModerationResponse {
+    flagged: bool,
+    #[serde(default)]
+    policy: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Moderation of the generated text with built-in rules and an external endpoint
+///
+/// The rules are checked on every generated token and the endpoint every `interval` tokens and at
+/// the end of the generation. A generation violating a policy ends early: the violating token is
+/// not sent and is replaced by `message` with the `content_filter` finish reason.
+#[derive(Debug)]
+pub(crate) struct OutputModeration {
+    /// Rules of the rules file, any match violates the policy of the rule whatever its action
+    rules: Vec<Rule>,
+    endpoint: Option<(reqwest::Client, String)>,
+    message: String,
+    interval: usize,
+}
+
+impl OutputModeration {
+    pub(crate) fn new(
+        rules_file: Option<&Path>,
+        endpoint: Option<String>,
+        message: String,
+        interval: usize,
+    ) -> Result<Self, ContentFilterError> {
+        let rules = rules_file.map(load_rules).transpose()?.unwrap_or_default();
+        Ok(Self {
+            rules,
+            endpoint: endpoint.map(|url| (reqwest::Client::new(), url)),
+            message,
+            interval,
+        })
+    }
+
+    /// Policy violated by the text generated so far, the endpoint is only called if `score`
+    ///
+    /// The generation continues if the endpoint is unavailable, the prompt was already filtered
+    async fn check(
+        &self,
+        text: &str,
+        score: bool,
+        tenant: Option<&str>,
+    ) -> Option<PolicyViolation> {
+        if let Some(rule) = self.rules.iter().find(|rule| rule.is_match(text)) {
+            return Some(PolicyViolation {
+                policy: rule.name().to_string(),
+                reason: rule.reason().map(str::to_string),
+            });
+        }
+
+        let (client, url) = self.endpoint.as_ref().filter(|_| score)?;
+        let request = serde_json::to_string(&ModerationRequest { text, tenant }).unwrap();
+        let response = client
+            .post(url)
+            .timeout(MODERATION_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(request)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        let response = match response {
+            Ok(response) => response.text().await.map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+        .and_then(|response| {
+            serde_json::from_str::<ModerationResponse>(&response).map_err(|err| err.to_string())
+        });
+        match response {
+            Ok(response) if response.flagged => Some(PolicyViolation {
+                policy: response.policy.unwrap_or_else(|| "moderation".to_string()),
+                reason: response.reason,
+            }),
+            Ok(_) => None,
+            Err(err) => {
+                metrics::increment_counter!("tgi_moderation_failure");
+                tracing::error!("Could not moderate the generated text: {err}");
+                None
+            }
+        }
+    }
+
+    /// Forward the responses of `response_rx` of a request of `tenant` until its generated text
+    /// violates a policy
+    ///
+    /// `response_rx` is then dropped: the batching task stops the generation as if the client
+    /// disconnected.
+    pub(crate) fn forward(
+        self: &Arc<Self>,
+        response_rx: ResponseReceiver,
+        tenant: Option<String>,
+    ) -> ResponseReceiver {
+        let moderation = self.clone();
+        let (response_tx, moderated_rx) = flume::unbounded();
+        tokio::spawn(async move {
+            let tenant = tenant.as_deref();
+            let queued = Instant::now();
+            let mut start = None;
+            let mut text = String::new();
+            let mut tokens = 0;
+            while let Ok(response) = response_rx.recv_async().await {
+                let violation = match &response {
+                    Ok(InferStreamResponse::Intermediate { token, .. }) => {
+                        start.get_or_insert_with(Instant::now);
+                        let mut generated = text.clone();
+                        if !token.special {
+                            generated.push_str(&token.text);
+                        }
+                        let score = (tokens + 1) % moderation.interval == 0;
+                        let violation = moderation.check(&generated, score, tenant).await;
+                        if violation.is_none() {
+                            text = generated;
+                            tokens += 1;
+                        }
+                        violation
+                    }
+                    Ok(InferStreamResponse::End { generated_text, .. }) => {
+                        moderation.check(&generated_text.text, true, tenant).await
+                    }
+                    _ => None,
+                };
+
+                let Some(violation) = violation else {
+                    if response_tx.send(response).is_err() {
+                        break;
+                    }
+                    continue;
+                };
+                metrics::increment_counter!("tgi_moderation_stopped", "policy" => violation.policy.clone());
+                tracing::info!(
+                    "Generation stopped by the policy `{}` after {tokens} tokens",
+                    violation.policy
+                );
+                let _ = response_tx.send(Ok(InferStreamResponse::End {
+                    token: Token {
+                        id: 0,
+                        text: moderation.message.clone(),
+                        logprob: f32::NAN,
+                        special: false,
+                    },
+                    top_tokens: vec![],
+                    generated_text: GeneratedText {
+                        text: text + &moderation.message,
+                        generated_tokens: tokens as u32,
+                        finish_reason: FinishReason::ContentFilter as i32,
+                        seed: None,
+                    },
+                    start: start.unwrap_or(queued),
+                    queued,
+                    continuation: None,
+                }));
+                break;
+            }
+        });
+        moderated_rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn intermediate(text: &str) -> Result<InferStreamResponse, InferError> {
+        Ok(InferStreamResponse::Intermediate {
+            token: Token {
+                id: 1,
+                text: text.to_string(),
+                logprob: 0.0,
+                special: false,
+            },
+            top_tokens: vec![],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_output_moderation() {
+        let path = std::env::temp_dir().join(format!("moderation-{}.json", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            r#"[{"name": "no-passwords", "keywords": ["password"], "action": "reject"}]"#,
+        )
+        .unwrap();
+        let moderation = Arc::new(
+            OutputModeration::new(Some(&path), None, " [removed]".to_string(), 16).unwrap(),
+        );
+
+        let (response_tx, response_rx) = flume::unbounded();
+        let moderated_rx = moderation.forward(response_rx, None);
+        for text in ["The", " admin", " password", " is"] {
+            response_tx.send(intermediate(text)).unwrap();
+        }
+
+        for text in ["The", " admin"] {
+            match moderated_rx.recv_async().await.unwrap() {
+                Ok(InferStreamResponse::Intermediate { token, .. }) => assert_eq!(token.text, text),
+                _ => panic!("Unexpected response"),
+            }
+        }
+        match moderated_rx.recv_async().await.unwrap() {
+            Ok(InferStreamResponse::End {
+                token,
+                generated_text,
+                ..
+            }) => {
+                assert_eq!(token.text, " [removed]");
+                assert_eq!(generated_text.text, "The admin [removed]");
+                assert_eq!(generated_text.generated_tokens, 2);
+                assert_eq!(
+                    generated_text.finish_reason,
+                    FinishReason::ContentFilter as i32
+                );
+            }
+            _ => panic!("Unexpected response"),
+        }
+        // The generation is stopped
+        assert!(moderated_rx.recv_async().await.is_err());
+        assert!(response_tx.send(intermediate(" secret")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_output_moderation_first_token() {
+        let path = std::env::temp_dir().join(format!("moderation-{}.json", rand::random::<u64>()));
+        std::fs::write(
+            &path,
+            r#"[{"name": "no-passwords", "keywords": ["password"], "action": "reject"}]"#,
+        )
+        .unwrap();
+        let moderation = Arc::new(
+            OutputModeration::new(Some(&path), None, " [removed]".to_string(), 16).unwrap(),
+        );
+
+        let (response_tx, response_rx) = flume::unbounded();
+        let moderated_rx = moderation.forward(response_rx, None);
+        response_tx.send(intermediate("password")).unwrap();
+
+        match moderated_rx.recv_async().await.unwrap() {
+            Ok(InferStreamResponse::End {
+                generated_text,
+                start,
+                ..
+            }) => {
+                assert_eq!(generated_text.text, " [removed]");
+                assert_eq!(generated_text.generated_tokens, 0);
+                // The streaming route reports the timings of the generation without any token
+                let inference_time = Instant::now() - start;
+                assert_eq!(
+                    crate::server::time_per_token(inference_time, generated_text.generated_tokens),
+                    inference_time
+                );
+            }
+            _ => panic!("Unexpected response"),
+        }
+    }
+}
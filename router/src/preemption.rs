@@ -0,0 +1,177 @@
+/// Preemption of running requests to make room for waiting requests of a higher priority
+use crate::validation::ValidGenerateRequest;
+use crate::{Entry, Priority, Queue};
+use nohash_hasher::IntMap;
+use std::cmp::Reverse;
+use text_generation_client::{GeneratedText, GrammarType};
+
+/// Text generated by a request that can be preempted
+///
+/// A preempted request is added back to the queue with the text it generated appended to its
+/// prompt, the shards only know of the text generated after the last preemption
+#[derive(Debug, Default)]
+pub(crate) struct Progress {
+    /// Text generated so far
+    text: String,
+    /// Number of tokens generated so far
+    tokens: u32,
+    /// Length of the part of `text` generated before the last preemption
+    preempted_len: usize,
+    /// Number of tokens generated before the last preemption
+    preempted_tokens: u32,
+}
+
+impl Progress {
+    /// Add the text of a generated token
+    pub(crate) fn push(&mut self, text: &str) {
+        self.text.push_str(text);
+        self.tokens += 1;
+    }
+
+    /// Number of generated tokens added to the prompt by the preemptions
+    pub(crate) fn preempted_tokens(&self) -> u32 {
+        self.preempted_tokens
+    }
+
+    /// Prepend the text generated before the last preemption to the text generated by the shards
+    pub(crate) fn complete(&self, mut generated_text: GeneratedText) -> GeneratedText {
+        generated_text
+            .text
+            .insert_str(0, &self.text[..self.preempted_len]);
+        generated_text.generated_tokens += self.preempted_tokens;
+        generated_text
+    }
+}
+
+/// Whether the generation of `request` can be interrupted and resumed from its generated text
+///
+/// High priority requests are never preempted. The state of grammars, choices and sessions
+/// cannot be rebuilt from the generated text.
+pub(crate) fn preemptible(request: &ValidGenerateRequest) -> bool {
+    request.priority < Priority::High
+        && request.parameters.grammar_type == GrammarType::None as i32
+        && request.choices.is_empty()
+        && request.session_id.is_none()
+}
+
+/// Number of tokens the request of `entry` can still generate
+fn remaining_tokens(entry: &Entry) -> u32 {
+    let generated = entry
+        .progress
+        .as_ref()
+        .map_or(0, |progress| progress.tokens - progress.preempted_tokens);
+    entry
+        .request
+        .stopping_parameters
+        .max_new_tokens
+        .saturating_sub(generated)
+}
+
+/// Rewrite the request of `entry` to resume its generation from the text generated so far
+fn resume(entry: &mut Entry) {
+    let progress = entry
+        .progress
+        .as_mut()
+        .expect("Preempted entry without progress. This is a bug.");
+    let text = &progress.text[progress.preempted_len..];
+    let tokens = progress.tokens - progress.preempted_tokens;
+
+    let request = &mut entry.request;
+    request.inputs.push_str(text);
+    // The tokenization of the prompt followed by the generated text is unknown
+    request.input_ids.clear();
+    request.input_length += tokens;
+    request.truncate = request.truncate.max(request.input_length);
+    request.stopping_parameters.max_new_tokens = request
+        .stopping_parameters
+        .max_new_tokens
+        .saturating_sub(tokens)
+        .max(1);
+    request.parameters.min_new_tokens = request.parameters.min_new_tokens.saturating_sub(tokens);
+    // Only the part of the healed text that was not generated yet is still forced
+    request.parameters.token_healing_prefix = request
+        .parameters
+        .token_healing_prefix
+        .strip_prefix(text)
+        .unwrap_or_default()
+        .to_string();
+    // The prefill tokens were already sent
+    request.decoder_input_details = false;
+
+    progress.preempted_len = progress.text.len();
+    progress.preempted_tokens = progress.tokens;
+    entry.generated_tokens = 0;
+}
+
+/// Preempt running entries of a lower priority than the next queued entry until it fits in the
+/// `free_tokens` of the token budget
+///
+/// Entries are preempted from the lowest priority and the longest remaining generation. They are
+/// added back to the queue and resume their generation once batched again. Nothing is preempted
+/// if the queued entry would not fit anyway. Returns whether entries were preempted.
+pub(crate) async fn preempt(
+    queue: &Queue,
+    entries: &mut IntMap<u64, Entry>,
+    mut free_tokens: u32,
+) -> bool {
+    let (priority, tokens) = match queue.peek().await {
+        Some(next) if next.1 > free_tokens => next,
+        _ => return false,
+    };
+
+    let mut candidates: Vec<(u64, &Entry)> = entries
+        .iter()
+        .filter(|(_, entry)| entry.progress.is_some() && entry.request.priority < priority)
+        .map(|(&id, entry)| (id, entry))
+        .collect();
+    candidates.sort_by_key(|(_, entry)| (entry.request.priority, Reverse(remaining_tokens(entry))));
+
+    let mut victims = Vec::new();
+    for (id, entry) in candidates {
+        if free_tokens >= tokens {
+            break;
+        }
+        free_tokens +=
+            entry.request.input_length + entry.request.stopping_parameters.max_new_tokens;
+        victims.push(id);
+    }
+    if free_tokens < tokens {
+        return false;
+    }
+
+    for id in victims.iter() {
+        let mut entry = entries
+            .remove(id)
+            .expect("ID not found in entries. This is a bug.");
+        resume(&mut entry);
+        metrics::increment_counter!("tgi_request_preempted", "priority" => entry.request.priority.as_str());
+        tracing::info!(parent: &entry.span, "Preempted by a request of priority {}", priority.as_str());
+        queue.append(entry);
+    }
+    !victims.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_complete() {
+        let mut progress = Progress::default();
+        progress.push("Hello");
+        progress.push(" world");
+        progress.preempted_len = progress.text.len();
+        progress.preempted_tokens = progress.tokens;
+        progress.push("!");
+
+        let generated_text = progress.complete(GeneratedText {
+            text: "!".to_string(),
+            generated_tokens: 1,
+            finish_reason: 0,
+            seed: None,
+        });
+        assert_eq!(generated_text.text, "Hello world!");
+        assert_eq!(generated_text.generated_tokens, 3);
+        assert_eq!(progress.preempted_tokens(), 2);
+    }
+}
@@ -0,0 +1,154 @@
+/// Radix tree of recently seen prompt prefixes
+use std::collections::HashMap;
+
+/// Token ids of recently prefilled prompts, stored in a radix tree
+///
+/// Used to tell the shards how many leading tokens of a request were already prefilled by a
+/// previous request so that they can reuse their KV cache instead of prefilling them again.
+/// The least recently used prompts are evicted once the tree holds more than `max_tokens`.
+#[derive(Debug)]
+pub(crate) struct PrefixCache {
+    root: Node,
+    /// Number of tokens stored in the tree
+    tokens: usize,
+    max_tokens: usize,
+    /// Logical clock used to order the uses of the nodes
+    clock: u64,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Tokens of the edge leading to this node
+    tokens: Vec<u32>,
+    /// Children indexed by the first token of their edge
+    children: HashMap<u32, Node>,
+    last_used: u64,
+}
+
+impl Node {
+    /// Last use of the least recently used leaf below this node
+    fn oldest_leaf(&self) -> u64 {
+        self.children
+            .values()
+            .map(Node::oldest_leaf)
+            .min()
+            .unwrap_or(self.last_used)
+    }
+
+    /// Remove the leaf below this node last used at `last_used` and return its number of tokens
+    fn remove_leaf(&mut self, last_used: u64) -> Option<usize> {
+        let first = self
+            .children
+            .iter()
+            .find(|(_, child)| child.oldest_leaf() == last_used)
+            .map(|(first, _)| *first)?;
+        let child = self.children.get_mut(&first)?;
+        if child.children.is_empty() {
+            return self.children.remove(&first).map(|child| child.tokens.len());
+        }
+        child.remove_leaf(last_used)
+    }
+}
+
+impl PrefixCache {
+    pub(crate) fn new(max_tokens: usize) -> Self {
+        Self {
+            root: Node::default(),
+            tokens: 0,
+            max_tokens,
+            clock: 0,
+        }
+    }
+
+    /// Insert the token ids of a prompt and return the length of its longest prefix that was
+    /// already in the cache
+    pub(crate) fn insert(&mut self, input_ids: &[u32]) -> usize {
+        self.clock += 1;
+        let mut matched = 0;
+        let mut node = &mut self.root;
+        loop {
+            node.last_used = self.clock;
+            let rest = &input_ids[matched..];
+            let Some(first) = rest.first() else {
+                break;
+            };
+
+            if !node.children.contains_key(first) {
+                node.children.insert(
+                    *first,
+                    Node {
+                        tokens: rest.to_vec(),
+                        children: HashMap::new(),
+                        last_used: self.clock,
+                    },
+                );
+                self.tokens += rest.len();
+                break;
+            }
+
+            let child = node.children.get_mut(first).unwrap();
+            let common = child
+                .tokens
+                .iter()
+                .zip(rest)
+                .take_while(|(a, b)| a == b)
+                .count();
+            matched += common;
+
+            if common < child.tokens.len() {
+                // Split the edge at the end of the common prefix
+                let tokens = child.tokens.split_off(common);
+                let split = Node {
+                    tokens,
+                    children: std::mem::take(&mut child.children),
+                    last_used: child.last_used,
+                };
+                child.children.insert(split.tokens[0], split);
+            }
+            node = child;
+        }
+
+        self.evict();
+        matched
+    }
+
+    /// Remove the least recently used prompts until the cache fits in `max_tokens`
+    fn evict(&mut self) {
+        while self.tokens > self.max_tokens {
+            let last_used = self.root.oldest_leaf();
+            match self.root.remove_leaf(last_used) {
+                Some(tokens) => self.tokens -= tokens,
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_cache() {
+        let mut cache = PrefixCache::new(100);
+        assert_eq!(cache.insert(&[1, 2, 3, 4]), 0);
+        assert_eq!(cache.insert(&[1, 2, 3, 4]), 4);
+        assert_eq!(cache.insert(&[1, 2, 5]), 2);
+        assert_eq!(cache.insert(&[1, 2, 5, 6]), 3);
+        assert_eq!(cache.insert(&[7]), 0);
+        assert_eq!(cache.tokens, 7);
+    }
+
+    #[test]
+    fn test_prefix_cache_eviction() {
+        let mut cache = PrefixCache::new(6);
+        cache.insert(&[1, 2, 3]);
+        cache.insert(&[4, 5, 6]);
+        // Use the first prompt again so that the second one is evicted
+        cache.insert(&[1, 2, 3]);
+        cache.insert(&[7, 8]);
+        assert_eq!(cache.tokens, 5);
+        assert_eq!(cache.insert(&[1, 2, 3]), 3);
+        assert_eq!(cache.insert(&[4, 5, 6]), 0);
+    }
+}
@@ -0,0 +1,224 @@
+/// Named prompt templates rendered by the router
+use crate::validation::ValidationError;
+use minijinja::{Environment, UndefinedBehavior};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+/// Interval between two checks of the templates directory
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Extensions of the template files
+const TEMPLATE_EXTENSIONS: [&str; 2] = ["j2", "jinja"];
+
+/// Prompt templates loaded from a directory, named after their file without the extension
+///
+/// Requests reference a template by name with variables instead of sending the whole prompt,
+/// so that prompts can be updated without changing every client. The directory is reloaded when
+/// a template is modified or when the router receives `SIGHUP`.
+#[derive(Clone, Debug)]
+pub(crate) struct PromptTemplates {
+    dir: PathBuf,
+    templates: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl PromptTemplates {
+    pub(crate) fn load(dir: PathBuf) -> Result<Self, PromptTemplatesError> {
+        let templates = Self {
+            dir,
+            templates: Arc::default(),
+        };
+        templates.reload()?;
+        Ok(templates)
+    }
+
+    /// Read the templates directory and replace the current templates
+    ///
+    /// The current templates are kept if any template fails to compile
+    fn reload(&self) -> Result<(), PromptTemplatesError> {
+        let mut templates = HashMap::new();
+        for path in template_paths(&self.dir)? {
+            let Some(name) = path.file_stem().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let source = std::fs::read_to_string(&path)?;
+            Environment::new()
+                .template_from_str(&source)
+                .map_err(|err| PromptTemplatesError::Compile(name.to_string(), err))?;
+            templates.insert(name.to_string(), source);
+        }
+        tracing::info!("Loaded {} prompt templates", templates.len());
+        metrics::gauge!("tgi_prompt_templates", templates.len() as f64);
+        *self.templates.write().unwrap() = templates;
+        Ok(())
+    }
+
+    /// Render the template `name` with `variables` and the `inputs` of the request
+    ///
+    /// Undefined variables are errors so that a missing variable does not silently produce an
+    /// incomplete prompt
+    pub(crate) fn render(
+        &self,
+        name: &str,
+        inputs: String,
+        variables: Map<String, Value>,
+    ) -> Result<String, ValidationError> {
+        let templates = self.templates.read().unwrap();
+        let source = templates
+            .get(name)
+            .ok_or_else(|| ValidationError::PromptTemplate(name.to_string()))?;
+
+        let mut env = Environment::new();
+        env.set_undefined_behavior(UndefinedBehavior::Strict);
+        let mut context = variables;
+        context.insert("inputs".to_string(), Value::String(inputs));
+        let prompt = env.render_str(source, context).map_err(|err| {
+            ValidationError::PromptTemplateRender(name.to_string(), err.to_string())
+        })?;
+        metrics::increment_counter!("tgi_prompt_template_rendered", "template" => name.to_string());
+        Ok(prompt)
+    }
+
+    /// Reload the templates in the background when they are modified or on `SIGHUP`
+    pub(crate) fn spawn_reload_task(&self) {
+        let templates = self.clone();
+        tokio::spawn(async move {
+            let mut last_modified = last_modified(&templates.dir);
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+            #[cfg(unix)]
+            let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                .expect("failed to install SIGHUP handler");
+
+            loop {
+                #[cfg(unix)]
+                let signaled = tokio::select! {
+                    _ = interval.tick() => false,
+                    _ = hangup.recv() => true,
+                };
+                #[cfg(not(unix))]
+                let signaled = {
+                    interval.tick().await;
+                    false
+                };
+
+                let current = last_modified(&templates.dir);
+                if signaled || current != last_modified {
+                    last_modified = current;
+                    if let Err(err) = templates.reload() {
+                        tracing::error!("Could not reload the prompt templates: {err}");
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Paths of the template files of `dir`
+fn template_paths(dir: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_template = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map_or(false, |extension| TEMPLATE_EXTENSIONS.contains(&extension));
+        if is_template && path.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Latest modification of the templates directory or of one of its templates
+///
+/// Adding or removing a template modifies the directory, editing one only modifies the file
+fn last_modified(dir: &Path) -> Option<SystemTime> {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let files = template_paths(dir).ok()?;
+    files
+        .iter()
+        .filter_map(|path| modified(path))
+        .chain(modified(dir))
+        .max()
+}
+
+#[derive(Error, Debug)]
+pub(crate) enum PromptTemplatesError {
+    #[error("Unable to read the prompt templates: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unable to compile the prompt template `{0}`: {1}")]
+    Compile(String, minijinja::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn templates_dir(templates: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("templates-{}", rand::random::<u64>()));
+        std::fs::create_dir(&dir).unwrap();
+        for (file, source) in templates {
+            std::fs::write(dir.join(file), source).unwrap();
+        }
+        dir
+    }
+
+    fn variables(value: Value) -> Map<String, Value> {
+        match value {
+            Value::Object(variables) => variables,
+            _ => panic!("variables must be an object"),
+        }
+    }
+
+    #[test]
+    fn test_prompt_templates() {
+        let dir = templates_dir(&[
+            (
+                "summarize_v2.j2",
+                "Summarize in {{ words }} words:\n{{ inputs }}",
+            ),
+            ("notes.txt", "not a template"),
+        ]);
+        let templates = PromptTemplates::load(dir.clone()).unwrap();
+
+        let prompt = templates
+            .render(
+                "summarize_v2",
+                "Hello world".to_string(),
+                variables(json!({"words": 10})),
+            )
+            .unwrap();
+        assert_eq!(prompt, "Summarize in 10 words:\nHello world");
+
+        match templates.render("notes", String::new(), Map::new()) {
+            Err(ValidationError::PromptTemplate(name)) => assert_eq!(name, "notes"),
+            _ => panic!("Unexpected template"),
+        }
+        // Missing variable
+        match templates.render("summarize_v2", String::new(), Map::new()) {
+            Err(ValidationError::PromptTemplateRender(name, _)) => {
+                assert_eq!(name, "summarize_v2")
+            }
+            _ => panic!("Unexpected render"),
+        }
+
+        // The current templates are kept if a template does not compile
+        std::fs::write(dir.join("broken.jinja"), "{{ inputs").unwrap();
+        assert!(matches!(
+            templates.reload(),
+            Err(PromptTemplatesError::Compile(..))
+        ));
+        assert!(templates
+            .render(
+                "summarize_v2",
+                String::new(),
+                variables(json!({"words": 1}))
+            )
+            .is_ok());
+    }
+}
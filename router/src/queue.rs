@@ -1,8 +1,20 @@
+use crate::audit::AuditLog;
+use crate::choice::ChoiceTracker;
+use crate::continuation::Continuations;
 use crate::infer::InferError;
 use crate::infer::InferStreamResponse;
+use crate::journal::JournalEntry;
+use crate::length_prediction::LengthPrediction;
+use crate::preemption::Progress;
+use crate::prefix_cache::PrefixCache;
+use crate::scheduling::{SchedulingPolicy, SchedulingPolicyKind};
+use crate::sessions::SessionTurn;
+use crate::shedding::SheddingPolicy;
+use crate::stop_regex::StopRegexTracker;
 use crate::validation::ValidGenerateRequest;
+use crate::{Priority, QueueLoad};
 use nohash_hasher::{BuildNoHashHasher, IntMap};
-use std::collections::VecDeque;
+use std::time::Duration;
 use text_generation_client::{Batch, Request};
 use tokio::sync::oneshot;
 use tokio::time::Instant;
@@ -23,6 +35,111 @@ pub(crate) struct Entry {
     pub queue_time: Instant,
     /// Instant when this entry was added to a batch
     pub batch_time: Option<Instant>,
+    /// Audit log the outcome of this entry is recorded to
+    pub audit_log: Option<AuditLog>,
+    /// Turn of the session of the request
+    pub session: Option<SessionTurn>,
+    /// Generated text of a request constrained to a choice
+    pub choice: Option<ChoiceTracker>,
+    /// Generated text of a request with regex stop sequences
+    pub stop_regex: Option<StopRegexTracker>,
+    /// Generated text of a request that can be preempted
+    pub progress: Option<Progress>,
+    /// Last queue position sent to the client
+    pub queue_position: Option<usize>,
+    /// Number of tokens generated since the request was last batched
+    pub generated_tokens: u32,
+    /// Number of priority levels gained while waiting in the queue
+    pub priority_boost: usize,
+    /// Record of the request in the queue journal, until it is batched
+    pub journal: Option<JournalEntry>,
+    /// Predicted number of tokens generated by the request
+    pub length_prediction: Option<LengthPrediction>,
+    /// Registry the generation is saved to when it pauses after `pause_after` tokens
+    pub continuations: Option<Continuations>,
+}
+
+/// Charge the tenant and the rate limit of the request for the tokens it used, however it ended:
+/// finished, cancelled, timed out or failed
+///
+/// The requests removed before their first prefill are not charged.
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if self.batch_time.is_none() {
+            return;
+        }
+        // The text generated before a preemption is accounted as generated, not as prompt
+        let preempted_tokens = self
+            .progress
+            .as_ref()
+            .map_or(0, Progress::preempted_tokens);
+        let generated_tokens = preempted_tokens + self.generated_tokens;
+        if let Some(tenant) = &self.request.tenant {
+            tenant.record_usage(
+                self.request.input_length - preempted_tokens,
+                generated_tokens,
+            );
+        }
+        if let Some(rate_limit) = &self.request.rate_limit {
+            rate_limit.record_tokens(generated_tokens);
+        }
+    }
+}
+
+impl Entry {
+    /// Whether the deadline of the request has passed
+    pub(crate) fn expired(&self, now: Instant) -> bool {
+        self.request
+            .deadline
+            .map_or(false, |deadline| now >= deadline)
+    }
+
+    /// Whether the request was never batched and waited in the queue for `max_queue_duration`
+    pub(crate) fn queue_expired(&self, now: Instant, max_queue_duration: Duration) -> bool {
+        self.batch_time.is_none() && now.duration_since(self.queue_time) >= max_queue_duration
+    }
+
+    /// Send the position of the request in the queue to the client if it changed
+    fn notify_position(&mut self, position: usize, estimated_wait: Option<Duration>) {
+        if !self.request.queue_events || self.queue_position == Some(position) {
+            return;
+        }
+        self.queue_position = Some(position);
+        // unwrap_or is valid here as we don't care if the receiver is gone.
+        self.response_tx
+            .send(Ok(InferStreamResponse::Queued {
+                position,
+                estimated_wait,
+            }))
+            .unwrap_or(());
+    }
+
+    /// Notify the client that its request timed out
+    pub(crate) fn notify_timeout(&self, id: u64) {
+        metrics::increment_counter!("tgi_request_failure", "err" => "timeout");
+        self.notify_error(id, InferError::Timeout);
+    }
+
+    /// Notify the client that its request waited in the queue for too long
+    fn notify_queue_timeout(&self, id: u64, max_queue_duration: Duration) {
+        metrics::increment_counter!("tgi_request_failure", "err" => "queue_timeout");
+        self.notify_error(id, InferError::QueueTimeout(max_queue_duration));
+    }
+
+    /// Notify the client that its request was rejected to admit a new request
+    fn notify_shed(&self, id: u64, load: QueueLoad) {
+        metrics::increment_counter!("tgi_request_failure", "err" => "shed");
+        self.notify_error(id, InferError::Overloaded(load));
+    }
+
+    fn notify_error(&self, id: u64, err: InferError) {
+        tracing::error!(parent: &self.span, "{err}");
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record_error(id, self, &err);
+        }
+        // unwrap_or is valid here as we don't care if the receiver is gone.
+        self.response_tx.send(Err(err)).unwrap_or(());
+    }
 }
 
 /// Request Queue
@@ -33,12 +150,31 @@ pub(crate) struct Queue {
 }
 
 impl Queue {
-    pub(crate) fn new(requires_padding: bool, block_size: u32) -> Self {
+    pub(crate) fn new(
+        model_id: String,
+        requires_padding: bool,
+        block_size: u32,
+        prefix_cache: Option<PrefixCache>,
+        max_queue_duration: Option<Duration>,
+        scheduling_policy: SchedulingPolicyKind,
+        priority_aging: Option<Duration>,
+        max_starvation: Option<Duration>,
+    ) -> Self {
         // Create channel
         let (queue_sender, queue_receiver) = flume::unbounded();
 
         // Launch background queue task
-        tokio::spawn(queue_task(requires_padding, block_size, queue_receiver));
+        tokio::spawn(queue_task(
+            model_id,
+            requires_padding,
+            block_size,
+            prefix_cache,
+            max_queue_duration,
+            scheduling_policy,
+            priority_aging,
+            max_starvation,
+            queue_receiver,
+        ));
 
         Self { queue_sender }
     }
@@ -54,12 +190,16 @@ impl Queue {
     }
 
     // Get the next batch
+    //
+    // When a `decode_token_budget` is given, the tokens the requests can generate are budgeted
+    // separately and `token_budget` only has to fit their prompts
     #[instrument(skip(self))]
     pub(crate) async fn next_batch(
         &self,
         min_size: Option<usize>,
         prefill_token_budget: u32,
         token_budget: u32,
+        decode_token_budget: Option<u32>,
     ) -> Option<NextBatch> {
         // Create response channel
         let (response_sender, response_receiver) = oneshot::channel();
@@ -70,6 +210,7 @@ impl Queue {
                 min_size,
                 prefill_token_budget,
                 token_budget,
+                decode_token_budget,
                 response_sender,
                 span: Span::current(),
             })
@@ -78,33 +219,128 @@ impl Queue {
         // Unwrap is safe here
         response_receiver.await.unwrap()
     }
+
+    /// Priority and number of tokens of the next entry to be batched
+    #[instrument(skip(self))]
+    pub(crate) async fn peek(&self) -> Option<(Priority, u32)> {
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Peek(response_sender))
+            .unwrap();
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Number of entries waiting in the queue
+    #[instrument(skip(self))]
+    pub(crate) async fn len(&self) -> usize {
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Len(response_sender))
+            .unwrap();
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Reject a queued entry according to `policy` to admit a new request of `priority`
+    ///
+    /// Returns whether an entry was rejected
+    #[instrument(skip(self))]
+    pub(crate) async fn shed(&self, policy: SheddingPolicy, priority: Priority) -> bool {
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Shed {
+                policy,
+                priority,
+                response_sender,
+            })
+            .unwrap();
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Load of the queue for a new entry
+    #[instrument(skip(self))]
+    pub(crate) async fn load(&self) -> QueueLoad {
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Unwrap is safe here
+        self.queue_sender
+            .send(QueueCommand::Load(response_sender))
+            .unwrap();
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
 }
 
 // Background task responsible of the queue state
 async fn queue_task(
+    model_id: String,
     requires_padding: bool,
     block_size: u32,
+    prefix_cache: Option<PrefixCache>,
+    max_queue_duration: Option<Duration>,
+    scheduling_policy: SchedulingPolicyKind,
+    priority_aging: Option<Duration>,
+    max_starvation: Option<Duration>,
     receiver: flume::Receiver<QueueCommand>,
 ) {
-    let mut state = State::new(requires_padding, block_size);
+    let mut state = State::new(requires_padding, block_size, prefix_cache)
+        .with_max_queue_duration(max_queue_duration)
+        .with_scheduling_policy(scheduling_policy)
+        .with_priority_aging(priority_aging)
+        .with_max_starvation(max_starvation);
 
     while let Ok(cmd) = receiver.recv_async().await {
         match cmd {
             QueueCommand::Append(entry, span) => {
                 span.in_scope(|| state.append(*entry));
-                metrics::increment_gauge!("tgi_queue_size", 1.0);
+                metrics::increment_gauge!("tgi_queue_size", 1.0, "model" => model_id.clone());
             }
             QueueCommand::NextBatch {
                 min_size,
                 prefill_token_budget,
                 token_budget,
+                decode_token_budget,
                 response_sender,
                 span,
             } => span.in_scope(|| {
-                let next_batch = state.next_batch(min_size, prefill_token_budget, token_budget);
+                let next_batch = state.next_batch(
+                    min_size,
+                    prefill_token_budget,
+                    token_budget,
+                    decode_token_budget,
+                );
                 response_sender.send(next_batch).unwrap();
-                metrics::gauge!("tgi_queue_size", state.entries.len() as f64);
+                state.notify_positions();
+                metrics::gauge!("tgi_queue_size", state.entries.len() as f64, "model" => model_id.clone());
             }),
+            QueueCommand::Len(response_sender) => {
+                let _ = response_sender.send(state.entries.len());
+            }
+            QueueCommand::Load(response_sender) => {
+                let _ = response_sender.send(state.load());
+            }
+            QueueCommand::Shed {
+                policy,
+                priority,
+                response_sender,
+            } => {
+                let _ = response_sender.send(state.shed(policy, priority));
+                metrics::gauge!("tgi_queue_size", state.entries.len() as f64, "model" => model_id.clone());
+            }
+            QueueCommand::Peek(response_sender) => {
+                let next = state.entries.front().map(|entry| {
+                    let request = &entry.request;
+                    (
+                        request.priority,
+                        request.input_length + request.stopping_parameters.max_new_tokens,
+                    )
+                });
+                let _ = response_sender.send(next);
+            }
         }
     }
 }
@@ -112,8 +348,8 @@ async fn queue_task(
 /// Queue State
 #[derive(Debug)]
 struct State {
-    /// Queue entries organized in a Vec
-    entries: VecDeque<(u64, Entry)>,
+    /// Queue entries, in the order of the scheduling policy
+    entries: Box<dyn SchedulingPolicy>,
 
     /// Id of the next entry
     next_id: u64,
@@ -126,19 +362,67 @@ struct State {
 
     /// Paged Attention block size
     block_size: u32,
+
+    /// Prompts already sent to the shards
+    prefix_cache: Option<PrefixCache>,
+
+    /// Maximum time an entry waits in the queue before being rejected
+    max_queue_duration: Option<Duration>,
+
+    /// Time an entry waits in the queue to gain a priority level
+    priority_aging: Option<Duration>,
+
+    /// Time after which an entry is batched before the other entries
+    max_starvation: Option<Duration>,
+
+    /// Average time between two entries leaving the queue while entries are waiting
+    dequeue_interval: Option<Duration>,
+
+    /// Average time between two batches while entries are waiting
+    batch_interval: Option<Duration>,
+
+    /// Instant of the last batch, if entries were left waiting in the queue
+    last_batch_time: Option<Instant>,
 }
 
 impl State {
-    fn new(requires_padding: bool, block_size: u32) -> Self {
+    fn new(requires_padding: bool, block_size: u32, prefix_cache: Option<PrefixCache>) -> Self {
         Self {
-            entries: VecDeque::with_capacity(128),
+            entries: SchedulingPolicyKind::default().build(),
             next_id: 0,
             next_batch_id: 0,
             requires_padding,
             block_size,
+            prefix_cache,
+            max_queue_duration: None,
+            priority_aging: None,
+            max_starvation: None,
+            dequeue_interval: None,
+            batch_interval: None,
+            last_batch_time: None,
         }
     }
 
+    fn with_max_queue_duration(mut self, max_queue_duration: Option<Duration>) -> Self {
+        self.max_queue_duration = max_queue_duration;
+        self
+    }
+
+    fn with_scheduling_policy(mut self, scheduling_policy: SchedulingPolicyKind) -> Self {
+        self.entries = scheduling_policy.build();
+        self
+    }
+
+    fn with_priority_aging(mut self, priority_aging: Option<Duration>) -> Self {
+        self.priority_aging = priority_aging;
+        self
+    }
+
+    fn with_max_starvation(mut self, max_starvation: Option<Duration>) -> Self {
+        self.max_starvation = max_starvation;
+        self
+    }
+
     /// Append an entry to the queue
     fn append(&mut self, mut entry: Entry) {
         // Create a span that will live as long as the entry is in the queue waiting to be batched
@@ -150,13 +434,95 @@ impl State {
         self.next_id += 1;
     }
 
+    /// Send their position in the queue to the clients of the waiting entries
+    fn notify_positions(&mut self) {
+        let positions = self.entries.positions();
+        let dequeue_interval = self.dequeue_interval;
+        for &mut (id, ref mut entry) in self.entries.iter_mut() {
+            let position = positions[&id];
+            let estimated_wait = dequeue_interval.map(|interval| interval * position as u32);
+            entry.notify_position(position, estimated_wait);
+        }
+    }
+
+    /// Update the average time between two entries leaving the queue with a batch of `size`
+    /// entries
+    fn record_dequeue(&mut self, size: u32) {
+        let now = Instant::now();
+        if let Some(last_batch_time) = self.last_batch_time {
+            let batch_interval = now - last_batch_time;
+            self.batch_interval = Some(ewma(self.batch_interval, batch_interval));
+            self.dequeue_interval = Some(ewma(self.dequeue_interval, batch_interval / size));
+        }
+        // The time spent without waiting entries does not say anything about the queue
+        self.last_batch_time = (!self.entries.is_empty()).then_some(now);
+    }
+
+    /// Reject the entry chosen by `policy` to admit a new request of `priority`
+    fn shed(&mut self, policy: SheddingPolicy, priority: Priority) -> bool {
+        let victim = policy.victim(self.entries.iter_mut().map(|entry| &*entry), priority);
+        let Some(victim) = victim else {
+            return false;
+        };
+        let load = self.load();
+        self.entries.retain(&mut |id, entry| {
+            if id == victim {
+                entry.notify_shed(id, load.clone());
+                return false;
+            }
+            true
+        });
+        true
+    }
+
+    /// Load of the queue for a new entry
+    fn load(&self) -> QueueLoad {
+        let queue_size = self.entries.len();
+        QueueLoad {
+            queue_size,
+            average_batch_latency_ms: self.batch_interval.map(|interval| interval.as_millis()),
+            // A new entry leaves the queue after all the waiting entries
+            estimated_wait_ms: self
+                .dequeue_interval
+                .map(|interval| (interval * (queue_size as u32 + 1)).as_millis()),
+        }
+    }
+
     // Get the next batch
     fn next_batch(
         &mut self,
         min_size: Option<usize>,
         prefill_token_budget: u32,
         token_budget: u32,
+        decode_token_budget: Option<u32>,
     ) -> Option<NextBatch> {
+        // Fail fast the entries that cannot start before their deadline and the entries that
+        // waited for longer than the clients are expected to
+        let now = Instant::now();
+        let max_queue_duration = self.max_queue_duration;
+        self.entries.retain(&mut |id, entry| {
+            if entry.expired(now) {
+                entry.notify_timeout(id);
+                return false;
+            }
+            if let Some(max_queue_duration) = max_queue_duration {
+                if entry.queue_expired(now, max_queue_duration) {
+                    entry.notify_queue_timeout(id, max_queue_duration);
+                    return false;
+                }
+            }
+            true
+        });
+
+        // Entries that waited long enough are batched before the newer entries of a higher
+        // priority
+        if let Some(priority_aging) = self.priority_aging {
+            self.entries.age(now, priority_aging);
+        }
+        if let Some(max_starvation) = self.max_starvation {
+            self.entries.promote_starved(now, max_starvation);
+        }
+
         if self.entries.is_empty() {
             return None;
         }
@@ -179,8 +545,12 @@ impl State {
         let mut max_input_length = 0;
         let mut prefill_tokens: u32 = 0;
         let mut decode_tokens: u32 = 0;
+        // Predicted length of the first entry of the batch and entries predicted to finish at
+        // very different times, left for the next batches
+        let mut batch_length: Option<u32> = None;
+        let mut deferred = Vec::new();
 
-        // Pop entries starting from the front of the queue
+        // Pop entries starting from the front of the highest priority queue
         while let Some((id, mut entry)) = self.entries.pop_front() {
             // Filter entries where the response receiver was dropped (== entries where the request
             // was dropped by the client)
@@ -189,6 +559,17 @@ impl State {
                 continue;
             }
 
+            if let Some(length_prediction) = &entry.length_prediction {
+                match batch_length {
+                    None => batch_length = Some(length_prediction.tokens),
+                    Some(tokens) if !length_prediction.similar(tokens) => {
+                        deferred.push((id, entry));
+                        continue;
+                    }
+                    Some(_) => {}
+                }
+            }
+
             if self.requires_padding {
                 // We pad to max input length in the Python shards
                 // We need to take these padding tokens into the equation
@@ -211,9 +592,13 @@ impl State {
                         * self.block_size;
             }
 
-            if prefill_tokens > prefill_token_budget
-                || (prefill_tokens + decode_tokens) > token_budget
-            {
+            let over_budget = match decode_token_budget {
+                Some(decode_token_budget) => {
+                    prefill_tokens > token_budget || decode_tokens > decode_token_budget
+                }
+                None => (prefill_tokens + decode_tokens) > token_budget,
+            };
+            if prefill_tokens > prefill_token_budget || over_budget {
                 // Entry is over budget
                 // Add it back to the front
                 self.entries.push_front((id, entry));
@@ -231,6 +616,16 @@ impl State {
             batch_requests.push(Request {
                 id,
                 prefill_logprobs: entry.request.decoder_input_details,
+                top_n_tokens: entry.request.top_n_tokens,
+                prefix_len: entry
+                    .session
+                    .as_ref()
+                    .map_or(0, |session| session.prefix_len),
+                session_id: entry.request.session_id.clone(),
+                prefill_offset: 0,
+                prefill_chunk_len: None,
+                input_chunks: entry.request.input_chunks.clone(),
+                add_special_tokens: entry.request.add_special_tokens,
                 inputs: entry.request.inputs.clone(),
                 truncate: entry.request.truncate,
                 parameters: Some(entry.request.parameters.clone()),
@@ -238,9 +633,14 @@ impl State {
             });
             // Set batch_time
             entry.batch_time = Some(Instant::now());
+            // The request is not lost anymore if the router restarts
+            entry.journal = None;
             // Insert in batch_entries IntMap
             batch_entries.insert(id, entry);
         }
+        for (id, entry) in deferred.into_iter().rev() {
+            self.entries.push_front((id, entry));
+        }
 
         // Empty batch
         if batch_requests.is_empty() {
@@ -262,9 +662,36 @@ impl State {
             }
         }
 
+        // Only the batches sent to the shards are added to the prefix cache
+        if let Some(prefix_cache) = &mut self.prefix_cache {
+            for request in batch_requests.iter_mut() {
+                let entry = &batch_entries[&request.id];
+                if entry.request.input_ids.is_empty() {
+                    continue;
+                }
+                let prefix_len = prefix_cache.insert(&entry.request.input_ids);
+                // Prefill logprobs need the whole prompt, and at least one token must be
+                // prefilled to get the logits of the first generated token
+                if !entry.request.decoder_input_details {
+                    let prefix_len = prefix_len.min(entry.request.input_ids.len() - 1) as u32;
+                    request.prefix_len = request.prefix_len.max(prefix_len);
+                    metrics::histogram!("tgi_request_prefix_len", request.prefix_len as f64);
+                }
+            }
+        }
+
+        for entry in batch_entries.values() {
+            metrics::histogram!(
+                "tgi_queue_wait_duration",
+                entry.queue_time.elapsed().as_secs_f64(),
+                "priority" => entry.request.priority.as_str()
+            );
+        }
+
         // Final batch size
         let size = batch_requests.len() as u32;
         next_batch_span.record("batch_size", size);
+        self.record_dequeue(size);
 
         let batch = Batch {
             id: self.next_batch_id,
@@ -276,6 +703,7 @@ impl State {
         self.next_batch_id += 1;
 
         metrics::histogram!("tgi_batch_next_size", batch.size as f64);
+        metrics::histogram!("tgi_batch_next_tokens", batch.max_tokens as f64);
 
         Some((batch_entries, batch, next_batch_span))
     }
@@ -283,6 +711,14 @@ impl State {
 
 type NextBatch = (IntMap<u64, Entry>, Batch, Span);
 
+/// Exponentially weighted moving average of the intervals
+fn ewma(average: Option<Duration>, interval: Duration) -> Duration {
+    match average {
+        Some(average) => average.mul_f64(0.8) + interval.mul_f64(0.2),
+        None => interval,
+    }
+}
+
 #[derive(Debug)]
 enum QueueCommand {
     Append(Box<Entry>, Span),
@@ -290,15 +726,29 @@ enum QueueCommand {
         min_size: Option<usize>,
         prefill_token_budget: u32,
         token_budget: u32,
+        decode_token_budget: Option<u32>,
         response_sender: oneshot::Sender<Option<NextBatch>>,
         span: Span,
     },
+    Len(oneshot::Sender<usize>),
+    Load(oneshot::Sender<QueueLoad>),
+    Peek(oneshot::Sender<Option<(Priority, u32)>>),
+    Shed {
+        policy: SheddingPolicy,
+        priority: Priority,
+        response_sender: oneshot::Sender<bool>,
+    },
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use text_generation_client::{NextTokenChooserParameters, StoppingCriteriaParameters};
+    use crate::auth::Tenant;
+    use crate::length_prediction::LengthPredictor;
+    use std::collections::HashMap;
+    use text_generation_client::{
+        GrammarType, NextTokenChooserParameters, StoppingCriteriaParameters,
+    };
     use tracing::info_span;
 
     fn default_entry() -> (
@@ -310,6 +760,9 @@ mod tests {
         let entry = Entry {
             request: ValidGenerateRequest {
                 inputs: "".to_string(),
+                input_ids: vec![],
+                input_chunks: vec![],
+                add_special_tokens: true,
                 input_length: 0,
                 truncate: 0,
                 decoder_input_details: false,
@@ -324,25 +777,59 @@ mod tests {
                     repetition_penalty: 0.0,
                     no_repeat_ngram_size: 0,
                     watermark: false,
+                    logit_bias: HashMap::new(),
+                    grammar: String::new(),
+                    grammar_type: GrammarType::None as i32,
+                    min_p: 0.0,
+                    frequency_penalty: 0.0,
+                    presence_penalty: 0.0,
+                    bad_words_ids: vec![],
+                    epsilon_cutoff: 0.0,
+                    eta_cutoff: 0.0,
+                    mirostat: 0,
+                    mirostat_tau: 0.0,
+                    mirostat_eta: 0.0,
+                    token_healing_prefix: String::new(),
                 },
                 stopping_parameters: StoppingCriteriaParameters {
                     ignore_eos_token: false,
                     max_new_tokens: 1,
                     stop_sequences: vec![],
+                    stop_token_ids: vec![],
                 },
+                top_n_tokens: 0,
+                priority: Priority::Normal,
+                deadline: None,
+                session_id: None,
+                tenant: None,
+                rate_limit: None,
+                choices: vec![],
+                stop_regexes: vec![],
+                queue_events: false,
             },
             response_tx,
             span: info_span!("entry"),
             temp_span: None,
             queue_time: Instant::now(),
             batch_time: None,
+            audit_log: None,
+            session: None,
+            choice: None,
+            stop_regex: None,
+            progress: None,
+            queue_position: None,
+            generated_tokens: 0,
+            priority_boost: 0,
+            journal: None,
+            length_prediction: None,
+            continuations: None,
         };
         (entry, receiver_tx)
     }
 
     #[test]
     fn test_append() {
-        let mut state = State::new(false, 1);
+        let mut state = State::new(false, 1, None);
         let (entry, _guard) = default_entry();
 
         assert_eq!(state.next_id, 0);
@@ -352,27 +839,27 @@ mod tests {
 
         assert_eq!(state.next_id, 1);
         assert_eq!(state.entries.len(), 1);
-        let (id, _) = state.entries.remove(0).unwrap();
+        let (id, _) = state.entries.pop_front().unwrap();
         assert_eq!(id, 0);
     }
 
     #[test]
     fn test_next_batch_empty() {
-        let mut state = State::new(false, 1);
+        let mut state = State::new(false, 1, None);
 
-        assert!(state.next_batch(None, 1, 1).is_none());
-        assert!(state.next_batch(Some(1), 1, 1).is_none());
+        assert!(state.next_batch(None, 1, 1, None).is_none());
+        assert!(state.next_batch(Some(1), 1, 1, None).is_none());
     }
 
     #[test]
     fn test_next_batch_min_size() {
-        let mut state = State::new(false, 1);
+        let mut state = State::new(false, 1, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
         state.append(entry2);
 
-        let (entries, batch, _) = state.next_batch(None, 2, 2).unwrap();
+        let (entries, batch, _) = state.next_batch(None, 2, 2, None).unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries.contains_key(&0));
         assert!(entries.contains_key(&1));
@@ -388,23 +875,23 @@ mod tests {
         let (entry3, _guard3) = default_entry();
         state.append(entry3);
 
-        assert!(state.next_batch(Some(2), 2, 2).is_none());
+        assert!(state.next_batch(Some(2), 2, 2, None).is_none());
 
         assert_eq!(state.next_id, 3);
         assert_eq!(state.entries.len(), 1);
-        let (id, _) = state.entries.remove(0).unwrap();
+        let (id, _) = state.entries.pop_front().unwrap();
         assert_eq!(id, 2);
     }
 
     #[test]
     fn test_next_batch_token_budget() {
-        let mut state = State::new(false, 1);
+        let mut state = State::new(false, 1, None);
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         state.append(entry1);
         state.append(entry2);
 
-        let (entries, batch, _) = state.next_batch(None, 1, 1).unwrap();
+        let (entries, batch, _) = state.next_batch(None, 1, 1, None).unwrap();
         assert_eq!(entries.len(), 1);
         assert!(entries.contains_key(&0));
         assert_eq!(batch.id, 0);
@@ -417,7 +904,7 @@ mod tests {
         let (entry3, _guard3) = default_entry();
         state.append(entry3);
 
-        let (entries, batch, _) = state.next_batch(None, 3, 3).unwrap();
+        let (entries, batch, _) = state.next_batch(None, 3, 3, None).unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries.contains_key(&1));
         assert!(entries.contains_key(&2));
@@ -429,30 +916,424 @@ mod tests {
         assert_eq!(state.next_batch_id, 2);
     }
 
+    #[test]
+    fn test_next_batch_decode_token_budget() {
+        let mut state = State::new(false, 1, None);
+        let (entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        let (entry3, _guard3) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        // The generated tokens do not fit in the combined budget
+        assert!(state.next_batch(None, 1, 0, None).is_none());
+
+        // The prompts fit in the token budget and the generated tokens in the decode budget
+        let (entries, batch, _) = state.next_batch(None, 1, 0, Some(2)).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(batch.size, 2);
+        assert_eq!(state.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_next_batch_priority() {
+        let mut state = State::new(false, 1, None);
+        let (mut entry1, _guard1) = default_entry();
+        entry1.request.priority = Priority::Low;
+        let (entry2, _guard2) = default_entry();
+        let (mut entry3, _guard3) = default_entry();
+        entry3.request.priority = Priority::High;
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        let (entries, batch, _) = state.next_batch(None, 2, 2, None).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains_key(&1));
+        assert!(entries.contains_key(&2));
+        assert_eq!(batch.requests[0].id, 2);
+        assert_eq!(batch.requests[1].id, 1);
+
+        assert_eq!(state.entries.len(), 1);
+        let (id, _) = state.entries.pop_front().unwrap();
+        assert_eq!(id, 0);
+    }
+
+    #[test]
+    fn test_next_batch_priority_aging() {
+        let mut state =
+            State::new(false, 1, None).with_priority_aging(Some(Duration::from_secs(1)));
+        let (mut entry1, _guard1) = default_entry();
+        entry1.request.priority = Priority::Low;
+        entry1.queue_time = Instant::now() - Duration::from_millis(1500);
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.priority = Priority::Low;
+        entry2.queue_time = Instant::now() - Duration::from_secs(5);
+        let (entry3, _guard3) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        // The oldest entry is promoted to high priority, the other one to normal priority
+        let (_, batch, _) = state.next_batch(None, 3, 3, None).unwrap();
+        let ids: Vec<u64> = batch.requests.iter().map(|request| request.id).collect();
+        assert_eq!(ids, vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn test_next_batch_fifo() {
+        let mut state =
+            State::new(false, 1, None).with_scheduling_policy(SchedulingPolicyKind::Fifo);
+        let (entry1, _guard1) = default_entry();
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.priority = Priority::High;
+        state.append(entry1);
+        state.append(entry2);
+
+        let (_, batch, _) = state.next_batch(None, 2, 2, None).unwrap();
+        assert_eq!(batch.requests[0].id, 0);
+        assert_eq!(batch.requests[1].id, 1);
+    }
+
+    #[test]
+    fn test_next_batch_shortest_job_first_starvation() {
+        let mut state = State::new(false, 1, None)
+            .with_scheduling_policy(SchedulingPolicyKind::ShortestJobFirst)
+            .with_max_starvation(Some(Duration::from_secs(1)));
+        let (mut entry1, _guard1) = default_entry();
+        entry1.request.input_length = 5;
+        entry1.queue_time = Instant::now() - Duration::from_secs(2);
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.input_length = 4;
+        entry2.queue_time = Instant::now() - Duration::from_secs(3);
+        let (mut entry3, _guard3) = default_entry();
+        entry3.request.input_length = 1;
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        // The starved entries are batched first, in their order of arrival
+        let (_, batch, _) = state.next_batch(None, 100, 100, None).unwrap();
+        let ids: Vec<u64> = batch.requests.iter().map(|request| request.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_next_batch_length_aware() {
+        let mut state =
+            State::new(false, 1, None).with_scheduling_policy(SchedulingPolicyKind::Fifo);
+        let length_predictor = LengthPredictor::default();
+        let mut guards = Vec::new();
+        for max_new_tokens in [10, 100, 15] {
+            let (mut entry, guard) = default_entry();
+            entry.length_prediction = Some(length_predictor.predict(max_new_tokens));
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The entry predicted to finish much later waits for the next batch
+        let (entries, batch, _) = state.next_batch(None, 100, 100, None).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(batch.requests[0].id, 0);
+        assert_eq!(batch.requests[1].id, 2);
+        let (id, _) = state.entries.pop_front().unwrap();
+        assert_eq!(id, 1);
+    }
+
+    #[test]
+    fn test_next_batch_shortest_job_first() {
+        let mut state = State::new(false, 1, None)
+            .with_scheduling_policy(SchedulingPolicyKind::ShortestJobFirst);
+        let (mut entry1, _guard1) = default_entry();
+        entry1.request.input_length = 3;
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.input_length = 1;
+        let (mut entry3, _guard3) = default_entry();
+        entry3.request.input_length = 3;
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        let positions = state.entries.positions();
+        assert_eq!(positions[&1], 1);
+        assert_eq!(positions[&0], 2);
+        assert_eq!(positions[&2], 3);
+
+        // The entry that does not fit is added back in front
+        let (entries, batch, _) = state.next_batch(None, 3, 6, None).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(batch.requests[0].id, 1);
+        let (id, _) = state.entries.pop_front().unwrap();
+        assert_eq!(id, 0);
+    }
+
+    #[test]
+    fn test_next_batch_tenants() {
+        let mut state = State::new(false, 1, None);
+        let bulk = Tenant::test("bulk", 1);
+        let chat = Tenant::test("chat", 2);
+        let mut guards = Vec::new();
+        for tenant in [&bulk, &bulk, &bulk, &chat, &chat, &chat] {
+            let (mut entry, guard) = default_entry();
+            entry.request.tenant = Some(tenant.clone());
+            state.append(entry);
+            guards.push(guard);
+        }
+
+        // The tenants are served in weighted round-robin
+        let (_, batch, _) = state.next_batch(None, 4, 4, None).unwrap();
+        let ids: Vec<u64> = batch.requests.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![0, 3, 4, 1]);
+
+        // Entries of a batch too small are added back in order
+        assert!(state.next_batch(Some(2), 1, 1, None).is_none());
+        let (_, batch, _) = state.next_batch(None, 2, 2, None).unwrap();
+        let ids: Vec<u64> = batch.requests.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![5, 2]);
+        assert_eq!(state.entries.len(), 0);
+    }
+
+    #[test]
+    fn test_notify_positions() {
+        let mut state = State::new(false, 1, None);
+        let (mut entry1, guard1) = default_entry();
+        entry1.request.queue_events = true;
+        let (mut entry2, guard2) = default_entry();
+        entry2.request.queue_events = true;
+        entry2.request.priority = Priority::High;
+        state.append(entry1);
+        state.append(entry2);
+
+        state.notify_positions();
+        assert!(matches!(
+            guard1.try_recv(),
+            Ok(Ok(InferStreamResponse::Queued { position: 2, .. }))
+        ));
+        assert!(matches!(
+            guard2.try_recv(),
+            Ok(Ok(InferStreamResponse::Queued { position: 1, .. }))
+        ));
+
+        // Positions are only sent when they change
+        state.notify_positions();
+        assert!(guard1.try_recv().is_err());
+
+        state.next_batch(None, 1, 1, None).unwrap();
+        state.notify_positions();
+        assert!(matches!(
+            guard1.try_recv(),
+            Ok(Ok(InferStreamResponse::Queued { position: 1, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_load() {
+        let mut state = State::new(false, 1, None);
+        let (entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        let (entry3, _guard3) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+        let load = state.load();
+        assert_eq!(load.queue_size, 3);
+        assert!(load.average_batch_latency_ms.is_none());
+        assert!(load.estimated_wait_ms.is_none());
+
+        state.next_batch(None, 1, 1, None).unwrap();
+        assert_eq!(state.load().queue_size, 2);
+        assert!(state.load().estimated_wait_ms.is_none());
+
+        // The intervals are known once a second batch left entries waiting
+        state.next_batch(None, 1, 1, None).unwrap();
+        let load = state.load();
+        assert_eq!(load.queue_size, 1);
+        assert!(load.average_batch_latency_ms.is_some());
+        assert!(load.estimated_wait_ms.is_some());
+    }
+
+    #[test]
+    fn test_next_batch_expired() {
+        let mut state = State::new(false, 1, None);
+        let (mut entry1, guard1) = default_entry();
+        entry1.request.deadline = Some(Instant::now());
+        let (entry2, _guard2) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+
+        let (entries, _, _) = state.next_batch(None, 2, 2, None).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&1));
+        assert!(matches!(guard1.try_recv(), Ok(Err(InferError::Timeout))));
+    }
+
+    #[test]
+    fn test_entry_charged_when_dropped() {
+        let tenant = Tenant::test("acme", 1);
+        let (mut entry, _guard) = default_entry();
+        entry.request.input_length = 10;
+        entry.request.tenant = Some(tenant.clone());
+        drop(entry);
+        // The requests removed before their first prefill are not charged
+        assert_eq!(tenant.usage().requests, 0);
+
+        // A cancelled request is charged for the tokens generated before its cancellation
+        let (mut entry, _guard) = default_entry();
+        entry.request.input_length = 10;
+        entry.request.tenant = Some(tenant.clone());
+        entry.batch_time = Some(Instant::now());
+        entry.generated_tokens = 3;
+        drop(entry);
+        let usage = tenant.usage();
+        assert_eq!(usage.requests, 1);
+        assert_eq!(usage.prompt_tokens, 10);
+        assert_eq!(usage.generated_tokens, 3);
+    }
+
+    #[test]
+    fn test_next_batch_queue_expired() {
+        let mut state =
+            State::new(false, 1, None).with_max_queue_duration(Some(Duration::from_millis(100)));
+        let (mut entry1, guard1) = default_entry();
+        entry1.queue_time = Instant::now() - Duration::from_millis(100);
+        let (entry2, _guard2) = default_entry();
+        state.append(entry1);
+        state.append(entry2);
+
+        let (entries, _, _) = state.next_batch(None, 2, 2, None).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(entries.contains_key(&1));
+        assert!(matches!(
+            guard1.try_recv(),
+            Ok(Err(InferError::QueueTimeout(_)))
+        ));
+    }
+
+    #[test]
+    fn test_shed() {
+        let mut state = State::new(false, 1, None);
+        let (mut entry1, guard1) = default_entry();
+        entry1.request.priority = Priority::Low;
+        let (entry2, _guard2) = default_entry();
+        let (mut entry3, guard3) = default_entry();
+        entry3.request.priority = Priority::Low;
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        assert!(!state.shed(SheddingPolicy::RejectNewest, Priority::High));
+        // Only the entries of a lower priority are rejected, the newest first
+        assert!(!state.shed(SheddingPolicy::RejectLowestPriority, Priority::Low));
+        assert!(state.shed(SheddingPolicy::RejectLowestPriority, Priority::Normal));
+        assert_eq!(state.entries.len(), 2);
+        assert!(matches!(
+            guard3.try_recv(),
+            Ok(Err(InferError::Overloaded(_)))
+        ));
+
+        assert!(state.shed(SheddingPolicy::RejectOldest, Priority::Low));
+        assert_eq!(state.entries.len(), 1);
+        assert!(matches!(
+            guard1.try_recv(),
+            Ok(Err(InferError::Overloaded(_)))
+        ));
+    }
+
+    #[test]
+    fn test_next_batch_prefix_cache() {
+        let mut state = State::new(false, 1, Some(PrefixCache::new(100)));
+        let (mut entry1, _guard1) = default_entry();
+        entry1.request.input_ids = vec![1, 2, 3];
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.input_ids = vec![1, 2, 3];
+        let (mut entry3, _guard3) = default_entry();
+        entry3.request.input_ids = vec![1, 2, 4];
+        state.append(entry1);
+        state.append(entry2);
+        state.append(entry3);
+
+        let (_, batch, _) = state.next_batch(None, 3, 3, None).unwrap();
+        let prefix_lens: Vec<u32> = batch.requests.iter().map(|r| r.prefix_len).collect();
+        // At least one token is always prefilled
+        assert_eq!(prefix_lens, vec![0, 2, 2]);
+    }
+
     #[tokio::test]
     async fn test_queue_append() {
-        let queue = Queue::new(false, 1);
+        let queue = Queue::new(
+            "model".to_string(),
+            false,
+            1,
+            None,
+            None,
+            SchedulingPolicyKind::FairShare,
+            None,
+            None,
+        );
         let (entry, _guard) = default_entry();
         queue.append(entry);
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_empty() {
-        let queue = Queue::new(false, 1);
+        let queue = Queue::new(
+            "model".to_string(),
+            false,
+            1,
+            None,
+            None,
+            SchedulingPolicyKind::FairShare,
+            None,
+            None,
+        );
+
+        assert!(queue.next_batch(None, 1, 1, None).await.is_none());
+        assert!(queue.next_batch(Some(1), 1, 1, None).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_queue_len() {
+        let queue = Queue::new(
+            "model".to_string(),
+            false,
+            1,
+            None,
+            None,
+            SchedulingPolicyKind::FairShare,
+            None,
+            None,
+        );
+        assert_eq!(queue.len().await, 0);
+
+        let (entry1, _guard1) = default_entry();
+        let (entry2, _guard2) = default_entry();
+        queue.append(entry1);
+        queue.append(entry2);
+        assert_eq!(queue.len().await, 2);
 
-        assert!(queue.next_batch(None, 1, 1).await.is_none());
-        assert!(queue.next_batch(Some(1), 1, 1).await.is_none());
+        queue.next_batch(None, 1, 1, None).await.unwrap();
+        assert_eq!(queue.len().await, 1);
     }
 
     #[tokio::test]
     async fn test_queue_next_batch_min_size() {
-        let queue = Queue::new(false, 1);
+        let queue = Queue::new(
+            "model".to_string(),
+            false,
+            1,
+            None,
+            None,
+            SchedulingPolicyKind::FairShare,
+            None,
+            None,
+        );
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
         queue.append(entry2);
 
-        let (entries, batch, _) = queue.next_batch(None, 2, 2).await.unwrap();
+        let (entries, batch, _) = queue.next_batch(None, 2, 2, None).await.unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries.contains_key(&0));
         assert!(entries.contains_key(&1));
@@ -465,11 +1346,11 @@ mod tests {
         queue.append(entry3);
 
         // Not enough requests pending
-        assert!(queue.next_batch(Some(2), 2, 2).await.is_none());
+        assert!(queue.next_batch(Some(2), 2, 2, None).await.is_none());
         // Not enough token budget
-        assert!(queue.next_batch(Some(1), 0, 0).await.is_none());
+        assert!(queue.next_batch(Some(1), 0, 0, None).await.is_none());
         // Ok
-        let (entries2, batch2, _) = queue.next_batch(Some(1), 2, 2).await.unwrap();
+        let (entries2, batch2, _) = queue.next_batch(Some(1), 2, 2, None).await.unwrap();
         assert_eq!(entries2.len(), 1);
         assert!(entries2.contains_key(&2));
         assert!(entries2.get(&2).unwrap().batch_time.is_some());
@@ -479,13 +1360,22 @@ mod tests {
 
     #[tokio::test]
     async fn test_queue_next_batch_token_budget() {
-        let queue = Queue::new(false, 1);
+        let queue = Queue::new(
+            "model".to_string(),
+            false,
+            1,
+            None,
+            None,
+            SchedulingPolicyKind::FairShare,
+            None,
+            None,
+        );
         let (entry1, _guard1) = default_entry();
         let (entry2, _guard2) = default_entry();
         queue.append(entry1);
         queue.append(entry2);
 
-        let (entries, batch, _) = queue.next_batch(None, 1, 1).await.unwrap();
+        let (entries, batch, _) = queue.next_batch(None, 1, 1, None).await.unwrap();
         assert_eq!(entries.len(), 1);
         assert!(entries.contains_key(&0));
         assert_eq!(batch.id, 0);
@@ -494,7 +1384,7 @@ mod tests {
         let (entry3, _guard3) = default_entry();
         queue.append(entry3);
 
-        let (entries, batch, _) = queue.next_batch(None, 3, 3).await.unwrap();
+        let (entries, batch, _) = queue.next_batch(None, 3, 3, None).await.unwrap();
         assert_eq!(entries.len(), 2);
         assert!(entries.contains_key(&1));
         assert!(entries.contains_key(&2));
@@ -502,12 +1392,44 @@ mod tests {
         assert_eq!(batch.size, 2);
     }
 
+    #[tokio::test]
+    async fn test_queue_peek() {
+        let queue = Queue::new(
+            "model".to_string(),
+            false,
+            1,
+            None,
+            None,
+            SchedulingPolicyKind::FairShare,
+            None,
+            None,
+        );
+        assert!(queue.peek().await.is_none());
+
+        let (entry1, _guard1) = default_entry();
+        let (mut entry2, _guard2) = default_entry();
+        entry2.request.priority = Priority::High;
+        entry2.request.input_length = 3;
+        queue.append(entry1);
+        queue.append(entry2);
+        assert_eq!(queue.peek().await, Some((Priority::High, 4)));
+    }
+
     #[tokio::test]
     async fn test_queue_next_batch_dropped_receiver() {
-        let queue = Queue::new(false, 1);
+        let queue = Queue::new(
+            "model".to_string(),
+            false,
+            1,
+            None,
+            None,
+            SchedulingPolicyKind::FairShare,
+            None,
+            None,
+        );
         let (entry, _) = default_entry();
         queue.append(entry);
 
-        assert!(queue.next_batch(None, 1, 1).await.is_none());
+        assert!(queue.next_batch(None, 1, 1, None).await.is_none());
     }
 }
@@ -0,0 +1,225 @@
+/// Generated tokens rate limiting per API key or client IP
+use crate::auth::Tenant;
+use crate::ErrorResponse;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+/// Number of buckets above which the full buckets are removed
+const MAX_IDLE_BUCKETS: usize = 10000;
+
+/// Token bucket refilled continuously up to its capacity
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    /// Tokens added per second
+    refill_rate: f64,
+    /// Available tokens, negative when the last requests generated more tokens than available
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(tokens_per_minute: u32) -> Self {
+        Self {
+            capacity: tokens_per_minute as f64,
+            refill_rate: tokens_per_minute as f64 / 60.0,
+            tokens: tokens_per_minute as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_rate).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Seconds until the bucket is full
+    fn reset(&self) -> u64 {
+        ((self.capacity - self.tokens) / self.refill_rate).ceil() as u64
+    }
+
+    /// Seconds until at least one token is available
+    fn retry_after(&self) -> u64 {
+        ((1.0 - self.tokens) / self.refill_rate).ceil().max(1.0) as u64
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+/// Bucket the generated tokens of a request are taken from
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimit(Arc<Mutex<TokenBucket>>);
+
+impl RateLimit {
+    /// Take the generated tokens of a request from the bucket
+    pub(crate) fn record_tokens(&self, tokens: u32) {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.tokens -= tokens as f64;
+    }
+
+    /// Whether at least one token is available in the bucket
+    pub(crate) fn has_tokens(&self) -> bool {
+        let mut bucket = self.0.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.tokens >= 1.0
+    }
+}
+
+/// Limit the number of tokens generated per minute for each API key, or for each client IP
+/// when API keys are disabled
+#[derive(Clone, Debug)]
+pub(crate) struct RateLimiter {
+    tokens_per_minute: u32,
+    buckets: Arc<Mutex<HashMap<String, RateLimit>>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(tokens_per_minute: u32) -> Self {
+        Self {
+            tokens_per_minute,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Bucket of the requests of `tenant`, or of `client_ip` when API keys are disabled
+    pub(crate) fn bucket_for(&self, tenant: Option<&Tenant>, client_ip: &str) -> RateLimit {
+        match tenant {
+            Some(tenant) => self.bucket(format!("tenant:{}", tenant.name())),
+            None => self.bucket(format!("ip:{client_ip}")),
+        }
+    }
+
+    fn bucket(&self, key: String) -> RateLimit {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_IDLE_BUCKETS && !buckets.contains_key(&key) {
+            // Full buckets are equivalent to new buckets
+            let now = Instant::now();
+            buckets.retain(|_, bucket| {
+                let mut bucket = bucket.0.lock().unwrap();
+                bucket.refill(now);
+                !bucket.is_full()
+            });
+        }
+        buckets
+            .entry(key)
+            .or_insert_with(|| {
+                RateLimit(Arc::new(Mutex::new(TokenBucket::new(
+                    self.tokens_per_minute,
+                ))))
+            })
+            .clone()
+    }
+}
+
+/// Reject the request if its bucket is empty and add the rate limit headers to the response
+///
+/// The `RateLimit` of the request is added to the request extensions
+pub(crate) async fn rate_limit<B>(
+    State(rate_limiter): State<RateLimiter>,
+    mut request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let rate_limit =
+        rate_limiter.bucket_for(request.extensions().get::<Tenant>(), &client_ip(&request));
+
+    let (remaining, reset, retry_after) = {
+        let mut bucket = rate_limit.0.lock().unwrap();
+        bucket.refill(Instant::now());
+        (bucket.tokens, bucket.reset(), bucket.retry_after())
+    };
+
+    let mut response = if remaining < 1.0 {
+        metrics::increment_counter!("tgi_request_failure", "err" => "rate_limited");
+        let mut response = (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(ErrorResponse {
+                error: "Generated tokens rate limit exceeded".to_string(),
+                error_type: "rate_limited".to_string(),
+                load: None,
+                policy_violation: None,
+            }),
+        )
+            .into_response();
+        response
+            .headers_mut()
+            .insert("retry-after", HeaderValue::from(retry_after));
+        response
+    } else {
+        request.extensions_mut().insert(rate_limit);
+        next.run(request).await
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(
+        "x-ratelimit-limit",
+        HeaderValue::from(rate_limiter.tokens_per_minute),
+    );
+    headers.insert(
+        "x-ratelimit-remaining",
+        HeaderValue::from(remaining.max(0.0) as u64),
+    );
+    headers.insert("x-ratelimit-reset", HeaderValue::from(reset));
+    response
+}
+
+/// IP of the client
+///
+/// The `X-Forwarded-For` header is only used when the peer address is unknown, as with ngrok
+pub(crate) fn client_ip<B>(request: &Request<B>) -> String {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .or_else(|| forwarded_ip(request.headers()))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn forwarded_ip(headers: &HeaderMap) -> Option<String> {
+    let forwarded = headers.get("x-forwarded-for")?.to_str().ok()?;
+    let ip = forwarded.split(',').next()?.trim();
+    (!ip.is_empty()).then(|| ip.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(60);
+        let start = bucket.last_refill;
+        bucket.tokens -= 90.0;
+        assert_eq!(bucket.retry_after(), 31);
+        assert_eq!(bucket.reset(), 90);
+
+        // One token per second
+        bucket.refill(start + Duration::from_secs(30));
+        assert_eq!(bucket.tokens, 0.0);
+        bucket.refill(start + Duration::from_secs(1000));
+        assert!(bucket.is_full());
+        assert_eq!(bucket.reset(), 0);
+    }
+
+    #[test]
+    fn test_forwarded_ip() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(forwarded_ip(&headers), None);
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("10.0.0.1, 10.0.0.2"),
+        );
+        assert_eq!(forwarded_ip(&headers), Some("10.0.0.1".to_string()));
+    }
+}
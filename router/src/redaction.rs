@@ -0,0 +1,275 @@
+/// Redaction of the personally identifiable information of the prompts and outputs
+use crate::infer::{InferError, InferStreamResponse};
+use crate::Token;
+use clap::ValueEnum;
+use regex::{Captures, Regex};
+use std::borrow::Cow;
+use std::sync::Arc;
+
+type ResponseReceiver = flume::Receiver<Result<InferStreamResponse, InferError>>;
+
+/// Texts whose personally identifiable information is redacted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum RedactionDirection {
+    /// Prompts, before they are filtered and tokenized
+    Prompts,
+    /// Generated texts, before they are sent to the clients
+    Outputs,
+    Both,
+}
+
+/// Masks the emails, phone numbers and credit card numbers of the prompts and outputs
+///
+/// Matches are replaced by `[EMAIL]`, `[PHONE]` and `[CREDIT_CARD]`. Credit card numbers must
+/// pass the Luhn checksum so that other long numbers are kept.
+#[derive(Debug)]
+pub(crate) struct PiiRedactor {
+    direction: RedactionDirection,
+    email: Regex,
+    phone: Regex,
+    credit_card: Regex,
+}
+
+impl PiiRedactor {
+    pub(crate) fn new(direction: RedactionDirection) -> Self {
+        Self {
+            direction,
+            email: Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}").unwrap(),
+            phone: Regex::new(
+                r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{2,4}\)[ .-]?|\b\d{2,4}[ .-]?)\d{3,4}[ .-]?\d{4}\b",
+            )
+            .unwrap(),
+            credit_card: Regex::new(r"\b(?:\d[ -]?){12,18}\d\b").unwrap(),
+        }
+    }
+
+    pub(crate) fn redacts_prompts(&self) -> bool {
+        self.direction != RedactionDirection::Outputs
+    }
+
+    pub(crate) fn redacts_outputs(&self) -> bool {
+        self.direction != RedactionDirection::Prompts
+    }
+
+    /// Mask the personally identifiable information of a `text` of `direction`
+    pub(crate) fn redact<'a>(&self, text: &'a str, direction: &'static str) -> Cow<'a, str> {
+        let (text, redactions) = self.mask(text);
+        for (kind, redacted) in redactions {
+            metrics::counter!("tgi_pii_redacted", redacted, "kind" => kind, "direction" => direction);
+        }
+        text
+    }
+
+    /// Mask the personally identifiable information of `text`, returns the number of matches of
+    /// each kind
+    fn mask<'a>(&self, text: &'a str) -> (Cow<'a, str>, Vec<(&'static str, u64)>) {
+        let mut text = Cow::Borrowed(text);
+        let mut redactions = Vec::new();
+        let kinds = [
+            ("credit_card", &self.credit_card, "[CREDIT_CARD]"),
+            ("email", &self.email, "[EMAIL]"),
+            ("phone", &self.phone, "[PHONE]"),
+        ];
+        for (kind, regex, mask) in kinds {
+            let mut redacted: u64 = 0;
+            let replaced = regex.replace_all(&text, |captures: &Captures| {
+                let matched = &captures[0];
+                if kind == "credit_card" && !luhn(matched) {
+                    return matched.to_string();
+                }
+                redacted += 1;
+                mask.to_string()
+            });
+            if redacted > 0 {
+                redactions.push((kind, redacted));
+                text = Cow::Owned(replaced.into_owned());
+            }
+        }
+        (text, redactions)
+    }
+
+    /// Forward the responses of `response_rx` with their personally identifiable information
+    /// redacted
+    ///
+    /// Personally identifiable information can span several tokens: the tokens are held back until
+    /// a whitespace ends a word, which cannot be followed by the rest of a phone or credit card
+    /// number. When the held back text is redacted, its first token carries the redacted text and
+    /// the others are emptied, so that the number of tokens is unchanged.
+    pub(crate) fn forward(self: &Arc<Self>, response_rx: ResponseReceiver) -> ResponseReceiver {
+        let redactor = self.clone();
+        let (response_tx, redacted_rx) = flume::unbounded();
+        tokio::spawn(async move {
+            let mut held: Vec<(Token, Vec<Token>)> = Vec::new();
+            while let Ok(response) = response_rx.recv_async().await {
+                let responses = match response {
+                    Ok(InferStreamResponse::Intermediate { token, top_tokens }) => {
+                        held.push((token, top_tokens));
+                        let released = released_tokens(&held);
+                        let mut released: Vec<_> = held.drain(..released).collect();
+                        redactor.redact_tokens(&mut released);
+                        released
+                            .into_iter()
+                            .map(|(token, top_tokens)| {
+                                Ok(InferStreamResponse::Intermediate { token, top_tokens })
+                            })
+                            .collect()
+                    }
+                    Ok(InferStreamResponse::End {
+                        token,
+                        top_tokens,
+                        mut generated_text,
+                        start,
+                        queued,
+                        continuation,
+                    }) => {
+                        held.push((token, top_tokens));
+                        redactor.redact_tokens(&mut held);
+                        let (token, top_tokens) = held.pop().unwrap();
+                        generated_text.text =
+                            redactor.redact(&generated_text.text, "output").into_owned();
+                        let mut responses: Vec<_> = held
+                            .drain(..)
+                            .map(|(token, top_tokens)| {
+                                Ok(InferStreamResponse::Intermediate { token, top_tokens })
+                            })
+                            .collect();
+                        responses.push(Ok(InferStreamResponse::End {
+                            token,
+                            top_tokens,
+                            generated_text,
+                            start,
+                            queued,
+                            continuation,
+                        }));
+                        responses
+                    }
+                    response => vec![response],
+                };
+                for response in responses {
+                    if response_tx.send(response).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        redacted_rx
+    }
+
+    /// Redact the concatenated text of `tokens`
+    ///
+    /// The redactions are counted on the generated text at the end of the generation
+    fn redact_tokens(&self, tokens: &mut [(Token, Vec<Token>)]) {
+        let text: String = tokens
+            .iter()
+            .map(|(token, _)| token.text.as_str())
+            .collect();
+        if let (Cow::Owned(redacted), _) = self.mask(&text) {
+            for (i, (token, _)) in tokens.iter_mut().enumerate() {
+                token.text = if i == 0 {
+                    redacted.clone()
+                } else {
+                    String::new()
+                };
+            }
+        }
+    }
+}
+
+/// Number of the first `held` tokens that can be released
+///
+/// Tokens are released up to the last whitespace following a word, a whitespace following a digit
+/// or a separator could be inside a phone or credit card number
+fn released_tokens(held: &[(Token, Vec<Token>)]) -> usize {
+    let mut released = 0;
+    let mut previous = None;
+    for (i, (token, _)) in held.iter().enumerate() {
+        for c in token.text.chars() {
+            let ends_word = previous.map_or(false, |previous: char| {
+                !previous.is_ascii_digit() && !matches!(previous, '+' | '-' | '(' | ')')
+            });
+            if c.is_whitespace() && ends_word {
+                // The whitespace can start the next token
+                released = i;
+            }
+            previous = Some(c);
+        }
+    }
+    released
+}
+
+/// Whether the digits of `number` pass the Luhn checksum
+fn luhn(number: &str) -> bool {
+    let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &digit)| match (i % 2, digit * 2) {
+            (0, _) => digit,
+            (_, doubled) if doubled > 9 => doubled - 9,
+            (_, doubled) => doubled,
+        })
+        .sum();
+    sum % 10 == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(text: &str) -> Token {
+        Token {
+            id: 1,
+            text: text.to_string(),
+            logprob: 0.0,
+            special: false,
+        }
+    }
+
+    #[test]
+    fn test_redact() {
+        let redactor = PiiRedactor::new(RedactionDirection::Both);
+        assert_eq!(
+            redactor.redact(
+                "Mail jane.doe@example.com or call +1 (555) 123-4567, card 4111 1111 1111 1111",
+                "prompt"
+            ),
+            "Mail [EMAIL] or call [PHONE], card [CREDIT_CARD]"
+        );
+        // Not a valid credit card number
+        assert_eq!(
+            redactor.redact("Order 1234567812345678", "prompt"),
+            "Order 1234567812345678"
+        );
+        assert!(matches!(
+            redactor.redact("Nothing to hide", "prompt"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_redact_stream() {
+        let redactor = Arc::new(PiiRedactor::new(RedactionDirection::Outputs));
+        let (response_tx, response_rx) = flume::unbounded();
+        let redacted_rx = redactor.forward(response_rx);
+        for text in ["Call", " 555", "-123", "-4567", " now", " please"] {
+            response_tx
+                .send(Ok(InferStreamResponse::Intermediate {
+                    token: token(text),
+                    top_tokens: vec![],
+                }))
+                .unwrap();
+        }
+
+        let mut texts = Vec::new();
+        for _ in 0..5 {
+            match redacted_rx.recv_async().await.unwrap() {
+                Ok(InferStreamResponse::Intermediate { token, .. }) => texts.push(token.text),
+                _ => panic!("Unexpected response"),
+            }
+        }
+        assert_eq!(texts, vec!["Call", " [PHONE] now", "", "", ""]);
+        // " please" is held back until a following word or the end of the generation
+        assert!(redacted_rx.try_recv().is_err());
+    }
+}
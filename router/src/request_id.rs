@@ -0,0 +1,38 @@
+/// Request ids echoed in the responses and the logs
+use axum::http::{HeaderValue, Request};
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::Instrument;
+
+/// Header of the request id, set by the client or generated by the router
+const REQUEST_ID: &str = "x-request-id";
+
+/// Maximum length in bytes of a request id set by the client
+const MAX_REQUEST_ID_LENGTH: usize = 256;
+
+/// Id of a request, available in its extensions
+#[derive(Clone, Debug)]
+pub(crate) struct RequestId(pub String);
+
+/// Keep the `X-Request-Id` of the request or generate one, record it in the logs of the request
+/// and echo it in the response
+pub(crate) async fn request_id<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| !value.is_empty() && value.len() <= MAX_REQUEST_ID_LENGTH)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()));
+    request
+        .extensions_mut()
+        .insert(RequestId(request_id.clone()));
+
+    let span = tracing::info_span!("request", request_id = %request_id);
+    let mut response = next.run(request).instrument(span).await;
+    // Visible ASCII ids are valid header values
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
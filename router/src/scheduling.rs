@@ -0,0 +1,490 @@
+/// Policies deciding the order in which the queued entries are batched
+use crate::auth::Tenant;
+use crate::queue::Entry;
+use crate::Priority;
+use clap::ValueEnum;
+use nohash_hasher::IntMap;
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Scheduling policy of the queue, selected by the operator
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SchedulingPolicyKind {
+    /// Entries are batched in their order of arrival
+    Fifo,
+    /// Entries of a higher priority are batched first, in their order of arrival
+    Priority,
+    /// Entries with the fewest prompt and new tokens are batched first
+    ShortestJobFirst,
+    /// Entries of a higher priority are batched first, in weighted round-robin across tenants
+    #[default]
+    FairShare,
+}
+
+impl SchedulingPolicyKind {
+    pub(crate) fn build(self) -> Box<dyn SchedulingPolicy> {
+        match self {
+            Self::Fifo => Box::<Fifo>::default(),
+            Self::Priority => Box::<PriorityFifo>::default(),
+            Self::ShortestJobFirst => Box::<ShortestJobFirst>::default(),
+            Self::FairShare => Box::<FairShare>::default(),
+        }
+    }
+}
+
+/// Queued entries, in the order they are batched
+///
+/// `pop_front` removes the next entry to be batched and `push_front` adds back the last entry it
+/// removed when it does not fit in the batch.
+pub(crate) trait SchedulingPolicy: std::fmt::Debug + Send {
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add a new entry
+    fn push_back(&mut self, entry: (u64, Entry));
+
+    /// Add back the last entry removed with `pop_front`
+    fn push_front(&mut self, entry: (u64, Entry));
+
+    /// Next entry to be batched
+    fn front(&self) -> Option<&Entry>;
+
+    /// Remove the next entry to be batched
+    fn pop_front(&mut self) -> Option<(u64, Entry)>;
+
+    /// Position of the entries in the queue, from 1 for the next entry to be batched
+    fn positions(&self) -> IntMap<u64, usize>;
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = &mut (u64, Entry)> + '_>;
+
+    /// Retain only the entries specified by the predicate
+    fn retain(&mut self, f: &mut dyn FnMut(u64, &Entry) -> bool);
+
+    /// Raise the priority of the entries by one level per `interval` waited in the queue
+    fn age(&mut self, _now: Instant, _interval: Duration) {}
+
+    /// Batch the entries that waited for `max_starvation` in the queue before the other entries
+    fn promote_starved(&mut self, _now: Instant, _max_starvation: Duration) {}
+}
+
+/// Priority level of the queue of an entry
+fn level(entry: &Entry) -> usize {
+    entry.request.priority as usize + entry.priority_boost
+}
+
+/// Priority level of an entry that waited in the queue until `now`, one level higher per
+/// `interval`
+fn aged_level(entry: &Entry, now: Instant, interval: Duration) -> usize {
+    let waited = now.saturating_duration_since(entry.queue_time);
+    let boost = (waited.as_nanos() / interval.as_nanos().max(1)) as usize;
+    (entry.request.priority as usize + boost).min(Priority::LEVELS - 1)
+}
+
+/// Move `entry` to the priority level `aged_level`
+fn promote(entry: &mut Entry, aged_level: usize) {
+    entry.priority_boost = aged_level - entry.request.priority as usize;
+    metrics::increment_counter!("tgi_request_priority_aged", "priority" => entry.request.priority.as_str());
+}
+
+/// Insert `entry` in `entries` sorted by ID, which is their order of arrival
+fn insert_by_id(entries: &mut VecDeque<(u64, Entry)>, entry: (u64, Entry)) {
+    let index = entries.partition_point(|(id, _)| *id < entry.0);
+    entries.insert(index, entry);
+}
+
+/// Positions of the entries of `ids`, after `ahead` entries
+fn positions_after(ahead: usize, ids: impl Iterator<Item = u64>) -> Vec<(u64, usize)> {
+    ids.enumerate()
+        .map(|(index, id)| (id, ahead + index + 1))
+        .collect()
+}
+
+/// Entries batched in their order of arrival
+#[derive(Debug, Default)]
+pub(crate) struct Fifo {
+    entries: VecDeque<(u64, Entry)>,
+}
+
+impl SchedulingPolicy for Fifo {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn push_back(&mut self, entry: (u64, Entry)) {
+        self.entries.push_back(entry);
+    }
+
+    fn push_front(&mut self, entry: (u64, Entry)) {
+        self.entries.push_front(entry);
+    }
+
+    fn front(&self) -> Option<&Entry> {
+        self.entries.front().map(|(_, entry)| entry)
+    }
+
+    fn pop_front(&mut self) -> Option<(u64, Entry)> {
+        self.entries.pop_front()
+    }
+
+    fn positions(&self) -> IntMap<u64, usize> {
+        positions_after(0, self.entries.iter().map(|(id, _)| *id))
+            .into_iter()
+            .collect()
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = &mut (u64, Entry)> + '_> {
+        Box::new(self.entries.iter_mut())
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(u64, &Entry) -> bool) {
+        self.entries.retain(|(id, entry)| f(*id, entry));
+    }
+}
+
+/// Entries of a higher priority batched first, entries of the same priority in their order of
+/// arrival
+#[derive(Debug, Default)]
+pub(crate) struct PriorityFifo {
+    /// One queue per priority level, indexed by `Priority as usize`
+    levels: [VecDeque<(u64, Entry)>; Priority::LEVELS],
+}
+
+impl SchedulingPolicy for PriorityFifo {
+    fn len(&self) -> usize {
+        self.levels.iter().map(VecDeque::len).sum()
+    }
+
+    fn push_back(&mut self, entry: (u64, Entry)) {
+        self.levels[level(&entry.1)].push_back(entry);
+    }
+
+    fn push_front(&mut self, entry: (u64, Entry)) {
+        self.levels[level(&entry.1)].push_front(entry);
+    }
+
+    fn front(&self) -> Option<&Entry> {
+        self.levels
+            .iter()
+            .rev()
+            .find_map(|level| level.front().map(|(_, entry)| entry))
+    }
+
+    fn pop_front(&mut self) -> Option<(u64, Entry)> {
+        self.levels.iter_mut().rev().find_map(VecDeque::pop_front)
+    }
+
+    fn positions(&self) -> IntMap<u64, usize> {
+        let mut positions = IntMap::with_capacity_and_hasher(self.len(), Default::default());
+        for level in self.levels.iter().rev() {
+            let ahead = positions.len();
+            positions.extend(positions_after(ahead, level.iter().map(|(id, _)| *id)));
+        }
+        positions
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = &mut (u64, Entry)> + '_> {
+        Box::new(self.levels.iter_mut().flat_map(|level| level.iter_mut()))
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(u64, &Entry) -> bool) {
+        for level in self.levels.iter_mut() {
+            level.retain(|(id, entry)| f(*id, entry));
+        }
+    }
+
+    fn age(&mut self, now: Instant, interval: Duration) {
+        for index in 0..Priority::LEVELS - 1 {
+            for (id, mut entry) in std::mem::take(&mut self.levels[index]) {
+                let aged_level = aged_level(&entry, now, interval);
+                if aged_level > index {
+                    promote(&mut entry, aged_level);
+                    insert_by_id(&mut self.levels[aged_level], (id, entry));
+                } else {
+                    self.levels[index].push_back((id, entry));
+                }
+            }
+        }
+    }
+}
+
+/// Entries with the fewest prompt and new tokens batched first, entries of the same size in their
+/// order of arrival
+///
+/// Starved entries are batched first, in their order of arrival, so that long entries are not
+/// postponed forever under a sustained load of short ones.
+#[derive(Debug, Default)]
+pub(crate) struct ShortestJobFirst {
+    /// Entries sorted by size
+    entries: VecDeque<(u64, Entry)>,
+}
+
+impl ShortestJobFirst {
+    /// Sort key of an entry: starved entries first, then its number of tokens, then its ID
+    fn key((id, entry): &(u64, Entry)) -> (bool, u32, u64) {
+        let request = &entry.request;
+        let starved = entry.priority_boost > 0;
+        let tokens = match starved {
+            true => 0,
+            false => request.input_length + request.stopping_parameters.max_new_tokens,
+        };
+        (!starved, tokens, *id)
+    }
+}
+
+impl SchedulingPolicy for ShortestJobFirst {
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn push_back(&mut self, entry: (u64, Entry)) {
+        let key = Self::key(&entry);
+        let index = self.entries.partition_point(|other| Self::key(other) < key);
+        self.entries.insert(index, entry);
+    }
+
+    fn push_front(&mut self, entry: (u64, Entry)) {
+        self.entries.push_front(entry);
+    }
+
+    fn front(&self) -> Option<&Entry> {
+        self.entries.front().map(|(_, entry)| entry)
+    }
+
+    fn pop_front(&mut self) -> Option<(u64, Entry)> {
+        self.entries.pop_front()
+    }
+
+    fn positions(&self) -> IntMap<u64, usize> {
+        positions_after(0, self.entries.iter().map(|(id, _)| *id))
+            .into_iter()
+            .collect()
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = &mut (u64, Entry)> + '_> {
+        Box::new(self.entries.iter_mut())
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(u64, &Entry) -> bool) {
+        self.entries.retain(|(id, entry)| f(*id, entry));
+    }
+
+    fn promote_starved(&mut self, now: Instant, max_starvation: Duration) {
+        let mut promoted = false;
+        for (_, entry) in self.entries.iter_mut() {
+            let waited = now.saturating_duration_since(entry.queue_time);
+            if entry.priority_boost == 0 && waited >= max_starvation {
+                // Starved entries are moved one level above the others
+                entry.priority_boost = 1;
+                promoted = true;
+                metrics::increment_counter!("tgi_request_starved");
+            }
+        }
+        if promoted {
+            self.entries.make_contiguous().sort_by_key(Self::key);
+        }
+    }
+}
+
+/// Queued entries of a tenant
+#[derive(Debug)]
+struct TenantQueue {
+    /// Name of the tenant, None for the requests without API key
+    tenant: Option<String>,
+    /// Number of consecutive entries served before the next tenant
+    weight: u32,
+    /// Number of entries served since the tenant reached the front of the round-robin
+    served: u32,
+    entries: VecDeque<(u64, Entry)>,
+}
+
+impl TenantQueue {
+    fn new(entry: (u64, Entry)) -> Self {
+        let tenant = entry.1.request.tenant.as_ref();
+        Self {
+            tenant: tenant.map(|tenant| tenant.name().to_string()),
+            weight: tenant.map_or(1, Tenant::scheduling_weight),
+            served: 0,
+            entries: VecDeque::from([entry]),
+        }
+    }
+}
+
+/// Queue entries of a priority level
+///
+/// Tenants are served in weighted round-robin so that the requests of a tenant cannot starve
+/// the other tenants. Entries of the same tenant are served in FIFO order
+#[derive(Debug, Default)]
+struct FairQueue {
+    /// Tenants with queued entries, the front one is served next
+    tenants: VecDeque<TenantQueue>,
+}
+
+impl FairQueue {
+    fn len(&self) -> usize {
+        self.tenants.iter().map(|queue| queue.entries.len()).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    /// Index of the queue of the tenant of `entry`
+    fn position(&self, entry: &Entry) -> Option<usize> {
+        let tenant = entry.request.tenant.as_ref().map(Tenant::name);
+        self.tenants
+            .iter()
+            .position(|queue| queue.tenant.as_deref() == tenant)
+    }
+
+    fn push_back(&mut self, entry: (u64, Entry)) {
+        match self.position(&entry.1) {
+            Some(index) => self.tenants[index].entries.push_back(entry),
+            None => self.tenants.push_back(TenantQueue::new(entry)),
+        }
+    }
+
+    /// Undo the last `pop_front`
+    fn push_front(&mut self, entry: (u64, Entry)) {
+        match self.position(&entry.1) {
+            Some(index) => {
+                let mut queue = self.tenants.remove(index).unwrap();
+                queue.served = queue.served.saturating_sub(1);
+                queue.entries.push_front(entry);
+                self.tenants.push_front(queue);
+            }
+            None => self.tenants.push_front(TenantQueue::new(entry)),
+        }
+    }
+
+    /// Next entry of the tenant at the front of the round-robin
+    fn front(&self) -> Option<&Entry> {
+        let queue = self.tenants.front()?;
+        queue.entries.front().map(|(_, entry)| entry)
+    }
+
+    fn pop_front(&mut self) -> Option<(u64, Entry)> {
+        // The next tenant is served once the front one used its share
+        let queue = self.tenants.front_mut()?;
+        if queue.served >= queue.weight {
+            queue.served = 0;
+            self.tenants.rotate_left(1);
+        }
+
+        let queue = self.tenants.front_mut()?;
+        let entry = queue.entries.pop_front();
+        queue.served += 1;
+        if queue.entries.is_empty() {
+            self.tenants.pop_front();
+        }
+        entry
+    }
+
+    fn retain(&mut self, mut f: impl FnMut(u64, &Entry) -> bool) {
+        for queue in self.tenants.iter_mut() {
+            queue.entries.retain(|(id, entry)| f(*id, entry));
+        }
+        self.tenants.retain(|queue| !queue.entries.is_empty());
+    }
+
+    /// Remove the entries specified by the predicate
+    fn take(&mut self, mut f: impl FnMut(&Entry) -> bool) -> Vec<(u64, Entry)> {
+        let mut taken = Vec::new();
+        for queue in self.tenants.iter_mut() {
+            let (matching, kept): (Vec<_>, VecDeque<_>) =
+                queue.entries.drain(..).partition(|(_, entry)| f(entry));
+            taken.extend(matching);
+            queue.entries = kept;
+        }
+        self.tenants.retain(|queue| !queue.entries.is_empty());
+        taken
+    }
+
+    /// Add an entry among the entries of its tenant in their order of arrival
+    fn insert(&mut self, entry: (u64, Entry)) {
+        match self.position(&entry.1) {
+            Some(index) => insert_by_id(&mut self.tenants[index].entries, entry),
+            None => self.tenants.push_back(TenantQueue::new(entry)),
+        }
+    }
+}
+
+/// Entries of a higher priority batched first, entries of the same priority in weighted
+/// round-robin across tenants
+#[derive(Debug, Default)]
+pub(crate) struct FairShare {
+    /// One queue per priority level, indexed by `Priority as usize`
+    levels: [FairQueue; Priority::LEVELS],
+}
+
+impl SchedulingPolicy for FairShare {
+    fn len(&self) -> usize {
+        self.levels.iter().map(FairQueue::len).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.levels.iter().all(FairQueue::is_empty)
+    }
+
+    fn push_back(&mut self, entry: (u64, Entry)) {
+        self.levels[level(&entry.1)].push_back(entry);
+    }
+
+    fn push_front(&mut self, entry: (u64, Entry)) {
+        self.levels[level(&entry.1)].push_front(entry);
+    }
+
+    fn front(&self) -> Option<&Entry> {
+        self.levels.iter().rev().find_map(FairQueue::front)
+    }
+
+    fn pop_front(&mut self) -> Option<(u64, Entry)> {
+        self.levels.iter_mut().rev().find_map(FairQueue::pop_front)
+    }
+
+    /// Entries of the same priority are counted in FIFO order
+    fn positions(&self) -> IntMap<u64, usize> {
+        let mut positions = IntMap::with_capacity_and_hasher(self.len(), Default::default());
+        for level in self.levels.iter().rev() {
+            let mut ids: Vec<u64> = level
+                .tenants
+                .iter()
+                .flat_map(|queue| queue.entries.iter().map(|(id, _)| *id))
+                .collect();
+            ids.sort_unstable();
+            let ahead = positions.len();
+            positions.extend(positions_after(ahead, ids.into_iter()));
+        }
+        positions
+    }
+
+    fn iter_mut(&mut self) -> Box<dyn Iterator<Item = &mut (u64, Entry)> + '_> {
+        Box::new(
+            self.levels
+                .iter_mut()
+                .flat_map(|level| level.tenants.iter_mut())
+                .flat_map(|queue| queue.entries.iter_mut()),
+        )
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(u64, &Entry) -> bool) {
+        self.levels
+            .iter_mut()
+            .for_each(|level| level.retain(&mut *f));
+    }
+
+    fn age(&mut self, now: Instant, interval: Duration) {
+        for index in 0..Priority::LEVELS - 1 {
+            let promoted =
+                self.levels[index].take(|entry| aged_level(entry, now, interval) > index);
+            for (id, mut entry) in promoted {
+                let aged_level = aged_level(&entry, now, interval);
+                promote(&mut entry, aged_level);
+                self.levels[aged_level].insert((id, entry));
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+/// Ratio of waiting to running requests adapted to the inter-token latency of the running batch
+use std::time::Duration;
+
+/// Bounds of the adaptive ratio
+const MIN_RATIO: f32 = 0.1;
+const MAX_RATIO: f32 = 10.0;
+/// Sensitivity of the ratio to the relative latency error
+const GAIN: f32 = 0.1;
+
+/// Effective `waiting_served_ratio` of the batching task
+///
+/// When adaptive, the ratio rises while the running requests wait longer than the target between
+/// two tokens, so that fewer prefills of new requests interrupt their decoding, and falls while
+/// they wait less, so that new requests are added to the running batch sooner.
+#[derive(Debug)]
+pub(crate) struct ServedRatio {
+    ratio: f32,
+    target_inter_token_latency: Option<Duration>,
+}
+
+impl ServedRatio {
+    pub(crate) fn new(ratio: f32, target_inter_token_latency: Option<Duration>) -> Self {
+        metrics::gauge!("tgi_batch_waiting_served_ratio", ratio as f64);
+        Self {
+            ratio,
+            target_inter_token_latency,
+        }
+    }
+
+    pub(crate) fn get(&self) -> f32 {
+        self.ratio
+    }
+
+    /// Adapt the ratio to the time the running requests waited for their last token
+    pub(crate) fn on_token(&mut self, latency: Duration) {
+        let Some(target) = self.target_inter_token_latency else {
+            return;
+        };
+        let error = latency.as_secs_f32() / target.as_secs_f32();
+        self.ratio = (self.ratio * error.powf(GAIN)).clamp(MIN_RATIO, MAX_RATIO);
+        metrics::gauge!("tgi_batch_waiting_served_ratio", self.ratio as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_served_ratio_fixed() {
+        let mut ratio = ServedRatio::new(1.2, None);
+        ratio.on_token(Duration::from_secs(10));
+        assert_eq!(ratio.get(), 1.2);
+    }
+
+    #[test]
+    fn test_served_ratio_adaptive() {
+        let mut ratio = ServedRatio::new(1.2, Some(Duration::from_millis(50)));
+        ratio.on_token(Duration::from_millis(50));
+        assert_eq!(ratio.get(), 1.2);
+
+        // Slower tokens concatenate new requests less aggressively
+        ratio.on_token(Duration::from_millis(100));
+        assert!(ratio.get() > 1.2);
+
+        // Faster tokens concatenate new requests more aggressively, down to the minimum
+        for _ in 0..1000 {
+            ratio.on_token(Duration::from_millis(10));
+        }
+        assert_eq!(ratio.get(), MIN_RATIO);
+    }
+}
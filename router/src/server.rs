@@ -1,30 +1,67 @@
 /// HTTP Server logic
-use crate::health::Health;
+use crate::admin::{self, Intake};
+use crate::audit::AuditLog;
+use crate::auth::{self, ApiKeys, Tenant};
+use crate::cancellation::Cancellations;
+use crate::chunking::StreamGranularity;
+use crate::content_filter::{ContentFilter, PolicyViolation};
+use crate::defaults::ParameterDefaults;
+use crate::fim::FimTokens;
+use crate::grpc;
+use crate::health::{GenerationHealth, Health};
+use crate::holdback::StopSequenceHoldback;
+use crate::idempotency::{self, IdempotencyCache};
 use crate::infer::{InferError, InferResponse, InferStreamResponse};
-use crate::validation::ValidationError;
+use crate::journal::{self, QueueJournal};
+use crate::models::Models;
+use crate::moderation::OutputModeration;
+use crate::prompt_templates::PromptTemplates;
+use crate::rate_limit::{self, RateLimit, RateLimiter};
+use crate::redaction::{PiiRedactor, RedactionDirection};
+use crate::request_id::{self, RequestId};
+use crate::scheduling::SchedulingPolicyKind;
+use crate::shedding::SheddingPolicy;
+use crate::token_budget::AdaptiveBudgetConfig;
+use crate::usage::TenantUsage;
+use crate::validation::{ValidationError, DEFAULT_TRUNCATION_MARKER};
 use crate::{
-    BestOfSequence, CompatGenerateRequest, Details, ErrorResponse, FinishReason,
-    GenerateParameters, GenerateRequest, GenerateResponse, HubModelInfo, Infer, Info, PrefillToken,
-    StreamDetails, StreamResponse, Token, Validation,
+    AuditConfig, BestOfSequence, ChatCompletion, ChatCompletionChoice, ChatCompletionChunk,
+    ChatCompletionChunkChoice, ChatCompletionDelta, ChatCompletionToolCallDelta, ChatRequest,
+    CompatGenerateRequest, Completion, CompletionChoice, CompletionRequest, Details,
+    DetokenizeRequest, DetokenizeResponse, EmbeddingData, EmbeddingRequest, EmbeddingResponse,
+    EmbeddingUsage, ErrorResponse, FinishReason, FunctionCall, FunctionDefinition,
+    GenerateBatchRequest, GenerateInputs, GenerateOutput, GenerateParameters, GenerateRequest,
+    GenerateResponse, HealthStatus, HubModelInfo, HubTokenizerConfig, Infer, Info, Message,
+    ModelHealth, OverflowPolicy, PrefillToken, Priority, QueueLoad, QueuePosition, ShardHealth,
+    SimpleToken, StreamDetails, StreamResponse, Token, TokenizeResponse, Tool, ToolCall,
+    TruncationSide, Usage, Validation, WebSocketRequest,
 };
-use axum::extract::Extension;
-use axum::http::{HeaderMap, Method, StatusCode};
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{Extension, FromRequestParts, Path, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
 use axum::response::sse::{Event, KeepAlive, Sse};
 use axum::response::{IntoResponse, Response};
-use axum::routing::{get, post};
-use axum::{http, Json, Router};
+use axum::routing::{delete, get, post};
+use axum::{async_trait, http, middleware, Json, Router};
 use axum_tracing_opentelemetry::opentelemetry_tracing_layer;
 use futures::stream::StreamExt;
-use futures::Stream;
+use futures::{Future, Stream};
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use serde::Serialize;
 use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::sync::atomic::AtomicBool;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use text_generation_client::{ShardInfo, ShardedClient};
 use tokenizers::Tokenizer;
 use tokio::signal;
+use tokio::sync::{watch, OwnedSemaphorePermit};
 use tokio::time::Instant;
+use tower_http::compression::CompressionLayer;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{info_span, instrument, Instrument};
 use utoipa::OpenApi;
@@ -36,6 +73,7 @@ post,
 tag = "Text Generation Inference",
 path = "/",
 request_body = CompatGenerateRequest,
+params(("X-Priority" = Option<Priority>, Header, description = "Priority of the request if it does not set a `priority` parameter")),
 responses(
 (status = 200, description = "Generated Text",
 content(
@@ -52,10 +90,13 @@ example = json ! ({"error": "Input validation error"})),
 example = json ! ({"error": "Incomplete generation"})),
 )
 )]
-#[instrument(skip(infer, req))]
+#[instrument(skip(models, info, context, req))]
 async fn compat_generate(
     default_return_full_text: Extension<bool>,
-    infer: Extension<Infer>,
+    models: Extension<Models>,
+    info: Extension<Info>,
+    sse_keep_alive: Extension<SseKeepAlive>,
+    context: RequestContext,
     req: Json<CompatGenerateRequest>,
 ) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
     let mut req = req.0;
@@ -67,13 +108,20 @@ async fn compat_generate(
 
     // switch on stream
     if req.stream {
-        Ok(generate_stream(infer, Json(req.into()))
-            .await
-            .into_response())
+        Ok(
+            generate_stream(models, sse_keep_alive, context, Json(req.into()))
+                .await?
+                .into_response(),
+        )
     } else {
-        let (headers, generation) = generate(infer, Json(req.into())).await?;
+        let req = GenerateRequest::from(req);
+        let (headers, Json(output)) = generate(models, info, context, Json(req.into())).await?;
         // wrap generation inside a Vec to match api-inference
-        Ok((headers, Json(vec![generation.0])).into_response())
+        let generations = match output {
+            GenerateOutput::Single(generation) => vec![generation],
+            GenerateOutput::Batch(generations) => generations,
+        };
+        Ok((headers, Json(generations)).into_response())
     }
 }
 
@@ -94,34 +142,158 @@ get,
 tag = "Text Generation Inference",
 path = "/health",
 responses(
-(status = 200, description = "Everything is working fine"),
-(status = 503, description = "Text generation inference is down", body = ErrorResponse,
-example = json ! ({"error": "unhealthy", "error_type": "healthcheck"})),
+(status = 200, description = "Everything is working fine", body = HealthStatus),
+(status = 503, description = "Text generation inference is down", body = HealthStatus),
 )
 )]
 #[instrument(skip(health))]
 /// Health check method
-async fn health(mut health: Extension<Health>) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
-    match health.check().await {
+///
+/// Calls every shard of every served model and reports their latency and device
+async fn health(mut health: Extension<Vec<Health>>) -> (StatusCode, Json<HealthStatus>) {
+    let mut models = Vec::with_capacity(health.0.len());
+    for health in health.0.iter_mut() {
+        models.push(health.status().await);
+    }
+    // Every served model must be healthy
+    let healthy = models.iter().all(|model| model.healthy);
+    let status_code = match healthy {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status_code, Json(HealthStatus { healthy, models }))
+}
+
+/// Consumption of the tenant of the API key since the router started
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/usage",
+responses(
+(status = 200, description = "Usage of the tenant", body = TenantUsage),
+(status = 401, description = "Invalid or missing API key", body = ErrorResponse,
+example = json ! ({"error": "Invalid or missing API key", "error_type": "authentication"})),
+)
+)]
+#[instrument(skip_all)]
+async fn usage(Extension(tenant): Extension<Tenant>) -> Json<TenantUsage> {
+    Json(tenant.usage())
+}
+
+/// Cancel a queued or running generation by its request id
+///
+/// Only the generations of the tenant of the API key can be cancelled. Non-streaming callers
+/// receive the tokens generated before the cancellation with a `cancelled` finish reason.
+#[utoipa::path(
+delete,
+tag = "Text Generation Inference",
+path = "/generate/{request_id}",
+params(("request_id" = String, Path, description = "`X-Request-Id` of the generation")),
+responses(
+(status = 204, description = "The generation was cancelled"),
+(status = 404, description = "No generation in flight with this request id", body = ErrorResponse,
+example = json ! ({"error": "No generation in flight with this request id", "error_type": "not_found"})),
+)
+)]
+#[instrument(skip(cancellations, tenant))]
+async fn cancel_generate(
+    Extension(cancellations): Extension<Cancellations>,
+    tenant: Option<Extension<Tenant>>,
+    Path(request_id): Path<String>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
+    let tenant = tenant.as_ref().map(|Extension(tenant)| tenant.name());
+    if cancellations.cancel(&request_id, tenant) {
+        tracing::info!("Generation cancelled");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "No generation in flight with this request id".to_string(),
+                error_type: "not_found".to_string(),
+                load: None,
+                policy_violation: None,
+            }),
+        ))
+    }
+}
+
+/// Liveness probe, answers as long as the router process is up
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/health/live",
+responses((status = 200, description = "The router is up"))
+)]
+#[instrument]
+async fn health_live() -> StatusCode {
+    StatusCode::OK
+}
+
+/// Maximum number of queued requests of a model for the router to be ready, unbounded if `None`
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ReadyMaxQueueSize(pub Option<usize>);
+
+/// Readiness probe
+///
+/// Ready when every served model is warmed up, its shards are reachable and its queue is not
+/// longer than `--ready-max-queue-size`
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/health/ready",
+responses(
+(status = 200, description = "Ready to receive traffic"),
+(status = 503, description = "Not ready to receive traffic", body = ErrorResponse,
+example = json ! ({"error": "not ready", "error_type": "healthcheck"})),
+)
+)]
+#[instrument(skip_all)]
+async fn health_ready(
+    mut health: Extension<Vec<Health>>,
+    models: Extension<Models>,
+    ready_max_queue_size: Extension<ReadyMaxQueueSize>,
+) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let mut ready = true;
+    for health in health.0.iter_mut() {
+        if !health.ready().await {
+            ready = false;
+            break;
+        }
+        if let Some(max_queue_size) = ready_max_queue_size.0 .0 {
+            let (_, infer) = models.get(Some(health.model_id()))?;
+            if infer.queue_size().await > max_queue_size {
+                ready = false;
+                break;
+            }
+        }
+    }
+    match ready {
         true => Ok(()),
         false => Err((
             StatusCode::SERVICE_UNAVAILABLE,
             Json(ErrorResponse {
-                error: "unhealthy".to_string(),
+                error: "not ready".to_string(),
                 error_type: "healthcheck".to_string(),
+                load: None,
+                policy_violation: None,
             }),
         )),
     }
 }
 
 /// Generate tokens
+///
+/// `inputs` can also be an array of prompts sharing the same parameters, in which case an array
+/// of responses is returned
 #[utoipa::path(
 post,
 tag = "Text Generation Inference",
 path = "/generate",
 request_body = GenerateRequest,
+params(("X-Priority" = Option<Priority>, Header, description = "Priority of the request if it does not set a `priority` parameter")),
 responses(
-(status = 200, description = "Generated Text", body = GenerateResponse),
+(status = 200, description = "Generated Text, or an array of Generated Text for an array of prompts", body = GenerateResponse),
 (status = 424, description = "Generation Error", body = ErrorResponse,
 example = json ! ({"error": "Request failed during generation"})),
 (status = 429, description = "Model is overloaded", body = ErrorResponse,
@@ -145,10 +317,91 @@ seed,
 )
 )]
 async fn generate(
+    models: Extension<Models>,
+    info: Extension<Info>,
+    context: RequestContext,
+    req: Json<GenerateBatchRequest>,
+) -> Result<(HeaderMap, Json<GenerateOutput>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let GenerateBatchRequest {
+        model,
+        inputs,
+        mut parameters,
+        add_special_tokens,
+    } = req.0;
+    let infer = Extension(models.get(model.as_deref())?.1);
+    context.apply(&mut parameters);
+
+    match inputs {
+        GenerateInputs::Single(inputs) => {
+            let req = GenerateRequest {
+                model,
+                inputs,
+                parameters,
+                add_special_tokens,
+            };
+            let (headers, _, Json(response)) =
+                generate_internal(infer, Json(req), None, span).await?;
+            Ok((headers, Json(GenerateOutput::Single(response))))
+        }
+        GenerateInputs::Batch(inputs) => {
+            if inputs.is_empty() || inputs.len() > info.max_client_batch_size {
+                let err = ValidationError::BatchSize(info.max_client_batch_size, inputs.len());
+                metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                tracing::error!("{err}");
+                return Err(InferError::from(err).into());
+            }
+            let requests: Vec<GenerateRequest> = inputs
+                .into_iter()
+                .map(|inputs| GenerateRequest {
+                    model: model.clone(),
+                    inputs,
+                    parameters: parameters.clone(),
+                    add_special_tokens,
+                })
+                .collect();
+
+            // The batch is admitted as a whole: the permits of every prompt and of its `best_of`
+            // candidates are acquired before any prompt is queued
+            let best_of = match parameters.best_of {
+                Some(best_of) if best_of > 1 => infer.validate_best_of(best_of)?,
+                _ => 1,
+            };
+            let mut permits = infer
+                .acquire_many(&requests[0], requests.len() * best_of)
+                .await?;
+
+            // Each prompt is batched independently by the continuous batching
+            let generations = futures::future::try_join_all(requests.into_iter().map(|req| {
+                let prompt_permits = permits.split_off(permits.len() - best_of);
+                generate_internal(infer.clone(), Json(req), Some(prompt_permits), span.clone())
+            }))
+            .await?;
+
+            let mut headers = Vec::with_capacity(generations.len());
+            let mut responses = Vec::with_capacity(generations.len());
+            for (generation_headers, _, Json(generation)) in generations {
+                headers.push(generation_headers);
+                responses.push(generation);
+            }
+            Ok((
+                aggregate_headers(headers),
+                Json(GenerateOutput::Batch(responses)),
+            ))
+        }
+    }
+}
+
+/// Run a generation and return its headers, the number of input tokens and the response
+///
+/// The generation acquires its permits of the concurrency limit, one per `best_of` candidate,
+/// unless `permits` were acquired by the caller.
+pub(crate) async fn generate_internal(
     infer: Extension<Infer>,
     req: Json<GenerateRequest>,
-) -> Result<(HeaderMap, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
-    let span = tracing::Span::current();
+    permits: Option<Vec<OwnedSemaphorePermit>>,
+    span: tracing::Span,
+) -> Result<(HeaderMap, u32, Json<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
     let start_time = Instant::now();
     metrics::increment_counter!("tgi_request_count");
 
@@ -160,17 +413,36 @@ async fn generate(
         add_prompt = Some(req.0.inputs.clone());
     }
 
-    let details = req.0.parameters.details || req.0.parameters.decoder_input_details;
+    let score_prompt = req.0.parameters.score_prompt;
+    let details =
+        req.0.parameters.details || req.0.parameters.decoder_input_details || score_prompt;
 
     // Inference
-    let (response, best_of_responses) = match req.0.parameters.best_of {
-        Some(best_of) if best_of > 1 => {
+    let (mut response, best_of_responses) = match (req.0.parameters.best_of, permits) {
+        (Some(best_of), None) if best_of > 1 => {
             let (response, best_of_responses) = infer.generate_best_of(req.0, best_of).await?;
             (response, Some(best_of_responses))
         }
-        _ => (infer.generate(req.0).await?, None),
+        (Some(best_of), Some(permits)) if best_of > 1 => {
+            let (response, best_of_responses) =
+                infer.generate_best_of_with_permits(req.0, permits).await?;
+            (response, Some(best_of_responses))
+        }
+        (_, None) => (infer.generate(req.0).await?, None),
+        (_, Some(mut permits)) => {
+            let permit = permits.pop().expect("a permit must be acquired per generation");
+            (infer.generate_with_permit(req.0, permit).await?, None)
+        }
     };
 
+    // Only the prompt is scored, drop the token decoded during prefill
+    if score_prompt {
+        response.generated_text.text.clear();
+        response.generated_text.generated_tokens = 0;
+        response.tokens.clear();
+        response.top_tokens.clear();
+    }
+
     // Token details
     let details = match details {
         true => {
@@ -193,6 +465,7 @@ async fn generate(
                             generated_tokens: response.generated_text.generated_tokens,
                             prefill: response.prefill,
                             tokens: response.tokens,
+                            top_tokens: response.top_tokens,
                             seed: response.generated_text.seed,
                         }
                     })
@@ -202,8 +475,10 @@ async fn generate(
             Some(Details {
                 finish_reason: FinishReason::from(response.generated_text.finish_reason),
                 generated_tokens: response.generated_text.generated_tokens,
+                prompt_tokens: response.input_length,
                 prefill: response.prefill,
                 tokens: response.tokens,
+                top_tokens: response.top_tokens,
                 seed: response.generated_text.seed,
                 best_of_sequences,
             })
@@ -216,7 +491,7 @@ async fn generate(
     let validation_time = response.queued - start_time;
     let queue_time = response.start - response.queued;
     let inference_time = Instant::now() - response.start;
-    let time_per_token = inference_time / response.generated_text.generated_tokens;
+    let time_per_token = time_per_token(inference_time, response.generated_text.generated_tokens);
 
     // Tracing metadata
     span.record("total_time", format!("{total_time:?}"));
@@ -257,6 +532,19 @@ async fn generate(
         "x-time-per-token",
         time_per_token.as_millis().to_string().parse().unwrap(),
     );
+    headers.insert(
+        "x-prompt-tokens",
+        response.input_length.to_string().parse().unwrap(),
+    );
+    headers.insert(
+        "x-generated-tokens",
+        response
+            .generated_text
+            .generated_tokens
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
 
     // Metrics
     metrics::increment_counter!("tgi_request_success");
@@ -288,11 +576,41 @@ async fn generate(
     tracing::debug!("Output: {}", output_text);
     tracing::info!("Success");
 
+    let input_length = response.input_length;
     let response = GenerateResponse {
         generated_text: output_text,
         details,
+        continuation: response.continuation,
     };
-    Ok((headers, Json(response)))
+    Ok((headers, input_length, Json(response)))
+}
+
+/// Headers named after the counts of a generation, summed over a batch of generations
+const SUMMED_HEADERS: [&str; 3] = [
+    "x-compute-characters",
+    "x-prompt-tokens",
+    "x-generated-tokens",
+];
+
+/// Headers of a batch of generations run concurrently: the counts are summed and the timings
+/// are the ones of the slowest generation
+fn aggregate_headers(batch_headers: Vec<HeaderMap>) -> HeaderMap {
+    let parse = |value: &HeaderValue| value.to_str().ok()?.parse::<u64>().ok();
+    let mut aggregated = HeaderMap::new();
+    for headers in batch_headers {
+        for (name, value) in headers.iter() {
+            let value = match (aggregated.get(name).and_then(parse), parse(value)) {
+                (Some(total), Some(value)) if SUMMED_HEADERS.contains(&name.as_str()) => {
+                    (total + value).into()
+                }
+                (Some(total), Some(value)) => total.max(value).into(),
+                // The other headers are the same for every generation
+                _ => value.clone(),
+            };
+            aggregated.insert(name.clone(), value);
+        }
+    }
+    aggregated
 }
 
 /// Generate a stream of token using Server-Sent Events
@@ -301,6 +619,7 @@ post,
 tag = "Text Generation Inference",
 path = "/generate_stream",
 request_body = GenerateRequest,
+params(("X-Priority" = Option<Priority>, Header, description = "Priority of the request if it does not set a `priority` parameter")),
 responses(
 (status = 200, description = "Generated Text", body = StreamResponse,
 content_type = "text/event-stream"),
@@ -331,13 +650,79 @@ seed,
 )
 )]
 async fn generate_stream(
+    models: Extension<Models>,
+    sse_keep_alive: Extension<SseKeepAlive>,
+    context: RequestContext,
+    mut req: Json<GenerateRequest>,
+) -> Result<
+    (
+        HeaderMap,
+        Sse<impl Stream<Item = Result<Event, Infallible>>>,
+    ),
+    (StatusCode, Json<ErrorResponse>),
+> {
+    let span = tracing::Span::current();
+    let infer = Extension(models.get(req.0.model.as_deref())?.1);
+    context.apply(&mut req.0.parameters);
+    let on_message_callback =
+        |stream_token: StreamResponse| Event::default().json_data(stream_token).unwrap();
+    let (headers, response_stream) =
+        generate_stream_internal(infer, req, on_message_callback, span).await;
+    Ok((
+        headers,
+        Sse::new(response_stream).keep_alive(sse_keep_alive.0.into()),
+    ))
+}
+
+/// Interval of the comment frames sent on idle Server-Sent Events streams, for example while
+/// a request is queued or prefilling, so that proxies do not close the connection
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SseKeepAlive(pub Duration);
+
+impl From<SseKeepAlive> for KeepAlive {
+    fn from(keep_alive: SseKeepAlive) -> Self {
+        KeepAlive::new().interval(keep_alive.0)
+    }
+}
+
+/// Add a `Retry-After` header to the 429 responses of the requests rejected because the queue is
+/// full or because they waited in the queue for longer than `max_queue_duration`
+///
+/// Every request queued at the time of the rejection is batched or rejected within
+/// `max_queue_duration`, after which a retry does not wait behind them
+async fn retry_after<B>(
+    State(max_queue_duration): State<Duration>,
+    request: http::Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mut response = next.run(request).await;
+    if response.status() == StatusCode::TOO_MANY_REQUESTS
+        && !response.headers().contains_key(RETRY_AFTER)
+    {
+        let retry_after = ((max_queue_duration.as_millis() as u64 + 999) / 1000).max(1);
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    response
+}
+
+/// Mean inference time of the generated tokens
+///
+/// No token is generated when scoring the prompt or when the moderation stops a generation at its
+/// first token
+pub(crate) fn time_per_token(inference_time: Duration, generated_tokens: u32) -> Duration {
+    inference_time / generated_tokens.max(1)
+}
+
+/// Run a streaming generation and map every `StreamResponse` to a Server-Sent Event with
+/// `on_message_callback`
+async fn generate_stream_internal(
     infer: Extension<Infer>,
     req: Json<GenerateRequest>,
-) -> (
-    HeaderMap,
-    Sse<impl Stream<Item = Result<Event, Infallible>>>,
-) {
-    let span = tracing::Span::current();
+    mut on_message_callback: impl FnMut(StreamResponse) -> Event,
+    span: tracing::Span,
+) -> (HeaderMap, impl Stream<Item = Result<Event, Infallible>>) {
     let start_time = Instant::now();
     metrics::increment_counter!("tgi_request_count");
 
@@ -363,54 +748,92 @@ async fn generate_stream(
             add_prompt = Some(req.0.inputs.clone());
         }
         let details = req.0.parameters.details;
+        let mut holdback = StopSequenceHoldback::new(req.0.parameters.stop.clone())
+            .with_granularity(req.0.parameters.stream_granularity);
 
         let best_of = req.0.parameters.best_of.unwrap_or(1);
-        if best_of != 1 {
-            let err = InferError::from(ValidationError::BestOfStream);
+        if req.0.parameters.decoder_input_details {
+            let err = InferError::from(ValidationError::PrefillDetailsStream);
             metrics::increment_counter!("tgi_request_failure", "err" => "validation");
             tracing::error!("{err}");
             yield Ok(Event::from(err));
-        } else if req.0.parameters.decoder_input_details {
-            let err = InferError::from(ValidationError::PrefillDetailsStream);
+        } else if req.0.parameters.score_prompt {
+            let err = InferError::from(ValidationError::ScorePromptStream);
             metrics::increment_counter!("tgi_request_failure", "err" => "validation");
             tracing::error!("{err}");
             yield Ok(Event::from(err));
+        } else if best_of != 1 {
+            // All the candidates must be generated before the best one is known
+            match infer.generate_best_of(req.0, best_of).instrument(info_span!(parent: &span, "async_stream")).await {
+                Ok((response, _)) => {
+                    // Metrics
+                    let total_time = start_time.elapsed();
+                    metrics::increment_counter!("tgi_request_success");
+                    metrics::histogram!("tgi_request_duration", total_time.as_secs_f64());
+                    metrics::histogram!("tgi_request_generated_tokens", response.generated_text.generated_tokens as f64);
+                    span.record("total_time", format!("{total_time:?}"));
+                    span.record("seed", format!("{:?}", response.generated_text.seed));
+                    tracing::info!(parent: &span, "Success");
+
+                    for stream_token in best_of_stream_responses(response, add_prompt, details, holdback) {
+                        yield Ok(on_message_callback(stream_token));
+                    }
+                }
+                Err(err) => yield Ok(Event::from(err)),
+            }
         } else {
             match infer.generate_stream(req.0).instrument(info_span!(parent: &span, "async_stream")).await {
                 // Keep permit as long as generate_stream lives
-                Ok((_permit, mut response_stream)) => {
+                Ok((_permit, input_length, mut response_stream)) => {
                     // Server-Sent Event stream
                     while let Some(response) = response_stream.next().await {
                         match response {
                             Ok(response) => {
                                 match response {
+                                    // Tell the client its position while it is queued
+                                    InferStreamResponse::Queued { position, estimated_wait } => {
+                                        let queue_position = QueuePosition {
+                                            position,
+                                            estimated_wait_ms: estimated_wait.map(|wait| wait.as_millis()),
+                                        };
+                                        yield Ok(Event::default().event("queue").json_data(queue_position).unwrap())
+                                    }
                                     // Prefill is ignored
                                     InferStreamResponse::Prefill(_) => {}
                                     // Yield event for every new token
-                                    InferStreamResponse::Token(token) => {
+                                    InferStreamResponse::Intermediate {
+                                        token,
+                                        top_tokens,
+                                    } => {
                                         tracing::debug!(parent: &span, "Token: {:?}", token);
 
-                                        // StreamResponse
-                                        let stream_token = StreamResponse {
-                                            token,
-                                            generated_text: None,
-                                            details: None,
-                                        };
-
-                                        yield Ok(Event::default().json_data(stream_token).unwrap())
+                                        // Tokens that could start a stop sequence are held back
+                                        for chunk in holdback.push(token, top_tokens) {
+                                            yield Ok(on_message_callback(StreamResponse::from(chunk)))
+                                        }
                                     }
                                     // Yield event for last token and compute timings
                                     InferStreamResponse::End {
                                         token,
+                                        top_tokens,
                                         generated_text,
                                         start,
                                         queued,
+                                        continuation,
                                     } => {
+                                        // Release the held back tokens and remove the stop sequence
+                                        let stopped = generated_text.finish_reason == text_generation_client::FinishReason::StopSequence as i32;
+                                        let (released, last) = holdback.finish(token, top_tokens, stopped);
+                                        for chunk in released {
+                                            yield Ok(on_message_callback(StreamResponse::from(chunk)))
+                                        }
+
                                         // Token details
                                         let details = match details {
                                             true => Some(StreamDetails {
                                                 finish_reason: FinishReason::from(generated_text.finish_reason),
                                                 generated_tokens: generated_text.generated_tokens,
+                                                prompt_tokens: input_length,
                                                 seed: generated_text.seed,
                                             }),
                                             false => None,
@@ -421,7 +844,7 @@ async fn generate_stream(
                                         let validation_time = queued - start_time;
                                         let queue_time = start - queued;
                                         let inference_time = Instant::now() - start;
-                                        let time_per_token = inference_time / generated_text.generated_tokens;
+                                        let time_per_token = time_per_token(inference_time, generated_text.generated_tokens);
 
                                         // Tracing metadata
                                         span.record("total_time", format!("{total_time:?}"));
@@ -452,12 +875,13 @@ async fn generate_stream(
                                         tracing::info!(parent: &span, "Success");
 
                                         let stream_token = StreamResponse {
-                                            token,
                                             generated_text: Some(output_text),
-                                            details
+                                            details,
+                                            continuation,
+                                            ..StreamResponse::from(last)
                                         };
 
-                                        yield Ok(Event::default().json_data(stream_token).unwrap());
+                                        yield Ok(on_message_callback(stream_token));
                                         break;
                                     }
                                 }
@@ -488,7 +912,936 @@ async fn generate_stream(
         }
     };
 
-    (headers, Sse::new(stream).keep_alive(KeepAlive::default()))
+    (headers, stream)
+}
+
+/// Stream responses of a generation that already ended
+///
+/// All the `best_of` candidates must be generated before the best one is selected, so its tokens
+/// are buffered and streamed at once
+pub(crate) fn best_of_stream_responses(
+    response: InferResponse,
+    add_prompt: Option<String>,
+    details: bool,
+    mut holdback: StopSequenceHoldback,
+) -> Vec<StreamResponse> {
+    let mut responses = Vec::with_capacity(response.tokens.len());
+    let mut tokens = response.tokens;
+    let mut top_tokens = response.top_tokens.into_iter();
+
+    let last = match tokens.pop() {
+        Some(last) => last,
+        None => return responses,
+    };
+    for token in tokens {
+        let top_tokens = top_tokens.next().unwrap_or_default();
+        responses.extend(
+            holdback
+                .push(token, top_tokens)
+                .into_iter()
+                .map(StreamResponse::from),
+        );
+    }
+
+    let input_length = response.input_length;
+    let generated_text = response.generated_text;
+    let stopped =
+        generated_text.finish_reason == text_generation_client::FinishReason::StopSequence as i32;
+    let (released, last) = holdback.finish(last, top_tokens.next().unwrap_or_default(), stopped);
+    responses.extend(released.into_iter().map(StreamResponse::from));
+
+    let details = match details {
+        true => Some(StreamDetails {
+            finish_reason: FinishReason::from(generated_text.finish_reason),
+            generated_tokens: generated_text.generated_tokens,
+            prompt_tokens: input_length,
+            seed: generated_text.seed,
+        }),
+        false => None,
+    };
+    let mut output_text = generated_text.text;
+    if let Some(prompt) = add_prompt {
+        output_text = prompt + &output_text;
+    }
+    responses.push(StreamResponse {
+        generated_text: Some(output_text),
+        details,
+        ..StreamResponse::from(last)
+    });
+    responses
+}
+
+/// Generate a stream of token over a WebSocket
+///
+/// Clients send `{"type": "generate", "inputs": ..., "parameters": ...}` messages and receive
+/// one `StreamResponse` message per token. A `{"type": "cancel"}` message stops the running
+/// generation, a `{"type": "cancel", "request_id": ...}` message the generation of another request
+/// of the same tenant.
+#[utoipa::path(
+get,
+tag = "Text Generation Inference",
+path = "/generate_ws",
+responses(
+(status = 101, description = "Switching to the WebSocket protocol"),
+)
+)]
+#[instrument(skip_all)]
+async fn generate_ws(
+    models: Extension<Models>,
+    context: RequestContext,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.on_upgrade(move |socket| generate_ws_session(models.0, context, socket))
+}
+
+/// Handle all the generation requests of a WebSocket connection
+async fn generate_ws_session(models: Models, context: RequestContext, mut socket: WebSocket) {
+    while let Some(Ok(message)) = socket.recv().await {
+        let request = match message {
+            WsMessage::Text(text) => serde_json::from_str::<WebSocketRequest>(&text),
+            WsMessage::Close(_) => break,
+            // Ping/Pong are handled by axum
+            _ => continue,
+        };
+
+        let open = match request {
+            Ok(WebSocketRequest::Generate(mut req)) => {
+                context.clone().apply(&mut req.parameters);
+                let span = info_span!("generate_ws", parameters = ?req.parameters);
+                match models.get(req.model.as_deref()) {
+                    Ok((_, infer)) => {
+                        generate_ws_request(&infer, &context, &mut socket, req)
+                            .instrument(span)
+                            .await
+                    }
+                    Err(err) => ws_send_error(&mut socket, err).await,
+                }
+            }
+            Ok(WebSocketRequest::Cancel {
+                request_id: Some(request_id),
+            }) => {
+                context.cancel(&request_id);
+                true
+            }
+            // Nothing is running
+            Ok(WebSocketRequest::Cancel { request_id: None }) => true,
+            Err(err) => ws_send_invalid_message(&mut socket, err.to_string()).await,
+        };
+
+        if !open {
+            break;
+        }
+    }
+}
+
+/// Stream a generation over the WebSocket until it ends or is cancelled by the client
+///
+/// Returns false if the connection was closed
+async fn generate_ws_request(
+    infer: &Infer,
+    context: &RequestContext,
+    socket: &mut WebSocket,
+    req: GenerateRequest,
+) -> bool {
+    let start_time = Instant::now();
+    metrics::increment_counter!("tgi_request_count");
+
+    let mut add_prompt = None;
+    if req.parameters.return_full_text.unwrap_or(false) {
+        add_prompt = Some(req.inputs.clone());
+    }
+    let details = req.parameters.details;
+    let mut holdback = StopSequenceHoldback::new(req.parameters.stop.clone())
+        .with_granularity(req.parameters.stream_granularity);
+
+    if req.parameters.decoder_input_details {
+        let err = InferError::from(ValidationError::PrefillDetailsStream);
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        tracing::error!("{err}");
+        return ws_send_error(socket, err).await;
+    }
+    if req.parameters.score_prompt {
+        let err = InferError::from(ValidationError::ScorePromptStream);
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        tracing::error!("{err}");
+        return ws_send_error(socket, err).await;
+    }
+
+    let best_of = req.parameters.best_of.unwrap_or(1);
+    if best_of != 1 {
+        // All the candidates must be generated before the best one is known
+        let response = tokio::select! {
+            response = infer.generate_best_of(req, best_of) => response,
+            // Dropping the generation future cancels all the candidates
+            open = ws_wait_cancel(context, socket) => return open,
+        };
+        return match response {
+            Ok((response, _)) => {
+                metrics::increment_counter!("tgi_request_success");
+                metrics::histogram!("tgi_request_duration", start_time.elapsed().as_secs_f64());
+                metrics::histogram!(
+                    "tgi_request_generated_tokens",
+                    response.generated_text.generated_tokens as f64
+                );
+                tracing::info!("Success");
+
+                for stream_token in
+                    best_of_stream_responses(response, add_prompt, details, holdback)
+                {
+                    if !ws_send(socket, &stream_token).await {
+                        return false;
+                    }
+                }
+                true
+            }
+            Err(err) => ws_send_error(socket, err).await,
+        };
+    }
+
+    // Keep permit as long as the response stream lives
+    let (_permit, input_length, mut response_stream) = match infer.generate_stream(req).await {
+        Ok(response) => response,
+        Err(err) => return ws_send_error(socket, err).await,
+    };
+
+    loop {
+        tokio::select! {
+            response = response_stream.next() => match response {
+                // Queue positions and prefill are ignored
+                Some(Ok(InferStreamResponse::Queued { .. } | InferStreamResponse::Prefill(_))) => {}
+                Some(Ok(InferStreamResponse::Intermediate { token, top_tokens })) => {
+                    tracing::debug!("Token: {:?}", token);
+                    for chunk in holdback.push(token, top_tokens) {
+                        if !ws_send(socket, &StreamResponse::from(chunk)).await {
+                            return false;
+                        }
+                    }
+                }
+                Some(Ok(InferStreamResponse::End {
+                    token,
+                    top_tokens,
+                    generated_text,
+                    continuation,
+                    ..
+                })) => {
+                    let stopped = generated_text.finish_reason
+                        == text_generation_client::FinishReason::StopSequence as i32;
+                    let (released, last) = holdback.finish(token, top_tokens, stopped);
+                    for chunk in released {
+                        if !ws_send(socket, &StreamResponse::from(chunk)).await {
+                            return false;
+                        }
+                    }
+
+                    let details = match details {
+                        true => Some(StreamDetails {
+                            finish_reason: FinishReason::from(generated_text.finish_reason),
+                            generated_tokens: generated_text.generated_tokens,
+                            prompt_tokens: input_length,
+                            seed: generated_text.seed,
+                        }),
+                        false => None,
+                    };
+
+                    metrics::increment_counter!("tgi_request_success");
+                    metrics::histogram!("tgi_request_duration", start_time.elapsed().as_secs_f64());
+                    metrics::histogram!("tgi_request_generated_tokens", generated_text.generated_tokens as f64);
+
+                    let mut output_text = generated_text.text;
+                    if let Some(prompt) = add_prompt {
+                        output_text = prompt + &output_text;
+                    }
+                    tracing::debug!("Output: {}", output_text);
+                    tracing::info!("Success");
+
+                    let stream_token = StreamResponse {
+                        generated_text: Some(output_text),
+                        details,
+                        continuation,
+                        ..StreamResponse::from(last)
+                    };
+                    return ws_send(socket, &stream_token).await;
+                }
+                Some(Err(err)) => return ws_send_error(socket, err).await,
+                None => {
+                    let err = InferError::IncompleteGeneration;
+                    metrics::increment_counter!("tgi_request_failure", "err" => "incomplete");
+                    tracing::error!("{err}");
+                    return ws_send_error(socket, err).await;
+                }
+            },
+            // Dropping the response stream stops the generation in the batching task
+            open = ws_wait_cancel(context, socket) => return open,
+        }
+    }
+}
+
+/// Wait for the client to cancel the running generation
+///
+/// The generations of other requests cancelled in the meantime are cancelled right away. The
+/// generation requests sent in the meantime are rejected with an error message, one generation
+/// runs at a time on a connection. Returns false if the connection was closed
+async fn ws_wait_cancel(context: &RequestContext, socket: &mut WebSocket) -> bool {
+    loop {
+        match socket.recv().await {
+            Some(Ok(WsMessage::Text(text))) => match serde_json::from_str(&text) {
+                Ok(WebSocketRequest::Cancel { request_id: None }) => {
+                    metrics::increment_counter!("tgi_request_failure", "err" => "cancelled");
+                    tracing::info!("Cancelled by the client");
+                    return true;
+                }
+                Ok(WebSocketRequest::Cancel {
+                    request_id: Some(request_id),
+                }) => context.cancel(&request_id),
+                Ok(WebSocketRequest::Generate(_)) => {
+                    let message = "a generation is already running on this connection, \
+                                   wait for its end or cancel it";
+                    if !ws_send_invalid_message(socket, message.to_string()).await {
+                        return false;
+                    }
+                }
+                Err(err) => {
+                    if !ws_send_invalid_message(socket, err.to_string()).await {
+                        return false;
+                    }
+                }
+            },
+            Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => {
+                metrics::increment_counter!("tgi_request_failure", "err" => "dropped");
+                return false;
+            }
+            // Ping/Pong are handled by axum
+            Some(Ok(_)) => {}
+        }
+    }
+}
+
+/// Send a JSON message over the WebSocket
+///
+/// Returns false if the connection was closed
+async fn ws_send<T: Serialize>(socket: &mut WebSocket, message: &T) -> bool {
+    let message = serde_json::to_string(message).unwrap();
+    socket.send(WsMessage::Text(message)).await.is_ok()
+}
+
+/// Reject a message of the client that cannot be handled
+async fn ws_send_invalid_message(socket: &mut WebSocket, error: String) -> bool {
+    ws_send(
+        socket,
+        &ErrorResponse {
+            error,
+            error_type: "invalid_message".to_string(),
+            load: None,
+            policy_violation: None,
+        },
+    )
+    .await
+}
+
+/// Send an `InferError` over the WebSocket
+async fn ws_send_error(socket: &mut WebSocket, err: InferError) -> bool {
+    ws_send(
+        socket,
+        &ErrorResponse {
+            error: err.to_string(),
+            error_type: err.error_type().to_string(),
+            load: err.load(),
+            policy_violation: err.policy_violation(),
+        },
+    )
+    .await
+}
+
+/// Generate tokens with the OpenAI Completions API
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/completions",
+request_body = CompletionRequest,
+params(("X-Priority" = Option<Priority>, Header, description = "Priority of the request if it does not set a `priority` parameter")),
+responses(
+(status = 200, description = "Generated Text",
+content(
+("application/json" = Completion),
+("text/event-stream" = Completion),
+)),
+(status = 424, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded"})),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+(status = 500, description = "Incomplete generation", body = ErrorResponse,
+example = json ! ({"error": "Incomplete generation"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(
+        parameters,
+        total_time,
+        validation_time,
+        queue_time,
+        inference_time,
+        time_per_token,
+        seed,
+    )
+)]
+async fn completions(
+    models: Extension<Models>,
+    sse_keep_alive: Extension<SseKeepAlive>,
+    context: RequestContext,
+    req: Json<CompletionRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let (model_id, infer) = models.get(req.0.model.as_deref())?;
+    let infer = Extension(infer);
+    let stream = req.0.stream;
+    let n = infer.validate_n(req.0.n)?;
+    let mut req: GenerateRequest = req.0.into();
+    context.apply(&mut req.parameters);
+    span.record("parameters", format!("{:?}", req.parameters));
+
+    let id = format!("cmpl-{:016x}", rand::random::<u64>());
+    let created = unix_timestamp();
+
+    if stream {
+        // With `echo`, the prompt is sent before the first token of every completion
+        let echo = req.parameters.return_full_text == Some(true);
+        let prompt = req.inputs.clone();
+        let on_message_callback = move |index: u32| {
+            let id = id.clone();
+            let model_id = model_id.clone();
+            let mut prompt = echo.then(|| prompt.clone());
+            move |stream_token: StreamResponse| {
+                let finish_reason = stream_token
+                    .details
+                    .map(|details| details.finish_reason.openai().to_string());
+                // Special tokens such as the end of sequence token are not part of the completion
+                let mut text = match stream_token.token.special {
+                    true => String::new(),
+                    false => stream_token.token.text,
+                };
+                if let Some(prompt) = prompt.take() {
+                    text.insert_str(0, &prompt);
+                }
+
+                Event::default()
+                    .json_data(Completion {
+                        id: id.clone(),
+                        object: "text_completion".to_string(),
+                        created,
+                        model: model_id.clone(),
+                        choices: vec![CompletionChoice {
+                            index,
+                            text,
+                            finish_reason,
+                        }],
+                        usage: None,
+                    })
+                    .unwrap()
+            }
+        };
+        let (headers, response_stream) =
+            generate_n_streams(infer, req, n, on_message_callback, span).await;
+        let sse = Sse::new(response_stream).keep_alive(sse_keep_alive.0.into());
+        Ok((headers, sse).into_response())
+    } else {
+        let (headers, input_length, generations) = generate_n(infer, req, n, span).await?;
+
+        let mut completion_tokens = 0;
+        let choices = generations
+            .into_iter()
+            .enumerate()
+            .map(|(index, generation)| {
+                // `details` is always requested by `From<CompletionRequest>`
+                let details = generation.details.unwrap();
+                completion_tokens += details.generated_tokens;
+                CompletionChoice {
+                    index: index as u32,
+                    text: generation.generated_text,
+                    finish_reason: Some(details.finish_reason.openai().to_string()),
+                }
+            })
+            .collect();
+        let response = Completion {
+            id,
+            object: "text_completion".to_string(),
+            created,
+            model: model_id,
+            choices,
+            usage: Some(Usage {
+                prompt_tokens: input_length,
+                completion_tokens,
+                total_tokens: input_length + completion_tokens,
+            }),
+        };
+        Ok((headers, Json(response)).into_response())
+    }
+}
+
+/// Generate tokens with the OpenAI Chat Completions API
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/chat/completions",
+request_body = ChatRequest,
+params(("X-Priority" = Option<Priority>, Header, description = "Priority of the request if it does not set a `priority` parameter")),
+responses(
+(status = 200, description = "Generated Chat Completion",
+content(
+("application/json" = ChatCompletion),
+("text/event-stream" = ChatCompletionChunk),
+)),
+(status = 424, description = "Generation Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded"})),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+(status = 500, description = "Incomplete generation", body = ErrorResponse,
+example = json ! ({"error": "Incomplete generation"})),
+)
+)]
+#[instrument(
+    skip_all,
+    fields(
+        parameters,
+        total_time,
+        validation_time,
+        queue_time,
+        inference_time,
+        time_per_token,
+        seed,
+    )
+)]
+async fn chat_completions(
+    models: Extension<Models>,
+    sse_keep_alive: Extension<SseKeepAlive>,
+    context: RequestContext,
+    req: Json<ChatRequest>,
+) -> Result<Response, (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let (model_id, infer) = models.get(req.0.model.as_deref())?;
+    let infer = Extension(infer);
+    let stream = req.0.stream;
+    let n = infer.validate_n(req.0.n)?;
+
+    let tools = req.0.selected_tools().map_err(|err| {
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        tracing::error!("{err}");
+        InferError::ValidationError(err)
+    })?;
+    let use_tools = tools.is_some();
+
+    // Render the conversation with the chat template of the model
+    let inputs = infer.apply_chat_template(req.0.messages(tools.as_deref()), tools.as_deref())?;
+    let mut req = req.0.into_generate_request(inputs, tools.as_deref());
+    context.apply(&mut req.parameters);
+    span.record("parameters", format!("{:?}", req.parameters));
+
+    let id = format!("chatcmpl-{:016x}", rand::random::<u64>());
+    let created = unix_timestamp();
+
+    if stream {
+        let on_message_callback = move |index: u32| {
+            let id = id.clone();
+            let model_id = model_id.clone();
+            let mut first_message = true;
+            let mut tool_call_text = String::new();
+            move |stream_token: StreamResponse| {
+                let mut finish_reason = stream_token
+                    .details
+                    .map(|details| details.finish_reason.openai().to_string());
+                // Special tokens such as the end of sequence token are not part of the message
+                let mut content = match stream_token.token.special {
+                    true => String::new(),
+                    false => stream_token.token.text,
+                };
+                let mut tool_calls = None;
+                if use_tools {
+                    // Tool calls are only parsed once fully generated, and a text answer is only
+                    // known not to be a tool call then: keep the connection alive with comments
+                    // until then
+                    tool_call_text.push_str(&content);
+                    if finish_reason.is_none() {
+                        return Event::default().comment("");
+                    }
+                    content = std::mem::take(&mut tool_call_text);
+                    if let Some(call) = ToolCall::parse(&content) {
+                        content = String::new();
+                        tool_calls = Some(vec![ChatCompletionToolCallDelta::from(call)]);
+                        finish_reason = Some("tool_calls".to_string());
+                    }
+                }
+                // The role is only sent with the first delta
+                let role = first_message.then(|| "assistant".to_string());
+                first_message = false;
+
+                Event::default()
+                    .json_data(ChatCompletionChunk {
+                        id: id.clone(),
+                        object: "chat.completion.chunk".to_string(),
+                        created,
+                        model: model_id.clone(),
+                        choices: vec![ChatCompletionChunkChoice {
+                            index,
+                            delta: ChatCompletionDelta {
+                                role,
+                                content,
+                                tool_calls,
+                            },
+                            finish_reason,
+                        }],
+                    })
+                    .unwrap()
+            }
+        };
+        let (headers, response_stream) =
+            generate_n_streams(infer, req, n, on_message_callback, span).await;
+        let sse = Sse::new(response_stream).keep_alive(sse_keep_alive.0.into());
+        Ok((headers, sse).into_response())
+    } else {
+        let (headers, input_length, generations) = generate_n(infer, req, n, span).await?;
+
+        let mut completion_tokens = 0;
+        let choices = generations
+            .into_iter()
+            .enumerate()
+            .map(|(index, generation)| {
+                // `details` is always requested by `ChatRequest::into_generate_request`
+                let details = generation.details.unwrap();
+                completion_tokens += details.generated_tokens;
+                let tool_call = match use_tools {
+                    true => ToolCall::parse(&generation.generated_text),
+                    false => None,
+                };
+                match tool_call {
+                    Some(call) => ChatCompletionChoice {
+                        index: index as u32,
+                        message: Message {
+                            tool_calls: Some(vec![call]),
+                            ..Message::new("assistant", "")
+                        },
+                        finish_reason: "tool_calls".to_string(),
+                    },
+                    // The model answered with text or stopped before the end of the tool call
+                    None => ChatCompletionChoice {
+                        index: index as u32,
+                        message: Message::new("assistant", generation.generated_text),
+                        finish_reason: details.finish_reason.openai().to_string(),
+                    },
+                }
+            })
+            .collect();
+        let response = ChatCompletion {
+            id,
+            object: "chat.completion".to_string(),
+            created,
+            model: model_id,
+            choices,
+            usage: Usage {
+                prompt_tokens: input_length,
+                completion_tokens,
+                total_tokens: input_length + completion_tokens,
+            },
+        };
+        Ok((headers, Json(response)).into_response())
+    }
+}
+
+/// Generate `n` independent completions of the same request
+///
+/// Every completion is an independent request: it is validated, queued and prefilled on its own,
+/// so nothing guarantees that they end up in the same batch. The prompt is only counted once in
+/// the returned input length.
+async fn generate_n(
+    infer: Extension<Infer>,
+    req: GenerateRequest,
+    n: usize,
+    span: tracing::Span,
+) -> Result<(HeaderMap, u32, Vec<GenerateResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let generations =
+        futures::future::try_join_all((0..n).map(|index| {
+            generate_internal(infer.clone(), Json(req.completion(index)), None, span.clone())
+        }))
+        .await?;
+
+    let mut generations = generations.into_iter();
+    // `n` is validated to be > 0
+    let (headers, input_length, Json(first)) = generations.next().unwrap();
+    let mut responses = vec![first];
+    responses.extend(generations.map(|(_, _, Json(generation))| generation));
+    Ok((headers, input_length, responses))
+}
+
+/// Stream `n` independent completions of the same request
+///
+/// `on_message_callback` creates the callback of the completion at the given index. The events
+/// of all the completions are interleaved in a single stream, ended by the `[DONE]` sentinel of
+/// the OpenAI API.
+async fn generate_n_streams<F>(
+    infer: Extension<Infer>,
+    req: GenerateRequest,
+    n: usize,
+    on_message_callback: impl Fn(u32) -> F,
+    span: tracing::Span,
+) -> (HeaderMap, impl Stream<Item = Result<Event, Infallible>>)
+where
+    F: FnMut(StreamResponse) -> Event,
+{
+    let mut headers = None;
+    let mut streams = Vec::with_capacity(n);
+    for index in 0..n {
+        let (stream_headers, stream) = generate_stream_internal(
+            infer.clone(),
+            Json(req.completion(index)),
+            on_message_callback(index as u32),
+            span.clone(),
+        )
+        .await;
+        headers.get_or_insert(stream_headers);
+        streams.push(Box::pin(stream));
+    }
+    let done = futures::stream::once(async { Ok(Event::default().data("[DONE]")) });
+    // `n` is validated to be > 0
+    (
+        headers.unwrap(),
+        futures::stream::select_all(streams).chain(done),
+    )
+}
+
+/// Compute embeddings with the OpenAI Embeddings API
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/v1/embeddings",
+request_body = EmbeddingRequest,
+responses(
+(status = 200, description = "Embeddings", body = EmbeddingResponse),
+(status = 424, description = "Embedding Error", body = ErrorResponse,
+example = json ! ({"error": "Request failed during generation"})),
+(status = 429, description = "Model is overloaded", body = ErrorResponse,
+example = json ! ({"error": "Model is overloaded"})),
+(status = 422, description = "Input validation error", body = ErrorResponse,
+example = json ! ({"error": "Input validation error"})),
+)
+)]
+#[instrument(skip_all, fields(total_time, inputs))]
+async fn embeddings(
+    models: Extension<Models>,
+    info: Extension<Info>,
+    req: Json<EmbeddingRequest>,
+) -> Result<(HeaderMap, Json<EmbeddingResponse>), (StatusCode, Json<ErrorResponse>)> {
+    let span = tracing::Span::current();
+    let (model_id, infer) = models.get(req.0.model.as_deref())?;
+    let start_time = Instant::now();
+    metrics::increment_counter!("tgi_embed_request_count");
+
+    let inputs: Vec<String> = req.0.input.into();
+    span.record("inputs", inputs.len());
+    if inputs.len() > info.max_client_batch_size {
+        let err = ValidationError::EmbedInputs(info.max_client_batch_size, inputs.len());
+        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+        tracing::error!("{err}");
+        return Err(InferError::from(err).into());
+    }
+
+    let (embeddings, input_length) = infer.embed(inputs).await?;
+
+    let total_time = start_time.elapsed();
+    span.record("total_time", format!("{total_time:?}"));
+
+    // Headers
+    let mut headers = HeaderMap::new();
+    headers.insert("x-compute-type", "gpu+optimized".parse().unwrap());
+    headers.insert(
+        "x-compute-time",
+        total_time.as_millis().to_string().parse().unwrap(),
+    );
+    headers.insert(
+        "x-total-time",
+        total_time.as_millis().to_string().parse().unwrap(),
+    );
+
+    // Metrics
+    metrics::increment_counter!("tgi_embed_request_success");
+    metrics::histogram!("tgi_embed_request_duration", total_time.as_secs_f64());
+
+    tracing::info!("Success");
+
+    let response = EmbeddingResponse {
+        object: "list".to_string(),
+        data: embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| EmbeddingData {
+                object: "embedding".to_string(),
+                embedding,
+                index: index as u32,
+            })
+            .collect(),
+        model: model_id,
+        usage: EmbeddingUsage {
+            prompt_tokens: input_length,
+            total_tokens: input_length,
+        },
+    };
+    Ok((headers, Json(response)))
+}
+
+/// Generation parameters given by the HTTP request instead of its body
+#[derive(Clone)]
+struct RequestContext {
+    /// Priority given by the `X-Priority` header
+    ///
+    /// It only applies to requests that do not set a `priority` parameter
+    priority: Option<Priority>,
+    /// Tenant of the API key of the request
+    tenant: Option<Tenant>,
+    /// Bucket the generated tokens of the request are taken from
+    rate_limit: Option<RateLimit>,
+    /// Registry the generations of the request are cancelled from, with the request id
+    cancellation: Option<(Cancellations, String)>,
+}
+
+impl RequestContext {
+    /// Cancel the generation of `request_id` if it was sent by the same tenant
+    fn cancel(&self, request_id: &str) {
+        if let Some((cancellations, _)) = &self.cancellation {
+            let tenant = self.tenant.as_ref().map(Tenant::name);
+            if cancellations.cancel(request_id, tenant) {
+                tracing::info!("Generation {request_id} cancelled");
+            }
+        }
+    }
+
+    fn apply(self, parameters: &mut GenerateParameters) {
+        parameters.priority = parameters.priority.or(self.priority);
+        parameters.cancel = self.cancellation.map(|(cancellations, request_id)| {
+            cancellations.register(&request_id, self.tenant.as_ref().map(Tenant::name))
+        });
+        parameters.tenant = self.tenant;
+        parameters.rate_limit = self.rate_limit;
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestContext {
+    type Rejection = (StatusCode, Json<ErrorResponse>);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let priority = match parts.headers.get("x-priority") {
+            Some(value) => Some(
+                String::from_utf8_lossy(value.as_bytes())
+                    .parse::<Priority>()
+                    .map_err(|err| {
+                        metrics::increment_counter!("tgi_request_failure", "err" => "validation");
+                        tracing::error!("{err}");
+                        InferError::ValidationError(err)
+                    })?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            priority,
+            tenant: parts.extensions.get::<Tenant>().cloned(),
+            rate_limit: parts.extensions.get::<RateLimit>().cloned(),
+            cancellation: parts
+                .extensions
+                .get::<Cancellations>()
+                .cloned()
+                .zip(parts.extensions.get::<RequestId>().map(|id| id.0.clone())),
+        })
+    }
+}
+
+/// Seconds since the UNIX epoch
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Tokenize inputs
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/tokenize",
+request_body = GenerateRequest,
+responses(
+(status = 200, description = "Tokenized ids", body = TokenizeResponse),
+(status = 404, description = "No tokenizer found", body = ErrorResponse,
+example = json ! ({"error": "No fast tokenizer available"})),
+)
+)]
+#[instrument(skip_all)]
+async fn tokenize(
+    models: Extension<Models>,
+    req: Json<GenerateRequest>,
+) -> Result<Json<TokenizeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let (_, infer) = models.get(req.0.model.as_deref())?;
+    match infer.tokenize(req.0).await? {
+        Some((encoding, input)) => {
+            // Offsets are byte offsets in the (optionally truncated) input the encoding was
+            // computed on
+            let tokens: Vec<SimpleToken> = encoding
+                .get_ids()
+                .iter()
+                .zip(encoding.get_offsets())
+                .map(|(&id, &(start, stop))| SimpleToken {
+                    id,
+                    text: input.get(start..stop).unwrap_or_default().to_string(),
+                    start,
+                    stop,
+                })
+                .collect();
+            Ok(Json(TokenizeResponse(tokens)))
+        }
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "No fast tokenizer or tokenizer.json for this model".to_string(),
+                error_type: "no fast tokenizer".to_string(),
+                load: None,
+                policy_violation: None,
+            }),
+        )),
+    }
+}
+
+/// Decode token ids
+#[utoipa::path(
+post,
+tag = "Text Generation Inference",
+path = "/detokenize",
+request_body = DetokenizeRequest,
+responses(
+(status = 200, description = "Decoded text", body = DetokenizeResponse),
+(status = 404, description = "No tokenizer found", body = ErrorResponse,
+example = json ! ({"error": "No fast tokenizer available"})),
+)
+)]
+#[instrument(skip_all)]
+async fn detokenize(
+    models: Extension<Models>,
+    req: Json<DetokenizeRequest>,
+) -> Result<Json<DetokenizeResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let req = req.0;
+    let (_, infer) = models.get(req.model.as_deref())?;
+    match infer.detokenize(req.ids, req.skip_special_tokens).await? {
+        Some(text) => Ok(Json(DetokenizeResponse { text })),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            Json(ErrorResponse {
+                error: "No fast tokenizer or tokenizer.json for this model".to_string(),
+                error_type: "no fast tokenizer".to_string(),
+                load: None,
+                policy_violation: None,
+            }),
+        )),
+    }
 }
 
 /// Prometheus metrics scrape endpoint
@@ -502,26 +1855,103 @@ async fn metrics(prom_handle: Extension<PrometheusHandle>) -> String {
     prom_handle.render()
 }
 
+/// CORS configuration of the HTTP API
+#[derive(Clone, Debug)]
+pub struct CorsConfig {
+    /// Any origin is allowed if None
+    pub allow_origin: Option<AllowOrigin>,
+    pub allow_methods: Vec<Method>,
+    pub allow_headers: Vec<http::HeaderName>,
+    /// Allow requests with cookies or an `Authorization` header set by the browser
+    ///
+    /// Requires explicit origins
+    pub allow_credentials: bool,
+}
+
+impl CorsConfig {
+    fn layer(self) -> CorsLayer {
+        CorsLayer::new()
+            .allow_methods(self.allow_methods)
+            .allow_headers(self.allow_headers)
+            .allow_origin(self.allow_origin.unwrap_or(AllowOrigin::any()))
+            .allow_credentials(self.allow_credentials)
+    }
+}
+
+/// Model served by a set of shards
+pub struct Backend {
+    pub model_info: HubModelInfo,
+    pub tokenizer_config: HubTokenizerConfig,
+    pub tokenizer: Option<Tokenizer>,
+    /// Sentinel tokens of the fill-in-the-middle prompts, if the model supports them
+    pub fim_tokens: Option<FimTokens>,
+    /// Maximum number of concurrent requests of the model
+    pub max_concurrent_requests: usize,
+    pub shard_info: ShardInfo,
+    pub client: ShardedClient,
+    /// Maximum number of tokens of a batch supported by the shards
+    pub max_batch_total_tokens: u32,
+}
+
 /// Serving method
+///
+/// `backends` must not be empty. The first backend serves the default model.
 #[allow(clippy::too_many_arguments)]
 pub async fn run(
-    model_info: HubModelInfo,
-    shard_info: ShardInfo,
+    backends: Vec<Backend>,
     compat_return_full_text: bool,
-    max_concurrent_requests: usize,
     max_best_of: usize,
+    max_client_batch_size: usize,
     max_stop_sequences: usize,
+    max_top_n_tokens: u32,
     max_input_length: usize,
     max_total_tokens: usize,
     waiting_served_ratio: f32,
     max_batch_prefill_tokens: u32,
-    max_batch_total_tokens: u32,
     max_waiting_tokens: usize,
-    client: ShardedClient,
-    tokenizer: Option<Tokenizer>,
     validation_workers: usize,
+    max_tokenization_batch_size: usize,
     addr: SocketAddr,
-    allow_origin: Option<AllowOrigin>,
+    grpc_addr: Option<SocketAddr>,
+    cors_config: CorsConfig,
+    api_keys_file: Option<PathBuf>,
+    rate_limit_tokens_per_minute: Option<u32>,
+    audit_config: Option<AuditConfig>,
+    prefix_cache_max_tokens: Option<usize>,
+    max_sessions: Option<usize>,
+    session_ttl: Duration,
+    sse_keep_alive: Duration,
+    ready_max_queue_size: Option<usize>,
+    drain_timeout: Duration,
+    default_parameters_file: Option<PathBuf>,
+    prompt_templates_dir: Option<PathBuf>,
+    content_filter_rules_file: Option<PathBuf>,
+    content_filter_url: Option<String>,
+    moderation_rules_file: Option<PathBuf>,
+    moderation_url: Option<String>,
+    moderation_message: String,
+    moderation_interval_tokens: usize,
+    pii_redaction: Option<RedactionDirection>,
+    usage_log_path: Option<PathBuf>,
+    usage_flush_interval: Duration,
+    admin_api_key: Option<String>,
+    adaptive_batch_total_tokens: bool,
+    target_decode_latency: Option<Duration>,
+    idempotency_window: Option<Duration>,
+    truncation_marker: Option<String>,
+    default_on_overflow: OverflowPolicy,
+    preemption: bool,
+    max_queue_duration: Option<Duration>,
+    max_prefill_chunk_tokens: Option<u32>,
+    max_batch_decode_tokens: Option<u32>,
+    scheduling_policy: SchedulingPolicyKind,
+    priority_aging: Option<Duration>,
+    deduplicate_requests: bool,
+    target_inter_token_latency: Option<Duration>,
+    queue_journal_path: Option<PathBuf>,
+    length_aware_batching: bool,
+    max_starvation: Option<Duration>,
+    shedding_policy: SheddingPolicy,
     ngrok: bool,
     ngrok_authtoken: Option<String>,
     ngrok_edge: Option<String>,
@@ -531,18 +1961,36 @@ pub async fn run(
     #[openapi(
     paths(
     health,
+    health_live,
+    health_ready,
+    usage,
+    cancel_generate,
     get_model_info,
     compat_generate,
     generate,
     generate_stream,
+    generate_ws,
+    completions,
+    chat_completions,
+    embeddings,
+    tokenize,
+    detokenize,
     metrics,
     ),
     components(
     schemas(
     Info,
+    TenantUsage,
+    StreamGranularity,
+    HealthStatus,
+    ModelHealth,
+    ShardHealth,
     CompatGenerateRequest,
     GenerateRequest,
     GenerateParameters,
+    Priority,
+    TruncationSide,
+    OverflowPolicy,
     PrefillToken,
     Token,
     GenerateResponse,
@@ -551,6 +1999,33 @@ pub async fn run(
     FinishReason,
     StreamResponse,
     StreamDetails,
+    QueuePosition,
+    QueueLoad,
+    PolicyViolation,
+    CompletionRequest,
+    Completion,
+    CompletionChoice,
+    Usage,
+    ChatRequest,
+    Message,
+    Tool,
+    FunctionDefinition,
+    ToolCall,
+    FunctionCall,
+    ChatCompletion,
+    ChatCompletionChoice,
+    ChatCompletionChunk,
+    ChatCompletionChunkChoice,
+    ChatCompletionDelta,
+    ChatCompletionToolCallDelta,
+    EmbeddingRequest,
+    EmbeddingResponse,
+    EmbeddingData,
+    EmbeddingUsage,
+    SimpleToken,
+    TokenizeResponse,
+    DetokenizeRequest,
+    DetokenizeResponse,
     ErrorResponse,
     )
     ),
@@ -567,28 +2042,180 @@ pub async fn run(
     )]
     struct ApiDoc;
 
-    // Create state
-    let validation = Validation::new(
-        validation_workers,
-        tokenizer,
-        max_best_of,
-        max_stop_sequences,
-        max_input_length,
-        max_total_tokens,
-    );
-    let generation_health = Arc::new(AtomicBool::new(false));
-    let health_ext = Health::new(client.clone(), generation_health.clone());
-    let infer = Infer::new(
-        client,
-        validation,
-        waiting_served_ratio,
-        max_batch_prefill_tokens,
-        max_batch_total_tokens,
-        max_waiting_tokens,
-        max_concurrent_requests,
-        shard_info.requires_padding,
-        generation_health,
-    );
+    // Audit log
+    let audit_log = audit_config.map(AuditLog::new).transpose()?;
+
+    // Journal of the queued requests, with the requests left queued by the previous run
+    let (queue_journal, journaled_requests) = match queue_journal_path {
+        Some(path) => {
+            let (queue_journal, pending) = QueueJournal::open(&path)?;
+            (Some(queue_journal), pending)
+        }
+        None => (None, Vec::new()),
+    };
+
+    // Default generation parameters, shared by every model
+    let parameter_defaults = default_parameters_file
+        .map(ParameterDefaults::load)
+        .transpose()?;
+    if let Some(parameter_defaults) = &parameter_defaults {
+        parameter_defaults.spawn_reload_task();
+    }
+
+    // Prompt templates, shared by every model
+    let prompt_templates = prompt_templates_dir
+        .map(PromptTemplates::load)
+        .transpose()?;
+    if let Some(prompt_templates) = &prompt_templates {
+        prompt_templates.spawn_reload_task();
+    }
+
+    // Content filter of the prompts, shared by every model
+    let content_filter = if content_filter_rules_file.is_some() || content_filter_url.is_some() {
+        Some(ContentFilter::new(
+            content_filter_rules_file.as_deref(),
+            content_filter_url,
+        )?)
+    } else {
+        None
+    };
+
+    // Moderation of the generated text, shared by every model
+    let output_moderation = if moderation_rules_file.is_some() || moderation_url.is_some() {
+        Some(Arc::new(OutputModeration::new(
+            moderation_rules_file.as_deref(),
+            moderation_url,
+            moderation_message,
+            moderation_interval_tokens,
+        )?))
+    } else {
+        None
+    };
+
+    // Redaction of the personally identifiable information, shared by every model
+    let pii_redactor = pii_redaction.map(|direction| Arc::new(PiiRedactor::new(direction)));
+
+    // Info of the default model
+    let default_backend = backends.first().expect("at least one model must be served");
+    let model_info = default_backend.model_info.clone();
+    let shard_info = default_backend.shard_info.clone();
+    let max_batch_total_tokens = default_backend.max_batch_total_tokens;
+    let max_concurrent_requests = default_backend.max_concurrent_requests;
+
+    // Create the state of every model, each with its own queue and batching task
+    let mut models = Vec::with_capacity(backends.len());
+    let mut health_ext = Vec::with_capacity(backends.len());
+    for backend in backends {
+        let validation = Validation::new(
+            validation_workers,
+            backend.tokenizer,
+            max_best_of,
+            max_stop_sequences,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        )
+        .with_max_tokenization_batch_size(max_tokenization_batch_size)
+        .with_image_tokens(backend.shard_info.image_tokens)
+        .with_embeddings(backend.shard_info.supports_embeddings)
+        .with_prompt_templates(prompt_templates.clone())
+        .with_content_filter(content_filter.clone())
+        .with_pii_redactor(pii_redactor.clone())
+        .with_fim_tokens(backend.fim_tokens)
+        .with_truncation_marker(
+            truncation_marker
+                .clone()
+                .unwrap_or_else(|| DEFAULT_TRUNCATION_MARKER.to_string()),
+        )
+        .with_overflow_policy(default_on_overflow);
+        // The prefixes are only worth tracking if the shards reuse their KV cache
+        let prefix_cache_max_tokens = if backend.shard_info.supports_prefix_caching {
+            prefix_cache_max_tokens
+        } else {
+            if prefix_cache_max_tokens.is_some() {
+                tracing::warn!(
+                    "Model {} does not reuse the KV cache of the prefixes, the prefix cache is disabled",
+                    backend.model_info.model_id
+                );
+            }
+            None
+        };
+        let max_sessions = if backend.shard_info.supports_sessions {
+            max_sessions
+        } else {
+            if max_sessions.is_some() {
+                tracing::warn!(
+                    "Model {} does not pin the KV cache of the sessions, the sessions are disabled",
+                    backend.model_info.model_id
+                );
+            }
+            None
+        };
+        let max_prefill_chunk_tokens = if backend.shard_info.supports_chunked_prefill {
+            max_prefill_chunk_tokens
+        } else {
+            if max_prefill_chunk_tokens.is_some() {
+                tracing::warn!(
+                    "Model {} does not prefill in chunks, the prefills are not split",
+                    backend.model_info.model_id
+                );
+            }
+            None
+        };
+        let generation_health = GenerationHealth::default();
+        health_ext.push(Health::new(
+            backend.model_info.model_id.clone(),
+            backend.client.clone(),
+            generation_health.clone(),
+        ));
+
+        let infer = Infer::new(
+            backend.client.clone(),
+            validation,
+            waiting_served_ratio,
+            max_batch_prefill_tokens,
+            backend.max_batch_total_tokens,
+            max_waiting_tokens,
+            backend.max_concurrent_requests,
+            backend.shard_info.requires_padding,
+            generation_health,
+            backend.tokenizer_config,
+            audit_log.clone(),
+            prefix_cache_max_tokens,
+            max_sessions,
+            session_ttl,
+            parameter_defaults.clone(),
+            adaptive_batch_total_tokens.then_some(AdaptiveBudgetConfig {
+                min_tokens: max_total_tokens as u32,
+                target_decode_latency,
+            }),
+            preemption,
+            max_queue_duration,
+            max_prefill_chunk_tokens,
+            max_batch_decode_tokens,
+            scheduling_policy,
+            priority_aging,
+            deduplicate_requests,
+            target_inter_token_latency,
+            backend.model_info.model_id.clone(),
+            queue_journal.clone(),
+            length_aware_batching,
+            max_starvation,
+            shedding_policy,
+            output_moderation.clone(),
+            pii_redactor.clone(),
+        );
+        models.push((backend.model_info.model_id, infer));
+    }
+    let models = Models::new(models);
+    journal::replay(&models, journaled_requests);
+
+    // Shutdown signal shared by the HTTP and gRPC servers
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_sender.send(true);
+    });
 
     // Duration buckets
     let duration_matcher = Matcher::Suffix(String::from("duration"));
@@ -619,6 +2246,15 @@ pub async fn run(
     // Batch size buckets
     let batch_size_matcher = Matcher::Full(String::from("tgi_batch_next_size"));
     let batch_size_buckets: Vec<f64> = (0..1024).map(|x| (x + 1) as f64).collect();
+    let batch_forward_size_matcher = Matcher::Full(String::from("tgi_batch_forward_size"));
+    // Batch tokens buckets
+    let batch_tokens_matcher = Matcher::Full(String::from("tgi_batch_next_tokens"));
+    let batch_forward_tokens_matcher = Matcher::Full(String::from("tgi_batch_forward_max_tokens"));
+    let batch_prefill_tokens_matcher =
+        Matcher::Full(String::from("tgi_batch_forward_prefill_tokens"));
+    let batch_tokens_buckets: Vec<f64> = (0..100)
+        .map(|x| (max_batch_total_tokens as f64 / 100.0) * (x + 1) as f64)
+        .collect();
 
     // Prometheus handler
     let builder = PrometheusBuilder::new()
@@ -631,17 +2267,21 @@ pub async fn run(
         .set_buckets_for_metric(max_new_tokens_matcher, &max_new_tokens_buckets)
         .unwrap()
         .set_buckets_for_metric(batch_size_matcher, &batch_size_buckets)
+        .unwrap()
+        .set_buckets_for_metric(batch_forward_size_matcher, &batch_size_buckets)
+        .unwrap()
+        .set_buckets_for_metric(batch_tokens_matcher, &batch_tokens_buckets)
+        .unwrap()
+        .set_buckets_for_metric(batch_forward_tokens_matcher, &batch_tokens_buckets)
+        .unwrap()
+        .set_buckets_for_metric(batch_prefill_tokens_matcher, &batch_tokens_buckets)
         .unwrap();
     let prom_handle = builder
         .install_recorder()
         .expect("failed to install metrics recorder");
 
     // CORS layer
-    let allow_origin = allow_origin.unwrap_or(AllowOrigin::any());
-    let cors_layer = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST])
-        .allow_headers([http::header::CONTENT_TYPE])
-        .allow_origin(allow_origin);
+    let cors_layer = cors_config.layer();
 
     // Endpoint info
     let info = Info {
@@ -650,9 +2290,12 @@ pub async fn run(
         model_dtype: shard_info.dtype,
         model_device_type: shard_info.device_type,
         model_pipeline_tag: model_info.pipeline_tag,
+        models: models.ids(),
         max_concurrent_requests,
         max_best_of,
+        max_client_batch_size,
         max_stop_sequences,
+        max_top_n_tokens,
         max_input_length,
         max_total_tokens,
         waiting_served_ratio,
@@ -664,18 +2307,112 @@ pub async fn run(
         docker_label: option_env!("DOCKER_LABEL"),
     };
 
-    // Create router
-    let app = Router::new()
-        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", ApiDoc::openapi()))
+    // Inference routes
+    let mut inference_routes = Router::new()
         // Base routes
         .route("/", post(compat_generate))
-        .route("/info", get(get_model_info))
         .route("/generate", post(generate))
         .route("/generate_stream", post(generate_stream))
+        .route("/generate_ws", get(generate_ws))
+        .route("/tokenize", post(tokenize))
+        .route("/detokenize", post(detokenize))
+        // OpenAI compatible routes
+        .route("/v1/completions", post(completions))
+        .route("/v1/chat/completions", post(chat_completions))
+        .route("/v1/embeddings", post(embeddings))
         // AWS Sagemaker route
-        .route("/invocations", post(compat_generate))
+        .route("/invocations", post(compat_generate));
+
+    // Cancellation route, neither rate limited nor paused with the intake
+    let mut cancel_routes = Router::new().route("/generate/:request_id", delete(cancel_generate));
+
+    // Tell the clients of the requests rejected by the queue when to retry
+    // Added first to only see the responses of the handlers
+    if let Some(max_queue_duration) = max_queue_duration {
+        inference_routes = inference_routes.route_layer(middleware::from_fn_with_state(
+            max_queue_duration,
+            retry_after,
+        ));
+    }
+
+    // Limits of the inference routes, shared with the gRPC front-end
+    let mut grpc_admission = grpc::Admission::default();
+
+    // Limit the generated tokens per minute of each API key or client IP
+    if let Some(tokens_per_minute) = rate_limit_tokens_per_minute {
+        let rate_limiter = RateLimiter::new(tokens_per_minute);
+        grpc_admission.rate_limiter = Some(rate_limiter.clone());
+        inference_routes = inference_routes.route_layer(middleware::from_fn_with_state(
+            rate_limiter,
+            rate_limit::rate_limit,
+        ));
+    }
+
+    // Replay the responses of the retried requests
+    // Added after the rate limiting so that replays do not count against it
+    if let Some(idempotency_window) = idempotency_window {
+        inference_routes = inference_routes.route_layer(middleware::from_fn_with_state(
+            IdempotencyCache::new(idempotency_window),
+            idempotency::idempotency,
+        ));
+    }
+
+    // Authenticate the inference routes with API keys
+    // Added last to run before the rate limiting which depends on the tenant
+    if let Some(api_keys_file) = api_keys_file {
+        let api_keys = ApiKeys::load(api_keys_file)?;
+        api_keys.spawn_reload_task();
+        if let Some(usage_log_path) = usage_log_path {
+            api_keys
+                .usage_tracker()
+                .spawn_flush_thread(usage_log_path, usage_flush_interval)?;
+        }
+        // Usage route, authenticated but not rate limited
+        let usage_routes = Router::new()
+            .route("/usage", get(usage))
+            .route_layer(middleware::from_fn_with_state(api_keys.clone(), auth::auth));
+        cancel_routes =
+            cancel_routes.route_layer(middleware::from_fn_with_state(api_keys.clone(), auth::auth));
+        grpc_admission.api_keys = Some(api_keys.clone());
+        inference_routes = inference_routes
+            .route_layer(middleware::from_fn_with_state(api_keys, auth::auth))
+            .merge(usage_routes);
+    }
+
+    // Administration routes, pausing the intake of the inference routes
+    let mut admin_routes = None;
+    if let Some(admin_api_key) = admin_api_key {
+        let intake = Intake::default();
+        inference_routes = inference_routes.route_layer(middleware::from_fn_with_state(
+            intake.clone(),
+            admin::intake,
+        ));
+        grpc_admission.intake = Some(intake.clone());
+        admin_routes = Some(admin::routes(admin_api_key, intake, models.clone()));
+    }
+
+    // gRPC front-end
+    if let Some(grpc_addr) = grpc_addr {
+        let models = models.clone();
+        let shutdown = shutdown_requested(shutdown_receiver.clone());
+        tokio::spawn(async move {
+            if let Err(err) = grpc::run(models, grpc_admission, grpc_addr, shutdown).await {
+                tracing::error!("gRPC server error: {err}");
+            }
+        });
+    }
+
+    // Create router
+    let mut app = Router::new()
+        .merge(SwaggerUi::new("/docs").url("/api-doc/openapi.json", ApiDoc::openapi()))
+        .merge(inference_routes)
+        .merge(cancel_routes)
+        .route("/info", get(get_model_info))
         // Base Health route
         .route("/health", get(health))
+        // Kubernetes probes
+        .route("/health/live", get(health_live))
+        .route("/health/ready", get(health_ready))
         // Inference API health route
         .route("/", get(health))
         // AWS Sagemaker health route
@@ -685,11 +2422,23 @@ pub async fn run(
         .layer(Extension(info))
         .layer(Extension(health_ext.clone()))
         .layer(Extension(compat_return_full_text))
-        .layer(Extension(infer))
+        .layer(Extension(SseKeepAlive(sse_keep_alive)))
+        .layer(Extension(ReadyMaxQueueSize(ready_max_queue_size)))
+        .layer(Extension(models))
+        .layer(Extension(Cancellations::default()))
         .layer(Extension(prom_handle.clone()))
+        // Compress the responses according to `Accept-Encoding`
+        // Server-Sent Events streams and small responses are never compressed
+        .layer(CompressionLayer::new())
+        // Echo the request id and record it in the logs of the request
+        .layer(middleware::from_fn(request_id::request_id))
         .layer(opentelemetry_tracing_layer())
         .layer(cors_layer);
 
+    if let Some(admin_routes) = admin_routes {
+        app = app.merge(admin_routes);
+    }
+
     if ngrok {
         #[cfg(feature = "ngrok")]
         {
@@ -724,15 +2473,15 @@ pub async fn run(
                             .into_make_service(),
                     )
                     //Wait until all requests are finished to shut down
-                    .with_graceful_shutdown(shutdown_signal()),
+                    .with_graceful_shutdown(shutdown_requested(shutdown_receiver.clone())),
             );
 
             // Run server
-            axum::Server::builder(listener)
+            let server = axum::Server::builder(listener)
                 .serve(app.into_make_service())
                 //Wait until all requests are finished to shut down
-                .with_graceful_shutdown(shutdown_signal())
-                .await?;
+                .with_graceful_shutdown(shutdown_requested(shutdown_receiver.clone()));
+            drain(server, shutdown_receiver, drain_timeout).await?;
         }
         #[cfg(not(feature = "ngrok"))]
         {
@@ -745,15 +2494,51 @@ pub async fn run(
         }
     } else {
         // Run server
-        axum::Server::bind(&addr)
-            .serve(app.into_make_service())
+        let server = axum::Server::bind(&addr)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
             // Wait until all requests are finished to shut down
-            .with_graceful_shutdown(shutdown_signal())
-            .await?;
+            .with_graceful_shutdown(shutdown_requested(shutdown_receiver.clone()));
+        drain(server, shutdown_receiver, drain_timeout).await?;
     }
+    // The shard connections are closed when the backends are dropped
+    tracing::info!("Requests drained, closing the shard connections");
+    opentelemetry::global::shutdown_tracer_provider();
     Ok(())
 }
 
+/// Wait for the in-flight and queued requests of a gracefully shutting down `server` to complete
+///
+/// Once the shutdown signal is received, the server stops accepting new requests. If the
+/// requests are still running `drain_timeout` later, they are dropped.
+async fn drain<E>(
+    server: impl Future<Output = Result<(), E>>,
+    shutdown_receiver: watch::Receiver<bool>,
+    drain_timeout: Duration,
+) -> Result<(), E> {
+    let deadline = async {
+        shutdown_requested(shutdown_receiver).await;
+        tracing::info!("Draining the in-flight requests for at most {drain_timeout:?}");
+        tokio::time::sleep(drain_timeout).await;
+    };
+    tokio::select! {
+        result = server => result,
+        _ = deadline => {
+            tracing::warn!("Drain timeout elapsed, dropping the remaining requests");
+            Ok(())
+        }
+    }
+}
+
+/// Resolves once the shutdown signal has been received
+async fn shutdown_requested(mut shutdown_receiver: watch::Receiver<bool>) {
+    while !*shutdown_receiver.borrow() {
+        // The sender is only dropped once the signal has been sent
+        if shutdown_receiver.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
 /// Shutdown signal handler
 async fn shutdown_signal() {
     let ctrl_c = async {
@@ -779,7 +2564,6 @@ async fn shutdown_signal() {
     }
 
     tracing::info!("signal received, starting graceful shutdown");
-    opentelemetry::global::shutdown_tracer_provider();
 }
 
 impl From<i32> for FinishReason {
@@ -789,6 +2573,8 @@ impl From<i32> for FinishReason {
             text_generation_client::FinishReason::Length => FinishReason::Length,
             text_generation_client::FinishReason::EosToken => FinishReason::EndOfSequenceToken,
             text_generation_client::FinishReason::StopSequence => FinishReason::StopSequence,
+            text_generation_client::FinishReason::Cancelled => FinishReason::Cancelled,
+            text_generation_client::FinishReason::ContentFilter => FinishReason::ContentFilter,
         }
     }
 }
@@ -799,8 +2585,21 @@ impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
         let status_code = match err {
             InferError::GenerationError(_) => StatusCode::FAILED_DEPENDENCY,
             InferError::Overloaded(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::ValidationError(ValidationError::ContentFilter(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             InferError::ValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             InferError::IncompleteGeneration => StatusCode::INTERNAL_SERVER_ERROR,
+            InferError::Timeout => StatusCode::REQUEST_TIMEOUT,
+            InferError::QueueTimeout(_) => StatusCode::TOO_MANY_REQUESTS,
+            InferError::SessionBusy => StatusCode::CONFLICT,
+            InferError::SessionsFull => StatusCode::TOO_MANY_REQUESTS,
+            InferError::ModelNotFound(_) => StatusCode::NOT_FOUND,
+            // Client Closed Request
+            InferError::Cancelled => StatusCode::from_u16(499).unwrap(),
+            InferError::MissingChatTemplate | InferError::TemplateError(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
         };
 
         (
@@ -808,6 +2607,8 @@ impl From<InferError> for (StatusCode, Json<ErrorResponse>) {
             Json(ErrorResponse {
                 error: err.to_string(),
                 error_type: err.error_type().to_string(),
+                load: err.load(),
+                policy_violation: err.policy_violation(),
             }),
         )
     }
@@ -819,6 +2620,8 @@ impl From<InferError> for Event {
             .json_data(ErrorResponse {
                 error: err.to_string(),
                 error_type: err.error_type().to_string(),
+                load: err.load(),
+                policy_violation: err.policy_violation(),
             })
             .unwrap()
     }
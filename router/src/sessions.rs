@@ -0,0 +1,185 @@
+/// Multi-turn sessions reusing the KV cache of their previous turn
+use crate::infer::InferError;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use text_generation_client::ShardedClient;
+use tokio::time::Instant;
+
+#[derive(Debug)]
+struct Session {
+    /// Token ids of the prompt and generated tokens of the last turn, whose KV cache is
+    /// pinned on the shards
+    context: Vec<u32>,
+    last_used: Instant,
+    /// Whether a turn of this session is running
+    busy: bool,
+}
+
+/// Registry of the sessions whose KV cache is pinned on the shards
+///
+/// Idle sessions are evicted after `ttl`, or when the registry is full, starting with the least
+/// recently used one. The shards are then asked to release their KV cache.
+#[derive(Clone, Debug)]
+pub(crate) struct Sessions {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    max_sessions: usize,
+    ttl: Duration,
+    client: ShardedClient,
+}
+
+impl Sessions {
+    pub(crate) fn new(max_sessions: usize, ttl: Duration, client: ShardedClient) -> Self {
+        Self {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            max_sessions,
+            ttl,
+            client,
+        }
+    }
+
+    /// Start a new turn of the session `id` with the token ids of its prompt
+    pub(crate) fn start(&self, id: String, input_ids: &[u32]) -> Result<SessionTurn, InferError> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+
+        // Evict the expired sessions
+        let mut evicted: Vec<String> = sessions
+            .iter()
+            .filter(|(_, session)| {
+                !session.busy && now.duration_since(session.last_used) > self.ttl
+            })
+            .map(|(id, _)| id.clone())
+            .collect();
+
+        if sessions.get(&id).map_or(false, |session| session.busy) {
+            return Err(InferError::SessionBusy);
+        }
+        let expired = evicted.contains(&id);
+        let prefix_len = match sessions.get_mut(&id).filter(|_| !expired) {
+            Some(session) => {
+                session.busy = true;
+                session.last_used = now;
+                // At least one token must be prefilled to get the logits of the first
+                // generated token
+                common_prefix_len(&session.context, input_ids)
+                    .min(input_ids.len().saturating_sub(1))
+            }
+            None => {
+                // The shards replace the KV cache of a restarted session
+                evicted.retain(|evicted| *evicted != id);
+                let active = sessions.len() - evicted.len() - usize::from(expired);
+                if active >= self.max_sessions {
+                    let lru = sessions
+                        .iter()
+                        .filter(|(other, session)| {
+                            !session.busy && **other != id && !evicted.contains(other)
+                        })
+                        .min_by_key(|(_, session)| session.last_used)
+                        .map(|(id, _)| id.clone())
+                        .ok_or(InferError::SessionsFull)?;
+                    evicted.push(lru);
+                }
+                sessions.insert(
+                    id.clone(),
+                    Session {
+                        context: vec![],
+                        last_used: now,
+                        busy: true,
+                    },
+                );
+                0
+            }
+        };
+
+        for id in evicted.iter() {
+            sessions.remove(id);
+        }
+        metrics::gauge!("tgi_sessions", sessions.len() as f64);
+        drop(sessions);
+
+        self.release(evicted);
+        Ok(SessionTurn {
+            sessions: self.clone(),
+            id,
+            prefix_len: prefix_len as u32,
+            generated_ids: vec![],
+            context: None,
+        })
+    }
+
+    /// Evict every idle session
+    pub(crate) fn clear(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let evicted: Vec<String> = sessions
+            .iter()
+            .filter(|(_, session)| !session.busy)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in evicted.iter() {
+            sessions.remove(id);
+        }
+        metrics::gauge!("tgi_sessions", sessions.len() as f64);
+        drop(sessions);
+        self.release(evicted);
+    }
+
+    /// Ask the shards to release the KV cache of the evicted sessions
+    fn release(&self, ids: Vec<String>) {
+        if ids.is_empty() {
+            return;
+        }
+        let mut client = self.client.clone();
+        tokio::spawn(async move {
+            if let Err(err) = client.release_sessions(ids).await {
+                tracing::error!("Could not release sessions: {err}");
+            }
+        });
+    }
+}
+
+/// Running turn of a session
+#[derive(Debug)]
+pub(crate) struct SessionTurn {
+    sessions: Sessions,
+    pub id: String,
+    /// Number of leading input tokens already in the pinned KV cache
+    pub prefix_len: u32,
+    generated_ids: Vec<u32>,
+    /// Context of the session once the turn finished
+    context: Option<Vec<u32>>,
+}
+
+impl SessionTurn {
+    pub(crate) fn push_token(&mut self, token_id: u32) {
+        self.generated_ids.push(token_id);
+    }
+
+    /// The KV cache of the prompt and generated tokens is now pinned on the shards
+    pub(crate) fn finish(&mut self, input_ids: &[u32]) {
+        // Without a fast tokenizer, the prompt tokens are unknown
+        if !input_ids.is_empty() {
+            // The KV cache of the last generated token is never computed
+            self.generated_ids.pop();
+            let mut context = input_ids.to_vec();
+            context.append(&mut self.generated_ids);
+            self.context = Some(context);
+        }
+    }
+}
+
+impl Drop for SessionTurn {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(&self.id) {
+            session.busy = false;
+            session.last_used = Instant::now();
+            // The pinned KV cache is unknown if the turn did not finish
+            session.context = self.context.take().unwrap_or_default();
+        }
+    }
+}
+
+fn common_prefix_len(a: &[u32], b: &[u32]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
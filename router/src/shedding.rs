@@ -0,0 +1,74 @@
+/// Policies deciding which request is rejected when the router is overloaded
+use crate::queue::Entry;
+use crate::Priority;
+use clap::ValueEnum;
+
+/// Fraction of the concurrency limit above which `EarlyDrop` starts rejecting new requests
+const EARLY_DROP_THRESHOLD: f64 = 0.8;
+
+/// Shedding policy of the concurrency limit, selected by the operator
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SheddingPolicy {
+    /// New requests are rejected
+    #[default]
+    RejectNewest,
+    /// The oldest queued request is rejected to admit the new request
+    RejectOldest,
+    /// The queued request of the lowest priority is rejected to admit a new request of a higher
+    /// priority
+    RejectLowestPriority,
+    /// New requests are rejected with a probability rising from 0 to 1 as the requests in flight
+    /// approach the concurrency limit
+    EarlyDrop,
+}
+
+impl SheddingPolicy {
+    /// Whether a new request is dropped before reaching the concurrency limit
+    pub(crate) fn drop_early(self, in_flight: usize, max: usize) -> bool {
+        if self != Self::EarlyDrop {
+            return false;
+        }
+        rand::random::<f64>() < early_drop_probability(in_flight, max)
+    }
+
+    /// Queued entry rejected to admit a new request of `priority`, if any
+    pub(crate) fn victim<'a>(
+        self,
+        entries: impl Iterator<Item = &'a (u64, Entry)>,
+        priority: Priority,
+    ) -> Option<u64> {
+        match self {
+            Self::RejectNewest | Self::EarlyDrop => None,
+            Self::RejectOldest => entries.map(|(id, _)| *id).min(),
+            // The newest of the lowest priority entries waited the least
+            Self::RejectLowestPriority => entries
+                .filter(|(_, entry)| entry.request.priority < priority)
+                .max_by_key(|(id, entry)| (std::cmp::Reverse(entry.request.priority), *id))
+                .map(|(id, _)| *id),
+        }
+    }
+}
+
+/// Probability of dropping a new request with `in_flight` requests out of `max`
+fn early_drop_probability(in_flight: usize, max: usize) -> f64 {
+    let threshold = max as f64 * EARLY_DROP_THRESHOLD;
+    if max == 0 || (in_flight as f64) < threshold {
+        return 0.0;
+    }
+    ((in_flight as f64 - threshold) / (max as f64 - threshold)).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_early_drop_probability() {
+        assert_eq!(early_drop_probability(0, 10), 0.0);
+        assert_eq!(early_drop_probability(7, 10), 0.0);
+        assert!((early_drop_probability(9, 10) - 0.5).abs() < 1e-9);
+        assert_eq!(early_drop_probability(10, 10), 1.0);
+        assert!(!SheddingPolicy::RejectNewest.drop_early(10, 10));
+        assert!(SheddingPolicy::EarlyDrop.drop_early(10, 10));
+    }
+}
@@ -0,0 +1,64 @@
+/// Regex stop sequences evaluated by the router on the generated text
+use regex::Regex;
+
+/// Generated text of a request with regex stop sequences
+///
+/// The shards only stop on literal stop sequences: the regexes are matched on the text generated
+/// so far after every token, and the generation is stopped by the router at the first match
+#[derive(Debug)]
+pub(crate) struct StopRegexTracker {
+    regexes: Vec<Regex>,
+    generated: String,
+    generated_tokens: u32,
+}
+
+impl StopRegexTracker {
+    pub(crate) fn new(regexes: Vec<Regex>) -> Self {
+        Self {
+            regexes,
+            generated: String::new(),
+            generated_tokens: 0,
+        }
+    }
+
+    /// Add the text of a generated token and return whether a regex matches the generated text
+    pub(crate) fn push(&mut self, text: &str) -> bool {
+        self.generated.push_str(text);
+        self.generated_tokens += 1;
+        self.regexes
+            .iter()
+            .any(|regex| regex.is_match(&self.generated))
+    }
+
+    /// Text generated so far
+    pub(crate) fn generated(&self) -> &str {
+        &self.generated
+    }
+
+    pub(crate) fn generated_tokens(&self) -> u32 {
+        self.generated_tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_regex_tracker() {
+        let mut tracker = StopRegexTracker::new(vec![
+            Regex::new(r"\n\s*\n").unwrap(),
+            Regex::new(r"Answer: \d+").unwrap(),
+        ]);
+        assert!(!tracker.push("Answer:"));
+        assert!(!tracker.push(" 4"));
+        assert!(tracker.push("2."));
+        assert_eq!(tracker.generated(), "Answer: 42.");
+        assert_eq!(tracker.generated_tokens(), 3);
+
+        // Matches spanning several tokens
+        let mut tracker = StopRegexTracker::new(vec![Regex::new(r"\n\s*\n").unwrap()]);
+        assert!(!tracker.push("Hello\n "));
+        assert!(tracker.push(" \nWorld"));
+    }
+}
@@ -0,0 +1,152 @@
+/// Chat template rendering logic
+use crate::{HubTokenizerConfig, Message, Tool};
+use minijinja::{Environment, ErrorKind};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// Chat template from the `chat_template` field of `tokenizer_config.json`
+///
+/// The environment is built once per model and shared by the clones of the template. Compiled
+/// templates must borrow their source for the lifetime of the environment, so the source is
+/// compiled again on each render instead, which is negligible next to the generation.
+#[derive(Clone)]
+pub(crate) struct ChatTemplate {
+    env: Arc<Environment<'static>>,
+    source: Arc<str>,
+    bos_token: Option<String>,
+    eos_token: Option<String>,
+}
+
+impl ChatTemplate {
+    /// Build the chat template of the model
+    ///
+    /// Returns None if the model does not define a chat template or if it fails to compile
+    pub(crate) fn new(tokenizer_config: HubTokenizerConfig) -> Option<Self> {
+        let source: Arc<str> = tokenizer_config.chat_template?.into();
+
+        // Reject the templates that do not compile once instead of on every request
+        if let Err(err) = Environment::new().template_from_str(&source) {
+            tracing::warn!("Could not compile the chat template: {err}");
+            return None;
+        }
+
+        let mut env = Environment::new();
+        env.add_function("raise_exception", raise_exception);
+
+        Some(Self {
+            env: Arc::new(env),
+            source,
+            bos_token: tokenizer_config
+                .bos_token
+                .map(|token| token.as_str().to_string()),
+            eos_token: tokenizer_config
+                .eos_token
+                .map(|token| token.as_str().to_string()),
+        })
+    }
+
+    /// Render a list of messages and the tools the model can call to a prompt
+    pub(crate) fn apply(
+        &self,
+        messages: Vec<Message>,
+        tools: Option<&[Tool]>,
+    ) -> Result<String, minijinja::Error> {
+        self.env.render_str(
+            &self.source,
+            ChatTemplateInputs {
+                messages,
+                tools,
+                bos_token: self.bos_token.as_deref(),
+                eos_token: self.eos_token.as_deref(),
+                add_generation_prompt: true,
+            },
+        )
+    }
+}
+
+/// Variables available in the chat template
+#[derive(Serialize)]
+struct ChatTemplateInputs<'a> {
+    messages: Vec<Message>,
+    tools: Option<&'a [Tool]>,
+    bos_token: Option<&'a str>,
+    eos_token: Option<&'a str>,
+    add_generation_prompt: bool,
+}
+
+/// Templates use `raise_exception` to reject invalid conversations
+fn raise_exception(err_text: String) -> Result<String, minijinja::Error> {
+    Err(minijinja::Error::new(ErrorKind::InvalidOperation, err_text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TokenizerConfigToken;
+
+    fn tokenizer_config(chat_template: &str) -> HubTokenizerConfig {
+        HubTokenizerConfig {
+            chat_template: Some(chat_template.to_string()),
+            bos_token: Some(TokenizerConfigToken::String("<s>".to_string())),
+            eos_token: Some(TokenizerConfigToken::Object {
+                content: "</s>".to_string(),
+            }),
+        }
+    }
+
+    fn messages() -> Vec<Message> {
+        vec![
+            Message::new("user", "Hi!"),
+            Message::new("assistant", "Hello how can I help?"),
+            Message::new("user", "What is Deep Learning?"),
+        ]
+    }
+
+    #[test]
+    fn test_chat_template() {
+        let template = ChatTemplate::new(tokenizer_config(
+            "{{ bos_token }}{% for message in messages %}{{ message['role'] + ': ' + message['content'] + eos_token }}{% endfor %}{% if add_generation_prompt %}assistant: {% endif %}",
+        ))
+        .unwrap();
+
+        let prompt = template.apply(messages(), None).unwrap();
+        assert_eq!(
+            prompt,
+            "<s>user: Hi!</s>assistant: Hello how can I help?</s>user: What is Deep Learning?</s>assistant: "
+        );
+    }
+
+    #[test]
+    fn test_chat_template_raise_exception() {
+        let template = ChatTemplate::new(tokenizer_config(
+            "{% if messages[0]['role'] != 'system' %}{{ raise_exception('Conversation must start with a system message') }}{% endif %}",
+        ))
+        .unwrap();
+
+        assert!(template.apply(messages(), None).is_err());
+    }
+
+    #[test]
+    fn test_chat_template_tools() {
+        let template = ChatTemplate::new(tokenizer_config(
+            "{% if tools %}{% for tool in tools %}{{ tool['function']['name'] + '\n' }}{% endfor %}{% endif %}{{ messages[-1]['content'] }}",
+        ))
+        .unwrap();
+        let tools: Vec<Tool> = serde_json::from_value(serde_json::json!([
+            {"type": "function", "function": {"name": "get_current_weather"}},
+            {"type": "function", "function": {"name": "get_time"}},
+        ]))
+        .unwrap();
+
+        let prompt = template.apply(messages(), Some(&tools)).unwrap();
+        assert_eq!(
+            prompt,
+            "get_current_weather\nget_time\nWhat is Deep Learning?"
+        );
+    }
+
+    #[test]
+    fn test_chat_template_missing() {
+        assert!(ChatTemplate::new(HubTokenizerConfig::default()).is_none());
+    }
+}
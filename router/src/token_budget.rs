@@ -0,0 +1,188 @@
+/// Token budget of the batches adapted to the feedback of the shards
+use std::time::Duration;
+use text_generation_client::ClientError;
+
+/// Number of consecutive decoding steps without pressure before the budget grows again
+const GROW_AFTER_STEPS: u32 = 100;
+
+/// Configuration of the adaptive token budget
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct AdaptiveBudgetConfig {
+    /// Lowest budget, which must fit the largest request
+    pub(crate) min_tokens: u32,
+    /// Decoding steps slower than this shrink the budget
+    pub(crate) target_decode_latency: Option<Duration>,
+}
+
+/// Effective `max_batch_total_tokens` of the batching task
+///
+/// When adaptive, the budget shrinks when a shard runs out of memory or when the decoding steps
+/// are slower than the target latency, instead of failing every batch as long-context traffic
+/// fills the KV cache. It then slowly grows back to the configured maximum.
+#[derive(Debug)]
+pub(crate) struct TokenBudget {
+    max_tokens: u32,
+    current: u32,
+    config: Option<AdaptiveBudgetConfig>,
+    /// Consecutive decoding steps without pressure
+    calm_steps: u32,
+    /// Number of free tokens of the KV cache last reported by the shards
+    free_tokens: Option<u32>,
+}
+
+impl TokenBudget {
+    pub(crate) fn new(max_tokens: u32, config: Option<AdaptiveBudgetConfig>) -> Self {
+        metrics::gauge!("tgi_batch_token_budget", max_tokens as f64);
+        Self {
+            max_tokens,
+            current: max_tokens,
+            config,
+            calm_steps: 0,
+            free_tokens: None,
+        }
+    }
+
+    pub(crate) fn get(&self) -> u32 {
+        self.current
+    }
+
+    /// Cap the token budget of a new batch to the free tokens of the KV cache, if reported by the
+    /// shards. The shards allocate the blocks of the tokens a request can generate when it is
+    /// prefilled, so the free tokens are all available to the new batch.
+    pub(crate) fn cap_to_free_tokens(&self, new_batch_tokens: u32) -> u32 {
+        match self.free_tokens {
+            Some(free_tokens) => new_batch_tokens.min(free_tokens),
+            None => new_batch_tokens,
+        }
+    }
+
+    /// Record the free blocks of the KV cache reported by the shards after a forward
+    pub(crate) fn on_free_blocks(&mut self, free_blocks: Option<u32>, block_size: u32) {
+        self.free_tokens = free_blocks.map(|free_blocks| free_blocks.saturating_mul(block_size));
+        if let Some(free_tokens) = self.free_tokens {
+            metrics::gauge!("tgi_batch_free_tokens", free_tokens as f64);
+        }
+    }
+
+    /// Shrink the budget if a forward failed because a shard ran out of memory
+    pub(crate) fn on_error(&mut self, err: &ClientError) {
+        if is_out_of_memory(err) {
+            self.shrink(0.75, "oom");
+        }
+    }
+
+    /// Adapt the budget to the latency of a successful decoding step
+    pub(crate) fn on_decode(&mut self, latency: Duration) {
+        let Some(config) = self.config else {
+            return;
+        };
+        if config
+            .target_decode_latency
+            .map_or(false, |target| latency > target)
+        {
+            self.shrink(0.9, "latency");
+            return;
+        }
+        self.calm_steps += 1;
+        if self.calm_steps >= GROW_AFTER_STEPS && self.current < self.max_tokens {
+            self.calm_steps = 0;
+            let grown = (self.current as f64 * 1.05).ceil() as u32;
+            self.set(grown.min(self.max_tokens));
+        }
+    }
+
+    fn shrink(&mut self, factor: f64, reason: &'static str) {
+        let Some(config) = self.config else {
+            return;
+        };
+        self.calm_steps = 0;
+        let shrunk = ((self.current as f64 * factor) as u32).max(config.min_tokens);
+        if shrunk < self.current {
+            metrics::increment_counter!("tgi_batch_token_budget_shrink", "reason" => reason);
+            tracing::warn!("Shrinking the batch token budget to {shrunk} ({reason})");
+            self.set(shrunk);
+        }
+    }
+
+    fn set(&mut self, tokens: u32) {
+        self.current = tokens;
+        metrics::gauge!("tgi_batch_token_budget", tokens as f64);
+    }
+}
+
+/// Whether the error is an out of memory error of a shard
+fn is_out_of_memory(err: &ClientError) -> bool {
+    match err {
+        ClientError::Generation(message) => {
+            let message = message.to_ascii_lowercase();
+            message.contains("out of memory") || message.contains("outofmemory")
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oom() -> ClientError {
+        ClientError::Generation("CUDA out of memory. Tried to allocate 2.00 GiB".to_string())
+    }
+
+    #[test]
+    fn test_token_budget_fixed() {
+        let mut budget = TokenBudget::new(1000, None);
+        budget.on_error(&oom());
+        budget.on_decode(Duration::from_secs(10));
+        assert_eq!(budget.get(), 1000);
+    }
+
+    #[test]
+    fn test_token_budget_oom() {
+        let config = AdaptiveBudgetConfig {
+            min_tokens: 600,
+            target_decode_latency: None,
+        };
+        let mut budget = TokenBudget::new(1000, Some(config));
+        budget.on_error(&ClientError::Generation("Unexpected error".to_string()));
+        assert_eq!(budget.get(), 1000);
+        budget.on_error(&oom());
+        assert_eq!(budget.get(), 750);
+        // Never below the minimum
+        budget.on_error(&oom());
+        assert_eq!(budget.get(), 600);
+
+        for _ in 0..GROW_AFTER_STEPS {
+            budget.on_decode(Duration::from_millis(10));
+        }
+        assert_eq!(budget.get(), 630);
+    }
+
+    #[test]
+    fn test_token_budget_free_blocks() {
+        let mut budget = TokenBudget::new(1000, None);
+        assert_eq!(budget.cap_to_free_tokens(400), 400);
+        // The KV cache is fuller than estimated
+        budget.on_free_blocks(Some(10), 16);
+        assert_eq!(budget.cap_to_free_tokens(400), 160);
+        assert_eq!(budget.cap_to_free_tokens(100), 100);
+        budget.on_free_blocks(Some(0), 16);
+        assert_eq!(budget.cap_to_free_tokens(400), 0);
+        // Shards not reporting their free blocks
+        budget.on_free_blocks(None, 16);
+        assert_eq!(budget.cap_to_free_tokens(400), 400);
+    }
+
+    #[test]
+    fn test_token_budget_latency() {
+        let config = AdaptiveBudgetConfig {
+            min_tokens: 100,
+            target_decode_latency: Some(Duration::from_millis(50)),
+        };
+        let mut budget = TokenBudget::new(1000, Some(config));
+        budget.on_decode(Duration::from_millis(40));
+        assert_eq!(budget.get(), 1000);
+        budget.on_decode(Duration::from_millis(60));
+        assert_eq!(budget.get(), 900);
+    }
+}
@@ -0,0 +1,150 @@
+/// Usage accounting of the tenants
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// Consumption of a tenant
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, ToSchema)]
+pub(crate) struct TenantUsage {
+    /// Finished generation requests
+    #[schema(example = 12)]
+    pub requests: u64,
+    #[schema(example = 2048)]
+    pub prompt_tokens: u64,
+    #[schema(example = 512)]
+    pub generated_tokens: u64,
+}
+
+impl TenantUsage {
+    fn add(&mut self, prompt_tokens: u32, generated_tokens: u32) {
+        self.requests += 1;
+        self.prompt_tokens += prompt_tokens as u64;
+        self.generated_tokens += generated_tokens as u64;
+    }
+}
+
+#[derive(Debug, Default)]
+struct UsageState {
+    /// Usage since the router started
+    total: HashMap<String, TenantUsage>,
+    /// Usage not yet written to the usage log
+    unflushed: HashMap<String, TenantUsage>,
+}
+
+/// Usage of every tenant, kept across reloads of the API keys file
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UsageTracker {
+    state: Arc<Mutex<UsageState>>,
+}
+
+impl UsageTracker {
+    /// Count a finished request of `tenant`
+    pub(crate) fn record(&self, tenant: &str, prompt_tokens: u32, generated_tokens: u32) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .total
+            .entry(tenant.to_string())
+            .or_default()
+            .add(prompt_tokens, generated_tokens);
+        state
+            .unflushed
+            .entry(tenant.to_string())
+            .or_default()
+            .add(prompt_tokens, generated_tokens);
+    }
+
+    /// Usage of `tenant` since the router started
+    pub(crate) fn get(&self, tenant: &str) -> TenantUsage {
+        self.state
+            .lock()
+            .unwrap()
+            .total
+            .get(tenant)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Usage since the last call
+    fn take_unflushed(&self) -> HashMap<String, TenantUsage> {
+        std::mem::take(&mut self.state.lock().unwrap().unflushed)
+    }
+
+    /// Append the usage of every tenant since the last flush to the JSONL file at `path` every
+    /// `interval`
+    ///
+    /// Records are written by a background thread so that the batching task never blocks on IO
+    pub(crate) fn spawn_flush_thread(
+        &self,
+        path: PathBuf,
+        interval: Duration,
+    ) -> std::io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let usage = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let mut records = Vec::new();
+            for (tenant, usage) in usage.take_unflushed() {
+                let record = UsageRecord {
+                    timestamp,
+                    tenant,
+                    usage,
+                };
+                // Serializing plain structs cannot fail
+                serde_json::to_writer(&mut records, &record).unwrap();
+                records.push(b'\n');
+            }
+            if let Err(err) = file.write_all(&records).and_then(|_| file.flush()) {
+                metrics::increment_counter!("tgi_usage_log_failure");
+                tracing::error!("Could not write to the usage log: {err}");
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Line of the usage log
+#[derive(Serialize)]
+struct UsageRecord {
+    /// Unix timestamp in seconds of the end of the period
+    timestamp: u64,
+    tenant: String,
+    #[serde(flatten)]
+    usage: TenantUsage,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_tracker() {
+        let usage = UsageTracker::default();
+        usage.record("acme", 10, 5);
+        usage.record("acme", 3, 2);
+        usage.record("globex", 1, 1);
+
+        let expected = TenantUsage {
+            requests: 2,
+            prompt_tokens: 13,
+            generated_tokens: 7,
+        };
+        assert_eq!(usage.get("acme"), expected);
+        assert_eq!(usage.get("unknown"), TenantUsage::default());
+
+        let unflushed = usage.take_unflushed();
+        assert_eq!(unflushed.len(), 2);
+        assert_eq!(unflushed["acme"], expected);
+        assert!(usage.take_unflushed().is_empty());
+        // The total is kept after a flush
+        assert_eq!(usage.get("acme"), expected);
+    }
+}
@@ -1,24 +1,93 @@
+use crate::auth::Tenant;
+use crate::content_filter::{ContentFilter, PolicyViolation};
+use crate::fim::FimTokens;
+use crate::gbnf::gbnf_to_automaton;
+use crate::grammar::{
+    choices_to_regex, json_schema_to_regex, regex_to_automaton, validate_regex, GrammarCache,
+    GrammarError,
+};
+use crate::image::{input_chunks, split_inputs, InputPart, MAX_IMAGES};
+use crate::prompt_templates::PromptTemplates;
+use crate::rate_limit::RateLimit;
+use crate::redaction::PiiRedactor;
 /// Payload validation logic
 use crate::validation::ValidationError::{BestOfSampling, BestOfSeed, EmptyInput};
-use crate::{GenerateParameters, GenerateRequest};
+use crate::{
+    GenerateParameters, GenerateRequest, GrammarType, OverflowPolicy, Priority, TruncationSide,
+};
 use rand::{thread_rng, Rng};
-use text_generation_client::{NextTokenChooserParameters, StoppingCriteriaParameters};
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use text_generation_client::{
+    Chunk, GrammarType as ProtoGrammarType, InputChunk, NextTokenChooserParameters,
+    StoppingCriteriaParameters, TokenIds,
+};
 use thiserror::Error;
 use tokenizers::tokenizer::Tokenizer;
-use tokenizers::TruncationDirection;
+use tokenizers::{Encoding, TruncationDirection};
 use tokio::sync::oneshot;
+use tokio::time::Instant;
 use tracing::{instrument, Span};
 
+/// Maximum number of compiled grammars kept in memory
+const GRAMMAR_CACHE_CAPACITY: usize = 128;
+
+/// Maximum length in bytes of a session id
+const MAX_SESSION_ID_LENGTH: usize = 256;
+
+/// Maximum size of the n-grams that cannot be repeated
+const MAX_NO_REPEAT_NGRAM_SIZE: u32 = 20;
+
+/// Maximum number of banned words of a request
+const MAX_BAD_WORDS: usize = 32;
+
+/// Maximum number of stop token ids of a request
+const MAX_STOP_TOKEN_IDS: usize = 32;
+/// Text replacing the middle of the inputs truncated with `TruncationSide::Middle`
+pub(crate) const DEFAULT_TRUNCATION_MARKER: &str = "\n...\n";
+/// Number of encodings of the inputs truncated in the middle before giving up on the marker
+const MIDDLE_TRUNCATION_ATTEMPTS: usize = 3;
+
 /// Validation
 #[derive(Debug, Clone)]
 pub struct Validation {
     /// Validation parameters
     max_best_of: usize,
     max_stop_sequences: usize,
+    max_top_n_tokens: u32,
     max_input_length: usize,
     max_total_tokens: usize,
+    /// Size of the tokenizer vocabulary, if we have a fast tokenizer
+    vocab_size: Option<usize>,
+    /// Special tokens of the tokenizer by id, empty without a fast tokenizer
+    special_tokens: HashMap<u32, String>,
+    /// Compiled grammars
+    grammar_cache: Arc<Mutex<GrammarCache>>,
+    /// Sentinel tokens of the fill-in-the-middle prompts, if the model supports them
+    fim_tokens: Option<FimTokens>,
+    /// Text replacing the middle of the inputs truncated with `TruncationSide::Middle`
+    truncation_marker: String,
+    /// Overflow policy of the requests without `on_overflow`
+    overflow_policy: OverflowPolicy,
+    /// Prompt templates requests can reference by name
+    prompt_templates: Option<PromptTemplates>,
+    /// Filter rejecting or rewriting the prompts
+    content_filter: Option<ContentFilter>,
+    /// Redaction of the personally identifiable information of the prompts
+    pii_redactor: Option<Arc<PiiRedactor>>,
+    /// Number of input tokens of an image, 0 if the model does not take images
+    image_tokens: u32,
+    /// Client downloading the image URLs, if the model takes images
+    image_client: Option<reqwest::Client>,
+    /// Whether the model computes embeddings
+    embeddings: bool,
+    /// Maximum number of inputs encoded together by a tokenization worker
+    max_tokenization_batch_size: Arc<AtomicUsize>,
     /// Channel to communicate with the background tokenization task
-    sender: Option<flume::Sender<TokenizerRequest>>,
+    sender: Option<flume::Sender<(TokenizerRequest, Instant)>>,
 }
 
 impl Validation {
@@ -27,9 +96,15 @@ impl Validation {
         tokenizer: Option<Tokenizer>,
         max_best_of: usize,
         max_stop_sequences: usize,
+        max_top_n_tokens: u32,
         max_input_length: usize,
         max_total_tokens: usize,
     ) -> Self {
+        let vocab_size = tokenizer.as_ref().map(vocab_size);
+        let special_tokens = tokenizer.as_ref().map(special_tokens).unwrap_or_default();
+        // Requests are encoded one at a time until `with_max_tokenization_batch_size`
+        let max_tokenization_batch_size = Arc::new(AtomicUsize::new(1));
+
         // If we have a fast tokenizer
         let sender = if let Some(tokenizer) = tokenizer {
             // Create channel
@@ -39,10 +114,11 @@ impl Validation {
             for _ in 0..workers {
                 let tokenizer_clone = tokenizer.clone();
                 let receiver_clone = validation_receiver.clone();
+                let max_batch_size_clone = max_tokenization_batch_size.clone();
 
                 // Spawn worker
                 tokio::task::spawn_blocking(move || {
-                    tokenizer_worker(tokenizer_clone, receiver_clone)
+                    tokenizer_worker(tokenizer_clone, receiver_clone, max_batch_size_clone)
                 });
             }
             Some(validation_sender)
@@ -54,18 +130,141 @@ impl Validation {
             max_best_of,
             sender,
             max_stop_sequences,
+            max_top_n_tokens,
             max_input_length,
             max_total_tokens,
+            vocab_size,
+            special_tokens,
+            grammar_cache: Arc::new(Mutex::new(GrammarCache::new(GRAMMAR_CACHE_CAPACITY))),
+            fim_tokens: None,
+            truncation_marker: DEFAULT_TRUNCATION_MARKER.to_string(),
+            overflow_policy: OverflowPolicy::Error,
+            prompt_templates: None,
+            content_filter: None,
+            pii_redactor: None,
+            image_tokens: 0,
+            image_client: None,
+            embeddings: false,
+            max_tokenization_batch_size,
         }
     }
 
+    /// Render the prompt templates referenced by the requests from `prompt_templates`
+    pub(crate) fn with_prompt_templates(
+        mut self,
+        prompt_templates: Option<PromptTemplates>,
+    ) -> Self {
+        self.prompt_templates = prompt_templates;
+        self
+    }
+
+    /// Reject or rewrite the prompts with `content_filter` before they are queued
+    pub(crate) fn with_content_filter(mut self, content_filter: Option<ContentFilter>) -> Self {
+        self.content_filter = content_filter;
+        self
+    }
+
+    /// Mask the personally identifiable information of the prompts with `pii_redactor`, if it
+    /// redacts the prompts
+    pub(crate) fn with_pii_redactor(mut self, pii_redactor: Option<Arc<PiiRedactor>>) -> Self {
+        self.pii_redactor = pii_redactor.filter(|pii_redactor| pii_redactor.redacts_prompts());
+        self
+    }
+
+    /// Accept images in the inputs, each counting for `image_tokens` input tokens
+    ///
+    /// Images are not accepted if `image_tokens` is 0
+    pub(crate) fn with_image_tokens(mut self, image_tokens: u32) -> Self {
+        self.image_tokens = image_tokens;
+        self.image_client = (image_tokens > 0).then(reqwest::Client::new);
+        self
+    }
+
+    /// Accept the embedding requests if the model computes embeddings
+    pub(crate) fn with_embeddings(mut self, embeddings: bool) -> Self {
+        self.embeddings = embeddings;
+        self
+    }
+
+    /// Let the tokenization workers encode up to `max_batch_size` queued inputs together
+    pub(crate) fn with_max_tokenization_batch_size(self, max_batch_size: usize) -> Self {
+        self.max_tokenization_batch_size
+            .store(max_batch_size.max(1), Ordering::Relaxed);
+        self
+    }
+
+    /// Assemble the requests with a `suffix` into fill-in-the-middle prompts with `fim_tokens`
+    pub(crate) fn with_fim_tokens(mut self, fim_tokens: Option<FimTokens>) -> Self {
+        self.fim_tokens = fim_tokens;
+        self
+    }
+
+    /// Apply `overflow_policy` to the requests that do not set `on_overflow`
+    pub(crate) fn with_overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Replace the middle of the inputs truncated with `TruncationSide::Middle` with `marker`
+    pub(crate) fn with_truncation_marker(mut self, marker: String) -> Self {
+        self.truncation_marker = marker;
+        self
+    }
+
+    /// Tokenize `inputs` with the background tokenization task and optionally truncate them
+    ///
+    /// The special tokens of the tokenizer are only added with `add_special_tokens`, prompts
+    /// rendered by a chat template already contain them. Returns None if the router does not have
+    /// a fast tokenizer
     #[instrument(skip_all)]
-    async fn validate_input(
+    pub(crate) async fn tokenize(
         &self,
         inputs: String,
         truncate: Option<usize>,
-        max_new_tokens: u32,
-    ) -> Result<(String, usize), ValidationError> {
+        truncation_side: TruncationSide,
+        add_special_tokens: bool,
+    ) -> Result<Option<(Encoding, String)>, ValidationError> {
+        // If we have a fast tokenizer
+        if let Some(sender) = &self.sender {
+            // Create response channel
+            let (response_sender, response_receiver) = oneshot::channel();
+            // Send request to the background validation task
+            // Unwrap is safe here
+            sender
+                .send((
+                    TokenizerRequest::Encode(
+                        (
+                            inputs,
+                            truncate,
+                            truncation_side,
+                            self.truncation_marker.clone(),
+                            add_special_tokens,
+                        ),
+                        response_sender,
+                        Span::current(),
+                    ),
+                    Instant::now(),
+                ))
+                .unwrap();
+
+            // Await on response channel
+            // Unwrap is safe here
+            let encoding = response_receiver.await.unwrap()?;
+            Ok(Some(encoding))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Decode token `ids` with the background tokenization task
+    ///
+    /// Returns None if the router does not have a fast tokenizer
+    #[instrument(skip_all)]
+    pub(crate) async fn detokenize(
+        &self,
+        ids: Vec<u32>,
+        skip_special_tokens: bool,
+    ) -> Result<Option<String>, ValidationError> {
         // If we have a fast tokenizer
         if let Some(sender) = &self.sender {
             // Create response channel
@@ -73,41 +272,112 @@ impl Validation {
             // Send request to the background validation task
             // Unwrap is safe here
             sender
-                .send(((inputs, truncate), response_sender, Span::current()))
+                .send((
+                    TokenizerRequest::Decode(
+                        (ids, skip_special_tokens),
+                        response_sender,
+                        Span::current(),
+                    ),
+                    Instant::now(),
+                ))
                 .unwrap();
 
             // Await on response channel
             // Unwrap is safe here
-            let (inputs, input_length) = response_receiver.await.unwrap()?;
+            let text = response_receiver.await.unwrap()?;
+            Ok(Some(text))
+        } else {
+            Ok(None)
+        }
+    }
 
-            // Get total tokens
-            let total_tokens = input_length + max_new_tokens as usize;
+    /// Tokenize the banned words into the token sequences the shards must never generate
+    ///
+    /// Most tokenizers encode a word differently at the start of a text and after a space, so
+    /// both variants are banned
+    #[instrument(skip_all)]
+    async fn tokenize_bad_words(
+        &self,
+        bad_words: Vec<String>,
+    ) -> Result<Vec<TokenIds>, ValidationError> {
+        if bad_words.is_empty() {
+            return Ok(vec![]);
+        }
+        if bad_words.len() > MAX_BAD_WORDS {
+            return Err(ValidationError::BadWords(MAX_BAD_WORDS, bad_words.len()));
+        }
+        if bad_words.iter().any(|word| word.trim().is_empty()) {
+            return Err(ValidationError::EmptyBadWord);
+        }
+        // The words can only be banned if the router can tokenize them
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or(ValidationError::BadWordsTokenizer)?;
 
-            // Validate MaxTotalTokens
-            if total_tokens > self.max_total_tokens {
-                return Err(ValidationError::MaxTotalTokens(
-                    self.max_total_tokens,
-                    input_length,
-                    max_new_tokens,
-                ));
+        let mut words = Vec::with_capacity(bad_words.len() * 2);
+        for word in bad_words {
+            if !word.starts_with(char::is_whitespace) {
+                words.push(format!(" {word}"));
             }
+            words.push(word);
+        }
 
-            // Validate InputLength
-            if input_length > self.max_input_length {
-                return Err(ValidationError::InputLength(
-                    self.max_input_length,
-                    input_length,
-                ));
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        // Unwrap is safe here
+        sender
+            .send((
+                TokenizerRequest::EncodeWords(words, response_sender, Span::current()),
+                Instant::now(),
+            ))
+            .unwrap();
+
+        // Await on response channel
+        // Unwrap is safe here
+        let sequences = response_receiver.await.unwrap()?;
+        let mut bad_words_ids: Vec<TokenIds> = Vec::with_capacity(sequences.len());
+        for ids in sequences {
+            if !ids.is_empty() && !bad_words_ids.iter().any(|banned| banned.ids == ids) {
+                bad_words_ids.push(TokenIds { ids });
             }
+        }
+        Ok(bad_words_ids)
+    }
 
-            metrics::histogram!("tgi_request_input_length", input_length as f64);
-            Ok((inputs, input_length))
+    #[instrument(skip_all)]
+    async fn validate_input(
+        &self,
+        inputs: String,
+        truncate: Option<usize>,
+        truncation_side: TruncationSide,
+        add_special_tokens: bool,
+        max_new_tokens: u32,
+    ) -> Result<(String, usize, Vec<u32>), ValidationError> {
+        // If we have a fast tokenizer
+        if let Some((encoding, inputs)) = self
+            .tokenize(
+                inputs.clone(),
+                truncate,
+                truncation_side,
+                add_special_tokens,
+            )
+            .await?
+        {
+            let input_length = encoding.len();
+            self.validate_input_length(input_length, max_new_tokens)?;
+            Ok((inputs, input_length, encoding.get_ids().to_vec()))
         }
         // Return inputs without validation
         else {
             // In this case, we don't know the real length in tokens of the inputs
             // However, the inputs will be truncated by the python servers
             // We make sure that truncate + max_new_tokens <= self.max_total_tokens
+            // The python servers only keep the end of the inputs
+            if truncation_side != TruncationSide::Left {
+                return Err(ValidationError::TruncationSideTokenizer);
+            }
             let input_length = truncate.unwrap_or(self.max_input_length);
 
             // Validate MaxNewTokens
@@ -118,32 +388,130 @@ impl Validation {
                 ));
             }
 
-            Ok((inputs, input_length))
+            Ok((inputs, input_length, vec![]))
+        }
+    }
+
+    /// Validate the inputs of a multimodal model, each image counting for `image_tokens`
+    #[instrument(skip_all)]
+    async fn validate_multimodal_input(
+        &self,
+        inputs: String,
+        input_chunks: &[InputChunk],
+        add_special_tokens: bool,
+        max_new_tokens: u32,
+    ) -> Result<(String, usize, Vec<u32>), ValidationError> {
+        let mut text = String::new();
+        let mut images = 0;
+        for chunk in input_chunks {
+            match &chunk.chunk {
+                Some(Chunk::Text(chunk)) => text.push_str(chunk),
+                Some(Chunk::Image(_)) => images += 1,
+                None => {}
+            }
+        }
+        let Some((encoding, _)) = self
+            .tokenize(text, None, TruncationSide::Left, add_special_tokens)
+            .await?
+        else {
+            // Without a fast tokenizer the shards check the length of the inputs
+            return self
+                .validate_input(
+                    inputs,
+                    None,
+                    TruncationSide::Left,
+                    add_special_tokens,
+                    max_new_tokens,
+                )
+                .await;
+        };
+        let input_length = encoding.len() + images * self.image_tokens as usize;
+        self.validate_input_length(input_length, max_new_tokens)?;
+        // The token ids do not cover the images so the request cannot share a prefix with
+        // other requests
+        Ok((inputs, input_length, vec![]))
+    }
+
+    /// Check that the inputs and the generated tokens fit the model
+    fn validate_input_length(
+        &self,
+        input_length: usize,
+        max_new_tokens: u32,
+    ) -> Result<(), ValidationError> {
+        // Get total tokens
+        let total_tokens = input_length + max_new_tokens as usize;
+
+        // Validate MaxTotalTokens
+        if total_tokens > self.max_total_tokens {
+            return Err(ValidationError::MaxTotalTokens(
+                self.max_total_tokens,
+                input_length,
+                max_new_tokens,
+            ));
+        }
+
+        // Validate InputLength
+        if input_length > self.max_input_length {
+            return Err(ValidationError::InputLength(
+                self.max_input_length,
+                input_length,
+            ));
         }
+
+        metrics::histogram!("tgi_request_input_length", input_length as f64);
+        Ok(())
     }
 
     /// Validate a payload and get the number of tokens in the input
     #[instrument(skip_all)]
     pub(crate) async fn validate(
         &self,
-        request: GenerateRequest,
+        mut request: GenerateRequest,
     ) -> Result<ValidGenerateRequest, ValidationError> {
+        let start_time = Instant::now();
         let GenerateParameters {
             best_of,
             temperature,
             repetition_penalty,
+            frequency_penalty,
+            presence_penalty,
             top_k,
             top_p,
             typical_p,
+            min_p,
+            epsilon_cutoff,
+            eta_cutoff,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta,
             do_sample,
             max_new_tokens,
             min_new_tokens,
             stop: stop_sequences,
+            stop_regex,
+            stop_token_ids,
+            bad_words,
+            suffix,
             truncate,
             seed,
             watermark,
             no_repeat_ngram_size,
             decoder_input_details,
+            score_prompt,
+            logit_bias,
+            top_n_tokens,
+            grammar,
+            priority,
+            timeout_ms,
+            session_id,
+            template,
+            variables,
+            token_healing,
+            truncation_side,
+            on_overflow,
+            queue_events,
+            tenant,
+            rate_limit,
             ..
         } = request.parameters;
 
@@ -153,12 +521,21 @@ impl Validation {
             || temperature.is_some()
             || top_k.is_some()
             || top_p.is_some()
-            || typical_p.is_some();
+            || typical_p.is_some()
+            || min_p.is_some()
+            || epsilon_cutoff.is_some()
+            || eta_cutoff.is_some()
+            || mirostat.is_some();
 
         if best_of > 1 && !sampling {
             return Err(BestOfSampling);
         }
 
+        // Scoring the prompt is deterministic, the candidates would all be equal
+        if score_prompt && best_of > 1 {
+            return Err(ValidationError::ScorePromptBestOf);
+        }
+
         let temperature = temperature.unwrap_or(1.0);
         if temperature <= 0.0 {
             return Err(ValidationError::Temperature);
@@ -169,6 +546,16 @@ impl Validation {
             return Err(ValidationError::RepetitionPenalty);
         }
 
+        let frequency_penalty = frequency_penalty.unwrap_or(0.0);
+        if !(-2.0..=2.0).contains(&frequency_penalty) {
+            return Err(ValidationError::FrequencyPenalty);
+        }
+
+        let presence_penalty = presence_penalty.unwrap_or(0.0);
+        if !(-2.0..=2.0).contains(&presence_penalty) {
+            return Err(ValidationError::PresencePenalty);
+        }
+
         // Different because the proto default value is not a valid value
         // for the user
         let top_p = top_p
@@ -189,6 +576,54 @@ impl Validation {
             })
             .unwrap_or(Ok(1.0))?;
 
+        let min_p = min_p
+            .map(|value| {
+                if value <= 0.0 || value >= 1.0 {
+                    return Err(ValidationError::MinP);
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0.0))?;
+
+        let epsilon_cutoff = epsilon_cutoff
+            .map(|value| {
+                if value <= 0.0 || value >= 1.0 {
+                    return Err(ValidationError::EpsilonCutoff);
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0.0))?;
+
+        let eta_cutoff = eta_cutoff
+            .map(|value| {
+                if value <= 0.0 || value >= 1.0 {
+                    return Err(ValidationError::EtaCutoff);
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0.0))?;
+
+        let (mirostat, mirostat_tau, mirostat_eta) = match mirostat {
+            None => {
+                if mirostat_tau.is_some() || mirostat_eta.is_some() {
+                    return Err(ValidationError::MirostatDisabled);
+                }
+                (0, 0.0, 0.0)
+            }
+            Some(mode @ (1 | 2)) => {
+                let tau = mirostat_tau.unwrap_or(5.0);
+                if tau <= 0.0 {
+                    return Err(ValidationError::MirostatTau);
+                }
+                let eta = mirostat_eta.unwrap_or(0.1);
+                if eta <= 0.0 || eta > 1.0 {
+                    return Err(ValidationError::MirostatEta);
+                }
+                (mode, tau, eta)
+            }
+            Some(mode) => return Err(ValidationError::Mirostat(mode)),
+        };
+
         let top_k: u32 = top_k
             .map(|value| {
                 if value <= 0 {
@@ -198,17 +633,128 @@ impl Validation {
             })
             .unwrap_or(Ok(0))?;
 
+        // The shards always decode the first token during prefill, it is dropped when scoring
+        // the prompt
+        let max_new_tokens = match score_prompt {
+            true => 1,
+            false => max_new_tokens.unwrap_or_else(crate::default_max_new_tokens),
+        };
         if max_new_tokens == 0 {
             return Err(ValidationError::NegativeMaxNewTokens);
         }
 
-        if stop_sequences.len() > self.max_stop_sequences {
+        // Regex stop sequences count against the same limit as the literal ones
+        let stop_count = stop_sequences.len() + stop_regex.len();
+        if stop_count > self.max_stop_sequences {
             return Err(ValidationError::StopSequence(
                 self.max_stop_sequences,
-                stop_sequences.len(),
+                stop_count,
+            ));
+        }
+        let stop_regexes = stop_regex
+            .iter()
+            .map(|pattern| compile_stop_regex(pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if stop_token_ids.len() > MAX_STOP_TOKEN_IDS {
+            return Err(ValidationError::StopTokenIds(
+                MAX_STOP_TOKEN_IDS,
+                stop_token_ids.len(),
+            ));
+        }
+        if let Some(vocab_size) = self.vocab_size {
+            if let Some(&token_id) = stop_token_ids
+                .iter()
+                .find(|&&token_id| token_id as usize >= vocab_size)
+            {
+                return Err(ValidationError::StopTokenId(vocab_size, token_id));
+            }
+        }
+
+        let bad_words_ids = self.tokenize_bad_words(bad_words).await?;
+
+        let top_n_tokens = top_n_tokens
+            .map(|value| {
+                if value > self.max_top_n_tokens {
+                    return Err(ValidationError::TopNTokens(self.max_top_n_tokens, value));
+                }
+                Ok(value)
+            })
+            .unwrap_or(Ok(0))?;
+
+        if let Some(session_id) = &session_id {
+            if session_id.is_empty() || session_id.len() > MAX_SESSION_ID_LENGTH {
+                return Err(ValidationError::SessionId(MAX_SESSION_ID_LENGTH));
+            }
+            // All the sequences would compete for the KV cache of the session
+            if best_of > 1 {
+                return Err(ValidationError::SessionBestOf);
+            }
+        }
+
+        if no_repeat_ngram_size > MAX_NO_REPEAT_NGRAM_SIZE {
+            return Err(ValidationError::NoRepeatNgramSize(
+                MAX_NO_REPEAT_NGRAM_SIZE,
+                no_repeat_ngram_size,
             ));
         }
 
+        // The deadline starts when the request is received
+        let deadline = match timeout_ms {
+            Some(0) => return Err(ValidationError::TimeoutMs),
+            Some(timeout_ms) => Some(start_time + Duration::from_millis(timeout_ms)),
+            None => None,
+        };
+
+        let logit_bias = logit_bias.unwrap_or_default();
+        for (&token_id, &bias) in logit_bias.iter() {
+            if !(-100.0..=100.0).contains(&bias) {
+                return Err(ValidationError::LogitBias(token_id, bias));
+            }
+            if let Some(vocab_size) = self.vocab_size {
+                if token_id as usize >= vocab_size {
+                    return Err(ValidationError::LogitBiasTokenId(vocab_size, token_id));
+                }
+            }
+            // Favouring a special token only makes sense to end the generation with it
+            if bias > 0.0 && !stop_token_ids.contains(&token_id) {
+                if let Some(token) = self.special_tokens.get(&token_id) {
+                    return Err(ValidationError::LogitBiasSpecialToken(
+                        token_id,
+                        token.clone(),
+                    ));
+                }
+            }
+        }
+
+        let mut choices = Vec::new();
+        let (grammar, grammar_type) = match grammar {
+            None => (String::new(), ProtoGrammarType::None),
+            Some(GrammarType::Json(schema)) => {
+                let grammar = self.compile_grammar("json", &schema.to_string(), || {
+                    regex_to_automaton(&json_schema_to_regex(&schema)?)
+                })?;
+                (grammar, ProtoGrammarType::Json)
+            }
+            Some(GrammarType::Regex(pattern)) => {
+                let automaton = self.compile_grammar("regex", &pattern, || {
+                    validate_regex(&pattern)?;
+                    regex_to_automaton(&pattern)
+                })?;
+                (automaton, ProtoGrammarType::Regex)
+            }
+            Some(GrammarType::Gbnf(grammar)) => {
+                let automaton =
+                    self.compile_grammar("gbnf", &grammar, || gbnf_to_automaton(&grammar))?;
+                (automaton, ProtoGrammarType::Gbnf)
+            }
+            Some(GrammarType::Choice(values)) => {
+                let automaton = regex_to_automaton(&choices_to_regex(&values)?)?;
+                choices = values;
+                (automaton, ProtoGrammarType::Regex)
+            }
+        };
+
         // If seed is None, assign a random one
         let seed = match seed {
             None => thread_rng().gen(),
@@ -220,11 +766,91 @@ impl Validation {
             }
         };
 
+        // Render the prompt template with the variables of the request
+        match template {
+            Some(template) => {
+                let prompt_templates = self
+                    .prompt_templates
+                    .as_ref()
+                    .ok_or(ValidationError::PromptTemplatesDisabled)?;
+                request.inputs = prompt_templates.render(
+                    &template,
+                    request.inputs,
+                    variables.unwrap_or_default(),
+                )?;
+            }
+            None if variables.is_some() => return Err(ValidationError::PromptTemplateVariables),
+            None => {}
+        }
+
+        // Mask the personally identifiable information before the prompt is filtered
+        let suffix = match &self.pii_redactor {
+            Some(pii_redactor) => {
+                request.inputs = pii_redactor.redact(&request.inputs, "prompt").into_owned();
+                suffix.map(|suffix| pii_redactor.redact(&suffix, "prompt").into_owned())
+            }
+            None => suffix,
+        };
+
+        // Filter the rendered prompt
+        if let Some(content_filter) = &self.content_filter {
+            request.inputs = content_filter
+                .apply(request.inputs, tenant.as_ref().map(Tenant::name))
+                .await?;
+        }
+
         // Check if inputs is empty
-        if request.inputs.is_empty() {
+        // The prefix of a fill-in-the-middle request can be empty
+        if request.inputs.is_empty() && suffix.is_none() {
             return Err(EmptyInput);
         }
 
+        // Images embedded in the inputs of a multimodal model
+        let parts = split_inputs(&request.inputs);
+        let images = parts
+            .iter()
+            .filter(|part| matches!(part, InputPart::Image(_)))
+            .count();
+        let input_chunks = if images == 0 {
+            vec![]
+        } else {
+            let client = self
+                .image_client
+                .as_ref()
+                .ok_or(ValidationError::ImageUnsupported)?;
+            if images > MAX_IMAGES {
+                return Err(ValidationError::Images(MAX_IMAGES, images));
+            }
+            // The text around the images cannot be modified
+            if truncate.is_some() || suffix.is_some() || token_healing {
+                return Err(ValidationError::ImageParameters);
+            }
+            metrics::histogram!("tgi_request_images", images as f64);
+            input_chunks(client, parts).await?
+        };
+
+        // Fill-in-the-middle: generate the text between the inputs and the suffix
+        let fill_in_the_middle = suffix.is_some();
+        let (inputs, token_healing_prefix) = match suffix {
+            None if token_healing => self.heal(request.inputs).await?,
+            None => (request.inputs, String::new()),
+            Some(suffix) => {
+                let fim_tokens = self
+                    .fim_tokens
+                    .as_ref()
+                    .ok_or(ValidationError::SuffixUnsupported)?;
+                // Truncating the prompt would remove the prefix token
+                if truncate.is_some() {
+                    return Err(ValidationError::SuffixTruncate);
+                }
+                // The prompt ends with the middle token
+                if token_healing {
+                    return Err(ValidationError::SuffixTokenHealing);
+                }
+                (fim_tokens.prompt(&request.inputs, &suffix), String::new())
+            }
+        };
+
         // Check if truncate is strictly positive and less than max_input_length
         let truncate = truncate
             .map(|value| {
@@ -235,10 +861,50 @@ impl Validation {
             })
             .unwrap_or(Ok(None))?;
 
+        // Truncate the inputs to fit the context of the model instead of rejecting the request.
+        // The prompt of a fill-in-the-middle request cannot be truncated
+        let overflow_policy = on_overflow.unwrap_or(self.overflow_policy);
+        let (truncate, truncation_side) = match overflow_policy {
+            OverflowPolicy::Truncate | OverflowPolicy::TruncateMiddle
+                if truncate.is_none() && !fill_in_the_middle =>
+            {
+                // Keep as many input tokens as the generated tokens leave room for
+                let max_input_length = self.max_input_length.min(
+                    self.max_total_tokens
+                        .saturating_sub(max_new_tokens as usize),
+                );
+                let truncation_side = match overflow_policy {
+                    OverflowPolicy::TruncateMiddle => TruncationSide::Middle,
+                    _ => truncation_side,
+                };
+                (
+                    (max_input_length > 0).then_some(max_input_length),
+                    truncation_side,
+                )
+            }
+            _ => (truncate, truncation_side),
+        };
+
         // Validate inputs
-        let (inputs, input_length) = self
-            .validate_input(request.inputs, truncate, max_new_tokens)
-            .await?;
+        let add_special_tokens = request.add_special_tokens;
+        let (inputs, input_length, input_ids) = if input_chunks.is_empty() {
+            self.validate_input(
+                inputs,
+                truncate,
+                truncation_side,
+                add_special_tokens,
+                max_new_tokens,
+            )
+            .await?
+        } else {
+            self.validate_multimodal_input(
+                inputs,
+                &input_chunks,
+                add_special_tokens,
+                max_new_tokens,
+            )
+            .await?
+        };
 
         let parameters = NextTokenChooserParameters {
             temperature,
@@ -251,25 +917,117 @@ impl Validation {
             repetition_penalty,
             no_repeat_ngram_size,
             watermark,
+            logit_bias,
+            grammar,
+            grammar_type: grammar_type as i32,
+            min_p,
+            frequency_penalty,
+            presence_penalty,
+            bad_words_ids,
+            epsilon_cutoff,
+            eta_cutoff,
+            mirostat,
+            mirostat_tau,
+            mirostat_eta,
+            token_healing_prefix,
         };
         let stopping_parameters = StoppingCriteriaParameters {
             max_new_tokens,
             stop_sequences,
             ignore_eos_token: false,
+            stop_token_ids,
         };
 
         metrics::histogram!("tgi_request_max_new_tokens", max_new_tokens as f64);
 
         Ok(ValidGenerateRequest {
             inputs,
-            decoder_input_details,
+            input_ids,
+            input_chunks,
+            add_special_tokens,
+            decoder_input_details: decoder_input_details || score_prompt,
             input_length: input_length as u32,
             truncate: truncate.unwrap_or(self.max_input_length) as u32,
             parameters,
             stopping_parameters,
+            top_n_tokens,
+            priority: priority.unwrap_or_default(),
+            deadline,
+            session_id,
+            tenant,
+            rate_limit,
+            choices,
+            stop_regexes,
+            queue_events,
         })
     }
 
+    /// Remove the last token of `inputs` so that the model generates it again
+    ///
+    /// Returns the trimmed inputs and the text the generated text must start with
+    #[instrument(skip_all)]
+    async fn heal(&self, inputs: String) -> Result<(String, String), ValidationError> {
+        // The last token can only be found if the router can tokenize the inputs
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or(ValidationError::TokenHealingTokenizer)?;
+
+        // Create response channel
+        let (response_sender, response_receiver) = oneshot::channel();
+        // Send request to the background validation task
+        // Unwrap is safe here
+        sender
+            .send((
+                TokenizerRequest::Heal(inputs, response_sender, Span::current()),
+                Instant::now(),
+            ))
+            .unwrap();
+
+        // Await on response channel
+        // Unwrap is safe here
+        response_receiver.await.unwrap()
+    }
+
+    /// Get the compiled `grammar` from the grammar cache or compile it with `compile`
+    fn compile_grammar(
+        &self,
+        grammar_type: &'static str,
+        grammar: &str,
+        compile: impl FnOnce() -> Result<String, GrammarError>,
+    ) -> Result<String, ValidationError> {
+        Ok(self
+            .grammar_cache
+            .lock()
+            .unwrap()
+            .get_or_compile(grammar_type, grammar, compile)?)
+    }
+
+    /// Validate an embedding input and get the number of tokens it contains
+    #[instrument(skip_all)]
+    pub(crate) async fn validate_embed_input(
+        &self,
+        inputs: String,
+    ) -> Result<(String, usize), ValidationError> {
+        if !self.embeddings {
+            return Err(ValidationError::EmbeddingsUnsupported);
+        }
+        // Check if inputs is empty
+        if inputs.is_empty() {
+            return Err(EmptyInput);
+        }
+        // Nothing is generated so the whole token budget is available for the inputs
+        let (inputs, input_length, _) = self
+            .validate_input(inputs, None, TruncationSide::Left, true, 0)
+            .await?;
+        Ok((inputs, input_length))
+    }
+
+    /// Maximum number of input tokens
+    pub(crate) fn max_input_length(&self) -> usize {
+        self.max_input_length
+    }
+
     /// Validate the best_of parameter
     #[instrument(skip_all)]
     pub(crate) fn validate_best_of(&self, best_of: usize) -> Result<usize, ValidationError> {
@@ -283,63 +1041,439 @@ impl Validation {
 
         Ok(best_of)
     }
+
+    /// Validate the number of completions `n` of a request
+    ///
+    /// Generating `n` completions costs as much as `best_of` so it shares the same limit
+    #[instrument(skip_all)]
+    pub(crate) fn validate_n(&self, n: usize) -> Result<usize, ValidationError> {
+        if n == 0 || n > self.max_best_of {
+            return Err(ValidationError::N(self.max_best_of, n));
+        }
+        Ok(n)
+    }
+}
+
+/// Number of token ids of the tokenizer
+///
+/// The added tokens are not always contiguous with the vocabulary of the model, so the size is
+/// one more than the largest id
+fn vocab_size(tokenizer: &Tokenizer) -> usize {
+    tokenizer
+        .get_vocab(true)
+        .values()
+        .max()
+        .map(|&id| id as usize + 1)
+        .unwrap_or_default()
+        .max(tokenizer.get_vocab_size(true))
+}
+
+/// Tokens of the tokenizer removed when decoding with `skip_special_tokens`
+///
+/// Special tokens can also be part of the vocabulary of the model, e.g. `<|endoftext|>` of GPT-2,
+/// so the whole vocabulary is checked
+fn special_tokens(tokenizer: &Tokenizer) -> HashMap<u32, String> {
+    let is_empty = |id: u32, skip_special_tokens: bool| {
+        tokenizer
+            .decode(&[id], skip_special_tokens)
+            .map(|text| text.is_empty())
+            .unwrap_or(false)
+    };
+    tokenizer
+        .get_vocab(true)
+        .into_iter()
+        .filter(|&(_, id)| is_empty(id, true) && !is_empty(id, false))
+        .map(|(token, id)| (id, token))
+        .collect()
+}
+
+/// Compile a regex stop sequence, which must not match the empty text
+fn compile_stop_regex(pattern: &str) -> Result<Regex, ValidationError> {
+    let regex = Regex::new(pattern)
+        .map_err(|err| ValidationError::StopRegex(pattern.to_string(), err.to_string()))?;
+    if regex.is_match("") {
+        return Err(ValidationError::StopRegex(
+            pattern.to_string(),
+            "it matches the empty text".to_string(),
+        ));
+    }
+    Ok(regex)
 }
 
 /// Start tokenization workers
-fn tokenizer_worker(tokenizer: Tokenizer, receiver: flume::Receiver<TokenizerRequest>) {
+///
+/// The inputs queued while the worker was busy are encoded together, up to `max_batch_size`
+fn tokenizer_worker(
+    tokenizer: Tokenizer,
+    receiver: flume::Receiver<(TokenizerRequest, Instant)>,
+    max_batch_size: Arc<AtomicUsize>,
+) {
     // Loop over requests
-    while let Ok(((inputs, truncate), response_tx, parent_span)) = receiver.recv() {
-        parent_span.in_scope(|| {
+    while let Ok(request) = receiver.recv() {
+        let max_batch_size = max_batch_size.load(Ordering::Relaxed);
+        let mut requests = vec![request];
+        while requests.len() < max_batch_size {
+            match receiver.try_recv() {
+                Ok(request) => requests.push(request),
+                Err(_) => break,
+            }
+        }
+        metrics::gauge!("tgi_tokenizer_queue_size", receiver.len() as f64);
+
+        let mut encode_requests = Vec::new();
+        for (request, queued) in requests {
+            metrics::histogram!(
+                "tgi_tokenizer_queue_duration",
+                queued.elapsed().as_secs_f64()
+            );
+            match request {
+                TokenizerRequest::Encode(parameters, response_tx, parent_span) => {
+                    encode_requests.push((parameters, response_tx, parent_span))
+                }
+                request => {
+                    let start = Instant::now();
+                    handle_request(request, &tokenizer);
+                    metrics::histogram!("tgi_tokenizer_duration", start.elapsed().as_secs_f64());
+                }
+            }
+        }
+        if !encode_requests.is_empty() {
+            encode_batch(encode_requests, &tokenizer);
+        }
+    }
+}
+
+/// Encode and optionally truncate the inputs of `requests` in a single tokenizer call
+///
+/// The inputs are encoded one by one if the batch fails so that a single bad input does not fail
+/// the whole batch
+fn encode_batch(requests: Vec<EncodeRequest>, tokenizer: &Tokenizer) {
+    let start = Instant::now();
+    metrics::histogram!("tgi_tokenizer_batch_size", requests.len() as f64);
+
+    // The special tokens are added to all the inputs of a batch or to none of them
+    let add_special_tokens = requests[0].0 .4;
+    let encodings = if requests.len() > 1
+        && requests
+            .iter()
+            .all(|((.., add), ..)| *add == add_special_tokens)
+    {
+        let inputs = requests
+            .iter()
+            .map(|((inputs, ..), ..)| inputs.as_str())
+            .collect();
+        tokenizer.encode_batch(inputs, add_special_tokens).ok()
+    } else {
+        None
+    };
+
+    match encodings {
+        Some(encodings) => {
+            for (
+                (
+                    (inputs, truncate, truncation_side, truncation_marker, add_special_tokens),
+                    response_tx,
+                    parent_span,
+                ),
+                encoding,
+            ) in requests.into_iter().zip(encodings)
+            {
+                parent_span.in_scope(|| {
+                    response_tx
+                        .send(truncate_input(
+                            inputs,
+                            encoding,
+                            truncate,
+                            truncation_side,
+                            &truncation_marker,
+                            add_special_tokens,
+                            tokenizer,
+                        ))
+                        .unwrap_or(())
+                })
+            }
+        }
+        None => {
+            for (
+                (inputs, truncate, truncation_side, truncation_marker, add_special_tokens),
+                response_tx,
+                parent_span,
+            ) in requests
+            {
+                parent_span.in_scope(|| {
+                    response_tx
+                        .send(prepare_input(
+                            inputs,
+                            truncate,
+                            truncation_side,
+                            &truncation_marker,
+                            add_special_tokens,
+                            tokenizer,
+                        ))
+                        .unwrap_or(())
+                })
+            }
+        }
+    }
+    metrics::histogram!("tgi_tokenizer_duration", start.elapsed().as_secs_f64());
+}
+
+/// Answer the tokenizer requests that are not batched
+fn handle_request(request: TokenizerRequest, tokenizer: &Tokenizer) {
+    match request {
+        TokenizerRequest::Encode(
+            (inputs, truncate, truncation_side, truncation_marker, add_special_tokens),
+            response_tx,
+            parent_span,
+        ) => parent_span.in_scope(|| {
             response_tx
-                .send(prepare_input(inputs, truncate, &tokenizer))
+                .send(prepare_input(
+                    inputs,
+                    truncate,
+                    truncation_side,
+                    &truncation_marker,
+                    add_special_tokens,
+                    tokenizer,
+                ))
                 .unwrap_or(())
-        })
+        }),
+        TokenizerRequest::EncodeWords(words, response_tx, parent_span) => {
+            parent_span.in_scope(|| {
+                response_tx
+                    .send(
+                        words
+                            .iter()
+                            .map(|word| {
+                                tokenizer
+                                    .encode(word.as_str(), false)
+                                    .map(|encoding| encoding.get_ids().to_vec())
+                                    .map_err(|err| ValidationError::Tokenizer(err.to_string()))
+                            })
+                            .collect(),
+                    )
+                    .unwrap_or(())
+            })
+        }
+        TokenizerRequest::Heal(inputs, response_tx, parent_span) => parent_span.in_scope(|| {
+            response_tx
+                .send(heal_input(inputs, tokenizer))
+                .unwrap_or(())
+        }),
+        TokenizerRequest::Decode((ids, skip_special_tokens), response_tx, parent_span) => {
+            parent_span.in_scope(|| {
+                response_tx
+                    .send(
+                        tokenizer
+                            .decode(&ids, skip_special_tokens)
+                            .map_err(|err| ValidationError::Tokenizer(err.to_string())),
+                    )
+                    .unwrap_or(())
+            })
+        }
     }
 }
 
-/// Get input length and optionally truncate it
+/// Get input encoding and optionally truncate it
 fn prepare_input(
     inputs: String,
     truncate: Option<usize>,
+    truncation_side: TruncationSide,
+    truncation_marker: &str,
+    add_special_tokens: bool,
     tokenizer: &Tokenizer,
-) -> Result<(String, usize), ValidationError> {
+) -> Result<(Encoding, String), ValidationError> {
     // Get the number of tokens in the input
-    let mut encoding = tokenizer
-        .encode(inputs.clone(), true)
+    let encoding = tokenizer
+        .encode(inputs.clone(), add_special_tokens)
         .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+    truncate_input(
+        inputs,
+        encoding,
+        truncate,
+        truncation_side,
+        truncation_marker,
+        add_special_tokens,
+        tokenizer,
+    )
+}
 
+/// Optionally truncate the input encoding
+fn truncate_input(
+    inputs: String,
+    mut encoding: Encoding,
+    truncate: Option<usize>,
+    truncation_side: TruncationSide,
+    truncation_marker: &str,
+    add_special_tokens: bool,
+    tokenizer: &Tokenizer,
+) -> Result<(Encoding, String), ValidationError> {
     // Optionally truncate
-    let (inputs, input_length) = match truncate {
+    let inputs = match truncate {
         // Truncate is some and < encoding length
         Some(truncate) if truncate < encoding.len() => {
+            let direction = match truncation_side {
+                TruncationSide::Left => TruncationDirection::Left,
+                TruncationSide::Right => TruncationDirection::Right,
+                TruncationSide::Middle => {
+                    if let Some(truncated) = truncate_middle(
+                        &inputs,
+                        &encoding,
+                        truncate,
+                        truncation_marker,
+                        add_special_tokens,
+                        tokenizer,
+                    )? {
+                        return Ok(truncated);
+                    }
+                    // Not enough tokens for the marker, keep the end of the inputs
+                    TruncationDirection::Left
+                }
+            };
             // truncate encoding and decode new inputs
-            encoding.truncate(truncate, 0, TruncationDirection::Left);
-            let inputs = tokenizer
+            encoding.truncate(truncate, 0, direction);
+            tokenizer
                 .decode(encoding.get_ids(), false)
-                .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
-            (inputs, encoding.len())
+                .map_err(|err| ValidationError::Tokenizer(err.to_string()))?
         }
         // Nothing to do
-        _ => (inputs, encoding.len()),
+        _ => inputs,
     };
 
-    Ok((inputs, input_length))
+    Ok((encoding, inputs))
 }
 
-type TokenizerRequest = (
-    (String, Option<usize>),
-    oneshot::Sender<Result<(String, usize), ValidationError>>,
-    Span,
-);
-
-#[derive(Debug)]
-pub(crate) struct ValidGenerateRequest {
-    pub inputs: String,
+/// Split the last token from the inputs
+///
+/// The token is kept when it does not end the inputs, e.g. before trailing whitespace, or when it
+/// is the only token
+fn heal_input(
+    mut inputs: String,
+    tokenizer: &Tokenizer,
+) -> Result<(String, String), ValidationError> {
+    let encoding = tokenizer
+        .encode(inputs.as_str(), false)
+        .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+    // Byte offsets of the tokens in the inputs
+    let offsets = encoding.get_offsets();
+    let [.., (_, previous_end), (_, end)] = offsets else {
+        return Ok((inputs, String::new()));
+    };
+    if *end != inputs.len() || *previous_end >= inputs.len() {
+        return Ok((inputs, String::new()));
+    }
+    // The whitespace between the two tokens is part of the last one
+    let prefix = inputs.split_off(*previous_end);
+    Ok((inputs, prefix))
+}
+
+/// Keep the beginning and the end of the inputs, replacing the middle with `marker`
+///
+/// The inputs are encoded again as tokens can merge around the marker. Returns None if `truncate`
+/// cannot fit the marker and a few tokens of each end.
+fn truncate_middle(
+    inputs: &str,
+    encoding: &Encoding,
+    truncate: usize,
+    marker: &str,
+    add_special_tokens: bool,
+    tokenizer: &Tokenizer,
+) -> Result<Option<(Encoding, String)>, ValidationError> {
+    let marker_length = tokenizer
+        .encode(marker, false)
+        .map_err(|err| ValidationError::Tokenizer(err.to_string()))?
+        .len();
+    // Byte offsets of the tokens of the text, the special tokens are added again when encoding
+    let offsets: Vec<(usize, usize)> = encoding
+        .get_offsets()
+        .iter()
+        .zip(encoding.get_special_tokens_mask())
+        .filter(|(_, special)| **special == 0)
+        .map(|(&offsets, _)| offsets)
+        .collect();
+    let special_tokens = encoding.len() - offsets.len();
+
+    let mut kept = truncate.saturating_sub(marker_length + special_tokens);
+    for _ in 0..MIDDLE_TRUNCATION_ATTEMPTS {
+        if kept < 2 {
+            return Ok(None);
+        }
+        let head = kept / 2;
+        let tail = kept - head;
+        let head_end = offsets[head - 1].1;
+        let tail_start = offsets[offsets.len() - tail].0.max(head_end);
+        let truncated = format!("{}{marker}{}", &inputs[..head_end], &inputs[tail_start..]);
+        let truncated_encoding = tokenizer
+            .encode(truncated.as_str(), add_special_tokens)
+            .map_err(|err| ValidationError::Tokenizer(err.to_string()))?;
+        if truncated_encoding.len() <= truncate {
+            return Ok(Some((truncated_encoding, truncated)));
+        }
+        kept = kept.saturating_sub(truncated_encoding.len() - truncate);
+    }
+    Ok(None)
+}
+
+type EncodeRequest = (
+    (String, Option<usize>, TruncationSide, String, bool),
+    oneshot::Sender<Result<(Encoding, String), ValidationError>>,
+    Span,
+);
+
+enum TokenizerRequest {
+    Encode(
+        (String, Option<usize>, TruncationSide, String, bool),
+        oneshot::Sender<Result<(Encoding, String), ValidationError>>,
+        Span,
+    ),
+    /// Encode words without special tokens
+    EncodeWords(
+        Vec<String>,
+        oneshot::Sender<Result<Vec<Vec<u32>>, ValidationError>>,
+        Span,
+    ),
+    /// Split the last token from the inputs
+    Heal(
+        String,
+        oneshot::Sender<Result<(String, String), ValidationError>>,
+        Span,
+    ),
+    Decode(
+        (Vec<u32>, bool),
+        oneshot::Sender<Result<String, ValidationError>>,
+        Span,
+    ),
+}
+
+#[derive(Debug)]
+pub(crate) struct ValidGenerateRequest {
+    pub inputs: String,
+    /// Token ids of the inputs, empty without a fast tokenizer or with images
+    pub input_ids: Vec<u32>,
+    /// Text and image chunks of the inputs, empty if the inputs are only text
+    pub input_chunks: Vec<InputChunk>,
+    /// The special tokens of the tokenizer are added to the inputs
+    pub add_special_tokens: bool,
     pub input_length: u32,
     pub truncate: u32,
     pub decoder_input_details: bool,
     pub parameters: NextTokenChooserParameters,
     pub stopping_parameters: StoppingCriteriaParameters,
+    pub top_n_tokens: u32,
+    pub priority: Priority,
+    /// Instant after which the request is aborted
+    pub deadline: Option<Instant>,
+    /// Session whose KV cache is reused
+    pub session_id: Option<String>,
+    /// Tenant the generated tokens are counted against
+    pub tenant: Option<Tenant>,
+    /// Bucket the generated tokens are taken from
+    pub rate_limit: Option<RateLimit>,
+    /// Strings the generated text is one of, empty without a choice grammar
+    pub choices: Vec<String>,
+    /// Regular expressions ending the generation, matched by the router
+    pub stop_regexes: Vec<Regex>,
+    /// Send the position of the request while it is queued
+    pub queue_events: bool,
 }
 
 #[derive(Error, Debug)]
@@ -352,14 +1486,44 @@ pub enum ValidationError {
     BestOfSampling,
     #[error("`seed` must not be set when `best_of` > 1")]
     BestOfSeed,
-    #[error("`best_of` != 1 is not supported when streaming tokens")]
-    BestOfStream,
+    #[error("`timeout_ms` must be strictly positive")]
+    TimeoutMs,
+    #[error("`priority` must be one of `low`, `normal` or `high`. Given: {0}")]
+    Priority(String),
+    #[error("`truncation_side` must be one of `left`, `right` or `middle`. Given: {0}")]
+    TruncationSide(String),
+    #[error("`on_overflow` must be one of `error`, `truncate` or `truncate_middle`. Given: {0}")]
+    OnOverflow(String),
+    #[error("`truncation_side` is only supported with `left` by this model")]
+    TruncationSideTokenizer,
+    #[error("`session_id` must be a non-empty string of at most {0} bytes")]
+    SessionId(usize),
+    #[error("`session_id` must not be set when `best_of` > 1")]
+    SessionBestOf,
+    #[error("sessions are disabled on this server")]
+    SessionsDisabled,
+    #[error("`pause_after` must be strictly positive")]
+    PauseAfter,
+    #[error("`continuation` is unknown or expired")]
+    Continuation,
+    #[error("`inputs` must contain between 1 and {0} prompts. Given: {1}")]
+    BatchSize(usize, usize),
+    #[error("`n` must be > 0 and <= {0}. Given: {1}")]
+    N(usize, usize),
     #[error("`decoder_input_details` == true is not supported when streaming tokens")]
     PrefillDetailsStream,
+    #[error("`score_prompt` == true is not supported when streaming tokens")]
+    ScorePromptStream,
+    #[error("`score_prompt` == true is not supported with `best_of` > 1")]
+    ScorePromptBestOf,
     #[error("`temperature` must be strictly positive")]
     Temperature,
     #[error("`repetition_penalty` must be strictly positive")]
     RepetitionPenalty,
+    #[error("`frequency_penalty` must be >= -2.0 and <= 2.0")]
+    FrequencyPenalty,
+    #[error("`presence_penalty` must be >= -2.0 and <= 2.0")]
+    PresencePenalty,
     #[error("`top_p` must be > 0.0 and < 1.0")]
     TopP,
     #[error("`top_k` must be strictly positive")]
@@ -368,6 +1532,20 @@ pub enum ValidationError {
     Truncate(usize, usize),
     #[error("`typical_p` must be > 0.0 and < 1.0")]
     TypicalP,
+    #[error("`min_p` must be > 0.0 and < 1.0")]
+    MinP,
+    #[error("`epsilon_cutoff` must be > 0.0 and < 1.0")]
+    EpsilonCutoff,
+    #[error("`eta_cutoff` must be > 0.0 and < 1.0")]
+    EtaCutoff,
+    #[error("`mirostat` must be 1 or 2. Given: {0}")]
+    Mirostat(u32),
+    #[error("`mirostat_tau` must be strictly positive")]
+    MirostatTau,
+    #[error("`mirostat_eta` must be > 0.0 and <= 1.0")]
+    MirostatEta,
+    #[error("`mirostat_tau` and `mirostat_eta` require `mirostat`")]
+    MirostatDisabled,
     #[error("`max_new_tokens` must be strictly positive")]
     NegativeMaxNewTokens,
     #[error("`max_new_tokens` must be <= {0}. Given: {1}")]
@@ -378,8 +1556,76 @@ pub enum ValidationError {
     InputLength(usize, usize),
     #[error("`inputs` cannot be empty")]
     EmptyInput,
-    #[error("`stop` supports up to {0} stop sequences. Given: {1}")]
+    #[error("`input` must contain at least one input")]
+    EmptyEmbedInputs,
+    #[error("`input` must contain at most {0} inputs. Given: {1}")]
+    EmbedInputs(usize, usize),
+    #[error("`input` must have at most {0} tokens in total. Given: {1}")]
+    EmbedTokens(u32, u32),
+    #[error("embeddings are not supported by this model")]
+    EmbeddingsUnsupported,
+    #[error("`stop` and `stop_regex` support up to {0} stop sequences. Given: {1}")]
     StopSequence(usize, usize),
+    #[error("`stop_regex` `{0}` is invalid: {1}")]
+    StopRegex(String, String),
+    #[error("`suffix` is not supported by this model")]
+    SuffixUnsupported,
+    #[error("`truncate` must not be set with `suffix`")]
+    SuffixTruncate,
+    #[error("`token_healing` must not be set with `suffix`")]
+    SuffixTokenHealing,
+    #[error("`token_healing` is not supported by this model")]
+    TokenHealingTokenizer,
+    #[error("the prompt violates the `{}` policy", .0.policy)]
+    PolicyViolation(PolicyViolation),
+    #[error("the content filter is unavailable: {0}")]
+    ContentFilter(String),
+    #[error("`template` is not supported: the router has no prompt templates")]
+    PromptTemplatesDisabled,
+    #[error("`template` `{0}` does not exist")]
+    PromptTemplate(String),
+    #[error("`template` `{0}` could not be rendered: {1}")]
+    PromptTemplateRender(String, String),
+    #[error("`variables` must be set with `template`")]
+    PromptTemplateVariables,
+    #[error("images are not supported by this model")]
+    ImageUnsupported,
+    #[error("`inputs` supports up to {0} images. Given: {1}")]
+    Images(usize, usize),
+    #[error("`truncate`, `suffix` and `token_healing` must not be set with images")]
+    ImageParameters,
+    #[error("`inputs` image is not valid: {0}")]
+    Image(String),
+    #[error("images must be at most {0} bytes")]
+    ImageSize(usize),
+    #[error("images must be PNG, JPEG, GIF or WEBP")]
+    ImageFormat,
+    #[error("`stop_token_ids` supports up to {0} token ids. Given: {1}")]
+    StopTokenIds(usize, usize),
+    #[error("`stop_token_ids` must be < {0}, the vocabulary size. Given: {1}")]
+    StopTokenId(usize, u32),
+    #[error("`bad_words` supports up to {0} words. Given: {1}")]
+    BadWords(usize, usize),
+    #[error("`bad_words` must not contain empty words")]
+    EmptyBadWord,
+    #[error("`bad_words` is not supported without a fast tokenizer")]
+    BadWordsTokenizer,
+    #[error("`no_repeat_ngram_size` must be <= {0}. Given: {1}")]
+    NoRepeatNgramSize(u32, u32),
+    #[error("`top_n_tokens` must be >= 0 and <= {0}. Given: {1}")]
+    TopNTokens(u32, u32),
+    #[error("`logit_bias` values must be >= -100.0 and <= 100.0. Given: {1} for token {0}")]
+    LogitBias(u32, f32),
+    #[error("`logit_bias` token ids must be < {0}, the vocabulary size. Given: {1}")]
+    LogitBiasTokenId(usize, u32),
+    #[error(
+        "`logit_bias` cannot favour the special token `{1}` ({0}) unless it is in `stop_token_ids`"
+    )]
+    LogitBiasSpecialToken(u32, String),
+    #[error("`grammar` is not valid: {0}")]
+    Grammar(#[from] GrammarError),
+    #[error("`tool_choice` function `{0}` is not in `tools`")]
+    ToolChoice(String),
     #[error("tokenizer error {0}")]
     Tokenizer(String),
 }
@@ -389,12 +1635,14 @@ mod tests {
     use super::*;
     use crate::default_parameters;
     use crate::tests::get_tokenizer;
+    use serde_json::json;
 
     #[tokio::test]
     async fn test_validation_max_new_tokens() {
         let tokenizer = None;
         let max_best_of = 2;
         let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
         let max_input_length = 4;
         let max_total_tokens = 5;
         let workers = 1;
@@ -403,13 +1651,19 @@ mod tests {
             tokenizer,
             max_best_of,
             max_stop_sequence,
+            max_top_n_tokens,
             max_input_length,
             max_total_tokens,
         );
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), None, max_new_tokens)
+            .validate_input(
+                "Hello".to_string(),
+                None,
+                TruncationSide::Left,
+                max_new_tokens,
+            )
             .await
         {
             Err(ValidationError::MaxNewTokens(1, 10)) => (),
@@ -422,6 +1676,7 @@ mod tests {
         let tokenizer = Some(get_tokenizer().await);
         let max_best_of = 2;
         let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
         let max_input_length = 4;
         let max_total_tokens = 5;
         let workers = 1;
@@ -430,13 +1685,19 @@ mod tests {
             tokenizer,
             max_best_of,
             max_stop_sequence,
+            max_top_n_tokens,
             max_input_length,
             max_total_tokens,
         );
 
         let max_new_tokens = 10;
         match validation
-            .validate_input("Hello".to_string(), None, max_new_tokens)
+            .validate_input(
+                "Hello".to_string(),
+                None,
+                TruncationSide::Left,
+                max_new_tokens,
+            )
             .await
         {
             Err(ValidationError::MaxTotalTokens(5, 1, 10)) => (),
@@ -444,11 +1705,177 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_validation_tokenize() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+
+        let (encoding, inputs) = validation
+            .tokenize("Hello world".to_string(), None, TruncationSide::Left, true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(inputs, "Hello world");
+        assert_eq!(encoding.get_ids(), &[15496, 995]);
+        assert_eq!(encoding.get_offsets()[0], (0, 5));
+
+        let (encoding, _) = validation
+            .tokenize(
+                "Hello world".to_string(),
+                Some(1),
+                TruncationSide::Left,
+                true,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(encoding.get_ids(), &[995]);
+
+        let (encoding, inputs) = validation
+            .tokenize(
+                "Hello world".to_string(),
+                Some(1),
+                TruncationSide::Right,
+                true,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(encoding.get_ids(), &[15496]);
+        assert_eq!(inputs, "Hello");
+
+        let validation = validation.with_truncation_marker(" ...".to_string());
+        let (encoding, inputs) = validation
+            .tokenize(
+                "Hello world, how are you today?".to_string(),
+                Some(5),
+                TruncationSide::Middle,
+                true,
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(encoding.len() <= 5);
+        assert!(inputs.starts_with("Hello"));
+        assert!(inputs.contains(" ..."));
+        assert!(inputs.ends_with('?'));
+
+        let validation = Validation::new(
+            workers,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        assert!(validation
+            .tokenize("Hello world".to_string(), None, TruncationSide::Left, true)
+            .await
+            .unwrap()
+            .is_none());
+        match validation
+            .validate_input(
+                "Hello world".to_string(),
+                Some(1),
+                TruncationSide::Right,
+                true,
+                1,
+            )
+            .await
+        {
+            Err(ValidationError::TruncationSideTokenizer) => (),
+            _ => panic!("Unexpected truncation side without a tokenizer"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_tokenize_batch() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        )
+        .with_max_tokenization_batch_size(4);
+
+        // Requests queued together are encoded in the same batch and truncated independently
+        let (first, second, third) = tokio::join!(
+            validation.tokenize("Hello world".to_string(), None, TruncationSide::Left, true),
+            validation.tokenize(
+                "Hello world".to_string(),
+                Some(1),
+                TruncationSide::Right,
+                true
+            ),
+            validation.detokenize(vec![15496, 995], true),
+        );
+        let (encoding, inputs) = first.unwrap().unwrap();
+        assert_eq!(encoding.get_ids(), &[15496, 995]);
+        assert_eq!(inputs, "Hello world");
+        let (encoding, inputs) = second.unwrap().unwrap();
+        assert_eq!(encoding.get_ids(), &[15496]);
+        assert_eq!(inputs, "Hello");
+        assert_eq!(third.unwrap().unwrap(), "Hello world");
+    }
+
+    #[tokio::test]
+    async fn test_validation_detokenize() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+
+        let text = validation
+            .detokenize(vec![15496, 995], true)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(text, "Hello world");
+    }
+
     #[tokio::test]
     async fn test_validation_best_of_sampling() {
         let tokenizer = Some(get_tokenizer().await);
         let max_best_of = 2;
         let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
         let max_input_length = 4;
         let max_total_tokens = 5;
         let workers = 1;
@@ -457,17 +1884,20 @@ mod tests {
             tokenizer,
             max_best_of,
             max_stop_sequence,
+            max_top_n_tokens,
             max_input_length,
             max_total_tokens,
         );
         match validation
             .validate(GenerateRequest {
+                model: None,
                 inputs: "Hello".to_string(),
                 parameters: GenerateParameters {
                     best_of: Some(2),
                     do_sample: false,
                     ..default_parameters()
                 },
+                add_special_tokens: true,
             })
             .await
         {
@@ -476,11 +1906,90 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_validation_timeout_ms() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    timeout_ms: Some(0),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::TimeoutMs) => (),
+            _ => panic!("Unexpected timeout_ms"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    timeout_ms: Some(1000),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert!(valid_request.deadline.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_validation_n() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation.validate_n(0) {
+            Err(ValidationError::N(2, 0)) => (),
+            _ => panic!("Unexpected n"),
+        }
+        match validation.validate_n(3) {
+            Err(ValidationError::N(2, 3)) => (),
+            _ => panic!("Unexpected n"),
+        }
+        assert_eq!(validation.validate_n(2).unwrap(), 2);
+    }
+
     #[tokio::test]
     async fn test_validation_top_p() {
         let tokenizer = Some(get_tokenizer().await);
         let max_best_of = 2;
         let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
         let max_input_length = 4;
         let max_total_tokens = 5;
         let workers = 1;
@@ -489,16 +1998,19 @@ mod tests {
             tokenizer,
             max_best_of,
             max_stop_sequence,
+            max_top_n_tokens,
             max_input_length,
             max_total_tokens,
         );
         match validation
             .validate(GenerateRequest {
+                model: None,
                 inputs: "Hello".to_string(),
                 parameters: GenerateParameters {
                     top_p: Some(1.0),
                     ..default_parameters()
                 },
+                add_special_tokens: true,
             })
             .await
         {
@@ -508,12 +2020,14 @@ mod tests {
 
         match validation
             .validate(GenerateRequest {
+                model: None,
                 inputs: "Hello".to_string(),
                 parameters: GenerateParameters {
                     top_p: Some(0.99),
-                    max_new_tokens: 1,
+                    max_new_tokens: Some(1),
                     ..default_parameters()
                 },
+                add_special_tokens: true,
             })
             .await
         {
@@ -523,16 +2037,1212 @@ mod tests {
 
         let valid_request = validation
             .validate(GenerateRequest {
+                model: None,
                 inputs: "Hello".to_string(),
                 parameters: GenerateParameters {
                     top_p: None,
-                    max_new_tokens: 1,
+                    max_new_tokens: Some(1),
                     ..default_parameters()
                 },
+                add_special_tokens: true,
             })
             .await
             .unwrap();
         // top_p == 1.0 is invalid for users to ask for but it's the default resolved value.
         assert_eq!(valid_request.parameters.top_p, 1.0);
     }
+
+    #[tokio::test]
+    async fn test_validation_min_p() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    min_p: Some(1.0),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::MinP) => (),
+            _ => panic!("Unexpected min_p"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    min_p: Some(0.05),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.min_p, 0.05);
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        // 0.0 disables min_p
+        assert_eq!(valid_request.parameters.min_p, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_openai_penalties() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    frequency_penalty: Some(2.5),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::FrequencyPenalty) => (),
+            _ => panic!("Unexpected frequency_penalty"),
+        }
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    presence_penalty: Some(-2.5),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::PresencePenalty) => (),
+            _ => panic!("Unexpected presence_penalty"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    frequency_penalty: Some(-2.0),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.frequency_penalty, -2.0);
+        // 0.0 disables the penalty
+        assert_eq!(valid_request.parameters.presence_penalty, 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_no_repeat_ngram_size() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    no_repeat_ngram_size: MAX_NO_REPEAT_NGRAM_SIZE + 1,
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::NoRepeatNgramSize(MAX_NO_REPEAT_NGRAM_SIZE, _)) => (),
+            _ => panic!("Unexpected no_repeat_ngram_size"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    no_repeat_ngram_size: 3,
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.no_repeat_ngram_size, 3);
+    }
+
+    #[tokio::test]
+    async fn test_validation_bad_words() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    bad_words: vec![" ".to_string()],
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::EmptyBadWord) => (),
+            _ => panic!("Unexpected bad_words"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    bad_words: vec!["hello".to_string(), " hello".to_string()],
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        // "hello" and " hello", the duplicate is removed
+        let bad_words_ids = valid_request.parameters.bad_words_ids;
+        assert_eq!(bad_words_ids.len(), 2);
+        assert_ne!(bad_words_ids[0].ids, bad_words_ids[1].ids);
+
+        // The words cannot be tokenized without a tokenizer
+        let validation = Validation::new(
+            workers,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    bad_words: vec!["hello".to_string()],
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::BadWordsTokenizer) => (),
+            _ => panic!("Unexpected bad_words"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_images() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 16;
+        let max_total_tokens = 17;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        let png = b"\x89PNG\r\n\x1a\n0000";
+        let inputs = format!(
+            "![](data:image/png;base64,{}) Hello world",
+            base64::engine::general_purpose::STANDARD.encode(png)
+        );
+        let request = |inputs: &str, truncate| GenerateRequest {
+            model: None,
+            inputs: inputs.to_string(),
+            parameters: GenerateParameters {
+                truncate,
+                max_new_tokens: Some(1),
+                ..default_parameters()
+            },
+            add_special_tokens: true,
+        };
+
+        match validation.validate(request(&inputs, None)).await {
+            Err(ValidationError::ImageUnsupported) => (),
+            _ => panic!("Unexpected images without image tokens"),
+        }
+
+        let validation = validation.with_image_tokens(10);
+        let valid_request = validation.validate(request(&inputs, None)).await.unwrap();
+        assert_eq!(valid_request.inputs, inputs);
+        // 10 image tokens and " Hello world"
+        assert_eq!(valid_request.input_length, 12);
+        assert!(valid_request.input_ids.is_empty());
+        assert_eq!(valid_request.input_chunks.len(), 2);
+
+        match validation.validate(request(&inputs, Some(4))).await {
+            Err(ValidationError::ImageParameters) => (),
+            _ => panic!("Unexpected truncate with images"),
+        }
+        match validation
+            .validate(request(&format!("{inputs}{inputs}"), None))
+            .await
+        {
+            Err(ValidationError::MaxTotalTokens(17, 24, 1)) => (),
+            _ => panic!("Unexpected input length with images"),
+        }
+        match validation
+            .validate(request("![](data:image/png;base64,SGVsbG8=)", None))
+            .await
+        {
+            Err(ValidationError::ImageFormat) => (),
+            _ => panic!("Unexpected image format"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_suffix() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "def".to_string(),
+                parameters: GenerateParameters {
+                    suffix: Some("return".to_string()),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::SuffixUnsupported) => (),
+            _ => panic!("Unexpected suffix"),
+        }
+
+        let validation = validation.with_fim_tokens(FimTokens::parse("<PRE>,<SUF>,<MID>"));
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: String::new(),
+                parameters: GenerateParameters {
+                    suffix: Some("return".to_string()),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.inputs, "<PRE><SUF>return<MID>");
+    }
+
+    #[tokio::test]
+    async fn test_validation_on_overflow() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        let request = |on_overflow, max_new_tokens| GenerateRequest {
+            model: None,
+            inputs: "Hello world, how are you today?".to_string(),
+            parameters: GenerateParameters {
+                on_overflow,
+                max_new_tokens: Some(max_new_tokens),
+                ..default_parameters()
+            },
+            add_special_tokens: true,
+        };
+
+        match validation.validate(request(None, 1)).await {
+            Err(ValidationError::MaxTotalTokens(5, _, 1)) => (),
+            _ => panic!("Unexpected overflow"),
+        }
+        let valid_request = validation
+            .validate(request(Some(OverflowPolicy::Truncate), 1))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.input_length, 4);
+        // The generated tokens leave room for fewer input tokens
+        let valid_request = validation
+            .validate(request(Some(OverflowPolicy::TruncateMiddle), 3))
+            .await
+            .unwrap();
+        assert_eq!(valid_request.input_length, 2);
+
+        // Server default
+        let validation = validation.with_overflow_policy(OverflowPolicy::Truncate);
+        assert!(validation.validate(request(None, 1)).await.is_ok());
+        assert!(validation
+            .validate(request(Some(OverflowPolicy::Error), 1))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validation_stop_regex() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+
+        // Literal and regex stop sequences share the limit
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    stop: vec!["a".to_string(), "b".to_string()],
+                    stop_regex: vec!["c+".to_string(), "d+".to_string()],
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::StopSequence(3, 4)) => (),
+            _ => panic!("Unexpected stop sequences"),
+        }
+
+        for pattern in ["(", "a*"] {
+            match validation
+                .validate(GenerateRequest {
+                    model: None,
+                    inputs: "Hello".to_string(),
+                    parameters: GenerateParameters {
+                        stop_regex: vec![pattern.to_string()],
+                        max_new_tokens: Some(1),
+                        ..default_parameters()
+                    },
+                    add_special_tokens: true,
+                })
+                .await
+            {
+                Err(ValidationError::StopRegex(invalid, _)) => assert_eq!(invalid, pattern),
+                _ => panic!("Unexpected stop_regex"),
+            }
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    stop_regex: vec![r"\n\s*\n".to_string()],
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.stop_regexes.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_validation_stop_token_ids() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    stop_token_ids: vec![198, 1_000_000],
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::StopTokenId(_, 1_000_000)) => (),
+            _ => panic!("Unexpected stop_token_ids"),
+        }
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    stop_token_ids: vec![198; MAX_STOP_TOKEN_IDS + 1],
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::StopTokenIds(MAX_STOP_TOKEN_IDS, _)) => (),
+            _ => panic!("Unexpected stop_token_ids"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    stop_token_ids: vec![198],
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.stopping_parameters.stop_token_ids, vec![198]);
+    }
+
+    #[tokio::test]
+    async fn test_validation_truncation_cutoffs() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    epsilon_cutoff: Some(0.0),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::EpsilonCutoff) => (),
+            _ => panic!("Unexpected epsilon_cutoff"),
+        }
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    eta_cutoff: Some(1.5),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::EtaCutoff) => (),
+            _ => panic!("Unexpected eta_cutoff"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    epsilon_cutoff: Some(3e-4),
+                    eta_cutoff: Some(2e-3),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.epsilon_cutoff, 3e-4);
+        assert_eq!(valid_request.parameters.eta_cutoff, 2e-3);
+    }
+
+    #[tokio::test]
+    async fn test_validation_mirostat() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    mirostat: Some(3),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::Mirostat(3)) => (),
+            _ => panic!("Unexpected mirostat"),
+        }
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    mirostat_tau: Some(3.0),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::MirostatDisabled) => (),
+            _ => panic!("Unexpected mirostat_tau without mirostat"),
+        }
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    mirostat: Some(2),
+                    mirostat_eta: Some(1.5),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::MirostatEta) => (),
+            _ => panic!("Unexpected mirostat_eta"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    mirostat: Some(2),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.parameters.mirostat, 2);
+        assert_eq!(valid_request.parameters.mirostat_tau, 5.0);
+        assert_eq!(valid_request.parameters.mirostat_eta, 0.1);
+    }
+
+    #[tokio::test]
+    async fn test_validation_token_healing() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello wor".to_string(),
+                parameters: GenerateParameters {
+                    token_healing: true,
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.inputs, "Hello");
+        assert_eq!(valid_request.input_length, 1);
+        assert_eq!(valid_request.parameters.token_healing_prefix, " wor");
+
+        // A single token is kept
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    token_healing: true,
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.inputs, "Hello");
+        assert_eq!(valid_request.parameters.token_healing_prefix, "");
+
+        let validation = Validation::new(
+            workers,
+            None,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello wor".to_string(),
+                parameters: GenerateParameters {
+                    token_healing: true,
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::TokenHealingTokenizer) => (),
+            _ => panic!("Unexpected token healing without a tokenizer"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_score_prompt() {
+        let tokenizer = None;
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 5;
+        let max_total_tokens = 6;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+
+        // `max_new_tokens` is ignored, it would not fit next to the inputs otherwise
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    score_prompt: true,
+                    max_new_tokens: Some(10),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.stopping_parameters.max_new_tokens, 1);
+        assert!(valid_request.decoder_input_details);
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    score_prompt: true,
+                    best_of: Some(2),
+                    do_sample: true,
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::ScorePromptBestOf) => (),
+            _ => panic!("Unexpected prompt scoring with best_of"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_validation_top_n_tokens() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    top_n_tokens: Some(5),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::TopNTokens(4, 5)) => (),
+            _ => panic!("Unexpected top_n_tokens"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    top_n_tokens: Some(4),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.top_n_tokens, 4);
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    top_n_tokens: None,
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(valid_request.top_n_tokens, 0);
+    }
+
+    #[tokio::test]
+    async fn test_validation_grammar() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    grammar: Some(GrammarType::Json(json!({"type": "unknown"}))),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::Grammar(_)) => (),
+            _ => panic!("Unexpected grammar"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    grammar: Some(GrammarType::Json(json!({"type": "integer"}))),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.grammar,
+            regex_to_automaton("-?(?:0|[1-9][0-9]*)").unwrap()
+        );
+        assert_eq!(
+            valid_request.parameters.grammar_type,
+            ProtoGrammarType::Json as i32
+        );
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    grammar: Some(GrammarType::Regex("(?<=a)b".to_string())),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::Grammar(_)) => (),
+            _ => panic!("Unexpected regex grammar"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    grammar: Some(GrammarType::Regex(r"\d{4}-\d{2}-\d{2}".to_string())),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.grammar,
+            regex_to_automaton(r"\d{4}-\d{2}-\d{2}").unwrap()
+        );
+        assert_eq!(
+            valid_request.parameters.grammar_type,
+            ProtoGrammarType::Regex as i32
+        );
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    grammar: Some(GrammarType::Gbnf("root ::= root | \"a\"".to_string())),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::Grammar(_)) => (),
+            _ => panic!("Unexpected GBNF grammar"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    grammar: Some(GrammarType::Gbnf("root ::= [ab]".to_string())),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.grammar,
+            r#"{"root":0,"rules":[[[{"chars":{"ranges":[[97,98]],"negated":false}}]]]}"#
+        );
+        assert_eq!(
+            valid_request.parameters.grammar_type,
+            ProtoGrammarType::Gbnf as i32
+        );
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    grammar: Some(GrammarType::Choice(vec![
+                        "positive".to_string(),
+                        "negative".to_string(),
+                    ])),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.grammar,
+            regex_to_automaton("(?:positive|negative)").unwrap()
+        );
+        assert_eq!(valid_request.choices, vec!["positive", "negative"]);
+    }
+
+    #[tokio::test]
+    async fn test_validation_logit_bias() {
+        let tokenizer = Some(get_tokenizer().await);
+        let max_best_of = 2;
+        let max_stop_sequence = 3;
+        let max_top_n_tokens = 4;
+        let max_input_length = 4;
+        let max_total_tokens = 5;
+        let workers = 1;
+        let validation = Validation::new(
+            workers,
+            tokenizer,
+            max_best_of,
+            max_stop_sequence,
+            max_top_n_tokens,
+            max_input_length,
+            max_total_tokens,
+        );
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    logit_bias: Some(HashMap::from([(15496, 200.0)])),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::LogitBias(15496, _)) => (),
+            _ => panic!("Unexpected logit_bias"),
+        }
+
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    logit_bias: Some(HashMap::from([(1_000_000, -100.0)])),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::LogitBiasTokenId(_, 1_000_000)) => (),
+            _ => panic!("Unexpected logit_bias token id"),
+        }
+
+        let valid_request = validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    logit_bias: Some(HashMap::from([(15496, -100.0)])),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            valid_request.parameters.logit_bias.get(&15496),
+            Some(&-100.0)
+        );
+
+        // <|endoftext|> is a special token
+        match validation
+            .validate(GenerateRequest {
+                model: None,
+                inputs: "Hello".to_string(),
+                parameters: GenerateParameters {
+                    logit_bias: Some(HashMap::from([(50256, 5.0)])),
+                    max_new_tokens: Some(1),
+                    ..default_parameters()
+                },
+                add_special_tokens: true,
+            })
+            .await
+        {
+            Err(ValidationError::LogitBiasSpecialToken(50256, token)) => {
+                assert_eq!(token, "<|endoftext|>")
+            }
+            _ => panic!("Unexpected logit_bias special token"),
+        }
+
+        // Banning it or stopping on it is allowed
+        for (bias, stop_token_ids) in [(-100.0, vec![]), (5.0, vec![50256])] {
+            validation
+                .validate(GenerateRequest {
+                    model: None,
+                    inputs: "Hello".to_string(),
+                    parameters: GenerateParameters {
+                        logit_bias: Some(HashMap::from([(50256, bias)])),
+                        stop_token_ids,
+                        max_new_tokens: Some(1),
+                        ..default_parameters()
+                    },
+                    add_special_tokens: true,
+                })
+                .await
+                .unwrap();
+        }
+    }
 }
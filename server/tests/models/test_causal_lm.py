@@ -26,6 +26,7 @@ def default_pb_request(default_pb_parameters, default_pb_stop_parameters):
         id=0,
         inputs="Test",
         prefill_logprobs=True,
+        add_special_tokens=True,
         truncate=100,
         parameters=default_pb_parameters,
         stopping_parameters=default_pb_stop_parameters,
@@ -97,6 +98,19 @@ def test_causal_lm_batch_type(default_causal_lm):
     assert default_causal_lm.batch_type == CausalLMBatch
 
 
+def test_causal_lm_embed(default_causal_lm):
+    embeddings = default_causal_lm.embed(["Test", "Test Test"], 100)
+
+    assert len(embeddings) == 2
+    for embedding in embeddings:
+        assert len(embedding) == default_causal_lm.model.config.n_embd
+        assert torch.tensor(embedding).norm().item() == pytest.approx(1.0, abs=1e-4)
+
+    # The padding of the shorter input does not change its embedding
+    single = default_causal_lm.embed(["Test"], 100)[0]
+    assert torch.allclose(torch.tensor(single), torch.tensor(embeddings[0]), atol=1e-4)
+
+
 def test_causal_lm_generate_token(default_causal_lm, default_causal_lm_batch):
     sequence_length = len(default_causal_lm_batch.all_input_ids[0])
     generations, next_batch = default_causal_lm.generate_token(default_causal_lm_batch)
@@ -134,6 +148,28 @@ def test_causal_lm_generate_token(default_causal_lm, default_causal_lm_batch):
     assert generations[0].request_id == 0
 
 
+def test_causal_lm_generate_token_stream(
+    default_causal_lm, default_multi_requests_causal_lm_batch
+):
+    stream = default_causal_lm.generate_token_stream(
+        default_multi_requests_causal_lm_batch
+    )
+
+    # Each generation is yielded before the next request is processed
+    first = next(stream)
+    assert first.request_id == 1
+    assert default_multi_requests_causal_lm_batch.input_lengths == [1, 1]
+
+    second = next(stream)
+    assert second.request_id == 2
+
+    with pytest.raises(StopIteration) as stop:
+        next(stream)
+    generations, next_batch = stop.value.value
+    assert generations == [first, second]
+    assert next_batch.input_lengths == [2, 2]
+
+
 def test_causal_lm_generate_token_completion(
     default_causal_lm, default_causal_lm_batch
 ):
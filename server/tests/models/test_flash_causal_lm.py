@@ -0,0 +1,72 @@
+import torch
+
+from text_generation_server.models.flash_causal_lm import BLOCK_SIZE, CacheManager
+
+
+def get_cache_manager(num_blocks: int) -> CacheManager:
+    return CacheManager(num_blocks, 1, 1, 8, torch.float32, torch.device("cpu"))
+
+
+def test_cache_manager_prefix_reuse():
+    cache_manager = get_cache_manager(4)
+    token_ids = list(range(2 * BLOCK_SIZE + 3))
+
+    block_indices = cache_manager.take(3).tolist()
+    cache_manager.register(token_ids, block_indices)
+
+    # Only the full blocks of the prefix are reused
+    assert cache_manager.lookup(token_ids) == block_indices[:2]
+    assert cache_manager.lookup(token_ids[: BLOCK_SIZE + 1]) == block_indices[:1]
+    # A different first block invalidates the whole prefix
+    assert cache_manager.lookup([-1] + token_ids[1:]) == []
+
+    # The prefix blocks are kept when their last sequence frees them
+    cache_manager.free(block_indices)
+    cache_manager.free(block_indices[:2])
+    cache_manager.free(block_indices[:1])
+    assert cache_manager.free_block_mask.sum().item() == 2
+    assert len(cache_manager.evictable_blocks) == 2
+    # The cached prefixes can be evicted for new sequences
+    assert cache_manager.free_blocks == 4
+
+    # and reused until they are evicted
+    assert cache_manager.lookup(token_ids) == block_indices[:2]
+    assert len(cache_manager.evictable_blocks) == 0
+
+
+def test_cache_manager_prefix_eviction():
+    cache_manager = get_cache_manager(2)
+    token_ids = list(range(2 * BLOCK_SIZE))
+
+    block_indices = cache_manager.take(2).tolist()
+    cache_manager.register(token_ids, block_indices)
+    cache_manager.free(block_indices)
+
+    # The second block of the prefix is evicted first
+    assert cache_manager.take(1).tolist() == block_indices[1:]
+    assert cache_manager.lookup(token_ids) == block_indices[:1]
+
+
+def test_cache_manager_sessions():
+    cache_manager = get_cache_manager(4)
+    token_ids = list(range(2 * BLOCK_SIZE + 3))
+
+    block_indices = cache_manager.take(3).tolist()
+    # The last token has no KV cache
+    cache_manager.pin_session("session", token_ids, block_indices)
+    cache_manager.free(block_indices)
+
+    # The full blocks are pinned for the next turn
+    assert cache_manager.free_block_mask.sum().item() == 2
+    assert cache_manager.free_blocks == 2
+    assert cache_manager.lookup(token_ids + [42]) == block_indices[:2]
+
+    # The next turn replaces the pinned blocks
+    cache_manager.pin_session("session", token_ids[:BLOCK_SIZE], block_indices)
+    cache_manager.free(block_indices[:2])
+    assert cache_manager.sessions["session"] == block_indices[:1]
+    assert len(cache_manager.evictable_blocks) == 1
+
+    cache_manager.release_sessions(["session"])
+    assert cache_manager.sessions == {}
+    assert len(cache_manager.evictable_blocks) == 2
@@ -11,7 +11,7 @@ def get_test_model():
         def batch_type(self):
             raise NotImplementedError
 
-        def generate_token(self, batch):
+        def generate_token_stream(self, batch):
             raise NotImplementedError
 
     tokenizer = AutoTokenizer.from_pretrained("huggingface/llama-7b")
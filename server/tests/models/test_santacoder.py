@@ -16,6 +16,7 @@ def default_pb_request(default_pb_parameters, default_pb_stop_parameters):
         id=0,
         inputs="def",
         prefill_logprobs=True,
+        add_special_tokens=True,
         truncate=100,
         parameters=default_pb_parameters,
         stopping_parameters=default_pb_stop_parameters,
@@ -33,6 +34,7 @@ def default_fim_pb_request(default_pb_parameters, default_pb_stop_parameters):
         id=0,
         inputs="<fim-prefix>def<fim-suffix>world<fim-middle>",
         prefill_logprobs=True,
+        add_special_tokens=True,
         truncate=100,
         parameters=default_pb_parameters,
         stopping_parameters=default_pb_stop_parameters,
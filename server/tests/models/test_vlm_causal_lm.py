@@ -0,0 +1,66 @@
+import io
+import pytest
+
+from PIL import Image
+from transformers import AutoTokenizer
+
+from text_generation_server.pb import generate_pb2
+from text_generation_server.models.vlm_causal_lm import VlmCausalLMBatch
+
+
+@pytest.fixture(scope="session")
+def gpt2_tokenizer():
+    tokenizer = AutoTokenizer.from_pretrained("gpt2", padding_side="left")
+    tokenizer.pad_token_id = 50256
+    return tokenizer
+
+
+def image_chunk():
+    data = io.BytesIO()
+    Image.new("RGB", (4, 4)).save(data, format="PNG")
+    return generate_pb2.InputChunk(
+        image=generate_pb2.Image(data=data.getvalue(), mimetype="image/png")
+    )
+
+
+def test_vlm_batch_tokenize(
+    monkeypatch, gpt2_tokenizer, default_pb_parameters, default_pb_stop_parameters
+):
+    monkeypatch.setattr(VlmCausalLMBatch, "image_token_id", 50000)
+    monkeypatch.setattr(VlmCausalLMBatch, "image_tokens", 3)
+
+    text_request = generate_pb2.Request(
+        id=0,
+        inputs="Test",
+        truncate=100,
+        parameters=default_pb_parameters,
+        stopping_parameters=default_pb_stop_parameters,
+    )
+    image_request = generate_pb2.Request(
+        id=1,
+        inputs="Test![](image)Test",
+        truncate=100,
+        parameters=default_pb_parameters,
+        stopping_parameters=default_pb_stop_parameters,
+        input_chunks=[
+            generate_pb2.InputChunk(text="Test"),
+            image_chunk(),
+            generate_pb2.InputChunk(text="Test"),
+        ],
+    )
+    batch_pb = generate_pb2.Batch(id=0, requests=[text_request, image_request], size=2)
+
+    tokenized_inputs = VlmCausalLMBatch.tokenize(batch_pb, gpt2_tokenizer, 100)
+
+    test_id = gpt2_tokenizer.encode("Test")[0]
+    # Each image is expanded into its placeholder tokens
+    assert tokenized_inputs["input_ids"][1].tolist() == [
+        test_id,
+        50000,
+        50000,
+        50000,
+        test_id,
+    ]
+    # The text only inputs are padded on the left
+    assert tokenized_inputs["attention_mask"][0].tolist() == [0, 0, 0, 0, 1]
+    assert tokenized_inputs["input_ids"][0, -1].item() == test_id
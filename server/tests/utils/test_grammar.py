@@ -0,0 +1,132 @@
+import json
+import torch
+
+from text_generation_server.utils.grammar import Grammar
+from text_generation_server.utils.logits_process import (
+    HeterogeneousGrammarLogitProcessor,
+)
+from text_generation_server.utils.tokens import HeterogeneousNextTokenChooser
+
+# Compiled by the router from `a[0-9]+`
+AUTOMATON = json.dumps(
+    {
+        "root": 0,
+        "rules": [
+            [
+                [
+                    {"chars": {"ranges": [[97, 97]], "negated": False}},
+                    {"rule": 1},
+                    {"rule": 2},
+                ]
+            ],
+            [[{"chars": {"ranges": [[48, 57]], "negated": False}}]],
+            [[{"rule": 1}, {"rule": 2}], []],
+        ],
+    }
+)
+
+
+class FakeTokenizer:
+    eos_token_id = 0
+    all_special_ids = [0]
+
+    def get_vocab(self):
+        return {"</s>": 0, "a": 1, "1": 2, "23": 3, "b": 4, "a1": 5}
+
+    def convert_tokens_to_string(self, tokens):
+        return "".join(tokens)
+
+
+def test_grammar_accept():
+    grammar = Grammar.from_json(AUTOMATON)
+    state = grammar.initial_state
+    assert not grammar.is_complete(state)
+
+    assert not grammar.accept_text(state, "b")
+    assert not grammar.accept_text(state, "1")
+
+    state = grammar.accept_text(state, "a")
+    assert state
+    assert not grammar.is_complete(state)
+
+    state = grammar.accept_text(state, "123")
+    assert grammar.is_complete(state)
+    assert not grammar.accept_text(state, "a")
+
+
+def test_grammar_logit_processor():
+    tokenizer = FakeTokenizer()
+    processor = HeterogeneousGrammarLogitProcessor(tokenizer, [AUTOMATON, ""])
+    input_ids = torch.zeros((2, 1), dtype=torch.int64)
+
+    scores = processor(input_ids, torch.zeros((2, 6)))
+    assert torch.isfinite(scores[0]).tolist() == [False, True, False, False, False, True]
+    assert torch.isfinite(scores[1]).all()
+
+    processor.advance([5, 4])
+    scores = processor(input_ids, torch.zeros((2, 6)))
+    # The grammar is complete so the generation can end
+    assert torch.isfinite(scores[0]).tolist() == [True, False, True, True, False, False]
+
+    processor = processor.filter([1])
+    assert processor is None
+
+
+def test_grammar_state_restored_on_concatenate():
+    def chooser(grammars):
+        n = len(grammars)
+        return HeterogeneousNextTokenChooser(
+            dtype=torch.float32,
+            device=torch.device("cpu"),
+            watermark=[False] * n,
+            temperature=[1.0] * n,
+            repetition_penalty=[1.0] * n,
+            top_k=[0] * n,
+            top_p=[1.0] * n,
+            typical_p=[1.0] * n,
+            do_sample=[False] * n,
+            seeds=[0] * n,
+            grammars=grammars,
+            tokenizer=FakeTokenizer(),
+        )
+
+    first = chooser([AUTOMATON])
+    # Generates `a`
+    first(torch.zeros((1, 1), dtype=torch.int64), torch.tensor([[0.0, 1.0, 0, 0, 0, 0]]))
+    second = chooser([""])
+
+    concatenated = chooser([AUTOMATON, ""]).restore_states([first, second])
+    next_ids, _, _ = concatenated(
+        torch.zeros((2, 1), dtype=torch.int64), torch.zeros((2, 6))
+    )
+    # `a` was already generated so only digits are allowed
+    assert next_ids.tolist()[0] in [2, 3]
+
+
+def test_grammar_recursive():
+    # Compiled by the router from `root ::= "(" root ")" | ""`
+    automaton = json.dumps(
+        {
+            "root": 0,
+            "rules": [
+                [
+                    [
+                        {"chars": {"ranges": [[40, 40]], "negated": False}},
+                        {"rule": 0},
+                        {"chars": {"ranges": [[41, 41]], "negated": False}},
+                    ],
+                    [],
+                ]
+            ],
+        }
+    )
+    grammar = Grammar.from_json(automaton)
+    state = grammar.initial_state
+    assert grammar.is_complete(state)
+    assert not grammar.accept_text(state, ")")
+
+    state = grammar.accept_text(state, "(()")
+    assert state
+    assert not grammar.is_complete(state)
+    assert grammar.is_complete(grammar.accept_text(state, ")"))
+    assert not grammar.accept_text(state, "))")
@@ -1,7 +1,13 @@
+import pytest
+import torch
+
 from text_generation_server.utils.tokens import (
+    HeterogeneousNextTokenChooser,
+    NextTokenChooser,
     StopSequenceCriteria,
     StoppingCriteria,
     FinishReason,
+    batch_top_tokens,
 )
 
 
@@ -42,3 +48,138 @@ def test_stopping_criteria_max():
     assert criteria(1, "") == (False, None)
     assert criteria(1, "") == (False, None)
     assert criteria(1, "") == (True, FinishReason.FINISH_REASON_LENGTH)
+
+
+def test_next_token_chooser_logit_bias():
+    chooser = HeterogeneousNextTokenChooser(
+        dtype=torch.float32,
+        device=torch.device("cpu"),
+        watermark=[False, False],
+        temperature=[1.0, 1.0],
+        repetition_penalty=[1.0, 1.0],
+        top_k=[0, 0],
+        top_p=[1.0, 1.0],
+        typical_p=[1.0, 1.0],
+        do_sample=[False, False],
+        seeds=[0, 0],
+        logit_bias=[{2: 100.0}, {}],
+    )
+    input_ids = torch.zeros((2, 1), dtype=torch.int64)
+    scores = torch.tensor([[1.0, 0.5, 0.0], [1.0, 0.5, 0.0]])
+
+    next_ids, _, _ = chooser(input_ids, scores)
+    assert next_ids.tolist() == [2, 0]
+
+
+def test_batch_top_tokens():
+    logprobs = torch.log_softmax(
+        torch.tensor([[1.0, 3.0, 2.0, -float("inf")], [4.0, 3.0, 2.0, 1.0]]), -1
+    )
+
+    top_ids, top_logprobs = batch_top_tokens([4, 0], logprobs)
+    assert top_ids == [[1, 2, 0], []]
+    assert len(top_logprobs[0]) == 3
+    assert top_logprobs[1] == []
+
+
+def test_next_token_chooser_min_p():
+    chooser = NextTokenChooser(input_seq_len=1, min_p=0.5, seed=0)
+    input_ids = torch.zeros((1, 1), dtype=torch.int64)
+    scores = torch.log(torch.tensor([[0.5, 0.3, 0.2]]))
+
+    for _ in range(10):
+        next_id, _ = chooser(input_ids, scores.clone())
+        # 0.2 is less likely than half of 0.5
+        assert next_id.item() in [0, 1]
+
+
+def test_next_token_chooser_presence_penalty():
+    chooser = NextTokenChooser(input_seq_len=1, presence_penalty=0.5)
+    input_ids = torch.zeros((1, 1), dtype=torch.int64)
+    scores = torch.tensor([[1.0, 0.9]])
+
+    next_id, _ = chooser(input_ids, scores.clone())
+    assert next_id.item() == 0
+    # 1.0 - 0.5 < 0.9
+    next_id, _ = chooser(input_ids, scores.clone())
+    assert next_id.item() == 1
+
+
+def test_next_token_chooser_bad_words():
+    chooser = NextTokenChooser(input_seq_len=1, bad_words_ids=[[1], [2, 3]])
+    input_ids = torch.zeros((1, 1), dtype=torch.int64)
+
+    next_id, _ = chooser(input_ids, torch.tensor([[0.0, 3.0, 2.0, 1.0]]))
+    assert next_id.item() == 2
+    # 3 completes the banned sequence after 2
+    next_id, _ = chooser(input_ids, torch.tensor([[0.0, 3.0, 1.0, 2.0]]))
+    assert next_id.item() == 2
+    next_id, _ = chooser(input_ids, torch.tensor([[0.0, 1.0, 0.0, 2.0]]))
+    assert next_id.item() == 0
+
+
+def test_stopping_criteria_stop_token_ids():
+    criteria = StoppingCriteria(
+        0, [], max_new_tokens=5, ignore_eos_token=True, stop_token_ids=[2]
+    )
+    assert criteria(0, "") == (False, None)
+    assert criteria(torch.tensor(2), "") == (
+        True,
+        FinishReason.FINISH_REASON_STOP_SEQUENCE,
+    )
+
+
+def test_next_token_chooser_epsilon_cutoff():
+    chooser = NextTokenChooser(input_seq_len=1, epsilon_cutoff=0.25, seed=0)
+    input_ids = torch.zeros((1, 1), dtype=torch.int64)
+    scores = torch.log(torch.tensor([[0.5, 0.3, 0.2]]))
+
+    for _ in range(10):
+        next_id, _ = chooser(input_ids, scores.clone())
+        assert next_id.item() in [0, 1]
+
+
+def test_next_token_chooser_mirostat():
+    chooser = NextTokenChooser(
+        input_seq_len=1, mirostat=2, mirostat_tau=0.5, mirostat_eta=0.1, seed=0
+    )
+    input_ids = torch.zeros((1, 1), dtype=torch.int64)
+    scores = torch.log(torch.tensor([[0.5, 0.3, 0.2]]))
+
+    # mu starts at 1 bit, only the most likely token is not more surprising
+    next_id, _ = chooser(input_ids, scores.clone())
+    assert next_id.item() == 0
+    # The generated token was not surprising at all in the truncated distribution
+    assert chooser.mirostat_warper.mu.item() == pytest.approx(1.0 + 0.1 * 0.5)
+
+
+class FakeTokenizer:
+    eos_token_id = 0
+    all_special_ids = [0]
+
+    def get_vocab(self):
+        return {"</s>": 0, " w": 1, " wor": 2, " world": 3, "or": 4, "x": 5}
+
+    def convert_tokens_to_string(self, tokens):
+        return "".join(tokens)
+
+
+def test_next_token_chooser_token_healing():
+    chooser = NextTokenChooser(
+        input_seq_len=1, token_healing_prefix=" wor", tokenizer=FakeTokenizer()
+    )
+    input_ids = torch.zeros((1, 1), dtype=torch.int64)
+
+    # Only the tokens consistent with the removed text can be generated
+    scores = chooser.token_healing_processor(input_ids, torch.zeros((1, 6)))
+    assert torch.isfinite(scores[0]).tolist() == [False, True, True, True, False, False]
+
+    # " w" is the most likely token, the rest of the removed text must follow
+    next_id, _ = chooser(input_ids, torch.tensor([[0.0, 3.0, 1.0, 2.0, 0.0, 4.0]]))
+    assert next_id.item() == 1
+    assert chooser.token_healing_processor.prefixes == ["or"]
+
+    next_id, _ = chooser(input_ids, torch.tensor([[0.0, 3.0, 1.0, 2.0, 0.0, 4.0]]))
+    assert next_id.item() == 4
+    # The removed text is regenerated, token healing stops constraining the generation
+    assert chooser.token_healing_processor is None
@@ -23,6 +23,10 @@ class Dtype(str, Enum):
     bloat16 = "bfloat16"
 
 
+class Compression(str, Enum):
+    gzip = "gzip"
+
+
 @app.command()
 def serve(
     model_id: str,
@@ -37,6 +41,12 @@ def serve(
     logger_level: str = "INFO",
     json_output: bool = False,
     otlp_endpoint: Optional[str] = None,
+    tls_port: Optional[int] = None,
+    tls_hostname: str = "localhost",
+    tls_cert: Optional[Path] = None,
+    tls_key: Optional[Path] = None,
+    tls_client_ca: Optional[Path] = None,
+    compression: Optional[Compression] = None,
 ):
     if sharded:
         assert (
@@ -51,6 +61,10 @@ def serve(
         assert (
             os.getenv("MASTER_PORT", None) is not None
         ), "MASTER_PORT must be set when sharded is True"
+    if tls_port is not None:
+        assert (
+            tls_cert is not None and tls_key is not None
+        ), "tls_cert and tls_key must be set when tls_port is set"
 
     # Remove default handler
     logger.remove()
@@ -75,12 +89,27 @@ def serve(
     # Downgrade enum into str for easier management later on
     quantize = None if quantize is None else quantize.value
     dtype = None if dtype is None else dtype.value
+    compression = None if compression is None else compression.value
     if dtype is not None and quantize is not None:
         raise RuntimeError(
             "Only 1 can be set between `dtype` and `quantize`, as they both decide how goes the final model."
         )
     server.serve(
-        model_id, base_model_id, revision, sharded, quantize, dtype, trust_remote_code, peft, uds_path
+        model_id,
+        base_model_id,
+        revision,
+        sharded,
+        quantize,
+        dtype,
+        trust_remote_code,
+        peft,
+        uds_path,
+        tls_port,
+        tls_hostname,
+        tls_cert,
+        tls_key,
+        tls_client_ca,
+        compression,
     )
 
 
@@ -1,3 +1,4 @@
+import inspect
 import torch
 import grpc
 
@@ -5,7 +6,7 @@ from google.rpc import status_pb2, code_pb2
 from grpc_status import rpc_status
 from grpc_interceptor.server import AsyncServerInterceptor
 from loguru import logger
-from typing import Callable, Any
+from typing import AsyncIterator, Callable, Any
 
 
 class ExceptionInterceptor(AsyncServerInterceptor):
@@ -18,16 +19,36 @@ class ExceptionInterceptor(AsyncServerInterceptor):
     ) -> Any:
         try:
             response = method(request_or_iterator, context)
+            if inspect.isasyncgen(response):
+                # Streamed responses are iterated by the caller
+                return self._intercept_stream(response, context, method_name)
             return await response
         except Exception as err:
-            method_name = method_name.split("/")[-1]
-            logger.exception(f"Method {method_name} encountered an error.")
+            await self._abort(err, context, method_name)
 
-            if torch.cuda.is_available():
-                torch.cuda.empty_cache()
+    async def _intercept_stream(
+        self,
+        response: AsyncIterator[Any],
+        context: grpc.ServicerContext,
+        method_name: str,
+    ) -> AsyncIterator[Any]:
+        try:
+            async for message in response:
+                yield message
+        except Exception as err:
+            await self._abort(err, context, method_name)
+
+    async def _abort(
+        self, err: Exception, context: grpc.ServicerContext, method_name: str
+    ):
+        method_name = method_name.split("/")[-1]
+        logger.exception(f"Method {method_name} encountered an error.")
+
+        if torch.cuda.is_available():
+            torch.cuda.empty_cache()
 
-            await context.abort_with_status(
-                rpc_status.to_status(
-                    status_pb2.Status(code=code_pb2.INTERNAL, message=str(err))
-                )
+        await context.abort_with_status(
+            rpc_status.to_status(
+                status_pb2.Status(code=code_pb2.INTERNAL, message=str(err))
             )
+        )
@@ -19,6 +19,7 @@ from text_generation_server.models.galactica import GalacticaSharded
 from text_generation_server.models.santacoder import SantaCoder
 from text_generation_server.models.t5 import T5Sharded
 from text_generation_server.models.gpt_neox import GPTNeoxSharded
+from text_generation_server.models.vlm_causal_lm import VlmCausalLM
 
 # The flag below controls whether to allow TF32 on matmul. This flag defaults to False
 # in PyTorch 1.12 and later.
@@ -40,6 +41,7 @@ __all__ = [
     "SantaCoder",
     "OPTSharded",
     "T5Sharded",
+    "VlmCausalLM",
     "get_model",
 ]
 
@@ -279,6 +281,20 @@ def get_model(
             trust_remote_code=trust_remote_code,
         )
 
+    elif model_type == "llava":
+        if sharded:
+            raise NotImplementedError("sharded is not supported for Llava")
+        return VlmCausalLM(
+            model_id,
+            base_model_id,
+            revision,
+            quantize=quantize,
+            dtype=dtype,
+            trust_remote_code=trust_remote_code,
+            peft=peft,
+            use_flash_attention=USE_HF_FLASH_ATTENTION,
+        )
+
     if sharded:
         raise ValueError("sharded is not supported for AutoModel")
     if quantize == "gptq":
@@ -5,9 +5,10 @@ from dataclasses import dataclass
 from opentelemetry import trace
 from peft import PeftModel, PeftConfig
 from transformers import AutoTokenizer, AutoModelForCausalLM, PreTrainedTokenizerBase, BitsAndBytesConfig
-from typing import Optional, Tuple, List, Type, Dict
+from typing import Generator, Optional, Tuple, List, Type, Dict
 
 from text_generation_server.models import Model
+from text_generation_server.models.model import mean_pooling
 from text_generation_server.models.types import (
     Batch,
     PrefillTokens,
@@ -15,7 +16,13 @@ from text_generation_server.models.types import (
     GeneratedText,
 )
 from text_generation_server.pb import generate_pb2
-from text_generation_server.utils import NextTokenChooser, StoppingCriteria, Sampling
+from text_generation_server.utils import (
+    NextTokenChooser,
+    StoppingCriteria,
+    Sampling,
+    batch_tokenize,
+    batch_top_tokens,
+)
 
 tracer = trace.get_tracer(__name__)
 
@@ -84,14 +91,7 @@ class CausalLMBatch(Batch):
             inputs.append(r.inputs)
             max_truncation = max(max_truncation, r.truncate)
 
-        tokenized_inputs = tokenizer(
-            inputs,
-            return_tensors="pt",
-            padding=True,
-            return_token_type_ids=False,
-            truncation=True,
-            max_length=max_truncation,
-        ).to(device)
+        tokenized_inputs = cls.tokenize(pb, tokenizer, max_truncation).to(device)
         input_lengths = tokenized_inputs["attention_mask"].sum(1)
 
         padding_right_offset = 0
@@ -145,6 +145,22 @@ class CausalLMBatch(Batch):
             max_tokens=max_tokens,
         )
 
+    @classmethod
+    def tokenize(
+        cls,
+        pb: generate_pb2.Batch,
+        tokenizer: PreTrainedTokenizerBase,
+        max_truncation: int,
+    ):
+        """Tokenize the inputs of the requests into padded `input_ids` and `attention_mask`"""
+        return batch_tokenize(
+            tokenizer,
+            [r.inputs for r in pb.requests],
+            [r.add_special_tokens for r in pb.requests],
+            max_truncation,
+            padding=True,
+        )
+
     @tracer.start_as_current_span("filter")
     def filter(self, request_ids: List[int]) -> Optional["CausalLMBatch"]:
         if len(request_ids) == 0:
@@ -451,6 +467,9 @@ class CausalLMBatch(Batch):
 
 
 class CausalLM(Model):
+    # Class of the transformers model loaded from the model ID
+    model_class = AutoModelForCausalLM
+
     def __init__(
         self,
         model_id: str,
@@ -499,7 +518,7 @@ class CausalLM(Model):
             )
         else:
             bnb_config = None
-        model = AutoModelForCausalLM.from_pretrained(
+        model = self.model_class.from_pretrained(
             base_model_id,
             revision=revision,
             torch_dtype=dtype,
@@ -560,20 +579,37 @@ class CausalLM(Model):
         outputs = self.model.forward(**kwargs)
         return outputs.logits, outputs.past_key_values
 
-    @tracer.start_as_current_span("generate_token")
-    def generate_token(
-        self, batch: CausalLMBatch
-    ) -> Tuple[List[Generation], Optional[CausalLMBatch]]:
-        # slice the attention mask to the correct shape
-        attention_mask = batch.attention_mask[:, : -batch.padding_right_offset]
-
-        logits, past = self.forward(
+    def forward_batch(
+        self, batch: CausalLMBatch, attention_mask: torch.Tensor
+    ) -> Tuple[torch.Tensor, List[Tuple[torch.Tensor, torch.Tensor]]]:
+        return self.forward(
             batch.input_ids,
             attention_mask,
             batch.position_ids,
             batch.past_key_values,
         )
 
+    @tracer.start_as_current_span("embed")
+    def embed(self, inputs: List[str], truncate: int) -> List[List[float]]:
+        tokenized_inputs = batch_tokenize(
+            self.tokenizer, inputs, [True] * len(inputs), truncate, padding=True
+        ).to(self.device)
+        attention_mask = tokenized_inputs["attention_mask"]
+        position_ids = attention_mask.long().cumsum(-1) - 1
+        position_ids.masked_fill_(attention_mask == 0, 1)
+
+        with self.capture_hidden_states() as hidden_states:
+            self.forward(tokenized_inputs["input_ids"], attention_mask, position_ids)
+        return mean_pooling(hidden_states[0], attention_mask)
+
+    def generate_token_stream(
+        self, batch: CausalLMBatch
+    ) -> Generator[Generation, None, Tuple[List[Generation], Optional[CausalLMBatch]]]:
+        # slice the attention mask to the correct shape
+        attention_mask = batch.attention_mask[:, : -batch.padding_right_offset]
+
+        logits, past = self.forward_batch(batch, attention_mask)
+
         # Results
         generations: List[Generation] = []
         stopped = True
@@ -666,6 +702,13 @@ class CausalLM(Model):
                 else:
                     prefill_tokens = None
 
+                top_token_ids, top_token_logprobs = batch_top_tokens(
+                    [request.top_n_tokens], logprobs
+                )
+                top_tokens = self.decode_top_tokens(
+                    top_token_ids[0], top_token_logprobs[0]
+                )
+
                 generation = Generation(
                     request.id,
                     prefill_tokens,
@@ -674,9 +717,11 @@ class CausalLM(Model):
                     next_token_text,
                     next_token_id_squeezed.item() in self.all_special_ids,
                     generated_text,
+                    top_tokens,
                 )
 
                 generations.append(generation)
+                yield generation
 
             # Update values
             batch.input_ids[i, 0] = next_token_id
@@ -5,10 +5,11 @@ import torch.distributed
 
 import numpy as np
 
+from collections import OrderedDict
 from dataclasses import dataclass
 from opentelemetry import trace
 from transformers import PreTrainedTokenizerBase
-from typing import Optional, Tuple, List, Type, Union, Dict
+from typing import Generator, Optional, Tuple, List, Type, Union, Dict
 
 from text_generation_server.models import Model
 from text_generation_server.models.types import (
@@ -18,7 +19,12 @@ from text_generation_server.models.types import (
     GeneratedText,
 )
 from text_generation_server.pb import generate_pb2
-from text_generation_server.utils import StoppingCriteria, HeterogeneousNextTokenChooser
+from text_generation_server.utils import (
+    StoppingCriteria,
+    HeterogeneousNextTokenChooser,
+    batch_tokenize,
+    batch_top_tokens,
+)
 from text_generation_server.utils.dist import MEMORY_FRACTION
 
 tracer = trace.get_tracer(__name__)
@@ -63,17 +69,30 @@ class CacheManager:
         self.slots = torch.arange(
             0, num_blocks * self.block_size, dtype=torch.int32
         ).view(num_blocks, self.block_size)
+        # Number of sequences using each block
+        self.ref_counts = torch.zeros(num_blocks, dtype=torch.int32)
 
-    def allocate(self, batch: "FlashCausalLMBatch"):
-        # Get free blocks indices by finding values in mask that are not set to 0
-        free_block_indices = self.free_block_mask.nonzero()
-        assert (
-            len(free_block_indices) >= batch.blocks
-        ), f"Out of available cache blocks: asked {batch.blocks}, only {len(free_block_indices)} free blocks"
+        # Blocks holding the KV cache of a prefix, indexed by the hash of the prefix tokens
+        self.prefix_blocks: Dict[int, int] = {}
+        self.block_hashes: Dict[int, int] = {}
+        # Cached prefix blocks no sequence uses, from the least recently used
+        self.evictable_blocks: OrderedDict[int, None] = OrderedDict()
+        # Prefix blocks pinned by each session until its next turn or its release
+        self.sessions: Dict[str, List[int]] = {}
 
-        # Slice by the number of required blocks
-        block_indices = free_block_indices[: batch.blocks]
-        block_indices = block_indices.flatten()
+    def allocate(self, batch: "FlashCausalLMBatch"):
+        # Reuse the cached blocks of the longest prefix of each sequence
+        prefix_blocks = [
+            self.lookup(all_input_ids[:prefix_len])
+            for all_input_ids, prefix_len in zip(batch.all_input_ids, batch.prefix_lens)
+        ]
+        try:
+            block_indices = self.take(
+                batch.blocks - sum(len(blocks) for blocks in prefix_blocks)
+            )
+        except Exception:
+            self.free(list(itertools.chain.from_iterable(prefix_blocks)))
+            raise
 
         # Padded block tables
         block_tables_tensor = torch.zeros(
@@ -85,30 +104,124 @@ class CacheManager:
         slots = []
         block_tables = []
         for i, (needed_blocks, needed_slots) in enumerate(batch.needed_blocks_slots):
-            # Get allocated blocks for this sequence
-            allocated_blocks = block_indices[
-                cumulative_blocks : cumulative_blocks + needed_blocks
-            ]
+            reused_blocks = prefix_blocks[i]
+            new_blocks = needed_blocks - len(reused_blocks)
+            # Get allocated blocks for this sequence, after the blocks of its cached prefix
+            allocated_blocks = torch.cat(
+                [
+                    torch.tensor(reused_blocks, dtype=block_indices.dtype),
+                    block_indices[cumulative_blocks : cumulative_blocks + new_blocks],
+                ]
+            )
             # Get slots for the allocated blocks
             allocated_slots = self.slots[allocated_blocks].flatten()[:needed_slots]
 
             slots.append(allocated_slots)
             block_tables.append(allocated_blocks.tolist())
             block_tables_tensor[i, :needed_blocks] = allocated_blocks
-            cumulative_blocks += needed_blocks
+            batch.cache_lengths[i] = len(reused_blocks) * self.block_size
+            cumulative_blocks += new_blocks
 
         batch.needed_blocks_slots = None
         batch.block_tables = block_tables
         batch.block_tables_tensor = block_tables_tensor.to(batch.input_ids.device)
         batch.slots = torch.concat(slots).to(batch.input_ids.device)
 
+    @property
+    def free_blocks(self) -> int:
+        """Number of blocks available to new sequences, including the unused cached prefixes"""
+        return int(self.free_block_mask.sum().item()) + len(self.evictable_blocks)
+
+    def take(self, num_blocks: int) -> torch.Tensor:
+        """Take free blocks, evicting the least recently used cached prefixes if needed"""
+        while (
+            self.free_block_mask.sum().item() < num_blocks and self.evictable_blocks
+        ):
+            block_index, _ = self.evictable_blocks.popitem(last=False)
+            del self.prefix_blocks[self.block_hashes.pop(block_index)]
+            self.free_block_mask[block_index] = 1
+
+        # Get free blocks indices by finding values in mask that are not set to 0
+        free_block_indices = self.free_block_mask.nonzero()
+        assert (
+            len(free_block_indices) >= num_blocks
+        ), f"Out of available cache blocks: asked {num_blocks}, only {len(free_block_indices)} free blocks"
+
+        # Slice by the number of required blocks
+        block_indices = free_block_indices[:num_blocks].flatten()
+
         # Allocate the required number of blocks by setting the mask to 0
         self.free_block_mask[block_indices] = 0
+        self.ref_counts[block_indices] = 1
+        return block_indices
+
+    def lookup(self, token_ids: List[int]) -> List[int]:
+        """Reference the cached blocks holding the longest prefix of `token_ids`"""
+        block_indices = []
+        parent_hash = None
+        for start in range(0, len(token_ids) - self.block_size + 1, self.block_size):
+            block_hash = hash(
+                (parent_hash, tuple(token_ids[start : start + self.block_size]))
+            )
+            block_index = self.prefix_blocks.get(block_hash)
+            if block_index is None:
+                break
+            block_indices.append(block_index)
+            parent_hash = block_hash
+
+        for block_index in block_indices:
+            self.ref_counts[block_index] += 1
+            self.evictable_blocks.pop(block_index, None)
+        return block_indices
+
+    def register(self, token_ids: List[int], block_indices: List[int]):
+        """Index the full blocks holding `token_ids` so that later sequences starting with the
+        same tokens reuse them"""
+        parent_hash = None
+        for i, start in enumerate(
+            range(0, len(token_ids) - self.block_size + 1, self.block_size)
+        ):
+            block_hash = hash(
+                (parent_hash, tuple(token_ids[start : start + self.block_size]))
+            )
+            block_index = block_indices[i]
+            if (
+                block_hash not in self.prefix_blocks
+                and block_index not in self.block_hashes
+            ):
+                self.prefix_blocks[block_hash] = block_index
+                self.block_hashes[block_index] = block_hash
+            parent_hash = block_hash
+
+    def pin_session(
+        self, session_id: str, token_ids: List[int], block_indices: List[int]
+    ):
+        """Keep the full blocks holding `token_ids` for the next turn of the session, in place of
+        the blocks pinned by its previous turn"""
+        self.register(token_ids, block_indices)
+        pinned_blocks = block_indices[: len(token_ids) // self.block_size]
+        for block_index in pinned_blocks:
+            self.ref_counts[block_index] += 1
+        self.free(self.sessions.pop(session_id, None))
+        self.sessions[session_id] = pinned_blocks
+
+    def release_sessions(self, session_ids: List[str]):
+        for session_id in session_ids:
+            self.free(self.sessions.pop(session_id, None))
 
     def free(self, block_indices: Optional[List[int]]):
         if block_indices is not None and block_indices:
-            # Reset mask
-            self.free_block_mask[block_indices] = 1
+            # Release the last blocks of the sequences first so that their prefixes are evicted last
+            for block_index in reversed(block_indices):
+                self.ref_counts[block_index] -= 1
+                if self.ref_counts[block_index] > 0:
+                    continue
+                if block_index in self.block_hashes:
+                    # Keep the cached prefix until its block is needed
+                    self.evictable_blocks[block_index] = None
+                else:
+                    # Reset mask
+                    self.free_block_mask[block_index] = 1
 
 
 @dataclass
@@ -159,6 +272,10 @@ class FlashCausalLMBatch(Batch):
     # Lengths of all generations present in the batch
     input_lengths: List[int]
     input_lengths_tensor: torch.Tensor
+    # Number of leading input tokens whose KV cache may be reused from a previous request
+    prefix_lens: List[int]
+    # Number of input tokens whose KV cache is computed before the next prefill forward
+    cache_lengths: List[int]
     prefix_offsets: List[Optional[int]]
     read_offsets: List[Optional[int]]
 
@@ -177,6 +294,7 @@ class FlashCausalLMBatch(Batch):
             request_ids=[r.id for r in self.requests],
             size=len(self),
             max_tokens=self.blocks * BLOCK_SIZE,
+            free_blocks=CACHE_MANAGER.free_blocks,
         )
 
     @classmethod
@@ -193,38 +311,30 @@ class FlashCausalLMBatch(Batch):
             batch_inputs.append(r.inputs)
             max_truncation = max(max_truncation, r.truncate)
 
-        batch_tokenized_inputs = tokenizer(
-            batch_inputs, truncation=True, max_length=max_truncation
-        )["input_ids"]
+        batch_tokenized_inputs = batch_tokenize(
+            tokenizer,
+            batch_inputs,
+            [r.add_special_tokens for r in pb.requests],
+            max_truncation,
+        )
 
-        position_ids = []
-        cu_seqlen_prefill = [0]
         needed_blocks_slots = []
         start_slots = []
-        slot_indices = []
 
         input_lengths = []
+        prefix_lens = []
         prefix_offsets = []
         read_offsets = []
         all_input_ids = []
         requests_idx_mapping = {}
 
-        all_prefill_logprobs = True
-        no_prefill_logprobs = True
-        prefill_head_indices = []
-        prefill_next_token_indices = []
-        prefill_cu_outlens = [0]
-
         next_token_chooser_parameters = []
         stopping_criterias = []
 
         # Cumulative length
-        cumulative_length = 0
         cumulative_max_length = 0
-        prefill_out_cumulative_length = 0
 
         blocks = 0
-        max_seqlen = 0
         max_length = 0
         max_blocks = 0
 
@@ -239,19 +349,14 @@ class FlashCausalLMBatch(Batch):
 
             input_length = len(tokenized_input)
             input_lengths.append(input_length)
+            # The last input token is always prefilled to generate the first token
+            prefix_lens.append(min(r.prefix_len, input_length - 1))
 
             prefix_offsets.append(input_length - 5)
             read_offsets.append(input_length)
 
             all_input_ids.append(tokenized_input)
 
-            # Position ids
-            request_position_ids = torch.arange(0, input_length, dtype=torch.int32)
-            position_ids.append(request_position_ids)
-
-            # Add cumulative lengths of all previous inputs
-            cu_seqlen_prefill.append(cumulative_length + input_length)
-
             next_token_chooser_parameters.append(r.parameters)
 
             stopping_criteria = StoppingCriteria.from_pb(
@@ -268,42 +373,13 @@ class FlashCausalLMBatch(Batch):
             needed_blocks_slots.append((needed_blocks, total_tokens))
             start_slots.append(cumulative_max_length)
 
-            request_slot_indices = torch.arange(
-                cumulative_max_length,
-                cumulative_max_length + input_length,
-                dtype=torch.int64,
-            )
-            slot_indices.append(request_slot_indices)
-
-            all_prefill_logprobs = all_prefill_logprobs and r.prefill_logprobs
-            no_prefill_logprobs = no_prefill_logprobs and not r.prefill_logprobs
-
-            if r.prefill_logprobs:
-                prefill_head_indices.append(request_position_ids + cumulative_length)
-                prefill_next_token_indices.append(
-                    prefill_out_cumulative_length + input_length - 1
-                )
-                prefill_cu_outlens.append(prefill_out_cumulative_length + input_length)
-                prefill_out_cumulative_length += input_length
-            else:
-                prefill_head_indices.append(
-                    torch.tensor(
-                        [cumulative_length + input_length - 1], dtype=torch.int32
-                    )
-                )
-                prefill_next_token_indices.append(prefill_out_cumulative_length)
-                prefill_cu_outlens.append(prefill_out_cumulative_length + 1)
-                prefill_out_cumulative_length += 1
-
             # Update
-            cumulative_length += input_length
             cumulative_max_length += total_tokens
-            max_seqlen = max(max_seqlen, input_length)
             max_blocks = max(max_blocks, needed_blocks)
             max_length = max(max_length, input_length + max_new_tokens)
 
         next_token_chooser = HeterogeneousNextTokenChooser.from_pb(
-            next_token_chooser_parameters, dtype, device
+            next_token_chooser_parameters, dtype, device, tokenizer
         )
         start_slots = torch.tensor(start_slots, dtype=torch.int64)
 
@@ -318,60 +394,31 @@ class FlashCausalLMBatch(Batch):
         all_input_ids_tensor = torch.tensor(
             all_input_ids_tensor, dtype=torch.int64, device=device
         )
-
-        if len(pb.requests) > 1:
-            input_ids = np.concatenate(all_input_ids, dtype=np.int64)
-            position_ids = torch.cat(position_ids)
-            slot_indices = torch.cat(slot_indices)
-        else:
-            input_ids = all_input_ids[0]
-            position_ids = position_ids[0]
-            slot_indices = slot_indices[0]
-
-        cu_seqlen_prefill = torch.tensor(
-            cu_seqlen_prefill, device=device, dtype=torch.int32
-        )
-
-        position_ids = position_ids.to(device)
-        slot_indices = slot_indices.to(device)
-        input_ids = torch.tensor(input_ids, dtype=torch.int64, device=device)
         input_lengths_tensor = torch.tensor(
             input_lengths, dtype=torch.int32, device=device
         )
 
-        if all_prefill_logprobs:
-            prefill_head_indices = None
-            prefill_next_token_indices = cu_seqlen_prefill[1:] - 1
-        elif no_prefill_logprobs:
-            prefill_head_indices = cu_seqlen_prefill[1:] - 1
-            prefill_next_token_indices = None
-        else:
-            prefill_head_indices = torch.tensor(
-                torch.cat(prefill_head_indices), dtype=torch.int64, device=device
-            )
-            prefill_next_token_indices = torch.tensor(
-                prefill_next_token_indices, dtype=torch.int64, device=device
-            )
-
-        return cls(
+        batch = cls(
             batch_id=pb.id,
             requests=pb.requests,
             requests_idx_mapping=requests_idx_mapping,
-            input_ids=input_ids,
-            position_ids=position_ids,
-            cu_seqlen_prefill=cu_seqlen_prefill,
+            input_ids=None,
+            position_ids=None,
+            cu_seqlen_prefill=None,
             start_slots=start_slots,
-            slot_indices=slot_indices,
+            slot_indices=None,
             needed_blocks_slots=needed_blocks_slots,
             block_tables=None,
             block_tables_tensor=None,
             slots=None,
-            max_seqlen=max_seqlen,
-            prefill_head_indices=prefill_head_indices,
-            prefill_next_token_indices=prefill_next_token_indices,
-            prefill_cu_outlens=prefill_cu_outlens,
+            max_seqlen=0,
+            prefill_head_indices=None,
+            prefill_next_token_indices=None,
+            prefill_cu_outlens=None,
             input_lengths=input_lengths,
             input_lengths_tensor=input_lengths_tensor,
+            prefix_lens=prefix_lens,
+            cache_lengths=[0] * len(pb.requests),
             prefix_offsets=prefix_offsets,
             read_offsets=read_offsets,
             all_input_ids=all_input_ids,
@@ -381,6 +428,101 @@ class FlashCausalLMBatch(Batch):
             blocks=blocks,
             max_blocks=max_blocks,
         )
+        batch.prepare_prefill(input_lengths)
+        return batch
+
+    def prepare_prefill(self, prefill_ends: List[int]):
+        """Set the inputs of the forward prefilling the input tokens of each request from the end
+        of its KV cache up to its prefill end"""
+        device = self.all_input_ids_tensor.device
+
+        input_ids = []
+        position_ids = []
+        slot_indices = []
+        cu_seqlen_prefill = [0]
+
+        all_prefill_logprobs = True
+        no_prefill_logprobs = True
+        prefill_head_indices = []
+        prefill_next_token_indices = []
+        prefill_cu_outlens = [0]
+
+        # Cumulative length
+        cumulative_length = 0
+        prefill_out_cumulative_length = 0
+
+        for r, all_input_ids, cache_length, prefill_end, start_slot in zip(
+            self.requests,
+            self.all_input_ids,
+            self.cache_lengths,
+            prefill_ends,
+            self.start_slots.tolist(),
+        ):
+            input_length = prefill_end - cache_length
+            input_ids.extend(all_input_ids[cache_length:prefill_end])
+
+            # Position ids
+            request_position_ids = torch.arange(
+                cache_length, prefill_end, dtype=torch.int32
+            )
+            position_ids.append(request_position_ids)
+            slot_indices.append(request_position_ids.to(torch.int64) + start_slot)
+
+            # Add cumulative lengths of all previous inputs
+            cu_seqlen_prefill.append(cumulative_length + input_length)
+
+            all_prefill_logprobs = all_prefill_logprobs and r.prefill_logprobs
+            no_prefill_logprobs = no_prefill_logprobs and not r.prefill_logprobs
+
+            if r.prefill_logprobs:
+                prefill_head_indices.append(
+                    torch.arange(
+                        cumulative_length,
+                        cumulative_length + input_length,
+                        dtype=torch.int64,
+                    )
+                )
+                prefill_next_token_indices.append(
+                    prefill_out_cumulative_length + input_length - 1
+                )
+                prefill_cu_outlens.append(prefill_out_cumulative_length + input_length)
+                prefill_out_cumulative_length += input_length
+            else:
+                prefill_head_indices.append(
+                    torch.tensor(
+                        [cumulative_length + input_length - 1], dtype=torch.int64
+                    )
+                )
+                prefill_next_token_indices.append(prefill_out_cumulative_length)
+                prefill_cu_outlens.append(prefill_out_cumulative_length + 1)
+                prefill_out_cumulative_length += 1
+
+            cumulative_length += input_length
+
+        cu_seqlen_prefill = torch.tensor(
+            cu_seqlen_prefill, device=device, dtype=torch.int32
+        )
+
+        if all_prefill_logprobs:
+            prefill_head_indices = None
+            prefill_next_token_indices = cu_seqlen_prefill[1:] - 1
+        elif no_prefill_logprobs:
+            prefill_head_indices = cu_seqlen_prefill[1:] - 1
+            prefill_next_token_indices = None
+        else:
+            prefill_head_indices = torch.cat(prefill_head_indices).to(device)
+            prefill_next_token_indices = torch.tensor(
+                prefill_next_token_indices, dtype=torch.int64, device=device
+            )
+
+        self.input_ids = torch.tensor(input_ids, dtype=torch.int64, device=device)
+        self.position_ids = torch.cat(position_ids).to(device)
+        self.slot_indices = torch.cat(slot_indices).to(device)
+        self.cu_seqlen_prefill = cu_seqlen_prefill
+        self.prefill_head_indices = prefill_head_indices
+        self.prefill_next_token_indices = prefill_next_token_indices
+        self.prefill_cu_outlens = prefill_cu_outlens
+        self.max_seqlen = max(prefill_ends)
 
     @tracer.start_as_current_span("filter")
     def filter(self, request_ids: List[int]) -> "FlashCausalLMBatch":
@@ -413,6 +555,8 @@ class FlashCausalLMBatch(Batch):
         all_input_ids = []
 
         input_lengths = []
+        prefix_lens = []
+        cache_lengths = []
         prefix_offsets = []
         read_offsets = []
 
@@ -437,6 +581,8 @@ class FlashCausalLMBatch(Batch):
             all_input_ids.append(self.all_input_ids[idx])
 
             input_lengths.append(request_input_length)
+            prefix_lens.append(self.prefix_lens[idx])
+            cache_lengths.append(self.cache_lengths[idx])
             prefix_offsets.append(self.prefix_offsets[idx])
             read_offsets.append(self.read_offsets[idx])
 
@@ -512,6 +658,8 @@ class FlashCausalLMBatch(Batch):
             prefill_cu_outlens=None,
             input_lengths=input_lengths,
             input_lengths_tensor=input_lengths_tensor,
+            prefix_lens=prefix_lens,
+            cache_lengths=cache_lengths,
             prefix_offsets=prefix_offsets,
             read_offsets=read_offsets,
             all_input_ids=all_input_ids,
@@ -572,6 +720,8 @@ class FlashCausalLMBatch(Batch):
         all_input_ids = []
 
         input_lengths = []
+        prefix_lens = []
+        cache_lengths = []
         prefix_offsets = []
         read_offsets = []
 
@@ -618,6 +768,8 @@ class FlashCausalLMBatch(Batch):
             all_input_ids.extend(batch.all_input_ids)
 
             input_lengths.extend(batch.input_lengths)
+            prefix_lens.extend(batch.prefix_lens)
+            cache_lengths.extend(batch.cache_lengths)
             prefix_offsets.extend(batch.prefix_offsets)
             read_offsets.extend(batch.read_offsets)
 
@@ -634,7 +786,8 @@ class FlashCausalLMBatch(Batch):
             next_token_chooser_parameters,
             dtype=batches[0].next_token_chooser.dtype,
             device=batches[0].next_token_chooser.device,
-        )
+            tokenizer=batches[0].next_token_chooser.tokenizer,
+        ).restore_states([b.next_token_chooser for b in batches])
 
         # Needed to avoid dropping blocks when the batches will go out of scope
         for b in batches:
@@ -660,6 +813,8 @@ class FlashCausalLMBatch(Batch):
             prefill_cu_outlens=None,
             input_lengths=input_lengths,
             input_lengths_tensor=input_lengths_tensor,
+            prefix_lens=prefix_lens,
+            cache_lengths=cache_lengths,
             prefix_offsets=prefix_offsets,
             read_offsets=read_offsets,
             all_input_ids=all_input_ids,
@@ -681,6 +836,8 @@ class FlashCausalLMBatch(Batch):
 
 
 class FlashCausalLM(Model):
+    supports_prefix_caching = True
+
     def __init__(
         self,
         model: torch.nn.Module,
@@ -798,19 +955,107 @@ class FlashCausalLM(Model):
             lm_head_indices=lm_head_indices,
         )
 
-    @tracer.start_as_current_span("generate_token")
-    def generate_token(
-        self, batch: FlashCausalLMBatch
-    ) -> Tuple[List[Generation], Optional[FlashCausalLMBatch]]:
-        prefill = batch.cu_seqlen_prefill is not None
-        prefill_logprobs = batch.prefill_next_token_indices is not None
+    def forward_cached_prefill(
+        self,
+        batch: FlashCausalLMBatch,
+        lm_head_indices: Optional[torch.Tensor] = None,
+    ) -> torch.Tensor:
+        """Prefill the input tokens of the batch following the tokens already in the KV cache
+
+        The flash attention kernel only attends to the tokens of the forward, so every input token
+        is decoded as its own sequence instead: it attends to the KV cache of its request up to its
+        position, which holds the cached tokens and the input tokens written by this forward.
+        """
+        request_indices = torch.repeat_interleave(
+            torch.arange(len(batch), device=batch.input_ids.device),
+            batch.cu_seqlen_prefill.diff().long(),
+        )
+        return self.forward(
+            batch.input_ids,
+            batch.position_ids,
+            None,
+            batch.block_tables_tensor[request_indices],
+            batch.slots[batch.slot_indices],
+            batch.position_ids + 1,
+            batch.max_seqlen,
+            lm_head_indices,
+        )
 
+    @tracer.start_as_current_span("prefill_chunk")
+    def prefill_chunk(self, batch: FlashCausalLMBatch, pb: generate_pb2.Batch):
         if batch.needed_blocks_slots:
             # Allocate blocks to this batch
             CACHE_MANAGER.allocate(batch)
 
-        try:
-            out = self.forward(
+        # The last input token of each request is left for the last chunk
+        prefill_ends = [
+            max(
+                cache_length,
+                min(
+                    prefix_len + r.prefill_offset + r.prefill_chunk_len,
+                    input_length - 1,
+                ),
+            )
+            for r, prefix_len, cache_length, input_length in zip(
+                pb.requests, batch.prefix_lens, batch.cache_lengths, batch.input_lengths
+            )
+        ]
+        batch.prepare_prefill(prefill_ends)
+
+        if len(batch.input_ids) > 0:
+            # Only fill the KV cache: the logits of the last token of each request are discarded
+            lm_head_indices = (batch.cu_seqlen_prefill[1:] - 1).clamp(min=0)
+            if any(batch.cache_lengths):
+                self.forward_cached_prefill(batch, lm_head_indices)
+            else:
+                self.forward(
+                    batch.input_ids,
+                    batch.position_ids,
+                    batch.cu_seqlen_prefill,
+                    batch.block_tables_tensor,
+                    batch.slots[batch.slot_indices],
+                    batch.input_lengths_tensor,
+                    batch.max_seqlen,
+                    lm_head_indices,
+                )
+
+        # The next chunk continues from the end of this one
+        batch.cache_lengths = prefill_ends
+        batch.prepare_prefill(batch.input_lengths)
+
+    def release_sessions(self, session_ids: List[str]):
+        if CACHE_MANAGER is not None:
+            CACHE_MANAGER.release_sessions(session_ids)
+
+    @tracer.start_as_current_span("embed")
+    def embed(self, inputs: List[str], truncate: int) -> List[List[float]]:
+        # Prefill the inputs without generating: the KV cache of the inputs is written to blocks
+        # released with the batch
+        parameters = generate_pb2.NextTokenChooserParameters(
+            temperature=1.0, top_p=1.0, typical_p=1.0, repetition_penalty=1.0
+        )
+        stopping_parameters = generate_pb2.StoppingCriteriaParameters(max_new_tokens=1)
+        pb = generate_pb2.Batch(
+            id=0,
+            requests=[
+                generate_pb2.Request(
+                    id=i,
+                    inputs=text,
+                    truncate=truncate,
+                    prefill_logprobs=True,
+                    add_special_tokens=True,
+                    parameters=parameters,
+                    stopping_parameters=stopping_parameters,
+                )
+                for i, text in enumerate(inputs)
+            ],
+            size=len(inputs),
+        )
+        batch = FlashCausalLMBatch.from_pb(pb, self.tokenizer, self.dtype, self.device)
+        CACHE_MANAGER.allocate(batch)
+
+        with self.capture_hidden_states() as hidden_states:
+            self.forward(
                 batch.input_ids,
                 batch.position_ids,
                 batch.cu_seqlen_prefill,
@@ -818,8 +1063,45 @@ class FlashCausalLM(Model):
                 batch.slots[batch.slot_indices],
                 batch.input_lengths_tensor,
                 batch.max_seqlen,
-                batch.prefill_head_indices,
             )
+        pooled = torch.stack(
+            [
+                sequence_hidden_states.mean(0)
+                for sequence_hidden_states in torch.split(
+                    hidden_states[0], batch.input_lengths
+                )
+            ]
+        )
+        del batch
+        return torch.nn.functional.normalize(pooled.float(), dim=-1).tolist()
+
+    def generate_token_stream(
+        self, batch: FlashCausalLMBatch
+    ) -> Generator[Generation, None, Tuple[List[Generation], Optional[FlashCausalLMBatch]]]:
+        prefill = batch.cu_seqlen_prefill is not None
+        prefill_logprobs = batch.prefill_next_token_indices is not None
+
+        if batch.needed_blocks_slots:
+            # Allocate blocks to this batch
+            CACHE_MANAGER.allocate(batch)
+            if any(batch.cache_lengths):
+                # Only prefill the input tokens following the cached prefixes
+                batch.prepare_prefill(batch.input_lengths)
+
+        try:
+            if prefill and any(batch.cache_lengths):
+                out = self.forward_cached_prefill(batch, batch.prefill_head_indices)
+            else:
+                out = self.forward(
+                    batch.input_ids,
+                    batch.position_ids,
+                    batch.cu_seqlen_prefill,
+                    batch.block_tables_tensor,
+                    batch.slots[batch.slot_indices],
+                    batch.input_lengths_tensor,
+                    batch.max_seqlen,
+                    batch.prefill_head_indices,
+                )
         except Exception as e:
             del batch
             raise e
@@ -831,10 +1113,14 @@ class FlashCausalLM(Model):
         else:
             next_token_logits = out
 
-        next_input_ids, next_token_logprobs = batch.next_token_chooser(
+        next_input_ids, next_token_logprobs, logprobs = batch.next_token_chooser(
             batch.all_input_ids_tensor[:, : batch.max_seqlen], next_token_logits
         )
 
+        batch_top_token_ids, batch_top_token_logprobs = batch_top_tokens(
+            [r.top_n_tokens for r in batch.requests], logprobs
+        )
+
         if prefill:
             if len(batch) > 1 and prefill_logprobs:
                 # We create the prefill_tokens_indices tensor that will be used to gather prefill logprobs
@@ -859,6 +1145,7 @@ class FlashCausalLM(Model):
         # Zipped iterator
         iterator = zip(
             batch.input_lengths,
+            batch.cache_lengths,
             batch.all_input_ids,
         )
 
@@ -869,13 +1156,18 @@ class FlashCausalLM(Model):
         # For each member of the batch
         for i, (
             input_length,
+            cache_length,
             all_input_ids,
         ) in enumerate(iterator):
-            # Indexing metadata
-            start_index = cumulative_length
-            end_index = cumulative_length + input_length
-
             if prefill:
+                # Indexing metadata
+                start_index = cumulative_length
+                end_index = cumulative_length + input_length - cache_length
+                cumulative_length = end_index
+
+                # The KV cache of the whole prompt is computed, later requests can reuse it
+                CACHE_MANAGER.register(all_input_ids, batch.block_tables[i])
+
                 # Indexing metadata
                 out_start_index = batch.prefill_cu_outlens[i]
                 out_end_index = batch.prefill_cu_outlens[i + 1]
@@ -900,8 +1192,6 @@ class FlashCausalLM(Model):
 
             batch.all_input_ids_tensor[i, input_length] = next_input_ids[i]
 
-            cumulative_length += input_length
-
         # Set values in batch
         batch.input_ids = next_input_ids
         batch.position_ids = next_position_ids + 1
@@ -925,6 +1215,7 @@ class FlashCausalLM(Model):
         iterator = zip(
             batch.requests,
             batch.input_lengths,
+            batch.cache_lengths,
             batch.prefix_offsets,
             batch.read_offsets,
             batch.stopping_criterias,
@@ -933,12 +1224,15 @@ class FlashCausalLM(Model):
             batch.next_token_chooser.seeds,
             next_token_ids,
             next_token_logprobs,
+            batch_top_token_ids,
+            batch_top_token_logprobs,
         )
 
         # For each member of the batch
         for i, (
             request,
             input_length,
+            cache_length,
             prefix_offset,
             read_offset,
             stopping_criteria,
@@ -947,6 +1241,8 @@ class FlashCausalLM(Model):
             seed,
             next_token_id,
             next_token_logprob,
+            top_token_ids,
+            top_token_logprobs,
         ) in enumerate(iterator):
             # Append next token to all tokens
             all_input_ids.append(next_token_id)
@@ -966,6 +1262,11 @@ class FlashCausalLM(Model):
 
             if not stop:
                 stopped = False
+            elif request.HasField("session_id"):
+                # The last generated token has no KV cache yet
+                CACHE_MANAGER.pin_session(
+                    request.session_id, all_input_ids[:-1], batch.block_tables[i]
+                )
 
             # Shard generations
             # All generations will be appended in the rust sharded client
@@ -990,7 +1291,10 @@ class FlashCausalLM(Model):
                     out_end_index = batch.prefill_cu_outlens[i + 1]
 
                     # Remove generated token to only have prefill and add nan for first prompt token
-                    request_prefill_logprobs = [float("nan")] + prefill_logprobs[
+                    # and the cached prompt tokens
+                    request_prefill_logprobs = [float("nan")] * (
+                        cache_length + 1
+                    ) + prefill_logprobs[
                         out_start_index : out_end_index - 1
                     ]
                     prefill_token_ids = all_input_ids[:-1]
@@ -1013,9 +1317,11 @@ class FlashCausalLM(Model):
                     next_token_text,
                     next_token_id in self.all_special_ids,
                     generated_text,
+                    self.decode_top_tokens(top_token_ids, top_token_logprobs),
                 )
 
                 generations.append(generation)
+                yield generation
 
             # Update values
             batch.input_lengths[i] = input_length + 1
@@ -14,6 +14,7 @@ from text_generation_server.models.causal_lm import CausalLMBatch
 from text_generation_server.pb import generate_pb2
 from text_generation_server.models.custom_modeling.opt_modeling import OPTForCausalLM
 from text_generation_server.utils import (
+    batch_tokenize,
     NextTokenChooser,
     StoppingCriteria,
     initialize_torch_distributed,
@@ -91,13 +92,12 @@ class GalacticaCausalLMBatch(CausalLMBatch):
             inputs.append(escape_custom_split_sequence(r.inputs))
             max_truncation = max(max_truncation, r.truncate)
 
-        tokenized_inputs = tokenizer(
+        tokenized_inputs = batch_tokenize(
+            tokenizer,
             inputs,
-            return_tensors="pt",
+            [r.add_special_tokens for r in pb.requests],
+            max_truncation,
             padding=True,
-            return_token_type_ids=False,
-            truncation=True,
-            max_length=max_truncation,
         ).to(device)
         input_lengths = tokenized_inputs["attention_mask"].sum(1)
 
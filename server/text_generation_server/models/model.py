@@ -2,17 +2,27 @@ import inspect
 import torch
 
 from abc import ABC, abstractmethod
-from typing import List, Tuple, Optional, TypeVar, Type
+from contextlib import contextmanager
+from opentelemetry import trace
+from typing import Generator, List, Tuple, Optional, TypeVar, Type
 from peft import PeftModel
 from transformers import PreTrainedTokenizerBase, PretrainedConfig
 
-from text_generation_server.models.types import Batch, GeneratedText
+from text_generation_server.models.types import Batch, Generation, TopTokens
+from text_generation_server.pb import generate_pb2
 from text_generation_server.pb.generate_pb2 import InfoResponse
 
+tracer = trace.get_tracer(__name__)
+
 B = TypeVar("B", bound=Batch)
 
 
 class Model(ABC):
+    # Whether the model reuses the KV cache of the prefixes shared with previous requests
+    supports_prefix_caching = False
+    # Number of input tokens each image of the inputs is embedded into, 0 if images are not supported
+    image_tokens = 0
+
     def __init__(
         self,
         model: torch.nn.Module,
@@ -50,6 +60,13 @@ class Model(ABC):
             requires_padding=self.requires_padding,
             dtype=str(self.dtype),
             device_type=self.device.type,
+            image_tokens=self.image_tokens,
+            supports_embeddings=type(self).embed is not Model.embed,
+            supports_prefix_caching=self.supports_prefix_caching,
+            supports_chunked_prefill=type(self).prefill_chunk
+            is not Model.prefill_chunk,
+            supports_sessions=type(self).release_sessions
+            is not Model.release_sessions,
         )
 
     @property
@@ -58,13 +75,80 @@ class Model(ABC):
         raise NotImplementedError
 
     @abstractmethod
-    def generate_token(self, batch: B) -> Tuple[List[GeneratedText], Optional[B]]:
+    def generate_token_stream(
+        self, batch: B
+    ) -> Generator[Generation, None, Tuple[List[Generation], Optional[B]]]:
+        """Generate a token for each request of the batch
+
+        Yields the generation of each request as soon as it is ready, then returns all the
+        generations and the next batch, None if every request stopped.
+        """
         raise NotImplementedError
 
+    @tracer.start_as_current_span("generate_token")
+    def generate_token(self, batch: B) -> Tuple[List[Generation], Optional[B]]:
+        stream = self.generate_token_stream(batch)
+        while True:
+            try:
+                next(stream)
+            except StopIteration as stop:
+                return stop.value
+
     def warmup(self, batch: B) -> Optional[int]:
         self.generate_token(batch)
         return None
 
+    def prefill_chunk(self, batch: B, pb: generate_pb2.Batch):
+        """Fill the KV cache of the `prefill_chunk_len` input tokens of each request of `pb`
+        following its `prefill_offset`, without generating any token"""
+        raise NotImplementedError(
+            f"{self.__class__.__name__} does not support chunked prefills"
+        )
+
+    def decode_top_tokens(
+        self, token_ids: List[int], logprobs: List[float]
+    ) -> Optional[TopTokens]:
+        """Decode the most likely tokens of a generation, None if they were not requested"""
+        if not token_ids:
+            return None
+        texts = self.tokenizer.batch_decode(
+            [[token_id] for token_id in token_ids],
+            clean_up_tokenization_spaces=False,
+            skip_special_tokens=False,
+        )
+        is_special = [token_id in self.all_special_ids for token_id in token_ids]
+        return TopTokens(token_ids, logprobs, texts, is_special)
+
+    def embed(self, inputs: List[str], truncate: int) -> List[List[float]]:
+        """Pool the last hidden states of each input into a normalized embedding"""
+        raise NotImplementedError(
+            f"{self.__class__.__name__} does not support embeddings"
+        )
+
+    def release_sessions(self, session_ids: List[str]):
+        """Release the KV cache pinned by the last turn of the sessions"""
+        pass
+
+    @contextmanager
+    def capture_hidden_states(self):
+        """Capture the last hidden states of the model, the inputs of its language modeling head"""
+        head = None
+        for name in ["lm_head", "embed_out"]:
+            head = getattr(self.model, name, None)
+            if head is not None:
+                break
+        if head is None:
+            head = self.model.get_output_embeddings()
+
+        hidden_states = []
+        handle = head.register_forward_hook(
+            lambda module, args, output: hidden_states.append(args[0])
+        )
+        try:
+            yield hidden_states
+        finally:
+            handle.remove()
+
     def decode_token(
         self,
         all_input_ids: List[int],
@@ -101,3 +185,16 @@ class Model(ABC):
             raise RuntimeError(
                 f"found uninitialized parameters in model {self.__class__.__name__}: {uninitialized_parameters}"
             )
+
+
+def mean_pooling(
+    hidden_states: torch.Tensor, attention_mask: torch.Tensor
+) -> List[List[float]]:
+    """Average the hidden states of the tokens of each sequence and normalize the result
+
+    `hidden_states` is of shape [batch_size, seq_len, hidden_size] and `attention_mask` of shape
+    [batch_size, seq_len], zero for the padding tokens.
+    """
+    mask = attention_mask.unsqueeze(-1).to(hidden_states.dtype)
+    pooled = (hidden_states * mask).sum(1) / mask.sum(1).clamp(min=1)
+    return torch.nn.functional.normalize(pooled.float(), dim=-1).tolist()
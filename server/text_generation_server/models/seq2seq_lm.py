@@ -3,9 +3,10 @@ import torch
 from dataclasses import dataclass
 from opentelemetry import trace
 from transformers import AutoTokenizer, AutoModelForSeq2SeqLM, PreTrainedTokenizerBase
-from typing import Optional, Tuple, List, Type, Dict
+from typing import Generator, Optional, Tuple, List, Type, Dict
 
 from text_generation_server.models import Model
+from text_generation_server.models.model import mean_pooling
 from text_generation_server.models.types import (
     GeneratedText,
     Batch,
@@ -13,7 +14,13 @@ from text_generation_server.models.types import (
     PrefillTokens,
 )
 from text_generation_server.pb import generate_pb2
-from text_generation_server.utils import NextTokenChooser, StoppingCriteria, Sampling
+from text_generation_server.utils import (
+    NextTokenChooser,
+    StoppingCriteria,
+    Sampling,
+    batch_tokenize,
+    batch_top_tokens,
+)
 
 tracer = trace.get_tracer(__name__)
 
@@ -104,13 +111,12 @@ class Seq2SeqLMBatch(Batch):
             )
 
         # Tokenize batch
-        tokenized_inputs = tokenizer(
+        tokenized_inputs = batch_tokenize(
+            tokenizer,
             inputs,
-            return_tensors="pt",
+            [r.add_special_tokens for r in pb.requests],
+            max_truncation,
             padding=True,
-            return_token_type_ids=False,
-            truncation=True,
-            max_length=max_truncation,
         ).to(device)
 
         input_lengths = tokenized_inputs["attention_mask"].sum(1)
@@ -585,10 +591,31 @@ class Seq2SeqLM(Model):
             outputs.past_key_values,
         )
 
-    @tracer.start_as_current_span("generate_token")
-    def generate_token(
+    @tracer.start_as_current_span("embed")
+    def embed(self, inputs: List[str], truncate: int) -> List[List[float]]:
+        tokenized_inputs = batch_tokenize(
+            self.tokenizer, inputs, [True] * len(inputs), truncate, padding=True
+        ).to(self.device)
+        attention_mask = tokenized_inputs["attention_mask"]
+        # The embeddings pool the outputs of the encoder, the decoder only sees its first token
+        decoder_input_ids = (
+            torch.tensor(self.tokenizer.bos_token_id, device=self.device)
+            .repeat(len(inputs))
+            .view(-1, 1)
+        )
+
+        _, encoder_last_hidden_state, _ = self.forward(
+            tokenized_inputs["input_ids"],
+            attention_mask,
+            decoder_input_ids,
+            None,
+            None,
+        )
+        return mean_pooling(encoder_last_hidden_state, attention_mask)
+
+    def generate_token_stream(
         self, batch: Seq2SeqLMBatch
-    ) -> Tuple[List[Generation], Optional[Seq2SeqLMBatch]]:
+    ) -> Generator[Generation, None, Tuple[List[Generation], Optional[Seq2SeqLMBatch]]]:
         if batch.decoder_attention_mask is not None:
             # slice to the correct shape
             decoder_attention_mask = batch.decoder_attention_mask[
@@ -698,6 +725,13 @@ class Seq2SeqLM(Model):
                 else:
                     prefill_tokens = None
 
+                top_token_ids, top_token_logprobs = batch_top_tokens(
+                    [request.top_n_tokens], logprobs
+                )
+                top_tokens = self.decode_top_tokens(
+                    top_token_ids[0], top_token_logprobs[0]
+                )
+
                 generation = Generation(
                     request.id,
                     prefill_tokens,
@@ -706,9 +740,11 @@ class Seq2SeqLM(Model):
                     next_token_text,
                     next_token_id_squeezed.item() in self.all_special_ids,
                     generated_text,
+                    top_tokens,
                 )
 
                 generations.append(generation)
+                yield generation
 
             # Update values
             batch.decoder_input_ids[i] = next_token_id
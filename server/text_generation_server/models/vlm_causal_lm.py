@@ -0,0 +1,186 @@
+import io
+import torch
+
+from dataclasses import dataclass
+from PIL import Image
+from transformers import (
+    AutoImageProcessor,
+    LlavaForConditionalGeneration,
+    PreTrainedTokenizerBase,
+)
+from typing import Optional, Tuple, List, Type
+
+from text_generation_server.models.causal_lm import CausalLM, CausalLMBatch
+from text_generation_server.pb import generate_pb2
+from text_generation_server.utils import batch_tokenize
+
+
+@dataclass
+class VlmCausalLMBatch(CausalLMBatch):
+    # Images of the requests in the order of their placeholder tokens, None after the prefill
+    pixel_values: Optional[torch.Tensor] = None
+
+    # Set by the model, the batches being built from the requests only
+    image_processor = None
+    image_token_id = 0
+    image_tokens = 0
+
+    @classmethod
+    def from_pb(
+        cls,
+        pb: generate_pb2.Batch,
+        tokenizer: PreTrainedTokenizerBase,
+        dtype: torch.dtype,
+        device: torch.device,
+    ) -> "VlmCausalLMBatch":
+        batch = super().from_pb(pb=pb, tokenizer=tokenizer, dtype=dtype, device=device)
+
+        images = [
+            Image.open(io.BytesIO(chunk.image.data)).convert("RGB")
+            for r in pb.requests
+            for chunk in r.input_chunks
+            if chunk.WhichOneof("chunk") == "image"
+        ]
+        if images:
+            batch.pixel_values = cls.image_processor(images, return_tensors="pt")[
+                "pixel_values"
+            ].to(device, dtype)
+        return batch
+
+    @classmethod
+    def tokenize(
+        cls,
+        pb: generate_pb2.Batch,
+        tokenizer: PreTrainedTokenizerBase,
+        max_truncation: int,
+    ):
+        if not any(r.input_chunks for r in pb.requests):
+            return super().tokenize(pb, tokenizer, max_truncation)
+
+        encodings = []
+        for r in pb.requests:
+            if not r.input_chunks:
+                input_ids = batch_tokenize(
+                    tokenizer, [r.inputs], [r.add_special_tokens], max_truncation
+                )[0]
+                encodings.append({"input_ids": input_ids})
+                continue
+
+            # The router checked the length of the inputs with images, which are not truncated
+            input_ids = []
+            for chunk in r.input_chunks:
+                if chunk.WhichOneof("chunk") == "image":
+                    input_ids.extend([cls.image_token_id] * cls.image_tokens)
+                else:
+                    # Only the first chunk starts with the special tokens
+                    input_ids.extend(
+                        tokenizer(
+                            chunk.text,
+                            add_special_tokens=r.add_special_tokens and not input_ids,
+                        )["input_ids"]
+                    )
+            encodings.append({"input_ids": input_ids})
+        return tokenizer.pad(encodings, return_tensors="pt")
+
+
+class VlmCausalLM(CausalLM):
+    model_class = LlavaForConditionalGeneration
+
+    def __init__(
+        self,
+        model_id: str,
+        base_model_id: Optional[str] = None,
+        revision: Optional[str] = None,
+        quantize: Optional[str] = None,
+        dtype: Optional[torch.dtype] = None,
+        trust_remote_code: bool = False,
+        peft: bool = False,
+        use_flash_attention: bool = False,
+    ):
+        super(VlmCausalLM, self).__init__(
+            model_id,
+            base_model_id,
+            revision,
+            quantize=quantize,
+            dtype=dtype,
+            trust_remote_code=trust_remote_code,
+            peft=peft,
+            use_flash_attention=use_flash_attention,
+        )
+
+        config = self.model.config
+        vision_config = config.vision_config
+        # One token per patch, plus the CLS token unless it is dropped
+        self.image_tokens = (vision_config.image_size // vision_config.patch_size) ** 2
+        if config.vision_feature_select_strategy == "full":
+            self.image_tokens += 1
+
+        VlmCausalLMBatch.image_processor = AutoImageProcessor.from_pretrained(
+            base_model_id or model_id,
+            revision=revision,
+            trust_remote_code=trust_remote_code,
+        )
+        VlmCausalLMBatch.image_token_id = config.image_token_index
+        VlmCausalLMBatch.image_tokens = self.image_tokens
+
+    @property
+    def batch_type(self) -> Type[VlmCausalLMBatch]:
+        return VlmCausalLMBatch
+
+    def embed_images(
+        self, input_ids: torch.Tensor, pixel_values: torch.Tensor
+    ) -> torch.Tensor:
+        """Embed the inputs, replacing the embeddings of the image placeholder tokens with the
+        projected features of the images"""
+        config = self.model.config
+        inputs_embeds = self.model.get_input_embeddings()(input_ids)
+
+        image_outputs = self.model.vision_tower(pixel_values, output_hidden_states=True)
+        image_features = image_outputs.hidden_states[config.vision_feature_layer]
+        if config.vision_feature_select_strategy == "default":
+            # Drop the CLS token
+            image_features = image_features[:, 1:]
+        image_features = self.model.multi_modal_projector(image_features)
+
+        mask = input_ids == config.image_token_index
+        inputs_embeds[mask] = image_features.reshape(-1, inputs_embeds.shape[-1]).to(
+            inputs_embeds.dtype
+        )
+        return inputs_embeds
+
+    def forward(
+        self,
+        input_ids,
+        attention_mask,
+        position_ids,
+        past_key_values: Optional = None,
+        pixel_values: Optional[torch.Tensor] = None,
+    ) -> Tuple[torch.Tensor, List[Tuple[torch.Tensor, torch.Tensor]]]:
+        kwargs = {
+            "attention_mask": attention_mask,
+            "position_ids": position_ids,
+            "past_key_values": past_key_values,
+            "use_cache": True,
+            "return_dict": True,
+        }
+        if pixel_values is None:
+            kwargs["input_ids"] = input_ids
+        else:
+            kwargs["inputs_embeds"] = self.embed_images(input_ids, pixel_values)
+
+        outputs = self.model.language_model(**kwargs)
+        return outputs.logits, outputs.past_key_values
+
+    def forward_batch(
+        self, batch: VlmCausalLMBatch, attention_mask: torch.Tensor
+    ) -> Tuple[torch.Tensor, List[Tuple[torch.Tensor, torch.Tensor]]]:
+        pixel_values = batch.pixel_values
+        # The images are only embedded by the prefill
+        batch.pixel_values = None
+        return self.forward(
+            batch.input_ids,
+            attention_mask,
+            batch.position_ids,
+            batch.past_key_values,
+            pixel_values,
+        )
@@ -2,6 +2,7 @@ import asyncio
 import os
 import torch
 
+import grpc
 from grpc import aio
 from loguru import logger
 
@@ -53,6 +54,17 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
 
         return generate_pb2.FilterBatchResponse(batch=filtered_batch.to_pb())
 
+    async def Embed(self, request, context):
+        embeddings = self.model.embed(list(request.inputs), request.truncate)
+
+        return generate_pb2.EmbedResponse(
+            embeddings=[generate_pb2.Embedding(values=values) for values in embeddings]
+        )
+
+    async def ReleaseSessions(self, request, context):
+        self.model.release_sessions(list(request.session_ids))
+        return generate_pb2.ReleaseSessionsResponse()
+
     async def Warmup(self, request, context):
         batch = self.model.batch_type.from_pb(
             request.batch, self.model.tokenizer, self.model.dtype, self.model.device
@@ -64,9 +76,18 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         )
 
     async def Prefill(self, request, context):
-        batch = self.model.batch_type.from_pb(
-            request.batch, self.model.tokenizer, self.model.dtype, self.model.device
-        )
+        # The batch of the previous chunks of a chunked prefill is continued
+        batch = self.cache.pop(request.batch.id)
+        if batch is None:
+            batch = self.model.batch_type.from_pb(
+                request.batch, self.model.tokenizer, self.model.dtype, self.model.device
+            )
+
+        if any(r.HasField("prefill_chunk_len") for r in request.batch.requests):
+            self.model.prefill_chunk(batch, request.batch)
+            self.cache.set(batch)
+            # No request has a generation before the last chunk
+            return generate_pb2.PrefillResponse()
 
         generations, next_batch = self.model.generate_token(batch)
         self.cache.set(next_batch)
@@ -77,6 +98,35 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
         )
 
     async def Decode(self, request, context):
+        batch = self._decode_batch(request)
+        generations, next_batch = self.model.generate_token(batch)
+        self.cache.set(next_batch)
+
+        return generate_pb2.DecodeResponse(
+            generations=[generation.to_pb() for generation in generations],
+            batch=next_batch.to_pb() if next_batch else None,
+        )
+
+    async def DecodeStream(self, request, context):
+        batch = self._decode_batch(request)
+        stream = self.model.generate_token_stream(batch)
+
+        # Every generation is sent as soon as its request is processed, the next batch last
+        while True:
+            try:
+                generation = next(stream)
+            except StopIteration as stop:
+                _, next_batch = stop.value
+                break
+            yield generate_pb2.DecodeStreamResponse(generations=[generation.to_pb()])
+
+        self.cache.set(next_batch)
+        yield generate_pb2.DecodeStreamResponse(
+            batch=next_batch.to_pb() if next_batch else None
+        )
+
+    def _decode_batch(self, request):
+        """Pop the cached batches of a decode request and concatenate them"""
         if len(request.batches) == 0:
             raise ValueError("Must provide at least one batch")
 
@@ -91,17 +141,8 @@ class TextGenerationService(generate_pb2_grpc.TextGenerationServiceServicer):
             raise ValueError("All batches are empty")
 
         if len(batches) > 1:
-            batch = self.model.batch_type.concatenate(batches)
-        else:
-            batch = batches[0]
-
-        generations, next_batch = self.model.generate_token(batch)
-        self.cache.set(next_batch)
-
-        return generate_pb2.DecodeResponse(
-            generations=[generation.to_pb() for generation in generations],
-            batch=next_batch.to_pb() if next_batch else None,
-        )
+            return self.model.batch_type.concatenate(batches)
+        return batches[0]
 
 
 def serve(
@@ -114,6 +155,12 @@ def serve(
         trust_remote_code: bool,
         peft: bool,
         uds_path: Path,
+        tls_port: Optional[int] = None,
+        tls_hostname: str = "localhost",
+        tls_cert: Optional[Path] = None,
+        tls_key: Optional[Path] = None,
+        tls_client_ca: Optional[Path] = None,
+        compression: Optional[str] = None,
 ):
     async def serve_inner(
             model_id: str,
@@ -136,6 +183,16 @@ def serve(
             local_url = unix_socket_template.format(uds_path, 0)
             server_urls = [local_url]
 
+        # The unix socket is kept for the launcher, the router connects to the TLS port
+        tls_url = None
+        if tls_port is not None:
+            rank = int(os.environ["RANK"]) if sharded else 0
+            tls_url = "[::]:{}".format(tls_port + rank)
+            server_urls = [
+                "https://{}:{}".format(tls_hostname, tls_port + rank)
+                for rank in range(len(server_urls))
+            ]
+
         try:
             model = get_model(
                 model_id, base_model_id, revision, sharded, quantize, dtype, trust_remote_code, peft
@@ -159,11 +216,13 @@ def serve(
             except ImportError:
                 pass
 
+        # Compression of the responses, the compressed requests are always accepted
         server = aio.server(
             interceptors=[
                 ExceptionInterceptor(),
                 UDSOpenTelemetryAioServerInterceptor(),
-            ]
+            ],
+            compression=grpc.Compression.Gzip if compression == "gzip" else None,
         )
         generate_pb2_grpc.add_TextGenerationServiceServicer_to_server(
             TextGenerationService(model, Cache(), server_urls), server
@@ -174,10 +233,21 @@ def serve(
         )
         reflection.enable_server_reflection(SERVICE_NAMES, server)
         server.add_insecure_port(local_url)
+        if tls_url is not None:
+            # Clients must present a certificate signed by `tls_client_ca` when it is set
+            client_ca = tls_client_ca.read_bytes() if tls_client_ca is not None else None
+            credentials = grpc.ssl_server_credentials(
+                [(tls_key.read_bytes(), tls_cert.read_bytes())],
+                root_certificates=client_ca,
+                require_client_auth=client_ca is not None,
+            )
+            server.add_secure_port(tls_url, credentials)
 
         await server.start()
 
         logger.info("Server started at {}".format(local_url))
+        if tls_url is not None:
+            logger.info("TLS server started at {}".format(tls_url))
 
         try:
             await server.wait_for_termination()
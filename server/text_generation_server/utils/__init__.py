@@ -17,6 +17,8 @@ from text_generation_server.utils.tokens import (
     FinishReason,
     Sampling,
     Greedy,
+    batch_tokenize,
+    batch_top_tokens,
 )
 
 __all__ = [
@@ -37,4 +39,6 @@ __all__ = [
     "StopSequenceCriteria",
     "FinishReason",
     "Weights",
+    "batch_tokenize",
+    "batch_top_tokens",
 ]
@@ -0,0 +1,227 @@
+import bisect
+import json
+import torch
+
+from functools import lru_cache
+from typing import Dict, FrozenSet, List, Optional, Tuple, Union
+
+from transformers import PreTrainedTokenizerBase
+
+# Position of the next element to match in a rule: (rule, alternative, index)
+Position = Tuple[int, int, int]
+# Positions in the rules being matched, innermost last. The empty stack matched the whole grammar
+Stack = Tuple[Position, ...]
+# All the stacks that can match the text generated so far
+State = FrozenSet[Stack]
+
+# Maximum number of cached transitions and token masks of a grammar
+MAX_CACHED_TRANSITIONS = 1 << 18
+MAX_CACHED_MASKS = 512
+
+SPIECE_UNDERLINE = "▁"
+
+
+class CharSet:
+    """Characters in inclusive ranges of code points, or out of them when negated"""
+
+    def __init__(self, ranges: List[Tuple[int, int]], negated: bool):
+        merged = []
+        for first, last in sorted(ranges):
+            if merged and first <= merged[-1][1] + 1:
+                merged[-1][1] = max(merged[-1][1], last)
+            else:
+                merged.append([first, last])
+        self.firsts = [first for first, _ in merged]
+        self.lasts = [last for _, last in merged]
+        self.negated = negated
+
+    def __contains__(self, code_point: int) -> bool:
+        i = bisect.bisect_right(self.firsts, code_point) - 1
+        return (i >= 0 and code_point <= self.lasts[i]) != self.negated
+
+
+class Grammar:
+    """
+    Pushdown automaton compiled by the router from a JSON schema, a regular expression or a GBNF
+    grammar. Every rule is a list of alternative sequences of characters and references to rules.
+
+    The state of a generation is the set of stacks of positions that can match the text generated
+    so far. States repeat a lot, so the transitions and the allowed tokens are cached.
+    """
+
+    def __init__(self, root: int, rules: List[List[List[Union[int, CharSet]]]]):
+        self.rules = rules
+        self.initial_state = self._expand(
+            [((root, alternative, 0),) for alternative in range(len(rules[root]))]
+        )
+        self._transitions: Dict[Tuple[State, str], State] = {}
+        self._masks: Dict[State, torch.Tensor] = {}
+
+    @classmethod
+    def from_json(cls, automaton: str) -> "Grammar":
+        automaton = json.loads(automaton)
+        rules = [
+            [
+                [
+                    element["rule"]
+                    if "rule" in element
+                    else CharSet(
+                        element["chars"]["ranges"], element["chars"]["negated"]
+                    )
+                    for element in sequence
+                ]
+                for sequence in alternatives
+            ]
+            for alternatives in automaton["rules"]
+        ]
+        return cls(automaton["root"], rules)
+
+    def _expand(self, stacks: List[Stack]) -> State:
+        """Expand the stacks until they are empty or their innermost element is a character set"""
+        expanded = set()
+        seen = set()
+        while stacks:
+            stack = stacks.pop()
+            # Also stops the expansion of rules that are left recursive through empty rules
+            if stack in seen:
+                continue
+            seen.add(stack)
+
+            if not stack:
+                expanded.add(stack)
+                continue
+            rule, alternative, index = stack[-1]
+            sequence = self.rules[rule][alternative]
+            if index == len(sequence):
+                stacks.append(stack[:-1])
+                continue
+            element = sequence[index]
+            if isinstance(element, CharSet):
+                expanded.add(stack)
+                continue
+
+            # Continue after the reference once the referenced rule is matched. The position is
+            # dropped at the end of a sequence so that the stacks of right recursive rules stay bounded
+            parent = stack[:-1]
+            if index + 1 < len(sequence):
+                parent += ((rule, alternative, index + 1),)
+            for referenced_alternative in range(len(self.rules[element])):
+                stacks.append(parent + ((element, referenced_alternative, 0),))
+        return frozenset(expanded)
+
+    def accept(self, state: State, char: str) -> State:
+        """State once `char` is generated, empty if the grammar does not allow it"""
+        key = (state, char)
+        next_state = self._transitions.get(key)
+        if next_state is None:
+            code_point = ord(char)
+            advanced = []
+            for stack in state:
+                if not stack:
+                    continue
+                rule, alternative, index = stack[-1]
+                if code_point in self.rules[rule][alternative][index]:
+                    advanced.append(stack[:-1] + ((rule, alternative, index + 1),))
+            next_state = self._expand(advanced)
+
+            if len(self._transitions) >= MAX_CACHED_TRANSITIONS:
+                self._transitions.clear()
+            self._transitions[key] = next_state
+        return next_state
+
+    def accept_text(self, state: State, text: str) -> State:
+        for char in text:
+            state = self.accept(state, char)
+            if not state:
+                break
+        return state
+
+    @staticmethod
+    def is_complete(state: State) -> bool:
+        """If the text generated so far matches the whole grammar"""
+        return () in state
+
+    def mask(self, state: State, vocabulary: "Vocabulary") -> torch.Tensor:
+        """Boolean mask of the tokens allowed in `state`"""
+        mask = self._masks.get(state)
+        if mask is not None:
+            return mask
+
+        mask = torch.zeros(vocabulary.size, dtype=torch.bool)
+        allowed = []
+        # Walk the prefix tree of the vocabulary, a subtree is skipped as soon as its prefix
+        # is not allowed
+        nodes = [(vocabulary.root, state)]
+        while nodes:
+            node, node_state = nodes.pop()
+            for char, child in node.children.items():
+                child_state = self.accept(node_state, char)
+                if child_state:
+                    allowed.extend(child.token_ids)
+                    if child.children:
+                        nodes.append((child, child_state))
+        if allowed:
+            mask[allowed] = True
+
+        # Always allow to end the generation instead of getting stuck
+        if vocabulary.eos_token_id is not None and (
+            self.is_complete(state) or not allowed
+        ):
+            mask[vocabulary.eos_token_id] = True
+
+        if len(self._masks) >= MAX_CACHED_MASKS:
+            # Evict the oldest mask
+            del self._masks[next(iter(self._masks))]
+        self._masks[state] = mask
+        return mask
+
+
+@lru_cache(32)
+def compile_grammar(automaton: str) -> Grammar:
+    """Grammars are shared by the requests using the same one, with their caches"""
+    return Grammar.from_json(automaton)
+
+
+class VocabularyNode:
+    def __init__(self):
+        self.children: Dict[str, "VocabularyNode"] = {}
+        self.token_ids: List[int] = []
+
+
+class Vocabulary:
+    """
+    Prefix tree of the text of the tokens
+
+    Special tokens and tokens that are not valid UTF-8 on their own, like the parts of a
+    character split in several byte-level tokens, cannot be matched one character at a time and
+    are never allowed by a grammar.
+    """
+
+    def __init__(self, tokenizer: PreTrainedTokenizerBase):
+        self.root = VocabularyNode()
+        self.eos_token_id = tokenizer.eos_token_id
+        self.strings: Dict[int, str] = {}
+
+        special_ids = set(tokenizer.all_special_ids)
+        vocab = tokenizer.get_vocab()
+        self.size = max(vocab.values()) + 1
+        for token, token_id in vocab.items():
+            if token_id in special_ids:
+                continue
+            string = tokenizer.convert_tokens_to_string([token])
+            # SentencePiece tokenizers strip the leading space of the first token
+            if token.startswith(SPIECE_UNDERLINE) or token == "<0x20>":
+                string = " " + string
+            if not string or "�" in string:
+                continue
+
+            self.strings[token_id] = string
+            node = self.root
+            for char in string:
+                node = node.children.setdefault(char, VocabularyNode())
+            node.token_ids.append(token_id)
+
+
+@lru_cache(4)
+def vocabulary(tokenizer: PreTrainedTokenizerBase) -> Vocabulary:
+    return Vocabulary(tokenizer)
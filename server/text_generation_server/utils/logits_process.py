@@ -7,12 +7,15 @@ from typing import Optional, List, Dict, Union
 from transformers import (
     LogitsWarper,
     LogitsProcessor,
+    PreTrainedTokenizerBase,
     TemperatureLogitsWarper,
     TopKLogitsWarper,
     TopPLogitsWarper,
     TypicalLogitsWarper,
 )
 
+from text_generation_server.utils.grammar import compile_grammar, vocabulary
+
 mempool = torch.cuda.graph_pool_handle() if torch.cuda.is_available() else None
 
 
@@ -118,6 +121,155 @@ class HeterogeneousRepetitionPenaltyLogitsProcessor(LogitsProcessor):
         return None
 
 
+class HeterogeneousFrequencyPenaltyLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] penalizing the generated tokens as the OpenAI API: `frequency_penalty` times their number of
+    occurrences and `presence_penalty` once they occurred are subtracted from their scores.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        frequency_penalty (`List[float]`):
+            The penalty per occurrence of a generated token. 0.0 means no penalty.
+        presence_penalty (`List[float]`):
+            The penalty of the tokens generated at least once. 0.0 means no penalty.
+    """
+
+    def __init__(
+        self,
+        frequency_penalty: List[float],
+        presence_penalty: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+    ):
+        self.frequency_penalty = frequency_penalty
+        self.presence_penalty = presence_penalty
+        self.frequency_penalty_tensor = torch.tensor(
+            frequency_penalty, dtype=dtype, device=device
+        ).unsqueeze(1)
+        self.presence_penalty_tensor = torch.tensor(
+            presence_penalty, dtype=dtype, device=device
+        ).unsqueeze(1)
+        # Occurrences of the generated tokens, created on the first call as we need the vocabulary size
+        self.counts = None
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        if self.counts is None:
+            self.counts = torch.zeros(
+                scores.shape, dtype=torch.int32, device=scores.device
+            )
+        scores.sub_(
+            self.frequency_penalty_tensor * self.counts
+            + self.presence_penalty_tensor * (self.counts > 0)
+        )
+        return scores
+
+    def advance(self, next_ids: torch.Tensor):
+        """Count the selected tokens"""
+        next_ids = next_ids.view(-1)
+        self.counts[torch.arange(len(next_ids), device=next_ids.device), next_ids] += 1
+
+    def restore(
+        self,
+        processors: List[Optional["HeterogeneousFrequencyPenaltyLogitsProcessor"]],
+        sizes: List[int],
+    ):
+        """Concatenate the counts of the processors of concatenated batches"""
+        counts = [
+            processor.counts
+            for processor in processors
+            if processor is not None and processor.counts is not None
+        ]
+        if not counts:
+            return
+        self.counts = torch.cat(
+            [
+                processor.counts
+                if processor is not None and processor.counts is not None
+                else counts[0].new_zeros((size, counts[0].shape[-1]))
+                for processor, size in zip(processors, sizes)
+            ]
+        )
+
+    def filter(self, indices):
+        self.frequency_penalty = [self.frequency_penalty[i] for i in indices]
+        self.presence_penalty = [self.presence_penalty[i] for i in indices]
+        if any([x != 0.0 for x in self.frequency_penalty + self.presence_penalty]):
+            self.frequency_penalty_tensor = self.frequency_penalty_tensor[indices]
+            self.presence_penalty_tensor = self.presence_penalty_tensor[indices]
+            if self.counts is not None:
+                self.counts = self.counts[indices]
+            return self
+        return None
+
+
+class HeterogeneousBadWordsLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] preventing the generation of token sequences: the last token of a sequence is banned when the
+    tokens generated last are the rest of the sequence.
+    This version allows for separate sequences for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        bad_words_ids (`List[List[List[int]]]`):
+            The token sequences that must never be generated, for each member of the batch.
+    """
+
+    def __init__(self, bad_words_ids: List[List[List[int]]]):
+        self.bad_words_ids = bad_words_ids
+        # Tokens generated last, as many as needed to match the longest sequence
+        self.history = [[] for _ in bad_words_ids]
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        rows = []
+        columns = []
+        for i, (bad_words_ids, history) in enumerate(
+            zip(self.bad_words_ids, self.history)
+        ):
+            for ids in bad_words_ids:
+                prefix = ids[:-1]
+                if ids[-1] < scores.shape[-1] and (
+                    not prefix
+                    or (len(prefix) <= len(history) and history[-len(prefix) :] == prefix)
+                ):
+                    rows.append(i)
+                    columns.append(ids[-1])
+        if rows:
+            scores[rows, columns] = -math.inf
+        return scores
+
+    def advance(self, next_ids: List[int]):
+        """Remember the selected tokens"""
+        for bad_words_ids, history, next_id in zip(
+            self.bad_words_ids, self.history, next_ids
+        ):
+            max_prefix_length = max([len(ids) - 1 for ids in bad_words_ids], default=0)
+            if max_prefix_length > 0:
+                history.append(next_id)
+                del history[:-max_prefix_length]
+
+    def restore(
+        self,
+        processors: List[Optional["HeterogeneousBadWordsLogitsProcessor"]],
+        sizes: List[int],
+    ):
+        """Concatenate the histories of the processors of concatenated batches"""
+        self.history = [
+            history
+            for processor, size in zip(processors, sizes)
+            for history in (
+                processor.history if processor is not None else [[] for _ in range(size)]
+            )
+        ]
+
+    def filter(self, indices):
+        self.bad_words_ids = [self.bad_words_ids[i] for i in indices]
+        self.history = [self.history[i] for i in indices]
+        if any(self.bad_words_ids):
+            return self
+        return None
+
+
 class HeterogeneousTemperatureLogitsWarper:
     r"""
     [`LogitsWarper`] for temperature (exponential scaling output probability distribution).
@@ -379,6 +531,255 @@ class HeterogeneousTypicalLogitsWarper(LogitsWarper):
         return None
 
 
+class HeterogeneousMinPLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs min-p, i.e. discarding the tokens less likely than `min_p` times the most likely one.
+    This version allows for a separate value for each sample.
+    It doesn't validate inputs.
+
+    Args:
+        min_p (`List[float]`):
+            Minimum probability of the tokens relative to the most likely token, 0 disables min-p warping for this
+            member of the batch.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        min_p: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.min_p = min_p
+        self.min_p_tensor = torch.tensor(min_p, dtype=dtype, device=device).unsqueeze(1)
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = scores.softmax(dim=-1)
+        threshold = probs.max(dim=-1, keepdim=True).values * self.min_p_tensor
+        # Not inplace as the scores can be the output buffer of a static warper
+        return scores.masked_fill(probs < threshold, self.filter_value)
+
+    def filter(self, indices):
+        self.min_p = [self.min_p[i] for i in indices]
+        if any([x > 0.0 for x in self.min_p]):
+            self.min_p_tensor = self.min_p_tensor[indices]
+            return self
+        return None
+
+
+class HeterogeneousEpsilonLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs epsilon-sampling, i.e. discarding the tokens less likely than `epsilon`. See
+    [Truncation Sampling as Language Model Desmoothing](https://arxiv.org/abs/2210.15191) for more information.
+    This version allows for a separate value for each sample.
+    It doesn't validate inputs.
+
+    Args:
+        epsilon (`List[float]`):
+            Minimum probability of the tokens, 0 disables epsilon warping for this member of the batch.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        epsilon: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.epsilon = epsilon
+        self.epsilon_tensor = torch.tensor(
+            epsilon, dtype=dtype, device=device
+        ).unsqueeze(1)
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        probs = scores.softmax(dim=-1)
+        # Always keep the most likely token
+        threshold = torch.minimum(
+            self.epsilon_tensor, probs.max(dim=-1, keepdim=True).values
+        )
+        return scores.masked_fill(probs < threshold, self.filter_value)
+
+    def filter(self, indices):
+        self.epsilon = [self.epsilon[i] for i in indices]
+        if any([x > 0.0 for x in self.epsilon]):
+            self.epsilon_tensor = self.epsilon_tensor[indices]
+            return self
+        return None
+
+
+class HeterogeneousEtaLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs eta-sampling, i.e. discarding the tokens less likely than
+    `min(eta, sqrt(eta) * exp(-entropy))`. See [Truncation Sampling as Language Model
+    Desmoothing](https://arxiv.org/abs/2210.15191) for more information.
+    This version allows for a separate value for each sample.
+    It doesn't validate inputs.
+
+    Args:
+        eta (`List[float]`):
+            The eta cutoff, 0 disables eta warping for this member of the batch.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    def __init__(
+        self,
+        eta: List[float],
+        dtype: torch.dtype,
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.eta = eta
+        self.eta_tensor = torch.tensor(eta, dtype=dtype, device=device).unsqueeze(1)
+        self.filter_value = filter_value
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        normalized = torch.nn.functional.log_softmax(scores, dim=-1)
+        probs = torch.exp(normalized)
+        entropy = -(normalized * probs).nansum(-1, keepdim=True)
+        eta = torch.minimum(
+            self.eta_tensor, torch.sqrt(self.eta_tensor) * torch.exp(-entropy)
+        )
+        # Always keep the most likely token
+        threshold = torch.minimum(eta, probs.max(dim=-1, keepdim=True).values)
+        return scores.masked_fill(probs < threshold, self.filter_value)
+
+    def filter(self, indices):
+        self.eta = [self.eta[i] for i in indices]
+        if any([x > 0.0 for x in self.eta]):
+            self.eta_tensor = self.eta_tensor[indices]
+            return self
+        return None
+
+
+class HeterogeneousMirostatLogitsWarper(LogitsWarper):
+    r"""
+    [`LogitsWarper`] that performs mirostat sampling, keeping the surprise of the generated tokens close to `tau` by
+    adjusting the truncation of the distribution after each token. See [Mirostat: A Neural Text Decoding Algorithm
+    that Directly Controls Perplexity](https://arxiv.org/abs/2007.14966) for more information.
+    This version allows for a separate mode and values for each sample.
+    It doesn't validate inputs.
+
+    Args:
+        mode (`List[int]`):
+            1 for mirostat, 2 for mirostat 2.0, 0 disables mirostat for this member of the batch.
+        tau (`List[float]`):
+            The target surprise, in bits.
+        eta (`List[float]`):
+            The learning rate of the maximum surprise.
+        filter_value (`float`, *optional*, defaults to `-float("Inf")`):
+            All filtered values will be set to this float value.
+    """
+
+    # Number of tokens used to estimate the Zipf exponent of the distribution in mirostat 1
+    ZIPF_TOKENS = 100
+
+    def __init__(
+        self,
+        mode: List[int],
+        tau: List[float],
+        eta: List[float],
+        device: torch.device,
+        filter_value: float = -math.inf,
+    ):
+        self.mode = mode
+        self.tau = tau
+        self.eta = eta
+        self.tau_tensor = torch.tensor(tau, dtype=torch.float32, device=device).unsqueeze(1)
+        self.eta_tensor = torch.tensor(eta, dtype=torch.float32, device=device).unsqueeze(1)
+        # Maximum surprise, starting at twice the target. Disabled members never truncate.
+        self.mu = torch.tensor(
+            [2 * t if m else math.inf for m, t in zip(mode, tau)],
+            dtype=torch.float32,
+            device=device,
+        ).unsqueeze(1)
+        self.v1_mask = self._v1_mask(device)
+        self.filter_value = filter_value
+
+    def _v1_mask(self, device):
+        if any([m == 1 for m in self.mode]):
+            return torch.tensor(
+                [m == 1 for m in self.mode], dtype=torch.bool, device=device
+            ).unsqueeze(1)
+        return None
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        logprobs = torch.log_softmax(scores.float(), dim=-1)
+        # Mirostat 2.0 removes the tokens more surprising than mu
+        indices_to_remove = -logprobs / math.log(2) > self.mu
+        if self.v1_mask is not None:
+            indices_to_remove = torch.where(
+                self.v1_mask, self._v1_indices_to_remove(logprobs), indices_to_remove
+            )
+        # Always keep the most likely token
+        indices_to_remove &= logprobs < logprobs.max(dim=-1, keepdim=True).values
+        return scores.masked_fill(indices_to_remove, self.filter_value)
+
+    def _v1_indices_to_remove(self, logprobs: torch.Tensor) -> torch.Tensor:
+        """Mirostat 1 keeps the top k tokens, k is computed from mu and the estimated Zipf exponent"""
+        vocab_size = logprobs.shape[-1]
+        sorted_logprobs, sorted_indices = torch.sort(logprobs, descending=True)
+
+        m = min(self.ZIPF_TOKENS, vocab_size)
+        i = torch.arange(m - 1, dtype=torch.float32, device=logprobs.device)
+        t = torch.log((i + 2) / (i + 1))
+        b = sorted_logprobs[:, : m - 1] - sorted_logprobs[:, 1:m]
+        # Tokens already removed by other warpers have no probability
+        b = torch.nan_to_num(b, nan=0.0, posinf=0.0)
+        s_hat = (t * b).sum(dim=-1, keepdim=True) / (t * t).sum()
+
+        epsilon_hat = s_hat - 1
+        k = (
+            (epsilon_hat * torch.pow(2, self.mu))
+            / (1 - torch.pow(vocab_size, -epsilon_hat))
+        ) ** (1 / s_hat)
+        k = torch.nan_to_num(k, nan=vocab_size).clamp(1, vocab_size)
+
+        ranks = torch.empty_like(sorted_indices)
+        ranks.scatter_(
+            1,
+            sorted_indices,
+            torch.arange(vocab_size, device=logprobs.device).expand_as(sorted_indices),
+        )
+        return ranks >= k
+
+    def advance(self, next_ids: torch.Tensor, logprobs: torch.Tensor):
+        """Move mu towards the target with the surprise of the selected tokens"""
+        surprise = -torch.gather(logprobs.float(), 1, next_ids.view(-1, 1)) / math.log(2)
+        self.mu -= self.eta_tensor * (surprise - self.tau_tensor)
+
+    def restore(
+        self,
+        processors: List[Optional["HeterogeneousMirostatLogitsWarper"]],
+        sizes: List[int],
+    ):
+        """Concatenate the maximum surprises of the warpers of concatenated batches"""
+        self.mu = torch.cat(
+            [
+                processor.mu if processor is not None else mu
+                for processor, mu in zip(processors, torch.split(self.mu, sizes))
+            ]
+        )
+
+    def filter(self, indices):
+        self.mode = [self.mode[i] for i in indices]
+        self.tau = [self.tau[i] for i in indices]
+        self.eta = [self.eta[i] for i in indices]
+        if any(self.mode):
+            self.tau_tensor = self.tau_tensor[indices]
+            self.eta_tensor = self.eta_tensor[indices]
+            self.mu = self.mu[indices]
+            self.v1_mask = self._v1_mask(self.mu.device)
+            return self
+        return None
+
+
 class HeterogeneousProcessorWrapper(LogitsProcessor):
     r"""
     A wrapper for logit warpers or processors without heterogeneous parameter support.
@@ -408,3 +809,188 @@ class HeterogeneousProcessorWrapper(LogitsProcessor):
             self.processors = new_processors
             return self
         return None
+
+
+class HeterogeneousLogitBiasLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] adding a bias to the scores of the given tokens.
+    This version allows for a separate value for each sample and runs inplace when possible.
+    It doesn't validate inputs.
+
+    Args:
+        logit_bias (`List[Dict[int, float]]`):
+            A mapping of token ids to the value added to their scores, for each member of the batch.
+    """
+
+    def __init__(self, logit_bias: List[Dict[int, float]]):
+        self.logit_bias = logit_bias
+        # Built lazily as we need the vocabulary size of the scores
+        self.bias_tensor = None
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        if self.bias_tensor is None:
+            bias_tensor = torch.zeros(
+                len(self.logit_bias), scores.shape[-1], dtype=scores.dtype
+            )
+            for i, logit_bias in enumerate(self.logit_bias):
+                for token_id, bias in logit_bias.items():
+                    # The router validates the ids against the tokenizer, which can be larger than the logits
+                    if token_id < scores.shape[-1]:
+                        bias_tensor[i, token_id] = bias
+            self.bias_tensor = bias_tensor.to(scores.device)
+
+        scores.add_(self.bias_tensor)
+        return scores
+
+    def filter(self, indices):
+        self.logit_bias = [self.logit_bias[i] for i in indices]
+        if any(self.logit_bias):
+            if self.bias_tensor is not None:
+                self.bias_tensor = self.bias_tensor[indices]
+            return self
+        return None
+
+
+class HeterogeneousGrammarLogitProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] only allowing the tokens that keep the generation valid against a grammar.
+    This version allows for a separate grammar for each sample, `None` disables it.
+    It doesn't validate inputs.
+
+    Args:
+        tokenizer (`PreTrainedTokenizerBase`):
+            The tokenizer of the model, used to know the text of the tokens.
+        grammars (`List[Optional[str]]`):
+            The pushdown automatons compiled by the router.
+    """
+
+    def __init__(
+        self, tokenizer: PreTrainedTokenizerBase, grammars: List[Optional[str]]
+    ):
+        self.tokenizer = tokenizer
+        self.grammars = [
+            compile_grammar(grammar) if grammar else None for grammar in grammars
+        ]
+        self.states = [
+            grammar.initial_state if grammar is not None else None
+            for grammar in self.grammars
+        ]
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        vocab = vocabulary(self.tokenizer)
+        mask = torch.ones(scores.shape, dtype=torch.bool)
+        for i, (grammar, state) in enumerate(zip(self.grammars, self.states)):
+            if grammar is None:
+                continue
+            # The logits can be larger than the vocabulary, the padding is never allowed
+            mask[i] = False
+            allowed = grammar.mask(state, vocab)[: scores.shape[-1]]
+            mask[i, : allowed.shape[0]] = allowed
+        scores.masked_fill_(~mask.to(scores.device), -math.inf)
+        return scores
+
+    def restore(
+        self,
+        processors: List[Optional["HeterogeneousGrammarLogitProcessor"]],
+        sizes: List[int],
+    ):
+        """Concatenate the states of the processors of concatenated batches"""
+        self.states = [
+            state
+            for processor, size in zip(processors, sizes)
+            for state in (
+                processor.states if processor is not None else [None] * size
+            )
+        ]
+
+    def advance(self, next_ids: List[int]):
+        """Move the grammars forward with the selected tokens"""
+        vocab = vocabulary(self.tokenizer)
+        for i, (grammar, state, next_id) in enumerate(
+            zip(self.grammars, self.states, next_ids)
+        ):
+            if grammar is not None:
+                # The end of sequence token has no text and ends the generation anyway
+                text = vocab.strings.get(next_id, "")
+                self.states[i] = grammar.accept_text(state, text)
+
+    def filter(self, indices):
+        self.grammars = [self.grammars[i] for i in indices]
+        self.states = [self.states[i] for i in indices]
+        if any([grammar is not None for grammar in self.grammars]):
+            return self
+        return None
+
+
+class HeterogeneousTokenHealingLogitsProcessor(LogitsProcessor):
+    r"""
+    [`LogitsProcessor`] regenerating the text removed from the end of the prompt by the router: only the tokens
+    whose text is consistent with the part of the removed text that was not generated yet are allowed.
+    This version allows for a separate text for each sample, an empty text disables it.
+    It doesn't validate inputs.
+
+    Args:
+        tokenizer (`PreTrainedTokenizerBase`):
+            The tokenizer of the model, used to know the text of the tokens.
+        prefixes (`List[str]`):
+            The text the generation must start with.
+    """
+
+    def __init__(self, tokenizer: PreTrainedTokenizerBase, prefixes: List[str]):
+        self.tokenizer = tokenizer
+        self.prefixes = prefixes
+
+    def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor) -> torch.Tensor:
+        vocab = vocabulary(self.tokenizer)
+        mask = torch.ones(scores.shape, dtype=torch.bool)
+        for i, prefix in enumerate(self.prefixes):
+            if not prefix:
+                continue
+            allowed = []
+            # Tokens whose text is the start of the prefix
+            node = vocab.root
+            for char in prefix:
+                node = node.children.get(char)
+                if node is None:
+                    break
+                allowed.extend(node.token_ids)
+            # Tokens whose text starts with the prefix
+            if node is not None:
+                nodes = list(node.children.values())
+                while nodes:
+                    child = nodes.pop()
+                    allowed.extend(child.token_ids)
+                    nodes.extend(child.children.values())
+            allowed = [token_id for token_id in allowed if token_id < scores.shape[-1]]
+            # The tokenizer cannot produce the prefix, do not get stuck
+            if allowed:
+                mask[i] = False
+                mask[i, allowed] = True
+        scores.masked_fill_(~mask.to(scores.device), -math.inf)
+        return scores
+
+    def advance(self, next_ids: List[int]):
+        """Remove the generated text from the prefixes"""
+        vocab = vocabulary(self.tokenizer)
+        for i, (prefix, next_id) in enumerate(zip(self.prefixes, next_ids)):
+            if prefix:
+                text = vocab.strings.get(next_id, "")
+                self.prefixes[i] = prefix[len(text) :] if prefix.startswith(text) else ""
+
+    def restore(
+        self,
+        processors: List[Optional["HeterogeneousTokenHealingLogitsProcessor"]],
+        sizes: List[int],
+    ):
+        """Concatenate the prefixes of the processors of concatenated batches"""
+        self.prefixes = [
+            prefix
+            for processor, size in zip(processors, sizes)
+            for prefix in (processor.prefixes if processor is not None else [""] * size)
+        ]
+
+    def filter(self, indices):
+        self.prefixes = [self.prefixes[i] for i in indices]
+        if any(self.prefixes):
+            return self
+        return None
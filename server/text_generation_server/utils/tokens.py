@@ -7,21 +7,41 @@ from transformers import (
     NoRepeatNGramLogitsProcessor,
     PreTrainedTokenizerBase,
 )
-from typing import List, Tuple, Optional
+from typing import Dict, List, Tuple, Optional
 
 from text_generation_server.pb import generate_pb2
-from text_generation_server.pb.generate_pb2 import FinishReason
+from text_generation_server.pb.generate_pb2 import FinishReason, GrammarType
 from text_generation_server.utils.watermark import WatermarkLogitsProcessor
 from text_generation_server.utils.logits_process import (
     static_warper,
     HeterogeneousRepetitionPenaltyLogitsProcessor,
+    HeterogeneousFrequencyPenaltyLogitsProcessor,
+    HeterogeneousBadWordsLogitsProcessor,
     HeterogeneousTemperatureLogitsWarper,
     HeterogeneousTopKLogitsWarper,
     HeterogeneousTopPLogitsWarper,
     HeterogeneousTypicalLogitsWarper,
+    HeterogeneousMinPLogitsWarper,
+    HeterogeneousEpsilonLogitsWarper,
+    HeterogeneousEtaLogitsWarper,
+    HeterogeneousMirostatLogitsWarper,
     HeterogeneousProcessorWrapper,
+    HeterogeneousLogitBiasLogitsProcessor,
+    HeterogeneousGrammarLogitProcessor,
+    HeterogeneousTokenHealingLogitsProcessor,
 )
 
+# Grammar types compiled by the router into the pushdown automaton of the shards
+AUTOMATON_GRAMMAR_TYPES = {
+    GrammarType.GRAMMAR_TYPE_JSON,
+    GrammarType.GRAMMAR_TYPE_REGEX,
+    GrammarType.GRAMMAR_TYPE_GBNF,
+}
+
+
+def grammar_automaton(pb: generate_pb2.NextTokenChooserParameters) -> str:
+    return pb.grammar if pb.grammar_type in AUTOMATON_GRAMMAR_TYPES else ""
+
 
 class NextTokenChooser:
     def __init__(
@@ -34,9 +54,22 @@ class NextTokenChooser:
         top_k=None,
         top_p=None,
         typical_p=None,
+        min_p=0.0,
+        epsilon_cutoff=0.0,
+        eta_cutoff=0.0,
+        mirostat=0,
+        mirostat_tau=0.0,
+        mirostat_eta=0.0,
         do_sample=False,
         min_new_tokens=0,
         no_repeat_ngram_size=0,
+        frequency_penalty=0.0,
+        presence_penalty=0.0,
+        bad_words_ids: Optional[List[List[int]]] = None,
+        logit_bias: Optional[Dict[int, float]] = None,
+        grammar: str = "",
+        token_healing_prefix: str = "",
+        tokenizer: Optional[PreTrainedTokenizerBase] = None,
         seed=0,
         device="cpu",
     ):
@@ -57,6 +90,33 @@ class NextTokenChooser:
             if min_new_tokens
             else None
         )
+        self.frequency_processor = (
+            HeterogeneousFrequencyPenaltyLogitsProcessor(
+                [frequency_penalty], [presence_penalty], torch.float32, device
+            )
+            if frequency_penalty or presence_penalty
+            else None
+        )
+        self.bad_words_processor = (
+            HeterogeneousBadWordsLogitsProcessor([bad_words_ids])
+            if bad_words_ids
+            else None
+        )
+        self.logit_bias_processor = (
+            HeterogeneousLogitBiasLogitsProcessor([logit_bias])
+            if logit_bias
+            else None
+        )
+        self.grammar_processor = (
+            HeterogeneousGrammarLogitProcessor(tokenizer, [grammar])
+            if grammar
+            else None
+        )
+        self.token_healing_processor = (
+            HeterogeneousTokenHealingLogitsProcessor(tokenizer, [token_healing_prefix])
+            if token_healing_prefix
+            else None
+        )
 
         has_warpers = (
             (temperature is not None and temperature != 1.0)
@@ -71,7 +131,32 @@ class NextTokenChooser:
         else:
             self.static_warper = None
 
-        sampling = do_sample or has_warpers
+        # Warpers that transformers does not implement, applied after the static warper
+        self.warpers = []
+        if min_p:
+            self.warpers.append(HeterogeneousMinPLogitsWarper([min_p], torch.float32, device))
+        if epsilon_cutoff:
+            self.warpers.append(
+                HeterogeneousEpsilonLogitsWarper([epsilon_cutoff], torch.float32, device)
+            )
+        if eta_cutoff:
+            self.warpers.append(
+                HeterogeneousEtaLogitsWarper([eta_cutoff], torch.float32, device)
+            )
+        self.mirostat_warper = (
+            HeterogeneousMirostatLogitsWarper(
+                [mirostat], [mirostat_tau], [mirostat_eta], device
+            )
+            if mirostat
+            else None
+        )
+
+        sampling = (
+            do_sample
+            or has_warpers
+            or bool(self.warpers)
+            or self.mirostat_warper is not None
+        )
         self.choice = Sampling(seed, device) if sampling else Greedy()
 
     def __call__(self, input_ids, scores):
@@ -83,14 +168,44 @@ class NextTokenChooser:
             scores = self.no_repeat_ngram_logits_processor(input_ids, scores)
         if self.min_new_tokens_processor is not None:
             scores = self.min_new_tokens_processor(input_ids, scores)
+        if self.frequency_processor is not None:
+            scores = self.frequency_processor(input_ids, scores)
+        if self.bad_words_processor is not None:
+            scores = self.bad_words_processor(input_ids, scores)
+        if self.logit_bias_processor is not None:
+            scores = self.logit_bias_processor(input_ids, scores)
+        if self.grammar_processor is not None:
+            scores = self.grammar_processor(input_ids, scores)
+        if self.token_healing_processor is not None:
+            scores = self.token_healing_processor(input_ids, scores)
 
         if self.static_warper is None:
             next_logprob = torch.log_softmax(scores, -1)
         else:
             scores, next_logprob = self.static_warper(scores)
 
+        if self.warpers or self.mirostat_warper is not None:
+            for warper in self.warpers:
+                scores = warper(input_ids, scores)
+            if self.mirostat_warper is not None:
+                scores = self.mirostat_warper(input_ids, scores)
+            next_logprob = torch.log_softmax(scores, -1)
+
         next_id = self.choice(scores[-1]).view(1, 1)
 
+        if self.mirostat_warper is not None:
+            self.mirostat_warper.advance(next_id, next_logprob[-1:])
+        if self.frequency_processor is not None:
+            self.frequency_processor.advance(next_id)
+        if self.bad_words_processor is not None:
+            self.bad_words_processor.advance([next_id.item()])
+        if self.grammar_processor is not None:
+            self.grammar_processor.advance([next_id.item()])
+        if self.token_healing_processor is not None:
+            self.token_healing_processor.advance([next_id.item()])
+            if not self.token_healing_processor.prefixes[0]:
+                self.token_healing_processor = None
+
         return next_id, next_logprob
 
     @classmethod
@@ -110,9 +225,22 @@ class NextTokenChooser:
             top_k=pb.top_k,
             top_p=pb.top_p,
             typical_p=pb.typical_p,
+            min_p=pb.min_p,
+            epsilon_cutoff=pb.epsilon_cutoff,
+            eta_cutoff=pb.eta_cutoff,
+            mirostat=pb.mirostat,
+            mirostat_tau=pb.mirostat_tau,
+            mirostat_eta=pb.mirostat_eta,
             do_sample=pb.do_sample,
             min_new_tokens=pb.min_new_tokens,
             no_repeat_ngram_size=pb.no_repeat_ngram_size,
+            frequency_penalty=pb.frequency_penalty,
+            presence_penalty=pb.presence_penalty,
+            bad_words_ids=[list(ids.ids) for ids in pb.bad_words_ids],
+            logit_bias=dict(pb.logit_bias),
+            grammar=grammar_automaton(pb),
+            token_healing_prefix=pb.token_healing_prefix,
+            tokenizer=tokenizer,
             seed=pb.seed,
             device=device,
         )
@@ -136,8 +264,10 @@ class StoppingCriteria:
         stop_sequence_criterias: List[StopSequenceCriteria],
         max_new_tokens: int = 20,
         ignore_eos_token: bool = False,
+        stop_token_ids: Optional[List[int]] = None,
     ):
         self.eos_token_id = eos_token_id
+        self.stop_token_ids = set(stop_token_ids or [])
         self.stop_sequence_criterias = stop_sequence_criterias
         self.max_new_tokens = max_new_tokens
         self.current_tokens = 0
@@ -152,6 +282,10 @@ class StoppingCriteria:
         if not self.ignore_eos_token and last_token == self.eos_token_id:
             return True, FinishReason.FINISH_REASON_EOS_TOKEN
 
+        # `last_token` can be a tensor which does not hash like an int
+        if self.stop_token_ids and int(last_token) in self.stop_token_ids:
+            return True, FinishReason.FINISH_REASON_STOP_SEQUENCE
+
         self.current_output += last_output
         for stop_sequence_criteria in self.stop_sequence_criterias:
             if stop_sequence_criteria(self.current_output):
@@ -173,6 +307,7 @@ class StoppingCriteria:
             stop_sequence_criterias,
             pb.max_new_tokens,
             pb.ignore_eos_token,
+            list(pb.stop_token_ids),
         )
 
 
@@ -189,6 +324,19 @@ class HeterogeneousNextTokenChooser:
         typical_p: List[float],
         do_sample: List[bool],
         seeds: List[int],
+        min_p: Optional[List[float]] = None,
+        epsilon_cutoff: Optional[List[float]] = None,
+        eta_cutoff: Optional[List[float]] = None,
+        mirostat: Optional[List[int]] = None,
+        mirostat_tau: Optional[List[float]] = None,
+        mirostat_eta: Optional[List[float]] = None,
+        frequency_penalty: Optional[List[float]] = None,
+        presence_penalty: Optional[List[float]] = None,
+        bad_words_ids: Optional[List[List[List[int]]]] = None,
+        logit_bias: Optional[List[Dict[int, float]]] = None,
+        grammars: Optional[List[str]] = None,
+        token_healing_prefixes: Optional[List[str]] = None,
+        tokenizer: Optional[PreTrainedTokenizerBase] = None,
     ):
         warpers = []
 
@@ -212,6 +360,39 @@ class HeterogeneousNextTokenChooser:
             else None
         )
 
+        self.frequency_processor = (
+            HeterogeneousFrequencyPenaltyLogitsProcessor(
+                frequency_penalty, presence_penalty, dtype, device
+            )
+            if frequency_penalty is not None
+            and any([x != 0.0 for x in frequency_penalty + presence_penalty])
+            else None
+        )
+
+        self.bad_words_processor = (
+            HeterogeneousBadWordsLogitsProcessor(bad_words_ids)
+            if bad_words_ids is not None and any(bad_words_ids)
+            else None
+        )
+
+        self.logit_bias_processor = (
+            HeterogeneousLogitBiasLogitsProcessor(logit_bias)
+            if logit_bias is not None and any(logit_bias)
+            else None
+        )
+
+        self.grammar_processor = (
+            HeterogeneousGrammarLogitProcessor(tokenizer, grammars)
+            if grammars is not None and any(grammars)
+            else None
+        )
+
+        self.token_healing_processor = (
+            HeterogeneousTokenHealingLogitsProcessor(tokenizer, token_healing_prefixes)
+            if token_healing_prefixes is not None and any(token_healing_prefixes)
+            else None
+        )
+
         if any([x != 1.0 for x in temperature]):
             do_sample = [
                 sample or x != 1.0 for x, sample in zip(temperature, do_sample)
@@ -232,8 +413,33 @@ class HeterogeneousNextTokenChooser:
             do_sample = [sample or x < 1.0 for x, sample in zip(typical_p, do_sample)]
             warpers.append(HeterogeneousTypicalLogitsWarper(typical_p, dtype, device))
 
+        if min_p is not None and any([x > 0.0 for x in min_p]):
+            do_sample = [sample or x > 0.0 for x, sample in zip(min_p, do_sample)]
+            warpers.append(HeterogeneousMinPLogitsWarper(min_p, dtype, device))
+
+        if epsilon_cutoff is not None and any([x > 0.0 for x in epsilon_cutoff]):
+            do_sample = [
+                sample or x > 0.0 for x, sample in zip(epsilon_cutoff, do_sample)
+            ]
+            warpers.append(
+                HeterogeneousEpsilonLogitsWarper(epsilon_cutoff, dtype, device)
+            )
+
+        if eta_cutoff is not None and any([x > 0.0 for x in eta_cutoff]):
+            do_sample = [sample or x > 0.0 for x, sample in zip(eta_cutoff, do_sample)]
+            warpers.append(HeterogeneousEtaLogitsWarper(eta_cutoff, dtype, device))
+
         self.warpers = warpers
 
+        # Stateful, applied after the other warpers
+        if mirostat is not None and any(mirostat):
+            do_sample = [sample or x != 0 for x, sample in zip(mirostat, do_sample)]
+            self.mirostat_warper = HeterogeneousMirostatLogitsWarper(
+                mirostat, mirostat_tau, mirostat_eta, device
+            )
+        else:
+            self.mirostat_warper = None
+
         if any(do_sample):
             self.choice = HeterogeneousSampling(do_sample, seeds, device)
         else:
@@ -243,22 +449,53 @@ class HeterogeneousNextTokenChooser:
         self.do_sample = do_sample
         self.dtype = dtype
         self.device = device
+        self.tokenizer = tokenizer
 
     def __call__(self, input_ids: torch.Tensor, scores: torch.Tensor):
         if self.watermark_processor is not None:
             scores = self.watermark_processor(input_ids, scores)
         if self.repetition_processor is not None:
             scores = self.repetition_processor(input_ids, scores)
+        if self.frequency_processor is not None:
+            scores = self.frequency_processor(input_ids, scores)
+        if self.bad_words_processor is not None:
+            scores = self.bad_words_processor(input_ids, scores)
+        if self.logit_bias_processor is not None:
+            scores = self.logit_bias_processor(input_ids, scores)
+        if self.grammar_processor is not None:
+            scores = self.grammar_processor(input_ids, scores)
+        if self.token_healing_processor is not None:
+            scores = self.token_healing_processor(input_ids, scores)
 
         for warper in self.warpers:
             scores = warper(input_ids, scores)
+        if self.mirostat_warper is not None:
+            scores = self.mirostat_warper(input_ids, scores)
 
         next_ids = self.choice(scores)
-        next_logprobs = torch.gather(
-            torch.log_softmax(scores, -1), 1, next_ids.view(-1, 1)
-        ).view(-1)
-
-        return next_ids, next_logprobs
+        if self.frequency_processor is not None:
+            self.frequency_processor.advance(next_ids)
+        if (
+            self.bad_words_processor is not None
+            or self.grammar_processor is not None
+            or self.token_healing_processor is not None
+        ):
+            # GPU <-> CPU sync
+            next_ids_list = next_ids.tolist()
+            if self.bad_words_processor is not None:
+                self.bad_words_processor.advance(next_ids_list)
+            if self.grammar_processor is not None:
+                self.grammar_processor.advance(next_ids_list)
+            if self.token_healing_processor is not None:
+                self.token_healing_processor.advance(next_ids_list)
+                if not any(self.token_healing_processor.prefixes):
+                    self.token_healing_processor = None
+        logprobs = torch.log_softmax(scores, -1)
+        next_logprobs = torch.gather(logprobs, 1, next_ids.view(-1, 1)).view(-1)
+        if self.mirostat_warper is not None:
+            self.mirostat_warper.advance(next_ids, logprobs)
+
+        return next_ids, next_logprobs, logprobs
 
     def filter(self, indices):
         if self.watermark_processor is not None:
@@ -267,6 +504,21 @@ class HeterogeneousNextTokenChooser:
         if self.repetition_processor is not None:
             self.repetition_processor = self.repetition_processor.filter(indices)
 
+        if self.frequency_processor is not None:
+            self.frequency_processor = self.frequency_processor.filter(indices)
+
+        if self.bad_words_processor is not None:
+            self.bad_words_processor = self.bad_words_processor.filter(indices)
+
+        if self.logit_bias_processor is not None:
+            self.logit_bias_processor = self.logit_bias_processor.filter(indices)
+
+        if self.grammar_processor is not None:
+            self.grammar_processor = self.grammar_processor.filter(indices)
+
+        if self.token_healing_processor is not None:
+            self.token_healing_processor = self.token_healing_processor.filter(indices)
+
         filtered_warpers = []
         for warper in self.warpers:
             filtered_warper = warper.filter(indices)
@@ -274,6 +526,9 @@ class HeterogeneousNextTokenChooser:
                 filtered_warpers.append(filtered_warper)
         self.warpers = filtered_warpers
 
+        if self.mirostat_warper is not None:
+            self.mirostat_warper = self.mirostat_warper.filter(indices)
+
         self.seeds = [self.seeds[i] for i in indices]
         self.do_sample = [self.do_sample[i] for i in indices]
 
@@ -284,12 +539,40 @@ class HeterogeneousNextTokenChooser:
 
         return self
 
+    def restore_states(self, choosers: List["HeterogeneousNextTokenChooser"]):
+        """Carry the states over from the choosers of concatenated batches, in order"""
+        sizes = [len(chooser.seeds) for chooser in choosers]
+        if self.frequency_processor is not None:
+            self.frequency_processor.restore(
+                [chooser.frequency_processor for chooser in choosers], sizes
+            )
+        if self.bad_words_processor is not None:
+            self.bad_words_processor.restore(
+                [chooser.bad_words_processor for chooser in choosers], sizes
+            )
+        if self.mirostat_warper is not None:
+            self.mirostat_warper.restore(
+                [chooser.mirostat_warper for chooser in choosers], sizes
+            )
+        if self.grammar_processor is not None:
+            self.grammar_processor.restore(
+                [chooser.grammar_processor for chooser in choosers], sizes
+            )
+        if self.token_healing_processor is not None:
+            self.token_healing_processor.restore(
+                [chooser.token_healing_processor for chooser in choosers], sizes
+            )
+            if not any(self.token_healing_processor.prefixes):
+                self.token_healing_processor = None
+        return self
+
     @classmethod
     def from_pb(
         cls,
         pb: List[generate_pb2.NextTokenChooserParameters],
         dtype: torch.dtype,
         device: torch.device,
+        tokenizer: Optional[PreTrainedTokenizerBase] = None,
     ) -> "HeterogeneousNextTokenChooser":
         return HeterogeneousNextTokenChooser(
             watermark=[pb_.watermark for pb_ in pb],
@@ -300,11 +583,53 @@ class HeterogeneousNextTokenChooser:
             typical_p=[pb_.typical_p for pb_ in pb],
             do_sample=[pb_.do_sample for pb_ in pb],
             seeds=[pb_.seed for pb_ in pb],
+            min_p=[pb_.min_p for pb_ in pb],
+            epsilon_cutoff=[pb_.epsilon_cutoff for pb_ in pb],
+            eta_cutoff=[pb_.eta_cutoff for pb_ in pb],
+            mirostat=[pb_.mirostat for pb_ in pb],
+            mirostat_tau=[pb_.mirostat_tau for pb_ in pb],
+            mirostat_eta=[pb_.mirostat_eta for pb_ in pb],
+            frequency_penalty=[pb_.frequency_penalty for pb_ in pb],
+            presence_penalty=[pb_.presence_penalty for pb_ in pb],
+            bad_words_ids=[[list(ids.ids) for ids in pb_.bad_words_ids] for pb_ in pb],
+            logit_bias=[dict(pb_.logit_bias) for pb_ in pb],
+            grammars=[grammar_automaton(pb_) for pb_ in pb],
+            token_healing_prefixes=[pb_.token_healing_prefix for pb_ in pb],
+            tokenizer=tokenizer,
             device=device,
             dtype=dtype,
         )
 
 
+def batch_top_tokens(
+    top_n_tokens: List[int], logprobs: torch.Tensor
+) -> Tuple[List[List[int]], List[List[float]]]:
+    """Select the `top_n_tokens[i]` most likely tokens of each row of `logprobs`"""
+    max_top_n = max(top_n_tokens, default=0)
+    if max_top_n == 0:
+        return [[] for _ in top_n_tokens], [[] for _ in top_n_tokens]
+
+    top_logprobs, top_ids = torch.topk(
+        logprobs, min(max_top_n, logprobs.shape[-1]), dim=-1
+    )
+    # GPU <-> CPU sync
+    top_logprobs = top_logprobs.tolist()
+    top_ids = top_ids.tolist()
+
+    batch_ids = []
+    batch_logprobs = []
+    for ids, row_logprobs, n in zip(top_ids, top_logprobs, top_n_tokens):
+        # Tokens removed by the warpers cannot be sampled, do not return them
+        kept = [
+            (token_id, logprob)
+            for token_id, logprob in zip(ids[:n], row_logprobs[:n])
+            if logprob != float("-inf")
+        ]
+        batch_ids.append([token_id for token_id, _ in kept])
+        batch_logprobs.append([logprob for _, logprob in kept])
+    return batch_ids, batch_logprobs
+
+
 class Sampling:
     def __init__(self, seed: int, device: str = "cpu"):
         self.generator = torch.Generator(device)
@@ -364,3 +689,43 @@ class HeterogeneousSampling:
         self.greedy_indices = new_greedy_indices
         self.sampling_mapping = new_sampling_mapping
         return self
+
+
+def batch_tokenize(
+    tokenizer: PreTrainedTokenizerBase,
+    inputs: List[str],
+    add_special_tokens: List[bool],
+    max_truncation: int,
+    padding: bool = False,
+):
+    """Tokenize the inputs of a batch
+
+    The special tokens of the tokenizer are only added to the inputs asking for them: the
+    prompts rendered by a chat template already contain them.
+    With `padding`, returns the padded `input_ids` and `attention_mask` tensors, else the
+    token ids of each input.
+    """
+    kwargs = dict(
+        truncation=True, max_length=max_truncation, return_token_type_ids=False
+    )
+    if all(add_special_tokens) or not any(add_special_tokens):
+        # A single call for the whole batch
+        if padding:
+            return tokenizer(
+                inputs,
+                add_special_tokens=add_special_tokens[0],
+                return_tensors="pt",
+                padding=True,
+                **kwargs,
+            )
+        return tokenizer(inputs, add_special_tokens=add_special_tokens[0], **kwargs)[
+            "input_ids"
+        ]
+
+    encodings = [
+        tokenizer(text, add_special_tokens=add, **kwargs)
+        for text, add in zip(inputs, add_special_tokens)
+    ]
+    if padding:
+        return tokenizer.pad(encodings, return_tensors="pt")
+    return [encoding["input_ids"] for encoding in encodings]